@@ -16,6 +16,11 @@
 
 #![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
 #[cfg(feature = "std")]
 use std::fmt::Debug;
 #[cfg(feature = "std")]
@@ -48,6 +53,9 @@ pub type Prefix<'a> = (&'a[u8], Option<u8>);
 /// or for root nodes.
 pub static EMPTY_PREFIX: Prefix<'static> = (&[], None);
 
+/// Owned version of a `Prefix`.
+pub type OwnedPrefix = (Vec<u8>, Option<u8>);
+
 /// Trait describing an object that can hash a slice of bytes. Used to abstract
 /// other types over the hashing algorithm. Defines a single `hash` method and an
 /// `Out` associated type with the necessary bounds.
@@ -128,6 +136,22 @@ pub trait HashDB<H: Hasher, T>: Send + Sync + AsHashDB<H, T> {
 	/// `insert()`s may happen without the data being eventually being inserted into the DB.
 	/// It can be "owed" more than once.
 	fn remove(&mut self, key: &H::Out, prefix: Prefix);
+
+	/// Like `emplace()` for each item in order. Implementations can override it to write all
+	/// the items at once.
+	fn emplace_batch(&mut self, items: Vec<(H::Out, OwnedPrefix, T)>) {
+		for (key, prefix, value) in items {
+			self.emplace(key, (&prefix.0[..], prefix.1), value);
+		}
+	}
+
+	/// Like `remove()` for each item in order. Implementations can override it to remove all
+	/// the items at once.
+	fn remove_batch(&mut self, items: Vec<(H::Out, OwnedPrefix)>) {
+		for (key, prefix) in items {
+			self.remove(&key, (&prefix.0[..], prefix.1));
+		}
+	}
 }
 
 /// Trait for immutable reference of HashDB.
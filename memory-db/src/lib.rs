@@ -20,7 +20,7 @@
 extern crate alloc;
 
 use hash_db::{HashDB, HashDBRef, PlainDB, PlainDBRef, Hasher as KeyHasher,
-	AsHashDB, AsPlainDB, Prefix};
+	AsHashDB, AsPlainDB, Prefix, OwnedPrefix};
 use parity_util_mem::{MallocSizeOf, MallocSizeOfOps};
 #[cfg(feature = "deprecated")]
 #[cfg(feature = "std")]
@@ -582,6 +582,13 @@ where
 		key
 	}
 
+	fn emplace_batch(&mut self, items: Vec<(H::Out, OwnedPrefix, T)>) {
+		self.data.reserve(items.len());
+		for (key, prefix, value) in items {
+			HashDB::emplace(self, key, (&prefix.0[..], prefix.1), value);
+		}
+	}
+
 	fn remove(&mut self, key: &H::Out, prefix: Prefix) {
 		if key == &self.hashed_null_node {
 			return;
@@ -688,6 +695,24 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn emplace_batch_matches_emplace() {
+		let values: Vec<&[u8]> = vec![b"doggo", b"arf", b"doggo", &[0u8][..]];
+		let mut single = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
+		let mut batched = single.clone();
+		let unknown = KeccakHasher::hash(b"unknown");
+		single.remove(&unknown, EMPTY_PREFIX);
+		batched.remove(&unknown, EMPTY_PREFIX);
+		let mut items = Vec::new();
+		for value in values.iter().chain(Some(&&b"unknown"[..])) {
+			let key = KeccakHasher::hash(value);
+			single.emplace(key, EMPTY_PREFIX, value.to_vec());
+			items.push((key, (Vec::new(), None), value.to_vec()));
+		}
+		batched.emplace_batch(items);
+		assert_eq!(single.drain(), batched.drain());
+	}
+
 	#[test]
 	fn default_works() {
 		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
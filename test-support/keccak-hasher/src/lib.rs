@@ -14,6 +14,8 @@
 
 //! Hasher implementation for the Keccak-256 hash
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
 use hash_db::Hasher;
 use tiny_keccak::Keccak;
 use hash256_std_hasher::Hash256StdHasher;
@@ -22,4 +22,5 @@ fn benchmark(c: &mut Criterion) {
 		reference_trie::ExtensionLayout,
 		reference_trie::ReferenceTrieStream,
 	>(c, "ref");
+	trie_bench::node_iteration_benchmark::<reference_trie::ExtensionLayout>(c, "ref");
 }
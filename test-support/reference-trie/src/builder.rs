@@ -0,0 +1,258 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tries built inline in tests, owning their database.
+//!
+//! ```
+//! use reference_trie::{builder::TrieBuilder, ExtensionLayout, Trie};
+//!
+//! let built = TrieBuilder::<ExtensionLayout>::new().insert(b"dog", b"puppy").build();
+//! assert_eq!(built.trie().get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+//! ```
+
+use std::marker::PhantomData;
+
+use hash_db::{HashDB, Prefix};
+use memory_db::{MemoryDB, PrefixedKey};
+use trie_db::node::{Node, NodeHandle};
+use trie_db::{
+	DBValue, NibbleSlice, TrieDB, TrieDBMut, TrieDBNodeIterator, TrieHash, TrieLayout, TrieMut,
+};
+
+/// Database of the built tries, keyed by prefix and hash so that nodes with the same data at
+/// different places are distinct.
+pub type BuiltDB<L> = MemoryDB<
+	<L as TrieLayout>::Hash,
+	PrefixedKey<<L as TrieLayout>::Hash>,
+	DBValue,
+>;
+
+/// Builder of a trie in a new in-memory database.
+pub struct TrieBuilder<L: TrieLayout> {
+	pairs: Vec<(Vec<u8>, Vec<u8>)>,
+	_marker: PhantomData<L>,
+}
+
+impl<L: TrieLayout> Default for TrieBuilder<L> {
+	fn default() -> Self {
+		TrieBuilder { pairs: Vec::new(), _marker: PhantomData }
+	}
+}
+
+impl<L: TrieLayout> TrieBuilder<L> {
+	/// Builder of an empty trie.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Insert `value` at `key`, replacing a previous value at `key`.
+	pub fn insert(mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Self {
+		self.pairs.push((key.as_ref().to_vec(), value.as_ref().to_vec()));
+		self
+	}
+
+	/// Insert the key-value pairs of `pairs`, in order.
+	pub fn extend<K, V>(mut self, pairs: impl IntoIterator<Item = (K, V)>) -> Self
+		where
+			K: AsRef<[u8]>,
+			V: AsRef<[u8]>,
+	{
+		self.pairs.extend(
+			pairs.into_iter().map(|(key, value)| (key.as_ref().to_vec(), value.as_ref().to_vec()))
+		);
+		self
+	}
+
+	/// Commit the trie to a new database.
+	pub fn build(self) -> BuiltTrie<L> {
+		let mut db = BuiltDB::<L>::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+			for (key, value) in self.pairs.iter() {
+				if t.insert(key, value).is_err() {
+					panic!("insertion in a new in-memory trie cannot fail");
+				}
+			}
+		}
+		BuiltTrie { db, root }
+	}
+}
+
+/// A trie with its own database.
+pub struct BuiltTrie<L: TrieLayout> {
+	db: BuiltDB<L>,
+	root: TrieHash<L>,
+}
+
+impl<L: TrieLayout> BuiltTrie<L> {
+	/// Root of the trie.
+	pub fn root(&self) -> &TrieHash<L> {
+		&self.root
+	}
+
+	/// Database of the trie.
+	pub fn db(&self) -> &BuiltDB<L> {
+		&self.db
+	}
+
+	/// Database of the trie, for changes the other methods do not cover.
+	pub fn db_mut(&mut self) -> &mut BuiltDB<L> {
+		&mut self.db
+	}
+
+	/// Split into the database and the root.
+	pub fn into_parts(self) -> (BuiltDB<L>, TrieHash<L>) {
+		(self.db, self.root)
+	}
+
+	/// Read the trie.
+	///
+	/// Panics if the root node was removed or corrupted.
+	pub fn trie(&self) -> TrieDB<'_, L> {
+		TrieDB::new(&self.db, &self.root)
+			.unwrap_or_else(|_| panic!("the root node of the built trie is missing or corrupt"))
+	}
+
+	/// Change the trie, the root being updated when the returned trie is dropped.
+	///
+	/// Panics if the root node was removed or corrupted.
+	pub fn trie_mut(&mut self) -> TrieDBMut<'_, L> {
+		TrieDBMut::from_existing(&mut self.db, &mut self.root)
+			.unwrap_or_else(|_| panic!("the root node of the built trie is missing or corrupt"))
+	}
+
+	/// Remove the node `hash` of the trie from the database.
+	///
+	/// Panics if no node of the trie references a child `hash`, the root aside.
+	pub fn remove_node(&mut self, hash: &TrieHash<L>) {
+		let (prefix, last) = self.node_prefix(hash);
+		self.db.remove(hash, (&prefix, last));
+	}
+
+	/// Truncate the data of the node `hash` of the trie by one byte, so that it no longer
+	/// matches its hash and fails to decode with the reference codecs.
+	///
+	/// Panics if the node is not in the trie or not in the database.
+	pub fn corrupt_node(&mut self, hash: &TrieHash<L>) {
+		let (prefix, last) = self.node_prefix(hash);
+		let prefix: Prefix = (&prefix, last);
+		let mut data = self.db.get(hash, prefix)
+			.expect("the corrupted node is in the database");
+		data.pop();
+		self.db.remove(hash, prefix);
+		self.db.emplace(*hash, prefix, data);
+	}
+
+	/// Prefix of the node `hash`, found by walking the trie. The node is found from the hash of
+	/// its parent, so that the prefix of a missing or corrupt node is found too.
+	fn node_prefix(&self, hash: &TrieHash<L>) -> (Vec<u8>, Option<u8>) {
+		if *hash == self.root {
+			return (Vec::new(), None);
+		}
+		let trie = self.trie();
+		let iter = TrieDBNodeIterator::new(&trie)
+			.unwrap_or_else(|_| panic!("the root node of the built trie is missing or corrupt"));
+		for item in iter {
+			let (prefix, _, node) = match item {
+				Ok(item) => item,
+				Err(_) => continue,
+			};
+			let (partial, children) = match node.node() {
+				Node::Extension(partial, child) => (partial, vec![(None, child)]),
+				Node::Branch(children, _) => (NibbleSlice::new(&[]), branch_children(&children)),
+				Node::NibbledBranch(partial, children, _) => (partial, branch_children(&children)),
+				Node::Empty | Node::Leaf(..) => continue,
+			};
+			for (index, child) in children {
+				if child != NodeHandle::Hash(hash.as_ref()) {
+					continue;
+				}
+				let mut prefix = prefix.clone();
+				for i in 0..partial.len() {
+					prefix.push(partial.at(i));
+				}
+				if let Some(index) = index {
+					prefix.push(index);
+				}
+				let (prefix, last) = prefix.as_prefix();
+				return (prefix.to_vec(), last);
+			}
+		}
+		panic!("no node of the built trie has the given hash");
+	}
+}
+
+/// The children of a branch, with their index.
+fn branch_children<'a>(
+	children: &[Option<NodeHandle<'a>>],
+) -> Vec<(Option<u8>, NodeHandle<'a>)> {
+	children.iter()
+		.enumerate()
+		.filter_map(|(index, child)| child.map(|child| (Some(index as u8), child)))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use trie_db::{Trie, TrieError, TrieMut};
+	use super::TrieBuilder;
+	use crate::{ExtensionLayout, NoExtensionLayout};
+
+	#[test]
+	fn built_trie_can_be_changed() {
+		let mut built = TrieBuilder::<NoExtensionLayout>::new()
+			.extend(vec![(&b"do"[..], &b"verb"[..]), (b"dog", b"puppy")])
+			.insert(b"do", b"noun")
+			.build();
+		assert_eq!(built.trie().get(b"do").unwrap(), Some(b"noun".to_vec()));
+		let root = *built.root();
+
+		built.trie_mut().insert(b"doge", b"coin").unwrap();
+		assert_ne!(*built.root(), root);
+		assert_eq!(built.trie().get(b"doge").unwrap(), Some(b"coin".to_vec()));
+		assert_eq!(built.trie().get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+	}
+
+	#[test]
+	fn corrupt_node_fails_decoding() {
+		let mut built = TrieBuilder::<ExtensionLayout>::new()
+			.insert([0x01], [1; 32])
+			.insert([0x02], [2; 32])
+			.build();
+		let leaf_hash = {
+			let trie = built.trie();
+			let iter = trie_db::TrieDBNodeIterator::new(&trie).unwrap();
+			iter.map(|item| item.unwrap())
+				.filter_map(|(prefix, hash, _)| hash.filter(|_| prefix.len() == 2))
+				.next()
+				.unwrap()
+		};
+
+		built.corrupt_node(&leaf_hash);
+		// with the `verify-node-hash` feature of trie-db, the node is rejected before decoding.
+		match built.trie().get(&[0x01]).map_err(|error| *error) {
+			Err(TrieError::DecoderError(hash, _)) => assert_eq!(hash, leaf_hash),
+			Err(TrieError::HashMismatch { expected, .. }) => assert_eq!(expected, leaf_hash),
+			result => panic!("unexpected result: {:?}", result),
+		}
+		assert_eq!(built.trie().get(&[0x02]).unwrap(), Some(vec![2; 32]));
+
+		built.remove_node(&leaf_hash);
+		match built.trie().get(&[0x01]).map_err(|error| *error) {
+			Err(TrieError::IncompleteDatabase { hash, .. }) => assert_eq!(hash, leaf_hash),
+			result => panic!("unexpected result: {:?}", result),
+		}
+	}
+}
@@ -14,34 +14,52 @@
 
 //! Reference implementation of a streamer.
 
-use std::fmt;
-use std::iter::once;
-use std::marker::PhantomData;
-use std::ops::Range;
+#![cfg_attr(not(feature = "std-helpers"), no_std)]
+
+#[cfg(not(feature = "std-helpers"))]
+extern crate alloc;
+
+#[cfg(feature = "std-helpers")]
+mod rstd {
+	pub use std::{borrow, cmp, fmt, iter, marker, ops, result};
+	pub use std::vec::Vec;
+}
+
+#[cfg(not(feature = "std-helpers"))]
+mod rstd {
+	pub use core::{borrow, cmp, fmt, iter, marker, ops, result};
+	pub use alloc::vec::Vec;
+}
+
+#[cfg(not(feature = "std-helpers"))]
+use alloc::vec;
+use self::rstd::{fmt, iter::once, marker::PhantomData, ops::Range, Vec};
 use parity_scale_codec::{Decode, Input, Output, Encode, Compact, Error as CodecError};
 use trie_root::Hasher;
 
 use trie_db::{
 	node::{NibbleSlicePlan, NodePlan, NodeHandlePlan},
-	triedbmut::ChildReference,
 	DBValue,
 	trie_visit,
 	TrieBuilder,
 	TrieRoot,
-	Partial,
 };
-use std::borrow::Borrow;
+use self::rstd::borrow::Borrow;
 use keccak_hasher::KeccakHasher;
 
 pub use trie_db::{
-	decode_compact, encode_compact,
-	nibble_ops, NibbleSlice, NibbleVec, NodeCodec, proof, Record, Recorder,
-	Trie, TrieConfiguration, TrieDB, TrieDBIterator, TrieDBMut, TrieDBNodeIterator, TrieError,
-	TrieIterator, TrieLayout, TrieMut,
+	CanonicalizeStats, ChildReference, commitment, decode_compact, encode_compact, Partial,
+	Children, nested, nibble_ops, NibbleOps, NibbleSlice, NibbleVec, NodeCodec, OwnedTrieDBIterator,
+	OwnedTrieDBNodeIterator, proof, prune, Record, Recorder, RecorderStats, RemovalInfo,
+	Radix16, Radix2, SharedNode, Trie, TrieConfiguration, TrieDB, TrieDBIterator, TrieDBMut,
+	TrieDBNodeIterator, TrieError, TrieIterator, TrieLayout, TrieMut, value_chunks, verify,
+	WriteDecision,
 };
 pub use trie_root::TrieStream;
+#[cfg(feature = "test-helpers")]
+pub mod builder;
 pub mod node {
-	pub use trie_db::node::Node;
+	pub use trie_db::node::{encode_node, Node, NodeHandle, NodePlan, Value};
 }
 
 /// Trie layout using extension nodes.
@@ -51,6 +69,7 @@ impl TrieLayout for ExtensionLayout {
 	const USE_EXTENSION: bool = true;
 	type Hash = KeccakHasher;
 	type Codec = ReferenceNodeCodec<KeccakHasher>;
+	type Nibble = Radix16;
 }
 
 impl TrieConfiguration for ExtensionLayout { }
@@ -63,6 +82,7 @@ impl<H: Hasher> TrieLayout for GenericNoExtensionLayout<H> {
 	const USE_EXTENSION: bool = false;
 	type Hash = H;
 	type Codec = ReferenceNodeCodecNoExt<H>;
+	type Nibble = Radix16;
 }
 
 impl<H: Hasher> TrieConfiguration for GenericNoExtensionLayout<H> { }
@@ -70,6 +90,86 @@ impl<H: Hasher> TrieConfiguration for GenericNoExtensionLayout<H> { }
 /// Trie layout without extension nodes.
 pub type NoExtensionLayout = GenericNoExtensionLayout<keccak_hasher::KeccakHasher>;
 
+/// Size of the largest value held by the nodes of `GenericValueNodeLayout`.
+pub const VALUE_NODE_THRESHOLD: usize = 32;
+
+/// Trie layout without extension nodes, storing the values of more than
+/// `VALUE_NODE_THRESHOLD` bytes in value nodes, allowing generic hasher.
+pub struct GenericValueNodeLayout<H>(PhantomData<H>);
+
+impl<H: Hasher> TrieLayout for GenericValueNodeLayout<H> {
+	const USE_EXTENSION: bool = false;
+	const MAX_INLINE_VALUE: Option<usize> = Some(VALUE_NODE_THRESHOLD);
+	type Hash = H;
+	type Codec = ReferenceNodeCodecNoExt<H>;
+	type Nibble = Radix16;
+}
+
+impl<H: Hasher> TrieConfiguration for GenericValueNodeLayout<H> { }
+
+/// Trie layout without extension nodes, with value nodes.
+pub type ValueNodeLayout = GenericValueNodeLayout<keccak_hasher::KeccakHasher>;
+
+/// Size of the chunks of the values of `GenericChunkedValueLayout`.
+pub const VALUE_CHUNK_LEN: usize = 64;
+
+/// Trie layout without extension nodes, storing the values of more than
+/// `VALUE_NODE_THRESHOLD` bytes in value nodes, cut in chunks of `VALUE_CHUNK_LEN` bytes
+/// when longer, allowing generic hasher.
+pub struct GenericChunkedValueLayout<H>(PhantomData<H>);
+
+impl<H: Hasher> TrieLayout for GenericChunkedValueLayout<H> {
+	const USE_EXTENSION: bool = false;
+	const MAX_INLINE_VALUE: Option<usize> = Some(VALUE_NODE_THRESHOLD);
+	const MAX_VALUE_CHUNK: Option<usize> = Some(VALUE_CHUNK_LEN);
+	type Hash = H;
+	type Codec = ReferenceNodeCodecNoExt<H>;
+	type Nibble = Radix16;
+}
+
+impl<H: Hasher> TrieConfiguration for GenericChunkedValueLayout<H> { }
+
+/// Trie layout without extension nodes, with values stored in chunks.
+pub type ChunkedValueLayout = GenericChunkedValueLayout<keccak_hasher::KeccakHasher>;
+
+/// Keccak-256 hasher truncated to 16 bytes.
+#[cfg(feature = "std-helpers")]
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ShortKeccakHasher;
+
+#[cfg(feature = "std-helpers")]
+impl Hasher for ShortKeccakHasher {
+	type Out = [u8; 16];
+	type StdHasher = std::collections::hash_map::DefaultHasher;
+	const LENGTH: usize = 16;
+
+	fn hash(x: &[u8]) -> Self::Out {
+		let mut out = [0u8; 16];
+		out.copy_from_slice(&KeccakHasher::hash(x)[..16]);
+		out
+	}
+}
+
+/// Trie layout setting all the options of `TrieLayout` to other values than the common
+/// layouts, for tests covering their interactions: it uses extension nodes, and the hashes
+/// of `ShortKeccakHasher`, so that nodes from 16 bytes are stored by hash rather than inlined
+/// in their parent.
+///
+/// New layout options should be enabled here with non-default values.
+#[cfg(feature = "std-helpers")]
+pub struct ComplexLayout;
+
+#[cfg(feature = "std-helpers")]
+impl TrieLayout for ComplexLayout {
+	const USE_EXTENSION: bool = true;
+	type Hash = ShortKeccakHasher;
+	type Codec = ReferenceNodeCodec<ShortKeccakHasher>;
+	type Nibble = Radix16;
+}
+
+#[cfg(feature = "std-helpers")]
+impl TrieConfiguration for ComplexLayout { }
+
 /// Children bitmap codec for radix 16 trie.
 pub struct Bitmap(u16);
 
@@ -102,10 +202,16 @@ pub type RefTrieDB<'a> = trie_db::TrieDB<'a, ExtensionLayout>;
 pub type RefTrieDBNoExt<'a> = trie_db::TrieDB<'a, NoExtensionLayout>;
 pub type RefTrieDBMut<'a> = trie_db::TrieDBMut<'a, ExtensionLayout>;
 pub type RefTrieDBMutNoExt<'a> = trie_db::TrieDBMut<'a, NoExtensionLayout>;
+pub type RefTrieDBValueNodes<'a> = trie_db::TrieDB<'a, ValueNodeLayout>;
+pub type RefTrieDBMutValueNodes<'a> = trie_db::TrieDBMut<'a, ValueNodeLayout>;
+pub type RefTrieDBChunkedValues<'a> = trie_db::TrieDB<'a, ChunkedValueLayout>;
+pub type RefTrieDBMutChunkedValues<'a> = trie_db::TrieDBMut<'a, ChunkedValueLayout>;
 pub type RefFatDB<'a> = trie_db::FatDB<'a, ExtensionLayout>;
 pub type RefFatDBMut<'a> = trie_db::FatDBMut<'a, ExtensionLayout>;
 pub type RefSecTrieDB<'a> = trie_db::SecTrieDB<'a, ExtensionLayout>;
 pub type RefSecTrieDBMut<'a> = trie_db::SecTrieDBMut<'a, ExtensionLayout>;
+pub type RefTrieDBBinary<'a> = trie_db::TrieDB<'a, BinaryLayout>;
+pub type RefTrieDBMutBinary<'a> = trie_db::TrieDBMut<'a, BinaryLayout>;
 pub type RefLookup<'a, Q> = trie_db::Lookup<'a, ExtensionLayout, Q>;
 pub type RefLookupNoExt<'a, Q> = trie_db::Lookup<'a, NoExtensionLayout, Q>;
 
@@ -117,6 +223,7 @@ pub fn reference_trie_root<I, A, B>(input: I) -> <KeccakHasher as Hasher>::Out w
 	trie_root::trie_root::<KeccakHasher, ReferenceTrieStream, _, _, _>(input)
 }
 
+#[cfg(feature = "std-helpers")]
 fn reference_trie_root_unhashed<I, A, B>(input: I) -> Vec<u8> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord + fmt::Debug,
@@ -133,6 +240,7 @@ pub fn reference_trie_root_no_extension<I, A, B>(input: I) -> <KeccakHasher as H
 	trie_root::trie_root_no_extension::<KeccakHasher, ReferenceTrieStreamNoExt, _, _, _>(input)
 }
 
+#[cfg(feature = "std-helpers")]
 fn reference_trie_root_unhashed_no_extension<I, A, B>(input: I) -> Vec<u8> where
 	I: IntoIterator<Item = (A, B)>,
 	A: AsRef<[u8]> + Ord + fmt::Debug,
@@ -188,7 +296,7 @@ fn fuse_nibbles_node_no_extension<'a>(
 	nibbles: &'a [u8],
 	kind: NodeKindNoExt,
 ) -> impl Iterator<Item = u8> + 'a {
-	let size = ::std::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, nibbles.len());
+	let size = rstd::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, nibbles.len());
 
 	let iter_start = match kind {
 		NodeKindNoExt::Leaf => size_and_prefix_iterator(size, LEAF_PREFIX_MASK_NO_EXT),
@@ -387,9 +495,9 @@ impl Encode for NodeHeader {
 /// Encode and allocate node type header (type and size), and partial value.
 /// It uses an iterator over encoded partial bytes as input.
 fn size_and_prefix_iterator(size: usize, prefix: u8) -> impl Iterator<Item = u8> {
-	let size = ::std::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, size);
+	let size = rstd::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, size);
 
-	let l1 = std::cmp::min(62, size);
+	let l1 = rstd::cmp::min(62, size);
 	let (first_byte, mut rem) = if size == l1 {
 		(once(prefix + l1 as u8), 0)
 	} else {
@@ -409,7 +517,7 @@ fn size_and_prefix_iterator(size: usize, prefix: u8) -> impl Iterator<Item = u8>
 			None
 		}
 	};
-	first_byte.chain(::std::iter::from_fn(next_bytes))
+	first_byte.chain(rstd::iter::from_fn(next_bytes))
 }
 
 fn encode_size_and_prefix(size: usize, prefix: u8, out: &mut impl Output) {
@@ -492,71 +600,137 @@ pub struct ReferenceNodeCodec<H>(PhantomData<H>);
 #[derive(Default, Clone)]
 pub struct ReferenceNodeCodecNoExt<H>(PhantomData<H>);
 
-fn partial_to_key(partial: Partial, offset: u8, over: u8) -> Vec<u8> {
+fn partial_to_key_into(partial: Partial, offset: u8, over: u8, output: &mut Vec<u8>) {
 	let number_nibble_encoded = (partial.0).0 as usize;
 	let nibble_count = partial.1.len() * nibble_ops::NIBBLE_PER_BYTE + number_nibble_encoded;
 	assert!(nibble_count < over as usize);
-	let mut output = vec![offset + nibble_count as u8];
+	output.push(offset + nibble_count as u8);
 	if number_nibble_encoded > 0 {
 		output.push(nibble_ops::pad_right((partial.0).1));
 	}
 	output.extend_from_slice(&partial.1[..]);
-	output
 }
 
-fn partial_from_iterator_to_key<I: Iterator<Item = u8>>(
+fn partial_from_iterator_to_key_into<I: Iterator<Item = u8>>(
 	partial: I,
 	nibble_count: usize,
 	offset: u8,
 	over: u8,
-) -> Vec<u8> {
+	output: &mut Vec<u8>,
+) {
 	assert!(nibble_count < over as usize);
-	let mut output = Vec::with_capacity(1 + (nibble_count / nibble_ops::NIBBLE_PER_BYTE));
+	output.reserve(1 + (nibble_count / nibble_ops::NIBBLE_PER_BYTE));
 	output.push(offset + nibble_count as u8);
 	output.extend(partial);
-	output
 }
 
-fn partial_from_iterator_encode<I: Iterator<Item = u8>>(
-	partial: I,
-	nibble_count: usize,
-	node_kind: NodeKindNoExt,
-) -> Vec<u8> {
-	let nibble_count = ::std::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, nibble_count);
-
-	let mut output = Vec::with_capacity(3 + (nibble_count / nibble_ops::NIBBLE_PER_BYTE));
+fn encode_header_no_ext(nibble_count: usize, node_kind: NodeKindNoExt, output: &mut Vec<u8>) {
 	match node_kind {
 		NodeKindNoExt::Leaf =>
-			NodeHeaderNoExt::Leaf(nibble_count).encode_to(&mut output),
+			NodeHeaderNoExt::Leaf(nibble_count).encode_to(output),
 		NodeKindNoExt::BranchWithValue =>
-			NodeHeaderNoExt::Branch(true, nibble_count).encode_to(&mut output),
+			NodeHeaderNoExt::Branch(true, nibble_count).encode_to(output),
 		NodeKindNoExt::BranchNoValue =>
-			NodeHeaderNoExt::Branch(false, nibble_count).encode_to(&mut output),
+			NodeHeaderNoExt::Branch(false, nibble_count).encode_to(output),
 	};
+}
+
+fn partial_from_iterator_encode_into<I: Iterator<Item = u8>>(
+	partial: I,
+	nibble_count: usize,
+	node_kind: NodeKindNoExt,
+	output: &mut Vec<u8>,
+) {
+	let nibble_count = rstd::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, nibble_count);
+
+	output.reserve(3 + (nibble_count / nibble_ops::NIBBLE_PER_BYTE));
+	encode_header_no_ext(nibble_count, node_kind, output);
 	output.extend(partial);
-	output
 }
 
-fn partial_encode(partial: Partial, node_kind: NodeKindNoExt) -> Vec<u8> {
+fn partial_encode_into<N: NibbleOps>(
+	partial: Partial,
+	node_kind: NodeKindNoExt,
+	output: &mut Vec<u8>,
+) {
 	let number_nibble_encoded = (partial.0).0 as usize;
-	let nibble_count = partial.1.len() * nibble_ops::NIBBLE_PER_BYTE + number_nibble_encoded;
+	let nibble_count = partial.1.len() * N::NIBBLE_PER_BYTE + number_nibble_encoded;
 
-	let nibble_count = ::std::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, nibble_count);
+	let nibble_count = rstd::cmp::min(NIBBLE_SIZE_BOUND_NO_EXT, nibble_count);
 
-	let mut output = Vec::with_capacity(3 + partial.1.len());
-	match node_kind {
-		NodeKindNoExt::Leaf =>
-			NodeHeaderNoExt::Leaf(nibble_count).encode_to(&mut output),
-		NodeKindNoExt::BranchWithValue =>
-			NodeHeaderNoExt::Branch(true, nibble_count).encode_to(&mut output),
-		NodeKindNoExt::BranchNoValue =>
-			NodeHeaderNoExt::Branch(false, nibble_count).encode_to(&mut output),
-	};
+	output.reserve(3 + partial.1.len());
+	encode_header_no_ext(nibble_count, node_kind, output);
 	if number_nibble_encoded > 0 {
-		output.push(nibble_ops::pad_right((partial.0).1));
+		output.push(N::pad_right(number_nibble_encoded, (partial.0).1));
 	}
 	output.extend_from_slice(&partial.1[..]);
-	output
+}
+
+/// Append the encoded children of a branch to `output`, returning which children are present.
+fn encode_children_into<HO: AsRef<[u8]>>(
+	children: impl Iterator<Item = impl Borrow<Option<ChildReference<HO>>>>,
+	output: &mut Vec<u8>,
+) -> [bool; nibble_ops::NIBBLE_LENGTH] {
+	let mut has_children = [false; nibble_ops::NIBBLE_LENGTH];
+	for (has_child, maybe_child) in has_children.iter_mut().zip(children) {
+		*has_child = match maybe_child.borrow() {
+			Some(ChildReference::Hash(h)) => {
+				h.as_ref().encode_to(output);
+				true
+			}
+			Some(ChildReference::Inline(inline_data, len)) => {
+				inline_data.as_ref()[..*len].encode_to(output);
+				true
+			}
+			None => false,
+		};
+	}
+	has_children
+}
+
+/// Check that the partial key of `nibble_count` nibbles starting at `offset` fits
+/// in `data` and that its padding (if any) is valid.
+fn check_partial_padding<N: NibbleOps>(
+	data: &[u8],
+	offset: usize,
+	nibble_count: usize,
+) -> Result<(), CodecError> {
+	let partial_len = (nibble_count + (N::NIBBLE_PER_BYTE - 1)) / N::NIBBLE_PER_BYTE;
+	if data.len() < offset + partial_len {
+		return Err("out of data".into());
+	}
+	let padding = N::number_padding(nibble_count);
+	if padding > 0 && N::pad_left(padding, data[offset]) != 0 {
+		return Err(CodecError::from("Bad format"));
+	}
+	Ok(())
+}
+
+/// Structural checks of the reference codecs for `NodeCodec::check_layout`, the header
+/// encodings of both layouts overlapping.
+fn check_plan_layout<N: NibbleOps>(
+	plan: &NodePlan<N>,
+	use_extension: bool,
+) -> Result<(), CodecError> {
+	let (has_value, children) = match plan {
+		NodePlan::NibbledBranch { .. } if use_extension =>
+			return Err("nibbled branch in a layout with extension nodes".into()),
+		NodePlan::Extension { .. } | NodePlan::Branch { .. } if !use_extension =>
+			return Err("extension or branch without partial key in a layout without extension nodes".into()),
+		NodePlan::Extension { partial, .. } => return if partial.len() == 0 {
+			Err("extension with empty partial key".into())
+		} else {
+			Ok(())
+		},
+		NodePlan::Branch { value, children }
+		| NodePlan::NibbledBranch { value, children, .. } => (value.is_some(), children),
+		NodePlan::Empty | NodePlan::Leaf { .. } => return Ok(()),
+	};
+	let child_count = children.iter().filter(|child| child.is_some()).count();
+	if child_count == 0 || (child_count == 1 && !has_value) {
+		return Err("branch with a single path, which would be a leaf or be merged".into());
+	}
+	Ok(())
 }
 
 struct ByteSliceInput<'a> {
@@ -581,6 +755,14 @@ impl<'a> ByteSliceInput<'a> {
 		self.offset += count;
 		Ok(range)
 	}
+
+	/// Check that the whole input was consumed.
+	fn finish(self) -> Result<(), CodecError> {
+		if self.offset != self.data.len() {
+			return Err("trailing bytes".into());
+		}
+		Ok(())
+	}
 }
 
 impl<'a> Input for ByteSliceInput<'a> {
@@ -618,15 +800,16 @@ impl<'a> Input for ByteSliceInput<'a> {
 impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 	type Error = CodecError;
 	type HashOut = H::Out;
+	type Nibble = Radix16;
 
 	fn hashed_null_node() -> <H as Hasher>::Out {
 		H::hash(<Self as NodeCodec>::empty_node())
 	}
 
-	fn decode_plan(data: &[u8]) -> ::std::result::Result<NodePlan, Self::Error> {
+	fn decode_plan(data: &[u8]) -> rstd::result::Result<NodePlan, Self::Error> {
 		let mut input = ByteSliceInput::new(data);
-		match NodeHeader::decode(&mut input)? {
-			NodeHeader::Null => Ok(NodePlan::Empty),
+		let plan = match NodeHeader::decode(&mut input)? {
+			NodeHeader::Null => NodePlan::Empty,
 			NodeHeader::Branch(has_value) => {
 				let bitmap_range = input.take(BITMAP_LENGTH)?;
 				let bitmap = Bitmap::decode(&data[bitmap_range])?;
@@ -637,10 +820,7 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 				} else {
 					None
 				};
-				let mut children = [
-					None, None, None, None, None, None, None, None,
-					None, None, None, None, None, None, None, None,
-				];
+				let mut children = Children::new();
 				for i in 0..nibble_ops::NIBBLE_LENGTH {
 					if bitmap.value_at(i) {
 						let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
@@ -652,7 +832,7 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 						});
 					}
 				}
-				Ok(NodePlan::Branch { value, children })
+				NodePlan::Branch { value, children }
 			}
 			NodeHeader::Extension(nibble_count) => {
 				let partial = input.take(
@@ -666,10 +846,10 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 				} else {
 					NodeHandlePlan::Inline(range)
 				};
-				Ok(NodePlan::Extension {
+				NodePlan::Extension {
 					partial: NibbleSlicePlan::new(partial, partial_padding),
 					child
-				})
+				}
 			}
 			NodeHeader::Leaf(nibble_count) => {
 				let partial = input.take(
@@ -678,12 +858,18 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 				let partial_padding = nibble_ops::number_padding(nibble_count);
 				let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
 				let value = input.take(count)?;
-				Ok(NodePlan::Leaf {
+				NodePlan::Leaf {
 					partial: NibbleSlicePlan::new(partial, partial_padding),
 					value,
-				})
+				}
 			}
-		}
+		};
+		input.finish()?;
+		Ok(plan)
+	}
+
+	fn check_layout(plan: &NodePlan, use_extension: bool) -> rstd::result::Result<(), Self::Error> {
+		check_plan_layout(plan, use_extension)
 	}
 
 	fn is_empty_node(data: &[u8]) -> bool {
@@ -695,8 +881,8 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 	}
 
 	fn leaf_node(partial: Partial, value: &[u8]) -> Vec<u8> {
-		let mut output = partial_to_key(partial, LEAF_NODE_OFFSET, LEAF_NODE_OVER);
-		value.encode_to(&mut output);
+		let mut output = Vec::new();
+		Self::leaf_node_into(partial, value, &mut output);
 		output
 	}
 
@@ -705,54 +891,70 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 		number_nibble: usize,
 		child: ChildReference<Self::HashOut>,
 	) -> Vec<u8> {
-		let mut output = partial_from_iterator_to_key(
+		let mut output = Vec::new();
+		Self::extension_node_into(partial, number_nibble, child, &mut output);
+		output
+	}
+
+	fn branch_node(
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<&[u8]>,
+	) -> Vec<u8> {
+		let mut output = Vec::new();
+		Self::branch_node_into(children, maybe_value, &mut output);
+		output
+	}
+
+	fn branch_node_nibbled(
+		_partial:	impl Iterator<Item = u8>,
+		_number_nibble: usize,
+		_children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		_maybe_value: Option<&[u8]>) -> Vec<u8> {
+		unreachable!()
+	}
+
+	fn leaf_node_into(partial: Partial, value: &[u8], output: &mut Vec<u8>) {
+		partial_to_key_into(partial, LEAF_NODE_OFFSET, LEAF_NODE_OVER, output);
+		value.encode_to(output);
+	}
+
+	fn extension_node_into(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		child: ChildReference<Self::HashOut>,
+		output: &mut Vec<u8>,
+	) {
+		partial_from_iterator_to_key_into(
 			partial,
 			number_nibble,
 			EXTENSION_NODE_OFFSET,
 			EXTENSION_NODE_OVER,
+			output,
 		);
 		match child {
-			ChildReference::Hash(h) => h.as_ref().encode_to(&mut output),
+			ChildReference::Hash(h) => h.as_ref().encode_to(output),
 			ChildReference::Inline(inline_data, len) =>
-				(&AsRef::<[u8]>::as_ref(&inline_data)[..len]).encode_to(&mut output),
+				(&AsRef::<[u8]>::as_ref(&inline_data)[..len]).encode_to(output),
 		};
-		output
 	}
 
-	fn branch_node(
+	fn branch_node_into(
 		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
 		maybe_value: Option<&[u8]>,
-	) -> Vec<u8> {
-		let mut output = vec![0; BITMAP_LENGTH + 1];
+		output: &mut Vec<u8>,
+	) {
+		let start = output.len();
+		output.resize(start + BITMAP_LENGTH + 1, 0);
 		let mut prefix: [u8; 3] = [0; 3];
 		let have_value = if let Some(value) = maybe_value {
-			value.encode_to(&mut output);
+			value.encode_to(output);
 			true
 		} else {
 			false
 		};
-		let has_children = children.map(|maybe_child| match maybe_child.borrow() {
-			Some(ChildReference::Hash(h)) => {
-				h.as_ref().encode_to(&mut output);
-				true
-			}
-			&Some(ChildReference::Inline(inline_data, len)) => {
-				inline_data.as_ref()[..len].encode_to(&mut output);
-				true
-			}
-			None => false,
-		});
-		branch_node_buffered(have_value, has_children, prefix.as_mut());
-		output[0..BITMAP_LENGTH + 1].copy_from_slice(prefix.as_ref());
-		output
-	}
-
-	fn branch_node_nibbled(
-		_partial:	impl Iterator<Item = u8>,
-		_number_nibble: usize,
-		_children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
-		_maybe_value: Option<&[u8]>) -> Vec<u8> {
-		unreachable!()
+		let has_children = encode_children_into(children, output);
+		branch_node_buffered(have_value, has_children.iter().cloned(), prefix.as_mut());
+		output[start..start + BITMAP_LENGTH + 1].copy_from_slice(prefix.as_ref());
 	}
 
 }
@@ -760,21 +962,18 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 impl<H: Hasher> NodeCodec for ReferenceNodeCodecNoExt<H> {
 	type Error = CodecError;
 	type HashOut = <H as Hasher>::Out;
+	type Nibble = Radix16;
 
 	fn hashed_null_node() -> <H as Hasher>::Out {
 		H::hash(<Self as NodeCodec>::empty_node())
 	}
 
-	fn decode_plan(data: &[u8]) -> ::std::result::Result<NodePlan, Self::Error> {
+	fn decode_plan(data: &[u8]) -> rstd::result::Result<NodePlan, Self::Error> {
 		let mut input = ByteSliceInput::new(data);
-		match NodeHeaderNoExt::decode(&mut input)? {
-			NodeHeaderNoExt::Null => Ok(NodePlan::Empty),
+		let plan = match NodeHeaderNoExt::decode(&mut input)? {
+			NodeHeaderNoExt::Null => NodePlan::Empty,
 			NodeHeaderNoExt::Branch(has_value, nibble_count) => {
-				let padding = nibble_count % nibble_ops::NIBBLE_PER_BYTE != 0;
-				// check that the padding is valid (if any)
-				if padding && nibble_ops::pad_left(data[input.offset]) != 0 {
-					return Err(CodecError::from("Bad format"));
-				}
+				check_partial_padding::<Radix16>(data, input.offset, nibble_count)?;
 				let partial = input.take(
 					(nibble_count + (nibble_ops::NIBBLE_PER_BYTE - 1)) / nibble_ops::NIBBLE_PER_BYTE
 				)?;
@@ -787,10 +986,7 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodecNoExt<H> {
 				} else {
 					None
 				};
-				let mut children = [
-					None, None, None, None, None, None, None, None,
-					None, None, None, None, None, None, None, None,
-				];
+				let mut children = Children::new();
 				for i in 0..nibble_ops::NIBBLE_LENGTH {
 					if bitmap.value_at(i) {
 						let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
@@ -802,30 +998,32 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodecNoExt<H> {
 						});
 					}
 				}
-				Ok(NodePlan::NibbledBranch {
+				NodePlan::NibbledBranch {
 					partial: NibbleSlicePlan::new(partial, partial_padding),
 					value,
 					children,
-				})
+				}
 			}
 			NodeHeaderNoExt::Leaf(nibble_count) => {
-				let padding = nibble_count % nibble_ops::NIBBLE_PER_BYTE != 0;
-				// check that the padding is valid (if any)
-				if padding && nibble_ops::pad_left(data[input.offset]) != 0 {
-					return Err(CodecError::from("Bad format"));
-				}
+				check_partial_padding::<Radix16>(data, input.offset, nibble_count)?;
 				let partial = input.take(
 					(nibble_count + (nibble_ops::NIBBLE_PER_BYTE - 1)) / nibble_ops::NIBBLE_PER_BYTE
 				)?;
 				let partial_padding = nibble_ops::number_padding(nibble_count);
 				let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
 				let value = input.take(count)?;
-				Ok(NodePlan::Leaf {
+				NodePlan::Leaf {
 					partial: NibbleSlicePlan::new(partial, partial_padding),
 					value,
-				})
+				}
 			}
-		}
+		};
+		input.finish()?;
+		Ok(plan)
+	}
+
+	fn check_layout(plan: &NodePlan, use_extension: bool) -> rstd::result::Result<(), Self::Error> {
+		check_plan_layout(plan, use_extension)
 	}
 
 	fn is_empty_node(data: &[u8]) -> bool {
@@ -837,8 +1035,8 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodecNoExt<H> {
 	}
 
 	fn leaf_node(partial: Partial, value: &[u8]) -> Vec<u8> {
-		let mut output = partial_encode(partial, NodeKindNoExt::Leaf);
-		value.encode_to(&mut output);
+		let mut output = Vec::new();
+		Self::leaf_node_into(partial, value, &mut output);
 		output
 	}
 
@@ -863,44 +1061,326 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodecNoExt<H> {
 		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
 		maybe_value: Option<&[u8]>,
 	) -> Vec<u8> {
-		let mut output = if maybe_value.is_some() {
-			partial_from_iterator_encode(
-				partial,
-				number_nibble,
-				NodeKindNoExt::BranchWithValue,
-			)
+		let mut output = Vec::new();
+		Self::branch_node_nibbled_into(partial, number_nibble, children, maybe_value, &mut output);
+		output
+	}
+
+	fn leaf_node_into(partial: Partial, value: &[u8], output: &mut Vec<u8>) {
+		partial_encode_into::<Radix16>(partial, NodeKindNoExt::Leaf, output);
+		value.encode_to(output);
+	}
+
+	fn branch_node_nibbled_into(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<&[u8]>,
+		output: &mut Vec<u8>,
+	) {
+		let node_kind = if maybe_value.is_some() {
+			NodeKindNoExt::BranchWithValue
 		} else {
-			partial_from_iterator_encode(
-				partial,
-				number_nibble,
-				NodeKindNoExt::BranchNoValue,
-			)
+			NodeKindNoExt::BranchNoValue
 		};
+		partial_from_iterator_encode_into(partial, number_nibble, node_kind, output);
 		let bitmap_index = output.len();
 		let mut bitmap: [u8; BITMAP_LENGTH] = [0; BITMAP_LENGTH];
-		(0..BITMAP_LENGTH).for_each(|_| output.push(0));
+		output.resize(bitmap_index + BITMAP_LENGTH, 0);
 		if let Some(value) = maybe_value {
-			value.encode_to(&mut output);
+			value.encode_to(output);
 		};
-		Bitmap::encode(children.map(|maybe_child| match maybe_child.borrow() {
-			Some(ChildReference::Hash(h)) => {
-				h.as_ref().encode_to(&mut output);
-				true
-			}
-			&Some(ChildReference::Inline(inline_data, len)) => {
-				inline_data.as_ref()[..len].encode_to(&mut output);
-				true
-			}
-			None => false,
-		}), bitmap.as_mut());
+		let has_children = encode_children_into(children, output);
+		Bitmap::encode(has_children.iter().cloned(), bitmap.as_mut());
 		output[bitmap_index..bitmap_index + BITMAP_LENGTH]
 			.copy_from_slice(&bitmap.as_ref()[..BITMAP_LENGTH]);
+	}
+
+}
+
+#[cfg(feature = "std-helpers")]
+thread_local! {
+	static DECODE_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Trie layout without extension nodes, counting the nodes decoded by its codec
+/// in `decode_count`.
+#[cfg(feature = "std-helpers")]
+pub struct CountingLayout;
+
+#[cfg(feature = "std-helpers")]
+impl TrieLayout for CountingLayout {
+	const USE_EXTENSION: bool = false;
+	type Hash = KeccakHasher;
+	type Codec = CountingCodec;
+	type Nibble = Radix16;
+}
+
+/// Node codec of `CountingLayout`.
+#[cfg(feature = "std-helpers")]
+pub struct CountingCodec;
+
+#[cfg(feature = "std-helpers")]
+type InnerCodec = ReferenceNodeCodecNoExt<KeccakHasher>;
+
+#[cfg(feature = "std-helpers")]
+impl NodeCodec for CountingCodec {
+	type Error = <InnerCodec as NodeCodec>::Error;
+	type HashOut = <InnerCodec as NodeCodec>::HashOut;
+	type Nibble = Radix16;
+
+	fn hashed_null_node() -> Self::HashOut {
+		InnerCodec::hashed_null_node()
+	}
+
+	fn decode_plan(data: &[u8]) -> rstd::result::Result<NodePlan, Self::Error> {
+		DECODE_COUNT.with(|count| count.set(count.get() + 1));
+		InnerCodec::decode_plan(data)
+	}
+
+	fn check_layout(plan: &NodePlan, use_extension: bool) -> rstd::result::Result<(), Self::Error> {
+		InnerCodec::check_layout(plan, use_extension)
+	}
+
+	fn is_empty_node(data: &[u8]) -> bool {
+		InnerCodec::is_empty_node(data)
+	}
+
+	fn empty_node() -> &'static [u8] {
+		InnerCodec::empty_node()
+	}
+
+	fn leaf_node(partial: Partial, value: &[u8]) -> Vec<u8> {
+		InnerCodec::leaf_node(partial, value)
+	}
+
+	fn extension_node(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		child_ref: ChildReference<Self::HashOut>,
+	) -> Vec<u8> {
+		InnerCodec::extension_node(partial, number_nibble, child_ref)
+	}
+
+	fn branch_node(
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		value: Option<&[u8]>,
+	) -> Vec<u8> {
+		InnerCodec::branch_node(children, value)
+	}
+
+	fn branch_node_nibbled(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		value: Option<&[u8]>,
+	) -> Vec<u8> {
+		InnerCodec::branch_node_nibbled(partial, number_nibble, children, value)
+	}
+
+	fn leaf_node_into(partial: Partial, value: &[u8], output: &mut Vec<u8>) {
+		InnerCodec::leaf_node_into(partial, value, output)
+	}
+
+	fn extension_node_into(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		child_ref: ChildReference<Self::HashOut>,
+		output: &mut Vec<u8>,
+	) {
+		InnerCodec::extension_node_into(partial, number_nibble, child_ref, output)
+	}
+
+	fn branch_node_into(
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		value: Option<&[u8]>,
+		output: &mut Vec<u8>,
+	) {
+		InnerCodec::branch_node_into(children, value, output)
+	}
+
+	fn branch_node_nibbled_into(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		value: Option<&[u8]>,
+		output: &mut Vec<u8>,
+	) {
+		InnerCodec::branch_node_nibbled_into(partial, number_nibble, children, value, output)
+	}
+}
+
+/// Number of nodes decoded by `CountingCodec` on the current thread.
+#[cfg(feature = "std-helpers")]
+pub fn decode_count() -> usize {
+	DECODE_COUNT.with(|count| count.get())
+}
+
+/// Trie layout without extension nodes with 1-bit nibbles: the branches of its tries have two
+/// children and its proofs hold one sibling per level.
+pub struct GenericBinaryLayout<H>(PhantomData<H>);
+
+impl<H: Hasher> TrieLayout for GenericBinaryLayout<H> {
+	const USE_EXTENSION: bool = false;
+	type Hash = H;
+	type Codec = ReferenceBinaryNodeCodec<H>;
+	type Nibble = Radix2;
+}
+
+impl<H: Hasher> TrieConfiguration for GenericBinaryLayout<H> { }
+
+/// Binary trie layout without extension nodes.
+pub type BinaryLayout = GenericBinaryLayout<KeccakHasher>;
+
+/// Size of the children bitmap of `ReferenceBinaryNodeCodec`, a bit for each child.
+const BINARY_BITMAP_LENGTH: usize = 1;
+
+/// Node codec of `GenericBinaryLayout`: the headers of `ReferenceNodeCodecNoExt`, followed by
+/// the partial key packed 8 nibbles to a byte, and for branches by a single byte bitmap of
+/// their two children.
+#[derive(Default, Clone)]
+pub struct ReferenceBinaryNodeCodec<H>(PhantomData<H>);
+
+impl<H: Hasher> NodeCodec for ReferenceBinaryNodeCodec<H> {
+	type Error = CodecError;
+	type HashOut = <H as Hasher>::Out;
+	type Nibble = Radix2;
+
+	fn hashed_null_node() -> <H as Hasher>::Out {
+		H::hash(<Self as NodeCodec>::empty_node())
+	}
+
+	fn decode_plan(data: &[u8]) -> rstd::result::Result<NodePlan<Radix2>, Self::Error> {
+		let mut input = ByteSliceInput::new(data);
+		let plan = match NodeHeaderNoExt::decode(&mut input)? {
+			NodeHeaderNoExt::Null => NodePlan::Empty,
+			NodeHeaderNoExt::Branch(has_value, nibble_count) => {
+				check_partial_padding::<Radix2>(data, input.offset, nibble_count)?;
+				let partial = input.take(nibble_count.div_ceil(Radix2::NIBBLE_PER_BYTE))?;
+				let partial_padding = Radix2::number_padding(nibble_count);
+				let bitmap = data[input.take(BINARY_BITMAP_LENGTH)?][0];
+				if bitmap >> Radix2::NIBBLE_LENGTH != 0 {
+					return Err("child of a binary branch at an index over 1".into());
+				}
+				let value = if has_value {
+					let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+					Some(input.take(count)?)
+				} else {
+					None
+				};
+				let mut children = Children::new();
+				for (i, child) in children.iter_mut().enumerate() {
+					if bitmap & (1 << i) != 0 {
+						let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+						let range = input.take(count)?;
+						*child = Some(if count == H::LENGTH {
+							NodeHandlePlan::Hash(range)
+						} else {
+							NodeHandlePlan::Inline(range)
+						});
+					}
+				}
+				NodePlan::NibbledBranch {
+					partial: NibbleSlicePlan::new(partial, partial_padding),
+					value,
+					children,
+				}
+			}
+			NodeHeaderNoExt::Leaf(nibble_count) => {
+				check_partial_padding::<Radix2>(data, input.offset, nibble_count)?;
+				let partial = input.take(nibble_count.div_ceil(Radix2::NIBBLE_PER_BYTE))?;
+				let partial_padding = Radix2::number_padding(nibble_count);
+				let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+				let value = input.take(count)?;
+				NodePlan::Leaf {
+					partial: NibbleSlicePlan::new(partial, partial_padding),
+					value,
+				}
+			}
+		};
+		input.finish()?;
+		Ok(plan)
+	}
+
+	fn check_layout(
+		plan: &NodePlan<Radix2>,
+		use_extension: bool,
+	) -> rstd::result::Result<(), Self::Error> {
+		check_plan_layout(plan, use_extension)
+	}
+
+	fn is_empty_node(data: &[u8]) -> bool {
+		data == <Self as NodeCodec>::empty_node()
+	}
+
+	fn empty_node() -> &'static [u8] {
+		&[EMPTY_TRIE_NO_EXT]
+	}
+
+	fn leaf_node(partial: Partial, value: &[u8]) -> Vec<u8> {
+		let mut output = Vec::new();
+		Self::leaf_node_into(partial, value, &mut output);
 		output
 	}
 
+	fn extension_node(
+		_partial: impl Iterator<Item = u8>,
+		_nbnibble: usize,
+		_child: ChildReference<<H as Hasher>::Out>,
+	) -> Vec<u8> {
+		unreachable!()
+	}
+
+	fn branch_node(
+		_children: impl Iterator<Item = impl Borrow<Option<ChildReference<<H as Hasher>::Out>>>>,
+		_maybe_value: Option<&[u8]>,
+	) -> Vec<u8> {
+		unreachable!()
+	}
+
+	fn branch_node_nibbled(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<&[u8]>,
+	) -> Vec<u8> {
+		let mut output = Vec::new();
+		Self::branch_node_nibbled_into(partial, number_nibble, children, maybe_value, &mut output);
+		output
+	}
+
+	fn leaf_node_into(partial: Partial, value: &[u8], output: &mut Vec<u8>) {
+		partial_encode_into::<Radix2>(partial, NodeKindNoExt::Leaf, output);
+		value.encode_to(output);
+	}
+
+	fn branch_node_nibbled_into(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<&[u8]>,
+		output: &mut Vec<u8>,
+	) {
+		let node_kind = if maybe_value.is_some() {
+			NodeKindNoExt::BranchWithValue
+		} else {
+			NodeKindNoExt::BranchNoValue
+		};
+		partial_from_iterator_encode_into(partial, number_nibble, node_kind, output);
+		let bitmap_index = output.len();
+		output.push(0);
+		if let Some(value) = maybe_value {
+			value.encode_to(output);
+		};
+		let has_children = encode_children_into(children.take(Radix2::NIBBLE_LENGTH), output);
+		output[bitmap_index] = has_children[..Radix2::NIBBLE_LENGTH].iter()
+			.enumerate()
+			.fold(0, |bitmap, (i, has_child)| bitmap | (*has_child as u8) << i);
+	}
 }
 
 /// Compare trie builder and in memory trie.
+#[cfg(feature = "std-helpers")]
 pub fn compare_implementations<X : hash_db::HashDB<KeccakHasher, DBValue> + Eq> (
 	data: Vec<(Vec<u8>, Vec<u8>)>,
 	mut memdb: X,
@@ -945,6 +1425,7 @@ pub fn compare_implementations<X : hash_db::HashDB<KeccakHasher, DBValue> + Eq>
 }
 
 /// Compare trie builder and trie root implementations.
+#[cfg(feature = "std-helpers")]
 pub fn compare_root(
 	data: Vec<(Vec<u8>, Vec<u8>)>,
 	mut memdb: impl hash_db::HashDB<KeccakHasher, DBValue>,
@@ -967,6 +1448,7 @@ pub fn compare_root(
 }
 
 /// Compare trie builder and trie root unhashed implementations.
+#[cfg(feature = "std-helpers")]
 pub fn compare_unhashed(
 	data: Vec<(Vec<u8>, Vec<u8>)>,
 ) {
@@ -982,6 +1464,7 @@ pub fn compare_unhashed(
 
 /// Compare trie builder and trie root unhashed implementations.
 /// This uses the variant without extension nodes.
+#[cfg(feature = "std-helpers")]
 pub fn compare_unhashed_no_extension(
 	data: Vec<(Vec<u8>, Vec<u8>)>,
 ) {
@@ -1059,6 +1542,7 @@ pub fn calc_root_build_no_extension<I, A, B, DB>(
 
 /// Compare trie builder and in memory trie.
 /// This uses the variant without extension nodes.
+#[cfg(feature = "std-helpers")]
 pub fn compare_implementations_no_extension(
 	data: Vec<(Vec<u8>, Vec<u8>)>,
 	mut memdb: impl hash_db::HashDB<KeccakHasher, DBValue>,
@@ -1102,6 +1586,7 @@ pub fn compare_implementations_no_extension(
 
 /// `compare_implementations_no_extension` for unordered input (trie_root does
 /// ordering before running when trie_build expect correct ordering).
+#[cfg(feature = "std-helpers")]
 pub fn compare_implementations_no_extension_unordered(
 	data: Vec<(Vec<u8>, Vec<u8>)>,
 	mut memdb: impl hash_db::HashDB<KeccakHasher, DBValue>,
@@ -1147,6 +1632,7 @@ pub fn compare_implementations_no_extension_unordered(
 
 /// Testing utility that uses some periodic removal over
 /// its input test data.
+#[cfg(feature = "std-helpers")]
 pub fn compare_no_extension_insert_remove(
 	data: Vec<(bool, Vec<u8>, Vec<u8>)>,
 	mut memdb: impl hash_db::HashDB<KeccakHasher, DBValue>,
@@ -1218,12 +1704,136 @@ mod tests {
 		let input = vec![0u8; (NIBBLE_SIZE_BOUND_NO_EXT as usize + 1) / 2 + 1];
 		let enc = <ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>
 		::leaf_node(((0, 0), &input), &[1]);
+		// The encoded nibble count is capped to the bound, leaving
+		// unread trailing bytes.
 		let dec = <ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>
-		::decode(&enc).unwrap();
-		let o_sl = if let Node::Leaf(sl, _) = dec {
-			Some(sl)
-		} else { None };
-		assert!(o_sl.is_some());
+		::decode(&enc);
+		assert_eq!(dec.err(), Some("trailing bytes".into()));
+	}
+
+	#[test]
+	fn trailing_bytes_are_rejected() {
+		let leaf = <ReferenceNodeCodec<KeccakHasher> as NodeCodec>
+		::leaf_node(((0, 0), &[0x12]), &[1, 2, 3]);
+		let leaf_no_ext = <ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>
+		::leaf_node(((0, 0), &[0x12]), &[1, 2, 3]);
+		assert!(<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::decode(&leaf).is_ok());
+		assert!(<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::decode(&leaf_no_ext).is_ok());
+
+		for padding in 1..4 {
+			let mut padded = leaf.clone();
+			padded.resize(leaf.len() + padding, 0);
+			assert!(<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::decode(&padded).is_err());
+			let mut padded = leaf_no_ext.clone();
+			padded.resize(leaf_no_ext.len() + padding, 0);
+			assert!(<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::decode(&padded).is_err());
+		}
+
+		let empty = [EMPTY_TRIE, 0];
+		assert!(<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::decode(&empty).is_err());
+		let empty_no_ext = [EMPTY_TRIE_NO_EXT, 0];
+		assert!(<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::decode(&empty_no_ext).is_err());
+	}
+
+	/// Truncated nodes found by fuzzing, they used to panic on decoding
+	/// or when building the decoded node.
+	const TRUNCATED_NODES_NO_EXT: &[&[u8]] = &[
+		// leaf with odd partial and no data
+		&[0x41],
+		// branches with odd partial and no data
+		&[0x81],
+		&[0xc1],
+		// leaf with a long partial using additional size bytes
+		&[0x7f, 0x05],
+		&[0x7f, 0x05, 0x01],
+		// branch with partial and missing bitmap
+		&[0x82, 0x12],
+		// leaf with missing value
+		&[0x42, 0x12],
+		&[0x42, 0x12, 0x0c, 0x01],
+	];
+
+	const TRUNCATED_NODES: &[&[u8]] = &[
+		// leaf with odd partial and no data
+		&[0x02],
+		&[0x03, 0x12],
+		// extension with missing child
+		&[0x82, 0x12],
+		&[0x82, 0x12, 0x80],
+		// branches with missing bitmap or children
+		&[0xfe, 0x01],
+		&[0xfe, 0x01, 0x00],
+		&[0xff, 0x00, 0x00, 0x04],
+	];
+
+	#[test]
+	fn truncated_nodes_regression() {
+		for data in TRUNCATED_NODES_NO_EXT {
+			assert!(
+				<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::decode(data).is_err(),
+				"{:x?} should not decode",
+				data,
+			);
+		}
+		for data in TRUNCATED_NODES {
+			assert!(
+				<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::decode(data).is_err(),
+				"{:x?} should not decode",
+				data,
+			);
+		}
+	}
+
+	fn check_truncations<C: NodeCodec>(encoded: &[u8]) {
+		for len in 0..encoded.len() {
+			// decoding may succeed on a shorter node, but building the
+			// node must then stay within the truncated data.
+			if let Ok(node) = C::decode(&encoded[..len]) {
+				match node {
+					Node::Leaf(partial, _)
+					| Node::Extension(partial, _)
+					| Node::NibbledBranch(partial, _, _) => {
+						assert_eq!(partial.iter().count(), partial.len());
+					},
+					Node::Branch(..) | Node::Empty => (),
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn truncated_trie_nodes_do_not_panic() {
+		let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
+			(vec![0x01], vec![0x01; 40]),
+			(vec![0x01, 0x23], vec![0x02]),
+			(vec![0x01, 0x23, 0x45], vec![0x03; 33]),
+			(vec![0x12], vec![0x04]),
+			(vec![0xf1, 0x01, 0x02, 0x03, 0x04], vec![]),
+		];
+		for (key, value) in &pairs {
+			let partial = NibbleSlice::<Radix16>::new(key);
+			check_truncations::<ReferenceNodeCodec<KeccakHasher>>(
+				&<ReferenceNodeCodec<KeccakHasher> as NodeCodec>::leaf_node(partial.right(), value),
+			);
+			check_truncations::<ReferenceNodeCodecNoExt<KeccakHasher>>(
+				&<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::leaf_node(partial.right(), value),
+			);
+			let partial = partial.mid(1);
+			check_truncations::<ReferenceNodeCodecNoExt<KeccakHasher>>(
+				&<ReferenceNodeCodecNoExt<KeccakHasher> as NodeCodec>::leaf_node(partial.right(), value),
+			);
+		}
+
+		let mut memdb = memory_db::MemoryDB::<KeccakHasher, memory_db::HashKey<_>, DBValue>::default();
+		calc_root_build(pairs.clone(), &mut memdb);
+		for (_, (encoded, _)) in memdb.drain() {
+			check_truncations::<ReferenceNodeCodec<KeccakHasher>>(&encoded);
+		}
+		let mut memdb = memory_db::MemoryDB::<KeccakHasher, memory_db::HashKey<_>, DBValue>::default();
+		calc_root_build_no_extension(pairs, &mut memdb);
+		for (_, (encoded, _)) in memdb.drain() {
+			check_truncations::<ReferenceNodeCodecNoExt<KeccakHasher>>(&encoded);
+		}
 	}
 
 	#[test]
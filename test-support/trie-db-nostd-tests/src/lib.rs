@@ -0,0 +1,162 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Uses of the core APIs of the trie crates without the standard library.
+//!
+//! The tests of this crate build it alone, natively and for `wasm32-unknown-unknown`, so that
+//! the dependencies are built without their `std` feature: an API used here which needs the
+//! standard library breaks these builds. The checks are run by the `check-all` binary of
+//! the native build.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{format, vec, vec::Vec};
+use core::fmt::Debug;
+use hash_db::{Hasher, EMPTY_PREFIX};
+use keccak_hasher::KeccakHasher;
+use memory_db::{HashKey, MemoryDB};
+use reference_trie::{ExtensionLayout, NoExtensionLayout};
+use trie_db::{
+	node::Node, proof::{generate_proof, verify_proof, VerifyError}, CError, DBValue, HashDB, Trie,
+	TrieDB, TrieDBMut, TrieDBNodeIterator, TrieError, TrieHash, TrieLayout, TrieMut,
+};
+
+/// Database of the checked tries.
+pub type CheckDB = MemoryDB<KeccakHasher, HashKey<KeccakHasher>, DBValue>;
+
+/// Key-value pairs of the checked tries, in key order, with keys prefix of other keys and
+/// values stored both inline and by hash.
+pub fn pairs() -> Vec<(Vec<u8>, Vec<u8>)> {
+	let mut pairs: Vec<_> = (0..64u8).map(|i| (vec![i / 8, i], vec![i; 1 + i as usize])).collect();
+	pairs.push((vec![3], vec![0xaa; 40]));
+	pairs.sort();
+	pairs
+}
+
+/// Build the trie of `pairs`.
+pub fn build<L: TrieLayout<Hash = KeccakHasher>>() -> (CheckDB, TrieHash<L>)
+	where CError<L>: Debug
+{
+	let mut db = CheckDB::default();
+	let mut root = Default::default();
+	{
+		let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+		for (key, value) in pairs() {
+			t.insert(&key, &value).unwrap();
+		}
+	}
+	(db, root)
+}
+
+/// Read the values of the trie, and of absent keys.
+pub fn check_get<L: TrieLayout<Hash = KeccakHasher>>()
+	where CError<L>: Debug
+{
+	let (db, root) = build::<L>();
+	let t = TrieDB::<L>::new(&db, &root).unwrap();
+	for (key, value) in pairs() {
+		assert_eq!(t.get(&key).unwrap(), Some(value));
+	}
+	assert_eq!(t.get(&[3, 64]).unwrap(), None);
+	assert_eq!(t.get(&[]).unwrap(), None);
+	assert_eq!(t.iter().unwrap().map(|item| item.unwrap()).collect::<Vec<_>>(), pairs());
+}
+
+/// Iterate over the nodes of the trie, checking the hashes of the nodes stored by hash.
+pub fn check_node_iterator<L: TrieLayout<Hash = KeccakHasher>>()
+	where CError<L>: Debug
+{
+	let (db, root) = build::<L>();
+	let t = TrieDB::<L>::new(&db, &root).unwrap();
+	let mut values = 0;
+	for item in TrieDBNodeIterator::new(&t).unwrap() {
+		let (_, hash, node) = item.unwrap();
+		if let Some(hash) = hash {
+			assert_eq!(KeccakHasher::hash(node.data()), hash);
+		}
+		match node.node() {
+			Node::Leaf(..) | Node::Branch(_, Some(_)) | Node::NibbledBranch(_, _, Some(_)) =>
+				values += 1,
+			_ => (),
+		}
+	}
+	assert_eq!(values, pairs().len());
+}
+
+/// Seek the iterator of the trie, to present and absent keys.
+pub fn check_seek<L: TrieLayout<Hash = KeccakHasher>>()
+	where CError<L>: Debug
+{
+	let (db, root) = build::<L>();
+	let t = TrieDB::<L>::new(&db, &root).unwrap();
+	let pairs = pairs();
+	let mut iter = t.iter().unwrap();
+	iter.seek(&[3]).unwrap();
+	let position = pairs.iter().position(|(key, _)| key == &[3]).unwrap();
+	assert_eq!(iter.next().unwrap().unwrap(), pairs[position]);
+	assert_eq!(iter.next().unwrap().unwrap(), pairs[position + 1]);
+
+	iter.seek_after(&[3]).unwrap();
+	assert_eq!(iter.next().unwrap().unwrap(), pairs[position + 1]);
+	iter.seek(&[7, 63, 0]).unwrap();
+	assert!(iter.next().is_none());
+}
+
+/// Generate and verify a proof of present and absent keys, and check the errors of a wrong
+/// proof and of a missing node.
+pub fn check_proof<L: TrieLayout<Hash = KeccakHasher>>()
+	where CError<L>: Debug
+{
+	let (mut db, root) = build::<L>();
+	let items = [
+		(vec![3], Some(vec![0xaa; 40])),
+		(vec![5, 42], Some(vec![42; 43])),
+		(vec![5, 64], None),
+	];
+	let proof = {
+		let t = TrieDB::<L>::new(&db, &root).unwrap();
+		let keys: Vec<_> = items.iter().map(|(key, _)| key).collect();
+		generate_proof::<_, L, _, _>(&t, keys).unwrap()
+	};
+	verify_proof::<L, _, _, _>(&root, &proof, items.iter()).unwrap();
+
+	let mut wrong = items.clone();
+	wrong[1].1 = Some(vec![41; 42]);
+	match verify_proof::<L, _, _, _>(&root, &proof, wrong.iter()) {
+		Err(error @ VerifyError::ValueMismatch(_)) | Err(error @ VerifyError::RootMismatch(_)) =>
+			assert!(!format!("{:?}", error).is_empty()),
+		result => panic!("unexpected verification result: {:?}", result),
+	}
+
+	HashDB::remove(&mut db, &root, EMPTY_PREFIX);
+	match TrieDB::<L>::new(&db, &root).map_err(|error| *error) {
+		Err(error @ TrieError::InvalidStateRoot(_)) => assert!(!format!("{}", error).is_empty()),
+		Err(error) => panic!("unexpected error: {}", error),
+		Ok(_) => panic!("the root node was removed"),
+	}
+}
+
+/// Run all the checks, with both reference layouts.
+pub fn check_all() {
+	check_get::<ExtensionLayout>();
+	check_get::<NoExtensionLayout>();
+	check_node_iterator::<ExtensionLayout>();
+	check_node_iterator::<NoExtensionLayout>();
+	check_seek::<ExtensionLayout>();
+	check_seek::<NoExtensionLayout>();
+	check_proof::<ExtensionLayout>();
+	check_proof::<NoExtensionLayout>();
+}
@@ -0,0 +1,69 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Builds of the crate without the standard library, and runs of its checks.
+//!
+//! Within the workspace, the dependencies get the `std` feature enabled by the other members:
+//! the crate is built again alone, in its own target directory, and its checks are run by the
+//! `check-all` binary of the build. The wasm build is ignored unless the target is installed
+//! and the ignored tests are run.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const WASM_TARGET: &str = "wasm32-unknown-unknown";
+
+fn target_dir() -> PathBuf {
+	Path::new(env!("CARGO_MANIFEST_DIR")).join("../../target/nostd")
+}
+
+/// Run `cargo command` on this crate alone, with the extra arguments `args`.
+fn cargo_alone(command: &str, args: &[&str]) {
+	let status = Command::new(env!("CARGO"))
+		.args([command, "--quiet", "--offline", "-p", env!("CARGO_PKG_NAME")])
+		.args(args)
+		.current_dir(env!("CARGO_MANIFEST_DIR"))
+		.env("CARGO_TARGET_DIR", target_dir())
+		.status()
+		.expect("cargo can be run");
+	assert!(status.success(), "cargo {} without std failed, see its output above", command);
+}
+
+#[test]
+fn checks_pass() {
+	cargo_alone("run", &["--bin", "check-all"]);
+}
+
+#[test]
+fn builds_without_std() {
+	cargo_alone("build", &["--lib"]);
+}
+
+#[test]
+#[ignore = "needs the wasm32-unknown-unknown target, `rustup target add wasm32-unknown-unknown`"]
+fn builds_for_wasm() {
+	let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+	let libdir = Command::new(rustc)
+		.args(["--print", "target-libdir", "--target", WASM_TARGET])
+		.output()
+		.ok()
+		.filter(|output| output.status.success())
+		.map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+	assert!(
+		libdir.is_some_and(|libdir| libdir.is_dir()),
+		"target {} is not installed",
+		WASM_TARGET,
+	);
+	cargo_alone("build", &["--lib", "--target", WASM_TARGET]);
+}
@@ -0,0 +1,440 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Asynchronous reads of the tries of `trie-db`.
+//!
+//! `AsyncTrie` reads a trie from an `AsyncNodeSource`, a database answering each node request
+//! with a future, such as a network or disk backend. The lookups only decode nodes between two
+//! requests: they are driven by polling their futures, with any executor, the crate depending
+//! on nothing but `futures-core` for the `Stream` of the pairs.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use hash_db::{HashDBRef, Hasher, OwnedPrefix, Prefix, EMPTY_PREFIX};
+use trie_db::node::{decode_hash, Node, NodeHandle, Value};
+use trie_db::value_chunks::ChunkIndex;
+use trie_db::{
+	proof, CError, DBValue, FnHashDB, NibbleSlice, NibbleVec, NodeCodec, Result,
+	TrieDB, TrieError, TrieHash, TrieItem, TrieLayout,
+};
+
+/// Asynchronous source of the nodes of tries.
+pub trait AsyncNodeSource<H: Hasher> {
+	/// The node `hash` at `prefix`, `None` if the source does not have it.
+	fn node(&self, hash: H::Out, prefix: OwnedPrefix) -> impl Future<Output = Option<DBValue>>;
+}
+
+/// Source answering at once from a synchronous database, such as a `MemoryDB` in tests.
+pub struct HashDBSource<D>(pub D);
+
+impl<H: Hasher, D: HashDBRef<H, DBValue>> AsyncNodeSource<H> for HashDBSource<D> {
+	async fn node(&self, hash: H::Out, prefix: OwnedPrefix) -> Option<DBValue> {
+		self.0.get(&hash, (&prefix.0, prefix.1))
+	}
+}
+
+/// A trie read from an asynchronous node source.
+pub struct AsyncTrie<L: TrieLayout, S> {
+	source: S,
+	root: TrieHash<L>,
+}
+
+impl<L, S> AsyncTrie<L, S>
+	where
+		L: TrieLayout,
+		S: AsyncNodeSource<L::Hash>,
+{
+	/// The trie at `root` in `source`. The root node is only requested by the reads.
+	pub fn new(source: S, root: TrieHash<L>) -> Self {
+		AsyncTrie { source, root }
+	}
+
+	/// Root of the trie.
+	pub fn root(&self) -> &TrieHash<L> {
+		&self.root
+	}
+
+	/// Source of the nodes.
+	pub fn source(&self) -> &S {
+		&self.source
+	}
+
+	/// Get the value at `key`, as `TrieDB::get`.
+	pub async fn get(&self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		self.look_up(key, None).await
+	}
+
+	/// Get the value at `key` with a proof of it, or of its absence, in the format of
+	/// `proof::generate_proof`, to check with `proof::verify_proof`.
+	pub async fn get_with_proof(
+		&self,
+		key: &[u8],
+	) -> Result<(Option<DBValue>, Vec<Vec<u8>>), TrieHash<L>, CError<L>> {
+		let mut nodes = Vec::new();
+		let value = self.look_up(key, Some(&mut nodes)).await?;
+		// The proof is generated from the nodes of the lookup, which are all it needs.
+		let db = FnHashDB::<L::Hash, _>::new(|hash: &TrieHash<L>, _: Prefix| {
+			nodes.iter().find(|(node_hash, _)| node_hash == hash).map(|(_, data)| data.clone())
+		});
+		let trie = TrieDB::<L>::new(&db, &self.root)?;
+		let proof = proof::generate_proof(&trie, &[key])?;
+		Ok((value, proof))
+	}
+
+	/// Stream of the pairs of the trie in key order, as `TrieDB::iter`. The stream ends after
+	/// an error.
+	pub fn iter(&self) -> PairStream<'_, L, S> {
+		PairStream {
+			trie: self,
+			pending: vec![(NibbleVec::new(), self.root, Child::Hash(self.root))],
+			fetching: None,
+		}
+	}
+
+	/// Fetch the node `hash` at `prefix`.
+	async fn fetch(
+		&self,
+		hash: TrieHash<L>,
+		prefix: Prefix<'_>,
+	) -> Result<DBValue, TrieHash<L>, CError<L>> {
+		let is_root = prefix == EMPTY_PREFIX && hash == self.root;
+		self.source.node(hash, (prefix.0.to_vec(), prefix.1)).await
+			.ok_or_else(|| Box::new(missing_node(hash, prefix, is_root)))
+	}
+
+	/// The value of value slot `slot` of the node at `prefix`, fetching its value node, or the
+	/// chunks of the value and their index, appended to `nodes`, if it is stored apart. `hash` is
+	/// the hash of the node, or of the closest node above it for inline nodes.
+	async fn value(
+		&self,
+		slot: &[u8],
+		hash: TrieHash<L>,
+		prefix: Prefix<'_>,
+		mut nodes: Option<&mut Vec<(TrieHash<L>, DBValue)>>,
+	) -> Result<DBValue, TrieHash<L>, CError<L>> {
+		let (value_hash, chunked) = match Value::from_slot::<L>(slot, hash)? {
+			Value::Inline(value) => return Ok(value.to_vec()),
+			Value::Node(value_hash) => (value_hash, false),
+			Value::Chunked(index_hash) => (index_hash, true),
+		};
+		let value_hash = decode_hash::<L::Hash>(value_hash)
+			.ok_or_else(|| Box::new(TrieError::InvalidHash(hash, value_hash.to_vec())))?;
+		let value = self.fetch(value_hash, prefix).await?;
+		if let Some(nodes) = nodes.as_mut() {
+			nodes.push((value_hash, value.clone()));
+		}
+		if !chunked {
+			return Ok(value);
+		}
+		let invalid = || Box::new(TrieError::InvalidValueChunks(value_hash));
+		let index = L::MAX_VALUE_CHUNK
+			.and_then(|chunk_size| ChunkIndex::<TrieHash<L>>::decode(&value, chunk_size))
+			.ok_or_else(invalid)?;
+		let mut value = Vec::with_capacity(index.len);
+		for (i, chunk_hash) in index.chunks.iter().enumerate() {
+			let chunk = self.fetch(*chunk_hash, prefix).await?;
+			if chunk.len() != index.chunk_range(i).len() {
+				return Err(invalid());
+			}
+			value.extend_from_slice(&chunk);
+			if let Some(nodes) = nodes.as_mut() {
+				nodes.push((*chunk_hash, chunk));
+			}
+		}
+		Ok(value)
+	}
+
+	/// Look up `key`, as `Lookup::look_up`, appending the fetched nodes to `nodes`.
+	async fn look_up(
+		&self,
+		key: &[u8],
+		mut nodes: Option<&mut Vec<(TrieHash<L>, DBValue)>>,
+	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		let key = NibbleSlice::<L::Nibble>::new(key);
+		let mut partial = key;
+		let mut hash = self.root;
+		let mut key_nibbles = 0;
+
+		// this loop iterates through non-inline nodes.
+		loop {
+			let node_data = self.fetch(hash, key.mid(key_nibbles).left()).await?;
+			if let Some(nodes) = nodes.as_mut() {
+				nodes.push((hash, node_data.clone()));
+			}
+
+			// this loop iterates through all inline children (usually max 1).
+			let mut node_data = &node_data[..];
+			loop {
+				let decoded = L::Codec::decode(node_data)
+					.map_err(|e| Box::new(TrieError::DecoderError(hash, e)))?;
+				// value nodes are stored under the prefix of their node.
+				let node_key = key.mid(key_nibbles);
+				let value = match decoded {
+					Node::Leaf(slice, value) if slice == partial => Some(value),
+					Node::Branch(_, value) if partial.is_empty() => value,
+					Node::NibbledBranch(slice, _, value) if slice == partial => value,
+					_ => None,
+				};
+				if let Some(slot) = value {
+					return self.value(slot, hash, node_key.left(), nodes).await.map(Some);
+				}
+				let next_node = match decoded {
+					Node::Leaf(..) => return Ok(None),
+					Node::Extension(slice, item) => {
+						if !partial.starts_with(&slice) {
+							return Ok(None);
+						}
+						partial = partial.mid(slice.len());
+						key_nibbles += slice.len();
+						item
+					},
+					Node::Branch(children, _) => match partial.try_at(0) {
+						None => return Ok(None),
+						Some(i) => match children[i as usize] {
+							Some(child) => {
+								partial = partial.mid(1);
+								key_nibbles += 1;
+								child
+							},
+							None => return Ok(None),
+						},
+					},
+					Node::NibbledBranch(slice, children, _) => {
+						if !partial.starts_with(&slice) {
+							return Ok(None);
+						}
+						match partial.try_at(slice.len()) {
+							None => return Ok(None),
+							Some(i) => match children[i as usize] {
+								Some(child) => {
+									partial = partial.mid(slice.len() + 1);
+									key_nibbles += slice.len() + 1;
+									child
+								},
+								None => return Ok(None),
+							},
+						}
+					},
+					Node::Empty => return Ok(None),
+				};
+
+				// check if new node data is inline or hash.
+				match next_node {
+					NodeHandle::Hash(data) => {
+						hash = decode_hash::<L::Hash>(data)
+							.ok_or_else(|| Box::new(TrieError::InvalidHash(hash, data.to_vec())))?;
+						break;
+					},
+					NodeHandle::Inline(data) => node_data = data,
+				}
+			}
+		}
+	}
+}
+
+/// Error on a node missing from the source at `prefix`.
+fn missing_node<H, E>(hash: H, prefix: Prefix, is_root: bool) -> TrieError<H, E> {
+	match is_root {
+		true => TrieError::InvalidStateRoot(hash),
+		false => TrieError::incomplete_database(hash, prefix),
+	}
+}
+
+/// A node to visit, or a value stored apart to fetch.
+enum Child<H> {
+	Hash(H),
+	Inline(Vec<u8>),
+	/// The value slot of a value stored apart, and the key of the value.
+	Value(Vec<u8>, Vec<u8>),
+}
+
+/// Future of a node requested from the source.
+type NodeFuture<'a> = Pin<Box<dyn Future<Output = Option<DBValue>> + 'a>>;
+
+/// Future of a value stored apart, read from the source.
+type ValueFuture<'a, L> =
+	Pin<Box<dyn Future<Output = Result<DBValue, TrieHash<L>, CError<L>>> + 'a>>;
+
+/// What a `PairStream` requested from the source.
+enum Fetching<'a, L: TrieLayout> {
+	/// A node, with its key nibbles before it and the hash of its node or of the node holding
+	/// it.
+	Node(NibbleVec<L::Nibble>, TrieHash<L>, NodeFuture<'a>),
+	/// A value stored apart, with its key.
+	Value(Vec<u8>, ValueFuture<'a, L>),
+}
+
+/// Stream of the pairs of an `AsyncTrie`, see `AsyncTrie::iter`.
+pub struct PairStream<'a, L: TrieLayout, S> {
+	trie: &'a AsyncTrie<L, S>,
+	/// Nodes to visit, the next one last, with their key nibbles before them and the hash of
+	/// their node or of the node holding them when inline.
+	pending: Vec<(NibbleVec<L::Nibble>, TrieHash<L>, Child<TrieHash<L>>)>,
+	/// Node requested from the source.
+	fetching: Option<Fetching<'a, L>>,
+}
+
+// The future of the fetched node is boxed, nothing of the stream is pinned.
+impl<'a, L: TrieLayout, S> Unpin for PairStream<'a, L, S> {}
+
+impl<'a, L, S> PairStream<'a, L, S>
+	where
+		L: TrieLayout,
+		S: AsyncNodeSource<L::Hash>,
+{
+	/// Queue the children of the node `data` at `prefix`, returning its value if any, or queuing
+	/// its value node before the children.
+	fn visit(
+		&mut self,
+		prefix: NibbleVec<L::Nibble>,
+		hash: TrieHash<L>,
+		data: &[u8],
+	) -> Option<TrieItem<'static, TrieHash<L>, CError<L>>> {
+		let node = match L::Codec::decode(data) {
+			Ok(node) => node,
+			Err(e) => return Some(Err(Box::new(TrieError::DecoderError(hash, e)))),
+		};
+		let (partial, children, value) = match node {
+			Node::Empty => return None,
+			Node::Leaf(partial, value) => (partial, Vec::new(), Some(value)),
+			Node::Extension(partial, child) => (partial, vec![(None, child)], None),
+			Node::Branch(children, value) =>
+				(NibbleSlice::new(&[]), branch_children(&children), value),
+			Node::NibbledBranch(partial, children, value) =>
+				(partial, branch_children(&children), value),
+		};
+		let mut key = prefix;
+		key.append_partial(partial.right());
+		for (index, child) in children.into_iter().rev() {
+			let mut child_key = key.clone();
+			if let Some(index) = index {
+				child_key.push(index);
+			}
+			let child = match child {
+				NodeHandle::Hash(data) => match decode_hash::<L::Hash>(data) {
+					Some(child_hash) => Child::Hash(child_hash),
+					None => return Some(Err(Box::new(TrieError::InvalidHash(hash, data.to_vec())))),
+				},
+				NodeHandle::Inline(data) => Child::Inline(data.to_vec()),
+			};
+			self.pending.push((child_key, hash, child));
+		}
+
+		let value = value?;
+		let (key_slice, extra_nibble) = key.as_prefix();
+		if let Some(extra_nibble) = extra_nibble {
+			let error = TrieError::ValueAtIncompleteKey(key_slice.to_vec(), extra_nibble);
+			return Some(Err(Box::new(error)));
+		}
+		let slot = match Value::from_slot::<L>(value, hash) {
+			Ok(slot) => slot,
+			Err(error) => return Some(Err(error)),
+		};
+		match slot {
+			Value::Inline(value) => Some(Ok((key_slice.to_vec(), value.to_vec()))),
+			// queued after the children, to be fetched before them.
+			Value::Node(_) | Value::Chunked(_) => {
+				let value_key = key_slice.to_vec();
+				// value nodes are stored under the prefix of their node.
+				key.drop_lasts(partial.len());
+				self.pending.push((key, hash, Child::Value(value.to_vec(), value_key)));
+				None
+			},
+		}
+	}
+}
+
+/// The children of a branch, with their index.
+fn branch_children<'a>(
+	children: &[Option<NodeHandle<'a>>],
+) -> Vec<(Option<u8>, NodeHandle<'a>)> {
+	children.iter()
+		.enumerate()
+		.filter_map(|(index, child)| child.map(|child| (Some(index as u8), child)))
+		.collect()
+}
+
+impl<'a, L, S> Stream for PairStream<'a, L, S>
+	where
+		L: TrieLayout,
+		S: AsyncNodeSource<L::Hash>,
+{
+	type Item = TrieItem<'static, TrieHash<L>, CError<L>>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let this = self.get_mut();
+		loop {
+			let item = match this.fetching.as_mut() {
+				Some(Fetching::Node(_, _, future)) => {
+					let data = match future.as_mut().poll(cx) {
+						Poll::Ready(data) => data,
+						Poll::Pending => return Poll::Pending,
+					};
+					let (prefix, hash) = match this.fetching.take() {
+						Some(Fetching::Node(prefix, hash, _)) => (prefix, hash),
+						_ => unreachable!("the fetched node was just polled; qed"),
+					};
+					match data {
+						Some(data) => this.visit(prefix, hash, &data),
+						None => {
+							let is_root = prefix.is_empty();
+							Some(Err(Box::new(missing_node(hash, prefix.as_prefix(), is_root))))
+						},
+					}
+				},
+				Some(Fetching::Value(_, future)) => {
+					let value = match future.as_mut().poll(cx) {
+						Poll::Ready(value) => value,
+						Poll::Pending => return Poll::Pending,
+					};
+					let key = match this.fetching.take() {
+						Some(Fetching::Value(key, _)) => key,
+						_ => unreachable!("the fetched value was just polled; qed"),
+					};
+					Some(value.map(|value| (key, value)))
+				},
+				None => match this.pending.pop() {
+					None => return Poll::Ready(None),
+					Some((prefix, _, Child::Hash(hash))) => {
+						let (key, last) = prefix.as_prefix();
+						let prefix_owned = (key.to_vec(), last);
+						let future = this.trie.source.node(hash, prefix_owned);
+						this.fetching = Some(Fetching::Node(prefix, hash, Box::pin(future)));
+						continue;
+					},
+					Some((prefix, hash, Child::Value(slot, key))) => {
+						let trie = this.trie;
+						let future = async move {
+							trie.value(&slot, hash, prefix.as_prefix(), None).await
+						};
+						this.fetching = Some(Fetching::Value(key, Box::pin(future)));
+						continue;
+					},
+					Some((prefix, hash, Child::Inline(data))) => this.visit(prefix, hash, &data),
+				},
+			};
+			match item {
+				Some(Err(error)) => {
+					this.pending.clear();
+					return Poll::Ready(Some(Err(error)));
+				},
+				Some(Ok(pair)) => return Poll::Ready(Some(Ok(pair))),
+				None => continue,
+			}
+		}
+	}
+}
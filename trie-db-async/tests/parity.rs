@@ -0,0 +1,242 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads of `AsyncTrie` against those of `TrieDB`, from a source answering each request after
+//! a pending poll.
+
+use std::future::{poll_fn, Future};
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use futures_core::Stream;
+use hash_db::{HashDB, HashDBRef, Hasher, OwnedPrefix};
+use keccak_hasher::KeccakHasher;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::{ChunkedValueLayout, ExtensionLayout, NoExtensionLayout, ValueNodeLayout};
+use trie_db::node::{decode_hash, Value};
+use trie_db::{proof, DBValue, Trie, TrieDB, TrieDBMut, TrieError, TrieLayout, TrieMut};
+use trie_db_async::{AsyncNodeSource, AsyncTrie, HashDBSource};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+type Root = <KeccakHasher as Hasher>::Out;
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+	fn wake(self: Arc<Self>) {
+		self.0.unpark();
+	}
+}
+
+/// Run `future` to completion on the current thread.
+fn block_on<F: Future>(future: F) -> F::Output {
+	let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+	let mut cx = Context::from_waker(&waker);
+	let mut future = pin!(future);
+	loop {
+		match future.as_mut().poll(&mut cx) {
+			Poll::Ready(output) => return output,
+			Poll::Pending => thread::park(),
+		}
+	}
+}
+
+/// Future returning pending once, waking its task, before answering.
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+	type Output = ();
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+		if self.0 {
+			return Poll::Ready(());
+		}
+		self.0 = true;
+		cx.waker().wake_by_ref();
+		Poll::Pending
+	}
+}
+
+/// Source of the nodes of a `MemoryDB`, answering each request after a pending poll.
+struct SlowSource<'a>(&'a MemoryDB);
+
+impl<'a> AsyncNodeSource<KeccakHasher> for SlowSource<'a> {
+	async fn node(&self, hash: Root, prefix: OwnedPrefix) -> Option<DBValue> {
+		YieldOnce(false).await;
+		HashDBRef::get(self.0, &hash, (&prefix.0, prefix.1))
+	}
+}
+
+fn build_trie<L: TrieLayout<Hash = KeccakHasher>>(seed: u64) -> (MemoryDB, Root, Vec<Vec<u8>>) {
+	let mut rng = SmallRng::seed_from_u64(seed);
+	let keys: Vec<Vec<u8>> = (0..100).map(|_| {
+		let len = rng.gen_range(0, 6);
+		(0..len).map(|_| rng.gen_range(0, 4)).collect()
+	}).collect();
+	// values over a few chunks, in layouts storing values in chunks.
+	let max_len = L::MAX_VALUE_CHUNK.map_or(48, |chunk_size| 4 * chunk_size);
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut trie = TrieDBMut::<L>::new(&mut db, &mut root);
+		for key in keys.iter().take(80) {
+			let len = rng.gen_range(1, max_len);
+			let value: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+			trie.insert(key, &value).unwrap();
+		}
+	}
+	(db, root, keys)
+}
+
+fn collect_stream<St: Stream + Unpin>(mut stream: St) -> Vec<St::Item> {
+	block_on(async move {
+		let mut items = Vec::new();
+		while let Some(item) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+			items.push(item);
+		}
+		items
+	})
+}
+
+fn check_parity<L: TrieLayout<Hash = KeccakHasher>>() {
+	for seed in 0..10 {
+		let (db, root, keys) = build_trie::<L>(seed);
+		let trie = TrieDB::<L>::new(&db, &root).unwrap();
+		let async_trie = AsyncTrie::<L, _>::new(SlowSource(&db), root);
+
+		// the last keys are mostly absent.
+		for key in keys.iter() {
+			let expected = trie.get(key).unwrap();
+			assert_eq!(block_on(async_trie.get(key)).unwrap(), expected);
+
+			let (value, proof) = block_on(async_trie.get_with_proof(key)).unwrap();
+			assert_eq!(value, expected);
+			assert_eq!(proof, proof::generate_proof(&trie, &[key]).unwrap());
+			proof::verify_proof::<L, _, _, _>(&root, &proof, &[(key, value)]).unwrap();
+		}
+
+		let expected: Vec<_> = trie.iter().unwrap().map(|item| item.unwrap()).collect();
+		let pairs: Vec<_> = collect_stream(async_trie.iter()).into_iter()
+			.map(|item| item.unwrap())
+			.collect();
+		assert_eq!(pairs, expected);
+	}
+}
+
+#[test]
+fn parity_with_extension() {
+	check_parity::<ExtensionLayout>();
+}
+
+#[test]
+fn parity_without_extension() {
+	check_parity::<NoExtensionLayout>();
+}
+
+#[test]
+fn parity_with_value_nodes() {
+	check_parity::<ValueNodeLayout>();
+}
+
+#[test]
+fn parity_with_chunked_values() {
+	check_parity::<ChunkedValueLayout>();
+}
+
+#[test]
+fn missing_value_nodes_are_errors() {
+	let (mut db, root, keys) = build_trie::<ValueNodeLayout>(5);
+	let (prefix, value_hash) = {
+		let trie = TrieDB::<ValueNodeLayout>::new(&db, &root).unwrap();
+		trie_db::TrieDBNodeIterator::new(&trie).unwrap()
+			.find_map(|item| {
+				let (prefix, _, node) = item.unwrap();
+				match node.value::<ValueNodeLayout>(Default::default()).unwrap() {
+					Some(Value::Node(hash)) => Some((prefix, decode_hash::<KeccakHasher>(hash)?)),
+					_ => None,
+				}
+			})
+			.expect("the trie has value nodes")
+	};
+	let owned_prefix = prefix.as_prefix();
+	HashDB::remove(&mut db, &value_hash, (owned_prefix.0, owned_prefix.1));
+
+	let async_trie = AsyncTrie::<ValueNodeLayout, _>::new(SlowSource(&db), root);
+	let missing = |error: &TrieError<Root, _>| match *error {
+		TrieError::IncompleteDatabase { hash, ref prefix } =>
+			hash == value_hash && (&prefix.0[..], prefix.1) == owned_prefix,
+		_ => false,
+	};
+	assert!(keys.iter().any(|key| match block_on(async_trie.get(key)) {
+		Err(error) => missing(&error),
+		Ok(_) => false,
+	}));
+	let items = collect_stream(async_trie.iter());
+	match items.last() {
+		Some(Err(error)) => assert!(missing(error), "unexpected error {:?}", error),
+		item => panic!("unexpected item {:?}", item),
+	}
+}
+
+#[test]
+fn reads_from_a_synchronous_database() {
+	let (db, root, keys) = build_trie::<ExtensionLayout>(11);
+	let trie = TrieDB::<ExtensionLayout>::new(&db, &root).unwrap();
+	let async_trie = AsyncTrie::<ExtensionLayout, _>::new(HashDBSource(db.clone()), root);
+	for key in keys.iter() {
+		assert_eq!(block_on(async_trie.get(key)).unwrap(), trie.get(key).unwrap());
+	}
+	assert_eq!(collect_stream(async_trie.iter()).len(), trie.iter().unwrap().count());
+}
+
+#[test]
+fn missing_nodes_are_errors() {
+	let (mut db, root, _) = build_trie::<ExtensionLayout>(3);
+	let missing_root = KeccakHasher::hash(b"missing");
+	let async_trie = AsyncTrie::<ExtensionLayout, _>::new(SlowSource(&db), missing_root);
+	match *block_on(async_trie.get(b"a")).unwrap_err() {
+		TrieError::InvalidStateRoot(hash) => assert_eq!(hash, missing_root),
+		ref error => panic!("unexpected error {:?}", error),
+	}
+
+	// remove a node below the root: the lookups through it and the stream fail on it.
+	let (prefix, hash) = {
+		let trie = TrieDB::<ExtensionLayout>::new(&db, &root).unwrap();
+		trie_db::TrieDBNodeIterator::new(&trie).unwrap()
+			.filter_map(|item| {
+				let (prefix, hash, _) = item.unwrap();
+				hash.filter(|_| !prefix.is_empty()).map(|hash| (prefix, hash))
+			})
+			.next()
+			.expect("the trie has nodes below the root")
+	};
+	let owned_prefix = prefix.as_prefix();
+	HashDB::remove(&mut db, &hash, (owned_prefix.0, owned_prefix.1));
+
+	let async_trie = AsyncTrie::<ExtensionLayout, _>::new(SlowSource(&db), root);
+	let items = collect_stream(async_trie.iter());
+	match items.last() {
+		Some(Err(error)) => match **error {
+			TrieError::IncompleteDatabase { hash: missing, ref prefix } => {
+				assert_eq!(missing, hash);
+				assert_eq!((&prefix.0[..], prefix.1), owned_prefix);
+			},
+			ref error => panic!("unexpected error {:?}", error),
+		},
+		item => panic!("unexpected item {:?}", item),
+	}
+	assert!(items[..items.len() - 1].iter().all(|item| item.is_ok()));
+}
@@ -0,0 +1,8 @@
+#![no_main]
+
+use trie_db_fuzz::fuzz_that_node_decode;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+	fuzz_that_node_decode(data);
+});
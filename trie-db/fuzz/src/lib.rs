@@ -363,3 +363,21 @@ fn test_generate_proof<L: TrieLayout>(
 
 	(root, proof, items)
 }
+
+pub fn fuzz_that_node_decode(input: &[u8]) {
+	use trie_db::{NodeCodec, node::Node};
+	fn check<C: NodeCodec>(input: &[u8]) {
+		if let Ok(node) = C::decode(input) {
+			match node {
+				Node::Leaf(partial, _)
+				| Node::Extension(partial, _)
+				| Node::NibbledBranch(partial, _, _) => {
+					assert_eq!(partial.iter().count(), partial.len());
+				},
+				Node::Branch(..) | Node::Empty => (),
+			}
+		}
+	}
+	check::<<ExtensionLayout as TrieLayout>::Codec>(input);
+	check::<<NoExtensionLayout as TrieLayout>::Codec>(input);
+}
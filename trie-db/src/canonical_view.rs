@@ -0,0 +1,390 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Iteration over the nodes of a trie as encoded under the inlining rules of its layout.
+//!
+//! Tries with the same pairs can be stored with different nodes: a writer not following the
+//! inlining rules may store a small node under its hash instead of inline in its parent, or
+//! the other way round. Their key-value iterations are the same, not their node iterations.
+//! `CanonicalNodeIterator` yields the nodes as a trie built from the pairs would store them,
+//! encoding again the nodes whose stored form differs, so that checksums of the nodes of such
+//! tries match.
+
+use hash_db::{Hasher, EMPTY_PREFIX};
+use crate::nibble::{Children, NibbleOps, NibbleSlice, NibbleVec, Radix16};
+use crate::node::{encode_node, Node, NodeHandle, OwnedNode};
+use crate::rstd::vec::Vec;
+use crate::{CError, ChildReference, DBValue, Result, Trie, TrieDB, TrieHash, TrieLayout};
+
+/// A node in its canonical form, see `TrieDBNodeIterator::canonical_view`.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Clone, Eq, PartialEq)]
+pub struct CanonicalNode<H, N: NibbleOps = Radix16> {
+	/// Key nibbles before the node.
+	pub prefix: NibbleVec<N>,
+	/// Hash of the canonical encoding, `None` if the node is inline in its parent.
+	pub hash: Option<H>,
+	/// Canonical encoding of the node, with the canonical references to its children.
+	pub encoded: Vec<u8>,
+	/// Whether the stored node differs from its canonical form: it is encoded differently,
+	/// for instance with other references to its children, or it is stored under its hash
+	/// while canonically inline, or the other way round.
+	pub denormalized: bool,
+}
+
+/// Canonical node iterator item.
+type CanonicalItem<L> =
+	Result<CanonicalNode<TrieHash<L>, <L as TrieLayout>::Nibble>, TrieHash<L>, CError<L>>;
+
+/// Canonical references to the children of a node.
+type CanonicalChildren<L> = Children<ChildReference<TrieHash<L>>, <L as TrieLayout>::Nibble>;
+
+/// A node whose children are being visited.
+struct Frame<L: TrieLayout> {
+	node: OwnedNode<DBValue, L::Nibble>,
+	/// Hash of the stored node, `None` if stored inline.
+	hash: Option<TrieHash<L>>,
+	/// Hash of the node, or of the node holding it when inline.
+	holder_hash: TrieHash<L>,
+	/// Index of the node in its parent, 0 for the child of an extension.
+	index: u8,
+	/// Number of key nibbles before the node.
+	prefix_len: usize,
+	/// Index of the next child to visit.
+	next_child: usize,
+	/// Canonical references to the children already visited.
+	children: CanonicalChildren<L>,
+}
+
+impl<L: TrieLayout> Frame<L> {
+	fn new(
+		node: OwnedNode<DBValue, L::Nibble>,
+		hash: Option<TrieHash<L>>,
+		holder_hash: TrieHash<L>,
+		index: u8,
+		prefix_len: usize,
+	) -> Self {
+		Frame {
+			node,
+			hash,
+			holder_hash,
+			index,
+			prefix_len,
+			next_child: 0,
+			children: Children::new(),
+		}
+	}
+}
+
+/// Iterator over the nodes of a trie in their canonical form, created with
+/// `TrieDBNodeIterator::canonical_view`.
+///
+/// As the canonical encoding of a node depends on the ones of its children, the nodes are
+/// yielded in post-order: the children of a node in key order before the node itself, the root
+/// last. The iteration ends after an error.
+pub struct CanonicalNodeIterator<'a, L: TrieLayout> {
+	db: &'a TrieDB<'a, L>,
+	stack: Vec<Frame<L>>,
+	key_nibbles: NibbleVec<L::Nibble>,
+}
+
+impl<'a, L: TrieLayout> CanonicalNodeIterator<'a, L> {
+	/// Create a new iterator, see `TrieDBNodeIterator::canonical_view`.
+	pub(crate) fn new(db: &'a TrieDB<L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		let (root_node, root_hash) = db.get_raw_or_lookup(
+			*db.root(),
+			NodeHandle::Hash(db.root().as_ref()),
+			EMPTY_PREFIX,
+		)?;
+		let mut stack = Vec::with_capacity(8);
+		stack.push(Frame::new(root_node, root_hash, *db.root(), 0, 0));
+		Ok(CanonicalNodeIterator { db, stack, key_nibbles: NibbleVec::new() })
+	}
+
+	/// Fetch the next child of the last node of the stack, returning `false` if it has no
+	/// children left.
+	fn descend(&mut self) -> Result<bool, TrieHash<L>, CError<L>> {
+		let frame = match self.stack.last_mut() {
+			Some(frame) => frame,
+			None => return Ok(false),
+		};
+		let node = frame.node.node();
+		let (partial, is_branch) = match node {
+			Node::Extension(partial, _) => (partial, false),
+			Node::Branch(..) => (NibbleSlice::new(&[]), true),
+			Node::NibbledBranch(partial, ..) => (partial, true),
+			Node::Empty | Node::Leaf(..) => return Ok(false),
+		};
+		let mut child = None;
+		while child.is_none() && frame.next_child < L::Nibble::NIBBLE_LENGTH {
+			let index = frame.next_child;
+			frame.next_child += 1;
+			child = match node {
+				Node::Extension(_, handle) if index == 0 => Some(handle),
+				Node::Branch(ref children, _) | Node::NibbledBranch(_, ref children, _) =>
+					children[index],
+				_ => None,
+			};
+		}
+		let child = match child {
+			Some(child) => child,
+			None => return Ok(false),
+		};
+
+		self.key_nibbles.drop_lasts(self.key_nibbles.len() - frame.prefix_len);
+		self.key_nibbles.append_partial(partial.right());
+		let index = (frame.next_child - 1) as u8;
+		if is_branch {
+			self.key_nibbles.push(index);
+		}
+		let (child_node, child_hash) = self.db.get_raw_or_lookup(
+			frame.holder_hash,
+			child,
+			self.key_nibbles.as_prefix(),
+		)?;
+		let holder_hash = child_hash.unwrap_or(frame.holder_hash);
+		let prefix_len = self.key_nibbles.len();
+		self.stack.push(Frame::new(child_node, child_hash, holder_hash, index, prefix_len));
+		Ok(true)
+	}
+
+	/// Encode the last node of the stack, whose children were all visited, in its canonical
+	/// form.
+	fn pop(&mut self) -> Option<CanonicalItem<L>> {
+		let frame = self.stack.pop()?;
+		self.key_nibbles.drop_lasts(self.key_nibbles.len() - frame.prefix_len);
+		let encoded = match encode_node::<L>(&canonical_node(frame.node.node(), &frame.children)) {
+			Ok(encoded) => encoded,
+			Err(error) => {
+				self.stack.clear();
+				return Some(Err(error));
+			},
+		};
+
+		let is_root = self.stack.is_empty();
+		let (hash, reference) = if !is_root && encoded.len() < L::Hash::LENGTH {
+			let mut data = TrieHash::<L>::default();
+			data.as_mut()[..encoded.len()].copy_from_slice(&encoded);
+			(None, ChildReference::Inline(data, encoded.len()))
+		} else {
+			let hash = L::Hash::hash(&encoded);
+			(Some(hash), ChildReference::Hash(hash))
+		};
+		if let Some(parent) = self.stack.last_mut() {
+			parent.children[frame.index as usize] = Some(reference);
+		}
+		let denormalized = encoded[..] != *frame.node.data()
+			|| hash.is_some() != frame.hash.is_some();
+		Some(Ok(CanonicalNode { prefix: self.key_nibbles.clone(), hash, encoded, denormalized }))
+	}
+}
+
+impl<'a, L: TrieLayout> Iterator for CanonicalNodeIterator<'a, L> {
+	type Item = CanonicalItem<L>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			match self.descend() {
+				Ok(true) => continue,
+				Ok(false) => return self.pop(),
+				Err(error) => {
+					self.stack.clear();
+					return Some(Err(error));
+				},
+			}
+		}
+	}
+}
+
+/// The node `node` with the references `children` to its children.
+fn canonical_node<'a, HO: AsRef<[u8]>, N: NibbleOps>(
+	node: Node<'a, N>,
+	children: &'a Children<ChildReference<HO>, N>,
+) -> Node<'a, N> {
+	let handle = |reference: &'a ChildReference<HO>| match reference {
+		ChildReference::Hash(hash) => NodeHandle::Hash(hash.as_ref()),
+		ChildReference::Inline(data, len) => NodeHandle::Inline(&data.as_ref()[..*len]),
+	};
+	let handles = || {
+		let mut handles = Children::new();
+		for (handle_at, reference) in handles.iter_mut().zip(children.iter()) {
+			*handle_at = reference.as_ref().map(handle);
+		}
+		handles
+	};
+	match node {
+		Node::Extension(partial, _) => Node::Extension(
+			partial,
+			handle(children[0].as_ref().expect("the children of a node are visited before it; qed")),
+		),
+		Node::Branch(_, value) => Node::Branch(handles(), value),
+		Node::NibbledBranch(partial, _, value) => Node::NibbledBranch(partial, handles(), value),
+		node => node,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use hash_db::{HashDB, Hasher};
+	use reference_trie::{
+		builder::{BuiltDB, TrieBuilder}, node::{encode_node, Node, NodeHandle}, Children,
+		ExtensionLayout, NibbleVec, NoExtensionLayout, NodeCodec, TrieDB, TrieDBNodeIterator,
+		TrieLayout,
+	};
+
+	type Hash<L> = <<L as TrieLayout>::Hash as Hasher>::Out;
+
+	/// Store the node `data` at `key` of `source` in `db` with its descendants, all under their
+	/// hash as a writer never inlining nodes would, returning the hash of the node.
+	fn store_outlined<L: TrieLayout>(
+		source: &BuiltDB<L>,
+		data: &[u8],
+		key: &mut NibbleVec,
+		db: &mut BuiltDB<L>,
+	) -> Hash<L> {
+		let node = L::Codec::decode(data).unwrap();
+		let mut handles = Vec::new();
+		match &node {
+			Node::Extension(partial, handle) => handles.push((None, *partial, *handle)),
+			Node::Branch(children, _) | Node::NibbledBranch(_, children, _) => {
+				let partial = match &node {
+					Node::NibbledBranch(partial, ..) => *partial,
+					_ => reference_trie::NibbleSlice::new(&[]),
+				};
+				for (index, child) in children.iter().enumerate() {
+					if let Some(child) = child {
+						handles.push((Some(index as u8), partial, *child));
+					}
+				}
+			},
+			Node::Empty | Node::Leaf(..) => (),
+		}
+
+		let mut hashes = [None; 16];
+		for (index, partial, handle) in handles {
+			let prefix_len = key.len();
+			key.append_partial(partial.right());
+			if let Some(index) = index {
+				key.push(index);
+			}
+			let child_data = match handle {
+				NodeHandle::Hash(hash) => {
+					let mut child_hash = Hash::<L>::default();
+					child_hash.as_mut().copy_from_slice(hash);
+					source.get(&child_hash, key.as_prefix()).unwrap()
+				},
+				NodeHandle::Inline(data) => data.to_vec(),
+			};
+			let child_hash = store_outlined::<L>(source, &child_data, key, db);
+			hashes[index.unwrap_or(0) as usize] = Some(child_hash);
+			key.drop_lasts(key.len() - prefix_len);
+		}
+
+		let handle = |index: usize| hashes[index].as_ref()
+			.map(|hash| NodeHandle::Hash(hash.as_ref()));
+		let mut children = Children::new();
+		for (index, child) in children.iter_mut().enumerate() {
+			*child = handle(index);
+		}
+		let outlined = match node {
+			Node::Extension(partial, _) => Node::Extension(partial, handle(0).unwrap()),
+			Node::Branch(_, value) => Node::Branch(children, value),
+			Node::NibbledBranch(partial, _, value) => Node::NibbledBranch(partial, children, value),
+			node => node,
+		};
+		db.insert(key.as_prefix(), &encode_node::<L>(&outlined).unwrap())
+	}
+
+	fn check_canonical_view<L: TrieLayout>() {
+		// Small values and keys, for nested inline branches and leaves, and a value in a branch.
+		let mut builder = TrieBuilder::<L>::new()
+			.insert([0x01], [0x01])
+			.insert([0x01, 0x02], [0x02]);
+		for i in 0..4u8 {
+			for j in 0..3u8 {
+				builder = builder
+					.insert([0x10 * i, j], [i ^ j])
+					.insert([0x10 * i + 1, 0x30, j], [j; 40]);
+			}
+		}
+		let built = builder.build();
+		let trie = built.trie();
+		let canonical: Vec<_> = TrieDBNodeIterator::canonical_view(&trie).unwrap()
+			.map(|item| item.unwrap())
+			.collect();
+		assert!(canonical.iter().all(|node| !node.denormalized));
+		assert!(canonical.iter().any(|node| node.hash.is_none()));
+		assert_eq!(canonical.last().unwrap().hash, Some(*built.root()));
+
+		let mut db = BuiltDB::<L>::default();
+		let root_data = built.db().get(built.root(), hash_db::EMPTY_PREFIX).unwrap();
+		let root = store_outlined::<L>(built.db(), &root_data, &mut NibbleVec::new(), &mut db);
+		assert_ne!(root, *built.root());
+		let outlined = TrieDB::<L>::new(&db, &root).unwrap();
+		let stored_hashes = |trie: &TrieDB<L>| TrieDBNodeIterator::new(trie).unwrap()
+			.map(|item| item.unwrap().1)
+			.collect::<Vec<_>>();
+		assert_ne!(stored_hashes(&outlined), stored_hashes(&trie));
+
+		let view: Vec<_> = TrieDBNodeIterator::canonical_view(&outlined).unwrap()
+			.map(|item| item.unwrap())
+			.collect();
+		assert_eq!(view.len(), canonical.len());
+		for (node, expected) in view.iter().zip(canonical.iter()) {
+			assert_eq!(
+				(&node.prefix, &node.hash, &node.encoded),
+				(&expected.prefix, &expected.hash, &expected.encoded),
+			);
+			// The nodes stored under their hash while inline, and their ancestors.
+			let outlined_below = canonical.iter().any(|other| {
+				other.hash.is_none() && other.prefix.starts_with_vec(&node.prefix)
+					&& (other.prefix.len() > node.prefix.len() || other == expected)
+			});
+			assert_eq!(node.denormalized, outlined_below, "{:?}", node.prefix);
+		}
+		assert!(view.iter().any(|node| !node.denormalized));
+	}
+
+	#[test]
+	fn canonical_view_with_extension() {
+		check_canonical_view::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn canonical_view_without_extension() {
+		check_canonical_view::<NoExtensionLayout>();
+	}
+
+	#[test]
+	fn canonical_view_ends_on_missing_node() {
+		let mut built = TrieBuilder::<ExtensionLayout>::new()
+			.insert([0x01], [1; 32])
+			.insert([0x02], [2; 32])
+			.build();
+		let leaf_hash = {
+			let trie = built.trie();
+			let mut iter = TrieDBNodeIterator::canonical_view(&trie).unwrap();
+			iter.next().unwrap().unwrap().hash.unwrap()
+		};
+		built.remove_node(&leaf_hash);
+		let trie = built.trie();
+		let mut iter = TrieDBNodeIterator::canonical_view(&trie).unwrap();
+		match iter.next().map(|item| item.map_err(|error| *error)) {
+			Some(Err(reference_trie::TrieError::IncompleteDatabase { hash, .. })) =>
+				assert_eq!(hash, leaf_hash),
+			item => panic!("unexpected item: {:?}", item),
+		}
+		assert!(iter.next().is_none());
+	}
+}
@@ -0,0 +1,237 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tries whose root is the value of a key of a parent trie, their nodes being kept in the
+//! database of the parent trie under a keyspace of their own.
+//!
+//! The keyspace of a child trie is prepended to the prefix of each of its nodes: in a database
+//! keyed by prefixes, as with `PrefixedKey`, child tries with the same content do not share
+//! their nodes, and the nodes of a child trie can be removed without touching the others. As
+//! in the `nested` module, the value of the key of a child trie in the parent trie is the root
+//! of the child trie, an absent key standing for an empty child trie.
+//!
+//! Child tries are read with `TrieDB::child_trie`, updated with `TrieDBMut::child_trie_mut`
+//! and removed with `TrieDBMut::remove_child_trie`.
+
+use hash_db::{AsHashDB, HashDB, HashDBRef, Hasher, OwnedPrefix, Prefix, EMPTY_PREFIX};
+use crate::node::decode_hash;
+use crate::rstd::{boxed::Box, vec::Vec};
+use crate::{
+	CError, DBValue, NodeCodec, OwnedTrieDBIterator, Query, Result, Trie, TrieDB, TrieError,
+	TrieHash, TrieItem, TrieIterator, TrieLayout,
+};
+
+/// Keyspace of the nodes of the child trie at `parent_key`: the hash of `parent_key`, so that
+/// all the keyspaces have the same length and none is a prefix of another.
+pub fn child_keyspace<H: Hasher>(parent_key: &[u8]) -> H::Out {
+	H::hash(parent_key)
+}
+
+/// Root of the child trie whose key has the value `value` in the trie at `parent_root`.
+///
+/// Returns `TrieError::InvalidHash` if `value` is not a hash.
+pub(crate) fn child_root<L: TrieLayout>(
+	parent_root: &TrieHash<L>,
+	value: Option<DBValue>,
+) -> Result<TrieHash<L>, TrieHash<L>, CError<L>> {
+	match value {
+		Some(value) => decode_hash::<L::Hash>(&value)
+			.ok_or_else(|| Box::new(TrieError::InvalidHash(*parent_root, value))),
+		None => Ok(L::Codec::hashed_null_node()),
+	}
+}
+
+/// `prefix` in `keyspace`, the keyspace coming before the nibbles of the prefix.
+fn keyspaced(keyspace: &[u8], prefix: Prefix) -> OwnedPrefix {
+	let mut key = Vec::with_capacity(keyspace.len() + prefix.0.len());
+	key.extend_from_slice(keyspace);
+	key.extend_from_slice(prefix.0);
+	(key, prefix.1)
+}
+
+/// Read-only view of the nodes of a database in a keyspace.
+pub struct KeySpacedDB<'a, H: Hasher> {
+	db: &'a dyn HashDBRef<H, DBValue>,
+	keyspace: H::Out,
+}
+
+impl<'a, H: Hasher> KeySpacedDB<'a, H> {
+	/// View of the nodes of `db` in `keyspace`.
+	pub fn new(db: &'a dyn HashDBRef<H, DBValue>, keyspace: H::Out) -> Self {
+		KeySpacedDB { db, keyspace }
+	}
+}
+
+impl<'a, H: Hasher> Clone for KeySpacedDB<'a, H> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+
+impl<'a, H: Hasher> Copy for KeySpacedDB<'a, H> {}
+
+impl<'a, H: Hasher> HashDBRef<H, DBValue> for KeySpacedDB<'a, H> {
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<DBValue> {
+		let prefix = keyspaced(self.keyspace.as_ref(), prefix);
+		self.db.get(key, (&prefix.0, prefix.1))
+	}
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		let prefix = keyspaced(self.keyspace.as_ref(), prefix);
+		self.db.contains(key, (&prefix.0, prefix.1))
+	}
+}
+
+/// View of the nodes of a database in a keyspace, the nodes written through it going to the
+/// keyspace.
+pub struct KeySpacedDBMut<'a, H: Hasher> {
+	db: &'a mut dyn HashDB<H, DBValue>,
+	keyspace: H::Out,
+}
+
+impl<'a, H: Hasher> KeySpacedDBMut<'a, H> {
+	/// View of the nodes of `db` in `keyspace`.
+	pub fn new(db: &'a mut dyn HashDB<H, DBValue>, keyspace: H::Out) -> Self {
+		KeySpacedDBMut { db, keyspace }
+	}
+}
+
+impl<'a, H: Hasher> HashDB<H, DBValue> for KeySpacedDBMut<'a, H> {
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<DBValue> {
+		let prefix = keyspaced(self.keyspace.as_ref(), prefix);
+		self.db.get(key, (&prefix.0, prefix.1))
+	}
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		let prefix = keyspaced(self.keyspace.as_ref(), prefix);
+		self.db.contains(key, (&prefix.0, prefix.1))
+	}
+
+	fn insert(&mut self, prefix: Prefix, value: &[u8]) -> H::Out {
+		let prefix = keyspaced(self.keyspace.as_ref(), prefix);
+		self.db.insert((&prefix.0, prefix.1), value)
+	}
+
+	fn emplace(&mut self, key: H::Out, prefix: Prefix, value: DBValue) {
+		let prefix = keyspaced(self.keyspace.as_ref(), prefix);
+		self.db.emplace(key, (&prefix.0, prefix.1), value)
+	}
+
+	fn remove(&mut self, key: &H::Out, prefix: Prefix) {
+		let prefix = keyspaced(self.keyspace.as_ref(), prefix);
+		self.db.remove(key, (&prefix.0, prefix.1))
+	}
+
+	fn emplace_batch(&mut self, items: Vec<(H::Out, OwnedPrefix, DBValue)>) {
+		let keyspace = self.keyspace;
+		self.db.emplace_batch(items.into_iter()
+			.map(|(key, prefix, value)| {
+				(key, keyspaced(keyspace.as_ref(), (&prefix.0, prefix.1)), value)
+			})
+			.collect())
+	}
+
+	fn remove_batch(&mut self, items: Vec<(H::Out, OwnedPrefix)>) {
+		let keyspace = self.keyspace;
+		self.db.remove_batch(items.into_iter()
+			.map(|(key, prefix)| (key, keyspaced(keyspace.as_ref(), (&prefix.0, prefix.1))))
+			.collect())
+	}
+}
+
+impl<'a, H: Hasher> HashDBRef<H, DBValue> for KeySpacedDBMut<'a, H> {
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<DBValue> {
+		HashDB::get(self, key, prefix)
+	}
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		HashDB::contains(self, key, prefix)
+	}
+}
+
+impl<'a, H: Hasher> AsHashDB<H, DBValue> for KeySpacedDBMut<'a, H> {
+	fn as_hash_db(&self) -> &dyn HashDB<H, DBValue> { self }
+	fn as_hash_db_mut<'b>(&'b mut self) -> &'b mut (dyn HashDB<H, DBValue> + 'b) { self }
+}
+
+/// Database of a child trie: the nodes of the keyspace of the child trie, and the empty node,
+/// which an empty child trie does not store.
+struct ChildDB<'db, L: TrieLayout>(KeySpacedDB<'db, L::Hash>);
+
+impl<'db, L: TrieLayout> Clone for ChildDB<'db, L> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+
+impl<'db, L: TrieLayout> Copy for ChildDB<'db, L> {}
+
+impl<'db, L: TrieLayout> HashDBRef<L::Hash, DBValue> for ChildDB<'db, L> {
+	fn get(&self, key: &TrieHash<L>, prefix: Prefix) -> Option<DBValue> {
+		match *key == L::Codec::hashed_null_node() {
+			true => Some(L::Codec::empty_node().to_vec()),
+			false => self.0.get(key, prefix),
+		}
+	}
+
+	fn contains(&self, key: &TrieHash<L>, prefix: Prefix) -> bool {
+		*key == L::Codec::hashed_null_node() || self.0.contains(key, prefix)
+	}
+}
+
+/// A read-only child trie, see `TrieDB::child_trie`.
+pub struct ChildTrie<'db, L: TrieLayout> {
+	db: ChildDB<'db, L>,
+	root: TrieHash<L>,
+}
+
+impl<'db, L: TrieLayout> ChildTrie<'db, L> {
+	/// Open the child trie at `root` in `keyspace` of `db`.
+	/// Returns an error if `root` does not exist.
+	pub(crate) fn new(
+		db: &'db dyn HashDBRef<L::Hash, DBValue>,
+		keyspace: TrieHash<L>,
+		root: TrieHash<L>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		let db = ChildDB(KeySpacedDB::new(db, keyspace));
+		if !db.contains(&root, EMPTY_PREFIX) {
+			return Err(Box::new(TrieError::InvalidStateRoot(root)));
+		}
+		Ok(ChildTrie { db, root })
+	}
+
+	/// A `TrieDB` over the child trie, for the reads `Trie` does not offer.
+	pub fn trie(&self) -> TrieDB<'_, L> {
+		TrieDB::new_unchecked(&self.db, &self.root)
+	}
+}
+
+impl<'db, L: TrieLayout> Trie<L> for ChildTrie<'db, L> {
+	fn root(&self) -> &TrieHash<L> { &self.root }
+
+	fn get_with<'a, 'key, Q: Query<L::Hash>>(&'a self, key: &'key [u8], query: Q)
+		-> Result<Option<Q::Item>, TrieHash<L>, CError<L>>
+		where 'a: 'key
+	{
+		self.trie().get_with_at(key, self.root, 0, query)
+	}
+
+	fn iter<'a>(&'a self) -> Result<
+		Box<dyn TrieIterator<L, Item = TrieItem<'a, TrieHash<L>, CError<L>>> + 'a>,
+		TrieHash<L>,
+		CError<L>,
+	> {
+		Ok(Box::new(OwnedTrieDBIterator::new(self.db, self.root)?))
+	}
+}
@@ -0,0 +1,301 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Commitments to small key-value maps, built in memory.
+//!
+//! A `Commitment` holds the whole trie of a set of key-value pairs in memory, without any
+//! `HashDB`. It gives the root of the trie and proves some of its entries with the compact
+//! proofs of the `proof` module, so proofs interoperate with the ones of a `TrieDB` holding
+//! the same pairs. It is meant for small maps, as all the nodes are kept in memory.
+
+use hash_db::{HashDBRef, Hasher, Prefix};
+use hashbrown::HashMap;
+use crate::iter_build::{trie_visit, ProcessEncodedNode};
+use crate::proof::{generate_proof, verify_proof, VerifyError};
+use crate::rstd::vec::Vec;
+use crate::{CError, ChildReference, DBValue, TrieDB, TrieHash, TrieLayout};
+
+/// The trie of a set of key-value pairs, held in memory.
+pub struct Commitment<L: TrieLayout> {
+	root: TrieHash<L>,
+	nodes: HashMap<TrieHash<L>, DBValue>,
+	len: usize,
+}
+
+impl<L: TrieLayout> Commitment<L> {
+	/// Build the trie of `pairs`.
+	///
+	/// Pairs are sorted, and when a key is given several times its last value is kept. Pairs
+	/// with an empty value are skipped, as inserting an empty value in a `TrieDBMut` removes
+	/// the key. The trie of an empty set has the root of the empty trie.
+	pub fn from_pairs<I, K, V>(pairs: I) -> Self
+		where
+			I: IntoIterator<Item = (K, V)>,
+			K: AsRef<[u8]>,
+			V: AsRef<[u8]>,
+	{
+		let mut sorted: Vec<(K, V)> = pairs.into_iter().collect();
+		// the sort is stable, so the last value of a key is the last of its run.
+		sorted.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+		let mut unique: Vec<(K, V)> = Vec::with_capacity(sorted.len());
+		for pair in sorted {
+			match unique.last_mut() {
+				Some(last) if last.0.as_ref() == pair.0.as_ref() => *last = pair,
+				_ => unique.push(pair),
+			}
+		}
+		unique.retain(|(_, value)| !value.as_ref().is_empty());
+
+		let mut collector = NodeCollector::<L> { root: None, nodes: HashMap::new() };
+		trie_visit::<L, _, _, _, _>(
+			unique.iter().map(|(key, value)| (key.as_ref(), value.as_ref())),
+			&mut collector,
+		);
+		Commitment {
+			root: collector.root.expect("the root is always processed by trie_visit; qed"),
+			nodes: collector.nodes,
+			len: unique.len(),
+		}
+	}
+
+	/// Root of the trie.
+	pub fn root(&self) -> &TrieHash<L> {
+		&self.root
+	}
+
+	/// Number of keys in the trie.
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Returns true if the trie has no key.
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Read only trie view over the commitment.
+	pub fn as_trie(&self) -> TrieDB<'_, L> {
+		TrieDB::new_unchecked(self, &self.root)
+	}
+
+	/// Generate a compact proof of the values of `keys`, which may be absent from the trie.
+	pub fn prove<'a, I, K>(&self, keys: I) -> Vec<Vec<u8>>
+		where
+			I: IntoIterator<Item = &'a K>,
+			K: 'a + AsRef<[u8]>,
+	{
+		match generate_proof(&self.as_trie(), keys) {
+			Ok(proof) => proof,
+			Err(_) => unreachable!("all the nodes of the trie are held in memory; qed"),
+		}
+	}
+
+	/// Verify a proof of `items` against `root`, as generated by `prove` or by
+	/// `proof::generate_proof` on a trie holding the same pairs. A `None` value proves the
+	/// absence of the key.
+	pub fn verify<'a, I, K, V>(
+		root: &TrieHash<L>,
+		proof: &[Vec<u8>],
+		items: I,
+	) -> Result<(), VerifyError<TrieHash<L>, CError<L>>>
+		where
+			I: IntoIterator<Item = &'a (K, Option<V>)>,
+			K: 'a + AsRef<[u8]>,
+			V: 'a + AsRef<[u8]>,
+	{
+		verify_proof::<L, _, _, _>(root, proof, items).map(|_| ())
+	}
+}
+
+// Manual implementations, deriving would require the bounds on the layout itself.
+impl<L: TrieLayout> Clone for Commitment<L> {
+	fn clone(&self) -> Self {
+		Commitment { root: self.root, nodes: self.nodes.clone(), len: self.len }
+	}
+}
+
+#[cfg(feature = "std")]
+impl<L: TrieLayout> std::fmt::Debug for Commitment<L> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("Commitment")
+			.field("root", &self.root)
+			.field("len", &self.len)
+			.field("nodes", &self.nodes)
+			.finish()
+	}
+}
+
+impl<L: TrieLayout> HashDBRef<L::Hash, DBValue> for Commitment<L> {
+	fn get(&self, key: &TrieHash<L>, _prefix: Prefix) -> Option<DBValue> {
+		self.nodes.get(key).cloned()
+	}
+
+	fn contains(&self, key: &TrieHash<L>, _prefix: Prefix) -> bool {
+		self.nodes.contains_key(key)
+	}
+}
+
+/// Collect the nodes visited by `trie_visit`, indexed by hash.
+struct NodeCollector<L: TrieLayout> {
+	root: Option<TrieHash<L>>,
+	nodes: HashMap<TrieHash<L>, DBValue>,
+}
+
+impl<L: TrieLayout> ProcessEncodedNode<TrieHash<L>> for NodeCollector<L> {
+	fn process(
+		&mut self,
+		_prefix: Prefix,
+		encoded_node: Vec<u8>,
+		is_root: bool,
+	) -> ChildReference<TrieHash<L>> {
+		let len = encoded_node.len();
+		if !is_root && len < <L::Hash as Hasher>::LENGTH {
+			let mut h = <TrieHash<L> as Default>::default();
+			h.as_mut()[..len].copy_from_slice(&encoded_node[..len]);
+
+			return ChildReference::Inline(h, len);
+		}
+		let hash = <L::Hash as Hasher>::hash(&encoded_node[..]);
+		self.nodes.insert(hash, encoded_node);
+		if is_root {
+			self.root = Some(hash);
+		}
+		ChildReference::Hash(hash)
+	}
+	fn process_value(&mut self, _prefix: Prefix, value: &[u8]) {
+		self.nodes.insert(<L::Hash as Hasher>::hash(value), value.to_vec());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{
+		commitment::Commitment, proof::{generate_proof, verify_proof, VerifyError},
+		ExtensionLayout, NoExtensionLayout, NodeCodec, Trie, TrieDB, TrieDBMut, TrieLayout, TrieMut,
+	};
+	use crate::DBValue;
+
+	type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+
+	fn entries() -> Vec<(&'static [u8], &'static [u8])> {
+		vec![
+			(b"alfa", &[0; 32]),
+			(b"bravo", b"bravo"),
+			(b"do", b"verb"),
+			(b"dog", b"puppy"),
+			(b"doge", &[0; 32]),
+			(b"horse", b"stallion"),
+			(b"house", b"building"),
+		]
+	}
+
+	#[test]
+	fn empty_commitment() {
+		let commitment = Commitment::<ExtensionLayout>::from_pairs(Vec::<(Vec<u8>, Vec<u8>)>::new());
+		assert!(commitment.is_empty());
+		assert_eq!(
+			*commitment.root(),
+			<ExtensionLayout as TrieLayout>::Codec::hashed_null_node(),
+		);
+		assert_eq!(commitment.as_trie().get(b"alfa").unwrap(), None);
+
+		let proof = commitment.prove(&[b"alfa"]);
+		let items: Vec<(&[u8], Option<&[u8]>)> = vec![(b"alfa", None)];
+		Commitment::<ExtensionLayout>::verify(commitment.root(), &proof, &items).unwrap();
+	}
+
+	#[test]
+	fn single_entry_commitment() {
+		let commitment = Commitment::<NoExtensionLayout>::from_pairs(vec![(b"dog", b"puppy")]);
+		assert_eq!(commitment.len(), 1);
+		assert_eq!(commitment.as_trie().get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+
+		let keys: [&[u8]; 2] = [b"dog", b"do"];
+		let proof = commitment.prove(&keys);
+		let items: Vec<(&[u8], Option<&[u8]>)> = vec![(b"dog", Some(b"puppy")), (b"do", None)];
+		Commitment::<NoExtensionLayout>::verify(commitment.root(), &proof, &items).unwrap();
+
+		let items: Vec<(&[u8], Option<&[u8]>)> = vec![(b"dog", Some(b"kitty")), (b"do", None)];
+		match Commitment::<NoExtensionLayout>::verify(commitment.root(), &proof, &items) {
+			Err(VerifyError::RootMismatch(_)) => (),
+			result => panic!("unexpected verification result: {:?}", result),
+		}
+	}
+
+	#[test]
+	fn commitments_clone() {
+		let commitment = Commitment::<ExtensionLayout>::from_pairs(entries());
+		let copy = commitment.clone();
+		assert_eq!(copy.root(), commitment.root());
+		assert_eq!(copy.len(), commitment.len());
+		assert_eq!(copy.as_trie().get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+		assert!(format!("{:?}", copy).starts_with("Commitment"));
+	}
+
+	#[test]
+	fn duplicate_keys_keep_last_value() {
+		let mut pairs = entries();
+		pairs.reverse();
+		pairs.insert(0, (b"dog", b"kitty"));
+		pairs.push((b"horse", b"pony"));
+		pairs.push((b"zebra", b""));
+		let commitment = Commitment::<ExtensionLayout>::from_pairs(pairs);
+
+		let mut expected = entries();
+		let horse = expected.len() - 2;
+		expected[horse].1 = b"pony";
+		let expected_commitment = Commitment::<ExtensionLayout>::from_pairs(expected.clone());
+		assert_eq!(commitment.root(), expected_commitment.root());
+		assert_eq!(commitment.len(), expected.len());
+		let pairs: Vec<_> = commitment.as_trie().iter().unwrap().map(|item| item.unwrap()).collect();
+		let expected: Vec<_> = expected.into_iter()
+			.map(|(key, value)| (key.to_vec(), value.to_vec()))
+			.collect();
+		assert_eq!(pairs, expected);
+	}
+
+	fn check_proof_interop<L: TrieLayout<Hash = KeccakHasher>>() {
+		let mut db = MemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+			for (key, value) in entries() {
+				t.insert(key, value).unwrap();
+			}
+		}
+		let commitment = Commitment::<L>::from_pairs(entries());
+		assert_eq!(*commitment.root(), root);
+
+		let keys: Vec<&[u8]> = vec![b"alfa", b"dog", b"horse", b"hotel"];
+		let items: Vec<(&[u8], Option<&[u8]>)> = vec![
+			(b"alfa", Some(&[0; 32])),
+			(b"dog", Some(b"puppy")),
+			(b"horse", Some(b"stallion")),
+			(b"hotel", None),
+		];
+		let trie = TrieDB::<L>::new(&db, &root).unwrap();
+		let trie_proof = generate_proof::<_, L, _, _>(&trie, &keys).unwrap();
+		let proof = commitment.prove(&keys);
+		assert_eq!(proof, trie_proof);
+		Commitment::<L>::verify(&root, &trie_proof, &items).unwrap();
+		verify_proof::<L, _, _, _>(&root, &proof, &items).unwrap();
+	}
+
+	#[test]
+	fn proofs_interoperate_with_triedb() {
+		check_proof_interop::<ExtensionLayout>();
+		check_proof_interop::<NoExtensionLayout>();
+	}
+}
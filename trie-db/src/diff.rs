@@ -0,0 +1,248 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Differences between the key-value pairs of two tries.
+
+use crate::iterator::{SharedNode, TrieDBNodeIterator};
+use crate::nibble::{NibbleOps, NibbleSlice, NibbleVec};
+use crate::rstd::{cmp::Ordering, vec::Vec};
+use crate::triedb::value_item;
+use super::{DBValue, Result, TrieDB, TrieHash, TrieLayout, CError};
+
+/// A difference between the pairs of two tries, see `trie_diff`.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Clone, Eq, PartialEq)]
+pub enum DiffItem {
+	/// A key only in the second trie, with its value.
+	Added(Vec<u8>, DBValue),
+	/// A key only in the first trie, with its value.
+	Removed(Vec<u8>, DBValue),
+	/// A key in both tries with different values, with its value in the first and in the second
+	/// trie.
+	Changed(Vec<u8>, DBValue, DBValue),
+}
+
+/// Iterator over the differences between the pairs of two tries, by key, see `trie_diff`.
+pub struct DiffIterator<'a, L: TrieLayout> {
+	from: DiffSide<'a, L>,
+	to: DiffSide<'a, L>,
+}
+
+/// Walk the nodes of `from` and `to` together, returning the keys added, removed or changed from
+/// `from` to `to` in key order.
+///
+/// The subtrees with the same hash at the same position in both tries hold the same pairs: they
+/// are skipped without fetching their children, so that the nodes read are the ones on the paths
+/// to the differences and their children. Missing nodes are returned as errors, the iteration
+/// going on after them.
+pub fn trie_diff<'a, L: TrieLayout>(
+	from: &'a TrieDB<L>,
+	to: &'a TrieDB<L>,
+) -> Result<DiffIterator<'a, L>, TrieHash<L>, CError<L>> {
+	Ok(DiffIterator {
+		from: DiffSide::new(from)?,
+		to: DiffSide::new(to)?,
+	})
+}
+
+/// A node yielded by a node iterator, with its prefix and its hash, `None` for inline nodes.
+type Head<L> = (NibbleVec<LayoutNibble<L>>, Option<TrieHash<L>>, SharedNode<LayoutNibble<L>>);
+
+/// Nibble width of the layout `L`.
+type LayoutNibble<L> = <L as TrieLayout>::Nibble;
+
+/// The walk of one of the tries of a `DiffIterator`.
+struct DiffSide<'a, L: TrieLayout> {
+	iter: TrieDBNodeIterator<'a, L>,
+	/// The next node, not visited yet.
+	head: Option<Head<L>>,
+	/// Whether the iterator is over.
+	done: bool,
+	/// The pair of a visited node, not returned or matched with the other trie yet.
+	pending: Option<(Vec<u8>, DBValue)>,
+}
+
+impl<'a, L: TrieLayout> DiffSide<'a, L> {
+	fn new(db: &'a TrieDB<L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		Ok(DiffSide {
+			iter: TrieDBNodeIterator::new(db)?,
+			head: None,
+			done: false,
+			pending: None,
+		})
+	}
+
+	/// The next node, `None` once all the nodes are visited.
+	fn head(&mut self) -> Result<Option<&Head<L>>, TrieHash<L>, CError<L>> {
+		if self.head.is_none() && !self.done {
+			match self.iter.next() {
+				Some(Ok(head)) => self.head = Some(head),
+				Some(Err(err)) => return Err(err),
+				None => self.done = true,
+			}
+		}
+		Ok(self.head.as_ref())
+	}
+
+	/// Visit the next node, keeping its pair as pending.
+	fn visit(&mut self) -> Result<(), TrieHash<L>, CError<L>> {
+		if let Some((prefix, hash, node)) = self.head.take() {
+			self.pending = value_item(self.iter.trie(), &prefix, hash, &node).transpose()?;
+		}
+		Ok(())
+	}
+
+	/// Skip the next node and its subtree.
+	fn skip(&mut self) {
+		if self.head.take().is_some() {
+			self.iter.skip_children();
+		}
+	}
+
+	/// Whether no pair at or after the node at `head` can be at `key`: the later nodes of a
+	/// pre-order walk are at or after it by key.
+	fn passed(&mut self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
+		Ok(match self.head()? {
+			Some((prefix, _, _)) => key_before(key, prefix),
+			None => true,
+		})
+	}
+}
+
+/// Outcome of a step of a `DiffIterator`.
+enum Step<T> {
+	Yield(T),
+	Continue,
+	Done,
+}
+
+impl<'a, L: TrieLayout> DiffIterator<'a, L> {
+	/// Return the pending pair of `side` as `item` once `other` cannot hold its key, otherwise
+	/// visit the next node of `other`.
+	fn resolve(
+		side: &mut DiffSide<'a, L>,
+		other: &mut DiffSide<'a, L>,
+		item: fn(Vec<u8>, DBValue) -> DiffItem,
+	) -> Step<Result<DiffItem, TrieHash<L>, CError<L>>> {
+		let passed = match &side.pending {
+			Some((key, _)) => other.passed(key),
+			None => return Step::Continue,
+		};
+		let visited = match passed {
+			Ok(true) => match side.pending.take() {
+				Some((key, value)) => return Step::Yield(Ok(item(key, value))),
+				None => return Step::Continue,
+			},
+			Ok(false) => other.visit(),
+			Err(err) => Err(err),
+		};
+		match visited {
+			Ok(()) => Step::Continue,
+			Err(err) => Step::Yield(Err(err)),
+		}
+	}
+
+	fn step(&mut self) -> Step<Result<DiffItem, TrieHash<L>, CError<L>>> {
+		let (from, to) = (&mut self.from, &mut self.to);
+		match (from.pending.take(), to.pending.take()) {
+			(Some((key, old)), Some((other_key, new))) => match key.cmp(&other_key) {
+				Ordering::Equal if old == new => Step::Continue,
+				Ordering::Equal => Step::Yield(Ok(DiffItem::Changed(key, old, new))),
+				Ordering::Less => {
+					to.pending = Some((other_key, new));
+					Step::Yield(Ok(DiffItem::Removed(key, old)))
+				},
+				Ordering::Greater => {
+					from.pending = Some((key, old));
+					Step::Yield(Ok(DiffItem::Added(other_key, new)))
+				},
+			},
+			(pending @ Some(_), None) => {
+				from.pending = pending;
+				Self::resolve(from, to, DiffItem::Removed)
+			},
+			(None, pending @ Some(_)) => {
+				to.pending = pending;
+				Self::resolve(to, from, DiffItem::Added)
+			},
+			(None, None) => {
+				let order = match (from.head(), to.head()) {
+					(Err(err), _) | (_, Err(err)) => return Step::Yield(Err(err)),
+					(Ok(None), Ok(None)) => return Step::Done,
+					(Ok(Some(_)), Ok(None)) => Ordering::Less,
+					(Ok(None), Ok(Some(_))) => Ordering::Greater,
+					(Ok(Some(head)), Ok(Some(other_head))) => {
+						let order = compare(&head.0, &other_head.0);
+						if order == Ordering::Equal && same_subtree::<L>(head, other_head) {
+							from.skip();
+							to.skip();
+							return Step::Continue;
+						}
+						order
+					},
+				};
+				let visited = match order {
+					Ordering::Less => from.visit(),
+					Ordering::Greater => to.visit(),
+					Ordering::Equal => from.visit().and_then(|()| to.visit()),
+				};
+				match visited {
+					Ok(()) => Step::Continue,
+					Err(err) => Step::Yield(Err(err)),
+				}
+			},
+		}
+	}
+}
+
+impl<'a, L: TrieLayout> Iterator for DiffIterator<'a, L> {
+	type Item = Result<DiffItem, TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			match self.step() {
+				Step::Yield(item) => return Some(item),
+				Step::Continue => {},
+				Step::Done => return None,
+			}
+		}
+	}
+}
+
+/// Whether the nodes at the same position hold the same subtree, comparing the encoded inline
+/// nodes.
+fn same_subtree<L: TrieLayout>(head: &Head<L>, other_head: &Head<L>) -> bool {
+	match (&head.1, &other_head.1) {
+		(Some(hash), Some(other_hash)) => hash == other_hash,
+		(None, None) => head.2.data() == other_head.2.data(),
+		_ => false,
+	}
+}
+
+/// Order of two nodes in a pre-order walk, from their prefixes.
+fn compare<N: NibbleOps>(prefix: &NibbleVec<N>, other: &NibbleVec<N>) -> Ordering {
+	(0..prefix.len().min(other.len()))
+		.map(|i| prefix.at(i).cmp(&other.at(i)))
+		.find(|order| *order != Ordering::Equal)
+		.unwrap_or_else(|| prefix.len().cmp(&other.len()))
+}
+
+/// Whether `key` is before all the keys starting with `prefix` or after it.
+fn key_before<N: NibbleOps>(key: &[u8], prefix: &NibbleVec<N>) -> bool {
+	let key = NibbleSlice::<N>::new(key);
+	(0..key.len().min(prefix.len()))
+		.map(|i| key.at(i).cmp(&prefix.at(i)))
+		.find(|order| *order != Ordering::Equal)
+		.map_or(key.len() < prefix.len(), |order| order == Ordering::Less)
+}
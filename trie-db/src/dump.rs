@@ -0,0 +1,292 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Human-readable dumps of the nodes of a trie, see `TrieDB::fmt_tree` and `TrieDB::fmt_dot`.
+//!
+//! Nodes are decoded without checking them against the layout, and nodes which cannot be read
+//! are shown in place of their subtree, so a dump can be taken of a broken trie.
+
+use crate::nibble::{Children, NibbleOps, NibbleSlice, NibbleVec};
+use crate::node::{decode_hash, Node, NodeHandle};
+use crate::rstd::{fmt, vec, vec::Vec};
+use crate::{Trie, TrieDB, TrieError, TrieHash, TrieLayout};
+
+/// Options of `TrieDB::fmt_tree` and `TrieDB::fmt_dot`.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct DumpOptions {
+	/// Depth of the deepest nodes shown, the root being at depth 0. All the nodes are shown if
+	/// `None`.
+	pub max_depth: Option<usize>,
+	/// Only the nodes of the keys starting with `prefix`, and the nodes on the path to them, are
+	/// shown.
+	pub prefix: Vec<u8>,
+	/// Whether the hashes of the nodes are shown.
+	pub hashes: bool,
+	/// Values of at most `max_inline_value` bytes are shown in hex, the others by their
+	/// length.
+	pub max_inline_value: usize,
+}
+
+impl Default for DumpOptions {
+	fn default() -> Self {
+		DumpOptions { max_depth: None, prefix: Vec::new(), hashes: true, max_inline_value: 16 }
+	}
+}
+
+/// Graphviz DOT output, or else an indented tree.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+	Tree,
+	Dot,
+}
+
+/// Write the nodes of `trie` to `out` in `format`.
+pub(crate) fn dump<L: TrieLayout>(
+	trie: &TrieDB<L>,
+	out: &mut impl fmt::Write,
+	options: &DumpOptions,
+	format: Format,
+) -> fmt::Result {
+	let mut dumper = Dumper {
+		trie,
+		options,
+		prefix: NibbleSlice::new(&options.prefix),
+		format,
+		out,
+		nodes: 0,
+	};
+	if format == Format::Dot {
+		writeln!(dumper.out, "digraph trie {{")?;
+		writeln!(dumper.out, "\tnode [shape=box, fontname=monospace];")?;
+	}
+	let root = *trie.root();
+	dumper.dump_node(root, NodeHandle::Hash(root.as_ref()), &mut NibbleVec::new(), None)?;
+	if format == Format::Dot {
+		writeln!(dumper.out, "}}")?;
+	}
+	Ok(())
+}
+
+struct Dumper<'a, 'db, L: TrieLayout, W> {
+	trie: &'a TrieDB<'db, L>,
+	options: &'a DumpOptions,
+	/// Nibbles of `options.prefix`.
+	prefix: NibbleSlice<'a, L::Nibble>,
+	format: Format,
+	out: &'a mut W,
+	/// Number of nodes written, identifying the nodes of the DOT output.
+	nodes: usize,
+}
+
+/// Edge to a node from its parent: the identifier of the parent in the DOT output, the depth
+/// of the node and its index in the parent branch, if any.
+type Edge = (usize, usize, Option<u8>);
+
+impl<'a, 'db, L: TrieLayout, W: fmt::Write> Dumper<'a, 'db, L, W> {
+	/// Whether the node at `key` is in the subtree to show or on the path to it.
+	fn on_path(&self, key: &NibbleVec<L::Nibble>) -> bool {
+		(0..key.len().min(self.prefix.len())).all(|i| key.at(i) == self.prefix.at(i))
+	}
+
+	/// Field separator of the labels.
+	fn separator(&self) -> &'static str {
+		match self.format {
+			Format::Tree => " ",
+			Format::Dot => "\\n",
+		}
+	}
+
+	/// Write the child of `parent_hash` at `key`, reached through `edge`, and its subtree.
+	fn dump_node(
+		&mut self,
+		parent_hash: TrieHash<L>,
+		handle: NodeHandle,
+		key: &mut NibbleVec<L::Nibble>,
+		edge: Option<Edge>,
+	) -> fmt::Result {
+		let id = self.nodes;
+		self.nodes += 1;
+		let depth = edge.map_or(0, |(_, depth, _)| depth);
+		match self.format {
+			Format::Tree => {
+				for _ in 0..depth {
+					self.out.write_str("  ")?;
+				}
+				if let Some((_, _, Some(index))) = edge {
+					write!(self.out, "{:x}: ", index)?;
+				}
+			},
+			Format::Dot => write!(self.out, "\tn{} [label=\"", id)?,
+		}
+
+		let node = match self.trie.get_lenient_node(parent_hash, handle, key.as_prefix()) {
+			Ok(node) => node,
+			Err(error) => {
+				let reference = match handle {
+					NodeHandle::Hash(data) => decode_hash::<L::Hash>(data),
+					NodeHandle::Inline(_) => None,
+				};
+				let description = match *error {
+					TrieError::InvalidStateRoot(_) | TrieError::IncompleteDatabase { .. } =>
+						"missing node",
+					TrieError::HashMismatch { .. } => "node with another hash",
+					TrieError::DecoderError(..) => "undecodable node",
+					TrieError::InvalidHash(..) => "invalid child hash",
+					_ => "unreadable node",
+				};
+				self.out.write_str(description)?;
+				self.write_hash(reference)?;
+				return self.end_node(id, edge);
+			},
+		};
+		let (node, node_hash) = node;
+		let sep = self.separator();
+		let children = match node.node() {
+			Node::Empty => {
+				self.out.write_str("empty")?;
+				None
+			},
+			Node::Leaf(partial, value) => {
+				self.out.write_str("leaf")?;
+				self.write_partial(&partial)?;
+				self.write_value(Some(value))?;
+				None
+			},
+			Node::Extension(partial, child) => {
+				self.out.write_str("extension")?;
+				self.write_partial(&partial)?;
+				Some((partial, vec![(None, child)]))
+			},
+			Node::Branch(children, value) => {
+				self.out.write_str("branch")?;
+				self.write_value(value)?;
+				self.write_children(&children)?;
+				Some((NibbleSlice::new(&[]), branch_children(children)))
+			},
+			Node::NibbledBranch(partial, children, value) => {
+				self.out.write_str("branch")?;
+				self.write_partial(&partial)?;
+				self.write_value(value)?;
+				self.write_children(&children)?;
+				Some((partial, branch_children(children)))
+			},
+		};
+		match node_hash {
+			Some(hash) => self.write_hash(Some(hash))?,
+			None if self.options.hashes => write!(self.out, "{}inline", sep)?,
+			None => (),
+		}
+		self.end_node(id, edge)?;
+
+		let (partial, children) = match (children, self.options.max_depth) {
+			(Some(_), Some(max_depth)) if depth >= max_depth => return Ok(()),
+			(Some(children), _) => children,
+			(None, _) => return Ok(()),
+		};
+		let node_hash = node_hash.unwrap_or(parent_hash);
+		key.append_partial(partial.right());
+		for (index, child) in children {
+			if let Some(index) = index {
+				key.push(index);
+			}
+			if self.on_path(key) {
+				self.dump_node(node_hash, child, key, Some((id, depth + 1, index)))?;
+			}
+			if index.is_some() {
+				key.pop();
+			}
+		}
+		key.drop_lasts(partial.len());
+		Ok(())
+	}
+
+	/// End the line of node `id`, and write the edge to it in the DOT output.
+	fn end_node(&mut self, id: usize, edge: Option<Edge>) -> fmt::Result {
+		match self.format {
+			Format::Tree => writeln!(self.out),
+			Format::Dot => {
+				writeln!(self.out, "\"];")?;
+				match edge {
+					Some((parent, _, Some(index))) =>
+						writeln!(self.out, "\tn{} -> n{} [label=\"{:x}\"];", parent, id, index),
+					Some((parent, _, None)) => writeln!(self.out, "\tn{} -> n{};", parent, id),
+					None => Ok(()),
+				}
+			},
+		}
+	}
+
+	fn write_partial(&mut self, partial: &NibbleSlice<L::Nibble>) -> fmt::Result {
+		if partial.is_empty() {
+			return Ok(());
+		}
+		write!(self.out, "{}partial ", self.separator())?;
+		for i in 0..partial.len() {
+			write!(self.out, "{:x}", partial.at(i))?;
+		}
+		Ok(())
+	}
+
+	fn write_value(&mut self, value: Option<&[u8]>) -> fmt::Result {
+		let value = match value {
+			Some(value) => value,
+			None => return Ok(()),
+		};
+		write!(self.out, "{}value ", self.separator())?;
+		if value.len() <= self.options.max_inline_value {
+			write_hex(self.out, value)
+		} else {
+			write!(self.out, "<{} bytes>", value.len())
+		}
+	}
+
+	fn write_children(&mut self, children: &[Option<NodeHandle>]) -> fmt::Result {
+		write!(self.out, "{}children ", self.separator())?;
+		for (index, child) in children.iter().enumerate() {
+			match child {
+				Some(_) => write!(self.out, "{:x}", index)?,
+				None => self.out.write_str(".")?,
+			}
+		}
+		Ok(())
+	}
+
+	fn write_hash(&mut self, hash: Option<TrieHash<L>>) -> fmt::Result {
+		match hash {
+			Some(hash) if self.options.hashes => {
+				write!(self.out, "{}hash ", self.separator())?;
+				write_hex(self.out, hash.as_ref())
+			},
+			_ => Ok(()),
+		}
+	}
+}
+
+/// The children of a branch, with their index.
+fn branch_children<'a, N: NibbleOps>(
+	children: Children<NodeHandle<'a>, N>,
+) -> Vec<(Option<u8>, NodeHandle<'a>)> {
+	children.iter().enumerate()
+		.filter_map(|(index, child)| child.map(|child| (Some(index as u8), child)))
+		.collect()
+}
+
+fn write_hex(out: &mut impl fmt::Write, bytes: &[u8]) -> fmt::Result {
+	out.write_str("0x")?;
+	for byte in bytes {
+		write!(out, "{:02x}", byte)?;
+	}
+	Ok(())
+}
@@ -13,8 +13,8 @@
 // limitations under the License.
 
 use hash_db::{HashDBRef, Hasher};
-use super::{Result, DBValue, TrieDB, Trie, TrieDBIterator, TrieItem, TrieIterator, Query,
-	TrieLayout, CError, TrieHash};
+use super::{Result, DBValue, TrieDB, Trie, TrieDBIterator, TrieError, TrieItem, TrieIterator,
+	Query, TrieLayout, CError, TrieHash};
 
 use crate::rstd::boxed::Box;
 
@@ -64,6 +64,7 @@ where
 		self.raw.get_with(L::Hash::hash(key).as_ref(), query)
 	}
 
+	/// Iterate over the pairs with their original keys, see `FatDBIterator`.
 	fn iter<'a>(&'a self) -> Result<
 		Box<dyn TrieIterator<L, Item = TrieItem<TrieHash<L>, CError<L>>> + 'a>,
 		TrieHash<L>,
@@ -73,7 +74,12 @@ where
 	}
 }
 
-/// Itarator over inserted pairs of key values.
+/// Iterator over the inserted pairs of a `FatDB`, with their original keys, in the order of
+/// the hashed keys.
+///
+/// `seek` and `seek_after` take an original key, and position the iterator at its hash. A key
+/// stored without its preimage, as by a `SecTrieDBMut`, is reported as
+/// `TrieError::IncompleteDatabase` with the hash of the preimage entry.
 pub struct FatDBIterator<'db, L>
 where
 	L: TrieLayout,
@@ -103,6 +109,11 @@ where
 		let hashed_key = L::Hash::hash(key);
 		self.trie_iterator.seek(hashed_key.as_ref())
 	}
+
+	fn seek_after(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		let hashed_key = L::Hash::hash(key);
+		self.trie_iterator.seek_after(hashed_key.as_ref())
+	}
 }
 
 impl<'db, L> Iterator for FatDBIterator<'db, L>
@@ -114,13 +125,15 @@ where
 	fn next(&mut self) -> Option<Self::Item> {
 		self.trie_iterator.next()
 			.map(|res| {
-				res.map(|(hash, value)| {
+				res.and_then(|(hash, value)| {
 					let aux_hash = L::Hash::hash(&hash);
-					(
-						self.trie.db().get(&aux_hash, Default::default())
-							.expect("Missing fatdb hash"),
-						value,
-					)
+					match self.trie.db().get(&aux_hash, Default::default()) {
+						Some(key) => Ok((key, value)),
+						None => Err(Box::new(TrieError::incomplete_database(
+							aux_hash,
+							Default::default(),
+						))),
+					}
 				})
 			})
 	}
@@ -0,0 +1,172 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read-only databases fetching their nodes with a closure.
+//!
+//! `FnHashDB` turns a closure into a `HashDBRef`, for backends reading nodes from elsewhere
+//! and for tests instrumenting a database. `fail_every_nth` and `flaky` build on it to inject
+//! read failures in a deterministic way.
+
+use hash_db::{HashDBRef, Hasher, Prefix};
+use crate::rstd::{cell::Cell, marker::PhantomData};
+use crate::DBValue;
+
+/// A read-only database calling a closure to get the nodes.
+///
+/// `contains` is implemented with `get`, so the closure is called for both.
+pub struct FnHashDB<H, F> {
+	get: F,
+	_marker: PhantomData<H>,
+}
+
+impl<H, F> FnHashDB<H, F>
+	where
+		H: Hasher,
+		F: Fn(&H::Out, Prefix) -> Option<DBValue>,
+{
+	/// Create a database returning the node `get(hash, prefix)` for `hash` at `prefix`.
+	pub fn new(get: F) -> Self {
+		FnHashDB { get, _marker: PhantomData }
+	}
+}
+
+impl<H, F> HashDBRef<H, DBValue> for FnHashDB<H, F>
+	where
+		H: Hasher,
+		F: Fn(&H::Out, Prefix) -> Option<DBValue>,
+{
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<DBValue> {
+		(self.get)(key, prefix)
+	}
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		self.get(key, prefix).is_some()
+	}
+}
+
+/// A view of `db` where every `n`-th read fails, the first read being counted as one.
+///
+/// Panics if `n` is zero.
+pub fn fail_every_nth<'a, H: Hasher>(
+	db: &'a dyn HashDBRef<H, DBValue>,
+	n: u64,
+) -> FnHashDB<H, impl Fn(&H::Out, Prefix) -> Option<DBValue> + 'a> {
+	assert!(n > 0, "a read out of zero cannot fail");
+	let reads = Cell::new(0u64);
+	FnHashDB::new(move |key: &H::Out, prefix| {
+		reads.set(reads.get() + 1);
+		match reads.get() % n {
+			0 => None,
+			_ => db.get(key, prefix),
+		}
+	})
+}
+
+/// A view of `db` where reads fail at random, one in `one_in` on average.
+///
+/// The failures only depend on `seed` and on the number of reads made: a given sequence of
+/// reads always fails at the same places, and a failed read can succeed when retried.
+///
+/// Panics if `one_in` is zero.
+pub fn flaky<'a, H: Hasher>(
+	db: &'a dyn HashDBRef<H, DBValue>,
+	seed: u64,
+	one_in: u64,
+) -> FnHashDB<H, impl Fn(&H::Out, Prefix) -> Option<DBValue> + 'a> {
+	assert!(one_in > 0, "a read out of zero cannot fail");
+	// xorshift state, which must not be zero.
+	let state = Cell::new(seed | 1);
+	FnHashDB::new(move |key: &H::Out, prefix| {
+		let mut x = state.get();
+		x ^= x << 13;
+		x ^= x >> 7;
+		x ^= x << 17;
+		state.set(x);
+		match x % one_in {
+			0 => None,
+			_ => db.get(key, prefix),
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::Cell;
+
+	use hash_db::{HashDBRef, Hasher};
+	use keccak_hasher::KeccakHasher;
+	use memory_db::{HashKey, MemoryDB};
+	use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieError, TrieMut};
+	use super::{fail_every_nth, flaky, FnHashDB};
+	use crate::DBValue;
+
+	type HashedMemoryDB = MemoryDB<KeccakHasher, HashKey<KeccakHasher>, DBValue>;
+
+	fn build_trie() -> (HashedMemoryDB, <KeccakHasher as Hasher>::Out) {
+		let mut db = MemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			for i in 0..100u32 {
+				t.insert(&i.to_be_bytes(), &[i as u8; 32]).unwrap();
+			}
+		}
+		(db, root)
+	}
+
+	#[test]
+	fn closure_reads_nodes() {
+		let (memdb, root) = build_trie();
+		let reads = Cell::new(0);
+		let db = FnHashDB::new(|key: &_, prefix| {
+			reads.set(reads.get() + 1);
+			HashDBRef::get(&memdb, key, prefix)
+		});
+		let t = RefTrieDB::new(&db, &root).unwrap();
+		assert_eq!(t.get(&7u32.to_be_bytes()).unwrap(), Some(vec![7; 32]));
+		assert_eq!(t.iter().unwrap().count(), 100);
+		assert!(reads.get() > 0);
+
+		let empty = FnHashDB::<KeccakHasher, _>::new(|_: &_, _| None);
+		assert!(RefTrieDB::new(&empty, &root).is_err());
+	}
+
+	#[test]
+	fn every_nth_read_fails() {
+		let (memdb, root) = build_trie();
+		let db = fail_every_nth(&memdb, 3);
+		// the root check and the read of the root succeed, the read of its child fails.
+		let t = RefTrieDB::new(&db, &root).unwrap();
+		match t.get(&7u32.to_be_bytes()).map_err(|e| *e) {
+			Err(TrieError::IncompleteDatabase { .. }) => {}
+			result => panic!("expected TrieError::IncompleteDatabase, got {:?}", result),
+		}
+		let items: Vec<_> = t.iter().unwrap().collect();
+		assert!(items.iter().any(|item| item.is_err()));
+	}
+
+	#[test]
+	fn flaky_reads_are_deterministic() {
+		let (memdb, root) = build_trie();
+		let read_all = |seed| {
+			let db = flaky(&memdb, seed, 4);
+			let t = RefTrieDB::new(&db, &root).unwrap();
+			(0..100u32).map(|i| t.get(&i.to_be_bytes()).is_ok()).collect::<Vec<_>>()
+		};
+		let reads = read_all(42);
+		assert_eq!(read_all(42), reads);
+		assert!(reads.iter().any(|ok| *ok));
+		assert!(reads.iter().any(|ok| !*ok));
+	}
+}
@@ -0,0 +1,212 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Interning of the child hashes of the nodes kept in memory.
+//!
+//! Tries sharing subtrees reference the same child hashes from many nodes, each encoded node
+//! holding its own copy. An `InternedNode` keeps its child hashes out of its encoding, in a
+//! `HashInterner` pool, so that a hash referenced by several nodes has a single allocation.
+//!
+//! The pool holds an `Arc` of each hash: `HashInterner::collect_garbage` drops the hashes no
+//! node references anymore. A hash only referenced by one node takes more memory interned
+//! than in its encoding: the pool pays off for the nodes of tries sharing most of their
+//! subtrees, such as the tries of successive roots.
+//!
+//! A `NodeCache` created `with_interning` owns a pool and keeps the nodes cached by the
+//! lookups and the iterations of its tries as `InternedNode`s, see `NodeCache::with_interning`.
+
+use std::collections::HashSet;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use hash_db::Hasher;
+use crate::nibble::NibbleOps;
+use crate::node::{NodeHandlePlan, NodePlan, OwnedNode};
+use crate::{DBValue, NodeCodec};
+
+/// A pool of hashes, each one allocated once.
+pub struct HashInterner<H: Hasher> {
+	pool: Mutex<Pool<H>>,
+}
+
+struct Pool<H: Hasher> {
+	hashes: HashSet<Arc<H::Out>>,
+	/// Number of hashes left by the last collection.
+	collected: usize,
+}
+
+impl<H: Hasher> Default for HashInterner<H> {
+	fn default() -> Self {
+		HashInterner { pool: Mutex::new(Pool { hashes: HashSet::new(), collected: 0 }) }
+	}
+}
+
+impl<H: Hasher> HashInterner<H> {
+	/// Create an empty pool.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	fn lock(&self) -> MutexGuard<'_, Pool<H>> {
+		self.pool.lock().expect("the pool is never left inconsistent")
+	}
+
+	/// Return the pooled allocation of `hash`, adding it to the pool if needed.
+	pub fn intern(&self, hash: &H::Out) -> Arc<H::Out> {
+		let mut pool = self.lock();
+		if let Some(interned) = pool.hashes.get(hash) {
+			return interned.clone();
+		}
+		let interned = Arc::new(*hash);
+		pool.hashes.insert(interned.clone());
+		interned
+	}
+
+	/// Number of hashes in the pool.
+	pub fn len(&self) -> usize {
+		self.lock().hashes.len()
+	}
+
+	/// Whether the pool is empty.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Remove the hashes only referenced by the pool, returning their number.
+	pub fn collect_garbage(&self) -> usize {
+		let mut pool = self.lock();
+		let before = pool.hashes.len();
+		pool.hashes.retain(|hash| Arc::strong_count(hash) > 1);
+		pool.collected = pool.hashes.len();
+		before - pool.collected
+	}
+
+	/// Remove the hashes only referenced by the pool as `collect_garbage`, if the pool has
+	/// doubled since the last collection, so that collecting after each release of nodes
+	/// takes a constant time per hash on average.
+	pub fn collect_garbage_if_grown(&self) -> usize {
+		let grown = {
+			let pool = self.lock();
+			pool.hashes.len() > 2 * pool.collected.max(64)
+		};
+		if grown { self.collect_garbage() } else { 0 }
+	}
+
+	/// Approximate heap size of the pool: the table of the set and the allocations of the
+	/// hashes, with their reference counts.
+	pub fn heap_size(&self) -> usize {
+		let pool = self.lock();
+		pool.hashes.capacity() * mem::size_of::<Arc<H::Out>>()
+			+ pool.hashes.len() * (mem::size_of::<H::Out>() + 2 * mem::size_of::<usize>())
+	}
+}
+
+/// An encoded node whose child hashes may be interned.
+///
+/// The interned hashes are removed from the encoding and put back by `encoded`.
+pub struct InternedNode<H: Hasher> {
+	/// Encoding of the node, without the interned hashes.
+	data: Vec<u8>,
+	/// Interned child hashes, with their offset in the full encoding, in order.
+	hashes: Vec<(u32, Arc<H::Out>)>,
+}
+
+impl<H: Hasher> InternedNode<H> {
+	/// Decode `data` according to the codec `C`, interning the child hashes in `interner` if
+	/// given. Without an interner, the node keeps its full encoding.
+	pub fn new<C: NodeCodec<HashOut = H::Out>>(
+		data: DBValue,
+		interner: Option<&HashInterner<H>>,
+	) -> Result<Self, C::Error> {
+		let plan = C::decode_plan(&data)?;
+		Ok(Self::from_plan(data, &plan, interner))
+	}
+
+	/// The decoded `node`, interning its child hashes in `interner` if given, without decoding
+	/// it again.
+	pub fn from_owned_node<N: NibbleOps>(
+		node: &OwnedNode<DBValue, N>,
+		interner: Option<&HashInterner<H>>,
+	) -> Self {
+		Self::from_plan(node.data().to_vec(), node.node_plan(), interner)
+	}
+
+	fn from_plan<N: NibbleOps>(
+		data: DBValue,
+		plan: &NodePlan<N>,
+		interner: Option<&HashInterner<H>>,
+	) -> Self {
+		let interner = match interner {
+			Some(interner) => interner,
+			None => return InternedNode { data, hashes: Vec::new() },
+		};
+		let mut ranges: Vec<_> = match plan {
+			NodePlan::Extension { child, .. } => vec![child],
+			NodePlan::Branch { children, .. } | NodePlan::NibbledBranch { children, .. } =>
+				children.iter().flatten().collect(),
+			NodePlan::Empty | NodePlan::Leaf { .. } => Vec::new(),
+		}.into_iter().filter_map(|child| match child {
+			NodeHandlePlan::Hash(range) if range.len() == H::LENGTH => Some(range.clone()),
+			_ => None,
+		}).collect();
+		if ranges.is_empty() {
+			return InternedNode { data, hashes: Vec::new() };
+		}
+		ranges.sort_by_key(|range| range.start);
+
+		let mut compact = Vec::with_capacity(data.len() - ranges.len() * H::LENGTH);
+		let mut hashes = Vec::with_capacity(ranges.len());
+		let mut copied = 0;
+		for range in ranges {
+			compact.extend_from_slice(&data[copied..range.start]);
+			let mut hash = H::Out::default();
+			hash.as_mut().copy_from_slice(&data[range.clone()]);
+			hashes.push((range.start as u32, interner.intern(&hash)));
+			copied = range.end;
+		}
+		compact.extend_from_slice(&data[copied..]);
+		InternedNode { data: compact, hashes }
+	}
+
+	/// The full encoding of the node.
+	pub fn encoded(&self) -> DBValue {
+		let mut encoded = Vec::with_capacity(self.data.len() + self.hashes.len() * H::LENGTH);
+		let mut copied = 0;
+		for (offset, hash) in self.hashes.iter() {
+			let end = *offset as usize - encoded.len() + copied;
+			encoded.extend_from_slice(&self.data[copied..end]);
+			encoded.extend_from_slice((**hash).as_ref());
+			copied = end;
+		}
+		encoded.extend_from_slice(&self.data[copied..]);
+		encoded
+	}
+
+	/// Decode the full encoding of the node.
+	pub fn to_owned_node<C: NodeCodec<HashOut = H::Out>>(
+		&self,
+	) -> Result<OwnedNode<DBValue, C::Nibble>, C::Error> {
+		OwnedNode::new::<C>(self.encoded())
+	}
+
+	/// Number of interned child hashes.
+	pub fn interned_hashes(&self) -> usize {
+		self.hashes.len()
+	}
+
+	/// Approximate heap size of the node, not counting the pooled hashes.
+	pub fn heap_size(&self) -> usize {
+		self.data.capacity() + self.hashes.capacity() * mem::size_of::<(u32, Arc<H::Out>)>()
+	}
+}
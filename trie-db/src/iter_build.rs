@@ -20,32 +20,26 @@
 use hash_db::{Hasher, HashDB, Prefix};
 use crate::rstd::{cmp::max, marker::PhantomData, vec::Vec};
 use crate::triedbmut::{ChildReference};
-use crate::nibble::NibbleSlice;
-use crate::nibble::nibble_ops;
+use crate::nibble::{Children, NibbleOps, NibbleSlice};
+use crate::node::{store_value, value_slot};
 use crate::node_codec::NodeCodec;
 use crate::{TrieLayout, TrieHash};
 
-macro_rules! exponential_out {
-	(@3, [$($inpp:expr),*]) => { exponential_out!(@2, [$($inpp,)* $($inpp),*]) };
-	(@2, [$($inpp:expr),*]) => { exponential_out!(@1, [$($inpp,)* $($inpp),*]) };
-	(@1, [$($inpp:expr),*]) => { [$($inpp,)* $($inpp),*] };
-}
-
 type CacheNode<HO> = Option<ChildReference<HO>>;
 
-#[inline(always)]
-fn new_vec_slice_buffer<HO>() -> [CacheNode<HO>; 16] {
-	exponential_out!(@3, [None, None])
-}
-
-type ArrayNode<T> = [CacheNode<TrieHash<T>>; 16];
+type ArrayNode<T> = Children<ChildReference<TrieHash<T>>, <T as TrieLayout>::Nibble>;
 
 /// Struct containing iteration cache, can be at most the length of the lowest nibble.
 ///
 /// Note that it is not memory optimal (all depth are allocated even if some are empty due
 /// to node partial).
 /// Three field are used, a cache over the children, an optional associated value and the depth.
-struct CacheAccum<T: TrieLayout, V> (Vec<(ArrayNode<T>, Option<V>, usize)>, PhantomData<T>);
+/// The nodes are encoded in the buffer of the second field, reused from one node to the next.
+struct CacheAccum<T: TrieLayout, V> (
+	Vec<(ArrayNode<T>, Option<V>, usize)>,
+	Vec<u8>,
+	PhantomData<T>,
+);
 
 /// Initially allocated cache depth.
 const INITIAL_DEPTH: usize = 10;
@@ -58,13 +52,13 @@ impl<T, V> CacheAccum<T, V>
 
 	fn new() -> Self {
 		let v = Vec::with_capacity(INITIAL_DEPTH);
-		CacheAccum(v, PhantomData)
+		CacheAccum(v, Vec::new(), PhantomData)
 	}
 
 	#[inline(always)]
 	fn set_cache_value(&mut self, depth:usize, value: Option<V>) {
 		if self.0.is_empty() || self.0[self.0.len() - 1].2 < depth {
-			self.0.push((new_vec_slice_buffer(), None, depth));
+			self.0.push((Children::new(), None, depth));
 		}
 		let last = self.0.len() - 1;
 		debug_assert!(self.0[last].2 <= depth);
@@ -74,13 +68,13 @@ impl<T, V> CacheAccum<T, V>
 	#[inline(always)]
 	fn set_node(&mut self, depth: usize, nibble_index: usize, node: CacheNode<TrieHash<T>>) {
 		if self.0.is_empty() || self.0[self.0.len() - 1].2 < depth {
-			self.0.push((new_vec_slice_buffer(), None, depth));
+			self.0.push((Children::new(), None, depth));
 		}
 
 		let last = self.0.len() - 1;
 		debug_assert!(self.0[last].2 == depth);
 
-		self.0[last].0.as_mut()[nibble_index] = node;
+		self.0[last].0[nibble_index] = node;
 	}
 
 	#[inline(always)]
@@ -126,15 +120,17 @@ impl<T, V> CacheAccum<T, V>
 		target_depth: usize,
 		(k2, v2): &(impl AsRef<[u8]>, impl AsRef<[u8]>),
 	) {
-		let nibble_value = nibble_ops::left_nibble_at(&k2.as_ref()[..], target_depth);
+		let nibble_value = T::Nibble::left_nibble_at(&k2.as_ref()[..], target_depth);
 		// is it a branch value (two candidate same ix)
-		let nkey = NibbleSlice::new_offset(&k2.as_ref()[..], target_depth + 1);
-		let encoded = T::Codec::leaf_node(nkey.right(), &v2.as_ref()[..]);
-		let pr = NibbleSlice::new_offset(
+		let nkey = NibbleSlice::<T::Nibble>::new_offset(&k2.as_ref()[..], target_depth + 1);
+		let pr = NibbleSlice::<T::Nibble>::new_offset(
 			&k2.as_ref()[..],
-			k2.as_ref().len() * nibble_ops::NIBBLE_PER_BYTE - nkey.len(),
+			k2.as_ref().len() * T::Nibble::NIBBLE_PER_BYTE - nkey.len(),
 		);
-		let hash = callback.process(pr.left(), encoded, false);
+		let slot = encode_value::<T>(callback, pr.left(), v2.as_ref());
+		self.1.clear();
+		T::Codec::leaf_node_into(nkey.right(), slot.as_deref().unwrap_or(v2.as_ref()), &mut self.1);
+		let hash = callback.process_encoded(pr.left(), &self.1, false);
 
 		// insert hash in branch (first level branch only at this point)
 		self.set_node(target_depth, nibble_value as usize, Some(hash));
@@ -175,7 +171,7 @@ impl<T, V> CacheAccum<T, V>
 			};
 			if !is_root {
 				// put hash in parent
-				let nibble: u8 = nibble_ops::left_nibble_at(&ref_branch.as_ref()[..], llix);
+				let nibble: u8 = T::Nibble::left_nibble_at(&ref_branch.as_ref()[..], llix);
 				self.set_node(llix, nibble as usize, Some(h));
 			}
 		}
@@ -195,19 +191,23 @@ impl<T, V> CacheAccum<T, V>
 
 		// encode branch
 		let v = self.0[last].1.take();
-		let encoded = T::Codec::branch_node(
-			self.0[last].0.as_ref().iter(),
-			v.as_ref().map(|v| v.as_ref()),
+		let pr = NibbleSlice::<T::Nibble>::new_offset(key_branch, branch_d);
+		let slot = v.as_ref().and_then(|v| encode_value::<T>(callback, pr.left(), v.as_ref()));
+		self.1.clear();
+		T::Codec::branch_node_into(
+			self.0[last].0.iter(),
+			slot.as_deref().or_else(|| v.as_ref().map(|v| v.as_ref())),
+			&mut self.1,
 		);
 		self.reset_depth(branch_d);
-		let pr = NibbleSlice::new_offset(&key_branch, branch_d);
-		let branch_hash = callback.process(pr.left(), encoded, is_root && nkey.is_none());
+		let branch_hash = callback.process_encoded(pr.left(), &self.1, is_root && nkey.is_none());
 
 		if let Some(nkeyix) = nkey {
-			let pr = NibbleSlice::new_offset(&key_branch, nkeyix.0);
+			let pr = NibbleSlice::<T::Nibble>::new_offset(&key_branch, nkeyix.0);
 			let nib = pr.right_range_iter(nkeyix.1);
-			let encoded = T::Codec::extension_node(nib, nkeyix.1, branch_hash);
-			let h = callback.process(pr.left(), encoded, is_root);
+			self.1.clear();
+			T::Codec::extension_node_into(nib, nkeyix.1, branch_hash, &mut self.1);
+			let h = callback.process_encoded(pr.left(), &self.1, is_root);
 			h
 		} else {
 			branch_hash
@@ -228,18 +228,27 @@ impl<T, V> CacheAccum<T, V>
 		// encode branch
 		let v = self.0[last].1.take();
 		let nkeyix = nkey.unwrap_or((0, 0));
-		let pr = NibbleSlice::new_offset(&key_branch, nkeyix.0);
-		let encoded = T::Codec::branch_node_nibbled(
+		// the value node is at the prefix of the branch, before its partial key.
+		let value_prefix =
+			NibbleSlice::<T::Nibble>::new_offset(key_branch, nkey.map_or(branch_d, |n| n.0));
+		let slot = v.as_ref()
+			.and_then(|v| encode_value::<T>(callback, value_prefix.left(), v.as_ref()));
+		let pr = NibbleSlice::<T::Nibble>::new_offset(&key_branch, nkeyix.0);
+		self.1.clear();
+		T::Codec::branch_node_nibbled_into(
 			pr.right_range_iter(nkeyix.1),
 			nkeyix.1,
-			self.0[last].0.as_ref().iter(), v.as_ref().map(|v| v.as_ref()));
+			self.0[last].0.iter(),
+			slot.as_deref().or_else(|| v.as_ref().map(|v| v.as_ref())),
+			&mut self.1,
+		);
 		self.reset_depth(branch_d);
 		let ext_length = nkey.as_ref().map(|nkeyix| nkeyix.0).unwrap_or(0);
-		let pr = NibbleSlice::new_offset(
+		let pr = NibbleSlice::<T::Nibble>::new_offset(
 			&key_branch,
 			branch_d - ext_length,
 		);
-		callback.process(pr.left(), encoded, is_root)
+		callback.process_encoded(pr.left(), &self.1, is_root)
 	}
 
 }
@@ -267,10 +276,11 @@ pub fn trie_visit<T, I, A, B, F>(input: I, callback: &mut F)
 		let mut single = true;
 		for (k, v) in iter_input {
 			single = false;
-			let common_depth = nibble_ops::biggest_depth(&previous_value.0.as_ref()[..], &k.as_ref()[..]);
+			let common_depth =
+				T::Nibble::biggest_depth(&previous_value.0.as_ref()[..], &k.as_ref()[..]);
 			// 0 is a reserved value : could use option
 			let depth_item = common_depth;
-			if common_depth == previous_value.0.as_ref().len() * nibble_ops::NIBBLE_PER_BYTE {
+			if common_depth == previous_value.0.as_ref().len() * T::Nibble::NIBBLE_PER_BYTE {
 				// the new key include the previous one : branch value case
 				// just stored value at branch depth
 				depth_queue.set_cache_value(common_depth, Some(previous_value.1));
@@ -291,12 +301,13 @@ pub fn trie_visit<T, I, A, B, F>(input: I, callback: &mut F)
 		if single {
 			// one single element corner case
 			let (k2, v2) = previous_value;
-			let nkey = NibbleSlice::new_offset(&k2.as_ref()[..], last_depth);
-			let encoded = T::Codec::leaf_node(nkey.right(), &v2.as_ref()[..]);
-			let pr = NibbleSlice::new_offset(
+			let nkey = NibbleSlice::<T::Nibble>::new_offset(&k2.as_ref()[..], last_depth);
+			let pr = NibbleSlice::<T::Nibble>::new_offset(
 				&k2.as_ref()[..],
-				k2.as_ref().len() * nibble_ops::NIBBLE_PER_BYTE - nkey.len(),
+				k2.as_ref().len() * T::Nibble::NIBBLE_PER_BYTE - nkey.len(),
 			);
+			let slot = encode_value::<T>(callback, pr.left(), v2.as_ref());
+			let encoded = T::Codec::leaf_node(nkey.right(), slot.as_deref().unwrap_or(v2.as_ref()));
 			callback.process(pr.left(), encoded, true);
 		} else {
 			depth_queue.flush_value(callback, last_depth, &previous_value);
@@ -305,10 +316,24 @@ pub fn trie_visit<T, I, A, B, F>(input: I, callback: &mut F)
 		}
 	} else {
 		// nothing null root corner case
-		callback.process(hash_db::EMPTY_PREFIX, T::Codec::empty_node().to_vec(), true);
+		callback.process_encoded(hash_db::EMPTY_PREFIX, T::Codec::empty_node(), true);
 	}
 }
 
+/// The value slot of `value` in a node of `T` at `prefix`, passing its value node or its
+/// chunks and their index, if any, to `callback`. `None` if the slot is `value` itself.
+fn encode_value<T: TrieLayout>(
+	callback: &mut impl ProcessEncodedNode<TrieHash<T>>,
+	prefix: Prefix,
+	value: &[u8],
+) -> Option<Vec<u8>> {
+	let hash = store_value::<T>(value, |node| {
+		callback.process_value(prefix, node);
+		T::Hash::hash(node)
+	});
+	value_slot::<T>(value, hash.as_ref())
+}
+
 /// Visitor trait to implement when using `trie_visit`.
 pub trait ProcessEncodedNode<HO> {
 	/// Function call with prefix, encoded value and a boolean indicating if the
@@ -319,6 +344,26 @@ pub trait ProcessEncodedNode<HO> {
 	/// This is not something direcly related to encoding but is here for
 	/// optimisation purpose (builder hash_db does return this value).
 	fn process(&mut self, prefix: Prefix, encoded_node: Vec<u8>, is_root: bool) -> ChildReference<HO>;
+
+	/// Function called as `process`, with the encoded node borrowed from a buffer reused from
+	/// one node to the next. `trie_visit` calls this function.
+	///
+	/// The default implementation passes a copy of the encoded node to `process`: visitors not
+	/// keeping the encoded nodes avoid an allocation per node by implementing it.
+	fn process_encoded(
+		&mut self,
+		prefix: Prefix,
+		encoded_node: &[u8],
+		is_root: bool,
+	) -> ChildReference<HO> {
+		self.process(prefix, encoded_node.to_vec(), is_root)
+	}
+
+	/// Function called with the value of each value node, at the prefix of the node holding
+	/// it, before that node, see `TrieLayout::MAX_INLINE_VALUE`. For a value stored in chunks,
+	/// called with each chunk and then their index, see `TrieLayout::MAX_VALUE_CHUNK`. Does
+	/// nothing by default.
+	fn process_value(&mut self, _prefix: Prefix, _value: &[u8]) {}
 }
 
 /// Get trie root and insert visited node in a hash_db.
@@ -343,6 +388,15 @@ impl<'a, H: Hasher, V, DB: HashDB<H, V>> ProcessEncodedNode<<H as Hasher>::Out>
 		prefix: Prefix,
 		encoded_node: Vec<u8>,
 		is_root: bool,
+	) -> ChildReference<<H as Hasher>::Out> {
+		self.process_encoded(prefix, &encoded_node, is_root)
+	}
+
+	fn process_encoded(
+		&mut self,
+		prefix: Prefix,
+		encoded_node: &[u8],
+		is_root: bool,
 	) -> ChildReference<<H as Hasher>::Out> {
 		let len = encoded_node.len();
 		if !is_root && len < <H as Hasher>::LENGTH {
@@ -351,12 +405,16 @@ impl<'a, H: Hasher, V, DB: HashDB<H, V>> ProcessEncodedNode<<H as Hasher>::Out>
 
 			return ChildReference::Inline(h, len);
 		}
-		let hash = self.db.insert(prefix, &encoded_node[..]);
+		let hash = self.db.insert(prefix, encoded_node);
 		if is_root {
 			self.root = Some(hash.clone());
 		};
 		ChildReference::Hash(hash)
 	}
+
+	fn process_value(&mut self, prefix: Prefix, value: &[u8]) {
+		self.db.insert(prefix, value);
+	}
 }
 
 /// Calculate the trie root of the trie.
@@ -375,9 +433,18 @@ impl<H, HO> Default for TrieRoot<H, HO> {
 impl<H: Hasher> ProcessEncodedNode<<H as Hasher>::Out> for TrieRoot<H, <H as Hasher>::Out> {
 	fn process(
 		&mut self,
-		_: Prefix,
+		prefix: Prefix,
 		encoded_node: Vec<u8>,
 		is_root: bool,
+	) -> ChildReference<<H as Hasher>::Out> {
+		self.process_encoded(prefix, &encoded_node, is_root)
+	}
+
+	fn process_encoded(
+		&mut self,
+		_: Prefix,
+		encoded_node: &[u8],
+		is_root: bool,
 	) -> ChildReference<<H as Hasher>::Out> {
 		let len = encoded_node.len();
 		if !is_root && len < <H as Hasher>::LENGTH {
@@ -386,7 +453,7 @@ impl<H: Hasher> ProcessEncodedNode<<H as Hasher>::Out> for TrieRoot<H, <H as Has
 
 			return ChildReference::Inline(h, len);
 		}
-		let hash = <H as Hasher>::hash(&encoded_node[..]);
+		let hash = <H as Hasher>::hash(encoded_node);
 		if is_root {
 			self.root = Some(hash.clone());
 		};
@@ -430,6 +497,15 @@ impl<H: Hasher> ProcessEncodedNode<<H as Hasher>::Out> for TrieRootPrint<H, <H a
 		p: Prefix,
 		encoded_node: Vec<u8>,
 		is_root: bool,
+	) -> ChildReference<<H as Hasher>::Out> {
+		self.process_encoded(p, &encoded_node, is_root)
+	}
+
+	fn process_encoded(
+		&mut self,
+		p: Prefix,
+		encoded_node: &[u8],
+		is_root: bool,
 	) -> ChildReference<<H as Hasher>::Out> {
 		println!("Encoded node: {:x?}", &encoded_node);
 		println!("	with prefix: {:x?}", &p);
@@ -441,7 +517,7 @@ impl<H: Hasher> ProcessEncodedNode<<H as Hasher>::Out> for TrieRootPrint<H, <H a
 			println!("	inline len {}", len);
 			return ChildReference::Inline(h, len);
 		}
-		let hash = <H as Hasher>::hash(&encoded_node[..]);
+		let hash = <H as Hasher>::hash(encoded_node);
 		if is_root {
 			self.root = Some(hash.clone());
 		};
@@ -453,9 +529,18 @@ impl<H: Hasher> ProcessEncodedNode<<H as Hasher>::Out> for TrieRootPrint<H, <H a
 impl<H: Hasher> ProcessEncodedNode<<H as Hasher>::Out> for TrieRootUnhashed<H> {
 	fn process(
 		&mut self,
-		_: Prefix,
+		prefix: Prefix,
 		encoded_node: Vec<u8>,
 		is_root: bool,
+	) -> ChildReference<<H as Hasher>::Out> {
+		self.process_encoded(prefix, &encoded_node, is_root)
+	}
+
+	fn process_encoded(
+		&mut self,
+		_: Prefix,
+		encoded_node: &[u8],
+		is_root: bool,
 	) -> ChildReference<<H as Hasher>::Out> {
 		let len = encoded_node.len();
 		if !is_root && len < <H as Hasher>::LENGTH {
@@ -464,9 +549,9 @@ impl<H: Hasher> ProcessEncodedNode<<H as Hasher>::Out> for TrieRootUnhashed<H> {
 
 			return ChildReference::Inline(h, len);
 		}
-		let hash = <H as Hasher>::hash(&encoded_node[..]);
+		let hash = <H as Hasher>::hash(encoded_node);
 		if is_root {
-			self.root = Some(encoded_node);
+			self.root = Some(encoded_node.to_vec());
 		};
 		ChildReference::Hash(hash)
 	}
@@ -12,13 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{CError, DBValue, Result, Trie, TrieHash, TrieIterator, TrieLayout};
-use hash_db::{Hasher, EMPTY_PREFIX};
+use super::{CError, DBValue, Result, Trie, TrieError, TrieHash, TrieIterator, TrieLayout};
+use hash_db::{Hasher, Prefix, EMPTY_PREFIX};
+use crate::canonical_view::CanonicalNodeIterator;
 use crate::triedb::TrieDB;
 use crate::node::{NodePlan, NodeHandle, OwnedNode};
-use crate::nibble::{NibbleSlice, NibbleVec, nibble_ops};
+use crate::nibble::{NibbleOps, NibbleSlice, NibbleVec, Radix16};
+use crate::recorder::Recorder;
 
-use crate::rstd::{rc::Rc, vec::Vec};
+use crate::rstd::{boxed::Box, mem, vec::Vec};
+#[cfg(not(feature = "send-node-iterator"))]
+use crate::rstd::rc::Rc as NodePointer;
+#[cfg(feature = "send-node-iterator")]
+use crate::rstd::sync::Arc as NodePointer;
 
 #[cfg_attr(feature = "std", derive(Debug))]
 #[derive(Clone, Copy, Eq, PartialEq)]
@@ -29,17 +35,98 @@ enum Status {
 	Exiting,
 }
 
+/// Number of leading key nibbles accounted for in the progress of an iterator.
+const PROGRESS_NIBBLES: usize = 16;
+
+/// A decoded node shared by a node iterator and its items: an `Rc`, or an `Arc` with the
+/// `send-node-iterator` feature so that the iterators owning their database, and the items of
+/// all the node iterators, are `Send`.
+pub type SharedNode<N = Radix16> = NodePointer<OwnedNode<DBValue, N>>;
+
+/// A decoded node with its hash, `None` for inline nodes.
+pub(crate) type HashedNode<O, N> = (SharedNode<N>, Option<O>);
+
+/// A node fetched by hash, with its hash, depth and prefix, to pass to a `Recorder`.
+type RecordedNode<O, N> = (SharedNode<N>, O, u32, NibbleVec<N>);
+
+/// Node iterator item borrowing the node prefix from the iterator, see
+/// `TrieDBNodeIterator::next_node`.
+pub type BorrowedNodeItem<'a, L> = Result<
+	(&'a NibbleVec<LayoutNibble<L>>, Option<TrieHash<L>>, SharedNode<LayoutNibble<L>>),
+	TrieHash<L>,
+	CError<L>,
+>;
+
+/// Nibbles of the layout `L`.
+type LayoutNibble<L> = <L as TrieLayout>::Nibble;
+
 #[cfg_attr(feature = "std", derive(Debug))]
 #[derive(Eq, PartialEq)]
-struct Crumb<H: Hasher> {
+struct Crumb<H: Hasher, N: NibbleOps> {
 	hash: Option<H::Out>,
-	node: Rc<OwnedNode<DBValue>>,
+	node: SharedNode<N>,
 	status: Status,
+	/// Inline children already decoded, keyed by child index (0 for the child of an
+	/// extension node).
+	inline_children: Vec<(u8, SharedNode<N>)>,
 }
 
-impl<H: Hasher> Crumb<H> {
-	/// Move on to next status in the node's sequence.
-	fn increment(&mut self) {
+impl<H: Hasher, N: NibbleOps> Crumb<H, N> {
+	/// Is this crumb at the same node as `other`? Inline nodes can only be compared once
+	/// reused from the cache of their parent.
+	fn is_same_node(&self, other: &Self) -> bool {
+		match (&self.hash, &other.hash) {
+			(Some(hash), Some(other_hash)) => hash == other_hash,
+			(None, None) => SharedNode::ptr_eq(&self.node, &other.node),
+			_ => false,
+		}
+	}
+
+	/// Get the child at `index` referenced by `handle`, the decoded inline children being
+	/// reused when entered again.
+	fn child<L: TrieLayout<Hash = H, Nibble = N>>(
+		&mut self,
+		db: &TrieDB<L>,
+		index: u8,
+		handle: NodeHandle,
+		prefix: Prefix,
+	) -> Result<HashedNode<H::Out, N>, H::Out, CError<L>> {
+		let inline = match handle {
+			NodeHandle::Inline(_) => true,
+			NodeHandle::Hash(_) => false,
+		};
+		if inline {
+			if let Some((_, node)) = self.inline_children.iter().find(|(i, _)| *i == index) {
+				profile!(cache_hits);
+				return Ok((node.clone(), None));
+			}
+		}
+		let (node, node_hash) = db.get_shared_node(self.hash.unwrap_or_default(), handle, prefix)?;
+		if inline {
+			profile!(cache_misses);
+			self.inline_children.push((index, node.clone()));
+		}
+		Ok((node, node_hash))
+	}
+
+	/// Move on to next status in the node's sequence, the children being visited from the
+	/// last one when `reversed`.
+	fn increment(&mut self, reversed: bool) {
+		if reversed {
+			self.status = match (self.status, self.node.node_plan()) {
+				(Status::Entering, NodePlan::Extension { .. })
+				| (Status::Entering, NodePlan::Branch { .. })
+				| (Status::Entering, NodePlan::NibbledBranch { .. }) => Status::At,
+				(Status::At, NodePlan::Branch { .. })
+				| (Status::At, NodePlan::NibbledBranch { .. }) =>
+					Status::AtChild(N::NIBBLE_LENGTH - 1),
+				(Status::AtChild(x), NodePlan::Branch { .. })
+				| (Status::AtChild(x), NodePlan::NibbledBranch { .. })
+				if x > 0 => Status::AtChild(x - 1),
+				_ => Status::Exiting,
+			};
+			return;
+		}
 		self.status = match (self.status, self.node.node_plan()) {
 			(Status::Entering, NodePlan::Extension { .. }) => Status::At,
 			(Status::Entering, NodePlan::Branch { .. })
@@ -48,28 +135,395 @@ impl<H: Hasher> Crumb<H> {
 			| (Status::At, NodePlan::NibbledBranch { .. }) => Status::AtChild(0),
 			(Status::AtChild(x), NodePlan::Branch { .. })
 			| (Status::AtChild(x), NodePlan::NibbledBranch { .. })
-			if x < (nibble_ops::NIBBLE_LENGTH - 1) => Status::AtChild(x + 1),
+			if x < (N::NIBBLE_LENGTH - 1) => Status::AtChild(x + 1),
 			_ => Status::Exiting,
 		}
 	}
 }
 
+/// Position of a node iterator, the trie being passed to every operation so that
+/// iterators owning their database can build it on demand.
+pub(crate) struct NodeIteratorState<L: TrieLayout> {
+	trail: Vec<Crumb<L::Hash, L::Nibble>>,
+	key_nibbles: NibbleVec<L::Nibble>,
+	/// Whether the nodes are iterated in reverse pre-order, so that the keys are in
+	/// descending order.
+	reversed: bool,
+	/// The nodes fetched by hash since last taken, when recording.
+	recorded: Option<Vec<RecordedNode<TrieHash<L>, L::Nibble>>>,
+}
+
+/// Saved position of a node iterator over the trie at a given root, to resume the iteration
+/// later on, see `TrieDBNodeIterator::save`.
+///
+/// The iteration can be resumed once the nodes pruned since were created again, as long as the
+/// trie has the same root: resuming on another root fails, unless the state is rebased on it.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Clone, Eq, PartialEq)]
+pub struct TrieIteratorState<H, N: NibbleOps = Radix16> {
+	root: H,
+	/// Hash of the node of each crumb, `None` for inline nodes, with the status of the crumb.
+	trail: Vec<(Option<H>, Status)>,
+	key_nibbles: NibbleVec<N>,
+	/// Smallest nibble key not iterated yet, `None` once the iteration is over. For reversed
+	/// iterations, the nibble key from which all the keys are iterated, `None` if none is.
+	position: Option<NibbleVec<N>>,
+	reversed: bool,
+}
+
+impl<H, N: NibbleOps> TrieIteratorState<H, N> {
+	/// Root of the trie the iteration was saved on.
+	pub fn root(&self) -> &H {
+		&self.root
+	}
+
+	/// State of an iteration over the trie `db`, positioned on the smallest key not iterated
+	/// yet in this state, to continue an iteration across a change of the trie on purpose.
+	/// The keys of `db` before that key are skipped, even if they were never iterated. Reversed
+	/// iterations continue from the largest key not iterated yet, the keys after it skipped.
+	pub fn rebase<L>(&self, db: &TrieDB<L>) -> Result<Self, H, CError<L>>
+		where
+			H: Copy,
+			L: TrieLayout<Nibble = N>,
+			L::Hash: Hasher<Out = H>,
+	{
+		let mut state = NodeIteratorState::<L>::new(db)?;
+		state.reversed = self.reversed;
+		match &self.position {
+			Some(position) => {
+				// The last nibble is padded with 0, no key coming between.
+				let (key, last) = position.as_prefix();
+				let mut key = key.to_vec();
+				key.extend(last);
+				if self.reversed {
+					state.seek_rev_inner(db, &key, true)?;
+				} else {
+					state.seek_prefix(db, &key)?;
+				}
+			},
+			// A reversed iteration not started.
+			None if self.reversed && !self.trail.is_empty() => {},
+			None => state.trail.clear(),
+		}
+		Ok(state.save(*db.root()))
+	}
+}
+
+// The encoding of a state, to persist iterations: the root, the trail of hashes and statuses,
+// the key nibbles, the position and the direction.
+#[cfg(feature = "codec")]
+impl<H: codec::Encode, N: NibbleOps> codec::Encode for TrieIteratorState<H, N> {
+	fn encode_to<T: codec::Output>(&self, dest: &mut T) {
+		self.root.encode_to(dest);
+		codec::Compact(self.trail.len() as u32).encode_to(dest);
+		for (hash, status) in self.trail.iter() {
+			hash.encode_to(dest);
+			match *status {
+				Status::Entering => dest.push_byte(0),
+				Status::At => dest.push_byte(1),
+				Status::AtChild(index) => {
+					dest.push_byte(2);
+					dest.push_byte(index as u8);
+				},
+				Status::Exiting => dest.push_byte(3),
+			}
+		}
+		encode_nibbles(&self.key_nibbles, dest);
+		match &self.position {
+			Some(position) => {
+				dest.push_byte(1);
+				encode_nibbles(position, dest);
+			},
+			None => dest.push_byte(0),
+		}
+		self.reversed.encode_to(dest);
+	}
+}
+
+// Only the shapes of trails saved by iterators are decoded: every crumb but the last is at a
+// child, with a nibble index. The hashes of the nodes are checked by `restore`.
+#[cfg(feature = "codec")]
+impl<H: codec::Decode, N: NibbleOps> codec::Decode for TrieIteratorState<H, N> {
+	fn decode<I: codec::Input>(input: &mut I) -> crate::rstd::result::Result<Self, codec::Error> {
+		let root = H::decode(input)?;
+		let len = <codec::Compact<u32>>::decode(input)?.0;
+		let mut trail = Vec::new();
+		for i in 0..len {
+			let hash = <Option<H>>::decode(input)?;
+			let status = match input.read_byte()? {
+				0 => Status::Entering,
+				1 => Status::At,
+				2 => match input.read_byte()? {
+					index if (index as usize) < N::NIBBLE_LENGTH =>
+						Status::AtChild(index as usize),
+					_ => return Err("Invalid child index in iterator state".into()),
+				},
+				3 => Status::Exiting,
+				_ => return Err("Invalid crumb status in iterator state".into()),
+			};
+			let last = i + 1 == len;
+			if !last && (status == Status::Entering || status == Status::Exiting) {
+				return Err("Invalid crumb status in iterator state".into());
+			}
+			trail.push((hash, status));
+		}
+		let key_nibbles = decode_nibbles(input)?;
+		let position = match input.read_byte()? {
+			0 => None,
+			1 => Some(decode_nibbles(input)?),
+			_ => return Err("Invalid position in iterator state".into()),
+		};
+		let reversed = bool::decode(input)?;
+		Ok(TrieIteratorState { root, trail, key_nibbles, position, reversed })
+	}
+}
+
+/// Encode `nibbles` as their number followed by their bytes.
+#[cfg(feature = "codec")]
+fn encode_nibbles<T: codec::Output, N: NibbleOps>(nibbles: &NibbleVec<N>, dest: &mut T) {
+	use codec::Encode;
+	codec::Compact(nibbles.len() as u32).encode_to(dest);
+	dest.write(nibbles.inner());
+}
+
+/// Decode nibbles encoded by `encode_nibbles`.
+#[cfg(feature = "codec")]
+fn decode_nibbles<I: codec::Input, N: NibbleOps>(
+	input: &mut I,
+) -> crate::rstd::result::Result<NibbleVec<N>, codec::Error> {
+	use codec::Decode;
+	let len = <codec::Compact<u32>>::decode(input)?.0 as usize;
+	let mut nibbles = NibbleVec::new();
+	let mut byte = 0;
+	for i in 0..len {
+		if i % N::NIBBLE_PER_BYTE == 0 {
+			byte = input.read_byte()?;
+		}
+		nibbles.push(N::at_left((i % N::NIBBLE_PER_BYTE) as u8, byte));
+	}
+	let last = len % N::NIBBLE_PER_BYTE;
+	if last != 0 && N::pad_left(last, byte) != byte {
+		return Err("Invalid nibble padding in iterator state".into());
+	}
+	Ok(nibbles)
+}
+
 /// Iterator for going through all nodes in the trie in pre-order traversal order.
 pub struct TrieDBNodeIterator<'a, L: TrieLayout> {
 	db: &'a TrieDB<'a, L>,
-	trail: Vec<Crumb<L::Hash>>,
-	key_nibbles: NibbleVec,
+	state: NodeIteratorState<L>,
+	recorder: Option<&'a mut Recorder<TrieHash<L>>>,
 }
 
 impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 	/// Create a new iterator.
 	pub fn new(db: &'a TrieDB<L>) -> Result<TrieDBNodeIterator<'a, L>, TrieHash<L>, CError<L>> {
-		let mut r = TrieDBNodeIterator {
-			db,
+		let state = NodeIteratorState::new(db)?;
+		Ok(TrieDBNodeIterator { db, state, recorder: None })
+	}
+
+	/// The iterated trie.
+	pub(crate) fn trie(&self) -> &'a TrieDB<'a, L> {
+		self.db
+	}
+
+	/// Create a new iterator recording in `recorder` the nodes it fetches by hash, while
+	/// iterating and seeking, with their depth as `TrieDB::get_with` does. Inline nodes are
+	/// recorded with the nodes holding them, and the nodes on a seek path even if they are
+	/// never yielded, so that the records prove the keys of the iterated range.
+	pub fn new_with_recorder(
+		db: &'a TrieDB<L>,
+		recorder: &'a mut Recorder<TrieHash<L>>,
+	) -> Result<TrieDBNodeIterator<'a, L>, TrieHash<L>, CError<L>> {
+		let mut state = NodeIteratorState::new(db)?;
+		state.start_recording();
+		let mut iter = TrieDBNodeIterator { db, state, recorder: Some(recorder) };
+		iter.record();
+		Ok(iter)
+	}
+
+	/// Pass the nodes fetched by hash since the last call to the recorder.
+	fn record(&mut self) {
+		let recorded = self.state.recorded.as_mut();
+		if let (Some(recorder), Some(recorded)) = (self.recorder.as_mut(), recorded) {
+			for (node, hash, depth, prefix) in recorded.drain(..) {
+				recorder.record_at(&hash, node.data(), depth, prefix.as_prefix());
+			}
+		}
+	}
+
+	/// Create an iterator going through the nodes in reverse pre-order, the children of a
+	/// node being visited from the last one before the node itself, so that the keys are in
+	/// descending order.
+	///
+	/// `seek` and `seek_after` on it position it at the last key not after, or strictly
+	/// before, the sought key.
+	pub fn new_reversed(
+		db: &'a TrieDB<L>,
+	) -> Result<TrieDBNodeIterator<'a, L>, TrieHash<L>, CError<L>> {
+		let mut state = NodeIteratorState::new(db)?;
+		state.reversed = true;
+		Ok(TrieDBNodeIterator { db, state, recorder: None })
+	}
+
+	/// Create an iterator over the nodes of `db` as encoded under the inlining rules of the
+	/// layout, with whether each stored node differs, see `CanonicalNodeIterator`.
+	///
+	/// Tries with the same pairs yield the same canonical nodes, whichever nodes their writers
+	/// stored inline.
+	pub fn canonical_view(
+		db: &'a TrieDB<L>,
+	) -> Result<CanonicalNodeIterator<'a, L>, TrieHash<L>, CError<L>> {
+		CanonicalNodeIterator::new(db)
+	}
+
+	/// Advance the iterator into a prefix, no value out of the prefix will be accessed
+	/// or returned after this operation.
+	pub fn prefix(&mut self, prefix: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		let result = self.state.prefix(self.db, prefix);
+		self.record();
+		result
+	}
+
+	/// Iterate in reverse pre-order from the last key not after `key`, for instance to find
+	/// the largest key not greater than `key`, see `new_reversed`.
+	pub fn seek_rev(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		let result = self.state.seek_rev(self.db, key);
+		self.record();
+		result
+	}
+
+	/// Seek `key`, or strictly after it if `after` is set, and restrict the iteration to
+	/// `prefix`, `key` starting with `prefix`.
+	pub(crate) fn seek_in_prefix(
+		&mut self,
+		prefix: &[u8],
+		key: &[u8],
+		after: bool,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		let result = self.state.seek_in_prefix(self.db, prefix, key, after);
+		self.record();
+		result
+	}
+
+	/// Resume the iteration saved in `state` over `db`.
+	///
+	/// Returns `TrieError::ResumeRootMismatch` if the root of `db` is not the root `state` was
+	/// saved on. The nodes of the position are fetched again, their hashes checked against the
+	/// saved ones.
+	pub fn restore(
+		db: &'a TrieDB<L>,
+		state: &TrieIteratorState<TrieHash<L>, L::Nibble>,
+	) -> Result<TrieDBNodeIterator<'a, L>, TrieHash<L>, CError<L>> {
+		let state = NodeIteratorState::restore(db, state)?;
+		Ok(TrieDBNodeIterator { db, state, recorder: None })
+	}
+
+	/// Save the position of the iterator, to resume the iteration with `restore`.
+	pub fn save(&self) -> TrieIteratorState<TrieHash<L>, L::Nibble> {
+		self.state.save(*self.db.root())
+	}
+
+	/// Estimate of the part of the key space already iterated, between 0 and 1, from the
+	/// position of the iterator only. It never decreases as the iterator advances, and is 1
+	/// once the iteration is over.
+	pub fn progress(&self) -> f64 {
+		self.state.progress()
+	}
+
+	/// Skip the children of the node last returned, in an iteration in pre-order: the next node
+	/// is the one following its subtree.
+	pub(crate) fn skip_children(&mut self) {
+		debug_assert!(!self.state.reversed, "the children are visited before their node in reverse");
+		self.state.skip_last();
+	}
+
+	/// Same as `next`, but borrows the node prefix instead of cloning it, so
+	/// callers only allocate when they actually need to keep the prefix.
+	///
+	/// The prefix is borrowed until the next call, so the items cannot be collected: `next`
+	/// is this method with a clone of the prefix.
+	pub fn next_node(
+		&mut self,
+	) -> Option<BorrowedNodeItem<'_, L>> {
+		if self.recorder.is_none() {
+			return self.state.next_node(self.db);
+		}
+		// The prefix is the key nibbles of the state, borrowed again once the nodes are recorded.
+		let item = self.state.next_node(self.db)?.map(|(_, hash, node)| (hash, node));
+		self.record();
+		let prefix = &self.state.key_nibbles;
+		Some(item.map(|(hash, node)| (prefix, hash, node)))
+	}
+}
+
+impl<'a, L: TrieLayout> TrieIterator<L> for TrieDBNodeIterator<'a, L> {
+	fn seek(
+		&mut self,
+		key: &[u8],
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		let result = self.state.seek(self.db, key);
+		self.record();
+		result
+	}
+
+	fn seek_after(
+		&mut self,
+		key: &[u8],
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		let result = self.state.seek_after(self.db, key);
+		self.record();
+		result
+	}
+}
+
+impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
+	type Item = Result<
+		(NibbleVec<L::Nibble>, Option<TrieHash<L>>, SharedNode<L::Nibble>),
+		TrieHash<L>,
+		CError<L>,
+	>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.next_node()
+			.map(|item| item.map(|(prefix, hash, node)| (prefix.clone(), hash, node)))
+	}
+}
+
+/// Part of the key space before the nibble key `key`, with the part of it under the last
+/// nibble of `key`.
+fn key_space_before<N: NibbleOps>(key: &NibbleVec<N>) -> (f64, f64) {
+	// Only the first nibbles change the result, given the precision of `f64`.
+	let mut part = 0.0;
+	let mut scale = 1.0;
+	for i in 0..key.len().min(PROGRESS_NIBBLES) {
+		scale /= N::NIBBLE_LENGTH as f64;
+		part += key.at(i) as f64 * scale;
+	}
+	(part, scale)
+}
+
+/// The smallest nibble key after the keys starting with `key`, `None` if there is none.
+fn next_sibling<N: NibbleOps>(mut key: NibbleVec<N>) -> Option<NibbleVec<N>> {
+	while let Some(nibble) = key.pop() {
+		if (nibble as usize) < N::NIBBLE_LENGTH - 1 {
+			key.push(nibble + 1);
+			return Some(key);
+		}
+	}
+	None
+}
+
+impl<L: TrieLayout> NodeIteratorState<L> {
+	/// Position at the root of `db`.
+	pub(crate) fn new(db: &TrieDB<L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		let mut r = NodeIteratorState {
 			trail: Vec::with_capacity(8),
 			key_nibbles: NibbleVec::new(),
+			reversed: false,
+			recorded: None,
 		};
-		let (root_node, root_hash) = db.get_raw_or_lookup(
+		let (root_node, root_hash) = db.get_shared_node(
 			*db.root(),
 			NodeHandle::Hash(db.root().as_ref()),
 			EMPTY_PREFIX
@@ -78,17 +532,161 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 		Ok(r)
 	}
 
+	/// Position saved in `saved`, see `TrieDBNodeIterator::restore`.
+	pub(crate) fn restore(
+		db: &TrieDB<L>,
+		saved: &TrieIteratorState<TrieHash<L>, L::Nibble>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		if saved.root != *db.root() {
+			return Err(Box::new(TrieError::ResumeRootMismatch {
+				expected: saved.root,
+				found: *db.root(),
+			}));
+		}
+		let mut r = NodeIteratorState {
+			trail: Vec::with_capacity(saved.trail.len()),
+			key_nibbles: saved.key_nibbles.clone(),
+			reversed: saved.reversed,
+			recorded: None,
+		};
+		let mut prefix = NibbleVec::<L::Nibble>::new();
+		for &(hash, status) in saved.trail.iter() {
+			let (node, node_hash) = match r.trail.last_mut() {
+				None => db.get_shared_node(
+					<TrieHash<L>>::default(),
+					NodeHandle::Hash(db.root().as_ref()),
+					EMPTY_PREFIX,
+				)?,
+				Some(parent) => {
+					let node = parent.node.clone();
+					let node_data = node.data();
+					// The parent has the hash it was saved with: it only has its saved children.
+					let (index, child) = match (parent.status, node.node_plan()) {
+						(Status::At, NodePlan::Extension { partial, child }) => {
+							prefix.append_partial(partial.build(node_data).right());
+							(0, Some(child))
+						},
+						(Status::AtChild(i), NodePlan::Branch { children, .. }) => {
+							prefix.push(i as u8);
+							(i, children[i].as_ref())
+						},
+						(Status::AtChild(i), NodePlan::NibbledBranch { partial, children, .. }) => {
+							prefix.append_partial(partial.build(node_data).right());
+							prefix.push(i as u8);
+							(i, children[i].as_ref())
+						},
+						_ => (0, None),
+					};
+					let child = child.expect(
+						"the saved crumbs are the children of their parent at its status, \
+						the parent being the same node; qed"
+					);
+					parent.child(db, index as u8, child.build(node_data), prefix.as_prefix())?
+				},
+			};
+			if node_hash != hash {
+				let (key, last) = prefix.as_prefix();
+				return Err(Box::new(TrieError::HashMismatch {
+					expected: hash.unwrap_or_default(),
+					found: node_hash.unwrap_or_default(),
+					prefix: (key.to_vec(), last),
+				}));
+			}
+			r.trail.push(Crumb { hash, node, status, inline_children: Vec::new() });
+		}
+		Ok(r)
+	}
+
+	/// Record the nodes fetched by hash from now on, starting with those of the trail.
+	fn start_recording(&mut self) {
+		let trail = mem::take(&mut self.trail);
+		let key_nibbles = mem::take(&mut self.key_nibbles);
+		self.recorded = Some(Vec::new());
+		for crumb in trail {
+			self.descend(crumb.node, crumb.hash);
+		}
+		self.key_nibbles = key_nibbles;
+	}
+
+	/// Save the position, see `TrieDBNodeIterator::save`.
+	pub(crate) fn save(&self, root: TrieHash<L>) -> TrieIteratorState<TrieHash<L>, L::Nibble> {
+		TrieIteratorState {
+			root,
+			trail: self.trail.iter().map(|crumb| (crumb.hash, crumb.status)).collect(),
+			key_nibbles: self.key_nibbles.clone(),
+			position: if self.reversed { self.reversed_position() } else { self.position() },
+			reversed: self.reversed,
+		}
+	}
+
+	/// Smallest nibble key not iterated yet, `None` once the iteration is over.
+	fn position(&self) -> Option<NibbleVec<L::Nibble>> {
+		let crumb = self.trail.last()?;
+		let node_data = crumb.node.data();
+		let mut position = self.key_nibbles.clone();
+		// Past the key of the deepest node, whose descendants have keys starting with it and a
+		// nibble: the smallest of them is the key and a 0 nibble. Otherwise the deepest node,
+		// or the child at `key_nibbles`, is not iterated yet.
+		match (crumb.status, crumb.node.node_plan()) {
+			(Status::Exiting, NodePlan::Leaf { partial, .. })
+			| (Status::At, NodePlan::Extension { partial, .. })
+			| (Status::At, NodePlan::NibbledBranch { partial, .. }) => {
+				position.append_partial(partial.build(node_data).right());
+				position.push(0);
+			},
+			(Status::At, NodePlan::Branch { .. }) | (Status::Exiting, NodePlan::Empty) => {
+				position.push(0);
+			},
+			_ => {},
+		}
+		Some(position)
+	}
+
+	/// Nibble key from which all the keys are iterated, or skipped by a seek, in a reversed
+	/// iteration: `None` if there is none, or once the iteration is over.
+	fn reversed_position(&self) -> Option<NibbleVec<L::Nibble>> {
+		let crumb = self.trail.last()?;
+		let node_data = crumb.node.data();
+		let mut position = self.key_nibbles.clone();
+		match (crumb.status, crumb.node.node_plan()) {
+			// The children after `x` are iterated.
+			(Status::AtChild(x), _) => {
+				position.pop();
+				position.push(x as u8);
+				return next_sibling(position);
+			},
+			// Only the value of the node, just before its children, is left.
+			(Status::Exiting, NodePlan::Branch { .. })
+			| (Status::Exiting, NodePlan::NibbledBranch { .. }) => {
+				position.pop();
+				position.push(0);
+			},
+			(Status::Exiting, NodePlan::Leaf { partial, .. }) => {
+				position.append_partial(partial.build(node_data).right());
+				position.push(0);
+			},
+			(Status::Exiting, NodePlan::Empty) => position.push(0),
+			(Status::Exiting, NodePlan::Extension { .. }) => {},
+			// The node is not iterated yet, the keys after its subtree are.
+			(Status::Entering, _) | (Status::At, _) => return next_sibling(position),
+		}
+		Some(position)
+	}
+
 	/// Descend into a payload.
-	fn descend(&mut self, node: OwnedNode<DBValue>, node_hash: Option<TrieHash<L>>) {
+	fn descend(&mut self, node: SharedNode<L::Nibble>, node_hash: Option<TrieHash<L>>) {
+		if let (Some(recorded), Some(hash)) = (self.recorded.as_mut(), node_hash) {
+			// The depth of a node is the number of nodes fetched by hash above it, as in lookups.
+			let depth = self.trail.iter().filter(|crumb| crumb.hash.is_some()).count();
+			recorded.push((node.clone(), hash, depth as u32, self.key_nibbles.clone()));
+		}
 		self.trail.push(Crumb {
 			hash: node_hash,
 			status: Status::Entering,
-			node: Rc::new(node),
+			node,
+			inline_children: Vec::new(),
 		});
 	}
-}
-
-impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 
 	/// Seek a node position at 'key' for iterator.
 	/// Returns true if the cursor is at or after the key, but still shares
@@ -96,35 +694,195 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 	/// share its prefix with the node.
 	/// This indicates if there is still nodes to iterate over in the case
 	/// where we limit iteration to 'key' as a prefix.
-	fn seek_prefix(
+	pub(crate) fn seek_prefix(
 		&mut self,
+		db: &TrieDB<L>,
 		key: &[u8],
 	) -> Result<bool, TrieHash<L>, CError<L>> {
+		self.seek_inner(db, key, false)
+	}
+
+	/// Seek a node position at 'key' for iterator, see `TrieIterator::seek`: at the last key
+	/// not after 'key' for reversed iterators.
+	pub(crate) fn seek(
+		&mut self,
+		db: &TrieDB<L>,
+		key: &[u8],
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		if self.reversed {
+			self.seek_rev_inner(db, key, false)
+		} else {
+			self.seek_prefix(db, key).map(|_| ())
+		}
+	}
+
+	/// Seek a node position strictly after 'key' for iterator, see
+	/// `TrieIterator::seek_after`: strictly before 'key' for reversed iterators.
+	pub(crate) fn seek_after(
+		&mut self,
+		db: &TrieDB<L>,
+		key: &[u8],
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		if self.reversed {
+			self.seek_rev_inner(db, key, true)
+		} else {
+			self.seek_inner(db, key, true).map(|_| ())
+		}
+	}
+
+	/// Reverse the iteration and seek the last key not after 'key', see
+	/// `TrieDBNodeIterator::seek_rev`.
+	pub(crate) fn seek_rev(
+		&mut self,
+		db: &TrieDB<L>,
+		key: &[u8],
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		self.reversed = true;
+		self.seek_rev_inner(db, key, false)
+	}
+
+	/// Seek a node position at the last key not after 'key' for a reversed iterator, or
+	/// strictly before it when `before` is set. The nodes after the position are left as
+	/// already yielded: the branches on the path to 'key' are yielded after their children
+	/// before it.
+	fn seek_rev_inner(
+		&mut self,
+		db: &TrieDB<L>,
+		key: &[u8],
+		before: bool,
+	) -> Result<(), TrieHash<L>, CError<L>> {
 		self.trail.clear();
 		self.key_nibbles.clear();
 		let key = NibbleSlice::new(key);
 
-		let (mut node, mut node_hash) = self.db.get_raw_or_lookup(
+		let (mut node, mut node_hash) = db.get_shared_node(
+			<TrieHash<L>>::default(),
+			NodeHandle::Hash(db.root().as_ref()),
+			EMPTY_PREFIX
+		)?;
+		let mut partial = key;
+		let mut full_key_nibbles = 0;
+		loop {
+			self.descend(node, node_hash);
+			let crumb = self.trail.last_mut()
+				.expect("descend pushes a crumb onto the trail; qed");
+			let crumb_node = crumb.node.clone();
+			let node_data = crumb_node.data();
+
+			let (slice, extension_child, children) = match crumb_node.node_plan() {
+				NodePlan::Leaf { partial: partial_plan, .. } => {
+					let slice = partial_plan.build(node_data);
+					if slice > partial || (before && slice == partial) {
+						self.skip_last();
+					}
+					return Ok(());
+				},
+				NodePlan::Extension { partial: partial_plan, child } =>
+					(partial_plan.build(node_data), Some(child), None),
+				NodePlan::Branch { children, .. } => (NibbleSlice::new(&[]), None, Some(children)),
+				NodePlan::NibbledBranch { partial: partial_plan, children, .. } =>
+					(partial_plan.build(node_data), None, Some(children)),
+				NodePlan::Empty => return Ok(()),
+			};
+			if !partial.starts_with(&slice) {
+				// The keys of the node are all before or all after 'key'.
+				if slice > partial {
+					self.skip_last();
+				}
+				return Ok(());
+			}
+			full_key_nibbles += slice.len();
+			partial = partial.mid(slice.len());
+			self.key_nibbles.append_partial(slice.right());
+
+			let (index, child) = match children {
+				None => {
+					crumb.status = Status::At;
+					(0, extension_child)
+				},
+				Some(children) => {
+					let i = match partial.try_at(0) {
+						Some(i) => i,
+						None => {
+							// Only the value of the branch is not after 'key'.
+							if before {
+								self.key_nibbles.drop_lasts(slice.len());
+								self.skip_last();
+							} else {
+								crumb.status = Status::Exiting;
+								self.key_nibbles.push(0);
+							}
+							return Ok(());
+						},
+					};
+					crumb.status = Status::AtChild(i as usize);
+					self.key_nibbles.push(i);
+					full_key_nibbles += 1;
+					partial = partial.mid(1);
+					(i, children[i as usize].as_ref())
+				},
+			};
+			let child = match child {
+				Some(child) => child,
+				None => return Ok(()),
+			};
+			let prefix = key.back(full_key_nibbles);
+			let (next_node, next_node_hash) =
+				crumb.child(db, index, child.build(node_data), prefix.left())?;
+			node = next_node;
+			node_hash = next_node_hash;
+		}
+	}
+
+	/// Remove the last crumb of the trail, as done with.
+	fn skip_last(&mut self) {
+		self.trail.pop();
+		if let Some(parent) = self.trail.last_mut() {
+			parent.increment(self.reversed);
+		}
+	}
+
+	/// Seek a node position at 'key', or after it when `after` is set: the node at 'key' is
+	/// then left as already yielded, so its value is skipped but not its children.
+	fn seek_inner(
+		&mut self,
+		db: &TrieDB<L>,
+		key: &[u8],
+		after: bool,
+	) -> Result<bool, TrieHash<L>, CError<L>> {
+		// The previous trail keeps its decoded inline children for the nodes seeked again.
+		let mut previous_trail = mem::take(&mut self.trail);
+		self.key_nibbles.clear();
+		let key = NibbleSlice::new(key);
+
+		let (mut node, mut node_hash) = db.get_shared_node(
 			<TrieHash<L>>::default(),
-			NodeHandle::Hash(self.db.root().as_ref()),
+			NodeHandle::Hash(db.root().as_ref()),
 			EMPTY_PREFIX
 		)?;
 		let mut partial = key;
 		let mut full_key_nibbles = 0;
 		loop {
 			let (next_node, next_node_hash) = {
+				let depth = self.trail.len();
 				self.descend(node, node_hash);
 				let crumb = self.trail.last_mut()
 					.expect(
 						"descend_into_node pushes a crumb onto the trial; \
 						thus the trail is non-empty; qed"
 					);
-				let node_data = crumb.node.data();
+				if let Some(previous) = previous_trail.get_mut(depth) {
+					if previous.is_same_node(crumb) {
+						crumb.inline_children = mem::take(&mut previous.inline_children);
+					}
+				}
+				let node = crumb.node.clone();
+				let node_data = node.data();
 
-				match crumb.node.node_plan() {
+				match node.node_plan() {
 					NodePlan::Leaf { partial: partial_plan, .. } => {
 						let slice = partial_plan.build(node_data);
-						if slice < partial {
+						if slice < partial || (after && slice == partial) {
 							crumb.status = Status::Exiting;
 							return Ok(false);
 						}
@@ -147,18 +905,18 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 						self.key_nibbles.append_partial(slice.right());
 
 						let prefix = key.back(full_key_nibbles);
-						self.db.get_raw_or_lookup(
-							node_hash.unwrap_or_default(),
-							child.build(node_data),
-							prefix.left()
-						)?
+						crumb.child(db, 0, child.build(node_data), prefix.left())?
 					},
 					NodePlan::Branch { value: _, children } => {
-						if partial.is_empty() {
-							return Ok(true);
-						}
-
-						let i = partial.at(0);
+						let i = match partial.try_at(0) {
+							Some(i) => i,
+							None => {
+								if after {
+									crumb.status = Status::At;
+								}
+								return Ok(true);
+							},
+						};
 						crumb.status = Status::AtChild(i as usize);
 						self.key_nibbles.push(i);
 
@@ -167,11 +925,7 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 							partial = partial.mid(1);
 
 							let prefix = key.back(full_key_nibbles);
-							self.db.get_raw_or_lookup(
-								node_hash.unwrap_or_default(),
-								child.build(node_data),
-								prefix.left()
-							)?
+							crumb.child(db, i, child.build(node_data), prefix.left())?
 						} else {
 							return Ok(false);
 						}
@@ -182,7 +936,7 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 							if slice < partial {
 								crumb.status = Status::Exiting;
 								self.key_nibbles.append_partial(slice.right());
-								self.key_nibbles.push((nibble_ops::NIBBLE_LENGTH - 1) as u8);
+								self.key_nibbles.push((L::Nibble::NIBBLE_LENGTH - 1) as u8);
 								return Ok(false);
 							}
 							return Ok(slice.starts_with(&partial));
@@ -191,11 +945,15 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 						full_key_nibbles += slice.len();
 						partial = partial.mid(slice.len());
 
-						if partial.is_empty() {
-							return Ok(true);
-						}
-
-						let i = partial.at(0);
+						let i = match partial.try_at(0) {
+							Some(i) => i,
+							None => {
+								if after {
+									crumb.status = Status::At;
+								}
+								return Ok(true);
+							},
+						};
 						crumb.status = Status::AtChild(i as usize);
 						self.key_nibbles.append_partial(slice.right());
 						self.key_nibbles.push(i);
@@ -205,11 +963,7 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 							partial = partial.mid(1);
 
 							let prefix = key.back(full_key_nibbles);
-							self.db.get_raw_or_lookup(
-								node_hash.unwrap_or_default(),
-								child.build(node_data),
-								prefix.left()
-							)?
+							crumb.child(db, i, child.build(node_data), prefix.left())?
 						} else {
 							return Ok(false);
 						}
@@ -229,10 +983,56 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 		}
 	}
 
-	/// Advance the iterator into a prefix, no value out of the prefix will be accessed
-	/// or returned after this operation.
-	pub fn prefix(&mut self, prefix: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
-		if self.seek_prefix(prefix)? {
+	/// Part of the key space already iterated, see `TrieDBNodeIterator::progress`.
+	pub(crate) fn progress(&self) -> f64 {
+		let crumb = match self.trail.last() {
+			Some(crumb) => crumb,
+			None => return 1.0,
+		};
+		if self.reversed {
+			// The key space from the position is iterated.
+			return match self.reversed_position() {
+				Some(position) => 1.0 - key_space_before(&position).0,
+				None => 0.0,
+			};
+		}
+		let (mut progress, scale) = key_space_before(&self.key_nibbles);
+		// Past a child of the deepest node, or done with it: the key space up to the next child
+		// index at the last nibble is iterated.
+		match crumb.status {
+			Status::AtChild(_) | Status::Exiting => progress += scale,
+			Status::Entering | Status::At => {},
+		}
+		if progress > 1.0 {
+			1.0
+		} else {
+			progress
+		}
+	}
+
+	/// Seek `key`, or strictly after it if `after` is set, restricting the iteration to
+	/// `prefix` as `prefix` does, `key` starting with `prefix`.
+	pub(crate) fn seek_in_prefix(
+		&mut self,
+		db: &TrieDB<L>,
+		prefix: &[u8],
+		key: &[u8],
+		after: bool,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		if !self.seek_prefix(db, prefix)? {
+			self.trail.clear();
+			return Ok(())
+		}
+		// the nodes above the node of `prefix`, the same on the path of `key`.
+		let above = self.trail.len() - 1;
+		self.seek_inner(db, key, after)?;
+		self.trail.drain(..above.min(self.trail.len()));
+		Ok(())
+	}
+
+	/// Restrict the iteration to `prefix`, see `TrieDBNodeIterator::prefix`.
+	pub(crate) fn prefix(&mut self, db: &TrieDB<L>, prefix: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		if self.seek_prefix(db, prefix)? {
 			if let Some(v) = self.trail.pop() {
 				self.trail.clear();
 				self.trail.push(v);
@@ -244,34 +1044,26 @@ impl<'a, L: TrieLayout> TrieDBNodeIterator<'a, L> {
 		Ok(())
 	}
 
-}
-
-impl<'a, L: TrieLayout> TrieIterator<L> for TrieDBNodeIterator<'a, L> {
-	fn seek(
+	/// Move to the next node, see `TrieDBNodeIterator::next_node`.
+	pub(crate) fn next_node(
 		&mut self,
-		key: &[u8],
-	) -> Result<(), TrieHash<L>, CError<L>> {
-		self.seek_prefix(key)
-			.map(|_| ())
-	}
-}
-
-impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
-	type Item = Result<(NibbleVec, Option<TrieHash<L>>, Rc<OwnedNode<DBValue>>), TrieHash<L>, CError<L>>;
-
-	fn next(&mut self) -> Option<Self::Item> {
-		enum IterStep<O, E> {
+		db: &TrieDB<L>,
+	) -> Option<BorrowedNodeItem<'_, L>> {
+		enum IterStep<O, E, N: NibbleOps> {
 			YieldNode,
 			PopTrail,
+			PopAndYield,
 			Continue,
-			Descend(Result<(OwnedNode<DBValue>, Option<O>), O, E>),
+			Descend(Result<HashedNode<O, N>, O, E>),
 		}
 		loop {
 			let iter_step = {
 				let b = self.trail.last_mut()?;
-				let node_data = b.node.data();
+				let node = b.node.clone();
+				let node_data = node.data();
 
-				match (b.status, b.node.node_plan()) {
+				match (b.status, node.node_plan()) {
+					(Status::Entering, _) if self.reversed => IterStep::Continue,
 					(Status::Entering, _) => IterStep::YieldNode,
 					(Status::Exiting, node) => {
 						match node {
@@ -284,17 +1076,17 @@ impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
 								self.key_nibbles.drop_lasts(partial.len() + 1);
 							},
 						}
-						IterStep::PopTrail
+						if self.reversed {
+							IterStep::PopAndYield
+						} else {
+							IterStep::PopTrail
+						}
 					},
 					(Status::At, NodePlan::Extension { partial: partial_plan, child }) => {
 						let partial = partial_plan.build(node_data);
 						self.key_nibbles.append_partial(partial.right());
-						IterStep::Descend::<TrieHash<L>, CError<L>>(
-							self.db.get_raw_or_lookup(
-								b.hash.unwrap_or_default(),
-								child.build(node_data),
-								self.key_nibbles.as_prefix()
-							)
+						IterStep::Descend::<TrieHash<L>, CError<L>, L::Nibble>(
+							b.child(db, 0, child.build(node_data), self.key_nibbles.as_prefix())
 						)
 					},
 					(Status::At, NodePlan::Branch { .. }) => {
@@ -312,12 +1104,8 @@ impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
 						if let Some(child) = &children[i] {
 							self.key_nibbles.pop();
 							self.key_nibbles.push(i as u8);
-							IterStep::Descend::<TrieHash<L>, CError<L>>(
-								self.db.get_raw_or_lookup(
-									b.hash.unwrap_or_default(),
-									child.build(node_data),
-									self.key_nibbles.as_prefix()
-								)
+							IterStep::Descend::<TrieHash<L>, CError<L>, L::Nibble>(
+								b.child(db, i as u8, child.build(node_data), self.key_nibbles.as_prefix())
 							)
 						} else {
 							IterStep::Continue
@@ -338,9 +1126,10 @@ impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
 							trial could not have been modified within the block since it was immutably borrowed;\
 							qed"
 						);
-					crumb.increment();
+					crumb.increment(self.reversed);
+					profile!(iterator_steps);
 					return Some(Ok((
-						self.key_nibbles.clone(),
+						&self.key_nibbles,
 						crumb.hash.clone(),
 						crumb.node.clone()
 					)));
@@ -353,12 +1142,25 @@ impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
 							qed"
 						);
 					self.trail.last_mut()?
-						.increment();
+						.increment(self.reversed);
+				},
+				IterStep::PopAndYield => {
+					let crumb = self.trail.pop()
+						.expect(
+							"method would have exited at top of previous block if trial were empty;\
+							trial could not have been modified within the block since it was immutably borrowed;\
+							qed"
+						);
+					if let Some(parent) = self.trail.last_mut() {
+						parent.increment(true);
+					}
+					profile!(iterator_steps);
+					return Some(Ok((&self.key_nibbles, crumb.hash, crumb.node)));
 				},
-				IterStep::Descend::<TrieHash<L>, CError<L>>(Ok((node, node_hash))) => {
+				IterStep::Descend::<TrieHash<L>, CError<L>, L::Nibble>(Ok((node, node_hash))) => {
 					self.descend(node, node_hash);
 				},
-				IterStep::Descend::<TrieHash<L>, CError<L>>(Err(err)) => {
+				IterStep::Descend::<TrieHash<L>, CError<L>, L::Nibble>(Err(err)) => {
 					// Increment here as there is an implicit PopTrail.
 					self.trail.last_mut()
 						.expect(
@@ -366,7 +1168,7 @@ impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
 								trial could not have been modified within the block since it was immutably borrowed;\
 								qed"
 						)
-						.increment();
+						.increment(self.reversed);
 					return Some(Err(err));
 				},
 				IterStep::Continue => {
@@ -376,7 +1178,7 @@ impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
 							trial could not have been modified within the block since it was immutably borrowed;\
 							qed"
 						)
-						.increment();
+						.increment(self.reversed);
 				},
 			}
 		}
@@ -385,55 +1187,22 @@ impl<'a, L: TrieLayout> Iterator for TrieDBNodeIterator<'a, L> {
 
 #[cfg(test)]
 mod tests {
-	use crate::DBValue;
 	use hex_literal::hex;
-	use hash_db::{HashDB, Hasher};
-	use keccak_hasher::KeccakHasher;
 	use reference_trie::{
-		RefTrieDB, RefTrieDBMut,
-		TrieError, TrieMut, TrieIterator, TrieDBNodeIterator, NibbleSlice, NibbleVec,
+		TrieError, TrieIterator, TrieDBIterator, TrieDBNodeIterator, NibbleSlice, NibbleVec,
 		node::Node,
 	};
-	use reference_trie::{RefTrieDBNoExt, RefTrieDBMutNoExt};
-
-	type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
-
-	fn build_trie_db_with_extension(pairs: &[(Vec<u8>, Vec<u8>)])
-		-> (MemoryDB, <KeccakHasher as Hasher>::Out)
-	{
-		let mut memdb = MemoryDB::default();
-		let mut root = Default::default();
-		{
-			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
-			for (x, y) in pairs.iter() {
-				t.insert(x, y).unwrap();
-			}
-		}
-		(memdb, root)
-	}
-
-	fn build_trie_db_without_extension(pairs: &[(Vec<u8>, Vec<u8>)])
-		-> (MemoryDB, <KeccakHasher as Hasher>::Out)
-	{
-		let mut memdb = MemoryDB::default();
-		let mut root = Default::default();
-		{
-			let mut t = RefTrieDBMutNoExt::new(&mut memdb, &mut root);
-			for (x, y) in pairs.iter() {
-				t.insert(x, y).unwrap();
-			}
-		}
-		(memdb, root)
-	}
+	use reference_trie::{ExtensionLayout, NoExtensionLayout};
+	use reference_trie::{decode_count, CountingLayout, Trie};
+	use reference_trie::builder::TrieBuilder;
 
 	fn nibble_vec<T: AsRef<[u8]>>(bytes: T, len: usize) -> NibbleVec {
-		let slice = NibbleSlice::new(bytes.as_ref());
-
-		let mut v = NibbleVec::new();
-		for i in 0..len {
-			v.push(slice.at(i));
-		}
-		v
+		let bytes = bytes.as_ref();
+		let last = match len % 2 {
+			0 => None,
+			_ => Some(bytes[len / 2]),
+		};
+		NibbleVec::from_prefix((&bytes[..len / 2], last))
 	}
 
 	#[test]
@@ -444,8 +1213,8 @@ mod tests {
 			(hex!("02").to_vec(), vec![1; 32]),
 		];
 
-		let (memdb, root) = build_trie_db_with_extension(&pairs);
-		let trie = RefTrieDB::new(&memdb, &root).unwrap();
+		let built = TrieBuilder::<ExtensionLayout>::new().extend(pairs).build();
+		let trie = built.trie();
 		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 		match iter.next() {
@@ -517,8 +1286,8 @@ mod tests {
 			(hex!("02").to_vec(), vec![1; 32]),
 		];
 
-		let (memdb, root) = build_trie_db_without_extension(&pairs);
-		let trie = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+		let built = TrieBuilder::<NoExtensionLayout>::new().extend(pairs).build();
+		let trie = built.trie();
 		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 		match iter.next() {
@@ -575,8 +1344,8 @@ mod tests {
 
 	#[test]
 	fn iterator_over_empty_works() {
-		let (memdb, root) = build_trie_db_with_extension(&[]);
-		let trie = RefTrieDB::new(&memdb, &root).unwrap();
+		let built = TrieBuilder::<ExtensionLayout>::new().build();
+		let trie = built.trie();
 		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 		match iter.next() {
@@ -601,8 +1370,8 @@ mod tests {
 			(hex!("02").to_vec(), vec![1; 32]),
 		];
 
-		let (memdb, root) = build_trie_db_with_extension(&pairs);
-		let trie = RefTrieDB::new(&memdb, &root).unwrap();
+		let built = TrieBuilder::<ExtensionLayout>::new().extend(pairs).build();
+		let trie = built.trie();
 		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 		TrieIterator::seek(&mut iter, &hex!("")[..]).unwrap();
@@ -638,6 +1407,44 @@ mod tests {
 	}
 
 
+	#[test]
+	fn seek_after_skips_the_key_only() {
+		let pairs = vec![
+			(hex!("01").to_vec(), b"aaaa".to_vec()),
+			(hex!("0123").to_vec(), b"bbbb".to_vec()),
+			(hex!("02").to_vec(), vec![1; 32]),
+		];
+
+		let built = TrieBuilder::<ExtensionLayout>::new().extend(pairs).build();
+		let trie = built.trie();
+		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
+
+		// the branch at "01" is skipped, but not its child.
+		TrieIterator::seek_after(&mut iter, &hex!("01")[..]).unwrap();
+		match iter.next() {
+			Some(Ok((prefix, _, _))) =>
+				assert_eq!(prefix, nibble_vec(hex!("0120"), 3)),
+			_ => panic!("unexpected item"),
+		}
+
+		TrieIterator::seek_after(&mut iter, &hex!("0123")[..]).unwrap();
+		match iter.next() {
+			Some(Ok((prefix, _, _))) =>
+				assert_eq!(prefix, nibble_vec(hex!("02"), 2)),
+			_ => panic!("unexpected item"),
+		}
+
+		TrieIterator::seek_after(&mut iter, &hex!("0122")[..]).unwrap();
+		match iter.next() {
+			Some(Ok((prefix, _, _))) =>
+				assert_eq!(prefix, nibble_vec(hex!("0120"), 3)),
+			_ => panic!("unexpected item"),
+		}
+
+		TrieIterator::seek_after(&mut iter, &hex!("02")[..]).unwrap();
+		assert!(iter.next().is_none());
+	}
+
 	#[test]
 	fn seek_works_without_extension() {
 		let pairs = vec![
@@ -646,8 +1453,8 @@ mod tests {
 			(hex!("02").to_vec(), vec![1; 32]),
 		];
 
-		let (memdb, root) = build_trie_db_without_extension(&pairs);
-		let trie = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+		let built = TrieBuilder::<NoExtensionLayout>::new().extend(pairs).build();
+		let trie = built.trie();
 		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 		TrieIterator::seek(&mut iter, &hex!("")[..]).unwrap();
@@ -684,8 +1491,8 @@ mod tests {
 
 	#[test]
 	fn seek_over_empty_works() {
-		let (memdb, root) = build_trie_db_with_extension(&[]);
-		let trie = RefTrieDB::new(&memdb, &root).unwrap();
+		let built = TrieBuilder::<ExtensionLayout>::new().build();
+		let trie = built.trie();
 		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 		TrieIterator::seek(&mut iter, &hex!("")[..]).unwrap();
@@ -713,11 +1520,11 @@ mod tests {
 			(hex!("03").to_vec(), vec![2; 32]),
 		];
 
-		let (mut memdb, root) = build_trie_db_with_extension(&pairs);
+		let mut built = TrieBuilder::<ExtensionLayout>::new().extend(pairs).build();
 
 		// Look up the leaf node with prefix "02".
 		let leaf_hash = {
-			let trie = RefTrieDB::new(&memdb, &root).unwrap();
+			let trie = built.trie();
 			let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 			TrieIterator::seek(&mut iter, &hex!("02")[..]).unwrap();
@@ -733,28 +1540,27 @@ mod tests {
 		};
 
 		// Remove the leaf node from the DB.
-		let prefix = (&hex!("02")[..], None);
-		memdb.remove(&leaf_hash, prefix);
+		built.remove_node(&leaf_hash);
 
 		// Seek to missing node returns error.
 		{
-			let trie = RefTrieDB::new(&memdb, &root).unwrap();
+			let trie = built.trie();
 			let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 			match TrieIterator::seek(&mut iter, &hex!("02")[..]) {
-				Err(ref err) if **err == TrieError::IncompleteDatabase(leaf_hash) => {},
+				Err(ref err) if err.missing_node_hash() == Some(&leaf_hash) => {},
 				_ => panic!("expected IncompleteDatabase error"),
 			}
 		}
 
 		// Iterate over missing node works.
 		{
-			let trie = RefTrieDB::new(&memdb, &root).unwrap();
+			let trie = built.trie();
 			let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 			TrieIterator::seek(&mut iter, &hex!("0130")[..]).unwrap();
 			match iter.next() {
-				Some(Err(ref err)) if **err == TrieError::IncompleteDatabase(leaf_hash) => {},
+				Some(Err(ref err)) if err.missing_node_hash() == Some(&leaf_hash) => {},
 				_ => panic!("expected IncompleteDatabase error"),
 			}
 			match iter.next() {
@@ -780,8 +1586,8 @@ mod tests {
 			(hex!("02").to_vec(), vec![1; 32]),
 		];
 
-		let (memdb, root) = build_trie_db_with_extension(&pairs);
-		let trie = RefTrieDB::new(&memdb, &root).unwrap();
+		let built = TrieBuilder::<ExtensionLayout>::new().extend(pairs).build();
+		let trie = built.trie();
 		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 		iter.prefix(&hex!("01").to_vec()[..]).unwrap();
@@ -828,8 +1634,8 @@ mod tests {
 			(hex!("02").to_vec(), vec![1; 32]),
 		];
 
-		let (memdb, root) = build_trie_db_without_extension(&pairs);
-		let trie = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+		let built = TrieBuilder::<NoExtensionLayout>::new().extend(pairs).build();
+		let trie = built.trie();
 		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 
 		iter.prefix(&hex!("01").to_vec()[..]).unwrap();
@@ -871,8 +1677,8 @@ mod tests {
 
 	#[test]
 	fn prefix_over_empty_works() {
-		let (memdb, root) = build_trie_db_with_extension(&[]);
-		let trie = RefTrieDB::new(&memdb, &root).unwrap();
+		let built = TrieBuilder::<ExtensionLayout>::new().build();
+		let trie = built.trie();
 		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
 		iter.prefix(&hex!("")[..]).unwrap();
 		match iter.next() {
@@ -892,5 +1698,491 @@ mod tests {
 		iter.prefix(&hex!("00")[..]).unwrap();
 		assert!(iter.next().is_none());
 	}
-}
 
+	#[test]
+	fn inline_nodes_are_decoded_once() {
+		// Small keys and values, so that the subtrees under the root are nested inline nodes.
+		let mut pairs = Vec::new();
+		for i in 1..4u8 {
+			for j in 0..3u8 {
+				pairs.push((vec![i, j], vec![j]));
+			}
+		}
+
+		let built = TrieBuilder::<CountingLayout>::new().extend(pairs.iter().cloned()).build();
+		let trie = built.trie();
+
+		let before = decode_count();
+		let nodes: Vec<_> = TrieDBNodeIterator::new(&trie).unwrap()
+			.map(|item| item.unwrap())
+			.collect();
+		assert_eq!(decode_count() - before, nodes.len());
+		assert!(nodes.iter().filter(|(_, hash, _)| hash.is_none()).count() > 6);
+
+		let mut iter = trie.iter().unwrap();
+		let before = decode_count();
+		iter.seek(&[2, 1]).unwrap();
+		// Root, inline branch and inline leaf.
+		assert_eq!(decode_count() - before, 3);
+		assert_eq!(iter.next().unwrap().unwrap(), (vec![2, 1], vec![1]));
+
+		// Seeking again only decodes the root, fetched from the database.
+		let before = decode_count();
+		iter.seek(&[2, 1]).unwrap();
+		assert_eq!(decode_count() - before, 1);
+		let items: Vec<_> = iter.map(|item| item.unwrap()).collect();
+		assert_eq!(items, pairs[4..].to_vec());
+	}
+
+	/// Trie of `count` keys of two bytes spread evenly over the key space.
+	fn uniform_trie<L: reference_trie::TrieLayout>(count: u32) -> reference_trie::builder::BuiltTrie<L> {
+		TrieBuilder::<L>::new()
+			.extend((0..count).map(|i| (((i << 16) / count) as u16).to_be_bytes()).map(|key| (key, key)))
+			.build()
+	}
+
+	fn progress_is_monotonic<L: reference_trie::TrieLayout>() {
+		let built = uniform_trie::<L>(1000);
+		let trie = built.trie();
+
+		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
+		let mut last = iter.progress();
+		assert_eq!(last, 0.0);
+		while let Some(item) = iter.next() {
+			item.unwrap();
+			assert!(iter.progress() >= last);
+			last = iter.progress();
+		}
+		assert_eq!(iter.progress(), 1.0);
+
+		let mut iter = TrieDBIterator::new(&trie).unwrap();
+		let mut last = 0.0;
+		let mut count = 0;
+		while let Some(item) = iter.next() {
+			item.unwrap();
+			count += 1;
+			assert!(iter.progress() >= last);
+			last = iter.progress();
+		}
+		assert_eq!(count, 1000);
+		assert_eq!(iter.progress(), 1.0);
+	}
+
+	#[test]
+	fn progress_is_monotonic_with_extension() {
+		progress_is_monotonic::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn progress_is_monotonic_without_extension() {
+		progress_is_monotonic::<NoExtensionLayout>();
+	}
+
+	#[test]
+	fn progress_is_accurate_at_midpoint() {
+		let built = uniform_trie::<NoExtensionLayout>(1000);
+		let trie = built.trie();
+		let mut iter = TrieDBIterator::new(&trie).unwrap();
+		for _ in 0..500 {
+			iter.next().unwrap().unwrap();
+		}
+		assert!((iter.progress() - 0.5).abs() < 0.05, "progress {}", iter.progress());
+	}
+
+	#[test]
+	fn progress_over_empty_and_missing_nodes() {
+		let built = TrieBuilder::<ExtensionLayout>::new().build();
+		let trie = built.trie();
+		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
+		assert_eq!(iter.progress(), 0.0);
+		iter.next().unwrap().unwrap();
+		assert_eq!(iter.progress(), 1.0);
+
+		// The error on the missing leaf under nibble 1 moves past its subtree.
+		let mut built = TrieBuilder::<ExtensionLayout>::new()
+			.insert([0x10], [1; 32])
+			.insert([0x20], [2; 32])
+			.build();
+		let leaf_hash = {
+			let trie = built.trie();
+			let iter = TrieDBNodeIterator::new(&trie).unwrap();
+			iter.map(|item| item.unwrap())
+				.filter_map(|(prefix, hash, _)| hash.filter(|_| prefix.len() == 1))
+				.next()
+				.unwrap()
+		};
+		built.remove_node(&leaf_hash);
+		let trie = built.trie();
+		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
+		iter.next().unwrap().unwrap();
+		assert!(iter.next().unwrap().is_err());
+		assert_eq!(iter.progress(), 2.0 / 16.0);
+	}
+
+	fn resume_pairs() -> Vec<(Vec<u8>, Vec<u8>)> {
+		(0..200u32).map(|i| (i.to_be_bytes()[2..].to_vec(), vec![i as u8; 1 + i as usize % 40])).collect()
+	}
+
+	#[test]
+	fn saved_iteration_resumes_on_the_same_root() {
+		let built = TrieBuilder::<ExtensionLayout>::new().extend(resume_pairs()).build();
+		let trie = built.trie();
+		let nodes: Vec<_> = TrieDBNodeIterator::new(&trie).unwrap().map(|item| item.unwrap()).collect();
+
+		for split in [0, 1, nodes.len() / 2, nodes.len() - 1, nodes.len()] {
+			let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
+			let mut resumed: Vec<_> = iter.by_ref().take(split).map(|item| item.unwrap()).collect();
+			let state = iter.save();
+			assert_eq!(state.root(), built.root());
+
+			// The nodes are created again in another database, as after their pruning.
+			let rebuilt = TrieBuilder::<ExtensionLayout>::new().extend(resume_pairs()).build();
+			let trie = rebuilt.trie();
+			let iter = TrieDBNodeIterator::restore(&trie, &state).unwrap();
+			resumed.extend(iter.map(|item| item.unwrap()));
+			assert_eq!(resumed, nodes);
+		}
+
+		let pairs = resume_pairs();
+		let mut iter = TrieDBIterator::new(&trie).unwrap();
+		let mut resumed: Vec<_> = iter.by_ref().take(77).map(|item| item.unwrap()).collect();
+		let iter = TrieDBIterator::restore(&trie, &iter.save()).unwrap();
+		resumed.extend(iter.map(|item| item.unwrap()));
+		assert_eq!(resumed, pairs);
+	}
+
+	#[test]
+	fn saved_iteration_fails_on_another_trie() {
+		let built = TrieBuilder::<NoExtensionLayout>::new().extend(resume_pairs()).build();
+		let trie = built.trie();
+		let mut iter = TrieDBIterator::new(&trie).unwrap();
+		iter.by_ref().take(100).for_each(|item| { item.unwrap(); });
+		let state = iter.save();
+
+		let changed = TrieBuilder::<NoExtensionLayout>::new()
+			.extend(resume_pairs())
+			.insert(b"new", b"value")
+			.build();
+		let trie = changed.trie();
+		match TrieDBIterator::restore(&trie, &state).map(|_| ()).map_err(|error| *error) {
+			Err(TrieError::ResumeRootMismatch { expected, found }) => {
+				assert_eq!(&expected, built.root());
+				assert_eq!(&found, changed.root());
+			},
+			result => panic!("unexpected result: {:?}", result),
+		}
+
+		// A node of the path pruned and not created again is reported at its depth.
+		let mut pruned = TrieBuilder::<NoExtensionLayout>::new().extend(resume_pairs()).build();
+		let (path_hash, state) = {
+			let trie = pruned.trie();
+			let nodes: Vec<_> = TrieDBNodeIterator::new(&trie).unwrap().map(|item| item.unwrap()).collect();
+			// The first branch under the root, saved once its first child is iterated.
+			let index = nodes.iter()
+				.position(|(prefix, hash, node)| {
+					!prefix.is_empty() && hash.is_some() && matches!(node.node(), Node::NibbledBranch(..))
+				})
+				.unwrap();
+			let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
+			iter.by_ref().take(index + 2).for_each(|item| { item.unwrap(); });
+			(nodes[index].1.unwrap(), iter.save())
+		};
+		pruned.remove_node(&path_hash);
+		let trie = pruned.trie();
+		match TrieDBNodeIterator::restore(&trie, &state).map(|_| ()).map_err(|error| *error) {
+			Err(TrieError::IncompleteDatabase { hash, .. }) => assert_eq!(hash, path_hash),
+			result => panic!("unexpected result: {:?}", result),
+		}
+	}
+
+	#[test]
+	fn saved_iteration_rebases_on_another_trie() {
+		let pairs = resume_pairs();
+		let built = TrieBuilder::<ExtensionLayout>::new().extend(pairs.iter().cloned()).build();
+		let changed_pairs: Vec<_> = pairs.iter()
+			.filter(|(key, _)| key[1] % 3 != 0)
+			.cloned()
+			.chain((0..200u32).map(|i| ((i * 7).to_be_bytes()[1..].to_vec(), vec![7])))
+			.collect();
+		let changed = TrieBuilder::<ExtensionLayout>::new().extend(changed_pairs.iter().cloned()).build();
+		// The pairs in key order, the last value inserted at a key kept.
+		let changed_pairs: Vec<_> = changed.trie().iter().unwrap().map(|item| item.unwrap()).collect();
+
+		let trie = built.trie();
+		for split in [0, 1, 100, 199, 200] {
+			let mut iter = TrieDBIterator::new(&trie).unwrap();
+			let last = iter.by_ref().take(split).map(|item| item.unwrap().0).last();
+			let state = iter.save().rebase(&changed.trie()).unwrap();
+			assert_eq!(state.root(), changed.root());
+
+			let changed_trie = changed.trie();
+			let resumed: Vec<_> = TrieDBIterator::restore(&changed_trie, &state).unwrap()
+				.map(|item| item.unwrap())
+				.collect();
+			let expected: Vec<_> = changed_pairs.iter()
+				.filter(|(key, _)| last.as_ref().is_none_or(|last| key > last))
+				.cloned()
+				.collect();
+			assert_eq!(resumed, expected, "resumed after {:?}", last);
+		}
+
+		// Nodes are iterated from the first node not iterated yet too.
+		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
+		iter.by_ref().take(5).for_each(|item| { item.unwrap(); });
+		let state = iter.save().rebase(&built.trie()).unwrap();
+		let resumed: Vec<_> = TrieDBNodeIterator::restore(&trie, &state).unwrap()
+			.map(|item| item.unwrap().0)
+			.collect();
+		let expected: Vec<_> = TrieDBNodeIterator::new(&trie).unwrap()
+			.map(|item| item.unwrap().0)
+			.skip(5)
+			.collect();
+		assert_eq!(resumed, expected);
+	}
+
+	/// Pairs with values in branches, keys of several lengths and inline nodes.
+	fn reversed_pairs() -> Vec<(Vec<u8>, Vec<u8>)> {
+		let mut pairs = resume_pairs();
+		pairs.extend(vec![
+			(vec![], vec![0; 40]),
+			(vec![0x00], vec![1]),
+			(vec![0x00, 0x10, 0x00], vec![2; 33]),
+			(vec![0x01], vec![3]),
+			(vec![0x01, 0x23, 0x45], vec![4]),
+			(vec![0xf0], vec![5; 32]),
+		]);
+		pairs.sort();
+		pairs
+	}
+
+	fn check_reversed_iteration<L: reference_trie::TrieLayout>() {
+		let pairs = reversed_pairs();
+		let built = TrieBuilder::<L>::new().extend(pairs.iter().cloned()).build();
+		let trie = built.trie();
+
+		let mut nodes: Vec<_> = TrieDBNodeIterator::new(&trie).unwrap()
+			.map(|item| item.unwrap())
+			.collect();
+		nodes.reverse();
+		let reversed: Vec<_> = TrieDBNodeIterator::new_reversed(&trie).unwrap()
+			.map(|item| item.unwrap())
+			.collect();
+		assert_eq!(reversed, nodes);
+
+		let reversed_pairs = |iter: TrieDBIterator<L>| {
+			iter.map(|item| item.unwrap()).collect::<Vec<_>>()
+		};
+		let expected: Vec<_> = pairs.iter().rev().cloned().collect();
+		assert_eq!(reversed_pairs(TrieDBIterator::new_reversed(&trie).unwrap()), expected);
+
+		// Keys of the trie, between them, before the first one and after the last one.
+		let mut sought = vec![vec![], vec![0xff; 3], vec![0x00, 0x00], vec![0x00, 0x0f]];
+		for (key, _) in pairs.iter() {
+			sought.push(key.clone());
+			let mut after = key.clone();
+			after.push(0);
+			sought.push(after);
+			if let Some(last) = key.last() {
+				let mut before = key.clone();
+				*before.last_mut().unwrap() = last.wrapping_sub(1);
+				sought.push(before);
+			}
+		}
+		for key in sought {
+			let mut iter = TrieDBIterator::new(&trie).unwrap();
+			iter.seek_rev(&key).unwrap();
+			let expected: Vec<_> = pairs.iter().rev().filter(|(k, _)| *k <= key).cloned().collect();
+			assert_eq!(reversed_pairs(iter), expected, "seek_rev {:?}", key);
+
+			let mut iter = TrieDBIterator::new_reversed(&trie).unwrap();
+			TrieIterator::seek_after(&mut iter, &key).unwrap();
+			let expected: Vec<_> = pairs.iter().rev().filter(|(k, _)| *k < key).cloned().collect();
+			assert_eq!(reversed_pairs(iter), expected, "seek_after {:?}", key);
+		}
+	}
+
+	#[test]
+	fn reversed_iteration_with_extension() {
+		check_reversed_iteration::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn reversed_iteration_without_extension() {
+		check_reversed_iteration::<NoExtensionLayout>();
+	}
+
+	#[test]
+	fn reversed_iteration_over_empty_works() {
+		let built = TrieBuilder::<ExtensionLayout>::new().build();
+		let trie = built.trie();
+		let mut iter = TrieDBNodeIterator::new_reversed(&trie).unwrap();
+		assert_eq!(iter.progress(), 0.0);
+		match iter.next() {
+			Some(Ok((prefix, Some(_), node))) => {
+				assert!(prefix.is_empty());
+				assert_eq!(node.node(), Node::Empty);
+			},
+			_ => panic!("unexpected item"),
+		}
+		assert!(iter.next().is_none());
+		assert_eq!(iter.progress(), 1.0);
+
+		let mut iter = TrieDBIterator::new(&trie).unwrap();
+		iter.seek_rev(&hex!("01")).unwrap();
+		assert!(iter.next().is_none());
+	}
+
+	#[test]
+	fn reversed_iteration_progresses_and_resumes() {
+		let built = uniform_trie::<NoExtensionLayout>(1000);
+		let trie = built.trie();
+		let mut iter = TrieDBIterator::new_reversed(&trie).unwrap();
+		let mut last = iter.progress();
+		assert_eq!(last, 0.0);
+		for _ in 0..500 {
+			iter.next().unwrap().unwrap();
+			assert!(iter.progress() >= last);
+			last = iter.progress();
+		}
+		assert!((iter.progress() - 0.5).abs() < 0.05, "progress {}", iter.progress());
+
+		let pairs = reversed_pairs();
+		let built = TrieBuilder::<ExtensionLayout>::new().extend(pairs.iter().cloned()).build();
+		let trie = built.trie();
+		let expected: Vec<_> = pairs.iter().rev().cloned().collect();
+		let changed_pairs: Vec<_> = pairs.iter()
+			.filter(|(key, _)| key.len() != 2 || key[1] % 3 != 0)
+			.cloned()
+			.chain((0..50u8).map(|i| (vec![0x00, i * 5, 0x01], vec![7])))
+			.collect();
+		let changed = TrieBuilder::<ExtensionLayout>::new().extend(changed_pairs).build();
+		let changed_pairs: Vec<_> = changed.trie().iter().unwrap().map(|item| item.unwrap()).collect();
+		for split in [0, 1, 100, pairs.len()] {
+			let mut iter = TrieDBIterator::new_reversed(&trie).unwrap();
+			let mut resumed: Vec<_> = iter.by_ref().take(split).map(|item| item.unwrap()).collect();
+			let state = iter.save();
+			let restored = TrieDBIterator::restore(&trie, &state).unwrap();
+			let rebased = TrieDBIterator::restore(&trie, &state.rebase(&trie).unwrap()).unwrap();
+			let rebased: Vec<_> = resumed.iter().cloned()
+				.chain(rebased.map(|item| item.unwrap()))
+				.collect();
+			resumed.extend(restored.map(|item| item.unwrap()));
+			assert_eq!(resumed, expected);
+			assert_eq!(rebased, expected);
+
+			// On another trie, from the key before the last key iterated.
+			let last = expected[..split].last().map(|(key, _)| key.clone());
+			let changed_trie = changed.trie();
+			let state = state.rebase(&changed_trie).unwrap();
+			let rebased: Vec<_> = TrieDBIterator::restore(&changed_trie, &state).unwrap()
+				.map(|item| item.unwrap())
+				.collect();
+			let changed_expected: Vec<_> = changed_pairs.iter().rev()
+				.filter(|(key, _)| last.as_ref().is_none_or(|last| key < last))
+				.cloned()
+				.collect();
+			assert_eq!(rebased, changed_expected, "rebased before {:?}", last);
+		}
+	}
+
+	#[test]
+	fn next_node_borrows_the_items_of_next() {
+		let pairs: Vec<_> = (0..100u8).map(|i| (vec![i / 3, i], vec![i; 1 + i as usize % 40])).collect();
+		let built = TrieBuilder::<ExtensionLayout>::new().extend(pairs).build();
+		let trie = built.trie();
+
+		let expected: Vec<_> = TrieDBNodeIterator::new(&trie).unwrap()
+			.map(|item| item.unwrap())
+			.collect();
+		let mut iter = TrieDBNodeIterator::new(&trie).unwrap();
+		let mut items = Vec::new();
+		while let Some(item) = iter.next_node() {
+			let (prefix, hash, node) = item.unwrap();
+			items.push((prefix.clone(), hash, node));
+		}
+		assert_eq!(items, expected);
+	}
+
+	#[test]
+	fn node_hashes_are_the_hashes_of_the_stored_nodes() {
+		use hash_db::Hasher;
+		use keccak_hasher::KeccakHasher;
+
+		// leaves with short values are inline in their branch, the others are stored.
+		let pairs: Vec<_> = (0..40u8).map(|i| (vec![i], vec![i; 1 + (i % 2) as usize * 40])).collect();
+		let built = TrieBuilder::<ExtensionLayout>::new().extend(pairs).build();
+		let trie = built.trie();
+
+		let (mut inline, mut stored) = (0, 0);
+		for item in TrieDBNodeIterator::new(&trie).unwrap() {
+			let (prefix, hash, node) = item.unwrap();
+			match hash {
+				Some(hash) => {
+					stored += 1;
+					assert_eq!(hash, KeccakHasher::hash(node.data()));
+					assert_eq!(prefix.is_empty(), hash == *trie.root());
+				},
+				None => {
+					inline += 1;
+					assert!(node.data().len() < KeccakHasher::LENGTH);
+					assert!(matches!(node.node(), Node::Leaf(..)));
+				},
+			}
+		}
+		// the root branch, one branch per first nibble and the leaves with long values.
+		assert_eq!((inline, stored), (20, 24));
+	}
+
+	#[test]
+	fn recorded_iteration_proves_the_range() {
+		use hash_db::Hasher;
+		use keccak_hasher::KeccakHasher;
+		use reference_trie::{Recorder, TrieDB};
+
+		let pairs: Vec<_> = (0..300u16)
+			.map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 1 + i as usize % 40]))
+			.collect();
+		let built = TrieBuilder::<ExtensionLayout>::new().extend(pairs).build();
+		let trie = built.trie();
+		let (start, end) = (&[0x00, 0x50][..], &[0x01, 0x10][..]);
+		let range = |iter: TrieDBIterator<ExtensionLayout>| -> Vec<_> {
+			iter.map(|item| item.unwrap()).take_while(|(key, _)| &key[..] < end).collect()
+		};
+
+		let mut recorder = Recorder::new();
+		let expected = {
+			let mut iter = TrieDBIterator::new_with_recorder(&trie, &mut recorder).unwrap();
+			iter.seek(start).unwrap();
+			range(iter)
+		};
+		assert_eq!(expected.len(), 0x110 - 0x50);
+		let records = recorder.clone().drain();
+		// only the nodes fetched by hash are recorded, with the depths of the lookups.
+		assert!(records.iter().all(|record| record.hash == KeccakHasher::hash(&record.data)));
+		for (key, _) in expected.iter() {
+			let mut lookup = Recorder::new();
+			trie.get_with(key, &mut lookup).unwrap().unwrap();
+			assert!(lookup.drain().iter().all(|record| records.contains(record)));
+		}
+
+		let (partial_db, root) = recorder.into_partial_db::<ExtensionLayout>().unwrap();
+		let partial = TrieDB::<ExtensionLayout>::new(&partial_db, &root).unwrap();
+		let mut iter = TrieDBIterator::new(&partial).unwrap();
+		iter.seek(start).unwrap();
+		assert_eq!(range(iter), expected);
+
+		// the nodes on the path of a seek are recorded, even if never yielded.
+		let key = [0x00, 0x77];
+		let mut recorder = Recorder::new();
+		{
+			let mut iter = TrieDBNodeIterator::new_with_recorder(&trie, &mut recorder).unwrap();
+			iter.seek(&key).unwrap();
+		}
+		let mut lookup = Recorder::new();
+		trie.get_with(&key, &mut lookup).unwrap().unwrap();
+		let mut seeked: Vec<_> = recorder.drain().into_iter().map(|record| record.hash).collect();
+		seeked.dedup();
+		let looked_up: Vec<_> = lookup.drain().into_iter().map(|record| record.hash).collect();
+		assert_eq!(seeked, looked_up);
+	}
+}
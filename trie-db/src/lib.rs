@@ -20,58 +20,126 @@ extern crate alloc;
 
 #[cfg(feature = "std")]
 mod rstd {
-	pub use std::{borrow, boxed, cmp, convert, fmt, hash, iter, marker, mem, ops, rc, result, vec};
-	pub use std::collections::VecDeque;
+	pub use std::{
+		borrow, boxed, cell, cmp, convert, fmt, hash, iter, marker, mem, ops, rc, result, sync,
+		vec,
+	};
+	pub use std::collections::{BTreeMap, VecDeque};
 	pub use std::error::Error;
 }
 
 #[cfg(not(feature = "std"))]
 mod rstd {
-	pub use core::{borrow, convert, cmp, iter, fmt, hash, marker, mem, ops, result};
-	pub use alloc::{boxed, rc, vec};
-	pub use alloc::collections::VecDeque;
+	pub use core::{borrow, cell, convert, cmp, iter, fmt, hash, marker, mem, ops, result};
+	pub use alloc::{boxed, rc, sync, vec};
+	pub use alloc::collections::{BTreeMap, VecDeque};
 	pub trait Error {}
 	impl<T> Error for T {}
 }
 
 #[cfg(feature = "std")]
-use self::rstd::{fmt, Error};
+use self::rstd::Error;
+use self::rstd::fmt;
 
-use hash_db::MaybeDebug;
+use hash_db::{MaybeDebug, Prefix};
 use self::rstd::{boxed::Box, vec::Vec};
 
+/// Increment a counter of the `profiling` module, by one or by the given amount.
+/// Compiled out without the `trie-profiling` feature.
+#[cfg(feature = "trie-profiling")]
+macro_rules! profile {
+	($counter:ident) => { profile!($counter, 1) };
+	($counter:ident, $amount:expr) => {
+		$crate::profiling::record(|counters| counters.$counter += $amount as u64)
+	};
+}
+
+#[cfg(not(feature = "trie-profiling"))]
+macro_rules! profile {
+	($counter:ident) => {};
+	($counter:ident, $amount:expr) => {};
+}
+
+pub mod child_trie;
+pub mod commitment;
+pub mod fn_db;
+#[cfg(feature = "hash-interning")]
+pub mod interner;
+pub mod nested;
 pub mod node;
 pub mod proof;
+pub mod prune;
 pub mod triedb;
 pub mod triedbmut;
 pub mod sectriedb;
 pub mod sectriedbmut;
 pub mod recorder;
-
+pub mod value_chunks;
+pub mod verify;
+#[cfg(feature = "trie-profiling")]
+pub mod profiling;
+
+mod canonical_view;
+mod diff;
+mod dump;
 mod fatdb;
 mod fatdbmut;
 mod iter_build;
 mod iterator;
 mod lookup;
+mod migrate;
 mod nibble;
+mod node_provider;
 mod node_codec;
+mod owned_iterator;
+mod pin;
+mod stats;
+mod subtriedb;
+mod trie_cache;
 mod trie_codec;
+#[cfg(feature = "codec")]
+mod pairs_codec;
 
 pub use hash_db::{HashDB, HashDBRef, Hasher};
-pub use self::triedb::{TrieDB, TrieDBIterator};
-pub use self::triedbmut::{TrieDBMut, ChildReference};
+pub use self::triedb::{
+	FilteredItem, KeyItem, KeyRange, LeafInfo, LeafItem, LongestPrefixMatch, NodeCounts,
+	PrefixPage, TrieDB, TrieDBBuilder, TrieDBIterator, ValueItem, ValueWithPath, VALUE_CHUNK_SIZE,
+};
+pub use self::triedbmut::{
+	CanonicalizeStats, CheckpointError, CheckpointId, ChildReference, CommitStats, RemovalInfo,
+	TrieDBMut, TrieDBMutIterator, WriteDecision,
+};
+#[cfg(feature = "std")]
+pub use self::triedbmut::InsertChunkedError;
 pub use self::sectriedbmut::SecTrieDBMut;
 pub use self::sectriedb::SecTrieDB;
 pub use self::fatdb::{FatDB, FatDBIterator};
+pub use self::subtriedb::{SubTrieDB, SubTrieDBIterator};
 pub use self::fatdbmut::FatDBMut;
-pub use self::recorder::{Recorder, Record};
-pub use self::lookup::Lookup;
-pub use self::nibble::{NibbleSlice, NibbleVec, nibble_ops};
+pub use self::recorder::{PartialDB, Recorder, RecorderStats, Record};
+pub use self::fn_db::FnHashDB;
+pub use self::lookup::{Lookup, exists};
+pub use self::nibble::{Children, NibbleOps, NibbleSlice, NibbleVec, Radix16, Radix2, nibble_ops};
+pub use self::node_provider::{NodeProvider, NodeProviderError};
 pub use crate::node_codec::{NodeCodec, Partial};
 pub use crate::iter_build::{trie_visit, ProcessEncodedNode,
 	 TrieBuilder, TrieRoot, TrieRootUnhashed};
-pub use crate::iterator::TrieDBNodeIterator;
+pub use crate::canonical_view::{CanonicalNode, CanonicalNodeIterator};
+pub use crate::diff::{trie_diff, DiffItem, DiffIterator};
+pub use crate::dump::DumpOptions;
+pub use crate::migrate::{migrate_trie, migrate_trie_range, MigrationError};
+pub use crate::iterator::{BorrowedNodeItem, SharedNode, TrieDBNodeIterator, TrieIteratorState};
+pub use crate::owned_iterator::{OwnedTrieDBIterator, OwnedTrieDBNodeIterator};
+pub use crate::pin::{
+	PinnedIterator, PinnedNodeIterator, PinnedRoots, PinnedTrie, PinnedTrieIterator, PruningGuard,
+};
+pub use crate::stats::{NodeSizeStats, TrieStats, SIZE_BUCKETS};
+pub use crate::trie_cache::{
+	LoadSummary, NodeCache, NodeCacheStats, ValueCache, ValueCacheStats,
+};
 pub use crate::trie_codec::{decode_compact, encode_compact};
+#[cfg(feature = "codec")]
+pub use crate::pairs_codec::{decode_pairs, encode_pairs_to, PAIRS_CHUNK_SIZE};
 
 #[cfg(feature = "std")]
 pub use crate::iter_build::TrieRootPrint;
@@ -88,7 +156,13 @@ pub enum TrieError<T, E> {
 	/// Attempted to create a trie with a state root not in the DB.
 	InvalidStateRoot(T),
 	/// Trie item not found in the database,
-	IncompleteDatabase(T),
+	IncompleteDatabase {
+		/// Hash of the missing node.
+		hash: T,
+		/// Prefix the node was requested with, the path of the node in the trie for the
+		/// nodes of a trie.
+		prefix: (Vec<u8>, Option<u8>),
+	},
 	/// A value was found in the trie with a nibble key that was not byte-aligned.
 	/// The first parameter is the byte-aligned part of the prefix and the second parameter is the
 	/// remaining nibble.
@@ -96,16 +170,41 @@ pub enum TrieError<T, E> {
 	/// Corrupt Trie item
 	DecoderError(T, E),
 	InvalidHash(T, Vec<u8>),
+	/// A node read from the database does not hash to the hash it was requested with.
+	/// Only checked with the `verify-node-hash` feature, and for the nodes of a node provider.
+	HashMismatch {
+		/// Requested hash.
+		expected: T,
+		/// Hash of the data returned by the database.
+		found: T,
+		/// Prefix the node was requested with.
+		prefix: (Vec<u8>, Option<u8>),
+	},
+	/// An iteration saved on a trie was resumed on a trie with another root.
+	ResumeRootMismatch {
+		/// Root of the trie the iteration was saved on.
+		expected: T,
+		/// Root of the trie the iteration was resumed on.
+		found: T,
+	},
+	/// The node provider of the trie failed to fetch a node missing from the database, see
+	/// `TrieDBBuilder::with_node_provider`.
+	NodeProviderFailed(T),
+	/// The index of the chunks of a value with this hash does not decode, or one of its chunks
+	/// has another length than the index gives it, see `TrieLayout::MAX_VALUE_CHUNK`.
+	InvalidValueChunks(T),
+	/// The value slot of the node with this hash, or of the closest node above it for inline
+	/// nodes, starts with this unknown tag, see `TrieLayout::MAX_INLINE_VALUE`.
+	InvalidValueTag(T, u8),
 }
 
-#[cfg(feature = "std")]
-impl<T, E> fmt::Display for TrieError<T, E> where T: MaybeDebug, E: MaybeDebug {
+impl<T, E> fmt::Display for TrieError<T, E> where T: fmt::Debug, E: fmt::Debug {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match *self {
 			TrieError::InvalidStateRoot(ref root) =>
 				write!(f, "Invalid state root: {:?}", root),
-			TrieError::IncompleteDatabase(ref missing) =>
-				write!(f, "Database missing expected key: {:?}", missing),
+			TrieError::IncompleteDatabase { ref hash, ref prefix } =>
+				write!(f, "Database missing expected key: {:?} at prefix {:?}", hash, prefix),
 			TrieError::ValueAtIncompleteKey(ref bytes, ref extra) =>
 				write!(f, "Value found in trie at incomplete key {:?} + {:?}", bytes, extra),
 			TrieError::DecoderError(ref hash, ref decoder_err) => {
@@ -117,6 +216,24 @@ impl<T, E> fmt::Display for TrieError<T, E> where T: MaybeDebug, E: MaybeDebug {
 					"Encoded node {:?} contains invalid hash reference with length: {}",
 					hash, data.len()
 				),
+			TrieError::HashMismatch { ref expected, ref found, ref prefix } =>
+				write!(
+					f,
+					"Node requested with hash {:?} at prefix {:?} has hash {:?}",
+					expected, prefix, found
+				),
+			TrieError::ResumeRootMismatch { ref expected, ref found } =>
+				write!(
+					f,
+					"Iteration saved on the trie with root {:?} resumed on the trie with root {:?}",
+					expected, found
+				),
+			TrieError::NodeProviderFailed(ref hash) =>
+				write!(f, "Node provider failed to fetch node: {:?}", hash),
+			TrieError::InvalidValueChunks(ref hash) =>
+				write!(f, "Chunks of the value with index {:?} do not match the index", hash),
+			TrieError::InvalidValueTag(ref hash, tag) =>
+				write!(f, "Encoded node {:?} contains a value slot with unknown tag {}", hash, tag),
 		}
 	}
 }
@@ -126,10 +243,32 @@ impl<T, E> Error for TrieError<T, E> where T: fmt::Debug, E: Error {
 	fn description(&self) -> &str {
 		match *self {
 			TrieError::InvalidStateRoot(_) => "Invalid state root",
-			TrieError::IncompleteDatabase(_) => "Incomplete database",
+			TrieError::IncompleteDatabase { .. } => "Incomplete database",
 			TrieError::ValueAtIncompleteKey(_, _) => "Value at incomplete key",
 			TrieError::DecoderError(_, ref err) => err.description(),
 			TrieError::InvalidHash(_, _) => "Encoded node contains invalid hash reference",
+			TrieError::HashMismatch { .. } => "Node data does not match its hash",
+			TrieError::ResumeRootMismatch { .. } => "Iteration resumed on another trie",
+			TrieError::NodeProviderFailed(_) => "Node provider failure",
+			TrieError::InvalidValueChunks(_) => "Chunks of a value do not match their index",
+			TrieError::InvalidValueTag(_, _) => "Encoded node contains an unknown value tag",
+		}
+	}
+}
+
+impl<T, E> TrieError<T, E> {
+	/// `TrieError::IncompleteDatabase` for the node with hash `hash` requested at `prefix`.
+	pub fn incomplete_database(hash: T, prefix: Prefix) -> Self {
+		TrieError::IncompleteDatabase { hash, prefix: (prefix.0.to_vec(), prefix.1) }
+	}
+
+	/// Hash of the node missing from the database, for `TrieError::IncompleteDatabase` and
+	/// for `TrieError::InvalidStateRoot`, the root being missing.
+	pub fn missing_node_hash(&self) -> Option<&T> {
+		match *self {
+			TrieError::InvalidStateRoot(ref hash)
+			| TrieError::IncompleteDatabase { ref hash, .. } => Some(hash),
+			_ => None,
 		}
 	}
 }
@@ -156,6 +295,22 @@ pub trait Query<H: Hasher> {
 
 	/// Record that a node has been passed through.
 	fn record(&mut self, _hash: &H::Out, _data: &[u8], _depth: u32) {}
+
+	/// Record that a node has been passed through, with the key prefix of the node.
+	/// Defaults to `record`.
+	fn record_at(&mut self, hash: &H::Out, data: &[u8], depth: u32, _prefix: Prefix) {
+		self.record(hash, data, depth)
+	}
+
+	/// Record that an inline node has been passed through, at the depth of the node holding it
+	/// and with its key prefix.
+	fn record_inline(&mut self, _data: &[u8], _depth: u32, _prefix: Prefix) {}
+
+	/// Record that a value node has been read, at the depth of the node holding it and with its
+	/// key prefix, see `TrieLayout::MAX_INLINE_VALUE`. Defaults to `record_at`.
+	fn record_value(&mut self, hash: &H::Out, value: &[u8], depth: u32, prefix: Prefix) {
+		self.record_at(hash, value, depth, prefix)
+	}
 }
 
 impl<'a, H: Hasher> Query<H> for &'a mut Recorder<H::Out> {
@@ -164,6 +319,15 @@ impl<'a, H: Hasher> Query<H> for &'a mut Recorder<H::Out> {
 	fn record(&mut self, hash: &H::Out, data: &[u8], depth: u32) {
 		(&mut **self).record(hash, data, depth);
 	}
+	fn record_at(&mut self, hash: &H::Out, data: &[u8], depth: u32, prefix: Prefix) {
+		(**self).record_at(hash, data, depth, prefix);
+	}
+	fn record_inline(&mut self, data: &[u8], depth: u32, prefix: Prefix) {
+		(**self).record_inline_at::<H>(data, depth, prefix);
+	}
+	fn record_value(&mut self, hash: &H::Out, value: &[u8], depth: u32, prefix: Prefix) {
+		(**self).record_value_at(hash, value, depth, prefix);
+	}
 }
 
 impl<F, T, H: Hasher> Query<H> for F where F: for<'a> FnOnce(&'a [u8]) -> T {
@@ -177,6 +341,15 @@ impl<'a, F, T, H: Hasher> Query<H> for (&'a mut Recorder<H::Out>, F) where F: Fn
 	fn record(&mut self, hash: &H::Out, data: &[u8], depth: u32) {
 		self.0.record(hash, data, depth)
 	}
+	fn record_at(&mut self, hash: &H::Out, data: &[u8], depth: u32, prefix: Prefix) {
+		self.0.record_at(hash, data, depth, prefix)
+	}
+	fn record_inline(&mut self, data: &[u8], depth: u32, prefix: Prefix) {
+		self.0.record_inline_at::<H>(data, depth, prefix)
+	}
+	fn record_value(&mut self, hash: &H::Out, value: &[u8], depth: u32, prefix: Prefix) {
+		self.0.record_value_at(hash, value, depth, prefix)
+	}
 }
 
 /// A key-value datastore implemented as a database-backed modified Merkle tree.
@@ -187,9 +360,9 @@ pub trait Trie<L: TrieLayout> {
 	/// Is the trie empty?
 	fn is_empty(&self) -> bool { *self.root() == L::Codec::hashed_null_node() }
 
-	/// Does the trie contain a given key?
+	/// Does the trie contain a given key? The value is not copied.
 	fn contains(&self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
-		self.get(key).map(|x| x.is_some() )
+		self.get_with(key, |_: &[u8]| ()).map(|x| x.is_some())
 	}
 
 	/// What is the value of the given key in this trie?
@@ -252,6 +425,10 @@ pub trait TrieMut<L: TrieLayout> {
 pub trait TrieIterator<L: TrieLayout>: Iterator {
 	/// Position the iterator on the first element with key >= `key`
 	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>>;
+
+	/// Position the iterator on the first element with key > `key`, to resume an iteration
+	/// after the last key returned.
+	fn seek_after(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>>;
 }
 
 /// Trie types
@@ -394,8 +571,27 @@ pub trait TrieLayout {
 	const USE_EXTENSION: bool;
 	/// Hasher to use for this trie.
 	type Hash: Hasher;
+	/// Width of the nibbles of the keys, the radix of the trie: `Radix16` for branches of 16
+	/// children, `Radix2` for binary tries.
+	type Nibble: NibbleOps;
 	/// Codec to use (needs to match hasher and nibble ops).
-	type Codec: NodeCodec<HashOut=<Self::Hash as Hasher>::Out>;
+	type Codec: NodeCodec<HashOut=<Self::Hash as Hasher>::Out, Nibble = Self::Nibble>;
+	/// Size of the largest values held by their node. A larger value is stored apart, as a
+	/// value node under its hash and the prefix of its node, the node holding the hash: proofs
+	/// of the other keys then only carry the hash. `None` if all the values are held by their
+	/// node.
+	///
+	/// With value nodes, the value slot of a node starts with a tag byte telling a value from
+	/// the hash of a value node, see `node::Value`. The compact encoding of a trie does not
+	/// hold the value nodes.
+	const MAX_INLINE_VALUE: Option<usize> = None;
+	/// Size of the chunks of the values stored apart, with `MAX_INLINE_VALUE`. A value larger
+	/// than a chunk is cut in chunks of this size, the last one shorter, stored with an index
+	/// of their hashes, the node holding the hash of the index, see the `value_chunks` module:
+	/// `TrieDB::get_chunked` then reads the value a chunk at a time, and `ValueRangeProof`
+	/// proves a byte range of the value with the chunks holding it. `None` if the values
+	/// stored apart are stored in one value node whatever their size.
+	const MAX_VALUE_CHUNK: Option<usize> = None;
 }
 
 /// This trait associates a trie definition with preferred methods.
@@ -458,3 +654,19 @@ pub trait TrieConfiguration: Sized + TrieLayout {
 pub type TrieHash<L> = <<L as TrieLayout>::Hash as Hasher>::Out;
 /// Alias accessor to `NodeCodec` associated `Error` type from a `TrieLayout`.
 pub type CError<L> = <<L as TrieLayout>::Codec as NodeCodec>::Error;
+
+/// Root of the empty trie of layout `L`: the hash of the null node.
+pub fn empty_trie_root<L: TrieLayout>() -> TrieHash<L> {
+	L::Codec::hashed_null_node()
+}
+
+#[cfg(all(test, not(feature = "trie-profiling")))]
+mod tests {
+	#[test]
+	fn profiling_is_compiled_out() {
+		let calls = core::cell::Cell::new(0);
+		profile!(nodes_fetched);
+		profile!(bytes_fetched, calls.replace(1));
+		assert_eq!(calls.get(), 0);
+	}
+}
@@ -14,11 +14,16 @@
 
 //! Trie lookup via HashDB.
 
-use hash_db::HashDBRef;
+use hash_db::{HashDBRef, Prefix};
+#[cfg(feature = "verify-node-hash")]
+use hash_db::Hasher;
+use crate::iterator::SharedNode;
 use crate::nibble::NibbleSlice;
-use crate::node::{Node, NodeHandle, decode_hash};
+use crate::node::{Node, NodeHandle, OwnedNode, Value, decode_hash};
 use crate::node_codec::NodeCodec;
 use crate::rstd::boxed::Box;
+use crate::trie_cache::NodeCache;
+use crate::value_chunks::read_chunked;
 use super::{DBValue, Result, TrieError, Query, TrieLayout, CError, TrieHash};
 
 /// Trie lookup helper object.
@@ -39,41 +44,134 @@ where
 	/// Look up the given key. If the value is found, it will be passed to the given
 	/// function to decode or copy.
 	pub fn look_up(
-		mut self,
-		key: NibbleSlice,
+		self,
+		key: NibbleSlice<L::Nibble>,
+	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>> {
+		self.look_up_with(key, false)
+	}
+
+	/// Same as `look_up`, checking every decoded node with `NodeCodec::check_layout`
+	/// if `strict` is set.
+	pub(crate) fn look_up_with(
+		self,
+		key: NibbleSlice<L::Nibble>,
+		strict: bool,
 	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>> {
-		let mut partial = key;
+		self.look_up_at(key, 0, strict)
+	}
+
+	/// Same as `look_up_with`, `hash` being the hash of the node at nibble `start` on the path
+	/// of `key` instead of the root.
+	pub(crate) fn look_up_at(
+		self,
+		key: NibbleSlice<L::Nibble>,
+		start: usize,
+		strict: bool,
+	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>> {
+		let db = self.db;
+		self.look_up_through(key, start, strict, None, |hash, prefix, is_root| {
+			fetch_node::<L>(db, hash, prefix, is_root, || Ok(None))
+		})
+	}
+
+	/// Same as `look_up_at`, the nodes being read from `cache` first, then with `fetch`, which
+	/// is given whether the node is the root, the nodes read with `fetch` being inserted into
+	/// `cache`.
+	pub(crate) fn look_up_through<F>(
+		self,
+		key: NibbleSlice<L::Nibble>,
+		start: usize,
+		strict: bool,
+		cache: Option<&NodeCache<L>>,
+		fetch: F,
+	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>>
+		where F: Fn(&TrieHash<L>, Prefix, bool) -> Result<DBValue, TrieHash<L>, CError<L>>,
+	{
+		let fetch = &fetch;
+		self.look_up_slot_through(
+			key,
+			start,
+			strict,
+			cache,
+			fetch,
+			|lookup, slot, hash, prefix, depth|
+				lookup.decode_value(slot, hash, prefix, depth, fetch),
+		)
+	}
+
+	/// Same as `look_up_through`, the value slot of `key` being passed to `on_value` instead
+	/// of being decoded, with the hash of its node, or of the closest node above it for inline
+	/// nodes, the prefix of its node and the depth of the node.
+	pub(crate) fn look_up_slot_through<F, R>(
+		mut self,
+		key: NibbleSlice<L::Nibble>,
+		start: usize,
+		strict: bool,
+		cache: Option<&NodeCache<L>>,
+		fetch: &F,
+		on_value: impl FnOnce(Self, &[u8], TrieHash<L>, Prefix, u32)
+			-> Result<R, TrieHash<L>, CError<L>>,
+	) -> Result<Option<R>, TrieHash<L>, CError<L>>
+		where F: Fn(&TrieHash<L>, Prefix, bool) -> Result<DBValue, TrieHash<L>, CError<L>>,
+	{
+		profile!(lookups);
+		let mut partial = key.mid(start);
 		let mut hash = self.hash;
-		let mut key_nibbles = 0;
+		let mut key_nibbles = start;
 
 		// this loop iterates through non-inline nodes.
 		for depth in 0.. {
-			let node_data = match self.db.get(&hash, key.mid(key_nibbles).left()) {
-				Some(value) => value,
-				None => return Err(Box::new(match depth {
-					0 => TrieError::InvalidStateRoot(hash),
-					_ => TrieError::IncompleteDatabase(hash),
-				})),
+			// without cache, the node is decoded in place, saving the allocation of a
+			// shared node.
+			let node_key = key.mid(key_nibbles);
+			let prefix = node_key.left();
+			let is_root = depth == 0 && start == 0;
+			let shared;
+			let fetched;
+			let fetched_plan;
+			let (mut node_data, mut plan) = match cache {
+				Some(cache) => {
+					shared = match cache.get(&hash) {
+						Some(node) => node,
+						None => {
+							let node_data = fetch(&hash, prefix, is_root)?;
+							profile!(nodes_decoded);
+							let node = OwnedNode::new::<L::Codec>(node_data)
+								.map_err(|e| Box::new(TrieError::DecoderError(hash, e)))?;
+							let node = SharedNode::new(node);
+							cache.insert(hash, node.clone());
+							node
+						},
+					};
+					(shared.data(), shared.node_plan())
+				},
+				None => {
+					fetched = fetch(&hash, prefix, is_root)?;
+					profile!(nodes_decoded);
+					fetched_plan = L::Codec::decode_plan(&fetched)
+						.map_err(|e| Box::new(TrieError::DecoderError(hash, e)))?;
+					(&fetched[..], &fetched_plan)
+				},
 			};
 
-			self.query.record(&hash, &node_data, depth);
+			self.query.record_at(&hash, node_data, depth, prefix);
+			if strict {
+				L::Codec::check_layout(plan, L::USE_EXTENSION)
+					.map_err(|e| Box::new(TrieError::DecoderError(hash, e)))?;
+			}
 
 			// this loop iterates through all inline children (usually max 1)
 			// without incrementing the depth.
-			let mut node_data = &node_data[..];
+			let mut inline_plan;
 			loop {
-				let decoded = match L::Codec::decode(node_data) {
-					Ok(node) => node,
-					Err(e) => {
-						return Err(Box::new(TrieError::DecoderError(hash, e)))
-					}
-				};
+				let decoded = plan.build(node_data);
 				let next_node = match decoded {
 					Node::Leaf(slice, value) => {
-						return Ok(match slice == partial {
-							true => Some(self.query.decode(value)),
-							false => None,
-						})
+						if slice != partial {
+							return Ok(None);
+						}
+						let node_key = key.mid(key_nibbles);
+						return on_value(self, value, hash, node_key.left(), depth).map(Some);
 					}
 					Node::Extension(slice, item) => {
 						if partial.starts_with(&slice) {
@@ -84,9 +182,16 @@ where
 							return Ok(None)
 						}
 					}
-					Node::Branch(children, value) => match partial.is_empty() {
-						true => return Ok(value.map(move |val| self.query.decode(val))),
-						false => match children[partial.at(0) as usize] {
+					Node::Branch(children, value) => match partial.try_at(0) {
+						None => {
+							let node_key = key.mid(key_nibbles);
+							return match value {
+								Some(slot) => on_value(self, slot, hash, node_key.left(), depth)
+									.map(Some),
+								None => Ok(None),
+							};
+						},
+						Some(i) => match children[i as usize] {
 							Some(x) => {
 								partial = partial.mid(1);
 								key_nibbles += 1;
@@ -100,9 +205,16 @@ where
 							return Ok(None)
 						}
 
-						match partial.len() == slice.len() {
-							true => return Ok(value.map(move |val| self.query.decode(val))),
-							false => match children[partial.at(slice.len()) as usize] {
+						match partial.try_at(slice.len()) {
+							None => {
+								let node_key = key.mid(key_nibbles);
+								return match value {
+									Some(slot) => on_value(self, slot, hash, node_key.left(), depth)
+										.map(Some),
+									None => Ok(None),
+								};
+							},
+							Some(i) => match children[i as usize] {
 								Some(x) => {
 									partial = partial.mid(slice.len() + 1);
 									key_nibbles += slice.len() + 1;
@@ -123,11 +235,139 @@ where
 						break;
 					},
 					NodeHandle::Inline(data) => {
+						self.query.record_inline(data, depth, key.mid(key_nibbles).left());
+						profile!(nodes_decoded);
+						inline_plan = L::Codec::decode_plan(data)
+							.map_err(|e| Box::new(TrieError::DecoderError(hash, e)))?;
+						if strict {
+							L::Codec::check_layout(&inline_plan, L::USE_EXTENSION)
+								.map_err(|e| Box::new(TrieError::DecoderError(hash, e)))?;
+						}
 						node_data = data;
+						plan = &inline_plan;
 					},
 				}
 			}
 		}
 		Ok(None)
 	}
+
+	/// Decode the value of value slot `slot` of the node at `prefix` and `depth` with the query,
+	/// reading it with `fetch` if it is stored apart. `hash` is the hash of the node, or of the
+	/// closest node above it for inline nodes.
+	fn decode_value<F>(
+		mut self,
+		slot: &[u8],
+		hash: TrieHash<L>,
+		prefix: Prefix,
+		depth: u32,
+		fetch: &F,
+	) -> Result<Q::Item, TrieHash<L>, CError<L>>
+		where F: Fn(&TrieHash<L>, Prefix, bool) -> Result<DBValue, TrieHash<L>, CError<L>>,
+	{
+		match Value::from_slot::<L>(slot, hash)? {
+			Value::Inline(value) => Ok(self.query.decode(value)),
+			Value::Node(value_hash) => {
+				let value_hash = decode_hash::<L::Hash>(value_hash)
+					.ok_or_else(|| Box::new(TrieError::InvalidHash(hash, value_hash.to_vec())))?;
+				let value = fetch(&value_hash, prefix, false)?;
+				self.query.record_value(&value_hash, &value, depth, prefix);
+				Ok(self.query.decode(&value))
+			},
+			Value::Chunked(index_hash) => {
+				let index_hash = decode_hash::<L::Hash>(index_hash)
+					.ok_or_else(|| Box::new(TrieError::InvalidHash(hash, index_hash.to_vec())))?;
+				let query = &mut self.query;
+				let value = read_chunked::<L::Hash, _>(
+					&index_hash,
+					L::MAX_VALUE_CHUNK,
+					|node_hash| fetch(node_hash, prefix, false),
+					|node_hash, data| query.record_value(node_hash, data, depth, prefix),
+				)?;
+				Ok(self.query.decode(&value))
+			},
+		}
+	}
+}
+
+/// Read the node with hash `hash` from `db`, or else from `fallback`. A node neither has is
+/// reported as `TrieError::InvalidStateRoot` if it is the root, and as
+/// `TrieError::IncompleteDatabase` otherwise.
+pub(crate) fn fetch_node<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	hash: &TrieHash<L>,
+	prefix: Prefix,
+	is_root: bool,
+	fallback: impl FnOnce() -> Result<Option<DBValue>, TrieHash<L>, CError<L>>,
+) -> Result<DBValue, TrieHash<L>, CError<L>> {
+	let node_data = match db.get(hash, prefix) {
+		Some(value) => value,
+		None => match fallback()? {
+			Some(value) => value,
+			None => return Err(Box::new(match is_root {
+				true => TrieError::InvalidStateRoot(*hash),
+				false => TrieError::incomplete_database(*hash, prefix),
+			})),
+		},
+	};
+	profile!(nodes_fetched);
+	profile!(bytes_fetched, node_data.len());
+
+	#[cfg(feature = "verify-node-hash")]
+	verify_node_hash::<L>(hash, &node_data, prefix)?;
+
+	Ok(node_data)
+}
+
+/// Check whether `key` has a value in the trie with the given root.
+///
+/// This does not build a `TrieDB` (and so skips its root existence check) and never copies
+/// the value: the only heap allocations are the node buffers returned by `db`. The lookup stops
+/// at the value slot of `key`, a value stored apart being neither fetched nor read.
+/// A missing root node is reported as `TrieError::InvalidStateRoot`.
+///
+/// With a `cache`, the nodes are read from it first and the nodes read from `db` are inserted
+/// into it: a lookup whose nodes are all cached neither reads `db` nor allocates.
+pub fn exists<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	root: &TrieHash<L>,
+	key: &[u8],
+	cache: Option<&NodeCache<L>>,
+) -> Result<bool, TrieHash<L>, CError<L>> {
+	if *root == L::Codec::hashed_null_node() {
+		return Ok(false);
+	}
+	let lookup = Lookup::<L, _> {
+		db,
+		query: |_: &[u8]| (),
+		hash: *root,
+	};
+	lookup.look_up_slot_through(
+		NibbleSlice::new(key),
+		0,
+		false,
+		cache,
+		&|hash: &TrieHash<L>, prefix, is_root| {
+			fetch_node::<L>(db, hash, prefix, is_root, || Ok(None))
+		},
+		|_, _, _, _, _| Ok(()),
+	).map(|value| value.is_some())
+}
+
+/// Check that node data read from the database hashes to the hash it was requested with.
+#[cfg(feature = "verify-node-hash")]
+pub(crate) fn verify_node_hash<L: TrieLayout>(
+	hash: &TrieHash<L>,
+	node_data: &[u8],
+	prefix: Prefix,
+) -> Result<(), TrieHash<L>, CError<L>> {
+	let found = L::Hash::hash(node_data);
+	if found != *hash {
+		return Err(Box::new(TrieError::HashMismatch {
+			expected: *hash,
+			found,
+			prefix: (prefix.0.to_vec(), prefix.1),
+		}));
+	}
+	Ok(())
 }
@@ -0,0 +1,116 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Migration of the entries of a trie to a trie of another layout, see `migrate_trie`.
+//!
+//! The layouts may differ by their codec, their hasher or any other option: the entries are
+//! read from the source trie and inserted in the destination trie, which encodes them anew.
+//! `migrate_trie_range` migrates a chunk of the entries at a time, to spread a migration over
+//! many calls.
+
+use hash_db::{HashDB, HashDBRef};
+use crate::iterator::TrieDBNodeIterator;
+use crate::rstd::{boxed::Box, vec::Vec};
+#[cfg(feature = "std")]
+use crate::rstd::fmt;
+use crate::triedb::value_item;
+use crate::{
+	empty_trie_root, CError, DBValue, KeyRange, TrieDB, TrieDBMut, TrieError, TrieHash,
+	TrieIterator, TrieLayout, TrieMut,
+};
+
+/// Error of a migration from a trie of layout `Lsrc` to a trie of layout `Ldst`.
+pub enum MigrationError<Lsrc: TrieLayout, Ldst: TrieLayout> {
+	/// The source trie could not be read.
+	Source(Box<TrieError<TrieHash<Lsrc>, CError<Lsrc>>>),
+	/// The destination trie could not be opened or written.
+	Destination(Box<TrieError<TrieHash<Ldst>, CError<Ldst>>>),
+}
+
+#[cfg(feature = "std")]
+impl<Lsrc: TrieLayout, Ldst: TrieLayout> fmt::Debug for MigrationError<Lsrc, Ldst> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			MigrationError::Source(error) => f.debug_tuple("Source").field(error).finish(),
+			MigrationError::Destination(error) =>
+				f.debug_tuple("Destination").field(error).finish(),
+		}
+	}
+}
+
+/// Migrate the entries of the trie of layout `Lsrc` at `src_root` in `src_db` to a new trie of
+/// layout `Ldst` in `dst_db`, returning the root of the new trie.
+///
+/// The whole destination trie is built in memory before it is committed to `dst_db`: large
+/// tries are better migrated in chunks with `migrate_trie_range`.
+pub fn migrate_trie<Lsrc: TrieLayout, Ldst: TrieLayout>(
+	src_db: &dyn HashDBRef<Lsrc::Hash, DBValue>,
+	src_root: &TrieHash<Lsrc>,
+	dst_db: &mut dyn HashDB<Ldst::Hash, DBValue>,
+) -> Result<TrieHash<Ldst>, MigrationError<Lsrc, Ldst>> {
+	let mut dst_root = empty_trie_root::<Ldst>();
+	let range = (Vec::new(), Vec::new());
+	let limit = usize::MAX;
+	migrate_trie_range::<Lsrc, Ldst>(src_db, src_root, dst_db, &mut dst_root, &range, limit)?;
+	Ok(dst_root)
+}
+
+/// Migrate at most `limit` entries of `range` of the trie of layout `Lsrc` at `src_root` in
+/// `src_db` to the trie of layout `Ldst` at `dst_root` in `dst_db`, from the start of the range.
+///
+/// `dst_root` is updated to the root of the destination trie once the entries are committed.
+/// It is the empty root, `empty_trie_root::<Ldst>()`, before the first chunk of a migration.
+///
+/// Returns the key of the first entry of the range left to migrate, the start of the range of
+/// the next call, or `None` once the range is migrated. Entries of the destination trie are
+/// overwritten, so a chunk interrupted by an error can be run again.
+pub fn migrate_trie_range<Lsrc: TrieLayout, Ldst: TrieLayout>(
+	src_db: &dyn HashDBRef<Lsrc::Hash, DBValue>,
+	src_root: &TrieHash<Lsrc>,
+	dst_db: &mut dyn HashDB<Ldst::Hash, DBValue>,
+	dst_root: &mut TrieHash<Ldst>,
+	range: &KeyRange,
+	limit: usize,
+) -> Result<Option<Vec<u8>>, MigrationError<Lsrc, Ldst>> {
+	assert!(limit > 0, "a chunk migrates at least one entry");
+	let (start, end) = range;
+	let trie = TrieDB::<Lsrc>::new(src_db, src_root).map_err(MigrationError::Source)?;
+	let mut iter = TrieDBNodeIterator::new(&trie).map_err(MigrationError::Source)?;
+	iter.seek(start).map_err(MigrationError::Source)?;
+	let mut dst = if *dst_root == empty_trie_root::<Ldst>() {
+		TrieDBMut::<Ldst>::new(dst_db, dst_root)
+	} else {
+		TrieDBMut::<Ldst>::from_existing(dst_db, dst_root).map_err(MigrationError::Destination)?
+	};
+
+	let mut migrated = 0;
+	while let Some(item) = iter.next_node() {
+		let (prefix, hash, node) = item.map_err(MigrationError::Source)?;
+		let (key, value) = match value_item(&trie, prefix, hash, &node) {
+			Some(entry) => entry.map_err(MigrationError::Source)?,
+			None => continue,
+		};
+		if !end.is_empty() && key >= *end {
+			break;
+		}
+		if migrated == limit {
+			dst.commit();
+			return Ok(Some(key));
+		}
+		dst.insert(&key, &value).map_err(MigrationError::Destination)?;
+		migrated += 1;
+	}
+	dst.commit();
+	Ok(None)
+}
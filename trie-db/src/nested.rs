@@ -0,0 +1,326 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tries whose root is the value of a key of an index trie.
+//!
+//! The tries and the index trie share a database, an inner trie being identified by its key
+//! in the index trie: the root of the inner trie is always read or proven through the index,
+//! and an absent key stands for an empty inner trie.
+
+use hash_db::HashDBRef;
+use crate::node::decode_hash;
+use crate::proof::{generate_proof, verify_proof, VerifyError};
+use crate::rstd::{boxed::Box, vec::Vec};
+use crate::{
+	CError, DBValue, FnHashDB, NodeCodec, OwnedTrieDBIterator, Result as TrieResult, Trie,
+	TrieDB, TrieError, TrieHash, TrieItem, TrieIterator, TrieLayout,
+};
+
+/// A proof of keys of an inner trie, and of the root of the inner trie in the index trie.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct NestedProof {
+	/// Compact proof of the key of the inner trie in the index trie.
+	pub index_proof: Vec<Vec<u8>>,
+	/// Value of the key of the inner trie in the index trie, `None` if the inner trie is
+	/// empty.
+	pub inner_root: Option<Vec<u8>>,
+	/// Compact proof of the keys in the inner trie, empty if the inner trie is empty.
+	pub inner_proof: Vec<Vec<u8>>,
+}
+
+/// Iterator over the key-value pairs of an inner trie.
+pub type NestedIterator<'db, L> =
+	Box<dyn TrieIterator<L, Item = TrieItem<'db, TrieHash<L>, CError<L>>> + 'db>;
+
+/// Root of the inner trie in the index trie value `value`.
+fn inner_root<L: TrieLayout>(value: &[u8]) -> Option<TrieHash<L>> {
+	decode_hash::<L::Hash>(value)
+}
+
+/// Generate a proof of `inner_keys` in the inner trie at `subtrie_key` in the index trie at
+/// `index_root`, both tries being in `db`.
+///
+/// Returns `TrieError::InvalidHash` if the value at `subtrie_key` is not a hash.
+pub fn generate_nested_proof<'a, L, I, K>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	index_root: &TrieHash<L>,
+	subtrie_key: &[u8],
+	inner_keys: I,
+) -> TrieResult<NestedProof, TrieHash<L>, CError<L>>
+	where
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a K>,
+		K: 'a + AsRef<[u8]>,
+{
+	let index = TrieDB::<L>::new(db, index_root)?;
+	let index_proof = generate_proof::<_, L, _, _>(&index, &[subtrie_key])?;
+	let value = index.get(subtrie_key)?;
+	let inner_proof = match &value {
+		Some(value) => {
+			let root = inner_root::<L>(value)
+				.ok_or_else(|| Box::new(TrieError::InvalidHash(*index_root, value.clone())))?;
+			let inner = TrieDB::<L>::new(db, &root)?;
+			generate_proof::<_, L, _, _>(&inner, inner_keys)?
+		},
+		None => Vec::new(),
+	};
+	Ok(NestedProof { index_proof, inner_root: value, inner_proof })
+}
+
+/// Verify a proof of `inner_items` in the inner trie at `subtrie_key` in the index trie at
+/// `index_root`, `None` values meaning absent keys.
+///
+/// The root of the inner trie is the one proven at `subtrie_key` in the index trie, and all
+/// the keys of an empty inner trie are absent.
+pub fn verify_nested_proof<'a, L, I, K, V>(
+	index_root: &TrieHash<L>,
+	proof: &NestedProof,
+	subtrie_key: &[u8],
+	inner_items: I,
+) -> Result<(), VerifyError<TrieHash<L>, CError<L>>>
+	where
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a (K, Option<V>)>,
+		K: 'a + AsRef<[u8]>,
+		V: 'a + AsRef<[u8]>,
+{
+	let index_items = [(subtrie_key, proof.inner_root.as_ref())];
+	verify_proof::<L, _, _, _>(index_root, &proof.index_proof, index_items.iter())?;
+	match &proof.inner_root {
+		Some(value) => {
+			let root = inner_root::<L>(value)
+				.ok_or_else(|| VerifyError::InvalidNestedRoot(value.clone()))?;
+			verify_proof::<L, _, _, _>(&root, &proof.inner_proof, inner_items).map(|_| ())
+		},
+		None => {
+			if !proof.inner_proof.is_empty() {
+				return Err(VerifyError::ExtraneousNode);
+			}
+			match inner_items.into_iter().find(|(_, value)| value.is_some()) {
+				Some((key, _)) => Err(VerifyError::ValueMismatch(key.as_ref().to_vec())),
+				None => Ok(()),
+			}
+		},
+	}
+}
+
+/// Iterate over the key-value pairs of the inner trie at `subtrie_key` in the index trie at
+/// `index_root`, both tries being in `db`.
+///
+/// Iterates over nothing if `subtrie_key` is absent, and returns `TrieError::InvalidHash` if
+/// its value is not a hash.
+pub fn iter_nested<'db, L: TrieLayout + 'db>(
+	db: &'db dyn HashDBRef<L::Hash, DBValue>,
+	index_root: &TrieHash<L>,
+	subtrie_key: &[u8],
+) -> TrieResult<NestedIterator<'db, L>, TrieHash<L>, CError<L>> {
+	let root = match TrieDB::<L>::new(db, index_root)?.get(subtrie_key)? {
+		Some(value) => inner_root::<L>(&value)
+			.ok_or_else(|| Box::new(TrieError::InvalidHash(*index_root, value.clone())))?,
+		None => L::Codec::hashed_null_node(),
+	};
+	// the empty inner trie needs no node from `db`.
+	let null_node = L::Codec::hashed_null_node();
+	let db = FnHashDB::new(move |hash: &TrieHash<L>, prefix| if *hash == null_node {
+		Some(L::Codec::empty_node().to_vec())
+	} else {
+		db.get(hash, prefix)
+	});
+	Ok(Box::new(OwnedTrieDBIterator::new(db, root)?))
+}
+
+#[cfg(feature = "codec")]
+mod codec_impl {
+	use super::NestedProof;
+	use crate::rstd::vec::Vec;
+	use codec::{Decode, Encode, Error, Input, Output};
+
+	impl Encode for NestedProof {
+		fn encode_to<T: Output>(&self, dest: &mut T) {
+			self.index_proof.encode_to(dest);
+			self.inner_root.encode_to(dest);
+			self.inner_proof.encode_to(dest);
+		}
+	}
+
+	impl Decode for NestedProof {
+		fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+			Ok(NestedProof {
+				index_proof: <Vec<Vec<u8>>>::decode(input)?,
+				inner_root: <Option<Vec<u8>>>::decode(input)?,
+				inner_proof: <Vec<Vec<u8>>>::decode(input)?,
+			})
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use hash_db::Hasher;
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{
+		nested::{generate_nested_proof, iter_nested, verify_nested_proof, NestedProof},
+		proof::VerifyError, ExtensionLayout, NoExtensionLayout, TrieDBMut,
+		TrieLayout, TrieMut,
+	};
+	use crate::DBValue;
+
+	type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+	type Root = <KeccakHasher as Hasher>::Out;
+	type Items = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+	fn inner_pairs(subtrie: u8) -> Vec<(Vec<u8>, Vec<u8>)> {
+		(0..20u8).map(|i| (vec![i, i], vec![subtrie; 1 + i as usize * 2])).collect()
+	}
+
+	fn subtrie_key(subtrie: u8) -> Vec<u8> {
+		vec![b's', subtrie]
+	}
+
+	/// An index trie of three inner tries, and other keys.
+	fn build_index<L: TrieLayout<Hash = KeccakHasher>>() -> (MemoryDB, Root) {
+		let mut db = MemoryDB::default();
+		let mut index_root = Default::default();
+		let mut index = Vec::new();
+		for subtrie in 0..3u8 {
+			let mut root = Default::default();
+			{
+				let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+				for (key, value) in inner_pairs(subtrie) {
+					t.insert(&key, &value).unwrap();
+				}
+			}
+			index.push((subtrie_key(subtrie), root.as_ref().to_vec()));
+		}
+		index.push((b"other".to_vec(), b"value".to_vec()));
+		{
+			let mut t = TrieDBMut::<L>::new(&mut db, &mut index_root);
+			for (key, value) in index {
+				t.insert(&key, &value).unwrap();
+			}
+		}
+		(db, index_root)
+	}
+
+	/// Keys of the inner trie of `subtrie`, present and absent, with their values.
+	fn inner_items(subtrie: u8) -> Items {
+		let pairs = inner_pairs(subtrie);
+		vec![
+			(pairs[3].0.clone(), Some(pairs[3].1.clone())),
+			(pairs[10].0.clone(), Some(pairs[10].1.clone())),
+			(vec![3], None),
+			(vec![0xff, 0xff], None),
+		]
+	}
+
+	fn prove<L: TrieLayout<Hash = KeccakHasher>>(
+		db: &MemoryDB,
+		index_root: &Root,
+		subtrie_key: &[u8],
+		items: &Items,
+	) -> NestedProof {
+		let keys: Vec<_> = items.iter().map(|(key, _)| key.clone()).collect();
+		generate_nested_proof::<L, _, _>(db, index_root, subtrie_key, keys.iter()).unwrap()
+	}
+
+	fn check_nested_proofs<L: TrieLayout<Hash = KeccakHasher>>() {
+		let (db, index_root) = build_index::<L>();
+		let items = inner_items(1);
+		let proof = prove::<L>(&db, &index_root, &subtrie_key(1), &items);
+		verify_nested_proof::<L, _, _, _>(&index_root, &proof, &subtrie_key(1), items.iter())
+			.unwrap();
+
+		let mut wrong = items.clone();
+		wrong[2].1 = Some(b"value".to_vec());
+		assert!(
+			verify_nested_proof::<L, _, _, _>(&index_root, &proof, &subtrie_key(1), wrong.iter())
+				.is_err()
+		);
+
+		// an absent key is an empty inner trie.
+		let absent: Items = items.iter().map(|(key, _)| (key.clone(), None)).collect();
+		let proof = prove::<L>(&db, &index_root, b"s\xff", &absent);
+		assert_eq!((proof.inner_root.as_ref(), proof.inner_proof.len()), (None, 0));
+		verify_nested_proof::<L, _, _, _>(&index_root, &proof, b"s\xff", absent.iter()).unwrap();
+		match verify_nested_proof::<L, _, _, _>(&index_root, &proof, b"s\xff", items.iter()) {
+			Err(VerifyError::ValueMismatch(key)) => assert_eq!(key, items[0].0),
+			result => panic!("expected VerifyError::ValueMismatch, got {:?}", result),
+		}
+
+		// a value which is not a hash.
+		let no_keys: Vec<Vec<u8>> = Vec::new();
+		assert!(generate_nested_proof::<L, _, _>(&db, &index_root, b"other", no_keys.iter()).is_err());
+	}
+
+	fn check_wrong_inner_root<L: TrieLayout<Hash = KeccakHasher>>() {
+		let (db, index_root) = build_index::<L>();
+		let items = inner_items(1);
+		let other_items = inner_items(2);
+		let honest = prove::<L>(&db, &index_root, &subtrie_key(1), &items);
+		let other = prove::<L>(&db, &index_root, &subtrie_key(2), &other_items);
+		let verify = |proof: &NestedProof, items: &Items| verify_nested_proof::<L, _, _, _>(
+			&index_root,
+			proof,
+			&subtrie_key(1),
+			items.iter(),
+		);
+
+		// the proof of another inner trie.
+		assert!(verify(&other, &other_items).is_err());
+		// the root of another inner trie, with its own proof.
+		let swapped_root = NestedProof { index_proof: honest.index_proof.clone(), ..other.clone() };
+		assert!(verify(&swapped_root, &other_items).is_err());
+		// the inner proof of another inner trie.
+		let swapped_inner = NestedProof { inner_proof: other.inner_proof.clone(), ..honest.clone() };
+		assert!(verify(&swapped_inner, &other_items).is_err());
+		assert!(verify(&swapped_inner, &items).is_err());
+		// an absent inner trie.
+		let no_root = NestedProof { inner_root: None, inner_proof: Vec::new(), ..honest.clone() };
+		let absent: Items = items.iter().map(|(key, _)| (key.clone(), None)).collect();
+		assert!(verify(&no_root, &absent).is_err());
+		verify(&honest, &items).unwrap();
+	}
+
+	fn check_iteration<L: TrieLayout<Hash = KeccakHasher>>() {
+		let (db, index_root) = build_index::<L>();
+		let mut pairs = inner_pairs(2);
+		pairs.sort();
+		let iterated: Vec<_> = iter_nested::<L>(&db, &index_root, &subtrie_key(2)).unwrap()
+			.map(|item| item.unwrap())
+			.collect();
+		assert_eq!(iterated, pairs);
+
+		let mut iter = iter_nested::<L>(&db, &index_root, &subtrie_key(2)).unwrap();
+		iter.seek(&pairs[15].0).unwrap();
+		assert_eq!(iter.count(), 5);
+
+		assert_eq!(iter_nested::<L>(&db, &index_root, b"s\xff").unwrap().count(), 0);
+		assert!(iter_nested::<L>(&db, &index_root, b"other").is_err());
+	}
+
+	#[test]
+	fn nested_proofs_with_ext() {
+		check_nested_proofs::<ExtensionLayout>();
+		check_wrong_inner_root::<ExtensionLayout>();
+		check_iteration::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn nested_proofs_without_ext() {
+		check_nested_proofs::<NoExtensionLayout>();
+		check_wrong_inner_root::<NoExtensionLayout>();
+		check_iteration::<NoExtensionLayout>();
+	}
+}
@@ -12,25 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::rstd::cmp::{self, Ordering};
+use crate::rstd::{cmp::{self, Ordering}, marker::PhantomData};
 
-use crate::nibble::{nibble_ops::{self, NIBBLE_PER_BYTE}, NibbleSlice};
+use crate::nibble::{NibbleOps, NibbleSlice, Radix16};
 
 /// A representation of a nibble slice which is left-aligned. The regular `NibbleSlice` is
 /// right-aligned, meaning it does not support efficient truncation from the right side.
 ///
 /// This is an immutable struct. No operations actually change it.
-pub struct LeftNibbleSlice<'a> {
+#[derive(Clone, Copy)]
+pub struct LeftNibbleSlice<'a, N: NibbleOps = Radix16> {
 	bytes: &'a [u8],
 	len: usize,
+	_marker: PhantomData<N>,
 }
 
-impl<'a> LeftNibbleSlice<'a> {
+impl<'a, N: NibbleOps> LeftNibbleSlice<'a, N> {
 	/// Constructs a byte-aligned nibble slice from a byte slice.
 	pub fn new(bytes: &'a [u8]) -> Self {
 		LeftNibbleSlice {
 			bytes,
-			len: bytes.len() * NIBBLE_PER_BYTE,
+			len: bytes.len() * N::NIBBLE_PER_BYTE,
+			_marker: PhantomData,
 		}
 	}
 
@@ -43,7 +46,7 @@ impl<'a> LeftNibbleSlice<'a> {
 	/// out of bounds.
 	pub fn at(&self, index: usize) -> Option<u8> {
 		if index < self.len() {
-			Some(nibble_ops::left_nibble_at(self.bytes, index))
+			Some(N::left_nibble_at(self.bytes, index))
 		} else {
 			None
 		}
@@ -55,23 +58,24 @@ impl<'a> LeftNibbleSlice<'a> {
 		LeftNibbleSlice {
 			bytes: self.bytes,
 			len: cmp::min(len, self.len),
+			_marker: PhantomData,
 		}
 	}
 
 	/// Returns whether the given slice is a prefix of this one.
-	pub fn starts_with(&self, prefix: &LeftNibbleSlice<'a>) -> bool {
+	pub fn starts_with(&self, prefix: &LeftNibbleSlice<'a, N>) -> bool {
 		self.truncate(prefix.len()) == *prefix
 	}
 
 	/// Returns whether another regular (right-aligned) nibble slice is contained in this one at
 	/// the given offset.
-	pub fn contains(&self, partial: &NibbleSlice, offset: usize) -> bool {
+	pub fn contains(&self, partial: &NibbleSlice<N>, offset: usize) -> bool {
 		(0..partial.len()).all(|i| self.at(offset + i) == Some(partial.at(i)))
 	}
 
 	fn cmp(&self, other: &Self) -> Ordering {
 		let common_len = cmp::min(self.len(), other.len());
-		let common_byte_len = common_len / NIBBLE_PER_BYTE;
+		let common_byte_len = common_len / N::NIBBLE_PER_BYTE;
 
 		// Quickly compare the common prefix of the byte slices.
 		match self.bytes[..common_byte_len].cmp(&other.bytes[..common_byte_len]) {
@@ -80,7 +84,7 @@ impl<'a> LeftNibbleSlice<'a> {
 		}
 
 		// Compare nibble-by-nibble (either 0 or 1 nibbles) any after the common byte prefix.
-		for i in (common_byte_len * NIBBLE_PER_BYTE)..common_len {
+		for i in (common_byte_len * N::NIBBLE_PER_BYTE)..common_len {
 			let a = self.at(i).expect("i < len; len == self.len() qed");
 			let b = other.at(i).expect("i < len; len == other.len(); qed");
 			match a.cmp(&b) {
@@ -94,7 +98,7 @@ impl<'a> LeftNibbleSlice<'a> {
 	}
 }
 
-impl<'a> PartialEq for LeftNibbleSlice<'a> {
+impl<'a, N: NibbleOps> PartialEq for LeftNibbleSlice<'a, N> {
 	fn eq(&self, other: &Self) -> bool {
 		let len = self.len();
 		if other.len() != len {
@@ -102,13 +106,13 @@ impl<'a> PartialEq for LeftNibbleSlice<'a> {
 		}
 
 		// Quickly compare the common prefix of the byte slices.
-		let byte_len = len / NIBBLE_PER_BYTE;
+		let byte_len = len / N::NIBBLE_PER_BYTE;
 		if self.bytes[..byte_len] != other.bytes[..byte_len] {
 			return false;
 		}
 
 		// Compare nibble-by-nibble (either 0 or 1 nibbles) any after the common byte prefix.
-		for i in (byte_len * NIBBLE_PER_BYTE)..len {
+		for i in (byte_len * N::NIBBLE_PER_BYTE)..len {
 			let a = self.at(i).expect("i < len; len == self.len() qed");
 			let b = other.at(i).expect("i < len; len == other.len(); qed");
 			if a != b {
@@ -120,22 +124,22 @@ impl<'a> PartialEq for LeftNibbleSlice<'a> {
 	}
 }
 
-impl<'a> Eq for LeftNibbleSlice<'a> {}
+impl<'a, N: NibbleOps> Eq for LeftNibbleSlice<'a, N> {}
 
-impl<'a> PartialOrd for LeftNibbleSlice<'a> {
+impl<'a, N: NibbleOps> PartialOrd for LeftNibbleSlice<'a, N> {
 	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
 		Some(self.cmp(other))
 	}
 }
 
-impl<'a> Ord for LeftNibbleSlice<'a> {
+impl<'a, N: NibbleOps> Ord for LeftNibbleSlice<'a, N> {
 	fn cmp(&self, other: &Self) -> Ordering {
 		self.cmp(other)
 	}
 }
 
 #[cfg(feature = "std")]
-impl<'a> std::fmt::Debug for LeftNibbleSlice<'a> {
+impl<'a, N: NibbleOps> std::fmt::Debug for LeftNibbleSlice<'a, N> {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
 		for i in 0..self.len() {
 			let nibble = self.at(i).expect("i < self.len(); qed");
@@ -151,6 +155,10 @@ impl<'a> std::fmt::Debug for LeftNibbleSlice<'a> {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::nibble::Radix16;
+
+	type LeftNibbleSlice<'a> = super::LeftNibbleSlice<'a, Radix16>;
+	type NibbleSlice<'a> = crate::nibble::NibbleSlice<'a, Radix16>;
 
 	#[test]
 	fn test_len() {
@@ -15,7 +15,7 @@
 //! Nibble oriented methods.
 
 use crate::node::NodeKey;
-use crate::rstd::cmp;
+use crate::rstd::{cmp, fmt, hash, marker::PhantomData, ops};
 
 pub use self::leftnibbleslice::LeftNibbleSlice;
 
@@ -148,17 +148,183 @@ pub mod nibble_ops {
 
 }
 
+/// Width of the nibbles of the keys of a trie, and so its radix: the number of children of its
+/// branches, see `TrieLayout::Nibble`.
+///
+/// The nibbles of a byte are in order from its most significant bits. The methods are those of
+/// `nibble_ops` for a width of 1, 2 or 4 bits, the first nibble of a byte being at index 0.
+pub trait NibbleOps:
+	Copy + Default + Eq + Ord + hash::Hash + fmt::Debug + Send + Sync + 'static
+{
+	/// Single nibble length in bit.
+	const BIT_PER_NIBBLE: usize;
+	/// Number of nibble per byte.
+	const NIBBLE_PER_BYTE: usize = 8 / Self::BIT_PER_NIBBLE;
+	/// Number of child for a branch (trie radix).
+	const NIBBLE_LENGTH: usize = 1 << Self::BIT_PER_NIBBLE;
+	/// Mask of the last nibble of a byte.
+	const PADDING_BITMASK: u8 = (Self::NIBBLE_LENGTH - 1) as u8;
+
+	/// Mask a byte, keeping its first `n` nibbles.
+	#[inline(always)]
+	fn pad_left(n: usize, b: u8) -> u8 {
+		b & (0xff00u16 >> (n * Self::BIT_PER_NIBBLE)) as u8
+	}
+
+	/// Mask a byte, keeping its last `n` nibbles.
+	#[inline(always)]
+	fn pad_right(n: usize, b: u8) -> u8 {
+		b & ((1u16 << (n * Self::BIT_PER_NIBBLE)) - 1) as u8
+	}
+
+	/// Get u8 nibble value at a given index of a byte.
+	#[inline(always)]
+	fn at_left(ix: u8, b: u8) -> u8 {
+		(b >> (8 - Self::BIT_PER_NIBBLE * (ix as usize + 1))) & Self::PADDING_BITMASK
+	}
+
+	/// Get u8 nibble value at a given index in a left aligned array.
+	#[inline(always)]
+	fn left_nibble_at(v1: &[u8], ix: usize) -> u8 {
+		Self::at_left((ix % Self::NIBBLE_PER_BYTE) as u8, v1[ix / Self::NIBBLE_PER_BYTE])
+	}
+
+	/// Push u8 nibble value at a given index into an existing byte.
+	#[inline(always)]
+	fn push_at_left(ix: u8, v: u8, into: u8) -> u8 {
+		into | v << (8 - Self::BIT_PER_NIBBLE * (ix as usize + 1))
+	}
+
+	/// Number of padding nibbles before an array of nibble length `i` ending on a byte.
+	#[inline]
+	fn number_padding(i: usize) -> usize {
+		(Self::NIBBLE_PER_BYTE - i % Self::NIBBLE_PER_BYTE) % Self::NIBBLE_PER_BYTE
+	}
+
+	/// Count the biggest common depth between two left aligned packed nibble slice.
+	fn biggest_depth(v1: &[u8], v2: &[u8]) -> usize {
+		let upper_bound = cmp::min(v1.len(), v2.len());
+		for a in 0 .. upper_bound {
+			if v1[a] != v2[a] {
+				return a * Self::NIBBLE_PER_BYTE + Self::left_common(v1[a], v2[a]);
+			}
+		}
+		upper_bound * Self::NIBBLE_PER_BYTE
+	}
+
+	/// Calculate the number of common nibble between two left aligned bytes.
+	#[inline(always)]
+	fn left_common(a: u8, b: u8) -> usize {
+		(0..Self::NIBBLE_PER_BYTE)
+			.take_while(|ix| Self::at_left(*ix as u8, a) == Self::at_left(*ix as u8, b))
+			.count()
+	}
+
+	/// Shifts right aligned key to add a given left offset.
+	/// Resulting in possibly padding at both left and right
+	/// (example usage when combining two keys).
+	fn shift_key(key: &mut NodeKey, offset: usize) -> bool {
+		let old_offset = key.0;
+		key.0 = offset;
+		if old_offset > offset {
+			// shift left
+			let s2 = (old_offset - offset) * Self::BIT_PER_NIBBLE;
+			let s1 = 8 - s2;
+			let kl = key.1.len();
+			(0..kl - 1).for_each(|i| key.1[i] = key.1[i] << s2 | key.1[i+1] >> s1);
+			key.1[kl - 1] <<= s2;
+			true
+		} else if old_offset < offset {
+			// shift right
+			let s2 = (offset - old_offset) * Self::BIT_PER_NIBBLE;
+			let s1 = 8 - s2;
+			key.1.push(0);
+			(1..key.1.len()).rev().for_each(|i| key.1[i] = key.1[i - 1] << s1 | key.1[i] >> s2);
+			key.1[0] >>= s2;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+/// Nibbles of 4 bits, the radix 16 of `nibble_ops`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Radix16;
+
+impl NibbleOps for Radix16 {
+	const BIT_PER_NIBBLE: usize = nibble_ops::BIT_PER_NIBBLE;
+}
+
+/// Nibbles of 1 bit, for binary tries: their branches have two children.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Radix2;
+
+impl NibbleOps for Radix2 {
+	const BIT_PER_NIBBLE: usize = 1;
+}
+
+/// The children of a branch, a slot for each nibble of `N`.
+///
+/// The slots are kept in an array of the largest radix, 16, of which only the first
+/// `N::NIBBLE_LENGTH` are seen through `Deref`, so that the children of any radix are `Copy`
+/// when their type is.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Children<T, N: NibbleOps = Radix16>(
+	[Option<T>; nibble_ops::NIBBLE_LENGTH],
+	PhantomData<N>,
+);
+
+impl<T, N: NibbleOps> Children<T, N> {
+	/// Children with all their slots empty.
+	pub fn new() -> Self {
+		Children(Default::default(), PhantomData)
+	}
+}
+
+impl<T, N: NibbleOps> Default for Children<T, N> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<T, N: NibbleOps> ops::Deref for Children<T, N> {
+	type Target = [Option<T>];
+
+	fn deref(&self) -> &[Option<T>] {
+		&self.0[..N::NIBBLE_LENGTH]
+	}
+}
+
+impl<T, N: NibbleOps> ops::DerefMut for Children<T, N> {
+	fn deref_mut(&mut self) -> &mut [Option<T>] {
+		&mut self.0[..N::NIBBLE_LENGTH]
+	}
+}
+
+impl<T: fmt::Debug, N: NibbleOps> fmt::Debug for Children<T, N> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		(**self).fmt(f)
+	}
+}
+
+impl<T> From<[Option<T>; nibble_ops::NIBBLE_LENGTH]> for Children<T, Radix16> {
+	fn from(slots: [Option<T>; nibble_ops::NIBBLE_LENGTH]) -> Self {
+		Children(slots, PhantomData)
+	}
+}
+
 /// Backing storage for `NibbleVec`s.
 pub(crate) type BackingByteVec = smallvec::SmallVec<[u8; 36]>;
 
 /// Owning, nibble-oriented byte vector. Counterpart to `NibbleSlice`.
 /// Nibbles are always left aligned, so making a `NibbleVec` from
 /// a `NibbleSlice` can get costy.
-#[cfg_attr(feature = "std", derive(Debug))]
 #[derive(Clone, PartialEq, Eq)]
-pub struct NibbleVec {
+pub struct NibbleVec<N: NibbleOps = Radix16> {
 	inner: BackingByteVec,
 	len: usize,
+	_marker: PhantomData<N>,
 }
 
 /// Nibble-orientated view onto byte-slice, allowing nibble-precision offsets.
@@ -184,14 +350,15 @@ pub struct NibbleVec {
 /// }
 /// ```
 #[derive(Copy, Clone)]
-pub struct NibbleSlice<'a> {
+pub struct NibbleSlice<'a, N: NibbleOps = Radix16> {
 	data: &'a [u8],
 	offset: usize,
+	_marker: PhantomData<N>,
 }
 
 /// Iterator type for a nibble slice.
-pub struct NibbleSliceIterator<'a> {
-	p: &'a NibbleSlice<'a>,
+pub struct NibbleSliceIterator<'a, N: NibbleOps = Radix16> {
+	p: &'a NibbleSlice<'a, N>,
 	i: usize,
 }
 
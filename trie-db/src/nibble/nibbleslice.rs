@@ -14,13 +14,13 @@
 
 //! Nibble-orientated view onto byte-slice, allowing nibble-precision offsets.
 
-use crate::rstd::{cmp::*, fmt};
-use super::{nibble_ops, NibbleSlice, NibbleSliceIterator, BackingByteVec};
+use crate::rstd::{cmp::*, fmt, marker::PhantomData};
+use super::{NibbleOps, NibbleSlice, NibbleSliceIterator, NibbleVec, BackingByteVec};
 use crate::node::NodeKey;
 use crate::node_codec::Partial;
 use hash_db::Prefix;
 
-impl<'a> Iterator for NibbleSliceIterator<'a> {
+impl<'a, N: NibbleOps> Iterator for NibbleSliceIterator<'a, N> {
 	type Item = u8;
 	fn next(&mut self) -> Option<u8> {
 		self.i += 1;
@@ -31,7 +31,7 @@ impl<'a> Iterator for NibbleSliceIterator<'a> {
 	}
 }
 
-impl<'a> NibbleSlice<'a> {
+impl<'a, N: NibbleOps> NibbleSlice<'a, N> {
 	/// Create a new nibble slice with the given byte-slice.
 	pub fn new(data: &'a [u8]) -> Self { NibbleSlice::new_slice(data, 0) }
 
@@ -44,23 +44,24 @@ impl<'a> NibbleSlice<'a> {
 		NibbleSlice {
 			data,
 			offset,
+			_marker: PhantomData,
 		}
 	}
 
 	/// Get an iterator for the series of nibbles.
-	pub fn iter(&'a self) -> NibbleSliceIterator<'a> {
+	pub fn iter(&'a self) -> NibbleSliceIterator<'a, N> {
 		NibbleSliceIterator { p: self, i: 0 }
 	}
 
 	/// Get nibble slice from a `NodeKey`.
-	pub fn from_stored(i: &NodeKey) -> NibbleSlice {
+	pub fn from_stored(i: &NodeKey) -> NibbleSlice<N> {
 		NibbleSlice::new_offset(&i.1[..], i.0)
 	}
 
 	/// Helper function to create a owned `NodeKey` from this `NibbleSlice`.
 	pub fn to_stored(&self) -> NodeKey {
-		let split = self.offset / nibble_ops::NIBBLE_PER_BYTE;
-		let offset = self.offset % nibble_ops::NIBBLE_PER_BYTE;
+		let split = self.offset / N::NIBBLE_PER_BYTE;
+		let offset = self.offset % N::NIBBLE_PER_BYTE;
 		(offset, self.data[split..].into())
 	}
 
@@ -70,23 +71,23 @@ impl<'a> NibbleSlice<'a> {
 	/// original padding).
 	pub fn to_stored_range(&self, nb: usize) -> NodeKey {
 		if nb >= self.len() { return self.to_stored() }
-		if (self.offset + nb) % nibble_ops::NIBBLE_PER_BYTE == 0 {
+		if (self.offset + nb) % N::NIBBLE_PER_BYTE == 0 {
 			// aligned
-			let start = self.offset / nibble_ops::NIBBLE_PER_BYTE;
-			let end = (self.offset + nb) / nibble_ops::NIBBLE_PER_BYTE;
+			let start = self.offset / N::NIBBLE_PER_BYTE;
+			let end = (self.offset + nb) / N::NIBBLE_PER_BYTE;
 			(
-				self.offset % nibble_ops::NIBBLE_PER_BYTE,
+				self.offset % N::NIBBLE_PER_BYTE,
 				BackingByteVec::from_slice(&self.data[start..end]),
 			)
 		} else {
 			// unaligned
-			let start = self.offset / nibble_ops::NIBBLE_PER_BYTE;
-			let end = (self.offset + nb) / nibble_ops::NIBBLE_PER_BYTE;
+			let start = self.offset / N::NIBBLE_PER_BYTE;
+			let end = (self.offset + nb) / N::NIBBLE_PER_BYTE;
 			let ea = BackingByteVec::from_slice(&self.data[start..=end]);
-			let ea_offset = self.offset % nibble_ops::NIBBLE_PER_BYTE;
-			let n_offset = nibble_ops::number_padding(nb);
+			let ea_offset = self.offset % N::NIBBLE_PER_BYTE;
+			let n_offset = N::number_padding(nb);
 			let mut result = (ea_offset, ea);
-			nibble_ops::shift_key(&mut result, n_offset);
+			N::shift_key(&mut result, n_offset);
 			result.1.pop();
 			result
 		}
@@ -97,19 +98,35 @@ impl<'a> NibbleSlice<'a> {
 
 	/// Get the length (in nibbles, naturally) of this slice.
 	#[inline]
-	pub fn len(&self) -> usize { self.data.len() * nibble_ops::NIBBLE_PER_BYTE - self.offset }
+	pub fn len(&self) -> usize { self.data.len() * N::NIBBLE_PER_BYTE - self.offset }
 
 	/// Get the nibble at position `i`.
 	#[inline(always)]
 	pub fn at(&self, i: usize) -> u8 {
-		nibble_ops::at(&self, i)
+		N::left_nibble_at(self.data, self.offset + i)
+	}
+
+	/// Get the nibble at position `i`, or `None` if `i` is out of bounds.
+	#[inline]
+	pub fn try_at(&self, i: usize) -> Option<u8> {
+		if i < self.len() {
+			Some(self.at(i))
+		} else {
+			None
+		}
 	}
 
 	/// Return object which represents a view on to this slice (further) offset by `i` nibbles.
-	pub fn mid(&self, i: usize) -> NibbleSlice<'a> {
-		NibbleSlice {
-			data: self.data,
-			offset: self.offset + i,
+	pub fn mid(&self, i: usize) -> NibbleSlice<'a, N> {
+		NibbleSlice::new_offset(self.data, self.offset + i)
+	}
+
+	/// Checked version of `mid`, returns `None` if `i` is greater than the slice length.
+	pub fn try_mid(&self, i: usize) -> Option<NibbleSlice<'a, N>> {
+		if i <= self.len() {
+			Some(self.mid(i))
+		} else {
+			None
 		}
 	}
 
@@ -120,10 +137,17 @@ impl<'a> NibbleSlice<'a> {
 	}
 
 	/// Move back to a previously valid fix offset position.
-	pub fn back(&self, i: usize) -> NibbleSlice<'a> {
-		NibbleSlice {
-			data: self.data,
-			offset: i,
+	pub fn back(&self, i: usize) -> NibbleSlice<'a, N> {
+		NibbleSlice::new_offset(self.data, i)
+	}
+
+	/// Checked version of `back`, returns `None` if `i` is past the end of the
+	/// underlying data.
+	pub fn try_back(&self, i: usize) -> Option<NibbleSlice<'a, N>> {
+		if i <= self.data.len() * N::NIBBLE_PER_BYTE {
+			Some(self.back(i))
+		} else {
+			None
 		}
 	}
 
@@ -144,10 +168,10 @@ impl<'a> NibbleSlice<'a> {
 	/// Return `Partial` representation of this slice:
 	/// first encoded byte and following slice.
 	pub fn right(&'a self) -> Partial {
-		let split = self.offset / nibble_ops::NIBBLE_PER_BYTE;
-		let nb = (self.len() % nibble_ops::NIBBLE_PER_BYTE) as u8;
+		let split = self.offset / N::NIBBLE_PER_BYTE;
+		let nb = (self.len() % N::NIBBLE_PER_BYTE) as u8;
 		if nb > 0 {
-			((nb, nibble_ops::pad_right(self.data[split])), &self.data[split + 1 ..])
+			((nb, N::pad_right(nb as usize, self.data[split])), &self.data[split + 1 ..])
 		} else {
 			((0, 0), &self.data[split..])
 		}
@@ -159,8 +183,9 @@ impl<'a> NibbleSlice<'a> {
 		let mut ix = 0;
 		crate::rstd::iter::from_fn(move || {
 			if first.0 > 0 {
+				let nb = first.0 as usize;
 				first.0 = 0;
-				Some(nibble_ops::pad_right(first.1))
+				Some(N::pad_right(nb, first.1))
 			} else {
 				if ix < sl.len() {
 					ix += 1;
@@ -175,41 +200,32 @@ impl<'a> NibbleSlice<'a> {
 	/// Return `Partial` bytes iterator over a range of byte..
 	/// Warning can be slow when unaligned (similar to `to_stored_range`).
 	pub fn right_range_iter(&'a self, to: usize) -> impl Iterator<Item = u8> + 'a {
-		let mut nib_res = to % nibble_ops::NIBBLE_PER_BYTE;
-		let aligned_i = (self.offset + to) % nibble_ops::NIBBLE_PER_BYTE;
-		let aligned = aligned_i == 0;
-		let mut ix = self.offset / nibble_ops::NIBBLE_PER_BYTE;
-		let ix_lim = (self.offset + to) / nibble_ops::NIBBLE_PER_BYTE;
-		crate::rstd::iter::from_fn( move || {
-			if aligned {
-				if nib_res > 0 {
-					let v = nibble_ops::pad_right(self.data[ix]);
-					nib_res = 0;
-					ix += 1;
-					Some(v)
-				} else if ix < ix_lim {
-					ix += 1;
-					Some(self.data[ix - 1])
-				} else {
-					None
-				}
-			} else {
-				let (s1, s2) = nibble_ops::SPLIT_SHIFTS;
-				// unaligned
-				if nib_res > 0 {
-					let v = self.data[ix] >> s1;
-					let v = nibble_ops::pad_right(v);
-					nib_res = 0;
-					Some(v)
-				} else if ix < ix_lim {
-					ix += 1;
-					let b1 = self.data[ix - 1] << s2;
-					let b2 = self.data[ix] >> s1;
-					Some(b1 | b2)
-				} else {
-					None
-				}
+		let end = self.offset + to;
+		// nibbles of the next byte, the first one holding the nibbles of the range past its
+		// whole bytes.
+		let mut nb = match to % N::NIBBLE_PER_BYTE {
+			0 => N::NIBBLE_PER_BYTE,
+			nb => nb,
+		};
+		let mut start = self.offset;
+		// bits of the last byte of the range past its nibbles.
+		let shift = N::number_padding(end) * N::BIT_PER_NIBBLE;
+		crate::rstd::iter::from_fn(move || {
+			if start >= end {
+				return None;
 			}
+			start += nb;
+			let ix = start / N::NIBBLE_PER_BYTE;
+			let byte = match shift {
+				0 => self.data[ix - 1],
+				_ => {
+					let high = if ix > 0 { (self.data[ix - 1] as u16) << 8 } else { 0 };
+					((high | self.data[ix] as u16) >> shift) as u8
+				},
+			};
+			let byte = N::pad_right(nb, byte);
+			nb = N::NIBBLE_PER_BYTE;
+			Some(byte)
 		})
 	}
 
@@ -217,12 +233,12 @@ impl<'a> NibbleSlice<'a> {
 	/// originates from a full key it will be the `Prefix of
 	/// the node`.
 	pub fn left(&'a self) -> Prefix {
-		let split = self.offset / nibble_ops::NIBBLE_PER_BYTE;
-		let ix = (self.offset % nibble_ops::NIBBLE_PER_BYTE) as u8;
+		let split = self.offset / N::NIBBLE_PER_BYTE;
+		let ix = self.offset % N::NIBBLE_PER_BYTE;
 		if ix == 0 {
 			(&self.data[..split], None)
 		} else {
-			(&self.data[..split], Some(nibble_ops::pad_left(self.data[split])))
+			(&self.data[..split], Some(N::pad_left(ix, self.data[split])))
 		}
 	}
 
@@ -231,29 +247,39 @@ impl<'a> NibbleSlice<'a> {
 		let (a, b) = self.left();
 		(a.into(), b)
 	}
+
+	/// Compare the nibbles of `self` with the nibbles of `vec`, in the order of `NibbleSlice`
+	/// and `NibbleVec`, without converting either.
+	pub fn cmp_prefix(&self, vec: &NibbleVec<N>) -> Ordering {
+		let len = min(self.len(), vec.len());
+		(0..len)
+			.map(|i| self.at(i).cmp(&vec.at(i)))
+			.find(|ordering| *ordering != Ordering::Equal)
+			.unwrap_or_else(|| self.len().cmp(&vec.len()))
+	}
 }
 
-impl<'a> Into<NodeKey> for NibbleSlice<'a> {
+impl<'a, N: NibbleOps> Into<NodeKey> for NibbleSlice<'a, N> {
 	fn into(self) -> NodeKey {
 		(self.offset, self.data.into())
 	}
 }
 
-impl<'a> PartialEq for NibbleSlice<'a> {
+impl<'a, N: NibbleOps> PartialEq for NibbleSlice<'a, N> {
 	fn eq(&self, them: &Self) -> bool {
 		self.len() == them.len() && self.starts_with(them)
 	}
 }
 
-impl<'a> Eq for NibbleSlice<'a> { }
+impl<'a, N: NibbleOps> Eq for NibbleSlice<'a, N> { }
 
-impl<'a> PartialOrd for NibbleSlice<'a> {
+impl<'a, N: NibbleOps> PartialOrd for NibbleSlice<'a, N> {
 	fn partial_cmp(&self, them: &Self) -> Option<Ordering> {
 		Some(self.cmp(them))
 	}
 }
 
-impl<'a> Ord for NibbleSlice<'a> {
+impl<'a, N: NibbleOps> Ord for NibbleSlice<'a, N> {
 	fn cmp(&self, them: &Self) -> Ordering {
 		let s = min(self.len(), them.len());
 		let mut i = 0usize;
@@ -269,7 +295,7 @@ impl<'a> Ord for NibbleSlice<'a> {
 }
 
 #[cfg(feature = "std")]
-impl<'a> fmt::Debug for NibbleSlice<'a> {
+impl<'a, N: NibbleOps> fmt::Debug for NibbleSlice<'a, N> {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		for i in 0..self.len() {
 			match i {
@@ -283,7 +309,9 @@ impl<'a> fmt::Debug for NibbleSlice<'a> {
 
 #[cfg(test)]
 mod tests {
-	use crate::nibble::{NibbleSlice, BackingByteVec};
+	use crate::nibble::{BackingByteVec, Radix16};
+
+	type NibbleSlice<'a> = crate::nibble::NibbleSlice<'a, Radix16>;
 	static D: &'static [u8;3] = &[0x01u8, 0x23, 0x45];
 
 	#[test]
@@ -323,6 +351,24 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn checked_access() {
+		let n = NibbleSlice::new(D);
+		assert_eq!(n.try_at(5), Some(5));
+		assert_eq!(n.try_at(6), None);
+		assert_eq!(n.mid(3).try_at(2), Some(5));
+		assert_eq!(n.mid(3).try_at(3), None);
+
+		assert_eq!(n.try_mid(6).map(|m| m.len()), Some(0));
+		assert!(n.try_mid(7).is_none());
+		assert!(n.mid(4).try_mid(3).is_none());
+
+		let m = n.mid(4);
+		assert_eq!(m.try_back(1).map(|b| b.len()), Some(5));
+		assert_eq!(m.try_back(6).map(|b| b.len()), Some(0));
+		assert!(m.try_back(7).is_none());
+	}
+
 	#[test]
 	fn encoded_pre() {
 		let n = NibbleSlice::new(D);
@@ -420,4 +466,22 @@ mod tests {
 		assert!(n >= m.mid(4));
 		assert!(n <= m.mid(4));
 	}
+
+	#[test]
+	fn radix_2() {
+		type BinarySlice<'a> = crate::nibble::NibbleSlice<'a, crate::nibble::Radix2>;
+		let n = BinarySlice::new(D);
+		assert_eq!(n.len(), 24);
+		let bits: Vec<u8> = n.iter().collect();
+		assert_eq!(&bits[..8], &[0, 0, 0, 0, 0, 0, 0, 1]);
+		assert_eq!(&bits[8..16], &[0, 0, 1, 0, 0, 0, 1, 1]);
+		let m = n.mid(3);
+		assert_eq!(m.len(), 21);
+		assert_eq!(m.at(4), 1);
+		assert_eq!(m.left(), (&[][..], Some(0x00)));
+		assert_eq!(n.mid(10).left(), (&[0x01][..], Some(0x00)));
+		assert_eq!(n.mid(16).right(), ((0, 0), &[0x45][..]));
+		assert_eq!(n.mid(19).right(), ((5, 0x05), &[][..]));
+		assert_eq!(n.common_prefix(&BinarySlice::new(&[0x01, 0x3f])), 11);
+	}
 }
@@ -14,24 +14,25 @@
 
 //! An owning, nibble-oriented byte vector.
 
-use crate::nibble::{NibbleSlice, BackingByteVec};
-use crate::nibble::nibble_ops;
+use crate::nibble::{NibbleOps, NibbleSlice, BackingByteVec};
+use crate::rstd::{cmp::Ordering, marker::PhantomData, vec::Vec};
 use hash_db::Prefix;
 use crate::node_codec::Partial;
 use super::NibbleVec;
 
-impl Default for NibbleVec {
+impl<N: NibbleOps> Default for NibbleVec<N> {
 	fn default() -> Self {
 		NibbleVec::new()
 	}
 }
 
-impl NibbleVec {
+impl<N: NibbleOps> NibbleVec<N> {
 	/// Make a new `NibbleVec`.
 	pub fn new() -> Self {
 		NibbleVec {
 			inner: BackingByteVec::new(),
 			len: 0,
+			_marker: PhantomData,
 		}
 	}
 
@@ -45,21 +46,31 @@ impl NibbleVec {
 	/// Try to get the nibble at the given offset.
 	#[inline]
 	pub fn at(&self, idx: usize) -> u8 {
-		let ix = idx / nibble_ops::NIBBLE_PER_BYTE;
-		let pad = idx % nibble_ops::NIBBLE_PER_BYTE;
-		nibble_ops::at_left(pad as u8, self.inner[ix])
+		let ix = idx / N::NIBBLE_PER_BYTE;
+		let pad = idx % N::NIBBLE_PER_BYTE;
+		N::at_left(pad as u8, self.inner[ix])
+	}
+
+	/// Get the nibble at the given offset, or `None` if it is out of bounds.
+	#[inline]
+	pub fn try_at(&self, idx: usize) -> Option<u8> {
+		if idx < self.len {
+			Some(self.at(idx))
+		} else {
+			None
+		}
 	}
 
 	/// Push a nibble onto the `NibbleVec`. Ignores the high 4 bits.
 	pub fn push(&mut self, nibble: u8) {
-		let i = self.len % nibble_ops::NIBBLE_PER_BYTE;
+		let i = self.len % N::NIBBLE_PER_BYTE;
 
 		if i == 0 {
-			self.inner.push(nibble_ops::push_at_left(0, nibble, 0));
+			self.inner.push(N::push_at_left(0, nibble, 0));
 		} else {
 			let output = self.inner.last_mut()
 				.expect("len != 0 since len % 2 != 0; inner has a last element; qed");
-			*output = nibble_ops::push_at_left(i as u8, nibble, *output);
+			*output = N::push_at_left(i as u8, nibble, *output);
 		}
 		self.len += 1;
 	}
@@ -71,11 +82,11 @@ impl NibbleVec {
 		}
 		let byte = self.inner.pop().expect("len != 0; inner has last elem; qed");
 		self.len -= 1;
-		let i_new = self.len % nibble_ops::NIBBLE_PER_BYTE;
+		let i_new = self.len % N::NIBBLE_PER_BYTE;
 		if i_new != 0 {
-			self.inner.push(nibble_ops::pad_left(byte));
+			self.inner.push(N::pad_left(i_new, byte));
 		}
-		Some(nibble_ops::at_left(i_new as u8, byte))
+		Some(N::at_left(i_new as u8, byte))
 	}
 
 	/// Remove then n last nibbles in a faster way than popping n times.
@@ -86,70 +97,93 @@ impl NibbleVec {
 			return;
 		}
 		let end = self.len - n;
-		let end_index = end / nibble_ops::NIBBLE_PER_BYTE
-			+ if end % nibble_ops::NIBBLE_PER_BYTE == 0 { 0 } else { 1 };
+		let end_index = end / N::NIBBLE_PER_BYTE
+			+ if end % N::NIBBLE_PER_BYTE == 0 { 0 } else { 1 };
 		(end_index..self.inner.len()).for_each(|_| { self.inner.pop(); });
 		self.len = end;
-		let pos = self.len % nibble_ops::NIBBLE_PER_BYTE;
+		let pos = self.len % N::NIBBLE_PER_BYTE;
 		if pos != 0 {
 			let kl = self.inner.len() - 1;
-			self.inner[kl] = nibble_ops::pad_left(self.inner[kl]);
+			self.inner[kl] = N::pad_left(pos, self.inner[kl]);
 		}
 	}
 
 	/// Get `Prefix` representation of this `NibbleVec`.
 	pub fn as_prefix(&self) -> Prefix {
-		let split = self.len / nibble_ops::NIBBLE_PER_BYTE;
-		let pos = (self.len % nibble_ops::NIBBLE_PER_BYTE) as u8;
+		let split = self.len / N::NIBBLE_PER_BYTE;
+		let pos = self.len % N::NIBBLE_PER_BYTE;
 		if pos == 0 {
 			(&self.inner[..split], None)
 		} else {
-			(&self.inner[..split], Some(nibble_ops::pad_left(self.inner[split])))
+			(&self.inner[..split], Some(N::pad_left(pos, self.inner[split])))
 		}
 	}
 
-	/// Append another `NibbleVec`. Can be slow (alignement of second vec).
-	pub fn append(&mut self, v: &NibbleVec) {
+	/// Owned version of `as_prefix`: the whole bytes of nibbles and the nibbles past them, in
+	/// the high bits of their byte.
+	pub fn to_key_bytes(&self) -> (Vec<u8>, Option<u8>) {
+		let (bytes, last) = self.as_prefix();
+		(bytes.to_vec(), last)
+	}
 
+	/// The `NibbleVec` of `prefix`, the nibbles of its bytes followed by the first nibble of
+	/// its last byte, if any: the number of nibbles in the last byte of a `Prefix` is only
+	/// known for nibbles of 4 bits.
+	pub fn from_prefix(prefix: Prefix) -> Self {
+		let mut v = NibbleVec {
+			inner: prefix.0.into(),
+			len: prefix.0.len() * N::NIBBLE_PER_BYTE,
+			_marker: PhantomData,
+		};
+		if let Some(last) = prefix.1 {
+			v.push(N::at_left(0, last));
+		}
+		v
+	}
+
+	/// Append another `NibbleVec`, copying its bytes at once when both are aligned and
+	/// shifting them in a single pass otherwise.
+	pub fn append(&mut self, v: &NibbleVec<N>) {
 		if v.len == 0 { return; }
 		let final_len = self.len + v.len;
-		let offset = self.len % nibble_ops::NIBBLE_PER_BYTE;
-		let final_offset = final_len % nibble_ops::NIBBLE_PER_BYTE;
-		let last_index = self.len / nibble_ops::NIBBLE_PER_BYTE;
+		let offset = self.len % N::NIBBLE_PER_BYTE;
+		self.inner.reserve(v.inner.len());
 		if offset > 0 {
-			let (s1, s2) = nibble_ops::SPLIT_SHIFTS;
-			self.inner[last_index] = nibble_ops::pad_left(self.inner[last_index])
+			let s2 = offset * N::BIT_PER_NIBBLE;
+			let s1 = 8 - s2;
+			let last_index = self.len / N::NIBBLE_PER_BYTE;
+			self.inner[last_index] = N::pad_left(offset, self.inner[last_index])
 				| (v.inner[0] >> s2);
-			(0..v.inner.len() - 1)
-				.for_each(|i| self.inner.push(v.inner[i] << s1 | v.inner[i+1] >> s2));
-			if final_offset > 0 {
+			self.inner.extend(v.inner.windows(2).map(|w| w[0] << s1 | w[1] >> s2));
+			let final_bytes = final_len.div_ceil(N::NIBBLE_PER_BYTE);
+			if final_bytes > self.inner.len() {
 				self.inner.push(v.inner[v.inner.len() - 1] << s1);
 			}
 		} else {
-			(0..v.inner.len()).for_each(|i| self.inner.push(v.inner[i]));
+			self.inner.extend_from_slice(&v.inner[..]);
 		}
-		self.len += v.len;
+		self.len = final_len;
 	}
 
-	/// Append a `Partial`. Can be slow (alignement of partial).
+	/// Append a `Partial`, copying its bytes at once when aligned and shifting them in a
+	/// single pass otherwise.
 	pub fn append_partial(&mut self, (start_byte, sl): Partial) {
-		if start_byte.0 == 1 {
-			self.push(nibble_ops::at_left(1, start_byte.1));
-		}
-		let pad = self.inner.len() * nibble_ops::NIBBLE_PER_BYTE - self.len;
+		self.inner.reserve(sl.len() + 1);
+		let first = N::NIBBLE_PER_BYTE - start_byte.0 as usize;
+		(first..N::NIBBLE_PER_BYTE).for_each(|ix| self.push(N::at_left(ix as u8, start_byte.1)));
+		let pad = self.inner.len() * N::NIBBLE_PER_BYTE - self.len;
 		if pad == 0 {
 			self.inner.extend_from_slice(&sl[..]);
-		} else {
+		} else if !sl.is_empty() {
 			let kend = self.inner.len() - 1;
-			if sl.len() > 0 {
-				self.inner[kend] = nibble_ops::pad_left(self.inner[kend]);
-				let (s1, s2) = nibble_ops::SPLIT_SHIFTS;
-				self.inner[kend] |= sl[0] >> s1;
-				(0..sl.len() - 1).for_each(|i| self.inner.push(sl[i] << s2 | sl[i+1] >> s1));
-				self.inner.push(sl[sl.len() - 1] << s2);
-			}
+			let s1 = (N::NIBBLE_PER_BYTE - pad) * N::BIT_PER_NIBBLE;
+			let s2 = 8 - s1;
+			self.inner[kend] = N::pad_left(N::NIBBLE_PER_BYTE - pad, self.inner[kend]);
+			self.inner[kend] |= sl[0] >> s1;
+			self.inner.extend(sl.windows(2).map(|w| w[0] << s2 | w[1] >> s1));
+			self.inner.push(sl[sl.len() - 1] << s2);
 		}
-		self.len += sl.len() * nibble_ops::NIBBLE_PER_BYTE;
+		self.len += sl.len() * N::NIBBLE_PER_BYTE;
 	}
 
 	/// Utility function for chaining two optional appending
@@ -157,7 +191,7 @@ impl NibbleVec {
 	/// Can be slow.
 	pub(crate) fn append_optional_slice_and_nibble(
 		&mut self,
-		o_slice: Option<&NibbleSlice>,
+		o_slice: Option<&NibbleSlice<N>>,
 		o_index: Option<u8>,
 	) -> usize {
 		let mut res = 0;
@@ -176,7 +210,7 @@ impl NibbleVec {
 	/// Can be slow.
 	pub(crate) fn clone_append_optional_slice_and_nibble(
 		&self,
-		o_slice: Option<&NibbleSlice>,
+		o_slice: Option<&NibbleSlice<N>>,
 		o_index: Option<u8>,
 	) -> Self {
 		let mut p = self.clone();
@@ -195,27 +229,56 @@ impl NibbleVec {
 		self.len = 0;
 	}
 
-	/// Try to treat this `NibbleVec` as a `NibbleSlice`. Works only if there is no padding.
-	pub fn as_nibbleslice(&self) -> Option<NibbleSlice> {
-		if self.len % nibble_ops::NIBBLE_PER_BYTE == 0 {
+	/// Try to treat this `NibbleVec` as a `NibbleSlice`. Works only if there is no padding, that
+	/// is for an even number of nibbles.
+	pub fn as_nibbleslice(&self) -> Option<NibbleSlice<N>> {
+		if self.len % N::NIBBLE_PER_BYTE == 0 {
 			Some(NibbleSlice::new(self.inner()))
 		} else {
 			None
 		}
 	}
 
+	/// Keep the first `len` nibbles, clearing the padding nibble of an odd length. Does nothing
+	/// if the `NibbleVec` is not longer than `len`.
+	pub fn truncate(&mut self, len: usize) {
+		if len < self.len {
+			self.drop_lasts(self.len - len);
+		}
+	}
+
+	/// Number of nibbles at the start of both `self` and `other`.
+	pub fn common_prefix_len(&self, other: &Self) -> usize {
+		let len = self.len.min(other.len);
+		let byte_len = len / N::NIBBLE_PER_BYTE;
+		let common_bytes = self.inner[..byte_len].iter()
+			.zip(other.inner[..byte_len].iter())
+			.take_while(|(a, b)| a == b)
+			.count();
+		let mut common = common_bytes * N::NIBBLE_PER_BYTE;
+		while common < len && self.at(common) == other.at(common) {
+			common += 1;
+		}
+		common
+	}
+
+	/// Do we start with the same nibbles as the whole of `slice`?
+	pub fn starts_with(&self, slice: &NibbleSlice<N>) -> bool {
+		self.len >= slice.len() && (0..slice.len()).all(|i| self.at(i) == slice.at(i))
+	}
+
 	/// Do we start with the same nibbles as the whole of `them`?
-	pub fn starts_with(&self, other: &Self) -> bool {
+	pub fn starts_with_vec(&self, other: &Self) -> bool {
 		if self.len() < other.len() {
 			return false;
 		}
-		let byte_len = other.len() / nibble_ops::NIBBLE_PER_BYTE;
+		let byte_len = other.len() / N::NIBBLE_PER_BYTE;
 		if &self.inner[..byte_len] != &other.inner[..byte_len] {
 			return false;
 		}
-		for pad in 0..(other.len() - byte_len * nibble_ops::NIBBLE_PER_BYTE) {
-			let self_nibble = nibble_ops::at_left(pad as u8, self.inner[byte_len]);
-			let other_nibble = nibble_ops::at_left(pad as u8, other.inner[byte_len]);
+		for pad in 0..(other.len() - byte_len * N::NIBBLE_PER_BYTE) {
+			let self_nibble = N::at_left(pad as u8, self.inner[byte_len]);
+			let other_nibble = N::at_left(pad as u8, other.inner[byte_len]);
 			if self_nibble != other_nibble {
 				return false;
 			}
@@ -224,8 +287,26 @@ impl NibbleVec {
 	}
 }
 
-impl<'a> From<NibbleSlice<'a>> for NibbleVec {
-	fn from(s: NibbleSlice<'a>) -> Self {
+impl<N: NibbleOps> PartialOrd for NibbleVec<N> {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+/// Nibbles in order, a `NibbleVec` coming before the longer ones it is a prefix of, as for
+/// `NibbleSlice`.
+impl<N: NibbleOps> Ord for NibbleVec<N> {
+	fn cmp(&self, other: &Self) -> Ordering {
+		let common = self.common_prefix_len(other);
+		match (self.try_at(common), other.try_at(common)) {
+			(Some(a), Some(b)) => a.cmp(&b),
+			_ => self.len.cmp(&other.len),
+		}
+	}
+}
+
+impl<'a, N: NibbleOps> From<NibbleSlice<'a, N>> for NibbleVec<N> {
+	fn from(s: NibbleSlice<'a, N>) -> Self {
 		let mut v = NibbleVec::new();
 		for i in 0..s.len() {
 			v.push(s.at(i));
@@ -234,10 +315,36 @@ impl<'a> From<NibbleSlice<'a>> for NibbleVec {
 	}
 }
 
+#[cfg(feature = "std")]
+impl<N: NibbleOps> std::fmt::Debug for NibbleVec<N> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		for i in 0..self.len() {
+			match i {
+				0 => write!(f, "{:01x}", self.at(i))?,
+				_ => write!(f, "'{:01x}", self.at(i))?,
+			}
+		}
+		Ok(())
+	}
+}
+
 #[cfg(test)]
 mod tests {
-	use crate::nibble::NibbleVec;
-	use crate::nibble::nibble_ops;
+	use crate::nibble::{nibble_ops, Radix16};
+	use crate::rstd::cmp::Ordering;
+
+	type NibbleSlice<'a> = crate::nibble::NibbleSlice<'a, Radix16>;
+	type NibbleVec = crate::nibble::NibbleVec<Radix16>;
+
+	#[test]
+	fn debug_prints_nibbles() {
+		let mut v = NibbleVec::new();
+		assert_eq!(format!("{:?}", v), "");
+		v.push(0x1);
+		v.push(0xa);
+		v.push(0x3);
+		assert_eq!(format!("{:?}", v), "1'a'3");
+	}
 
 	#[test]
 	fn push_pop() {
@@ -258,6 +365,16 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn try_at() {
+		let mut v = NibbleVec::new();
+		assert_eq!(v.try_at(0), None);
+		v.push(1);
+		assert_eq!(v.try_at(0), Some(1));
+		// second nibble of the backing byte is not part of the vec
+		assert_eq!(v.try_at(1), None);
+	}
+
 	#[test]
 	fn append_partial() {
 		append_partial_inner(&[1, 2, 3], &[], ((1, 1), &[0x23]));
@@ -274,6 +391,56 @@ mod tests {
 		assert_eq!(resv, initv);
 	}
 
+	fn nibbles(len: usize, seed: u8) -> NibbleVec {
+		let mut v = NibbleVec::new();
+		(0..len).for_each(|i| v.push((i as u8).wrapping_mul(7).wrapping_add(seed) & 0x0f));
+		v
+	}
+
+	#[test]
+	fn append_matches_push() {
+		for init_len in 0..9 {
+			for len in 0..9 {
+				let init = nibbles(init_len, 3);
+				let other = nibbles(len, 11);
+				let mut expected = init.clone();
+				(0..len).for_each(|i| expected.push(other.at(i)));
+				let mut appended = init.clone();
+				appended.append(&other);
+				assert_eq!(appended, expected);
+			}
+		}
+	}
+
+	#[test]
+	fn append_partial_matches_push() {
+		for init_len in 0..9 {
+			for bytes_len in 0..5 {
+				let bytes: Vec<u8> = (0..bytes_len)
+					.map(|i| 0x1f_u8.wrapping_mul(i as u8 + 1))
+					.collect();
+				for start in [None, Some(0x0a)].iter() {
+					let init = nibbles(init_len, 5);
+					let mut expected = init.clone();
+					if let Some(start) = start {
+						expected.push(*start);
+					}
+					bytes.iter().for_each(|b| {
+						expected.push(nibble_ops::at_left(0, *b));
+						expected.push(nibble_ops::at_left(1, *b));
+					});
+					let start_byte = match start {
+						Some(start) => (1, *start),
+						None => (0, 0),
+					};
+					let mut appended = init.clone();
+					appended.append_partial((start_byte, &bytes[..]));
+					assert_eq!(appended, expected);
+				}
+			}
+		}
+	}
+
 	#[test]
 	fn drop_lasts_test() {
 		let test_trun = |a: &[u8], b: usize, c: (&[u8], usize)| {
@@ -297,4 +464,161 @@ mod tests {
 		test_trun(&[1, 2, 3], 4, (&[], 0));
 	}
 
+	/// Nibble vectors of up to six nibbles, with for each length the vectors differing from one
+	/// of them by a single nibble, on either side of each half-byte boundary.
+	fn vectors() -> Vec<NibbleVec> {
+		let mut vectors = Vec::new();
+		for len in 0..7 {
+			vectors.push(nibbles(len, 3));
+			vectors.push(nibbles(len, 0));
+			for changed in 0..len {
+				let mut v = nibbles(len, 3);
+				v.truncate(changed);
+				v.push((nibbles(len, 3).at(changed) + 1) & 0x0f);
+				(changed + 1..len).for_each(|i| v.push(nibbles(len, 3).at(i)));
+				vectors.push(v);
+			}
+		}
+		vectors
+	}
+
+	fn naive_common_prefix_len(a: &NibbleVec, b: &NibbleVec) -> usize {
+		(0..a.len().min(b.len())).take_while(|i| a.at(*i) == b.at(*i)).count()
+	}
+
+	#[test]
+	fn common_prefix_len_and_starts_with_vec() {
+		let vectors = vectors();
+		for a in vectors.iter() {
+			for b in vectors.iter() {
+				let common = naive_common_prefix_len(a, b);
+				assert_eq!(a.common_prefix_len(b), common, "{:?} {:?}", a, b);
+				assert_eq!(a.starts_with_vec(b), common == b.len(), "{:?} {:?}", a, b);
+			}
+		}
+	}
+
+	#[test]
+	fn starts_with_slices_at_both_offsets() {
+		let data = [0x12, 0x34, 0x56];
+		// the nibbles 1 to 6 then 0 at an odd offset.
+		let shifted = [0x01, 0x23, 0x45, 0x60];
+		let mut slices = Vec::new();
+		(0..4).for_each(|n| slices.push(NibbleSlice::new(&data[..n])));
+		(1..5).for_each(|n| slices.push(NibbleSlice::new_offset(&shifted[..n], 1)));
+		(0..6).for_each(|offset| slices.push(NibbleSlice::new_offset(&data, offset)));
+		slices.push(NibbleSlice::new_offset(&[0x11, 0x24], 1));
+		slices.push(NibbleSlice::new(&[0x13]));
+
+		let full = NibbleVec::from(NibbleSlice::new(&data));
+		for len in 0..7 {
+			let mut v = full.clone();
+			v.truncate(len);
+			for slice in slices.iter() {
+				let expected = slice.len() <= v.len()
+					&& (0..slice.len()).all(|i| v.at(i) == slice.at(i));
+				assert_eq!(v.starts_with(slice), expected, "{:?} {:?}", v, slice);
+			}
+		}
+	}
+
+	#[test]
+	fn truncate_clears_padding() {
+		for len in 0..7 {
+			for keep in 0..8 {
+				let mut truncated = nibbles(len, 9);
+				truncated.truncate(keep);
+				let expected = nibbles(len.min(keep), 9);
+				assert_eq!(truncated, expected);
+				assert_eq!(truncated.inner(), expected.inner());
+			}
+		}
+		let mut v = nibbles(3, 9);
+		v.truncate(1);
+		assert_eq!(v.inner(), &[nibble_ops::pad_left(nibbles(2, 9).inner()[0])]);
+	}
+
+	#[test]
+	fn as_nibbleslice_when_aligned() {
+		for len in 0..7 {
+			let v = nibbles(len, 5);
+			match v.as_nibbleslice() {
+				Some(slice) => {
+					assert_eq!(len % 2, 0);
+					assert_eq!(NibbleVec::from(slice), v);
+					assert!(v.starts_with(&slice));
+				},
+				None => assert_eq!(len % 2, 1),
+			}
+		}
+	}
+
+	#[test]
+	fn prefix_round_trip() {
+		for len in 0..9 {
+			for seed in 0..16 {
+				let v = nibbles(len, seed);
+				let (bytes, last) = v.to_key_bytes();
+				assert_eq!(bytes.len(), len / 2);
+				assert_eq!(last.is_some(), len % 2 == 1);
+				assert_eq!(last.map_or(0, |last| last & 0x0f), 0);
+				assert_eq!((&bytes[..], last), v.as_prefix());
+				assert_eq!(NibbleVec::from_prefix((&bytes, last)), v);
+			}
+		}
+		// the low half of the last byte is not part of the prefix.
+		assert_eq!(NibbleVec::from_prefix((&[0x12], Some(0x3f))), nibbles_of(&[1, 2, 3]));
+	}
+
+	fn nibbles_of(nibbles: &[u8]) -> NibbleVec {
+		let mut v = NibbleVec::new();
+		nibbles.iter().for_each(|nibble| v.push(*nibble));
+		v
+	}
+
+	#[test]
+	fn order_matches_slices() {
+		let vectors = vectors();
+		for a in vectors.iter() {
+			for b in vectors.iter() {
+				let naive = (0..a.len().min(b.len()))
+					.map(|i| a.at(i).cmp(&b.at(i)))
+					.find(|ordering| *ordering != Ordering::Equal)
+					.unwrap_or_else(|| a.len().cmp(&b.len()));
+				assert_eq!(a.cmp(b), naive, "{:?} {:?}", a, b);
+				assert_eq!(a.partial_cmp(b), Some(naive));
+				// `a` as a slice at an even offset for even lengths, at an odd offset for odd
+				// lengths.
+				let mut shifted = nibbles_of(&[0]);
+				shifted.append(a);
+				let slice = match a.as_nibbleslice() {
+					Some(slice) => slice,
+					None => NibbleSlice::new_offset(shifted.inner(), 1),
+				};
+				assert_eq!(slice.len(), a.len());
+				assert_eq!(slice.cmp_prefix(b), naive, "{:?} {:?}", a, b);
+			}
+		}
+		assert!(nibbles_of(&[1]) < nibbles_of(&[1, 0]));
+		assert!(nibbles_of(&[1, 0]) < nibbles_of(&[2]));
+		assert!(nibbles_of(&[0, 15]) < nibbles_of(&[1]));
+	}
+
+	#[test]
+	fn radix_2_push_pop() {
+		let mut v = crate::nibble::NibbleVec::<crate::nibble::Radix2>::new();
+		for i in 0..11 {
+			v.push((i % 3 == 0) as u8);
+		}
+		assert_eq!(v.len(), 11);
+		assert_eq!(v.inner(), &[0b1001_0010, 0b0100_0000]);
+		assert_eq!(v.as_prefix(), (&[0b1001_0010][..], Some(0b0100_0000)));
+		assert_eq!(v.pop(), Some(0));
+		assert_eq!(v.pop(), Some(1));
+		assert_eq!(v.len(), 9);
+		assert_eq!(v.inner(), &[0b1001_0010, 0]);
+		v.drop_lasts(1);
+		assert_eq!(v.inner(), &[0b1001_0010]);
+		assert_eq!(v.as_nibbleslice().unwrap().len(), 8);
+	}
 }
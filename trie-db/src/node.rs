@@ -13,11 +13,14 @@
 // limitations under the License.
 
 use hash_db::Hasher;
-use crate::nibble::{self, NibbleSlice};
-use crate::nibble::nibble_ops;
+use crate::nibble::{self, Children, NibbleOps, NibbleSlice, Radix16};
 use crate::node_codec::NodeCodec;
+use crate::value_chunks::{chunk_size, ChunkIndex};
+use crate::{ChildReference, CError, Result as TrieResult, TrieError, TrieHash, TrieLayout};
 
-use crate::rstd::{borrow::Borrow, ops::Range};
+use crate::rstd::{
+	borrow::Borrow, boxed::Box, convert::TryFrom, marker::PhantomData, ops::Range, vec::Vec,
+};
 
 /// Partial node key type: offset and owned value of a nibbleslice.
 /// Offset is applied on first byte of array (bytes are right aligned).
@@ -40,21 +43,172 @@ pub fn decode_hash<H: Hasher>(data: &[u8]) -> Option<H::Out> {
 	Some(hash)
 }
 
+/// Tag of the value slot of a node holding its value, in a layout with value nodes.
+const INLINE_VALUE: u8 = 0;
+/// Tag of the value slot of a node holding the hash of its value node.
+const HASHED_VALUE: u8 = 1;
+/// Tag of the value slot of a node holding the hash of the index of the chunks of its value.
+const CHUNKED_VALUE: u8 = 2;
+
+/// Value of a node, held by the node or stored apart in a value node, see
+/// `TrieLayout::MAX_INLINE_VALUE`.
+#[derive(Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum Value<'a> {
+	/// The value, held by the node.
+	Inline(&'a [u8]),
+	/// The hash of the value node, stored under the prefix of the node.
+	Node(&'a [u8]),
+	/// The hash of the index of the chunks of the value, stored under the prefix of the node
+	/// with the chunks, see `TrieLayout::MAX_VALUE_CHUNK`.
+	Chunked(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+	/// The value of `slot`, the value slot of a node of `L`, as found in `Node`. `node_hash` is
+	/// the hash of the node, or of the closest node above it for inline nodes. In a layout with
+	/// value nodes, an empty slot, as the omitted values of proofs, is read as an empty inline
+	/// value, and a slot with an unknown tag is a `TrieError::InvalidValueTag`.
+	pub fn from_slot<L: TrieLayout>(
+		slot: &'a [u8],
+		node_hash: TrieHash<L>,
+	) -> TrieResult<Self, TrieHash<L>, CError<L>> {
+		Self::from_tagged_slot(slot, L::MAX_INLINE_VALUE.is_some(), node_hash)
+	}
+
+	/// The value of `slot`, a value slot starting with a tag if `tagged`.
+	pub(crate) fn from_tagged_slot<T, E>(
+		slot: &'a [u8],
+		tagged: bool,
+		node_hash: T,
+	) -> TrieResult<Self, T, E> {
+		if !tagged {
+			return Ok(Value::Inline(slot));
+		}
+		match slot.split_first() {
+			None => Ok(Value::Inline(slot)),
+			Some((&INLINE_VALUE, value)) => Ok(Value::Inline(value)),
+			Some((&HASHED_VALUE, hash)) => Ok(Value::Node(hash)),
+			Some((&CHUNKED_VALUE, hash)) => Ok(Value::Chunked(hash)),
+			Some((&tag, _)) => Err(Box::new(TrieError::InvalidValueTag(node_hash, tag))),
+		}
+	}
+}
+
+/// Hash of the value node of `value` in `L`, or of the index of its chunks, `None` if `value`
+/// is held by its node.
+pub fn value_node_hash<L: TrieLayout>(value: &[u8]) -> Option<TrieHash<L>> {
+	store_value::<L>(value, L::Hash::hash)
+}
+
+/// Pass the nodes storing `value` apart in `L` to `write`, which returns their hash: the value
+/// node, or the chunks of the value followed by their index. Returns the hash of the last node
+/// written, `None` if `value` is held by its node.
+pub(crate) fn store_value<L: TrieLayout>(
+	value: &[u8],
+	mut write: impl FnMut(&[u8]) -> TrieHash<L>,
+) -> Option<TrieHash<L>> {
+	match L::MAX_INLINE_VALUE {
+		Some(max) if value.len() > max => (),
+		_ => return None,
+	}
+	let chunk_size = match chunk_size::<L>(value.len()) {
+		Some(chunk_size) => chunk_size,
+		None => return Some(write(value)),
+	};
+	let chunks = value.chunks(chunk_size).map(&mut write).collect();
+	let index = ChunkIndex { len: value.len(), chunk_size, chunks };
+	Some(write(&index.encode()))
+}
+
+/// Value slot of a node of `L` holding `value`, `hash` being `value_node_hash` of `value`.
+/// `None` if the slot is `value` itself, in a layout without value nodes.
+pub fn value_slot<L: TrieLayout>(value: &[u8], hash: Option<&TrieHash<L>>) -> Option<Vec<u8>> {
+	L::MAX_INLINE_VALUE?;
+	let (tag, content) = match hash {
+		Some(hash) if chunk_size::<L>(value.len()).is_some() => (CHUNKED_VALUE, hash.as_ref()),
+		Some(hash) => (HASHED_VALUE, hash.as_ref()),
+		None => (INLINE_VALUE, value),
+	};
+	let mut slot = Vec::with_capacity(1 + content.len());
+	slot.push(tag);
+	slot.extend_from_slice(content);
+	Some(slot)
+}
+
+/// Value slot of a node of `L` holding a value stored in chunks, `hash` being the hash of the
+/// index of its chunks.
+pub(crate) fn chunked_slot<L: TrieLayout>(hash: &TrieHash<L>) -> Vec<u8> {
+	let mut slot = Vec::with_capacity(1 + hash.as_ref().len());
+	slot.push(CHUNKED_VALUE);
+	slot.extend_from_slice(hash.as_ref());
+	slot
+}
+
 /// Type of node in the trie and essential information thereof.
 #[derive(Eq, PartialEq, Clone)]
 #[cfg_attr(feature = "std", derive(Debug))]
-pub enum Node<'a> {
+pub enum Node<'a, N: NibbleOps = Radix16> {
 	/// Null trie node; could be an empty root or an empty branch entry.
 	Empty,
 	/// Leaf node; has key slice and value. Value may not be empty.
-	Leaf(NibbleSlice<'a>, &'a [u8]),
+	Leaf(NibbleSlice<'a, N>, &'a [u8]),
 	/// Extension node; has key slice and node data. Data may not be null.
-	Extension(NibbleSlice<'a>, NodeHandle<'a>),
+	Extension(NibbleSlice<'a, N>, NodeHandle<'a>),
 	/// Branch node; has slice of child nodes (each possibly null)
 	/// and an optional immediate node data.
-	Branch([Option<NodeHandle<'a>>; nibble_ops::NIBBLE_LENGTH], Option<&'a [u8]>),
+	Branch(Children<NodeHandle<'a>, N>, Option<&'a [u8]>),
 	/// Branch node with support for a nibble (when extension nodes are not used).
-	NibbledBranch(NibbleSlice<'a>, [Option<NodeHandle<'a>>; nibble_ops::NIBBLE_LENGTH], Option<&'a [u8]>),
+	NibbledBranch(NibbleSlice<'a, N>, Children<NodeHandle<'a>, N>, Option<&'a [u8]>),
+}
+
+/// Encode `node` with the codec of `L`: the encoding `NodeCodec::decode` decoded `node` from,
+/// if it was canonical.
+///
+/// Returns `TrieError::InvalidHash` if a child handle has no `ChildReference`: a hash of
+/// another length than the hashes of `L`, or inline data longer than them. The node must be of
+/// a kind the layout uses, as the codecs may panic on the others.
+pub fn encode_node<L: TrieLayout>(
+	node: &Node<L::Nibble>,
+) -> TrieResult<Vec<u8>, TrieHash<L>, CError<L>> {
+	Ok(match node {
+		Node::Empty => L::Codec::empty_node().to_vec(),
+		Node::Leaf(partial, value) => L::Codec::leaf_node(partial.right(), value),
+		Node::Extension(partial, child) => L::Codec::extension_node(
+			partial.right_iter(),
+			partial.len(),
+			child_reference::<L>(*child)?,
+		),
+		Node::Branch(children, value) => {
+			L::Codec::branch_node(branch_children::<L>(children)?.iter(), *value)
+		},
+		Node::NibbledBranch(partial, children, value) => L::Codec::branch_node_nibbled(
+			partial.right_iter(),
+			partial.len(),
+			branch_children::<L>(children)?.iter(),
+			*value,
+		),
+	})
+}
+
+fn child_reference<L: TrieLayout>(
+	handle: NodeHandle,
+) -> TrieResult<ChildReference<TrieHash<L>>, TrieHash<L>, CError<L>> {
+	ChildReference::try_from(handle)
+		.map_err(|data| Box::new(TrieError::InvalidHash(Default::default(), data)))
+}
+
+/// References to the children of a branch.
+type BranchChildren<L> = Children<ChildReference<TrieHash<L>>, <L as TrieLayout>::Nibble>;
+
+fn branch_children<L: TrieLayout>(
+	children: &Children<NodeHandle, L::Nibble>,
+) -> TrieResult<BranchChildren<L>, TrieHash<L>, CError<L>> {
+	let mut references = Children::new();
+	for (reference, child) in references.iter_mut().zip(children.iter()) {
+		*reference = child.map(child_reference::<L>).transpose()?;
+	}
+	Ok(references)
 }
 
 /// A `NodeHandlePlan` is a decoding plan for constructing a `NodeHandle` from an encoded trie
@@ -81,29 +235,31 @@ impl NodeHandlePlan {
 /// `NibbleSlicePlan` is created by parsing a byte slice and can be reused multiple times.
 #[derive(Eq, PartialEq, Clone)]
 #[cfg_attr(feature = "std", derive(Debug))]
-pub struct NibbleSlicePlan {
+pub struct NibbleSlicePlan<N: NibbleOps = Radix16> {
 	bytes: Range<usize>,
 	offset: usize,
+	_marker: PhantomData<N>,
 }
 
-impl NibbleSlicePlan {
+impl<N: NibbleOps> NibbleSlicePlan<N> {
 	/// Construct a nibble slice decode plan.
 	pub fn new(bytes: Range<usize>, offset: usize) -> Self {
 		NibbleSlicePlan {
 			bytes,
-			offset
+			offset,
+			_marker: PhantomData,
 		}
 	}
 
 	/// Returns the nibble length of the slice.
 	pub fn len(&self) -> usize {
-		(self.bytes.end - self.bytes.start) * nibble_ops::NIBBLE_PER_BYTE - self.offset
+		(self.bytes.end - self.bytes.start) * N::NIBBLE_PER_BYTE - self.offset
 	}
 
 	/// Build a nibble slice by decoding a byte slice according to the plan. It is the
 	/// responsibility of the caller to ensure that the node plan was created for the argument
 	/// data, otherwise the call may decode incorrectly or panic.
-	pub fn build<'a, 'b>(&'a self, data: &'b [u8]) -> NibbleSlice<'b> {
+	pub fn build<'a, 'b>(&'a self, data: &'b [u8]) -> NibbleSlice<'b, N> {
 		NibbleSlice::new_offset(&data[self.bytes.clone()], self.offset)
 	}
 }
@@ -116,38 +272,38 @@ impl NibbleSlicePlan {
 /// ranges that can be used to index into a large byte slice.
 #[derive(Eq, PartialEq, Clone)]
 #[cfg_attr(feature = "std", derive(Debug))]
-pub enum NodePlan {
+pub enum NodePlan<N: NibbleOps = Radix16> {
 	/// Null trie node; could be an empty root or an empty branch entry.
 	Empty,
 	/// Leaf node; has a partial key plan and value.
 	Leaf {
-		partial: NibbleSlicePlan,
+		partial: NibbleSlicePlan<N>,
 		value: Range<usize>,
 	},
 	/// Extension node; has a partial key plan and child data.
 	Extension {
-		partial: NibbleSlicePlan,
+		partial: NibbleSlicePlan<N>,
 		child: NodeHandlePlan,
 	},
 	/// Branch node; has slice of child nodes (each possibly null)
 	/// and an optional immediate node data.
 	Branch {
 		value: Option<Range<usize>>,
-		children: [Option<NodeHandlePlan>; nibble_ops::NIBBLE_LENGTH],
+		children: Children<NodeHandlePlan, N>,
 	},
 	/// Branch node with support for a nibble (when extension nodes are not used).
 	NibbledBranch {
-		partial: NibbleSlicePlan,
+		partial: NibbleSlicePlan<N>,
 		value: Option<Range<usize>>,
-		children: [Option<NodeHandlePlan>; nibble_ops::NIBBLE_LENGTH],
+		children: Children<NodeHandlePlan, N>,
 	},
 }
 
-impl NodePlan {
+impl<N: NibbleOps> NodePlan<N> {
 	/// Build a node by decoding a byte slice according to the node plan. It is the responsibility
 	/// of the caller to ensure that the node plan was created for the argument data, otherwise the
 	/// call may decode incorrectly or panic.
-	pub fn build<'a, 'b>(&'a self, data: &'b [u8]) -> Node<'b> {
+	pub fn build<'a, 'b>(&'a self, data: &'b [u8]) -> Node<'b, N> {
 		match self {
 			NodePlan::Empty => Node::Empty,
 			NodePlan::Leaf { partial, value } =>
@@ -155,16 +311,16 @@ impl NodePlan {
 			NodePlan::Extension { partial, child } =>
 				Node::Extension(partial.build(data), child.build(data)),
 			NodePlan::Branch { value, children } => {
-				let mut child_slices = [None; nibble_ops::NIBBLE_LENGTH];
-				for i in 0..nibble_ops::NIBBLE_LENGTH {
+				let mut child_slices = Children::new();
+				for i in 0..N::NIBBLE_LENGTH {
 					child_slices[i] = children[i].as_ref().map(|child| child.build(data));
 				}
 				let value_slice = value.clone().map(|value| &data[value]);
 				Node::Branch(child_slices, value_slice)
 			},
 			NodePlan::NibbledBranch { partial, value, children } => {
-				let mut child_slices = [None; nibble_ops::NIBBLE_LENGTH];
-				for i in 0..nibble_ops::NIBBLE_LENGTH {
+				let mut child_slices = Children::new();
+				for i in 0..N::NIBBLE_LENGTH {
 					child_slices[i] = children[i].as_ref().map(|child| child.build(data));
 				}
 				let value_slice = value.clone().map(|value| &data[value]);
@@ -177,15 +333,15 @@ impl NodePlan {
 /// An `OwnedNode` is an owned type from which a `Node` can be constructed which borrows data from
 /// the `OwnedNode`. This is useful for trie iterators.
 #[cfg_attr(feature = "std", derive(Debug))]
-#[derive(PartialEq, Eq)]
-pub struct OwnedNode<D: Borrow<[u8]>> {
+#[derive(Clone, PartialEq, Eq)]
+pub struct OwnedNode<D: Borrow<[u8]>, N: NibbleOps = Radix16> {
 	data: D,
-	plan: NodePlan,
+	plan: NodePlan<N>,
 }
 
-impl<D: Borrow<[u8]>> OwnedNode<D> {
+impl<D: Borrow<[u8]>, N: NibbleOps> OwnedNode<D, N> {
 	/// Construct an `OwnedNode` by decoding an owned data source according to some codec.
-	pub fn new<C: NodeCodec>(data: D) -> Result<Self, C::Error> {
+	pub fn new<C: NodeCodec<Nibble = N>>(data: D) -> Result<Self, C::Error> {
 		let plan = C::decode_plan(data.borrow())?;
 		Ok(OwnedNode { data, plan })
 	}
@@ -196,12 +352,27 @@ impl<D: Borrow<[u8]>> OwnedNode<D> {
 	}
 
 	/// Returns a reference to the node decode plan.
-	pub fn node_plan(&self) -> &NodePlan {
+	pub fn node_plan(&self) -> &NodePlan<N> {
 		&self.plan
 	}
 
 	/// Construct a `Node` by borrowing data from this struct.
-	pub fn node(&self) -> Node {
+	pub fn node(&self) -> Node<N> {
 		self.plan.build(self.data.borrow())
 	}
+
+	/// The value of the node in a trie of `L`, inline or the hash of a value node, if any.
+	/// `node_hash` is the hash of the node, or of the closest node above it for inline nodes,
+	/// see `Value::from_slot`.
+	pub fn value<L: TrieLayout>(
+		&self,
+		node_hash: TrieHash<L>,
+	) -> TrieResult<Option<Value<'_>>, TrieHash<L>, CError<L>> {
+		let slot = match self.node() {
+			Node::Leaf(_, value) => Some(value),
+			Node::Branch(_, value) | Node::NibbledBranch(_, _, value) => value,
+			Node::Empty | Node::Extension(..) => None,
+		};
+		slot.map(|slot| Value::from_slot::<L>(slot, node_hash)).transpose()
+	}
 }
@@ -16,6 +16,7 @@
 //! to parametrize the hashes used in the codec.
 
 use crate::MaybeDebug;
+use crate::nibble::NibbleOps;
 use crate::node::{Node, NodePlan};
 use crate::ChildReference;
 
@@ -37,17 +38,37 @@ pub trait NodeCodec: Sized {
 	type HashOut: AsRef<[u8]> + AsMut<[u8]> + Default + MaybeDebug + PartialEq + Eq
 		+ hash::Hash + Send + Sync + Clone + Copy;
 
+	/// Width of the nibbles of the partial keys and radix of the branches of the nodes.
+	type Nibble: NibbleOps;
+
 	/// Get the hashed null node.
 	fn hashed_null_node() -> Self::HashOut;
 
 	/// Decode bytes to a `NodePlan`. Returns `Self::E` on failure.
-	fn decode_plan(data: &[u8]) -> Result<NodePlan, Self::Error>;
+	///
+	/// Implementations must check every range of the plan (including partial key
+	/// lengths) against `data`, so that building a `Node` from the plan cannot
+	/// index out of bounds. Truncated input must be reported as an error.
+	fn decode_plan(data: &[u8]) -> Result<NodePlan<Self::Nibble>, Self::Error>;
 
 	/// Decode bytes to a `Node`. Returns `Self::E` on failure.
-	fn decode(data: &[u8]) -> Result<Node, Self::Error> {
+	fn decode(data: &[u8]) -> Result<Node<Self::Nibble>, Self::Error> {
 		Ok(Self::decode_plan(data)?.build(data))
 	}
 
+	/// Check that a node decoded as `plan` can belong to a trie of a layout with
+	/// (`use_extension`) or without extension nodes, so that nodes written for the other
+	/// layout are reported instead of being silently misread.
+	///
+	/// Called by strict `TrieDB`s on every decoded node. The default implementation
+	/// accepts all nodes.
+	fn check_layout(
+		_plan: &NodePlan<Self::Nibble>,
+		_use_extension: bool,
+	) -> Result<(), Self::Error> {
+		Ok(())
+	}
+
 	/// Check if the provided bytes correspond to the codecs "empty" node.
 	fn is_empty_node(data: &[u8]) -> bool;
 
@@ -82,4 +103,46 @@ pub trait NodeCodec: Sized {
 		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
 		value: Option<&[u8]>
 	) -> Vec<u8>;
+
+	/// Append an encoded leaf node to `output`, as `leaf_node`.
+	///
+	/// The `_into` variants let a single buffer serve the encoding of many nodes. Their default
+	/// implementations append the result of the returning variants: codecs writing to `output`
+	/// directly avoid an allocation per node.
+	fn leaf_node_into(partial: Partial, value: &[u8], output: &mut Vec<u8>) {
+		output.extend_from_slice(&Self::leaf_node(partial, value));
+	}
+
+	/// Append an encoded extension node to `output`, as `extension_node`.
+	fn extension_node_into(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		child_ref: ChildReference<Self::HashOut>,
+		output: &mut Vec<u8>,
+	) {
+		output.extend_from_slice(&Self::extension_node(partial, number_nibble, child_ref));
+	}
+
+	/// Append an encoded branch node to `output`, as `branch_node`.
+	fn branch_node_into(
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		value: Option<&[u8]>,
+		output: &mut Vec<u8>,
+	) {
+		output.extend_from_slice(&Self::branch_node(children, value));
+	}
+
+	/// Append an encoded branch node with a possible partial path to `output`, as
+	/// `branch_node_nibbled`.
+	fn branch_node_nibbled_into(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		value: Option<&[u8]>,
+		output: &mut Vec<u8>,
+	) {
+		output.extend_from_slice(
+			&Self::branch_node_nibbled(partial, number_nibble, children, value)
+		);
+	}
 }
@@ -0,0 +1,43 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sources of the nodes missing from the database of a `TrieDB`, see
+//! `TrieDBBuilder::with_node_provider`.
+
+use hash_db::{Hasher, Prefix};
+use crate::DBValue;
+
+/// Failure of a `NodeProvider` to fetch a node, as opposed to the node being absent.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NodeProviderError;
+
+/// Source of the nodes missing from the database of a `TrieDB`, as the network for a light
+/// client.
+///
+/// Closures returning an `Option<DBValue>` are providers which never fail.
+pub trait NodeProvider<H: Hasher> {
+	/// Fetch the node with hash `hash` at `prefix`. Returns `Ok(None)` if the provider does not
+	/// have the node either, and an error if it could not tell.
+	fn provide(&self, hash: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, NodeProviderError>;
+}
+
+impl<H, F> NodeProvider<H> for F
+	where
+		H: Hasher,
+		F: Fn(&H::Out, Prefix) -> Option<DBValue>,
+{
+	fn provide(&self, hash: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, NodeProviderError> {
+		Ok(self(hash, prefix))
+	}
+}
@@ -0,0 +1,327 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Iterators owning their database.
+//!
+//! `TrieDBIterator` and `TrieDBNodeIterator` borrow a `TrieDB`, itself borrowing the
+//! database, so they cannot be returned from the function building the `TrieDB`. The
+//! iterators of this module own the database and the root instead, and build a `TrieDB`
+//! view for each step.
+
+use hash_db::{HashDBRef, EMPTY_PREFIX};
+use crate::iterator::{BorrowedNodeItem, NodeIteratorState, SharedNode, TrieIteratorState};
+use crate::nibble::NibbleVec;
+use crate::rstd::boxed::Box;
+use crate::triedb::value_item;
+use crate::{CError, DBValue, Result, TrieDB, TrieError, TrieHash, TrieIterator, TrieLayout};
+
+/// Node iterator owning its database, yielding the same items as `TrieDBNodeIterator`.
+pub struct OwnedTrieDBNodeIterator<L: TrieLayout, DB> {
+	db: DB,
+	root: TrieHash<L>,
+	state: NodeIteratorState<L>,
+}
+
+impl<L, DB> OwnedTrieDBNodeIterator<L, DB>
+	where
+		L: TrieLayout,
+		DB: HashDBRef<L::Hash, DBValue>,
+{
+	/// Create a new iterator over the trie at `root` in `db`.
+	/// Returns an error if `root` does not exist.
+	pub fn new(db: DB, root: TrieHash<L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		if !db.contains(&root, EMPTY_PREFIX) {
+			return Err(Box::new(TrieError::InvalidStateRoot(root)));
+		}
+		let state = NodeIteratorState::new(&TrieDB::new_unchecked(&db, &root))?;
+		Ok(OwnedTrieDBNodeIterator { db, root, state })
+	}
+
+	/// Resume the iteration saved in `state` over the trie at `root` in `db`, see
+	/// `TrieDBNodeIterator::restore`.
+	pub fn restore(
+		db: DB,
+		root: TrieHash<L>,
+		state: &TrieIteratorState<TrieHash<L>, L::Nibble>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		let state = NodeIteratorState::restore(&TrieDB::new_unchecked(&db, &root), state)?;
+		Ok(OwnedTrieDBNodeIterator { db, root, state })
+	}
+
+	/// Create a new iterator, limited to the nodes under `prefix`.
+	pub fn new_prefixed(
+		db: DB,
+		root: TrieHash<L>,
+		prefix: &[u8],
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		let mut iter = Self::new(db, root)?;
+		iter.state.prefix(&TrieDB::new_unchecked(&iter.db, &iter.root), prefix)?;
+		Ok(iter)
+	}
+
+	/// The root of the iterated trie.
+	pub fn root(&self) -> &TrieHash<L> {
+		&self.root
+	}
+
+	/// The backing database.
+	pub fn db(&self) -> &DB {
+		&self.db
+	}
+
+	/// Consume the iterator, returning the backing database.
+	pub fn into_db(self) -> DB {
+		self.db
+	}
+
+	/// Save the position of the iterator, to resume the iteration with `restore`.
+	pub fn save(&self) -> TrieIteratorState<TrieHash<L>, L::Nibble> {
+		self.state.save(self.root)
+	}
+
+	/// Estimate of the part of the key space already iterated, see
+	/// `TrieDBNodeIterator::progress`.
+	pub fn progress(&self) -> f64 {
+		self.state.progress()
+	}
+
+	/// Same as `next`, but borrows the node prefix instead of cloning it, see
+	/// `TrieDBNodeIterator::next_node`.
+	pub fn next_node(&mut self) -> Option<BorrowedNodeItem<'_, L>> {
+		self.state.next_node(&TrieDB::new_unchecked(&self.db, &self.root))
+	}
+}
+
+impl<L, DB> TrieIterator<L> for OwnedTrieDBNodeIterator<L, DB>
+	where
+		L: TrieLayout,
+		DB: HashDBRef<L::Hash, DBValue>,
+{
+	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.state.seek(&TrieDB::new_unchecked(&self.db, &self.root), key)
+	}
+
+	fn seek_after(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.state.seek_after(&TrieDB::new_unchecked(&self.db, &self.root), key)
+	}
+}
+
+impl<L, DB> Iterator for OwnedTrieDBNodeIterator<L, DB>
+	where
+		L: TrieLayout,
+		DB: HashDBRef<L::Hash, DBValue>,
+{
+	type Item = Result<
+		(NibbleVec<L::Nibble>, Option<TrieHash<L>>, SharedNode<L::Nibble>),
+		TrieHash<L>,
+		CError<L>,
+	>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.next_node()
+			.map(|item| item.map(|(prefix, hash, node)| (prefix.clone(), hash, node)))
+	}
+}
+
+/// Key-value iterator owning its database, yielding the same items as `TrieDBIterator`.
+pub struct OwnedTrieDBIterator<L: TrieLayout, DB> {
+	inner: OwnedTrieDBNodeIterator<L, DB>,
+}
+
+impl<L, DB> OwnedTrieDBIterator<L, DB>
+	where
+		L: TrieLayout,
+		DB: HashDBRef<L::Hash, DBValue>,
+{
+	/// Create a new iterator over the trie at `root` in `db`.
+	/// Returns an error if `root` does not exist.
+	pub fn new(db: DB, root: TrieHash<L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		Ok(OwnedTrieDBIterator { inner: OwnedTrieDBNodeIterator::new(db, root)? })
+	}
+
+	/// Resume the iteration saved in `state` over the trie at `root` in `db`, see
+	/// `TrieDBNodeIterator::restore`.
+	pub fn restore(
+		db: DB,
+		root: TrieHash<L>,
+		state: &TrieIteratorState<TrieHash<L>, L::Nibble>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		Ok(OwnedTrieDBIterator { inner: OwnedTrieDBNodeIterator::restore(db, root, state)? })
+	}
+
+	/// Create a new iterator, limited to the keys starting with `prefix`.
+	pub fn new_prefixed(
+		db: DB,
+		root: TrieHash<L>,
+		prefix: &[u8],
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		Ok(OwnedTrieDBIterator { inner: OwnedTrieDBNodeIterator::new_prefixed(db, root, prefix)? })
+	}
+
+	/// The root of the iterated trie.
+	pub fn root(&self) -> &TrieHash<L> {
+		self.inner.root()
+	}
+
+	/// The backing database.
+	pub fn db(&self) -> &DB {
+		self.inner.db()
+	}
+
+	/// Consume the iterator, returning the backing database.
+	pub fn into_db(self) -> DB {
+		self.inner.into_db()
+	}
+
+	/// Save the position of the iterator, to resume the iteration with `restore`.
+	pub fn save(&self) -> TrieIteratorState<TrieHash<L>, L::Nibble> {
+		self.inner.save()
+	}
+
+	/// Estimate of the part of the key space already iterated, see
+	/// `TrieDBNodeIterator::progress`.
+	pub fn progress(&self) -> f64 {
+		self.inner.progress()
+	}
+}
+
+impl<L, DB> TrieIterator<L> for OwnedTrieDBIterator<L, DB>
+	where
+		L: TrieLayout,
+		DB: HashDBRef<L::Hash, DBValue>,
+{
+	/// Position the iterator on the first element with key >= `key`
+	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.inner.seek(key)
+	}
+
+	/// Position the iterator on the first element with key > `key`
+	fn seek_after(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.inner.seek_after(key)
+	}
+}
+
+impl<L, DB> Iterator for OwnedTrieDBIterator<L, DB>
+	where
+		L: TrieLayout,
+		DB: HashDBRef<L::Hash, DBValue>,
+{
+	type Item = Result<(crate::rstd::vec::Vec<u8>, DBValue), TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let inner = &mut self.inner;
+		let trie = TrieDB::new_unchecked(&inner.db, &inner.root);
+		while let Some(item) = inner.state.next_node(&trie) {
+			match item {
+				Ok((prefix, hash, node)) =>
+					if let Some(item) = value_item(&trie, prefix, hash, &node) {
+						return Some(item);
+					},
+				Err(err) => return Some(Err(err)),
+			}
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use keccak_hasher::KeccakHasher;
+	use reference_trie::{
+		ExtensionLayout, OwnedTrieDBIterator, OwnedTrieDBNodeIterator, RefTrieDB, RefTrieDBMut,
+		Trie, TrieError, TrieIterator, TrieMut,
+	};
+	use crate::DBValue;
+
+	type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+	type Hash = <KeccakHasher as hash_db::Hasher>::Out;
+
+	fn entries() -> Vec<(&'static [u8], &'static [u8])> {
+		vec![
+			(b"do", b"verb"),
+			(b"dog", b"puppy"),
+			(b"doge", &[0; 32]),
+			(b"horse", b"stallion"),
+			(b"house", b"building"),
+		]
+	}
+
+	fn build_trie() -> (MemoryDB, Hash) {
+		let mut db = MemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			for (key, value) in entries() {
+				t.insert(key, value).unwrap();
+			}
+		}
+		(db, root)
+	}
+
+	/// The database and the trie view only live in this function.
+	fn pairs_with_prefix(prefix: &[u8]) -> impl Iterator<Item = (Vec<u8>, DBValue)> {
+		let (db, root) = build_trie();
+		OwnedTrieDBIterator::<ExtensionLayout, _>::new_prefixed(db, root, prefix)
+			.unwrap()
+			.map(|item| item.unwrap())
+	}
+
+	#[test]
+	fn owned_iterator_can_be_returned() {
+		let pairs: Vec<_> = pairs_with_prefix(b"do").collect();
+		let expected: Vec<_> = entries().into_iter()
+			.filter(|(key, _)| key.starts_with(b"do"))
+			.map(|(key, value)| (key.to_vec(), value.to_vec()))
+			.collect();
+		assert_eq!(pairs, expected);
+	}
+
+	#[test]
+	fn owned_iterators_match_borrowed_ones() {
+		let (db, root) = build_trie();
+		let (pairs, nodes, seeked) = {
+			let trie = RefTrieDB::new(&db, &root).unwrap();
+			let pairs: Vec<_> = trie.iter().unwrap().map(|item| item.unwrap()).collect();
+			let nodes: Vec<_> = reference_trie::TrieDBNodeIterator::new(&trie).unwrap()
+				.map(|item| item.unwrap())
+				.collect();
+			let mut iter = trie.iter().unwrap();
+			iter.seek(b"dog").unwrap();
+			let seeked: Vec<_> = iter.map(|item| item.unwrap()).collect();
+			(pairs, nodes, seeked)
+		};
+
+		let iter = OwnedTrieDBIterator::<ExtensionLayout, _>::new(db, root).unwrap();
+		assert_eq!(iter.root(), &root);
+		assert_eq!(iter.collect::<Vec<_>>(), pairs.into_iter().map(Ok).collect::<Vec<_>>());
+
+		let (db, root) = build_trie();
+		let iter = OwnedTrieDBNodeIterator::<ExtensionLayout, _>::new(db, root).unwrap();
+		assert_eq!(iter.map(|item| item.unwrap()).collect::<Vec<_>>(), nodes);
+
+		let (db, root) = build_trie();
+		let mut iter = OwnedTrieDBIterator::<ExtensionLayout, _>::new(db, root).unwrap();
+		iter.seek(b"dog").unwrap();
+		assert_eq!(iter.map(|item| item.unwrap()).collect::<Vec<_>>(), seeked);
+	}
+
+	#[test]
+	fn owned_iterator_checks_root() {
+		let root = Hash::default();
+		match OwnedTrieDBIterator::<ExtensionLayout, _>::new(MemoryDB::default(), root) {
+			Err(e) => assert_eq!(*e, TrieError::InvalidStateRoot(root)),
+			Ok(_) => panic!("missing root should be reported"),
+		}
+	}
+}
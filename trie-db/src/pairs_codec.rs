@@ -0,0 +1,88 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming SCALE encoding of the key-value pairs under a prefix.
+//!
+//! The number of pairs is not known before walking the trie, so pairs are written as a
+//! sequence of chunks. Each chunk is a SCALE encoded `Vec<(Vec<u8>, Vec<u8>)>` of at most
+//! `PAIRS_CHUNK_SIZE` pairs, and an empty chunk ends the stream. Only one chunk is held in
+//! memory at a time.
+
+use crate::rstd::vec::Vec;
+use crate::{CError, DBValue, Result, TrieDB, TrieDBIterator, TrieHash, TrieIterator, TrieLayout};
+use codec::{Decode, Encode, Error, Input, Output};
+
+/// Maximum number of pairs in a chunk of the stream.
+pub const PAIRS_CHUNK_SIZE: usize = 64;
+
+/// Write the key-value pairs of `db` with keys starting with `prefix` to `out`, starting at the
+/// first key greater or equal to `start_at`.
+///
+/// At most `limit` pairs are written. When the limit is hit while more pairs remain, the key of
+/// the next pair is returned so the caller can resume from it with `start_at`. Returns the
+/// number of pairs written with this continuation key.
+///
+/// On error, `out` holds an incomplete stream.
+pub fn encode_pairs_to<L: TrieLayout, W: Output>(
+	db: &TrieDB<L>,
+	prefix: &[u8],
+	start_at: Option<&[u8]>,
+	out: &mut W,
+	limit: Option<usize>,
+) -> Result<(u32, Option<Vec<u8>>), TrieHash<L>, CError<L>> {
+	let mut iter = TrieDBIterator::new(db)?;
+	match start_at {
+		Some(start) if start > prefix => iter.seek(start)?,
+		_ => iter.seek(prefix)?,
+	}
+
+	let mut count = 0u32;
+	let mut chunk: Vec<(Vec<u8>, DBValue)> = Vec::with_capacity(PAIRS_CHUNK_SIZE);
+	let mut continuation = None;
+	for item in iter {
+		let (key, value) = item?;
+		if !key.starts_with(prefix) {
+			break;
+		}
+		if Some(count as usize) == limit {
+			continuation = Some(key);
+			break;
+		}
+		chunk.push((key, value));
+		count += 1;
+		if chunk.len() == PAIRS_CHUNK_SIZE {
+			chunk.encode_to(out);
+			chunk.clear();
+		}
+	}
+	if !chunk.is_empty() {
+		chunk.encode_to(out);
+		chunk.clear();
+	}
+	// Empty chunk terminating the stream.
+	chunk.encode_to(out);
+	Ok((count, continuation))
+}
+
+/// Decode a stream written by `encode_pairs_to`.
+pub fn decode_pairs<I: Input>(input: &mut I) -> crate::rstd::result::Result<Vec<(Vec<u8>, DBValue)>, Error> {
+	let mut pairs = Vec::new();
+	loop {
+		let chunk = <Vec<(Vec<u8>, DBValue)>>::decode(input)?;
+		if chunk.is_empty() {
+			return Ok(pairs);
+		}
+		pairs.extend(chunk);
+	}
+}
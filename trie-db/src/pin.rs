@@ -0,0 +1,242 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pinned tries, keeping the nodes of a root alive while they are read.
+//!
+//! Nodes are content-addressed, so the nodes of a root stay valid while new roots are
+//! committed to the same database, until the old nodes are pruned. A `PinnedTrie` registers
+//! its root with a `PruningGuard` for as long as it, or an iterator built from it, is alive,
+//! and pruning code (such as `TrieDBMut::commit`) consults the guard before deleting nodes.
+
+use core::cell::RefCell;
+use hash_db::Prefix;
+use hashbrown::{HashMap, HashSet};
+use crate::rstd::vec::Vec;
+use crate::{
+	CError, Result, Trie, TrieDB, TrieDBIterator, TrieDBNodeIterator, TrieHash, TrieIterator,
+	TrieLayout,
+};
+
+/// Registry of pinned roots, consulted before deleting trie nodes.
+pub trait PruningGuard<H> {
+	/// Register a pin on `root`. `nodes` yields the hashes of the nodes reachable from `root`,
+	/// and need only be read when `root` is not pinned yet.
+	fn pin(&self, root: &H, nodes: &mut dyn Iterator<Item = H>);
+
+	/// Release a pin previously registered with `pin`.
+	fn unpin(&self, root: &H);
+
+	/// Whether the node with the given hash and prefix may be deleted from the database.
+	fn can_prune(&self, hash: &H, prefix: Prefix) -> bool;
+}
+
+/// A `PruningGuard` counting the pins of each root, and for each node the pinned roots
+/// reaching it.
+///
+/// The nodes reachable from a root are read when it is first pinned. A node may be pruned
+/// unless a pinned root reaches a node with its hash, whatever its prefix.
+pub struct PinnedRoots<H> {
+	/// Pins of each pinned root, with the nodes it reaches.
+	pins: RefCell<HashMap<H, (usize, Vec<H>)>>,
+	/// Number of pinned roots reaching each node.
+	nodes: RefCell<HashMap<H, usize>>,
+}
+
+impl<H: Eq + core::hash::Hash> Default for PinnedRoots<H> {
+	fn default() -> Self {
+		PinnedRoots { pins: RefCell::new(HashMap::new()), nodes: RefCell::new(HashMap::new()) }
+	}
+}
+
+impl<H: Eq + core::hash::Hash> PinnedRoots<H> {
+	/// Is `root` currently pinned?
+	pub fn is_pinned(&self, root: &H) -> bool {
+		self.pins.borrow().contains_key(root)
+	}
+
+	/// Is any root currently pinned?
+	pub fn has_pins(&self) -> bool {
+		!self.pins.borrow().is_empty()
+	}
+}
+
+impl<H: Eq + core::hash::Hash + Clone> PruningGuard<H> for PinnedRoots<H> {
+	fn pin(&self, root: &H, nodes: &mut dyn Iterator<Item = H>) {
+		let mut pins = self.pins.borrow_mut();
+		let (count, reached) = pins.entry(root.clone()).or_insert_with(|| (0, Vec::new()));
+		if *count == 0 {
+			let mut counts = self.nodes.borrow_mut();
+			// a root may reach the same node at several prefixes.
+			let mut seen = HashSet::new();
+			reached.extend(nodes.filter(|hash| seen.insert(hash.clone())));
+			for hash in reached.iter() {
+				*counts.entry(hash.clone()).or_insert(0) += 1;
+			}
+		}
+		*count += 1;
+	}
+
+	fn unpin(&self, root: &H) {
+		let mut pins = self.pins.borrow_mut();
+		let released = match pins.get_mut(root) {
+			Some((count, _)) => {
+				*count -= 1;
+				*count == 0
+			},
+			None => false,
+		};
+		if !released {
+			return;
+		}
+		let mut counts = self.nodes.borrow_mut();
+		for hash in pins.remove(root).map(|(_, reached)| reached).unwrap_or_default() {
+			let unreached = match counts.get_mut(&hash) {
+				Some(count) => {
+					*count -= 1;
+					*count == 0
+				},
+				None => false,
+			};
+			if unreached {
+				counts.remove(&hash);
+			}
+		}
+	}
+
+	fn can_prune(&self, hash: &H, _prefix: Prefix) -> bool {
+		!self.nodes.borrow().contains_key(hash)
+	}
+}
+
+/// A pin on a root, released on drop.
+struct Pin<'a, H> {
+	guard: Option<&'a dyn PruningGuard<H>>,
+	root: H,
+}
+
+impl<'a, H: Copy> Pin<'a, H> {
+	/// Pin `root`, reaching the nodes yielded by `nodes`, with `guard`.
+	fn new(
+		guard: Option<&'a dyn PruningGuard<H>>,
+		root: H,
+		nodes: &mut dyn Iterator<Item = H>,
+	) -> Self {
+		if let Some(guard) = guard {
+			guard.pin(&root, nodes);
+		}
+		Pin { guard, root }
+	}
+
+	fn clone_pin(&self) -> Self {
+		// the root is pinned by `self`, its nodes are known to the guard.
+		Pin::new(self.guard, self.root, &mut core::iter::empty())
+	}
+}
+
+impl<'a, H> Drop for Pin<'a, H> {
+	fn drop(&mut self) {
+		if let Some(guard) = self.guard {
+			guard.unpin(&self.root);
+		}
+	}
+}
+
+/// A trie whose root is registered with a `PruningGuard` for as long as it lives.
+///
+/// Iterators created from it hold their own pin, so they can outlive it.
+/// Created with `TrieDB::pin`.
+pub struct PinnedTrie<'a, 'db, L: TrieLayout> {
+	trie: &'a TrieDB<'db, L>,
+	pin: Pin<'a, TrieHash<L>>,
+}
+
+impl<'a, 'db, L: TrieLayout> PinnedTrie<'a, 'db, L> {
+	pub(crate) fn new(
+		trie: &'a TrieDB<'db, L>,
+		guard: Option<&'a dyn PruningGuard<TrieHash<L>>>,
+	) -> Self {
+		let pin = match guard {
+			// the nodes missing from the database are not reached.
+			Some(_) => match TrieDBNodeIterator::new(trie) {
+				Ok(iter) => {
+					let mut nodes = iter.filter_map(|item| item.ok().and_then(|(_, hash, _)| hash));
+					Pin::new(guard, *trie.root(), &mut nodes)
+				},
+				Err(_) => Pin::new(guard, *trie.root(), &mut core::iter::empty()),
+			},
+			None => Pin::new(None, *trie.root(), &mut core::iter::empty()),
+		};
+		PinnedTrie { trie, pin }
+	}
+
+	/// The pinned root.
+	pub fn root(&self) -> &TrieHash<L> {
+		&self.pin.root
+	}
+
+	/// The pinned trie.
+	pub fn trie(&self) -> &'a TrieDB<'db, L> {
+		self.trie
+	}
+
+	/// Iterator over the key-value pairs of the pinned trie, holding a pin.
+	pub fn iter(&self) -> Result<PinnedTrieIterator<'a, L>, TrieHash<L>, CError<L>> {
+		Ok(PinnedIterator {
+			inner: TrieDBIterator::new(self.trie)?,
+			_pin: self.pin.clone_pin(),
+		})
+	}
+
+	/// Iterator over the nodes of the pinned trie, holding a pin.
+	pub fn node_iter(&self) -> Result<PinnedNodeIterator<'a, L>, TrieHash<L>, CError<L>> {
+		Ok(PinnedIterator {
+			inner: TrieDBNodeIterator::new(self.trie)?,
+			_pin: self.pin.clone_pin(),
+		})
+	}
+}
+
+/// An iterator over a pinned trie, keeping the root pinned until dropped.
+pub struct PinnedIterator<'a, H, I> {
+	inner: I,
+	_pin: Pin<'a, H>,
+}
+
+/// Key-value iterator over a pinned trie.
+pub type PinnedTrieIterator<'a, L> = PinnedIterator<'a, TrieHash<L>, TrieDBIterator<'a, L>>;
+
+/// Node iterator over a pinned trie.
+pub type PinnedNodeIterator<'a, L> = PinnedIterator<'a, TrieHash<L>, TrieDBNodeIterator<'a, L>>;
+
+impl<'a, L, I> TrieIterator<L> for PinnedIterator<'a, TrieHash<L>, I>
+	where
+		L: TrieLayout,
+		I: TrieIterator<L>,
+{
+	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.inner.seek(key)
+	}
+
+	fn seek_after(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.inner.seek_after(key)
+	}
+}
+
+impl<'a, H, I: Iterator> Iterator for PinnedIterator<'a, H, I> {
+	type Item = I::Item;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.inner.next()
+	}
+}
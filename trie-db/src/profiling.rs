@@ -0,0 +1,63 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Thread-local counters of the work done by the tries, enabled by the `trie-profiling`
+//! feature.
+//!
+//! The counters are incremented by `Lookup`, `TrieDB`, `TrieDBMut` and the iterators of
+//! the current thread, and read with `take_and_reset`. Without the feature this module
+//! does not exist and the increments are compiled out.
+
+use std::cell::RefCell;
+
+/// Values of the profiling counters.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ProfileSnapshot {
+	/// Number of key lookups.
+	pub lookups: u64,
+	/// Number of nodes decoded, inline nodes included.
+	pub nodes_decoded: u64,
+	/// Number of nodes fetched from the database.
+	pub nodes_fetched: u64,
+	/// Total size of the nodes fetched from the database.
+	pub bytes_fetched: u64,
+	/// Number of nodes reused without being fetched or decoded again: nodes already in the
+	/// storage of a `TrieDBMut`, and inline nodes already decoded by an iterator.
+	pub cache_hits: u64,
+	/// Number of nodes loaded in the storage of a `TrieDBMut`, and inline nodes decoded by
+	/// an iterator for reuse.
+	pub cache_misses: u64,
+	/// Number of nodes yielded by the node iterators, key-value iterators included.
+	pub iterator_steps: u64,
+}
+
+thread_local! {
+	static COUNTERS: RefCell<ProfileSnapshot> = RefCell::new(ProfileSnapshot::default());
+}
+
+/// Update the counters of the current thread.
+pub(crate) fn record<F: FnOnce(&mut ProfileSnapshot)>(f: F) {
+	COUNTERS.with(|counters| f(&mut counters.borrow_mut()))
+}
+
+/// Values of the counters of the current thread.
+pub fn snapshot() -> ProfileSnapshot {
+	COUNTERS.with(|counters| *counters.borrow())
+}
+
+/// Values of the counters of the current thread, resetting them to zero.
+pub fn take_and_reset() -> ProfileSnapshot {
+	COUNTERS.with(|counters| counters.replace(ProfileSnapshot::default()))
+}
+
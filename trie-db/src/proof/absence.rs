@@ -0,0 +1,222 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proofs that a key is absent from a trie, with where its path leaves the trie.
+//!
+//! The proof holds the nodes on the path to the key as stored, from the root down to the node
+//! the path leaves the trie at, and the kind of divergence found there. The verifier walks the
+//! nodes from the root, checking each against the hash its parent references, and finds the
+//! divergence again: no database is built.
+
+use hash_db::{Hasher, EMPTY_PREFIX};
+use crate::node::{decode_hash, Node, NodeHandle};
+use crate::nibble::{Children, NibbleOps, NibbleSlice, NibbleVec, Radix16};
+use crate::rstd::{boxed::Box, vec::Vec};
+use crate::{CError, NodeCodec, Recorder, Result as TrieResult, Trie, TrieDB, TrieError, TrieHash,
+	TrieLayout};
+use super::VerifyError;
+
+/// Where the path to an absent key leaves the trie, see `AbsenceProof`.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Clone, PartialEq, Eq)]
+pub enum Divergence<N: NibbleOps = Radix16> {
+	/// The trie is empty.
+	EmptyTrie,
+	/// The path ends at a leaf with another partial key than the rest of the key, as when the
+	/// key is a strict prefix of the key of the leaf.
+	LeafMismatch {
+		/// The partial key of the leaf.
+		partial: NibbleVec<N>,
+	},
+	/// The rest of the key does not start with the partial key of an extension or of a branch.
+	PartialMismatch {
+		/// The partial key of the node.
+		partial: NibbleVec<N>,
+	},
+	/// The branch on the path has no child at the next nibble of the key.
+	MissingChild {
+		/// The nibble of the empty child slot.
+		index: u8,
+	},
+	/// The key ends at a branch without a value.
+	NoValue,
+}
+
+/// A proof that a key is absent from a trie, see `TrieDB::prove_absence`.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Clone, PartialEq, Eq)]
+pub struct AbsenceProof<N: NibbleOps = Radix16> {
+	/// The nodes fetched by hash on the path to the key, from the root, as stored.
+	pub nodes: Vec<Vec<u8>>,
+	/// Where the path to the key leaves the trie.
+	pub divergence: Divergence<N>,
+}
+
+impl<N: NibbleOps> AbsenceProof<N> {
+	/// Prove that `key` is absent from `trie`, returning `None` if it is present.
+	pub(crate) fn generate<L: TrieLayout<Nibble = N>>(
+		trie: &TrieDB<L>,
+		key: &[u8],
+	) -> TrieResult<Option<Self>, TrieHash<L>, CError<L>> {
+		let mut recorder = Recorder::new();
+		if trie.get_with(key, &mut recorder)?.is_some() {
+			return Ok(None);
+		}
+		let nodes: Vec<_> = recorder.drain().into_iter().map(|record| record.data).collect();
+		match find_divergence::<L>(trie.root(), &nodes, key) {
+			Ok(divergence) => Ok(Some(AbsenceProof { nodes, divergence })),
+			// The nodes read do not hash to the references to them.
+			Err(_) => Err(Box::new(TrieError::incomplete_database(*trie.root(), EMPTY_PREFIX))),
+		}
+	}
+
+	/// Verify that `key` is absent from the trie with root `root`.
+	///
+	/// The first node must hash to `root` and each other node to the reference of the node
+	/// above it, the path of the key leaving the trie at the last node as `divergence` tells.
+	/// Returns `VerifyError::ValueMismatch` if the nodes hold a value at `key`,
+	/// `VerifyError::IncompleteProof` if the path continues past the nodes and
+	/// `VerifyError::ExtraneousNode` if nodes are left once the path left the trie.
+	pub fn verify<L: TrieLayout<Nibble = N>>(
+		&self,
+		root: &TrieHash<L>,
+		key: &[u8],
+	) -> Result<(), VerifyError<TrieHash<L>, CError<L>>> {
+		let divergence = find_divergence::<L>(root, &self.nodes, key)?;
+		if divergence != self.divergence {
+			return Err(VerifyError::DivergenceMismatch);
+		}
+		Ok(())
+	}
+}
+
+/// A step of the walk of the path of a key.
+enum Step<'a, N: NibbleOps> {
+	/// The path ends at the value slot of the key.
+	Value(&'a [u8]),
+	/// The path leaves the trie.
+	Diverged(Divergence<N>),
+	/// The path goes on to a child.
+	Child(NodeHandle<'a>),
+}
+
+/// Where the path of a key through the nodes of a proof ends.
+pub(super) enum PathEnd<'a, N: NibbleOps> {
+	/// At the value slot of the key, after walking `nodes` nodes.
+	Value {
+		/// The value slot, see `node::Value::from_slot`.
+		slot: &'a [u8],
+		/// Number of nodes walked, the node holding the slot included.
+		nodes: usize,
+	},
+	/// The path leaves the trie at the last node.
+	Diverged(Divergence<N>),
+}
+
+/// Walk the path of `key` through `nodes`, returning where it leaves the trie.
+fn find_divergence<L: TrieLayout>(
+	root: &TrieHash<L>,
+	nodes: &[Vec<u8>],
+	key: &[u8],
+) -> Result<Divergence<L::Nibble>, VerifyError<TrieHash<L>, CError<L>>> {
+	match walk_path::<L>(root, nodes, key)? {
+		PathEnd::Value { .. } => Err(VerifyError::ValueMismatch(key.to_vec())),
+		PathEnd::Diverged(divergence) => Ok(divergence),
+	}
+}
+
+/// Walk the path of `key` through `nodes`, checking each node against the hash its parent
+/// references, until the value slot of the key or the node the path leaves the trie at.
+pub(super) fn walk_path<'a, L: TrieLayout>(
+	root: &TrieHash<L>,
+	nodes: &'a [Vec<u8>],
+	key_bytes: &[u8],
+) -> Result<PathEnd<'a, L::Nibble>, VerifyError<TrieHash<L>, CError<L>>> {
+	let key = NibbleSlice::new(key_bytes);
+	let mut offset = 0;
+	let mut hash = *root;
+	for (depth, encoded) in nodes.iter().enumerate() {
+		let found = L::Hash::hash(encoded);
+		if found != hash {
+			return Err(match depth {
+				0 => VerifyError::RootMismatch(found),
+				_ => VerifyError::IncompleteProof,
+			});
+		}
+		// the inline children of the node are walked before moving to the next node.
+		let mut data = &encoded[..];
+		loop {
+			let rest = key.mid(offset);
+			let step = match L::Codec::decode(data).map_err(VerifyError::DecodeError)? {
+				Node::Empty => Step::Diverged(Divergence::EmptyTrie),
+				Node::Leaf(partial, value) if partial == rest => Step::Value(value),
+				Node::Leaf(partial, _) => Step::Diverged(Divergence::LeafMismatch {
+					partial: partial.into(),
+				}),
+				Node::Extension(partial, child) if rest.starts_with(&partial) => {
+					offset += partial.len();
+					Step::Child(child)
+				},
+				Node::Extension(partial, _) => Step::Diverged(Divergence::PartialMismatch {
+					partial: partial.into(),
+				}),
+				Node::Branch(children, value) =>
+					branch_step(rest, &mut offset, None, children, value),
+				Node::NibbledBranch(partial, children, value) =>
+					branch_step(rest, &mut offset, Some(partial), children, value),
+			};
+			match step {
+				Step::Value(slot) => return Ok(PathEnd::Value { slot, nodes: depth + 1 }),
+				Step::Diverged(_) if depth + 1 < nodes.len() =>
+					return Err(VerifyError::ExtraneousNode),
+				Step::Diverged(divergence) => return Ok(PathEnd::Diverged(divergence)),
+				Step::Child(NodeHandle::Inline(child)) => data = child,
+				Step::Child(NodeHandle::Hash(child)) => {
+					hash = decode_hash::<L::Hash>(child)
+						.ok_or_else(|| VerifyError::InvalidChildReference(child.to_vec()))?;
+					break;
+				},
+			}
+		}
+	}
+	Err(VerifyError::IncompleteProof)
+}
+
+/// The step of the path of a key at a branch, `rest` being the rest of the key.
+fn branch_step<'a, N: NibbleOps>(
+	rest: NibbleSlice<N>,
+	offset: &mut usize,
+	partial: Option<NibbleSlice<N>>,
+	children: Children<NodeHandle<'a>, N>,
+	value: Option<&'a [u8]>,
+) -> Step<'a, N> {
+	let partial_len = match partial {
+		Some(partial) if !rest.starts_with(&partial) =>
+			return Step::Diverged(Divergence::PartialMismatch { partial: partial.into() }),
+		Some(partial) => partial.len(),
+		None => 0,
+	};
+	let index = match (rest.try_at(partial_len), value) {
+		(Some(index), _) => index,
+		(None, Some(value)) => return Step::Value(value),
+		(None, None) => return Step::Diverged(Divergence::NoValue),
+	};
+	match children[index as usize] {
+		Some(child) => {
+			*offset += partial_len + 1;
+			Step::Child(child)
+		},
+		None => Step::Diverged(Divergence::MissingChild { index }),
+	}
+}
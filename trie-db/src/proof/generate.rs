@@ -21,15 +21,17 @@ use crate::rstd::{
 use hash_db::Hasher;
 
 use crate::{
-	CError, ChildReference, nibble::LeftNibbleSlice, nibble_ops::NIBBLE_LENGTH, NibbleSlice, node::{NodeHandle, NodeHandlePlan, NodePlan, OwnedNode}, NodeCodec, Recorder,
+	CError, ChildReference, nibble::{Children, LeftNibbleSlice, NibbleOps}, NibbleSlice,
+	node::{value_node_hash, value_slot, NodeHandle, NodeHandlePlan, NodePlan, OwnedNode},
+	NodeCodec, Recorder,
 	Result as TrieResult, Trie, TrieError, TrieHash,
 	TrieLayout,
 };
 
 struct StackEntry<'a, C: NodeCodec> {
 	/// The prefix is the nibble path to the node in the trie.
-	prefix: LeftNibbleSlice<'a>,
-	node: OwnedNode<Vec<u8>>,
+	prefix: LeftNibbleSlice<'a, C::Nibble>,
+	node: OwnedNode<Vec<u8>, C::Nibble>,
 	/// The hash of the node or None if it is referenced inline.
 	node_hash: Option<C::HashOut>,
 	/// Whether the value should be omitted in the generated proof.
@@ -46,7 +48,7 @@ struct StackEntry<'a, C: NodeCodec> {
 
 impl<'a, C: NodeCodec> StackEntry<'a, C> {
 	fn new(
-		prefix: LeftNibbleSlice<'a>,
+		prefix: LeftNibbleSlice<'a, C::Nibble>,
 		node_data: Vec<u8>,
 		node_hash: Option<C::HashOut>,
 		output_index: Option<usize>,
@@ -59,7 +61,7 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 		let children_len = match node.node_plan() {
 			NodePlan::Empty | NodePlan::Leaf { .. } => 0,
 			NodePlan::Extension { .. } => 1,
-			NodePlan::Branch { .. } | NodePlan::NibbledBranch { .. } => NIBBLE_LENGTH,
+			NodePlan::Branch { .. } | NodePlan::NibbledBranch { .. } => C::Nibble::NIBBLE_LENGTH,
 		};
 		Ok(StackEntry {
 			prefix,
@@ -74,16 +76,21 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 	}
 
 	/// Encode this entry to an encoded trie node with data properly omitted.
+	///
+	/// The proof node is at most as long as the node, data being only stripped: its buffer is
+	/// allocated once.
 	fn encode_node(mut self) -> TrieResult<Vec<u8>, C::HashOut, C::Error> {
 		let node_data = self.node.data();
-		Ok(match self.node.node_plan() {
-			NodePlan::Empty => node_data.to_vec(),
-			NodePlan::Leaf { .. } if !self.omit_value => node_data.to_vec(),
+		let mut output = Vec::with_capacity(node_data.len());
+		match self.node.node_plan() {
+			NodePlan::Empty => output.extend_from_slice(node_data),
+			NodePlan::Leaf { .. } if !self.omit_value => output.extend_from_slice(node_data),
 			NodePlan::Leaf { partial, value: _ } => {
 				let partial = partial.build(node_data);
-				C::leaf_node(partial.right(), &[])
+				C::leaf_node_into(partial.right(), &[], &mut output)
 			}
-			NodePlan::Extension { .. } if self.child_index == 0 => node_data.to_vec(),
+			NodePlan::Extension { .. } if self.child_index == 0 =>
+				output.extend_from_slice(node_data),
 			NodePlan::Extension { partial: partial_plan, child: _ } => {
 				let partial = partial_plan.build(node_data);
 				let child = self.children[0]
@@ -92,10 +99,11 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 						child_index > 0; \
 						the branch guard guarantees that child_index > 0"
 					);
-				C::extension_node(
+				C::extension_node_into(
 					partial.right_iter(),
 					partial.len(),
-					child
+					child,
+					&mut output,
 				)
 			}
 			NodePlan::Branch { value, children } => {
@@ -105,9 +113,10 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 					self.child_index,
 					&mut self.children
 				)?;
-				C::branch_node(
+				C::branch_node_into(
 					self.children.into_iter(),
-					value_with_omission(node_data, value, self.omit_value)
+					value_with_omission(node_data, value, self.omit_value),
+					&mut output,
 				)
 			},
 			NodePlan::NibbledBranch { partial: partial_plan, value, children } => {
@@ -118,14 +127,16 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 					self.child_index,
 					&mut self.children
 				)?;
-				C::branch_node_nibbled(
+				C::branch_node_nibbled_into(
 					partial.right_iter(),
 					partial.len(),
 					self.children.into_iter(),
-					value_with_omission(node_data, value, self.omit_value)
+					value_with_omission(node_data, value, self.omit_value),
+					&mut output,
 				)
 			},
-		})
+		}
+		Ok(output)
 	}
 
 	/// Populate the remaining references in `children` with references copied from
@@ -135,12 +146,12 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 	/// - children has size NIBBLE_LENGTH.
 	fn complete_branch_children(
 		node_data: &[u8],
-		child_handles: &[Option<NodeHandlePlan>; NIBBLE_LENGTH],
+		child_handles: &Children<NodeHandlePlan, C::Nibble>,
 		child_index: usize,
 		children: &mut [Option<ChildReference<C::HashOut>>],
 	) -> TrieResult<(), C::HashOut, C::Error>
 	{
-		for i in child_index..NIBBLE_LENGTH {
+		for i in child_index..C::Nibble::NIBBLE_LENGTH {
 			children[i] = child_handles[i]
 				.as_ref()
 				.map(|child_plan|
@@ -157,9 +168,10 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 	}
 
 	/// Sets the reference for the child at index `child_index`. If the child is hash-referenced in
-	/// the trie, the proof node reference will be an omitted child. If the child is
-	/// inline-referenced in the trie, the proof node reference will also be inline.
-	fn set_child(&mut self, encoded_child: &[u8]) {
+	/// the trie, the proof node reference will be `proof_hash`, the hash of the proof node of the
+	/// child. If the child is inline-referenced in the trie, the proof node reference will also be
+	/// inline.
+	fn set_child(&mut self, encoded_child: &[u8], proof_hash: C::HashOut) {
 		let child_ref = match self.node.node_plan() {
 			NodePlan::Empty | NodePlan::Leaf { .. } => panic!(
 				"empty and leaf nodes have no children; \
@@ -173,18 +185,18 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 					set_child is called when the only child is popped from the stack; \
 					child_index is 0 before child is pushed to the stack; qed"
 				);
-				Some(Self::replacement_child_ref(encoded_child, child))
+				Some(Self::replacement_child_ref(encoded_child, proof_hash, child))
 			}
 			NodePlan::Branch { children, .. } | NodePlan::NibbledBranch { children, .. } => {
 				assert!(
-					self.child_index < NIBBLE_LENGTH,
+					self.child_index < C::Nibble::NIBBLE_LENGTH,
 					"extension nodes have at most NIBBLE_LENGTH children; \
 					set_child is called when the only child is popped from the stack; \
 					child_index is <NIBBLE_LENGTH before child is pushed to the stack; qed"
 				);
 				children[self.child_index]
 					.as_ref()
-					.map(|child| Self::replacement_child_ref(encoded_child, child))
+					.map(|child| Self::replacement_child_ref(encoded_child, proof_hash, child))
 			}
 		};
 		self.children[self.child_index] = child_ref;
@@ -192,13 +204,16 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 	}
 
 	/// Build a proof node child reference. If the child is hash-referenced in the trie, the proof
-	/// node reference will be an omitted child. If the child is inline-referenced in the trie, the
+	/// node reference will be `proof_hash`. If the child is inline-referenced in the trie, the
 	/// proof node reference will also be inline.
-	fn replacement_child_ref(encoded_child: &[u8], child: &NodeHandlePlan)
-							 -> ChildReference<C::HashOut>
+	fn replacement_child_ref(
+		encoded_child: &[u8],
+		proof_hash: C::HashOut,
+		child: &NodeHandlePlan,
+	) -> ChildReference<C::HashOut>
 	{
 		match child {
-			NodeHandlePlan::Hash(_) => ChildReference::Inline(C::HashOut::default(), 0),
+			NodeHandlePlan::Hash(_) => ChildReference::Hash(proof_hash),
 			NodeHandlePlan::Inline(_) => {
 				let mut hash = C::HashOut::default();
 				assert!(
@@ -215,6 +230,40 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 	}
 }
 
+/// A compact proof covering the provable keys of a request, with the keys which could not be
+/// proven as the database misses nodes on their path.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ProofWithOmissions<HO> {
+	/// The proof of the covered keys, in the format of `generate_proof`. The nodes on the path of
+	/// an omitted key are left out, from the first one not on the path of a covered key: its hash
+	/// is kept in its parent node, so that statements about the omitted keys are rejected.
+	pub proof: Vec<Vec<u8>>,
+	/// The omitted keys, in key order.
+	pub omitted: Vec<OmittedKey<HO>>,
+}
+
+/// Size of the compact proof of a set of keys, see `estimate_proof_size`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ProofSizeEstimate {
+	/// Size of the proof `generate_proof` returns: the sum of the lengths of its nodes.
+	pub exact_known_bytes: usize,
+	/// Size of the nodes of the proof as stored in the database, before the values proven are
+	/// left out: an upper bound of the size of the proof, known from the lookups alone.
+	pub upper_bound_bytes: usize,
+}
+
+/// A key left out of a proof.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OmittedKey<HO> {
+	/// The key.
+	pub key: Vec<u8>,
+	/// The hash of the first node missing from the database on the path of the key.
+	pub missing_node: HO,
+	/// The depth of the missing node, as in `Record`: the number of nodes above it on the path of
+	/// the key.
+	pub depth: u32,
+}
+
 /// Generate a compact proof for key-value pairs in a trie given a set of keys.
 ///
 /// Assumes inline nodes have only inline children.
@@ -225,30 +274,158 @@ pub fn generate_proof<'a, T, L, I, K>(trie: &T, keys: I)
 		L: TrieLayout,
 		I: IntoIterator<Item=&'a K>,
 		K: 'a + AsRef<[u8]>
+{
+	generate_proof_with_omissions(trie, keys, false).map(|proof| proof.proof)
+}
+
+/// Size of the compact proof `generate_proof` returns for `keys`, without keeping the proof.
+///
+/// The nodes of the proof are encoded one at a time to measure them, only their sizes being kept:
+/// the memory used is that of the nodes on one path instead of that of the proof.
+pub fn estimate_proof_size<'a, T, L, I, K>(trie: &T, keys: I)
+	-> TrieResult<ProofSizeEstimate, TrieHash<L>, CError<L>>
+	where
+		T: Trie<L>,
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a K>,
+		K: 'a + AsRef<[u8]>
+{
+	let mut sizes = ProofNodeSizes::default();
+	generate(trie, keys, false, &mut sizes)?;
+	Ok(sizes.estimate)
+}
+
+/// Generate a compact proof for key-value pairs in a trie given a set of keys, as
+/// `generate_proof`.
+///
+/// With `allow_partial`, a key whose lookup reaches a node missing from the database is listed
+/// in the omitted keys instead of failing the generation, the other keys being proven. Without
+/// it, a missing node fails the generation with `TrieError::IncompleteDatabase`, and no key is
+/// omitted.
+pub fn generate_proof_with_omissions<'a, T, L, I, K>(
+	trie: &T,
+	keys: I,
+	allow_partial: bool,
+) -> TrieResult<ProofWithOmissions<TrieHash<L>>, TrieHash<L>, CError<L>>
+	where
+		T: Trie<L>,
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a K>,
+		K: 'a + AsRef<[u8]>
+{
+	let mut proof_nodes = Vec::new();
+	let omitted = generate(trie, keys, allow_partial, &mut proof_nodes)?;
+	Ok(ProofWithOmissions { proof: proof_nodes, omitted })
+}
+
+/// Output of the proof generation, at places reserved for the nodes in the order they are entered.
+trait ProofNodes {
+	/// Reserve the place of a node, stored in the database as `stored_len` bytes, returning its
+	/// index.
+	fn reserve(&mut self, stored_len: usize) -> usize;
+
+	/// Release the place of the last node reserved, left out of the proof.
+	fn release(&mut self);
+
+	/// Set the proof node at `index`.
+	fn set(&mut self, index: usize, encoded: Vec<u8>);
+}
+
+impl ProofNodes for Vec<Vec<u8>> {
+	fn reserve(&mut self, _stored_len: usize) -> usize {
+		// A placeholder, replaced when the entry of the node is popped from the stack.
+		self.push(Vec::new());
+		self.len() - 1
+	}
+
+	fn release(&mut self) {
+		self.pop();
+	}
+
+	fn set(&mut self, index: usize, encoded: Vec<u8>) {
+		self[index] = encoded;
+	}
+}
+
+/// Sizes of the proof nodes, for `estimate_proof_size`.
+#[derive(Default)]
+struct ProofNodeSizes {
+	/// Number of nodes reserved.
+	reserved: usize,
+	/// Stored length of the nodes reserved and not set yet, the nodes of the stack: they are set
+	/// in the reverse order of their reservation.
+	pending: Vec<usize>,
+	estimate: ProofSizeEstimate,
+}
+
+impl ProofNodes for ProofNodeSizes {
+	fn reserve(&mut self, stored_len: usize) -> usize {
+		self.pending.push(stored_len);
+		self.estimate.upper_bound_bytes += stored_len;
+		self.reserved += 1;
+		self.reserved - 1
+	}
+
+	fn release(&mut self) {
+		if let Some(stored_len) = self.pending.pop() {
+			self.estimate.upper_bound_bytes -= stored_len;
+			self.reserved -= 1;
+		}
+	}
+
+	fn set(&mut self, _index: usize, encoded: Vec<u8>) {
+		self.pending.pop();
+		self.estimate.exact_known_bytes += encoded.len();
+	}
+}
+
+/// Generate the proof of `keys` into `proof_nodes`, returning the omitted keys, see
+/// `generate_proof_with_omissions`.
+fn generate<'a, T, L, I, K, P>(
+	trie: &T,
+	keys: I,
+	allow_partial: bool,
+	proof_nodes: &mut P,
+) -> TrieResult<Vec<OmittedKey<TrieHash<L>>>, TrieHash<L>, CError<L>>
+	where
+		T: Trie<L>,
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a K>,
+		K: 'a + AsRef<[u8]>,
+		P: ProofNodes,
 {
 	// Sort and deduplicate keys.
 	let mut keys = keys.into_iter()
 		.map(|key| key.as_ref())
 		.collect::<Vec<_>>();
-	keys.sort();
+	keys.sort_unstable();
 	keys.dedup();
 
 	// The stack of nodes through a path in the trie. Each entry is a child node of the preceding
 	// entry.
 	let mut stack = <Vec<StackEntry<L::Codec>>>::new();
 
-	// The mutated trie nodes comprising the final proof.
-	let mut proof_nodes = Vec::new();
+	let mut omitted = Vec::new();
 
 	for key_bytes in keys {
 		let key = LeftNibbleSlice::new(key_bytes);
 
 		// Unwind the stack until the new entry is a child of the last entry on the stack.
-		unwind_stack(&mut stack, &mut proof_nodes, Some(&key))?;
+		unwind_stack::<L::Hash, _, _>(&mut stack, proof_nodes, Some(&key))?;
+		// The entries on the stack are on the path of a proven key.
+		let proven_len = stack.len();
 
 		// Perform the trie lookup for the next key, recording the sequence of nodes traversed.
+		// The value nodes are left out, the verifier hashing the values it is given.
 		let mut recorder = Recorder::new();
-		let expected_value = trie.get_with(key_bytes, &mut recorder)?;
+		recorder.record_values(false);
+		let (expected_value, missing_node) = match trie.get_with(key_bytes, &mut recorder) {
+			Ok(value) => (value, None),
+			Err(error) => match *error {
+				TrieError::IncompleteDatabase { hash, .. } if allow_partial => (None, Some(hash)),
+				error => return Err(Box::new(error)),
+			},
+		};
 		let mut recorded_nodes = recorder.drain().into_iter().peekable();
 
 		// Skip over recorded nodes already on the stack. Their indexes into the respective vector
@@ -290,20 +467,40 @@ pub fn generate_proof<'a, T, L, I, K>(trie: &T, keys: I)
 					let child_prefix = key.truncate(child_prefix_len);
 					let child_entry = match child {
 						NodeHandle::Hash(hash) => {
-							let child_record = recorded_nodes.next()
-								.expect(
-									"this function's trie traversal logic mirrors that of Lookup; \
-									thus the sequence of traversed nodes must be the same; \
-									so the next child node must have been recorded and must have \
-									the expected hash"
-								);
+							let child_record = match recorded_nodes.next() {
+								Some(child_record) => child_record,
+								None => {
+									// The lookup stopped at this child, missing from the database:
+									// its parent keeps its hash in the proof.
+									let missing_node = missing_node
+										.expect(
+											"this function's trie traversal logic mirrors that of \
+											Lookup; \
+											all nodes on the path were recorded unless the lookup \
+											failed on a missing node"
+										);
+									assert_eq!(missing_node.as_ref(), hash);
+									omitted.push(OmittedKey {
+										key: key_bytes.to_vec(),
+										missing_node,
+										depth: stack.len() as u32,
+									});
+									// The nodes only on the path of this key are left out of the
+									// proof, their parent keeping the hash of the first one.
+									while stack.len() > proven_len {
+										let entry = stack.pop()
+											.expect("stack is longer than proven_len; qed");
+										if entry.output_index.is_some() {
+											proof_nodes.release();
+										}
+									}
+									break;
+								}
+							};
 							// Proof for `assert_eq` is in the `expect` proof above.
 							assert_eq!(child_record.hash.as_ref(), hash);
 
-							let output_index = proof_nodes.len();
-							// Insert a placeholder into output which will be replaced when this
-							// new entry is popped from the stack.
-							proof_nodes.push(Vec::new());
+							let output_index = proof_nodes.reserve(child_record.data.len());
 							StackEntry::new(
 								child_prefix,
 								child_record.data,
@@ -328,9 +525,14 @@ pub fn generate_proof<'a, T, L, I, K>(trie: &T, keys: I)
 					stack.push(child_entry);
 				}
 				Step::FoundValue(value) => {
+					// The value found is the value slot of the node, see
+					// `TrieLayout::MAX_INLINE_VALUE`.
+					let expected_slot = expected_value.as_ref()
+						.and_then(|v| value_slot::<L>(v, value_node_hash::<L>(v).as_ref()));
 					assert_eq!(
 						value,
-						expected_value.as_ref().map(|v| v.as_ref()),
+						expected_slot.as_deref()
+							.or_else(|| expected_value.as_ref().map(|v| v.as_ref())),
 						"expected_value is found using `trie_db::Lookup`; \
 						value is found by traversing the same nodes recorded during the lookup \
 						using the same logic; \
@@ -350,8 +552,8 @@ pub fn generate_proof<'a, T, L, I, K>(trie: &T, keys: I)
 		}
 	}
 
-	unwind_stack(&mut stack, &mut proof_nodes, None)?;
-	Ok(proof_nodes)
+	unwind_stack::<L::Hash, _, _>(&mut stack, proof_nodes, None)?;
+	Ok(omitted)
 }
 
 enum Step<'a> {
@@ -366,11 +568,11 @@ enum Step<'a> {
 /// entry on the stack.
 fn match_key_to_node<'a, C: NodeCodec>(
 	node_data: &'a [u8],
-	node_plan: &NodePlan,
+	node_plan: &NodePlan<C::Nibble>,
 	omit_value: &mut bool,
 	child_index: &mut usize,
 	children: &mut [Option<ChildReference<C::HashOut>>],
-	key: &LeftNibbleSlice,
+	key: &LeftNibbleSlice<C::Nibble>,
 	prefix_len: usize,
 ) -> TrieResult<Step<'a>, C::HashOut, C::Error>
 {
@@ -402,7 +604,7 @@ fn match_key_to_node<'a, C: NodeCodec>(
 			match_key_to_branch_node::<C>(
 				node_data,
 				value,
-				&child_handles,
+				child_handles,
 				omit_value,
 				child_index,
 				children,
@@ -414,7 +616,7 @@ fn match_key_to_node<'a, C: NodeCodec>(
 			match_key_to_branch_node::<C>(
 				node_data,
 				value,
-				&child_handles,
+				child_handles,
 				omit_value,
 				child_index,
 				children,
@@ -428,19 +630,21 @@ fn match_key_to_node<'a, C: NodeCodec>(
 fn match_key_to_branch_node<'a, 'b, C: NodeCodec>(
 	node_data: &'a [u8],
 	value_range: &'b Option<Range<usize>>,
-	child_handles: &'b [Option<NodeHandlePlan>; NIBBLE_LENGTH],
+	child_handles: &'b Children<NodeHandlePlan, C::Nibble>,
 	omit_value: &mut bool,
 	child_index: &mut usize,
 	children: &mut [Option<ChildReference<C::HashOut>>],
-	key: &'b LeftNibbleSlice<'b>,
+	key: &'b LeftNibbleSlice<'b, C::Nibble>,
 	prefix_len: usize,
-	partial: NibbleSlice<'b>,
+	partial: NibbleSlice<'b, C::Nibble>,
 ) -> TrieResult<Step<'a>, C::HashOut, C::Error>
 {
 	if !key.contains(&partial, prefix_len) {
 		return Ok(Step::FoundValue(None));
 	}
 
+	// The key ends at the branch, other keys having it as prefix. Its value slot is empty in the
+	// proof whether the key has a value or not, the verifier filling it from the statement.
 	if key.len() == prefix_len + partial.len() {
 		*omit_value = true;
 		let value = value_range.clone().map(|range| &node_data[range]);
@@ -495,11 +699,12 @@ fn value_with_omission<'a>(
 
 /// Unwind the stack until the given key is prefixed by the entry at the top of the stack. If the
 /// key is None, unwind the stack completely. As entries are popped from the stack, they are
-/// encoded into proof nodes and added to the finalized proof.
-fn unwind_stack<C: NodeCodec>(
+/// encoded into proof nodes and added to the finalized proof, their parent referencing them by
+/// the hash of the proof node.
+fn unwind_stack<H: Hasher, C: NodeCodec<HashOut = H::Out>, P: ProofNodes>(
 	stack: &mut Vec<StackEntry<C>>,
-	proof_nodes: &mut Vec<Vec<u8>>,
-	maybe_key: Option<&LeftNibbleSlice>,
+	proof_nodes: &mut P,
+	maybe_key: Option<&LeftNibbleSlice<C::Nibble>>,
 ) -> TrieResult<(), C::HashOut, C::Error>
 {
 	while let Some(entry) = stack.pop() {
@@ -514,10 +719,14 @@ fn unwind_stack<C: NodeCodec>(
 				let index = entry.output_index;
 				let encoded = entry.encode_node()?;
 				if let Some(parent_entry) = stack.last_mut() {
-					parent_entry.set_child(&encoded);
+					let proof_hash = match index {
+						Some(_) => H::hash(&encoded),
+						None => Default::default(),
+					};
+					parent_entry.set_child(&encoded, proof_hash);
 				}
 				if let Some(index) = index {
-					proof_nodes[index] = encoded;
+					proof_nodes.set(index, encoded);
 				}
 			}
 		}
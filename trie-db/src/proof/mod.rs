@@ -18,22 +18,36 @@
 //! non-inclusion of certain key-value pairs in a trie with a known root. The proof contains
 //! information so that the verifier can reconstruct the subset of nodes in the trie required to
 //! lookup the keys. The trie nodes are not included in their entirety as data which the verifier
-//! can compute for themself is omitted. In particular, the values of included keys and and hashes
-//! of other trie nodes in the proof are omitted.
+//! can compute for themself is omitted. In particular, the values of included keys are omitted.
 //!
 //! The proof is a sequence of the subset of nodes in the trie traversed while performing lookups
-//! on all keys. The trie nodes are listed in pre-order traversal order with some values and
-//! internal hashes omitted. In particular, values on leaf nodes, child references on extension
-//! nodes, values on branch nodes corresponding to a key in the statement, and child references on
-//! branch nodes corresponding to another node in the proof are all omitted. The proof is verified
-//! by iteratively reconstructing the trie nodes using the values proving as part of the statement
-//! and the hashes of other reconstructed nodes. Since the nodes in the proof are arranged in
-//! pre-order traversal order, the construction can be done efficiently using a stack.
+//! on all keys. The trie nodes are listed in pre-order traversal order with some values omitted
+//! and some references replaced. In particular, values on leaf nodes and values on branch nodes
+//! corresponding to a key in the statement are omitted, and a child reference to another node in
+//! the proof is the hash of that proof node. The proof is verified by iteratively reconstructing
+//! the trie nodes using the values proving as part of the statement and the hashes of other
+//! reconstructed nodes, finding each proof node by the hash its parent references it with. The
+//! proof nodes can therefore be in any order for `verify_proof`, and `canonicalize` restores the
+//! pre-order.
 
-pub use self::generate::generate_proof;
-pub use self::verify::{Error as VerifyError, verify_proof};
+pub use self::absence::{AbsenceProof, Divergence};
+pub use self::generate::{
+	estimate_proof_size, generate_proof, generate_proof_with_omissions, OmittedKey,
+	ProofSizeEstimate, ProofWithOmissions,
+};
+pub use self::verify::{
+	canonicalize, Error as VerifyError, is_canonical, VerifiedProof, verify_proof,
+	verify_proof_into_view, verify_proof_multi_root, VerifyStats,
+};
+pub use self::multi::{MultiProof, generate_multi_proof, verify_multi_proof};
+pub use self::prefix::{generate_absence_of_prefix_proof, verify_absence_of_prefix_proof};
+pub use self::value_range::ValueRangeProof;
 
+mod absence;
 mod generate;
+mod multi;
+mod prefix;
+mod value_range;
 mod verify;
 
 #[cfg(test)]
@@ -41,9 +55,16 @@ mod tests {
 	use hash_db::Hasher;
 	use reference_trie::{
 		ExtensionLayout, NoExtensionLayout,
-		proof::{generate_proof, verify_proof, VerifyError}, Trie, TrieDB, TrieDBMut, TrieLayout,
-		TrieMut,
+		proof::{
+			AbsenceProof, canonicalize, Divergence, generate_absence_of_prefix_proof,
+			generate_multi_proof, generate_proof, generate_proof_with_omissions, is_canonical,
+			OmittedKey, verify_absence_of_prefix_proof, verify_multi_proof, verify_proof,
+			verify_proof_into_view, verify_proof_multi_root, VerifyError, VerifyStats,
+		},
+		CountingLayout, decode_count, Radix16, Recorder, Trie, TrieDB, TrieDBMut, TrieError,
+		TrieLayout, TrieMut,
 	};
+	use hash_db::{HashDB, HashDBRef, EMPTY_PREFIX};
 
 	use crate::DBValue;
 
@@ -105,9 +126,9 @@ mod tests {
 				b"doge",
 				b"bravo",
 				b"alfabet", // None, not found under leaf node
-				b"d", // None, witness is extension node with omitted child
+				b"d", // None, witness is extension node with its child in the proof
 				b"do\x10", // None, empty branch child
-				b"halp", // None, witness is extension node with non-omitted child
+				b"halp", // None, witness is extension node with its child out of the proof
 			],
 		);
 
@@ -124,9 +145,9 @@ mod tests {
 				b"doge",
 				b"bravo",
 				b"alfabet", // None, not found under leaf node
-				b"d", // None, witness is extension node with omitted child
+				b"d", // None, witness is extension node with its child in the proof
 				b"do\x10", // None, empty branch child
-				b"halp", // None, witness is extension node with non-omitted child
+				b"halp", // None, witness is extension node with its child out of the proof
 			],
 		);
 
@@ -147,6 +168,42 @@ mod tests {
 		verify_proof::<NoExtensionLayout, _, _, _>(&root, &proof, items.iter()).unwrap();
 	}
 
+	fn check_proof_of_unordered_keys<L: TrieLayout>() {
+		use rand::{SeedableRng, rngs::SmallRng, seq::SliceRandom};
+
+		// "alfz" and "hoa" diverge inside the partial keys of a leaf and of an extension or a
+		// branch.
+		let keys: Vec<&'static [u8]> =
+			vec![b"alfa", b"alfz", b"do", b"dog", b"doge", b"dogs", b"hoa", b"horse", b"house"];
+		let (root, proof, items) = test_generate_proof::<L>(test_entries(), keys.clone());
+		verify_proof::<L, _, _, _>(&root, &proof, items.iter()).unwrap();
+		// the nodes on the shared paths are in the proof once.
+		let (db, root) = test_build_trie::<L>(&test_entries());
+		let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+		let mut recorder = Recorder::new();
+		for key in keys.iter() {
+			trie.get_with(key, &mut recorder).unwrap();
+		}
+		let hashes: std::collections::HashSet<_> =
+			recorder.drain().into_iter().map(|record| record.hash).collect();
+		assert_eq!(proof.len(), hashes.len());
+
+		let mut rng = SmallRng::seed_from_u64(3);
+		for _ in 0..10 {
+			let mut unordered = keys.clone();
+			unordered.extend((0..3).filter_map(|_| keys.choose(&mut rng)).cloned());
+			unordered.shuffle(&mut rng);
+			let (_, unordered_proof, _) = test_generate_proof::<L>(test_entries(), unordered);
+			assert_eq!(unordered_proof, proof);
+		}
+	}
+
+	#[test]
+	fn proof_of_unordered_keys() {
+		check_proof_of_unordered_keys::<ExtensionLayout>();
+		check_proof_of_unordered_keys::<NoExtensionLayout>();
+	}
+
 	#[test]
 	fn test_verify_duplicate_keys() {
 		let (root, proof, _) = test_generate_proof::<NoExtensionLayout>(
@@ -273,9 +330,16 @@ mod tests {
 			vec![b"alfa"],
 		);
 
-		proof.pop();
+		// the parent of the node left out references it by a hash the proof does not hold.
+		let removed = proof.pop().unwrap();
 		assert_eq!(
 			verify_proof::<NoExtensionLayout, _, _, _>(&root, &proof, items.iter()),
+			Err(VerifyError::ExtraneousHashReference(
+				<NoExtensionLayout as TrieLayout>::Hash::hash(&removed)
+			))
+		);
+		assert_eq!(
+			verify_proof::<NoExtensionLayout, _, _, _>(&root, &[], items.iter()),
 			Err(VerifyError::IncompleteProof)
 		);
 	}
@@ -309,4 +373,807 @@ mod tests {
 			result => panic!("expected VerifyError::DecodeError, got {:?}", result),
 		}
 	}
+
+	#[test]
+	fn test_verify_padded_node() {
+		let (root, proof, items) = test_generate_proof::<ExtensionLayout>(
+			test_entries(),
+			vec![b"do", b"dog", b"doge"],
+		);
+
+		for i in 0..proof.len() {
+			let mut padded = proof.clone();
+			padded[i].extend_from_slice(&[0, 0, 0]);
+			match verify_proof::<ExtensionLayout, _, _, _>(&root, &padded, items.iter()) {
+				Err(VerifyError::DecodeError(_)) => {}
+				result => panic!("expected VerifyError::DecodeError, got {:?}", result),
+			}
+		}
+	}
+
+	#[test]
+	fn verified_proof_as_trie() {
+		let (root, proof, items) = test_generate_proof::<NoExtensionLayout>(
+			test_entries(),
+			vec![b"do", b"dog", b"doge"],
+		);
+		let view = verify_proof_into_view::<NoExtensionLayout, _, _, _>(
+			&root,
+			&proof,
+			items.iter(),
+		).unwrap();
+		assert_eq!(view.root(), &root);
+		let trie = view.as_trie();
+
+		assert_eq!(trie.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+		// Absence is provable inside the nodes of the proof.
+		assert_eq!(trie.get(b"dot").unwrap(), None);
+		// "alfa" is behind a hash reference omitted from the proof.
+		match trie.get(b"alfa").map_err(|e| *e) {
+			Err(TrieError::IncompleteDatabase { .. }) => {}
+			result => panic!("expected TrieError::IncompleteDatabase, got {:?}", result),
+		}
+
+		// Iterating from the start reaches the "alfa" leaf first.
+		let mut iter = trie.iter().unwrap();
+		match iter.next().map(|item| item.map_err(|e| *e)) {
+			Some(Err(TrieError::IncompleteDatabase { .. })) => {}
+			result => panic!("expected TrieError::IncompleteDatabase, got {:?}", result),
+		}
+
+		let mut iter = trie.iter().unwrap();
+		iter.seek(b"do").unwrap();
+		let keys: Vec<_> = iter
+			.take(3)
+			.map(|item| item.unwrap().0)
+			.collect();
+		assert_eq!(keys, vec![b"do".to_vec(), b"dog".to_vec(), b"doge".to_vec()]);
+
+		let copy = view.clone();
+		assert_eq!(copy.len(), view.len());
+		assert_eq!(copy.as_trie().get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+		assert!(format!("{:?}", copy).starts_with("VerifiedProof"));
+	}
+
+	#[test]
+	fn verify_proof_multi_root_matches_second_root() {
+		let (root, proof, items) = test_generate_proof::<CountingLayout>(
+			test_entries(),
+			vec![b"do", b"dog", b"doge", b"bravo", b"halp"],
+		);
+		let (other_root, _, _) = test_generate_proof::<CountingLayout>(
+			vec![(b"do", b"noun")],
+			vec![b"do"],
+		);
+
+		let before = decode_count();
+		verify_proof::<CountingLayout, _, _, _>(&root, &proof, items.iter()).unwrap();
+		let single_decodes = decode_count() - before;
+
+		let roots = vec![other_root, root, other_root];
+		let before = decode_count();
+		assert_eq!(
+			verify_proof_multi_root::<CountingLayout, _, _, _>(&roots, &proof, items.iter()),
+			Ok(1)
+		);
+		// Same decoding work as a single verification.
+		assert_eq!(decode_count() - before, single_decodes);
+	}
+
+	#[test]
+	fn verify_proof_multi_root_matches_none() {
+		let (root, proof, items) = test_generate_proof::<CountingLayout>(
+			test_entries(),
+			vec![b"dog"],
+		);
+		let (other_root, _, _) = test_generate_proof::<CountingLayout>(
+			vec![(b"do", b"noun")],
+			vec![b"do"],
+		);
+
+		let roots = vec![other_root; 4];
+		let before = decode_count();
+		assert_eq!(
+			verify_proof_multi_root::<CountingLayout, _, _, _>(&roots, &proof, items.iter()),
+			Err(VerifyError::RootMismatch(root))
+		);
+		assert_eq!(decode_count() - before, proof.len());
+		assert_eq!(
+			verify_proof_multi_root::<CountingLayout, _, _, _>(&[], &proof, items.iter()),
+			Err(VerifyError::RootMismatch(root))
+		);
+	}
+
+	fn check_shuffled_proofs<L: TrieLayout>() {
+		use rand::{SeedableRng, rngs::SmallRng, seq::SliceRandom};
+
+		let mut rng = SmallRng::seed_from_u64(7);
+		let key_sets: Vec<Vec<&'static [u8]>> = vec![
+			vec![b"do", b"dog", b"doge", b"bravo", b"alfabet", b"d", b"do\x10", b"halp"],
+			vec![b"alfa", b"horse", b"house"],
+			vec![b"doge", b"hotel"],
+		];
+		for keys in key_sets {
+			let (root, proof, items) = test_generate_proof::<L>(test_entries(), keys);
+			assert!(is_canonical::<L, _, _, _>(&root, &proof, items.iter()));
+			for _ in 0..10 {
+				let mut shuffled = proof.clone();
+				shuffled.shuffle(&mut rng);
+				verify_proof::<L, _, _, _>(&root, &shuffled, items.iter()).unwrap();
+				assert_eq!(
+					is_canonical::<L, _, _, _>(&root, &shuffled, items.iter()),
+					shuffled == proof,
+				);
+				assert_eq!(canonicalize::<L, _, _, _>(&root, &shuffled, items.iter()).unwrap(), proof);
+			}
+		}
+	}
+
+	#[test]
+	fn shuffled_proofs_verify() {
+		check_shuffled_proofs::<ExtensionLayout>();
+		check_shuffled_proofs::<NoExtensionLayout>();
+	}
+
+	fn check_shuffled_proofs_of_same_shapes<L: TrieLayout>() {
+		use rand::{Rng, SeedableRng, rngs::SmallRng, seq::SliceRandom};
+
+		let mut rng = SmallRng::seed_from_u64(11);
+		// Random keys give many branches with a single child in the proof at the same index, and
+		// the sibling subtrees of the keys under `[0xff, 0xfe]` have the same shape and values:
+		// their proof nodes have the same encodings.
+		let mut keys: Vec<Vec<u8>> = (0..1000).map(|_| rng.gen::<[u8; 8]>().to_vec()).collect();
+		keys.extend((0..8u8).flat_map(|sibling| {
+			(0..3u8).map(move |last| vec![0xff, 0xfe, sibling, last])
+		}));
+		let mut db = <MemoryDB<L::Hash>>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = <TrieDBMut<L>>::new(&mut db, &mut root);
+			for key in keys.iter() {
+				trie.insert(key, &[key.len() as u8; 40]).unwrap();
+			}
+		}
+		let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+		let mut proven: Vec<Vec<u8>> = keys.iter().step_by(40).cloned().collect();
+		proven.extend((0..8u8).map(|sibling| vec![0xff, 0xfe, sibling, 1]));
+		proven.extend((0..5).map(|_| rng.gen::<[u8; 8]>().to_vec()));
+		let proof = generate_proof::<_, L, _, _>(&trie, proven.iter()).unwrap();
+		let items: Vec<_> = proven.iter()
+			.map(|key| (key.clone(), trie.get(key).unwrap()))
+			.collect();
+		let distinct = proof.iter().collect::<std::collections::HashSet<_>>().len();
+		assert!(distinct < proof.len());
+
+		let canonical_view = verify_proof_into_view::<L, _, _, _>(&root, &proof, items.iter())
+			.unwrap();
+		for _ in 0..10 {
+			let mut shuffled = proof.clone();
+			shuffled.shuffle(&mut rng);
+			verify_proof::<L, _, _, _>(&root, &shuffled, items.iter()).unwrap();
+			assert!(!is_canonical::<L, _, _, _>(&root, &shuffled, items.iter()));
+			assert_eq!(canonicalize::<L, _, _, _>(&root, &shuffled, items.iter()).unwrap(), proof);
+			let view = verify_proof_into_view::<L, _, _, _>(&root, &shuffled, items.iter())
+				.unwrap();
+			assert_eq!(view.len(), canonical_view.len());
+		}
+
+		// Any node missing or repeated fails the proof, whatever the order.
+		let mut shuffled = proof.clone();
+		shuffled.shuffle(&mut rng);
+		for index in 0..shuffled.len() {
+			let mut missing = shuffled.clone();
+			let node = missing.remove(index);
+			assert!(verify_proof::<L, _, _, _>(&root, &missing, items.iter()).is_err());
+			let mut repeated = shuffled.clone();
+			repeated.insert(rng.gen_range(0, shuffled.len()), node);
+			assert!(verify_proof::<L, _, _, _>(&root, &repeated, items.iter()).is_err());
+		}
+	}
+
+	#[test]
+	fn shuffled_proofs_of_same_shapes_verify() {
+		check_shuffled_proofs_of_same_shapes::<ExtensionLayout>();
+		check_shuffled_proofs_of_same_shapes::<NoExtensionLayout>();
+	}
+
+	#[test]
+	fn shuffled_proofs_detect_extraneous_nodes() {
+		let (root, proof, items) = test_generate_proof::<NoExtensionLayout>(
+			test_entries(),
+			vec![b"do", b"dog", b"doge"],
+		);
+		let (_, other_proof, _) = test_generate_proof::<NoExtensionLayout>(
+			test_entries(),
+			vec![b"alfa"],
+		);
+		let extra_nodes = vec![other_proof[other_proof.len() - 1].clone(), proof[1].clone()];
+		for extra_node in extra_nodes {
+			for position in 0..=proof.len() {
+				let mut padded = proof.clone();
+				padded.insert(position, extra_node.clone());
+				assert!(verify_proof::<NoExtensionLayout, _, _, _>(&root, &padded, items.iter()).is_err());
+				assert!(canonicalize::<NoExtensionLayout, _, _, _>(&root, &padded, items.iter()).is_err());
+				assert!(!is_canonical::<NoExtensionLayout, _, _, _>(&root, &padded, items.iter()));
+			}
+			let mut padded = proof.clone();
+			padded.push(extra_node);
+			assert_eq!(
+				verify_proof::<NoExtensionLayout, _, _, _>(&root, &padded, items.iter()),
+				Err(VerifyError::ExtraneousNode)
+			);
+		}
+	}
+
+	#[test]
+	fn shuffled_proofs_check_values() {
+		let (root, mut proof, mut items) = test_generate_proof::<ExtensionLayout>(
+			test_entries(),
+			vec![b"do", b"dog", b"doge", b"horse"],
+		);
+		proof.reverse();
+		verify_proof::<ExtensionLayout, _, _, _>(&root, &proof, items.iter()).unwrap();
+		items[1].1 = Some(b"kitty".to_vec());
+		assert!(verify_proof::<ExtensionLayout, _, _, _>(&root, &proof, items.iter()).is_err());
+		assert!(!is_canonical::<ExtensionLayout, _, _, _>(&root, &proof, items.iter()));
+	}
+
+	fn prefix_entries() -> Vec<(&'static [u8], &'static [u8])> {
+		vec![
+			(b"cat", &[1; 32]),
+			(b"dog", &[2; 32]),
+			(b"doge", &[3; 32]),
+			(b"dot", &[4; 32]),
+			(b"horse", b"stallion"),
+		]
+	}
+
+	fn check_absent_prefix_keys<L: TrieLayout>() {
+		let absent: Vec<&'static [u8]> = vec![
+			// prefixes of a single key.
+			b"ca",
+			b"hors",
+			// prefixes of several keys, "do" ending at a branch without value.
+			b"d",
+			b"do",
+			// keys having an existing key as prefix.
+			b"cats",
+			b"dogs",
+			b"dogez",
+		];
+		// each key alone, then all the keys along the existing ones.
+		let mut statements: Vec<Vec<&'static [u8]>> = absent.iter().map(|key| vec![*key]).collect();
+		statements.push(absent.iter().cloned().chain(vec![&b"dog"[..], b"doge", b"cat"]).collect());
+		for keys in statements {
+			let (root, proof, items) = test_generate_proof::<L>(prefix_entries(), keys);
+			verify_proof::<L, _, _, _>(&root, &proof, items.iter()).unwrap();
+
+			for (index, (key, value)) in items.iter().enumerate() {
+				if value.is_some() {
+					continue;
+				}
+				let mut claimed = items.clone();
+				claimed[index].1 = Some(b"value".to_vec());
+				assert!(
+					verify_proof::<L, _, _, _>(&root, &proof, claimed.iter()).is_err(),
+					"{:?} claimed present",
+					key,
+				);
+			}
+		}
+	}
+
+	fn check_absent_key_with_branch_value<L: TrieLayout>() {
+		let (root, proof, _) = test_generate_proof::<L>(prefix_entries(), vec![b"doge"]);
+		// the proof of "doge" holds the value of "dog", in the branch node at "dog".
+		let items = [
+			(&b"dog"[..], None),
+			(&b"doge"[..], Some(&[3; 32][..])),
+		];
+		match verify_proof::<L, _, _, _>(&root, &proof, items.iter()) {
+			Err(VerifyError::NonExistenceBranchHasValue(key)) => assert_eq!(key, b"dog".to_vec()),
+			result => panic!("expected VerifyError::NonExistenceBranchHasValue, got {:?}", result),
+		}
+
+		// with the value omitted, only the root tells the key is present.
+		let (root, proof, _) = test_generate_proof::<L>(prefix_entries(), vec![b"dog", b"doge"]);
+		match verify_proof::<L, _, _, _>(&root, &proof, items.iter()) {
+			Err(VerifyError::RootMismatch(_)) => {}
+			result => panic!("expected VerifyError::RootMismatch, got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn absent_prefix_keys_with_ext() {
+		check_absent_prefix_keys::<ExtensionLayout>();
+		check_absent_key_with_branch_value::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn absent_prefix_keys_without_ext() {
+		check_absent_prefix_keys::<NoExtensionLayout>();
+		check_absent_key_with_branch_value::<NoExtensionLayout>();
+	}
+
+	fn absence_of_prefix_entries() -> Vec<(&'static [u8], &'static [u8])> {
+		vec![
+			// a branch at nibble 3 with children 3 and 5, and a value at 0x0125.
+			(&[0x01, 0x23, 0xaa], &[1; 32]),
+			(&[0x01, 0x25], &[2; 32]),
+			(&[0x01, 0x25, 0xbb], &[3; 32]),
+			// an inline leaf.
+			(&[0x40], b"inline"),
+		]
+	}
+
+	fn check_absence_of_prefix<L: TrieLayout>() {
+		let (db, root) = test_build_trie::<L>(&absence_of_prefix_entries());
+		let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+		let absent: Vec<&'static [u8]> = vec![
+			// the path diverges inside the partial key of the node at nibble 1.
+			&[0x01, 0x33],
+			&[0x41],
+			// the path ends at an empty child slot of the root, before the prefix completes.
+			&[0x20],
+			// the prefix ends on the empty child slot 4 of the branch at nibble 3.
+			&[0x01, 0x24],
+			// past a leaf.
+			&[0x01, 0x23, 0xaa, 0x00],
+		];
+		for prefix in absent {
+			let proof = generate_absence_of_prefix_proof(&trie, prefix).unwrap();
+			verify_absence_of_prefix_proof::<L>(&root, &proof, prefix).unwrap();
+		}
+
+		let present: Vec<&'static [u8]> = vec![&[], &[0x01], &[0x01, 0x23], &[0x01, 0x25], &[0x40]];
+		for prefix in present {
+			let proof = generate_absence_of_prefix_proof(&trie, prefix).unwrap();
+			assert!(
+				verify_absence_of_prefix_proof::<L>(&root, &proof, prefix).is_err(),
+				"{:?} claimed empty",
+				prefix,
+			);
+		}
+		// the inline leaf is in the proof, the key found is reported.
+		let proof = generate_absence_of_prefix_proof(&trie, &[0x40]).unwrap();
+		match verify_absence_of_prefix_proof::<L>(&root, &proof, &[0x40]) {
+			Err(VerifyError::PrefixNotEmpty(key)) => assert_eq!(key, vec![0x40]),
+			result => panic!("expected VerifyError::PrefixNotEmpty, got {:?}", result),
+		}
+		// the children of the branch at 0x01 are only referenced by hash.
+		let proof = generate_absence_of_prefix_proof(&trie, &[0x01]).unwrap();
+		match verify_absence_of_prefix_proof::<L>(&root, &proof, &[0x01]) {
+			Err(VerifyError::IncompleteProof) => {}
+			result => panic!("expected VerifyError::IncompleteProof, got {:?}", result),
+		}
+
+		// nothing starts with any prefix in the empty trie.
+		let (db, root) = test_build_trie::<L>(&[]);
+		let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+		for prefix in [&[][..], &[0x01]].iter() {
+			let proof = generate_absence_of_prefix_proof(&trie, prefix).unwrap();
+			verify_absence_of_prefix_proof::<L>(&root, &proof, prefix).unwrap();
+		}
+	}
+
+	fn check_absence_of_prefix_after_clear<L: TrieLayout>() {
+		use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+		let mut rng = SmallRng::seed_from_u64(11);
+		for _ in 0..50 {
+			// few distinct bytes, for keys sharing prefixes.
+			let keys: Vec<Vec<u8>> = (0..rng.gen_range(1, 40))
+				.map(|_| (0..rng.gen_range(1, 5)).map(|_| rng.gen_range(0, 4) * 0x11).collect())
+				.collect();
+			let mut db = <MemoryDB<L::Hash>>::default();
+			let mut root = Default::default();
+			{
+				let mut trie = <TrieDBMut<L>>::new(&mut db, &mut root);
+				for key in keys.iter() {
+					let value = vec![rng.gen_range(1, 255); rng.gen_range(1, 40)];
+					trie.insert(key, &value).unwrap();
+				}
+			}
+			let key = &keys[rng.gen_range(0, keys.len())];
+			let prefix = key[..rng.gen_range(0, key.len() + 1)].to_vec();
+
+			let before = {
+				let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+				generate_absence_of_prefix_proof(&trie, &prefix).unwrap()
+			};
+			let old_root = root;
+			<TrieDBMut<L>>::from_existing(&mut db, &mut root).unwrap()
+				.clear_prefix(&prefix, false).unwrap();
+
+			let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+			let after = generate_absence_of_prefix_proof(&trie, &prefix).unwrap();
+			verify_absence_of_prefix_proof::<L>(&root, &after, &prefix).unwrap();
+			// the keys were present before the clear.
+			assert!(verify_absence_of_prefix_proof::<L>(&old_root, &before, &prefix).is_err());
+			assert!(verify_absence_of_prefix_proof::<L>(&root, &before, &prefix).is_err());
+			assert!(verify_absence_of_prefix_proof::<L>(&old_root, &after, &prefix).is_err());
+		}
+	}
+
+	#[test]
+	fn absence_of_prefix_with_ext() {
+		check_absence_of_prefix::<ExtensionLayout>();
+		check_absence_of_prefix_after_clear::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn absence_of_prefix_without_ext() {
+		check_absence_of_prefix::<NoExtensionLayout>();
+		check_absence_of_prefix_after_clear::<NoExtensionLayout>();
+	}
+
+	fn check_absence_proofs<L: TrieLayout<Nibble = Radix16>>() {
+		use reference_trie::NibbleVec;
+
+		let (db, root) = test_build_trie::<L>(&test_entries());
+		let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+		let prove = |key: &[u8]| -> AbsenceProof<L::Nibble> {
+			let proof = trie.prove_absence(key).unwrap().unwrap();
+			proof.verify::<L>(&root, key).unwrap();
+			proof
+		};
+		let nibbles = |nibbles: &[u8]| -> NibbleVec {
+			let mut vec = NibbleVec::new();
+			nibbles.iter().for_each(|nibble| vec.push(*nibble));
+			vec
+		};
+
+		// "dz" leaves the partial key "o" of the extension, or of the branch without extensions,
+		// under "d".
+		let partial = nibbles(&[6, 15]);
+		assert_eq!(prove(b"dz").divergence, Divergence::PartialMismatch { partial });
+		// the branch at "do" has no child at the first nibble of "\x10".
+		assert_eq!(prove(b"do\x10").divergence, Divergence::MissingChild { index: 1 });
+		// "alf" is a strict prefix of the key of the leaf of "alfa".
+		match prove(b"alf").divergence {
+			Divergence::LeafMismatch { .. } => {},
+			divergence => panic!("unexpected divergence {:?}", divergence),
+		}
+		for key in [&b"alfb"[..], b"bravo\x00", b"d", b"dogs", b"ho", b"horsey", b"z"].iter() {
+			prove(key);
+		}
+		assert_eq!(trie.prove_absence(b"dog").unwrap(), None);
+
+		let proof = prove(b"dogs");
+		// the proof does not verify for other keys or another root, or with other nodes.
+		assert!(matches!(
+			proof.verify::<L>(&root, b"dog"),
+			Err(VerifyError::ValueMismatch(ref key)) if key == b"dog"
+		));
+		assert!(matches!(
+			proof.verify::<L>(&root, b"dog\x10"),
+			Err(VerifyError::DivergenceMismatch)
+		));
+		assert!(matches!(proof.verify::<L>(&root, b"doge"), Err(VerifyError::IncompleteProof)));
+		let other_root = <L::Hash as Hasher>::hash(b"other");
+		assert!(matches!(
+			proof.verify::<L>(&other_root, b"dogs"),
+			Err(VerifyError::RootMismatch(_))
+		));
+		let mut stuffed = proof.clone();
+		stuffed.nodes.push(prove(b"alf").nodes.pop().unwrap());
+		assert!(matches!(stuffed.verify::<L>(&root, b"dogs"), Err(VerifyError::ExtraneousNode)));
+		let mut truncated = proof.clone();
+		truncated.nodes.pop();
+		assert!(matches!(truncated.verify::<L>(&root, b"dogs"), Err(VerifyError::IncompleteProof)));
+
+		let (db, root) = test_build_trie::<L>(&[]);
+		let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+		let proof = trie.prove_absence(b"alfa").unwrap().unwrap();
+		assert_eq!(proof.divergence, Divergence::EmptyTrie);
+		proof.verify::<L>(&root, b"alfa").unwrap();
+	}
+
+	#[test]
+	fn absence_proofs_with_ext() {
+		check_absence_proofs::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn absence_proofs_without_ext() {
+		check_absence_proofs::<NoExtensionLayout>();
+	}
+
+	fn proof_stats<L: TrieLayout>(keys: Vec<&'static [u8]>) -> (VerifyStats, Vec<Vec<u8>>) {
+		let (root, proof, items) = test_generate_proof::<L>(test_entries(), keys);
+		(verify_proof::<L, _, _, _>(&root, &proof, items.iter()).unwrap(), proof)
+	}
+
+	#[test]
+	fn verification_stats_of_small_proofs() {
+		let node_bytes = |proof: &[Vec<u8>]| proof.iter().map(Vec::len).sum::<usize>();
+
+		// the root holds "bravo" as an inline leaf.
+		let (stats, proof) = proof_stats::<NoExtensionLayout>(vec![b"bravo"]);
+		assert_eq!(stats, VerifyStats {
+			nodes: 1,
+			node_bytes: node_bytes(&proof),
+			values_verified: 1,
+			value_bytes: 5,
+			max_depth: 2,
+		});
+
+		// "doge" is under the branches of "do" and "dog".
+		let (stats, proof) = proof_stats::<NoExtensionLayout>(vec![b"doge"]);
+		assert_eq!(stats, VerifyStats {
+			nodes: 4,
+			node_bytes: node_bytes(&proof),
+			values_verified: 1,
+			value_bytes: 32,
+			max_depth: 4,
+		});
+
+		// "alfabet" is absent, the proof holds the leaf of "alfa".
+		let (stats, proof) = proof_stats::<NoExtensionLayout>(vec![b"alfabet", b"do"]);
+		assert_eq!(stats, VerifyStats {
+			nodes: 3,
+			node_bytes: node_bytes(&proof),
+			values_verified: 1,
+			value_bytes: 4,
+			max_depth: 2,
+		});
+
+		let (root, proof, _) = test_generate_proof::<NoExtensionLayout>(vec![], vec![b"alfa"]);
+		let items = [(b"alfa", None::<&[u8]>)];
+		let stats = verify_proof::<NoExtensionLayout, _, _, _>(&root, &proof, items.iter()).unwrap();
+		assert_eq!(stats, VerifyStats { nodes: 1, node_bytes: 1, max_depth: 1, ..Default::default() });
+	}
+
+	fn check_stats_monotonicity<L: TrieLayout>() {
+		let keys: [&'static [u8]; 10] = [
+			b"do", b"alfabet", b"horse", b"dog", b"d", b"bravo", b"doge", b"house", b"halp", b"alfa",
+		];
+		let mut last = VerifyStats::default();
+		for count in 1..=keys.len() {
+			let (stats, proof) = proof_stats::<L>(keys[..count].to_vec());
+			assert_eq!(stats.nodes as usize, proof.len());
+			assert!(stats.nodes >= last.nodes);
+			assert!(stats.max_depth >= last.max_depth);
+			assert!(stats.values_verified >= last.values_verified);
+			last = stats;
+		}
+	}
+
+	#[test]
+	fn verification_stats_grow_with_keys() {
+		check_stats_monotonicity::<ExtensionLayout>();
+		check_stats_monotonicity::<NoExtensionLayout>();
+	}
+
+	/// The last node on the path of `key`, holding its value, with its depth.
+	fn value_node<L: TrieLayout>(trie: &TrieDB<L>, key: &[u8]) -> (<L::Hash as Hasher>::Out, u32) {
+		let mut recorder = Recorder::new();
+		trie.get_with(key, &mut recorder).unwrap();
+		let record = recorder.drain().pop().unwrap();
+		(record.hash, record.depth)
+	}
+
+	fn check_partial_proof<L: TrieLayout>() {
+		let (mut db, root) = test_build_trie::<L>(&test_entries());
+		let keys: Vec<&'static [u8]> = vec![
+			b"alfa", b"bravo", b"cat", b"do", b"dog", b"doge", b"dop", b"horse", b"house",
+		];
+		let (items, full_proof, alfa_node, do_node) = {
+			let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+			let items: Vec<_> = keys.iter().map(|key| (*key, trie.get(key).unwrap())).collect();
+			let full_proof = generate_proof_with_omissions::<_, L, _, _>(&trie, &keys, true).unwrap();
+			(items, full_proof, value_node(&trie, b"alfa"), value_node(&trie, b"do"))
+		};
+		// Nothing is omitted from a complete database.
+		assert!(full_proof.omitted.is_empty());
+		assert_eq!(
+			full_proof.proof,
+			generate_proof::<_, L, _, _>(&<TrieDB<L>>::new(&db, &root).unwrap(), &keys).unwrap(),
+		);
+
+		// Prune the leaf of "alfa" and the subtree of "do", holding "dog" and "doge".
+		HashDB::remove(&mut db, &alfa_node.0, EMPTY_PREFIX);
+		HashDB::remove(&mut db, &do_node.0, EMPTY_PREFIX);
+		let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+		match generate_proof::<_, L, _, _>(&trie, &keys).map_err(|error| *error) {
+			Err(TrieError::IncompleteDatabase { hash, .. }) => assert_eq!(hash, alfa_node.0),
+			result => panic!("unexpected generation result: {:?}", result),
+		}
+
+		let partial = generate_proof_with_omissions::<_, L, _, _>(&trie, &keys, true).unwrap();
+		let omitted = |key: &[u8], (missing_node, depth)| OmittedKey {
+			key: key.to_vec(),
+			missing_node,
+			depth,
+		};
+		assert_eq!(partial.omitted, vec![
+			omitted(b"alfa", alfa_node),
+			omitted(b"do", do_node),
+			omitted(b"dog", do_node),
+			omitted(b"doge", do_node),
+			omitted(b"dop", do_node),
+		]);
+
+		// The covered keys verify, present or absent.
+		let covered: Vec<_> = items.iter()
+			.filter(|(key, _)| partial.omitted.iter().all(|omitted| omitted.key != *key))
+			.cloned()
+			.collect();
+		assert_eq!(covered.iter().map(|(key, _)| *key).collect::<Vec<_>>(), vec![
+			&b"bravo"[..], b"cat", b"horse", b"house",
+		]);
+		verify_proof::<L, _, _, _>(&root, &partial.proof, covered.iter()).unwrap();
+
+		// Statements about omitted keys are rejected, whatever their value, at the hash of the
+		// first node left out on their path.
+		for (key, value) in [(&b"dog"[..], Some(b"puppy".to_vec())), (b"dop", None)] {
+			let mut statement = covered.clone();
+			statement.push((key, value));
+			match verify_proof::<L, _, _, _>(&root, &partial.proof, statement.iter()) {
+				Err(VerifyError::ExtraneousHashReference(_)) => (),
+				result => panic!("unexpected verification result: {:?}", result),
+			}
+		}
+	}
+
+	#[test]
+	fn partial_proof_with_ext() {
+		check_partial_proof::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn partial_proof_without_ext() {
+		check_partial_proof::<NoExtensionLayout>();
+	}
+
+	fn test_build_trie<L: TrieLayout>(
+		entries: &[(&'static [u8], &'static [u8])],
+	) -> (MemoryDB<L::Hash>, <L::Hash as Hasher>::Out) {
+		let mut db = <MemoryDB<L::Hash>>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = <TrieDBMut<L>>::new(&mut db, &mut root);
+			for (key, value) in entries.iter() {
+				trie.insert(key, value).unwrap();
+			}
+		}
+		(db, root)
+	}
+
+	fn test_statement<L: TrieLayout>(
+		db: &MemoryDB<L::Hash>,
+		root: &<L::Hash as Hasher>::Out,
+		keys: &[Vec<u8>],
+	) -> (<L::Hash as Hasher>::Out, Vec<(Vec<u8>, Option<Vec<u8>>)>) {
+		let trie = <TrieDB<L>>::new(db, root).unwrap();
+		let items = keys.iter()
+			.map(|key| (key.clone(), trie.get(key).unwrap()))
+			.collect();
+		(*root, items)
+	}
+
+	#[test]
+	fn multi_proof_overlapping_tries() {
+		let entries = test_entries();
+		// the child trie shares every subtrie but "horse" and "house" with the parent.
+		let (parent_db, parent_root) = test_build_trie::<ExtensionLayout>(&entries);
+		let (child_db, child_root) = test_build_trie::<ExtensionLayout>(&entries[..5]);
+
+		let parent_keys = vec![b"doge".to_vec(), b"horse".to_vec(), b"dog".to_vec()];
+		let child_keys = vec![b"doge".to_vec(), b"alfa".to_vec(), b"halp".to_vec()];
+		let proof = generate_multi_proof::<ExtensionLayout>(&[
+			(parent_root, parent_keys.clone(), &parent_db as &dyn HashDBRef<_, _>),
+			(child_root, child_keys.clone(), &child_db as &dyn HashDBRef<_, _>),
+		]).unwrap();
+		let single = generate_multi_proof::<ExtensionLayout>(&[
+			(child_root, child_keys.clone(), &child_db as &dyn HashDBRef<_, _>),
+		]).unwrap();
+		assert!(proof.nodes.len() < single.nodes.len() + 4);
+		assert_eq!(proof.roots.len(), 2);
+
+		let statements = vec![
+			test_statement::<ExtensionLayout>(&parent_db, &parent_root, &parent_keys),
+			test_statement::<ExtensionLayout>(&child_db, &child_root, &child_keys),
+		];
+		verify_multi_proof::<ExtensionLayout>(&proof, &statements).unwrap();
+
+		// the child statement alone does not use every node.
+		match verify_multi_proof::<ExtensionLayout>(&proof, &statements[1..]) {
+			Err(VerifyError::ExtraneousNode) => {}
+			result => panic!("expected VerifyError::ExtraneousNode, got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn multi_proof_disjoint_tries() {
+		let entries = test_entries();
+		let (db1, root1) = test_build_trie::<NoExtensionLayout>(&entries[..2]);
+		let (db2, root2) = test_build_trie::<NoExtensionLayout>(&entries[5..]);
+		let keys1 = vec![b"alfa".to_vec(), b"bravo".to_vec()];
+		let keys2 = vec![b"horse".to_vec(), b"do".to_vec()];
+
+		let proof = generate_multi_proof::<NoExtensionLayout>(&[
+			(root1, keys1.clone(), &db1 as &dyn HashDBRef<_, _>),
+			(root2, keys2.clone(), &db2 as &dyn HashDBRef<_, _>),
+		]).unwrap();
+		let mut statements = vec![
+			test_statement::<NoExtensionLayout>(&db1, &root1, &keys1),
+			test_statement::<NoExtensionLayout>(&db2, &root2, &keys2),
+		];
+		verify_multi_proof::<NoExtensionLayout>(&proof, &statements).unwrap();
+		// the keys of a statement can be in any order.
+		let mut reordered = statements.clone();
+		reordered[0].1.reverse();
+		verify_multi_proof::<NoExtensionLayout>(&proof, &reordered).unwrap();
+
+		statements[1].1[1].1 = Some(b"verb".to_vec());
+		match verify_multi_proof::<NoExtensionLayout>(&proof, &statements) {
+			Err(VerifyError::ValueMismatch(key)) => assert_eq!(key, b"do".to_vec()),
+			result => panic!("expected VerifyError::ValueMismatch, got {:?}", result),
+		}
+
+		let mut incomplete = proof.clone();
+		incomplete.nodes.pop();
+		statements[1].1[1].1 = None;
+		match verify_multi_proof::<NoExtensionLayout>(&incomplete, &statements) {
+			Err(VerifyError::IncompleteProof) => {}
+			result => panic!("expected VerifyError::IncompleteProof, got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn multi_proof_rejects_other_roots() {
+		let entries = test_entries();
+		let (db1, root1) = test_build_trie::<NoExtensionLayout>(&entries[..2]);
+		let (db2, root2) = test_build_trie::<NoExtensionLayout>(&entries[..3]);
+		let keys = vec![b"alfa".to_vec()];
+		let proof = generate_multi_proof::<NoExtensionLayout>(&[
+			(root1, keys.clone(), &db1 as &dyn HashDBRef<_, _>),
+		]).unwrap();
+
+		// "alfa" has the same value under root2, whose nodes are not in the proof.
+		let statements = vec![test_statement::<NoExtensionLayout>(&db2, &root2, &keys)];
+		match verify_multi_proof::<NoExtensionLayout>(&proof, &statements) {
+			Err(VerifyError::UnknownRoot(root)) => assert_eq!(root, root2),
+			result => panic!("expected VerifyError::UnknownRoot, got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn multi_proof_rejects_other_keys() {
+		let entries = test_entries();
+		let (db, root) = test_build_trie::<NoExtensionLayout>(&entries[..2]);
+		let keys = vec![b"alfa".to_vec(), b"bravo".to_vec()];
+		let proof = generate_multi_proof::<NoExtensionLayout>(&[
+			(root, keys.clone(), &db as &dyn HashDBRef<_, _>),
+		]).unwrap();
+
+		let other_keys = [
+			vec![b"alfa".to_vec()],
+			vec![b"alfa".to_vec(), b"bravo".to_vec(), b"charlie".to_vec()],
+			vec![b"alfa".to_vec(), b"alfa".to_vec()],
+		];
+		for keys in other_keys.iter() {
+			let statements = vec![test_statement::<NoExtensionLayout>(&db, &root, keys)];
+			match verify_multi_proof::<NoExtensionLayout>(&proof, &statements) {
+				Err(VerifyError::KeysMismatch(mismatch)) => assert_eq!(mismatch, root),
+				result => panic!("expected VerifyError::KeysMismatch, got {:?}", result),
+			}
+		}
+	}
+
+	#[cfg(feature = "codec")]
+	#[test]
+	fn multi_proof_codec() {
+		use codec::{Decode, Encode};
+		use reference_trie::proof::MultiProof;
+
+		let (db, root) = test_build_trie::<ExtensionLayout>(&test_entries());
+		let proof = generate_multi_proof::<ExtensionLayout>(&[
+			(root, vec![b"dog".to_vec()], &db as &dyn HashDBRef<_, _>),
+		]).unwrap();
+		let encoded = proof.encode();
+		let decoded = <MultiProof<ExtensionLayout>>::decode(&mut &encoded[..]).unwrap();
+		assert_eq!(decoded, proof);
+	}
 }
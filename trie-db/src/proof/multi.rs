@@ -0,0 +1,201 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proofs of statements over multiple tries sharing a single node set.
+//!
+//! Unlike the compact proofs of this module, the nodes of a `MultiProof` are included
+//! unmodified, so that nodes common to several tries (for instance a child trie and its
+//! parent) are only included once.
+
+use crate::rstd::vec::Vec;
+use crate::{
+	CError, DBValue, HashDBRef, Recorder, Result as TrieResult, Trie, TrieDB, TrieError,
+	TrieHash, TrieLayout,
+};
+use hash_db::{Hasher, Prefix};
+use hashbrown::{HashMap, HashSet};
+use super::VerifyError;
+
+/// A proof of key-value pairs in several tries, with their nodes deduplicated.
+pub struct MultiProof<L: TrieLayout> {
+	/// Encoded trie nodes needed to lookup every key, without duplicates.
+	pub nodes: Vec<Vec<u8>>,
+	/// The roots of the proven tries with the list of keys looked up in each of them.
+	pub roots: Vec<(TrieHash<L>, Vec<Vec<u8>>)>,
+}
+
+// Manual implementations, deriving would require the bounds on the layout itself.
+impl<L: TrieLayout> Clone for MultiProof<L> {
+	fn clone(&self) -> Self {
+		MultiProof {
+			nodes: self.nodes.clone(),
+			roots: self.roots.clone(),
+		}
+	}
+}
+
+impl<L: TrieLayout> PartialEq for MultiProof<L> {
+	fn eq(&self, other: &Self) -> bool {
+		self.nodes == other.nodes && self.roots == other.roots
+	}
+}
+
+impl<L: TrieLayout> Eq for MultiProof<L> { }
+
+#[cfg(feature = "std")]
+impl<L: TrieLayout> crate::rstd::fmt::Debug for MultiProof<L> {
+	fn fmt(&self, f: &mut crate::rstd::fmt::Formatter) -> crate::rstd::fmt::Result {
+		f.debug_struct("MultiProof")
+			.field("nodes", &self.nodes)
+			.field("roots", &self.roots)
+			.finish()
+	}
+}
+
+/// Generate a proof for keys spread over several tries. Each item is a trie root, the keys
+/// to prove in this trie and the database holding its nodes.
+pub fn generate_multi_proof<L: TrieLayout>(
+	items: &[(TrieHash<L>, Vec<Vec<u8>>, &dyn HashDBRef<L::Hash, DBValue>)],
+) -> TrieResult<MultiProof<L>, TrieHash<L>, CError<L>> {
+	let mut seen = HashSet::new();
+	let mut nodes = Vec::new();
+	let mut roots = Vec::with_capacity(items.len());
+	for (root, keys, db) in items {
+		let trie = <TrieDB<L>>::new(*db, root)?;
+		let mut recorder = Recorder::new();
+		for key in keys {
+			trie.get_with(key, &mut recorder)?;
+		}
+		for record in recorder.drain() {
+			if seen.insert(record.hash) {
+				nodes.push(record.data);
+			}
+		}
+		roots.push((*root, keys.clone()));
+	}
+	Ok(MultiProof { nodes, roots })
+}
+
+/// Verify a `MultiProof` against a set of statements. Each statement is a trie root with
+/// the expected values of keys in this trie, `None` meaning the key is absent.
+///
+/// All statements are checked against a single map of the proof nodes, and every node of
+/// the proof must be used by at least one of the statements. Each statement must be about a root
+/// of the proof and the keys listed for it, in any order.
+pub fn verify_multi_proof<L: TrieLayout>(
+	proof: &MultiProof<L>,
+	statements: &[(TrieHash<L>, Vec<(Vec<u8>, Option<Vec<u8>>)>)],
+) -> Result<(), VerifyError<TrieHash<L>, CError<L>>> {
+	let mut nodes = ProofNodes::<L::Hash>(HashMap::with_capacity(proof.nodes.len()));
+	for node in proof.nodes.iter() {
+		if nodes.0.insert(L::Hash::hash(node), node.clone()).is_some() {
+			return Err(VerifyError::ExtraneousNode);
+		}
+	}
+
+	let mut used = HashSet::with_capacity(nodes.0.len());
+	for (root, items) in statements {
+		let mut keys = items.iter().map(|(key, _)| key).collect::<Vec<_>>();
+		keys.sort();
+		let mut roots = proof.roots.iter().filter(|(proof_root, _)| proof_root == root).peekable();
+		if roots.peek().is_none() {
+			return Err(VerifyError::UnknownRoot(*root));
+		}
+		let listed = roots.any(|(_, proof_keys)| {
+			let mut proof_keys = proof_keys.iter().collect::<Vec<_>>();
+			proof_keys.sort();
+			proof_keys == keys
+		});
+		if !listed {
+			return Err(VerifyError::KeysMismatch(*root));
+		}
+
+		let trie = <TrieDB<L>>::new(&nodes, root).map_err(|e| map_trie_error(*e))?;
+		let mut recorder = Recorder::new();
+		for (key, expected) in items {
+			let value = trie.get_with(key, &mut recorder).map_err(|e| map_trie_error(*e))?;
+			if &value != expected {
+				return Err(VerifyError::ValueMismatch(key.clone()));
+			}
+		}
+		used.extend(recorder.drain().into_iter().map(|record| record.hash));
+	}
+
+	if used.len() != nodes.0.len() {
+		return Err(VerifyError::ExtraneousNode);
+	}
+	Ok(())
+}
+
+fn map_trie_error<HO, CE>(error: TrieError<HO, CE>) -> VerifyError<HO, CE> {
+	match error {
+		TrieError::InvalidStateRoot(_)
+		| TrieError::IncompleteDatabase { .. }
+		| TrieError::ValueAtIncompleteKey(..)
+		| TrieError::HashMismatch { .. }
+		| TrieError::ResumeRootMismatch { .. }
+		| TrieError::NodeProviderFailed(_)
+		| TrieError::InvalidValueChunks(_)
+		| TrieError::InvalidValueTag(..) => VerifyError::IncompleteProof,
+		TrieError::DecoderError(_, error) => VerifyError::DecodeError(error),
+		TrieError::InvalidHash(_, data) => VerifyError::InvalidChildReference(data),
+	}
+}
+
+/// Proof nodes indexed by their hash.
+struct ProofNodes<H: Hasher>(HashMap<H::Out, DBValue>);
+
+impl<H: Hasher> HashDBRef<H, DBValue> for ProofNodes<H> {
+	fn get(&self, key: &H::Out, _prefix: Prefix) -> Option<DBValue> {
+		self.0.get(key).cloned()
+	}
+
+	fn contains(&self, key: &H::Out, _prefix: Prefix) -> bool {
+		self.0.contains_key(key)
+	}
+}
+
+#[cfg(feature = "codec")]
+mod codec_impl {
+	use super::MultiProof;
+	use crate::rstd::vec::Vec;
+	use crate::{TrieHash, TrieLayout};
+	use codec::{Compact, Decode, Encode, Error, Input, Output};
+
+	impl<L: TrieLayout> Encode for MultiProof<L> {
+		fn encode_to<T: Output>(&self, dest: &mut T) {
+			self.nodes.encode_to(dest);
+			Compact(self.roots.len() as u32).encode_to(dest);
+			for (root, keys) in self.roots.iter() {
+				dest.write(root.as_ref());
+				keys.encode_to(dest);
+			}
+		}
+	}
+
+	impl<L: TrieLayout> Decode for MultiProof<L> {
+		fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+			let nodes = <Vec<Vec<u8>>>::decode(input)?;
+			let nb_roots = <Compact<u32>>::decode(input)?.0 as usize;
+			let mut roots = Vec::new();
+			for _ in 0..nb_roots {
+				let mut root = <TrieHash<L>>::default();
+				input.read(root.as_mut())?;
+				let keys = <Vec<Vec<u8>>>::decode(input)?;
+				roots.push((root, keys));
+			}
+			Ok(MultiProof { nodes, roots })
+		}
+	}
+}
@@ -0,0 +1,70 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proofs that no key of a trie starts with a prefix, for instance after a `clear_prefix`.
+//!
+//! The proof is the compact proof of the absence of the prefix itself as a key. The lookup of
+//! the prefix stops where the path to it leaves the trie: inside the partial key of a node, at
+//! an empty child slot of a branch, or at the node found at the prefix. No key has the prefix
+//! in the first two cases, and in the last one only if the node has neither a value nor
+//! children, which happens for the empty trie alone.
+
+use crate::rstd::vec::Vec;
+use crate::{CError, Result as TrieResult, Trie, TrieDBIterator, TrieHash, TrieLayout};
+use super::{generate_proof, verify_proof_into_view, VerifyError};
+
+/// Generate a proof that no key of `trie` starts with `prefix`.
+///
+/// The proof is generated whether or not keys start with `prefix`, it only verifies if none
+/// does.
+pub fn generate_absence_of_prefix_proof<T, L>(
+	trie: &T,
+	prefix: &[u8],
+) -> TrieResult<Vec<Vec<u8>>, TrieHash<L>, CError<L>>
+	where
+		T: Trie<L>,
+		L: TrieLayout,
+{
+	generate_proof::<_, L, _, _>(trie, &[prefix])
+}
+
+/// Verify a proof generated by `generate_absence_of_prefix_proof` that no key of the trie
+/// with root `root` starts with `prefix`.
+///
+/// Returns `VerifyError::PrefixNotEmpty` with the key found if the proof holds a key starting
+/// with `prefix`, and `VerifyError::IncompleteProof` if the path to `prefix` continues past
+/// the nodes of the proof.
+pub fn verify_absence_of_prefix_proof<L: TrieLayout>(
+	root: &TrieHash<L>,
+	proof: &[Vec<u8>],
+	prefix: &[u8],
+) -> Result<(), VerifyError<TrieHash<L>, CError<L>>> {
+	let view = verify_proof_into_view::<L, _, _, Vec<u8>>(root, proof, &[(prefix, None)])
+		.map_err(|error| match error {
+			// The proof holds a value at the prefix itself.
+			VerifyError::ValueMismatch(key) | VerifyError::NonExistenceBranchHasValue(key) =>
+				VerifyError::PrefixNotEmpty(key),
+			error => error,
+		})?;
+	let trie = view.as_trie();
+	// The nodes of the view were decoded during verification, only a missing node can fail
+	// the iteration.
+	let mut iter = TrieDBIterator::new_prefixed(&trie, prefix)
+		.map_err(|_| VerifyError::IncompleteProof)?;
+	match iter.next() {
+		None => Ok(()),
+		Some(Ok((key, _))) => Err(VerifyError::PrefixNotEmpty(key)),
+		Some(Err(_)) => Err(VerifyError::IncompleteProof),
+	}
+}
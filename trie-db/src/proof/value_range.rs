@@ -0,0 +1,165 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proofs of a range of the bytes of a value, without the rest of the value.
+//!
+//! The proof holds the nodes on the path to the key as stored, from the root down to the node
+//! holding the value, and, for a value stored in chunks, the index of the chunks and the chunks
+//! holding the range, see `TrieLayout::MAX_VALUE_CHUNK`. A value node is held whole, and an
+//! inline value is part of its node. The verifier walks the nodes from the root, checking each
+//! against the hash its parent references, then the index and the chunks against their hashes.
+
+use hash_db::Hasher;
+use crate::node::{decode_hash, Value};
+use crate::rstd::{ops::Range, vec, vec::Vec};
+use crate::value_chunks::{decode_index, ChunkIndex};
+use crate::{CError, Result as TrieResult, TrieDB, TrieHash, TrieLayout};
+use super::absence::{walk_path, PathEnd};
+use super::VerifyError;
+
+/// A proof of a range of the bytes of the value at a key, see `TrieDB::prove_value_range`.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Clone, PartialEq, Eq)]
+pub struct ValueRangeProof {
+	/// The nodes fetched by hash on the path to the key, from the root, as stored.
+	pub nodes: Vec<Vec<u8>>,
+	/// The index of the chunks of the value, empty if the value is not stored in chunks.
+	pub index: Vec<u8>,
+	/// The chunks holding the range, in order, or the value node of a value stored apart in one
+	/// node. Empty for a value held by its node.
+	pub chunks: Vec<Vec<u8>>,
+}
+
+impl ValueRangeProof {
+	/// Prove the bytes `range` of the value at `key` in `trie`, returning `None` if `key` has
+	/// no value. The part of `range` past the end of the value is left out of the proof.
+	pub(crate) fn generate<L: TrieLayout>(
+		trie: &TrieDB<L>,
+		key: &[u8],
+		range: Range<usize>,
+	) -> TrieResult<Option<Self>, TrieHash<L>, CError<L>> {
+		let mut nodes = Vec::new();
+		let proof = trie.look_up_slot_with_path(key, &mut nodes, |slot, hash, prefix| {
+			let (index, chunks) = match Value::from_slot::<L>(slot, hash)? {
+				Value::Inline(_) => (Vec::new(), Vec::new()),
+				Value::Node(value_hash) => {
+					let (_, value) = trie.read_value_node(value_hash, hash, prefix)?;
+					(Vec::new(), vec![value])
+				},
+				Value::Chunked(index_hash) => {
+					let (index_hash, data) = trie.read_value_node(index_hash, hash, prefix)?;
+					let index =
+						decode_index::<L::Hash, _>(&index_hash, &data, L::MAX_VALUE_CHUNK)?;
+					let range = range.start.min(index.len)..range.end.min(index.len);
+					let mut chunks = Vec::new();
+					for i in index.chunks_of(range) {
+						let chunk_hash = index.chunks[i].as_ref();
+						chunks.push(trie.read_value_node(chunk_hash, index_hash, prefix)?.1);
+					}
+					(data, chunks)
+				},
+			};
+			Ok((index, chunks))
+		})?;
+		Ok(proof.map(|(index, chunks)| ValueRangeProof { nodes, index, chunks }))
+	}
+
+	/// Verify the proof of the bytes `range` of the value at `key` in the trie with root
+	/// `root`, returning these bytes.
+	///
+	/// The first node must hash to `root` and each other node to the reference of the node
+	/// above it, the last one holding the value slot of `key`. Returns
+	/// `VerifyError::ValueMismatch` if the nodes hold no value at `key` or a value slot with an
+	/// unknown tag, `VerifyError::ValueRangeOutOfBounds` if `range` is not within the value and
+	/// `VerifyError::ValueChunksMismatch` if the index or the chunks are not those of the value.
+	pub fn verify<L: TrieLayout>(
+		&self,
+		root: &TrieHash<L>,
+		key: &[u8],
+		range: Range<usize>,
+	) -> Result<Vec<u8>, VerifyError<TrieHash<L>, CError<L>>> {
+		let slot = match walk_path::<L>(root, &self.nodes, key)? {
+			PathEnd::Value { slot, nodes } if nodes == self.nodes.len() => slot,
+			PathEnd::Value { .. } => return Err(VerifyError::ExtraneousNode),
+			PathEnd::Diverged(_) => return Err(VerifyError::ValueMismatch(key.to_vec())),
+		};
+		let value = Value::from_slot::<L>(slot, *root)
+			.map_err(|_| VerifyError::ValueMismatch(key.to_vec()))?;
+		let value_hash = match value {
+			Value::Inline(value) => {
+				if !self.index.is_empty() || !self.chunks.is_empty() {
+					return Err(VerifyError::ExtraneousNode);
+				}
+				return value_range(value, range);
+			},
+			Value::Node(value_hash) => {
+				let value_hash = decode_hash::<L::Hash>(value_hash)
+					.ok_or_else(|| VerifyError::InvalidChildReference(value_hash.to_vec()))?;
+				if !self.index.is_empty() {
+					return Err(VerifyError::ExtraneousNode);
+				}
+				return match &self.chunks[..] {
+					[] => Err(VerifyError::IncompleteProof),
+					[value] if L::Hash::hash(value) == value_hash => value_range(value, range),
+					[_] => Err(VerifyError::ValueChunksMismatch),
+					_ => Err(VerifyError::ExtraneousNode),
+				};
+			},
+			Value::Chunked(index_hash) => decode_hash::<L::Hash>(index_hash)
+				.ok_or_else(|| VerifyError::InvalidChildReference(index_hash.to_vec()))?,
+		};
+
+		if self.index.is_empty() {
+			return Err(VerifyError::IncompleteProof);
+		}
+		if L::Hash::hash(&self.index) != value_hash {
+			return Err(VerifyError::ValueChunksMismatch);
+		}
+		let index = L::MAX_VALUE_CHUNK
+			.and_then(|chunk_size| ChunkIndex::<TrieHash<L>>::decode(&self.index, chunk_size))
+			.ok_or(VerifyError::ValueChunksMismatch)?;
+		if range.start > range.end || range.end > index.len {
+			return Err(VerifyError::ValueRangeOutOfBounds(index.len));
+		}
+		let chunks = index.chunks_of(range.clone());
+		if chunks.is_empty() && self.chunks.is_empty() {
+			return Ok(Vec::new());
+		}
+		if self.chunks.len() < chunks.len() {
+			return Err(VerifyError::IncompleteProof);
+		}
+		if self.chunks.len() > chunks.len() {
+			return Err(VerifyError::ExtraneousNode);
+		}
+		let start = index.chunk_range(chunks.start).start;
+		let mut bytes = Vec::with_capacity(range.len());
+		for (i, chunk) in chunks.zip(self.chunks.iter()) {
+			let valid = L::Hash::hash(chunk) == index.chunks[i]
+				&& chunk.len() == index.chunk_range(i).len();
+			if !valid {
+				return Err(VerifyError::ValueChunksMismatch);
+			}
+			bytes.extend_from_slice(chunk);
+		}
+		Ok(bytes[range.start - start..range.end - start].to_vec())
+	}
+}
+
+/// The bytes `range` of `value`.
+fn value_range<HO, CE>(value: &[u8], range: Range<usize>) -> Result<Vec<u8>, VerifyError<HO, CE>> {
+	match value.get(range) {
+		Some(bytes) => Ok(bytes.to_vec()),
+		None => Err(VerifyError::ValueRangeOutOfBounds(value.len())),
+	}
+}
@@ -13,20 +13,20 @@
 //! Verification of compact proofs for Merkle-Patricia tries.
 
 use crate::rstd::{
-	convert::TryInto, iter::Peekable, marker::PhantomData, result::Result, vec, vec::Vec,
+	convert::TryInto, fmt, iter::Peekable, marker::PhantomData, mem, result::Result, vec, vec::Vec,
 };
 use crate::{
-	CError, ChildReference, nibble::LeftNibbleSlice, nibble_ops::NIBBLE_LENGTH,
-	node::{Node, NodeHandle}, NodeCodec, TrieHash, TrieLayout,
+	CError, ChildReference, DBValue, HashDBRef, nibble::{Children, LeftNibbleSlice, NibbleOps},
+	node::{value_node_hash, value_slot, Node, NodeHandle}, NodeCodec, TrieDB, TrieHash, TrieLayout,
 };
-use hash_db::Hasher;
+use hash_db::{Hasher, Prefix};
+use hashbrown::{HashMap, HashSet};
 
 
 /// Errors that may occur during proof verification. Most of the errors types simply indicate that
 /// the proof is invalid with respect to the statement being verified, and the exact error type can
 /// be used for debugging.
-#[derive(PartialEq, Eq)]
-#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(PartialEq, Eq, Debug)]
 pub enum Error<HO, CE> {
 	/// The statement being verified contains multiple key-value pairs with the same key. The
 	/// parameter is the duplicated key.
@@ -36,12 +36,33 @@ pub enum Error<HO, CE> {
 	/// The proof contains at least one extraneous value which should have been omitted from the
 	/// proof.
 	ExtraneousValue(Vec<u8>),
-	/// The proof contains at least one extraneous hash reference the should have been omitted.
+	/// The proof references by hash a node it does not hold, with keys of the statement under
+	/// it: the node is missing from the proof or the keys are not covered by the proof.
 	ExtraneousHashReference(HO),
 	/// The proof contains an invalid child reference that exceeds the hash length.
 	InvalidChildReference(Vec<u8>),
 	/// The proof indicates that an expected value was not found in the trie.
 	ValueMismatch(Vec<u8>),
+	/// The statement claims a key is absent, but the proof holds a value for it in the branch node
+	/// at the key: the key is a prefix of other keys and has a value.
+	NonExistenceBranchHasValue(Vec<u8>),
+	/// The statement claims no key starts with a prefix, but the proof holds such a key. The
+	/// parameter is the key found.
+	PrefixNotEmpty(Vec<u8>),
+	/// The value proven at the key of an inner trie in its index trie is not a hash.
+	InvalidNestedRoot(Vec<u8>),
+	/// The divergence of an absence proof is not the one found in its nodes.
+	DivergenceMismatch,
+	/// The range of a value range proof is not within the value. The parameter is the length
+	/// of the value.
+	ValueRangeOutOfBounds(usize),
+	/// The index or the chunks of a value range proof are not those of the value.
+	ValueChunksMismatch,
+	/// A statement verified with a multi-root proof is about a root the proof does not list.
+	UnknownRoot(HO),
+	/// The keys of a statement verified with a multi-root proof are not the keys the proof lists
+	/// for its root. The parameter is the root.
+	KeysMismatch(HO),
 	/// The proof is missing trie nodes required to verify.
 	IncompleteProof,
 	/// The root hash computed from the proof is incorrect.
@@ -50,9 +71,8 @@ pub enum Error<HO, CE> {
 	DecodeError(CE),
 }
 
-#[cfg(feature = "std")]
-impl<HO: std::fmt::Debug, CE: std::error::Error> std::fmt::Display for Error<HO, CE> {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+impl<HO: fmt::Debug, CE: fmt::Display> fmt::Display for Error<HO, CE> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
 		match self {
 			Error::DuplicateKey(key) =>
 				write!(f, "Duplicate key in input statement: key={:?}", key),
@@ -67,13 +87,29 @@ impl<HO: std::fmt::Debug, CE: std::error::Error> std::fmt::Display for Error<HO,
 			Error::ExtraneousHashReference(hash) =>
 				write!(
 					f,
-					"Extraneous hash reference found in proof should have been omitted: hash={:?}",
+					"Proof references a node it does not hold above a proven key: hash={:?}",
 					hash
 				),
 			Error::InvalidChildReference(data) =>
 				write!(f, "Invalid child reference exceeds hash length: {:?}", data),
 			Error::ValueMismatch(key) =>
 				write!(f, "Expected value was not found in the trie: key={:?}", key),
+			Error::NonExistenceBranchHasValue(key) =>
+				write!(f, "Key claimed absent has a value in its branch node: key={:?}", key),
+			Error::PrefixNotEmpty(key) =>
+				write!(f, "Prefix claimed empty has a key: key={:?}", key),
+			Error::InvalidNestedRoot(value) =>
+				write!(f, "Value of the inner trie key is not a trie root: {:?}", value),
+			Error::DivergenceMismatch =>
+				write!(f, "Divergence of the absence proof is not the one of its nodes"),
+			Error::ValueRangeOutOfBounds(len) =>
+				write!(f, "Range is out of the bounds of the value: len={}", len),
+			Error::ValueChunksMismatch =>
+				write!(f, "Chunks of the value range proof are not those of the value"),
+			Error::UnknownRoot(root) =>
+				write!(f, "Root of the statement is not a root of the proof: {:?}", root),
+			Error::KeysMismatch(root) =>
+				write!(f, "Keys of the statement are not those of the proof: root={:?}", root),
 			Error::IncompleteProof =>
 				write!(f, "Proof is incomplete -- expected more nodes"),
 			Error::RootMismatch(hash) =>
@@ -94,10 +130,38 @@ impl<HO: std::fmt::Debug, CE: std::error::Error + 'static> std::error::Error for
 	}
 }
 
+/// Measures of a proof, gathered while verifying it.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VerifyStats {
+	/// Number of proof nodes.
+	pub nodes: u32,
+	/// Total size of the proof nodes, in bytes.
+	pub node_bytes: usize,
+	/// Number of values proven present.
+	pub values_verified: u32,
+	/// Total size of the values proven present, in bytes.
+	pub value_bytes: usize,
+	/// Number of nodes of the longest path from the root to a node of the proof, inline nodes
+	/// included.
+	pub max_depth: u32,
+}
+
+impl VerifyStats {
+	fn add_node(&mut self, node: &[u8], depth: usize) {
+		self.nodes += 1;
+		self.node_bytes += node.len();
+		self.add_depth(depth);
+	}
+
+	fn add_depth(&mut self, depth: usize) {
+		self.max_depth = self.max_depth.max(depth as u32);
+	}
+}
+
 struct StackEntry<'a, C: NodeCodec> {
 	/// The prefix is the nibble path to the node in the trie.
-	prefix: LeftNibbleSlice<'a>,
-	node: Node<'a>,
+	prefix: LeftNibbleSlice<'a, C::Nibble>,
+	node: Node<'a, C::Nibble>,
 	is_inline: bool,
 	/// The value associated with this trie node.
 	value: Option<&'a [u8]>,
@@ -109,23 +173,46 @@ struct StackEntry<'a, C: NodeCodec> {
 	_marker: PhantomData<C>,
 }
 
+// Not derived, as it would require `C: Clone`.
+impl<'a, C: NodeCodec> Clone for StackEntry<'a, C> {
+	fn clone(&self) -> Self {
+		StackEntry {
+			prefix: self.prefix,
+			node: self.node.clone(),
+			is_inline: self.is_inline,
+			value: self.value,
+			child_index: self.child_index,
+			children: self.children.clone(),
+			_marker: PhantomData,
+		}
+	}
+}
+
 impl<'a, C: NodeCodec> StackEntry<'a, C> {
-	fn new(node_data: &'a [u8], prefix: LeftNibbleSlice<'a>, is_inline: bool)
+	fn new(node_data: &'a [u8], prefix: LeftNibbleSlice<'a, C::Nibble>, is_inline: bool)
 		   -> Result<Self, Error<C::HashOut, C::Error>>
 	{
 		let node = C::decode(node_data)
 			.map_err(Error::DecodeError)?;
+		Ok(Self::from_node(node, prefix, is_inline))
+	}
+
+	fn from_node(
+		node: Node<'a, C::Nibble>,
+		prefix: LeftNibbleSlice<'a, C::Nibble>,
+		is_inline: bool,
+	) -> Self {
 		let children_len = match node {
 			Node::Empty | Node::Leaf(..) => 0,
 			Node::Extension(..) => 1,
-			Node::Branch(..) | Node::NibbledBranch(..) => NIBBLE_LENGTH,
+			Node::Branch(..) | Node::NibbledBranch(..) => C::Nibble::NIBBLE_LENGTH,
 		};
 		let value = match node {
 			Node::Empty | Node::Extension(_, _) => None,
 			Node::Leaf(_, value) => Some(value),
 			Node::Branch(_, value) | Node::NibbledBranch(_, _, value) => value,
 		};
-		Ok(StackEntry {
+		StackEntry {
 			node,
 			is_inline,
 			prefix,
@@ -133,7 +220,7 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 			child_index: 0,
 			children: vec![None; children_len],
 			_marker: PhantomData::default(),
-		})
+		}
 	}
 
 	/// Encode this entry to an encoded trie node with data properly reconstructed.
@@ -175,21 +262,18 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 		})
 	}
 
-	fn advance_child_index<I>(
+	/// Move to the child at `child_prefix`, returning its handle.
+	fn advance_child_index(
 		&mut self,
-		child_prefix: LeftNibbleSlice<'a>,
-		proof_iter: &mut I,
-	) -> Result<Self, Error<C::HashOut, C::Error>>
-		where
-			I: Iterator<Item=&'a Vec<u8>>,
-	{
+		child_prefix: LeftNibbleSlice<'a, C::Nibble>,
+	) -> Result<NodeHandle<'a>, Error<C::HashOut, C::Error>> {
 		match self.node {
 			Node::Extension(_, child) => {
 				// Guaranteed because of sorted keys order.
 				assert_eq!(self.child_index, 0);
-				Self::make_child_entry(proof_iter, child, child_prefix)
+				Ok(child)
 			}
-			Node::Branch(children, _) | Node::NibbledBranch(_, children, _) => {
+			Node::Branch(ref children, _) | Node::NibbledBranch(_, ref children, _) => {
 				// because this is a branch
 				assert!(child_prefix.len() > 0);
 				let child_index = child_prefix.at(child_prefix.len() - 1)
@@ -203,9 +287,7 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 					}
 					self.child_index += 1;
 				}
-				let child = children[self.child_index]
-					.expect("guaranteed by advance_item");
-				Self::make_child_entry(proof_iter, child, child_prefix)
+				Ok(children[self.child_index].expect("guaranteed by advance_item"))
 			}
 			_ => panic!("cannot have children"),
 		}
@@ -220,8 +302,8 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 				self.children[self.child_index] = Some(child_ref);
 				self.child_index += 1;
 			}
-			Node::Branch(children, _) | Node::NibbledBranch(_, children, _) => {
-				while self.child_index < NIBBLE_LENGTH {
+			Node::Branch(ref children, _) | Node::NibbledBranch(_, ref children, _) => {
+				while self.child_index < C::Nibble::NIBBLE_LENGTH {
 					if let Some(child) = children[self.child_index] {
 						let child_ref = child.try_into()
 							.map_err(Error::InvalidChildReference)?;
@@ -235,24 +317,13 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 		Ok(())
 	}
 
-	fn make_child_entry<I>(
-		proof_iter: &mut I,
+	/// Entry of a child which is not omitted from its parent node.
+	fn make_child_entry(
 		child: NodeHandle<'a>,
-		prefix: LeftNibbleSlice<'a>,
-	) -> Result<Self, Error<C::HashOut, C::Error>>
-		where
-			I: Iterator<Item=&'a Vec<u8>>,
-	{
+		prefix: LeftNibbleSlice<'a, C::Nibble>,
+	) -> Result<Self, Error<C::HashOut, C::Error>> {
 		match child {
-			NodeHandle::Inline(data) => {
-				if data.is_empty() {
-					let node_data = proof_iter.next()
-						.ok_or(Error::IncompleteProof)?;
-					StackEntry::new(node_data, prefix, false)
-				} else {
-					StackEntry::new(data, prefix, true)
-				}
-			}
+			NodeHandle::Inline(data) => StackEntry::new(data, prefix, true),
 			NodeHandle::Hash(data) => {
 				let mut hash = C::HashOut::default();
 				if data.len() != hash.as_ref().len() {
@@ -264,8 +335,9 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 		}
 	}
 
-	fn advance_item<I>(&mut self, items_iter: &mut Peekable<I>)
-					   -> Result<Step<'a>, Error<C::HashOut, C::Error>>
+	/// Match the items under this node, counting the values proven in `stats`.
+	fn advance_item<I>(&mut self, items_iter: &mut Peekable<I>, stats: &mut VerifyStats)
+					   -> Result<Step<'a, C::Nibble>, Error<C::HashOut, C::Error>>
 		where
 			I: Iterator<Item=(&'a [u8], Option<&'a [u8]>)>
 	{
@@ -288,9 +360,17 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 							},
 						ValueMatch::NotOmitted =>
 							return Err(Error::ExtraneousValue(key_bytes.to_vec())),
+						ValueMatch::BranchHasValue => return Err(match value {
+							Some(_) => Error::ExtraneousValue(key_bytes.to_vec()),
+							None => Error::NonExistenceBranchHasValue(key_bytes.to_vec()),
+						}),
 						ValueMatch::IsChild(child_prefix) =>
 							break Step::Descend(child_prefix),
 					}
+					if let Some(value) = value {
+						stats.values_verified += 1;
+						stats.value_bytes += value.len();
+					}
 
 					items_iter.next();
 					continue;
@@ -302,7 +382,7 @@ impl<'a, C: NodeCodec> StackEntry<'a, C> {
 	}
 }
 
-enum ValueMatch<'a> {
+enum ValueMatch<'a, N: NibbleOps> {
 	/// The key matches a leaf node, so the value at the key must be present.
 	MatchesLeaf,
 	/// The key matches a branch node, so the value at the key may or may not be present.
@@ -311,15 +391,20 @@ enum ValueMatch<'a> {
 	NotFound,
 	/// The key matches a location in trie, but the value was not omitted.
 	NotOmitted,
+	/// The key matches a branch node, but the value of the branch was not omitted. Unlike an
+	/// omitted value, it shows that the key has a value.
+	BranchHasValue,
 	/// The key may match below a child of this node. Parameter is the prefix of the child node.
-	IsChild(LeftNibbleSlice<'a>),
+	IsChild(LeftNibbleSlice<'a, N>),
 }
 
 /// Determines whether a node on the stack carries a value at the given key or whether any nodes
 /// in the subtrie do. The prefix of the node is given by the first `prefix_len` nibbles of `key`.
-fn match_key_to_node<'a>(key: &LeftNibbleSlice<'a>, prefix_len: usize, node: &Node)
-						 -> ValueMatch<'a>
-{
+fn match_key_to_node<'a, N: NibbleOps>(
+	key: &LeftNibbleSlice<'a, N>,
+	prefix_len: usize,
+	node: &Node<N>,
+) -> ValueMatch<'a, N> {
 	match node {
 		Node::Empty => ValueMatch::NotFound,
 		Node::Leaf(partial, value) => {
@@ -357,18 +442,21 @@ fn match_key_to_node<'a>(key: &LeftNibbleSlice<'a>, prefix_len: usize, node: &No
 /// Determines whether a branch node on the stack carries a value at the given key or whether any
 /// nodes in the subtrie do. The key of the branch node value is given by the first
 /// `prefix_plus_partial_len` nibbles of `key`.
-fn match_key_to_branch_node<'a>(
-	key: &LeftNibbleSlice<'a>,
+fn match_key_to_branch_node<'a, N: NibbleOps>(
+	key: &LeftNibbleSlice<'a, N>,
 	prefix_plus_partial_len: usize,
-	children: &[Option<NodeHandle>; NIBBLE_LENGTH],
+	children: &Children<NodeHandle, N>,
 	value: &Option<&[u8]>,
-) -> ValueMatch<'a>
+) -> ValueMatch<'a, N>
 {
 	if key.len() == prefix_plus_partial_len {
+		// The key ends at the branch, other keys having it as prefix. An omitted value and no
+		// value are both empty in the proof: the value of the statement fills the value slot,
+		// and the root computed tells whether the key is absent.
 		if value.is_none() {
 			ValueMatch::MatchesBranch
 		} else {
-			ValueMatch::NotOmitted
+			ValueMatch::BranchHasValue
 		}
 	} else {
 		let index = key.at(prefix_plus_partial_len)
@@ -382,19 +470,205 @@ fn match_key_to_branch_node<'a>(
 	}
 }
 
-enum Step<'a> {
-	Descend(LeftNibbleSlice<'a>),
+enum Step<'a, N: NibbleOps> {
+	Descend(LeftNibbleSlice<'a, N>),
 	UnwindStack,
 }
 
 /// Verify a compact proof for key-value pairs in a trie given a root hash.
+///
+/// The proof nodes may be in any order: each node is found by the hash of its encoding, which its
+/// parent holds as the reference to it, and the proof is walked once. The root node is the only
+/// node no other node references, and every node must be reached from it.
+///
+/// Returns the measures of the proof.
 pub fn verify_proof<'a, L, I, K, V>(root: &<L::Hash as Hasher>::Out, proof: &[Vec<u8>], items: I)
-									-> Result<(), Error<TrieHash<L>, CError<L>>>
+									-> Result<VerifyStats, Error<TrieHash<L>, CError<L>>>
+	where
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a (K, Option<V>)>,
+		K: 'a + AsRef<[u8]>,
+		V: 'a + AsRef<[u8]>,
+{
+	verify_proof_inner::<L, _, _, _, _, _>(proof, items, |computed| computed == root, |_, _| ())
+		.map(|(_, _, stats)| stats)
+}
+
+/// Verify a compact proof for key-value pairs against several candidate roots, returning the
+/// index of the first root matching the proof.
+///
+/// The proof nodes are only decoded once, whatever the number of candidates. As the root of a
+/// compact proof is computed from its nodes, the only reason for a candidate not to match is the
+/// root being different from the computed one, reported with `Error::RootMismatch` when none of
+/// the candidates match.
+pub fn verify_proof_multi_root<'a, L, I, K, V>(
+	roots: &[TrieHash<L>],
+	proof: &[Vec<u8>],
+	items: I,
+) -> Result<usize, Error<TrieHash<L>, CError<L>>>
+	where
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a (K, Option<V>)>,
+		K: 'a + AsRef<[u8]>,
+		V: 'a + AsRef<[u8]>,
+{
+	let accept = |computed: &TrieHash<L>| roots.contains(computed);
+	match verify_proof_inner::<L, _, _, _, _, _>(proof, items, accept, |_, _| ())?.0 {
+		Some(computed_root) => Ok(roots.iter()
+			.position(|root| *root == computed_root)
+			.expect("only roots in the candidates are accepted; qed")),
+		// Nothing to prove, any candidate matches.
+		None if !roots.is_empty() => Ok(0),
+		None => Err(Error::IncompleteProof),
+	}
+}
+
+/// Verify a compact proof like `verify_proof`, and return the trie nodes reconstructed
+/// during verification as a `VerifiedProof` that can be read like a trie.
+pub fn verify_proof_into_view<'a, L, I, K, V>(
+	root: &<L::Hash as Hasher>::Out,
+	proof: &[Vec<u8>],
+	items: I,
+) -> Result<VerifiedProof<L>, Error<TrieHash<L>, CError<L>>>
+	where
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a (K, Option<V>)>,
+		K: 'a + AsRef<[u8]>,
+		V: 'a + AsRef<[u8]>,
+{
+	let mut nodes = HashMap::new();
+	verify_proof_inner::<L, _, _, _, _, _>(proof, items, |computed| computed == root, |hash, node| {
+		nodes.insert(hash, node);
+	})?;
+	Ok(VerifiedProof { root: *root, nodes })
+}
+
+/// Returns true if `proof` is a valid proof of `items` against `root` with its nodes in the
+/// order of `generate_proof`, the order in which the lookups of the items reach them.
+pub fn is_canonical<'a, L, I, K, V>(
+	root: &<L::Hash as Hasher>::Out,
+	proof: &[Vec<u8>],
+	items: I,
+) -> bool
+	where
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a (K, Option<V>)>,
+		K: 'a + AsRef<[u8]>,
+		V: 'a + AsRef<[u8]>,
+{
+	match verify_proof_inner::<L, _, _, _, _, _>(proof, items, |computed| computed == root, |_, _| ()) {
+		Ok((_, order, _)) => order.iter().enumerate().all(|(position, index)| position == *index),
+		Err(_) => false,
+	}
+}
+
+/// Reorder the nodes of a valid proof of `items` against `root` in the order of
+/// `generate_proof`, returning the error of `verify_proof` if the proof is invalid.
+///
+/// Like `is_canonical`, this walks the proof through the items to find the order of the nodes.
+pub fn canonicalize<'a, L, I, K, V>(
+	root: &<L::Hash as Hasher>::Out,
+	proof: &[Vec<u8>],
+	items: I,
+) -> VerifyResult<Vec<Vec<u8>>, L>
 	where
 		L: TrieLayout,
 		I: IntoIterator<Item=&'a (K, Option<V>)>,
 		K: 'a + AsRef<[u8]>,
 		V: 'a + AsRef<[u8]>,
+{
+	let (_, order, _) = verify_proof_inner::<L, _, _, _, _, _>(
+		proof,
+		items,
+		|computed| computed == root,
+		|_, _| (),
+	)?;
+	Ok(order.into_iter().map(|index| proof[index].clone()).collect())
+}
+
+/// The trie nodes reconstructed from a verified proof.
+///
+/// The nodes are indexed by hash and can be accessed through a `TrieDB` with `as_trie`.
+/// Lookups or iterations leaving the part of the trie covered by the proof return
+/// `TrieError::IncompleteDatabase`.
+pub struct VerifiedProof<L: TrieLayout> {
+	root: TrieHash<L>,
+	nodes: HashMap<TrieHash<L>, DBValue>,
+}
+
+impl<L: TrieLayout> VerifiedProof<L> {
+	/// Root of the verified trie.
+	pub fn root(&self) -> &TrieHash<L> {
+		&self.root
+	}
+
+	/// Number of nodes of the trie available through this proof.
+	pub fn len(&self) -> usize {
+		self.nodes.len()
+	}
+
+	/// Returns true if the proof did not contain any node.
+	pub fn is_empty(&self) -> bool {
+		self.nodes.is_empty()
+	}
+
+	/// Read only trie view over the proof nodes.
+	pub fn as_trie(&self) -> TrieDB<'_, L> {
+		TrieDB::new_unchecked(self, &self.root)
+	}
+}
+
+// Manual implementations, deriving would require the bounds on the layout itself.
+impl<L: TrieLayout> Clone for VerifiedProof<L> {
+	fn clone(&self) -> Self {
+		VerifiedProof { root: self.root, nodes: self.nodes.clone() }
+	}
+}
+
+#[cfg(feature = "std")]
+impl<L: TrieLayout> fmt::Debug for VerifiedProof<L> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("VerifiedProof")
+			.field("root", &self.root)
+			.field("nodes", &self.nodes)
+			.finish()
+	}
+}
+
+impl<L: TrieLayout> HashDBRef<L::Hash, DBValue> for VerifiedProof<L> {
+	fn get(&self, key: &TrieHash<L>, _prefix: Prefix) -> Option<DBValue> {
+		self.nodes.get(key).cloned()
+	}
+
+	fn contains(&self, key: &TrieHash<L>, _prefix: Prefix) -> bool {
+		self.nodes.contains_key(key)
+	}
+}
+
+type VerifyResult<T, L> = Result<T, Error<TrieHash<L>, CError<L>>>;
+
+/// Root computed, order of the nodes and measures of a verified proof.
+type Verified<L> = (Option<TrieHash<L>>, Vec<usize>, VerifyStats);
+
+/// Verify the proof structure and compute the root it proves, `None` if there is no item.
+///
+/// The proof nodes are found by the hash their parent references them with, see `ProofNodes`,
+/// whatever their order, the root found being accepted by `accept`. `on_node` is only called
+/// once the root is accepted. Returns the root, the order of the nodes, as indices of the nodes
+/// in `proof`, and the measures of the proof.
+fn verify_proof_inner<'a, L, I, K, V, A, F>(
+	proof: &[Vec<u8>],
+	items: I,
+	accept: A,
+	mut on_node: F,
+) -> VerifyResult<Verified<L>, L>
+	where
+		L: TrieLayout,
+		I: IntoIterator<Item=&'a (K, Option<V>)>,
+		K: 'a + AsRef<[u8]>,
+		V: 'a + AsRef<[u8]>,
+		A: Fn(&TrieHash<L>) -> bool,
+		F: FnMut(TrieHash<L>, Vec<u8>),
 {
 	// Sort items.
 	let mut items = items.into_iter()
@@ -404,7 +678,7 @@ pub fn verify_proof<'a, L, I, K, V>(root: &<L::Hash as Hasher>::Out, proof: &[Ve
 
 	if items.is_empty() {
 		return if proof.is_empty() {
-			Ok(())
+			Ok((None, Vec::new(), VerifyStats::default()))
 		} else {
 			Err(Error::ExtraneousNode)
 		};
@@ -417,69 +691,200 @@ pub fn verify_proof<'a, L, I, K, V>(root: &<L::Hash as Hasher>::Out, proof: &[Ve
 		}
 	}
 
-	// Iterate simultaneously in order through proof nodes and key-value pairs to verify.
-	let mut proof_iter = proof.iter();
-	let mut items_iter = items.into_iter().peekable();
+	// The values are matched against the value slots of the nodes, which hold the hash of the
+	// values stored apart, see `TrieLayout::MAX_INLINE_VALUE`.
+	let slots = items.iter()
+		.map(|(_, value)| value.and_then(|value| {
+			value_slot::<L>(value, value_node_hash::<L>(value).as_ref())
+		}))
+		.collect::<Vec<_>>();
+	let value_bytes = items.iter().filter_map(|(_, value)| *value).map(<[u8]>::len).sum();
+	let items = items.iter()
+		.zip(&slots)
+		.map(|(&(key, value), slot)| (key, slot.as_deref().or(value)))
+		.collect::<Vec<_>>();
+	let mut nodes = ProofNodes::<L>::new(proof)?;
+	let root_index = nodes.take_root()?;
+	// The nodes reconstructed, given to `on_node` once the root is accepted.
+	let mut reconstructed = Vec::new();
+	let mut walk = Walk::<L, _>::new(
+		&proof[root_index],
+		nodes.decoded[root_index].clone(),
+		items.iter().cloned(),
+	);
+	let computed_root = walk.run(&mut nodes, &mut |hash, node| reconstructed.push((hash, node)))?;
+	if nodes.order.len() != proof.len() {
+		return Err(Error::ExtraneousNode);
+	}
+	if !accept(&computed_root) {
+		return Err(Error::RootMismatch(computed_root));
+	}
+	reconstructed.into_iter().for_each(|(hash, node)| on_node(hash, node));
+	let mut stats = walk.stats;
+	stats.value_bytes = value_bytes;
+	Ok((Some(computed_root), nodes.order, stats))
+}
 
-	// A stack of child references to fill in omitted branch children for later trie nodes in the
-	// proof.
-	let mut stack: Vec<StackEntry<L::Codec>> = Vec::new();
+/// The nodes of a proof, decoded, found by the hash of their encoding: the reference their
+/// parent node holds to them, see `generate_proof`.
+struct ProofNodes<'a, L: TrieLayout> {
+	proof: &'a [Vec<u8>],
+	decoded: Vec<Node<'a, L::Nibble>>,
+	hashes: Vec<TrieHash<L>>,
+	/// Indices of the nodes not taken yet by hash, the last one first. Nodes with the same
+	/// encoding share a hash and are taken in the order of the proof.
+	left: HashMap<TrieHash<L>, Vec<usize>>,
+	/// Indices of the nodes taken, in the order of `generate_proof`.
+	order: Vec<usize>,
+}
 
-	let root_node = match proof_iter.next() {
-		Some(node) => node,
-		None => return Err(Error::IncompleteProof),
-	};
-	let mut last_entry = StackEntry::new(
-		root_node,
-		LeftNibbleSlice::new(&[]),
-		false
-	)?;
-	loop {
-		// Insert omitted value.
-		match last_entry.advance_item(&mut items_iter)? {
-			Step::Descend(child_prefix) => {
-				let next_entry = last_entry.advance_child_index(child_prefix, &mut proof_iter)?;
-				stack.push(last_entry);
-				last_entry = next_entry;
+impl<'a, L: TrieLayout> ProofNodes<'a, L> {
+	fn new(proof: &'a [Vec<u8>]) -> VerifyResult<Self, L> {
+		let decoded = proof.iter()
+			.map(|node| L::Codec::decode(node).map_err(Error::DecodeError))
+			.collect::<Result<Vec<_>, _>>()?;
+		let hashes = proof.iter().map(|node| L::Hash::hash(node)).collect::<Vec<_>>();
+		let mut left = HashMap::<_, Vec<_>>::with_capacity(proof.len());
+		for (index, hash) in hashes.iter().enumerate().rev() {
+			left.entry(*hash).or_default().push(index);
+		}
+		Ok(ProofNodes { proof, decoded, hashes, left, order: Vec::with_capacity(proof.len()) })
+	}
+
+	/// Take the node referenced by `hash`, if it is in the proof and not taken yet.
+	fn take(&mut self, hash: &[u8]) -> Option<usize> {
+		let mut key = <TrieHash<L>>::default();
+		if key.as_ref().len() != hash.len() {
+			return None;
+		}
+		key.as_mut().copy_from_slice(hash);
+		let index = self.left.get_mut(&key)?.pop()?;
+		self.order.push(index);
+		Some(index)
+	}
+
+	/// Take the root node, the only node no other node of the proof references.
+	fn take_root(&mut self) -> VerifyResult<usize, L> {
+		let mut referenced = HashSet::new();
+		for node in self.decoded.iter() {
+			match node {
+				Node::Extension(_, NodeHandle::Hash(hash)) => {
+					referenced.insert(*hash);
+				}
+				Node::Branch(children, _) | Node::NibbledBranch(_, children, _) =>
+					referenced.extend(children.iter().filter_map(|child| match child {
+						Some(NodeHandle::Hash(hash)) => Some(*hash),
+						_ => None,
+					})),
+				_ => (),
 			}
-			Step::UnwindStack => {
-				let is_inline = last_entry.is_inline;
-				let node_data = last_entry.encode_node()?;
+		}
+		let mut roots = self.hashes.iter().filter(|hash| !referenced.contains(hash.as_ref()));
+		let root = *roots.next().ok_or(Error::IncompleteProof)?;
+		if roots.next().is_some() {
+			return Err(Error::ExtraneousNode);
+		}
+		Ok(self.take(root.as_ref()).expect("the root is a node of the proof; qed"))
+	}
+}
 
-				let child_ref = if is_inline {
-					if node_data.len() > L::Hash::LENGTH {
-						return Err(Error::InvalidChildReference(node_data));
-					}
-					let mut hash = <TrieHash<L>>::default();
-					&mut hash.as_mut()[..node_data.len()].copy_from_slice(node_data.as_ref());
-					ChildReference::Inline(hash, node_data.len())
-				} else {
-					let hash = L::Hash::hash(&node_data);
-					ChildReference::Hash(hash)
-				};
-
-				if let Some(entry) = stack.pop() {
-					last_entry = entry;
-					last_entry.children[last_entry.child_index] = Some(child_ref);
-					last_entry.child_index += 1;
-				} else {
-					if proof_iter.next().is_some() {
-						return Err(Error::ExtraneousNode);
-					}
-					let computed_root = match child_ref {
-						ChildReference::Hash(hash) => hash,
-						ChildReference::Inline(_, _) => panic!(
-							"the bottom item on the stack has is_inline = false; qed"
+/// Verification of a proof in progress, walking through the items from the root node.
+struct Walk<'a, L: TrieLayout, I: Iterator> {
+	items_iter: Peekable<I>,
+	/// A stack of child references to fill in the references to the other proof nodes with the
+	/// hashes of the trie nodes reconstructed.
+	stack: Vec<StackEntry<'a, L::Codec>>,
+	last_entry: StackEntry<'a, L::Codec>,
+	/// Measures of the nodes and items walked through.
+	stats: VerifyStats,
+}
+
+impl<'a, L, I> Walk<'a, L, I>
+	where
+		L: TrieLayout,
+		I: Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+{
+	fn new(root_data: &'a [u8], root_node: Node<'a, L::Nibble>, items_iter: I) -> Self {
+		let mut stats = VerifyStats::default();
+		stats.add_node(root_data, 1);
+		Walk {
+			items_iter: items_iter.peekable(),
+			stack: Vec::new(),
+			last_entry: StackEntry::from_node(root_node, LeftNibbleSlice::new(&[]), false),
+			stats,
+		}
+	}
+
+	/// Walk through the items, taking the proof nodes referenced on their paths from `nodes`, and
+	/// compute the root.
+	fn run<F>(
+		&mut self,
+		nodes: &mut ProofNodes<'a, L>,
+		on_node: &mut F,
+	) -> VerifyResult<TrieHash<L>, L>
+		where F: FnMut(TrieHash<L>, Vec<u8>),
+	{
+		loop {
+			// Insert omitted value.
+			match self.last_entry.advance_item(&mut self.items_iter, &mut self.stats)? {
+				Step::Descend(child_prefix) => {
+					let child = self.last_entry.advance_child_index(child_prefix)?;
+					let taken = match child {
+						NodeHandle::Hash(hash) => nodes.take(hash),
+						// A child left out of an older proof, without reference.
+						NodeHandle::Inline(&[]) => return Err(Error::IncompleteProof),
+						NodeHandle::Inline(_) => None,
+					};
+					let entry = match taken {
+						Some(index) => StackEntry::from_node(
+							nodes.decoded[index].clone(),
+							child_prefix,
+							false,
 						),
+						None => StackEntry::make_child_entry(child, child_prefix)?,
+					};
+					self.stack.push(mem::replace(&mut self.last_entry, entry));
+					let depth = self.stack.len() + 1;
+					match taken {
+						Some(index) => self.stats.add_node(&nodes.proof[index], depth),
+						None => self.stats.add_depth(depth),
+					}
+				}
+				Step::UnwindStack => {
+					let parent = self.stack.pop();
+					let is_root = parent.is_none();
+					let entry = match parent {
+						Some(parent) => mem::replace(&mut self.last_entry, parent),
+						None => self.last_entry.clone(),
+					};
+					let is_inline = entry.is_inline;
+					let node_data = entry.encode_node()?;
+
+					let child_ref = if is_inline {
+						if node_data.len() > L::Hash::LENGTH {
+							return Err(Error::InvalidChildReference(node_data));
+						}
+						let mut hash = <TrieHash<L>>::default();
+						&mut hash.as_mut()[..node_data.len()].copy_from_slice(node_data.as_ref());
+						ChildReference::Inline(hash, node_data.len())
+					} else {
+						let hash = L::Hash::hash(&node_data);
+						on_node(hash, node_data);
+						ChildReference::Hash(hash)
 					};
-					if computed_root != *root {
-						return Err(Error::RootMismatch(computed_root));
+
+					if is_root {
+						return match child_ref {
+							ChildReference::Hash(hash) => Ok(hash),
+							ChildReference::Inline(_, _) => panic!(
+								"the bottom item on the stack has is_inline = false; qed"
+							),
+						};
 					}
-					break;
+					self.last_entry.children[self.last_entry.child_index] = Some(child_ref);
+					self.last_entry.child_index += 1;
 				}
 			}
 		}
 	}
-
-	Ok(())
-}
\ No newline at end of file
+}
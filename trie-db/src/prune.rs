@@ -0,0 +1,573 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Nodes of an old trie which are no longer used by newer tries.
+//!
+//! `nodes_to_prune` walks the trie of an old root and yields its nodes which are not part of
+//! the tries of a set of retained roots, so they can be removed from the database. A node is
+//! identified by its hash and its prefix, as when removed by `TrieDBMut`.
+//!
+//! The retained tries are descended along the path of the walk, so a subtree shared with a
+//! retained trie is skipped as soon as it is met, and only the nodes of the retained tries on
+//! the paths to the nodes yielded are fetched. The result is exact: a node reachable from a
+//! retained root at the same prefix is never yielded.
+//!
+//! In layouts with value nodes, the value node of each walked node follows it, or the chunks of
+//! its value and their index, unless the node of a retained trie at the same prefix references
+//! the same value node or chunk.
+
+use hash_db::{HashDBRef, EMPTY_PREFIX};
+pub use hash_db::OwnedPrefix;
+use hashbrown::HashSet;
+use crate::nibble::NibbleVec;
+use crate::node::{NodeHandle, NodePlan, OwnedNode, Value, decode_hash};
+use crate::rstd::{boxed::Box, rc::Rc, vec::Vec};
+use crate::value_chunks::value_nodes;
+use crate::{CError, DBValue, Result, TrieDB, TrieError, TrieHash, TrieLayout};
+
+/// A node to prune, with its prefix.
+pub type PrunedNode<L> = (TrieHash<L>, OwnedPrefix);
+
+/// Iterate over the nodes of the trie of `old_root` which are not in the tries of
+/// `retained_roots`, in pre-order, with their prefix.
+///
+/// The root of the empty trie is yielded as any other root. The iteration stops after the
+/// first error, a missing node of a retained trie included, as the nodes still in use could
+/// not be told apart.
+pub fn nodes_to_prune<'a, L: TrieLayout>(
+	db: &'a dyn HashDBRef<L::Hash, DBValue>,
+	old_root: &TrieHash<L>,
+	retained_roots: &[TrieHash<L>],
+) -> NodesToPrune<'a, L> {
+	NodesToPrune {
+		db,
+		old_root: *old_root,
+		retained_roots: retained_roots.to_vec(),
+		stack: Vec::new(),
+		value_nodes: Vec::new(),
+		state: State::NotStarted,
+	}
+}
+
+/// Iterator over the nodes to prune, see `nodes_to_prune`.
+pub struct NodesToPrune<'a, L: TrieLayout> {
+	db: &'a dyn HashDBRef<L::Hash, DBValue>,
+	old_root: TrieHash<L>,
+	retained_roots: Vec<TrieHash<L>>,
+	stack: Vec<Frame<L>>,
+	/// Value nodes of the last node yielded, yielded next from the end.
+	value_nodes: Vec<PrunedNode<L>>,
+	state: State,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+	NotStarted,
+	Walking,
+	Done,
+}
+
+/// A node of a trie with the prefix of its children.
+struct Frame<L: TrieLayout> {
+	node: Rc<OwnedNode<DBValue, L::Nibble>>,
+	/// Hash of the node, or of the node holding it when inline.
+	owner: TrieHash<L>,
+	/// Prefix of the children, the partial key of the node included.
+	child_prefix: NibbleVec<L::Nibble>,
+	/// Next child index to visit.
+	next_child: usize,
+	/// Nodes of the retained tries at or above the node.
+	cursors: Vec<Cursor<L>>,
+}
+
+/// The deepest node of a retained trie at the prefix of a walked node or above it.
+struct Cursor<L: TrieLayout> {
+	node: Rc<OwnedNode<DBValue, L::Nibble>>,
+	/// Hash of the node, `None` when inline.
+	hash: Option<TrieHash<L>>,
+	/// Hash of the node, or of the node holding it when inline.
+	owner: TrieHash<L>,
+	prefix: NibbleVec<L::Nibble>,
+}
+
+impl<L: TrieLayout> Clone for Cursor<L> {
+	fn clone(&self) -> Self {
+		Cursor {
+			node: self.node.clone(),
+			hash: self.hash,
+			owner: self.owner,
+			prefix: self.prefix.clone(),
+		}
+	}
+}
+
+impl<'a, L: TrieLayout> NodesToPrune<'a, L> {
+	fn trie(&self) -> TrieDB<'_, L> {
+		TrieDB::new_unchecked(self.db, &self.old_root)
+	}
+
+	/// Fetch the root nodes, returning the old root when it is not retained.
+	fn start(&mut self) -> Result<Option<PrunedNode<L>>, TrieHash<L>, CError<L>> {
+		if self.retained_roots.contains(&self.old_root) {
+			return Ok(None);
+		}
+		let mut cursors: Vec<Cursor<L>> = Vec::with_capacity(self.retained_roots.len());
+		for root in self.retained_roots.iter() {
+			if cursors.iter().any(|cursor| cursor.hash == Some(*root)) {
+				continue;
+			}
+			let (node, _) = self.trie().get_raw_or_lookup(
+				*root,
+				NodeHandle::Hash(root.as_ref()),
+				EMPTY_PREFIX,
+			)?;
+			cursors.push(Cursor {
+				node: Rc::new(node),
+				hash: Some(*root),
+				owner: *root,
+				prefix: NibbleVec::new(),
+			});
+		}
+		let (node, _) = self.trie().get_raw_or_lookup(
+			self.old_root,
+			NodeHandle::Hash(self.old_root.as_ref()),
+			EMPTY_PREFIX,
+		)?;
+		let root = (&node, self.old_root);
+		self.value_nodes = pruned_values::<L>(self.db, root, &NibbleVec::new(), &cursors)?;
+		self.stack.push(Frame::new(Rc::new(node), self.old_root, NibbleVec::new(), cursors));
+		Ok(Some((self.old_root, (Vec::new(), None))))
+	}
+
+	/// Walk to the next node to prune.
+	fn step(&mut self) -> Result<Option<PrunedNode<L>>, TrieHash<L>, CError<L>> {
+		if let Some(value_node) = self.value_nodes.pop() {
+			return Ok(Some(value_node));
+		}
+		let trie = TrieDB::<L>::new_unchecked(self.db, &self.old_root);
+		loop {
+			let frame = match self.stack.last_mut() {
+				Some(frame) => frame,
+				None => return Ok(None),
+			};
+			let node = frame.node.clone();
+			let data = node.data();
+			let mut prefix = frame.child_prefix.clone();
+			let handle = match node.node_plan() {
+				NodePlan::Extension { child, .. } if frame.next_child == 0 => {
+					frame.next_child = 1;
+					child.build(data)
+				},
+				NodePlan::Branch { children, .. } | NodePlan::NibbledBranch { children, .. } => {
+					let next = children.iter().enumerate()
+						.skip(frame.next_child)
+						.find_map(|(i, child)| child.as_ref().map(|child| (i, child)));
+					match next {
+						Some((i, child)) => {
+							frame.next_child = i + 1;
+							prefix.push(i as u8);
+							child.build(data)
+						},
+						None => {
+							self.stack.pop();
+							continue;
+						},
+					}
+				},
+				_ => {
+					self.stack.pop();
+					continue;
+				},
+			};
+
+			let mut cursors: Vec<Cursor<L>> = Vec::with_capacity(frame.cursors.len());
+			for cursor in frame.cursors.iter() {
+				if let Some(cursor) = advance(&trie, cursor.clone(), &prefix)? {
+					let duplicate = cursor.hash.is_some() && cursors.iter().any(|other|
+						other.hash == cursor.hash && other.prefix.len() == cursor.prefix.len()
+					);
+					if !duplicate {
+						cursors.push(cursor);
+					}
+				}
+			}
+			let hash = match handle {
+				NodeHandle::Hash(data) => decode_hash::<L::Hash>(data),
+				NodeHandle::Inline(_) => None,
+			};
+			if let Some(hash) = hash {
+				let retained = cursors.iter().any(|cursor|
+					cursor.hash == Some(hash) && cursor.prefix.len() == prefix.len()
+				);
+				if retained {
+					continue;
+				}
+			}
+
+			let (child, _) = trie.get_raw_or_lookup(frame.owner, handle, prefix.as_prefix())?;
+			let owner = hash.unwrap_or(frame.owner);
+			let (key, last) = prefix.as_prefix();
+			let item = hash.map(|hash| (hash, (key.to_vec(), last)));
+			self.value_nodes = pruned_values::<L>(self.db, (&child, owner), &prefix, &cursors)?;
+			self.stack.push(Frame::new(Rc::new(child), owner, prefix, cursors));
+			match item {
+				Some(item) => return Ok(Some(item)),
+				// the value nodes of an inline node are yielded in its place.
+				None if !self.value_nodes.is_empty() => return Ok(self.value_nodes.pop()),
+				None => (),
+			}
+		}
+	}
+}
+
+impl<L: TrieLayout> Frame<L> {
+	fn new(
+		node: Rc<OwnedNode<DBValue, L::Nibble>>,
+		owner: TrieHash<L>,
+		prefix: NibbleVec<L::Nibble>,
+		cursors: Vec<Cursor<L>>,
+	) -> Self {
+		let mut child_prefix = prefix;
+		match node.node_plan() {
+			NodePlan::Extension { partial, .. } | NodePlan::NibbledBranch { partial, .. } =>
+				child_prefix.append_partial(partial.build(node.data()).right()),
+			_ => (),
+		}
+		Frame { node, owner, child_prefix, next_child: 0, cursors }
+	}
+}
+
+/// The nodes storing the value of `node`, walked at `prefix`, apart, but those a node of a
+/// retained trie at the same prefix in `cursors` references too. The index of a value stored
+/// in chunks is read from `db`.
+fn pruned_values<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	(node, owner): (&OwnedNode<DBValue, L::Nibble>, TrieHash<L>),
+	prefix: &NibbleVec<L::Nibble>,
+	cursors: &[Cursor<L>],
+) -> Result<Vec<PrunedNode<L>>, TrieHash<L>, CError<L>> {
+	let (key, last) = prefix.as_prefix();
+	let stored_apart = |value: Value| {
+		let mut missing = None;
+		let hashes = value_nodes::<L>(value, |hash| {
+			let data = db.get(hash, (key, last));
+			if data.is_none() {
+				missing = Some(*hash);
+			}
+			data
+		});
+		match missing {
+			Some(hash) => Err(Box::new(TrieError::incomplete_database(hash, (key, last)))),
+			None => Ok(hashes),
+		}
+	};
+	let hashes = match node.value::<L>(owner)? {
+		Some(value) => stored_apart(value)?,
+		None => return Ok(Vec::new()),
+	};
+	if hashes.is_empty() {
+		return Ok(Vec::new());
+	}
+	let mut retained = HashSet::new();
+	for cursor in cursors.iter().filter(|cursor| cursor.prefix.len() == prefix.len()) {
+		if let Some(value) = cursor.node.value::<L>(cursor.owner)? {
+			retained.extend(stored_apart(value)?);
+		}
+	}
+	// a value may hold the same chunk twice, yielded once.
+	Ok(hashes.into_iter()
+		.filter(|hash| retained.insert(*hash))
+		.map(|hash| (hash, (key.to_vec(), last)))
+		.collect())
+}
+
+/// Descend `cursor` to the deepest node of its trie at `prefix` or above it, or `None` if its
+/// trie has no node at or below `prefix`. The prefix of `cursor` starts `prefix`.
+fn advance<L: TrieLayout>(
+	trie: &TrieDB<L>,
+	mut cursor: Cursor<L>,
+	prefix: &NibbleVec<L::Nibble>,
+) -> Result<Option<Cursor<L>>, TrieHash<L>, CError<L>> {
+	loop {
+		if cursor.prefix.len() == prefix.len() {
+			return Ok(Some(cursor));
+		}
+		let node = cursor.node.clone();
+		let data = node.data();
+		let mut child_prefix = cursor.prefix.clone();
+		let (is_branch, children) = match node.node_plan() {
+			NodePlan::Empty | NodePlan::Leaf { .. } => return Ok(None),
+			NodePlan::Extension { partial, child } => {
+				child_prefix.append_partial(partial.build(data).right());
+				(false, Some(child))
+			},
+			NodePlan::Branch { .. } => (true, None),
+			NodePlan::NibbledBranch { partial, .. } => {
+				child_prefix.append_partial(partial.build(data).right());
+				(true, None)
+			},
+		};
+		if child_prefix.len() >= prefix.len() {
+			if !child_prefix.starts_with_vec(prefix) {
+				return Ok(None);
+			}
+			// `prefix` ends in the partial key of the node, or at the children of a branch.
+			if is_branch || child_prefix.len() > prefix.len() {
+				return Ok(Some(cursor));
+			}
+		} else if !prefix.starts_with_vec(&child_prefix) {
+			return Ok(None);
+		}
+
+		let handle = match (children, node.node_plan()) {
+			(Some(child), _) => child.build(data),
+			(None, NodePlan::Branch { children, .. })
+			| (None, NodePlan::NibbledBranch { children, .. }) => {
+				let index = prefix.at(child_prefix.len());
+				match &children[index as usize] {
+					Some(child) => {
+						child_prefix.push(index);
+						child.build(data)
+					},
+					None => return Ok(None),
+				}
+			},
+			_ => unreachable!("only extensions and branches have children; qed"),
+		};
+		let hash = match handle {
+			NodeHandle::Hash(data) => decode_hash::<L::Hash>(data),
+			NodeHandle::Inline(_) => None,
+		};
+		let (child, _) = trie.get_raw_or_lookup(cursor.owner, handle, child_prefix.as_prefix())?;
+		cursor = Cursor {
+			node: Rc::new(child),
+			hash,
+			owner: hash.unwrap_or(cursor.owner),
+			prefix: child_prefix,
+		};
+	}
+}
+
+impl<'a, L: TrieLayout> Iterator for NodesToPrune<'a, L> {
+	type Item = Result<PrunedNode<L>, TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let result = match self.state {
+			State::NotStarted => {
+				self.state = State::Walking;
+				self.start()
+			},
+			State::Walking => self.step(),
+			State::Done => return None,
+		};
+		match result {
+			Ok(Some(item)) => Some(Ok(item)),
+			Ok(None) => {
+				self.state = State::Done;
+				None
+			},
+			Err(error) => {
+				self.state = State::Done;
+				self.stack.clear();
+				Some(Err(error))
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::{BTreeMap, HashSet};
+	use hash_db::{HashDBRef, Hasher};
+	use keccak_hasher::KeccakHasher;
+	use memory_db::{MemoryDB, PrefixedKey};
+	use rand::{Rng, SeedableRng, rngs::SmallRng};
+	use reference_trie::{
+		node::Value, prune::{nodes_to_prune, OwnedPrefix}, value_chunks::ChunkIndex,
+		ChunkedValueLayout, ExtensionLayout, NoExtensionLayout, TrieDB, TrieDBMut,
+		TrieDBNodeIterator, TrieLayout, TrieMut, ValueNodeLayout,
+	};
+	use crate::node::decode_hash;
+	use crate::DBValue;
+
+	type PrefixedMemoryDB = MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>;
+	type Root = <KeccakHasher as Hasher>::Out;
+	type NodeSet = HashSet<(Root, OwnedPrefix)>;
+	type Index = ChunkIndex<Root>;
+
+	/// Roots of a random history, each root inserting, updating and removing a few keys of
+	/// the previous one.
+	fn build_history<L: TrieLayout<Hash = KeccakHasher>>(
+		db: &mut PrefixedMemoryDB,
+		seed: u64,
+	) -> Vec<Root> {
+		let mut rng = SmallRng::seed_from_u64(seed);
+		let mut pairs = BTreeMap::new();
+		// values over a few chunks, in layouts storing values in chunks.
+		let max_len = L::MAX_VALUE_CHUNK.map_or(64, |chunk_size| 3 * chunk_size);
+		(0..8).map(|step| {
+			let changes = if step == 0 { 200 } else { rng.gen_range(1, 20) };
+			for _ in 0..changes {
+				let key = rng.gen_range(0u16, 300).to_be_bytes().to_vec();
+				if rng.gen_range(0, 4) == 0 {
+					pairs.remove(&key);
+				} else {
+					let len = rng.gen_range(1, max_len);
+					pairs.insert(key, (0..len).map(|_| rng.gen()).collect::<Vec<u8>>());
+				}
+			}
+			let mut step_db = PrefixedMemoryDB::default();
+			let mut root = Default::default();
+			{
+				let mut t = TrieDBMut::<L>::new(&mut step_db, &mut root);
+				for (key, value) in pairs.iter() {
+					t.insert(key, value).unwrap();
+				}
+			}
+			db.consolidate(step_db);
+			root
+		}).collect()
+	}
+
+	/// The nodes of the trie at `root` stored in `db`, value nodes included.
+	fn trie_nodes<L: TrieLayout<Hash = KeccakHasher>>(db: &PrefixedMemoryDB, root: &Root) -> NodeSet {
+		let trie = TrieDB::<L>::new(db, root).unwrap();
+		let mut nodes = NodeSet::new();
+		for item in TrieDBNodeIterator::new(&trie).unwrap() {
+			let (prefix, hash, node) = item.unwrap();
+			let (key, last) = prefix.as_prefix();
+			if let Some(hash) = hash {
+				nodes.insert((hash, (key.to_vec(), last)));
+			}
+			let mut value_hashes = match node.value::<L>(Default::default()).unwrap() {
+				Some(Value::Node(hash)) | Some(Value::Chunked(hash)) =>
+					vec![decode_hash::<KeccakHasher>(hash).unwrap()],
+				_ => Vec::new(),
+			};
+			if let Some(Value::Chunked(_)) = node.value::<L>(Default::default()).unwrap() {
+				let index = db.get(&value_hashes[0], (key, last)).unwrap();
+				let chunk_size = L::MAX_VALUE_CHUNK.unwrap();
+				value_hashes.extend(Index::decode(&index, chunk_size).unwrap().chunks);
+			}
+			for hash in value_hashes {
+				nodes.insert((hash, (key.to_vec(), last)));
+			}
+		}
+		nodes
+	}
+
+	fn check_against_subtraction<L: TrieLayout<Hash = KeccakHasher>>() {
+		for seed in 0..10 {
+			let mut db = PrefixedMemoryDB::default();
+			let roots = build_history::<L>(&mut db, seed);
+			for old in 0..roots.len() {
+				let retained = &roots[old + 1..];
+				let mut expected = trie_nodes::<L>(&db, &roots[old]);
+				for root in retained {
+					for node in trie_nodes::<L>(&db, root) {
+						expected.remove(&node);
+					}
+				}
+				let pruned: Vec<_> = nodes_to_prune::<L>(&db, &roots[old], retained)
+					.map(|item| item.unwrap())
+					.collect();
+				let pruned_set: NodeSet = pruned.iter().cloned().collect();
+				assert_eq!(pruned.len(), pruned_set.len());
+				assert_eq!(pruned_set, expected);
+			}
+		}
+	}
+
+	#[test]
+	fn prune_matches_set_subtraction() {
+		check_against_subtraction::<ExtensionLayout>();
+		check_against_subtraction::<NoExtensionLayout>();
+		check_against_subtraction::<ValueNodeLayout>();
+		check_against_subtraction::<ChunkedValueLayout>();
+	}
+
+	#[test]
+	fn overwritten_chunks_are_pruned() {
+		let build = |db: &mut PrefixedMemoryDB, value: &[u8]| {
+			let mut root = Default::default();
+			let mut t = TrieDBMut::<ChunkedValueLayout>::new(db, &mut root);
+			t.insert(&[1], value).unwrap();
+			t.insert(&[2], &[7; 10]).unwrap();
+			*t.root()
+		};
+		let old_value: Vec<u8> = (0..200u8).collect();
+		let mut new_value = old_value.clone();
+		new_value[100] = 0;
+		let mut db = PrefixedMemoryDB::default();
+		let old_root = build(&mut db, &old_value);
+		let new_root = build(&mut db, &new_value);
+		let pruned: NodeSet = nodes_to_prune::<ChunkedValueLayout>(&db, &old_root, &[new_root])
+			.map(|item| item.unwrap())
+			.collect();
+		let old_index = Index::new::<KeccakHasher>(&old_value, 64);
+		let new_index = Index::new::<KeccakHasher>(&new_value, 64);
+		let index_hash = KeccakHasher::hash(&old_index.encode());
+		assert!(pruned.iter().any(|(hash, _)| *hash == index_hash));
+		// only the chunk holding the changed byte is pruned.
+		for (old, new) in old_index.chunks.iter().zip(new_index.chunks.iter()) {
+			assert_eq!(pruned.iter().any(|(hash, _)| hash == old), old != new);
+		}
+	}
+
+	#[test]
+	fn overwritten_value_nodes_are_pruned() {
+		let build = |db: &mut PrefixedMemoryDB, value: u8| {
+			let mut root = Default::default();
+			let mut t = TrieDBMut::<ValueNodeLayout>::new(db, &mut root);
+			t.insert(&[1], &[value; 100]).unwrap();
+			t.insert(&[1, 2], &[8; 100]).unwrap();
+			t.insert(&[2], &[7; 100]).unwrap();
+			*t.root()
+		};
+		// the nodes of both roots are kept, as by a database pruning later.
+		let mut db = PrefixedMemoryDB::default();
+		let old_root = build(&mut db, 9);
+		let new_root = build(&mut db, 6);
+		let pruned: NodeSet = nodes_to_prune::<ValueNodeLayout>(&db, &old_root, &[new_root])
+			.map(|item| item.unwrap())
+			.collect();
+		let overwritten = KeccakHasher::hash(&[9; 100]);
+		assert!(pruned.iter().any(|(hash, _)| *hash == overwritten));
+		// the values kept by the new root are shared.
+		assert!(!pruned.iter().any(|(hash, _)| *hash == KeccakHasher::hash(&[8; 100])));
+		assert!(!pruned.iter().any(|(hash, _)| *hash == KeccakHasher::hash(&[7; 100])));
+	}
+
+	#[test]
+	fn retained_old_root_is_not_pruned() {
+		let mut db = PrefixedMemoryDB::default();
+		let roots = build_history::<ExtensionLayout>(&mut db, 42);
+		assert_eq!(nodes_to_prune::<ExtensionLayout>(&db, &roots[0], &roots[..2]).count(), 0);
+		// without retained roots, all the nodes are pruned.
+		assert_eq!(
+			nodes_to_prune::<ExtensionLayout>(&db, &roots[0], &[]).count(),
+			trie_nodes::<ExtensionLayout>(&db, &roots[0]).len(),
+		);
+	}
+
+	#[test]
+	fn missing_retained_node_is_an_error() {
+		let mut db = PrefixedMemoryDB::default();
+		let roots = build_history::<ExtensionLayout>(&mut db, 42);
+		let missing = KeccakHasher::hash(b"missing");
+		let mut iter = nodes_to_prune::<ExtensionLayout>(&db, &roots[0], &[roots[1], missing]);
+		assert!(iter.next().unwrap().is_err());
+		assert!(iter.next().is_none());
+	}
+}
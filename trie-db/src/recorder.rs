@@ -14,7 +14,11 @@
 
 //! Trie query recorder.
 
-use crate::rstd::vec::Vec;
+use crate::rstd::{hash::Hash, sync::Arc, vec::Vec};
+use crate::nibble::{NibbleVec, nibble_ops};
+use crate::{DBValue, TrieHash, TrieLayout};
+use hash_db::{HashDBRef, Hasher, Prefix};
+use hashbrown::{HashMap, HashSet};
 
 /// A record of a visited node.
 #[cfg_attr(feature = "std", derive(Debug))]
@@ -30,11 +34,46 @@ pub struct Record<HO> {
 	pub hash: HO,
 }
 
-/// Records trie nodes as they pass it.
+/// Measures of the distinct nodes held by a `Recorder`, see `Recorder::stats`.
 #[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecorderStats {
+	/// Number of distinct nodes.
+	pub nodes: usize,
+	/// Total size of the data of the distinct nodes.
+	pub bytes: usize,
+	/// Largest depth of a node, 0 without nodes.
+	pub max_depth: u32,
+}
+
+/// Predicate on the hash and the depth of the nodes to record, see `Recorder::record_if`.
+type RecordPredicate<HO> = Arc<dyn Fn(&HO, u32) -> bool + Send + Sync>;
+
+/// Records trie nodes as they pass it.
+#[derive(Clone)]
 pub struct Recorder<HO> {
 	nodes: Vec<Record<HO>>,
 	min_depth: u32,
+	prefix_filter: Option<NibbleVec>,
+	predicate: Option<RecordPredicate<HO>>,
+	size: usize,
+	inline: bool,
+	values: bool,
+}
+
+#[cfg(feature = "std")]
+impl<HO: std::fmt::Debug> std::fmt::Debug for Recorder<HO> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("Recorder")
+			.field("nodes", &self.nodes)
+			.field("min_depth", &self.min_depth)
+			.field("prefix_filter", &self.prefix_filter)
+			.field("predicate", &self.predicate.is_some())
+			.field("size", &self.size)
+			.field("inline", &self.inline)
+			.field("values", &self.values)
+			.finish()
+	}
 }
 
 impl<HO: Copy> Default for Recorder<HO> {
@@ -55,12 +94,104 @@ impl<HO: Copy> Recorder<HO> {
 		Recorder {
 			nodes: Vec::new(),
 			min_depth: depth,
+			prefix_filter: None,
+			predicate: None,
+			size: 0,
+			inline: false,
+			values: true,
+		}
+	}
+
+	/// Create a `Recorder` which only records nodes beyond a given depth.
+	/// Same as `with_depth`.
+	pub fn with_min_depth(depth: u32) -> Self {
+		Recorder::with_depth(depth)
+	}
+
+	/// Create a `Recorder` which only records nodes whose key prefix starts with `prefix`.
+	///
+	/// The prefix of a node is only known when recorded with `record_at`, nodes recorded
+	/// with `record` are skipped.
+	pub fn with_prefix_filter(prefix: NibbleVec) -> Self {
+		Recorder {
+			prefix_filter: Some(prefix),
+			..Recorder::with_depth(0)
+		}
+	}
+
+	/// Only record the nodes passed from now on beyond a given depth, the nodes already
+	/// recorded being kept.
+	pub fn set_min_depth(&mut self, depth: u32) {
+		self.min_depth = depth;
+	}
+
+	/// Only record the nodes passed from now on for which `predicate` returns true, given their
+	/// hash and depth, as the nodes a verifier already has. The nodes already recorded are
+	/// kept, and the predicate replaces the previous one, the minimum depth and the prefix
+	/// filter still applying.
+	pub fn record_if<F>(&mut self, predicate: F)
+		where F: Fn(&HO, u32) -> bool + Send + Sync + 'static,
+	{
+		self.predicate = Some(Arc::new(predicate));
+	}
+
+	/// Also record the inline nodes passed by lookups, for debugging: they are recorded at the
+	/// depth of the node holding them, with the hash of their encoding although they are not
+	/// stored under it.
+	pub fn record_inline(&mut self, enabled: bool) {
+		self.inline = enabled;
+	}
+
+	/// Record the value nodes read by lookups, as by default, see `TrieLayout::MAX_INLINE_VALUE`.
+	/// Without them, the recorded nodes prove the hash of the values stored apart, and a
+	/// `PartialDB` built from them cannot read these values.
+	pub fn record_values(&mut self, enabled: bool) {
+		self.values = enabled;
+	}
+
+	/// Record a value node read by a lookup, given its hash, the value, the depth of the node
+	/// holding it and its key prefix, when value nodes are recorded.
+	pub(crate) fn record_value_at(&mut self, hash: &HO, value: &[u8], depth: u32, prefix: Prefix) {
+		if self.values {
+			self.record_at(hash, value, depth, prefix);
+		}
+	}
+
+	/// Record an inline node passed by a lookup, given its data, depth and the key prefix it is
+	/// at, when inline nodes are recorded.
+	pub(crate) fn record_inline_at<H>(&mut self, data: &[u8], depth: u32, prefix: Prefix)
+		where H: Hasher<Out = HO>,
+	{
+		if self.inline {
+			self.record_at(&H::hash(data), data, depth, prefix);
 		}
 	}
 
 	/// Record a visited node, given its hash, data, and depth.
 	pub fn record(&mut self, hash: &HO, data: &[u8], depth: u32) {
-		if depth >= self.min_depth {
+		if self.prefix_filter.is_none() {
+			self.push(hash, data, depth);
+		}
+	}
+
+	/// Record a visited node, given its hash, data, depth and the key prefix it is at.
+	pub fn record_at(&mut self, hash: &HO, data: &[u8], depth: u32, prefix: Prefix) {
+		let matches = match self.prefix_filter {
+			Some(ref filter) => prefix_starts_with(prefix, filter),
+			None => true,
+		};
+		if matches {
+			self.push(hash, data, depth);
+		}
+	}
+
+	fn push(&mut self, hash: &HO, data: &[u8], depth: u32) {
+		let accepted = depth >= self.min_depth && match self.predicate {
+			Some(ref predicate) => predicate(hash, depth),
+			None => true,
+		};
+		if accepted {
+			self.size += data.len();
 			self.nodes.push(Record {
 				depth: depth,
 				data: data.into(),
@@ -69,10 +200,125 @@ impl<HO: Copy> Recorder<HO> {
 		}
 	}
 
+	/// Total size of the data of the recorded nodes, not including filtered out nodes.
+	pub fn estimated_size(&self) -> usize {
+		self.size
+	}
+
 	/// Drain all visited records.
 	pub fn drain(&mut self) -> Vec<Record<HO>> {
+		self.size = 0;
 		crate::rstd::mem::replace(&mut self.nodes, Vec::new())
 	}
+
+	/// Measures of the recorded nodes, each node recorded several times being counted once.
+	pub fn stats(&self) -> RecorderStats
+		where HO: Eq + Hash,
+	{
+		let mut seen = HashSet::new();
+		let mut stats = RecorderStats::default();
+		for record in self.nodes.iter().filter(|record| seen.insert(record.hash)) {
+			stats.nodes += 1;
+			stats.bytes += record.data.len();
+			stats.max_depth = stats.max_depth.max(record.depth);
+		}
+		stats
+	}
+
+	/// Consume the recorder, returning the data of the recorded nodes, each node once, parents
+	/// before their children: the nodes are ordered by depth, the nodes at the same depth being
+	/// in the order they were recorded.
+	///
+	/// The nodes are the ones read by the recorded lookups, to read them again from a database
+	/// holding these nodes only, as with `into_partial_db`.
+	pub fn into_proof(mut self) -> Vec<Vec<u8>>
+		where HO: Eq + Hash,
+	{
+		self.nodes.sort_by_key(|record| record.depth);
+		let mut seen = HashSet::new();
+		self.nodes.into_iter()
+			.filter(|record| seen.insert(record.hash))
+			.map(|record| record.data)
+			.collect()
+	}
+
+	/// Consume the recorder, returning the recorded nodes as a database and the root of the
+	/// recorded trie, the first node recorded at depth 0.
+	///
+	/// Returns `None` if no root was recorded, as with a minimum depth or a prefix filter.
+	pub fn into_partial_db<L>(self) -> Option<(PartialDB<L>, TrieHash<L>)>
+		where
+			L: TrieLayout,
+			L::Hash: Hasher<Out = HO>,
+			HO: Eq + Hash,
+	{
+		let root = self.nodes.iter().find(|record| record.depth == 0)?.hash;
+		let nodes: HashMap<TrieHash<L>, DBValue> = self.nodes.into_iter()
+			.map(|record| (record.hash, record.data))
+			.collect();
+		Some((PartialDB { nodes }, root))
+	}
+}
+
+/// The nodes recorded by a `Recorder`, read as a database with `into_partial_db`.
+///
+/// The nodes are indexed by hash only, ignoring the prefixes they are read at: recorded
+/// nodes may miss their prefix, and the nodes of a proof are identified by hash. A `TrieDB`
+/// over them reads the recorded keys again, other keys returning
+/// `TrieError::IncompleteDatabase`.
+pub struct PartialDB<L: TrieLayout> {
+	nodes: HashMap<TrieHash<L>, DBValue>,
+}
+
+impl<L: TrieLayout> PartialDB<L> {
+	/// Number of recorded nodes.
+	pub fn len(&self) -> usize {
+		self.nodes.len()
+	}
+
+	/// Returns true if no node was recorded.
+	pub fn is_empty(&self) -> bool {
+		self.nodes.is_empty()
+	}
+}
+
+// Manual implementations, deriving would require the bounds on the layout itself.
+impl<L: TrieLayout> Clone for PartialDB<L> {
+	fn clone(&self) -> Self {
+		PartialDB { nodes: self.nodes.clone() }
+	}
+}
+
+#[cfg(feature = "std")]
+impl<L: TrieLayout> std::fmt::Debug for PartialDB<L> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("PartialDB").field("nodes", &self.nodes).finish()
+	}
+}
+
+impl<L: TrieLayout> HashDBRef<L::Hash, DBValue> for PartialDB<L> {
+	fn get(&self, key: &TrieHash<L>, _prefix: Prefix) -> Option<DBValue> {
+		self.nodes.get(key).cloned()
+	}
+
+	fn contains(&self, key: &TrieHash<L>, _prefix: Prefix) -> bool {
+		self.nodes.contains_key(key)
+	}
+}
+
+/// Check if a node key prefix starts with the nibbles of `filter`.
+fn prefix_starts_with(prefix: Prefix, filter: &NibbleVec) -> bool {
+	let (bytes, last) = prefix;
+	let len = bytes.len() * nibble_ops::NIBBLE_PER_BYTE + if last.is_some() { 1 } else { 0 };
+	if len < filter.len() {
+		return false;
+	}
+	(0..filter.len()).all(|i| {
+		let byte = bytes.get(i / nibble_ops::NIBBLE_PER_BYTE).cloned()
+			.or(last)
+			.expect("i < len; qed");
+		nibble_ops::at_left((i % nibble_ops::NIBBLE_PER_BYTE) as u8, byte) == filter.at(i)
+	})
 }
 
 #[cfg(test)]
@@ -80,7 +326,8 @@ mod tests {
 	use memory_db::{MemoryDB, HashKey};
 	use hash_db::Hasher;
 	use keccak_hasher::KeccakHasher;
-	use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut, Recorder, Record};
+	use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut, Recorder, Record, NibbleVec,
+		ExtensionLayout, TrieError, RecorderStats};
 
 	#[test]
 	fn basic_recorder() {
@@ -132,8 +379,7 @@ mod tests {
 		});
 	}
 
-	#[test]
-	fn trie_record() {
+	fn test_trie() -> (MemoryDB<KeccakHasher, HashKey<KeccakHasher>, Vec<u8>>, <KeccakHasher as Hasher>::Out) {
 		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
 		let mut root = Default::default();
 		{
@@ -148,7 +394,256 @@ mod tests {
 			x.insert(b"pirate", b"aargh!").unwrap();
 			x.insert(b"yo ho ho", b"and a bottle of rum").unwrap();
 		}
+		(db, root)
+	}
 
+	#[test]
+	fn min_depth_proof_with_upper_nodes_from_other_source() {
+		use hash_db::{HashDB, EMPTY_PREFIX};
+
+		let (db, root) = test_trie();
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+
+		// upper node the verifier got from a previous proof.
+		let mut full = Recorder::new();
+		trie.get_with(b"pirate", &mut full).unwrap().unwrap();
+		let upper: Vec<_> = full.drain().into_iter().filter(|r| r.depth < 1).collect();
+		assert_eq!(upper.len(), 1);
+
+		let mut recorder = Recorder::with_min_depth(1);
+		trie.get_with(b"pirate", &mut recorder).unwrap().unwrap();
+		trie.get_with(b"letter", &mut recorder).unwrap().unwrap();
+		let expected_size: usize = [b"pirate", b"letter"].iter()
+			.map(|key| {
+				let mut full = Recorder::new();
+				trie.get_with(&key[..], &mut full).unwrap();
+				full.drain().into_iter().skip(1).map(|r| r.data.len()).sum::<usize>()
+			})
+			.sum();
+		assert_eq!(recorder.estimated_size(), expected_size);
+		let records = recorder.drain();
+		assert!(records.iter().all(|r| r.depth >= 1));
+
+		let mut proof_db = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+		for record in records {
+			proof_db.insert(EMPTY_PREFIX, &record.data);
+		}
+		assert!(RefTrieDB::new(&proof_db, &root).is_err());
+
+		for record in upper {
+			proof_db.insert(EMPTY_PREFIX, &record.data);
+		}
+		let proof_trie = RefTrieDB::new(&proof_db, &root).unwrap();
+		assert_eq!(proof_trie.get(b"pirate").unwrap(), Some(b"aargh!".to_vec()));
+		assert_eq!(proof_trie.get(b"letter").unwrap(), Some(b"confusion".to_vec()));
+	}
+
+	#[test]
+	fn prefix_filter() {
+		let (db, root) = test_trie();
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+
+		let mut full = Recorder::new();
+		trie.get_with(b"letter", &mut full).unwrap().unwrap();
+		let full = full.drain();
+		assert_eq!(full.len(), 2);
+
+		// only the node at nibble 6 ("dog", "hotdog", "insert", "letter", "lunch", "notdog").
+		let mut filter = NibbleVec::new();
+		filter.push(6);
+		let mut recorder = Recorder::with_prefix_filter(filter.clone());
+		trie.get_with(b"letter", &mut recorder).unwrap().unwrap();
+		trie.get_with(b"yo ho ho", &mut recorder).unwrap().unwrap();
+		assert_eq!(recorder.estimated_size(), full[1].data.len());
+		assert_eq!(recorder.drain(), vec![full[1].clone()]);
+
+		// nodes recorded without a prefix are filtered out.
+		let mut recorder = Recorder::with_prefix_filter(filter);
+		recorder.record(&full[1].hash, &full[1].data, full[1].depth);
+		assert_eq!(recorder.estimated_size(), 0);
+		assert!(recorder.drain().is_empty());
+	}
+
+	#[test]
+	fn recorded_lookups_replay_offline() {
+		let (db, root) = test_trie();
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+		let keys: [&[u8]; 3] = [b"pirate", b"yo", b"yo ho ho"];
+		let mut recorder = Recorder::new();
+		let results: Vec<_> = keys.iter()
+			.map(|key| trie.get_with(key, &mut recorder).unwrap())
+			.collect();
+
+		let (partial_db, recorded_root) = recorder.into_partial_db::<ExtensionLayout>().unwrap();
+		assert_eq!(recorded_root, root);
+		let offline = RefTrieDB::new(&partial_db, &recorded_root).unwrap();
+		let offline_results: Vec<_> = keys.iter()
+			.map(|key| offline.get(key).unwrap())
+			.collect();
+		assert_eq!(offline_results, results);
+		assert_eq!(results[1], None);
+
+		// "letter" is in a node which was not recorded.
+		match offline.get(b"letter") {
+			Err(e) => match *e {
+				TrieError::IncompleteDatabase { .. } => (),
+				e => panic!("unexpected error: {:?}", e),
+			},
+			result => panic!("unexpected result: {:?}", result),
+		}
+	}
+
+	#[test]
+	fn partial_db_needs_the_root() {
+		let (db, root) = test_trie();
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+		let mut recorder = Recorder::with_min_depth(1);
+		trie.get_with(b"pirate", &mut recorder).unwrap().unwrap();
+		assert!(recorder.into_partial_db::<ExtensionLayout>().is_none());
+	}
+
+	#[test]
+	fn recorders_and_partial_dbs_clone() {
+		let (db, root) = test_trie();
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+		let mut recorder = Recorder::new();
+		trie.get_with(b"pirate", &mut recorder).unwrap().unwrap();
+
+		let mut copy = recorder.clone();
+		trie.get_with(b"letter", &mut recorder).unwrap().unwrap();
+		let (partial_db, _) = copy.clone().into_partial_db::<ExtensionLayout>().unwrap();
+		assert!(copy.drain().len() < recorder.drain().len());
+
+		let copy = partial_db.clone();
+		assert_eq!(copy.len(), partial_db.len());
+		assert_eq!(
+			RefTrieDB::new(&copy, &root).unwrap().get(b"pirate").unwrap(),
+			Some(b"aargh!".to_vec()),
+		);
+		assert!(format!("{:?}", copy).starts_with("PartialDB"));
+	}
+
+	#[test]
+	fn overlapping_lookups_prove_each_node_once() {
+		let (db, root) = test_trie();
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+		let keys: [&[u8]; 5] = [b"pirate", b"letter", b"lunch", b"pirate", b"yo"];
+		let mut recorder = Recorder::new();
+		for key in keys.iter() {
+			trie.get_with(key, &mut recorder).unwrap();
+		}
+		let records = recorder.clone().drain();
+		let mut distinct: Vec<Record<_>> = Vec::new();
+		for record in records.iter() {
+			if !distinct.iter().any(|seen| seen.hash == record.hash) {
+				distinct.push(record.clone());
+			}
+		}
+		// the root is read by every lookup, the node of "letter" and "lunch" by both.
+		assert_eq!(records.iter().filter(|record| record.hash == root).count(), keys.len());
+		assert!(distinct.len() + keys.len() <= records.len());
+		assert_eq!(recorder.stats(), RecorderStats {
+			nodes: distinct.len(),
+			bytes: distinct.iter().map(|record| record.data.len()).sum(),
+			max_depth: distinct.iter().map(|record| record.depth).max().unwrap(),
+		});
+
+		let proof = recorder.into_proof();
+		assert_eq!(proof.len(), distinct.len());
+		assert_eq!(proof[0], distinct[0].data);
+		assert!(distinct.iter().all(|record| proof.contains(&record.data)));
+		let mut proof_db = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+		for node in proof.iter() {
+			hash_db::HashDB::insert(&mut proof_db, hash_db::EMPTY_PREFIX, node);
+		}
+		let proof_trie = RefTrieDB::new(&proof_db, &root).unwrap();
+		for key in keys.iter() {
+			assert_eq!(proof_trie.get(key).unwrap(), trie.get(key).unwrap());
+		}
+		let empty = Recorder::<<KeccakHasher as Hasher>::Out>::new();
+		assert_eq!(empty.stats(), RecorderStats::default());
+	}
+
+	#[test]
+	fn inline_nodes_are_recorded_on_demand() {
+		let mut db = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = RefTrieDBMut::new(&mut db, &mut root);
+			trie.insert(b"a", &[0; 40]).unwrap();
+			trie.insert(b"b", b"x").unwrap();
+		}
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+
+		let mut recorder = Recorder::new();
+		trie.get_with(b"b", &mut recorder).unwrap();
+		let stored = recorder.drain();
+
+		let mut recorder = Recorder::new();
+		recorder.record_inline(true);
+		assert_eq!(trie.get_with(b"b", &mut recorder).unwrap(), Some(b"x".to_vec()));
+		let mut records = recorder.drain();
+		let inline = records.pop().unwrap();
+		assert_eq!(records, stored);
+		// the inline leaf of "b" is at the depth of the branch holding it.
+		assert_eq!(inline.depth, stored.last().unwrap().depth);
+		assert_eq!(inline.hash, KeccakHasher::hash(&inline.data));
+		assert!(inline.data.len() < 32);
+	}
+
+	#[test]
+	fn min_depth_changes_after_construction() {
+		let (db, root) = test_trie();
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+		let mut recorder = Recorder::new();
+		trie.get_with(b"pirate", &mut recorder).unwrap().unwrap();
+		let first = recorder.clone().drain();
+
+		recorder.set_min_depth(1);
+		trie.get_with(b"letter", &mut recorder).unwrap().unwrap();
+		let records = recorder.drain();
+		assert_eq!(records[..first.len()], first[..]);
+		assert!(records.len() > first.len());
+		assert!(records[first.len()..].iter().all(|record| record.depth >= 1));
+	}
+
+	#[test]
+	fn two_phase_proof_skips_the_nodes_sent() {
+		use hash_db::{HashDB, EMPTY_PREFIX};
+		use std::collections::HashSet;
+
+		let (db, root) = test_trie();
+		let trie = RefTrieDB::new(&db, &root).unwrap();
+
+		// first phase: the nodes of "letter" are sent to the client.
+		let mut recorder = Recorder::new();
+		trie.get_with(b"letter", &mut recorder).unwrap().unwrap();
+		let sent = recorder.drain();
+		let known: HashSet<_> = sent.iter().map(|record| record.hash).collect();
+
+		// second phase: only the nodes the client misses are recorded.
+		recorder.record_if(move |hash, _| !known.contains(hash));
+		trie.get_with(b"lunch", &mut recorder).unwrap().unwrap();
+		trie.get_with(b"pirate", &mut recorder).unwrap().unwrap();
+		let missing = recorder.drain();
+		assert!(missing.iter().all(|record| sent.iter().all(|sent| sent.hash != record.hash)));
+		let mut full = Recorder::new();
+		trie.get_with(b"lunch", &mut full).unwrap();
+		trie.get_with(b"pirate", &mut full).unwrap();
+		assert!(missing.len() < full.drain().len());
+
+		let mut client_db = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+		for record in sent.iter().chain(missing.iter()) {
+			client_db.insert(EMPTY_PREFIX, &record.data);
+		}
+		let client_trie = RefTrieDB::new(&client_db, &root).unwrap();
+		assert_eq!(client_trie.get(b"lunch").unwrap(), Some(b"time".to_vec()));
+		assert_eq!(client_trie.get(b"pirate").unwrap(), Some(b"aargh!".to_vec()));
+	}
+
+	#[test]
+	fn trie_record() {
+		let (db, root) = test_trie();
 		let trie = RefTrieDB::new(&db, &root).unwrap();
 		let mut recorder = Recorder::new();
 
@@ -74,6 +74,9 @@ where
 		self.raw.get_with(L::Hash::hash(key).as_ref(), query)
 	}
 
+	/// Iterate over the pairs with their hashed keys, in the order of the hashed keys: the
+	/// original keys are not stored, see `FatDB` for a trie keeping them. `seek` and
+	/// `seek_after` take a hashed key.
 	fn iter<'a>(&'a self) -> Result<
 		Box<dyn TrieIterator<L, Item = TrieItem<TrieHash<L>, CError<L>>> + 'a>,
 		TrieHash<L>,
@@ -0,0 +1,220 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Statistics of the nodes of a trie, see `TrieDB::statistics`.
+//!
+//! The statistics are accumulated in a single walk and in a space bounded by the depth of the
+//! trie, sizes being counted in power of two buckets rather than kept.
+
+use crate::iterator::TrieDBNodeIterator;
+use crate::node::Node;
+use crate::rstd::{fmt, vec::Vec};
+use crate::{CError, Result, TrieDB, TrieHash, TrieLayout};
+
+/// Number of buckets of `NodeSizeStats::buckets`.
+pub const SIZE_BUCKETS: usize = 32;
+
+/// Sizes of the encodings of the nodes of a type.
+#[derive(Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct NodeSizeStats {
+	/// Number of nodes.
+	pub count: u64,
+	/// Total size of the nodes.
+	pub bytes: u64,
+	/// Size of the smallest node, 0 if there is none.
+	pub min: usize,
+	/// Size of the largest node.
+	pub max: usize,
+	/// Number of nodes by size: bucket `i > 0` counts the sizes from `2^(i - 1)` to `2^i - 1`,
+	/// the last bucket counting the larger sizes too.
+	pub buckets: [u64; SIZE_BUCKETS],
+}
+
+impl NodeSizeStats {
+	fn add(&mut self, size: usize) {
+		self.min = if self.count == 0 { size } else { self.min.min(size) };
+		self.max = self.max.max(size);
+		self.count += 1;
+		self.bytes += size as u64;
+		let bucket = (usize::BITS - size.leading_zeros()) as usize;
+		self.buckets[bucket.min(SIZE_BUCKETS - 1)] += 1;
+	}
+
+	/// Average size of the nodes, rounded down, 0 if there is none.
+	pub fn average(&self) -> usize {
+		match self.count {
+			0 => 0,
+			count => (self.bytes / count) as usize,
+		}
+	}
+
+	/// Bound of the size of `percent` percent of the nodes: the largest size of the bucket of
+	/// the node at that rank, capped by `max`. 0 if there is no node.
+	pub fn percentile(&self, percent: u8) -> usize {
+		if self.count == 0 {
+			return 0;
+		}
+		let rank = (self.count * percent.min(100) as u64).div_ceil(100).max(1);
+		let mut seen = 0;
+		for (bucket, count) in self.buckets.iter().enumerate() {
+			seen += count;
+			if seen >= rank {
+				return ((1usize << bucket) - 1).min(self.max);
+			}
+		}
+		self.max
+	}
+}
+
+impl fmt::Display for NodeSizeStats {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{} nodes, {} bytes, size min {} avg {} p50 {} p90 {} p99 {} max {}",
+			self.count,
+			self.bytes,
+			self.min,
+			self.average(),
+			self.percentile(50),
+			self.percentile(90),
+			self.percentile(99),
+			self.max,
+		)
+	}
+}
+
+/// Statistics of the nodes of a trie, see `TrieDB::statistics`.
+///
+/// The sizes are the sizes of the encoded nodes, inline nodes included. A branch with a
+/// partial key counts as a branch.
+#[derive(Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct TrieStats {
+	/// Sizes of the leaves.
+	pub leaves: NodeSizeStats,
+	/// Sizes of the extensions.
+	pub extensions: NodeSizeStats,
+	/// Sizes of the branches.
+	pub branches: NodeSizeStats,
+	/// Number of entries, the leaves and the values of the branches.
+	pub entries: u64,
+	/// Number of leaves by depth, the root being at depth 0.
+	pub leaf_depths: Vec<u64>,
+	/// Number of children inlined in their parent.
+	pub inline_children: u64,
+	/// Number of children referenced by hash.
+	pub hashed_children: u64,
+	/// Number of nodes stored in the database, the root included.
+	pub stored_nodes: u64,
+	/// Total size of the nodes stored in the database.
+	pub stored_bytes: u64,
+}
+
+impl TrieStats {
+	/// Share of the children inlined in their parent, in percent rounded down, 0 if there is
+	/// no child.
+	pub fn inline_percent(&self) -> u64 {
+		match self.inline_children + self.hashed_children {
+			0 => 0,
+			children => self.inline_children * 100 / children,
+		}
+	}
+}
+
+impl fmt::Display for TrieStats {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "entries: {}", self.entries)?;
+		writeln!(f, "stored: {} nodes, {} bytes", self.stored_nodes, self.stored_bytes)?;
+		writeln!(f, "leaves: {}", self.leaves)?;
+		writeln!(f, "extensions: {}", self.extensions)?;
+		writeln!(f, "branches: {}", self.branches)?;
+		writeln!(
+			f,
+			"children: {} inline, {} hashed ({}% inline)",
+			self.inline_children,
+			self.hashed_children,
+			self.inline_percent(),
+		)?;
+		write!(f, "leaf depths:")?;
+		for (depth, count) in self.leaf_depths.iter().enumerate() {
+			if *count > 0 {
+				write!(f, " {}: {}", depth, count)?;
+			}
+		}
+		writeln!(f)
+	}
+}
+
+/// Walk the nodes of `trie`, see `TrieDB::statistics`.
+pub(crate) fn statistics<L: TrieLayout>(
+	trie: &TrieDB<L>,
+) -> Result<TrieStats, TrieHash<L>, CError<L>> {
+	let mut stats = TrieStats::default();
+	// Prefix length of the children of the ancestors of the next node, in pre-order: the
+	// ancestors of a node are the nodes whose children have a shorter prefix than its own.
+	let mut ancestors: Vec<usize> = Vec::new();
+	let mut iter = TrieDBNodeIterator::new(trie)?;
+	while let Some(item) = iter.next_node() {
+		let (prefix, hash, node) = item?;
+		let size = node.data().len();
+		while let Some(&children) = ancestors.last() {
+			if children <= prefix.len() {
+				break;
+			}
+			ancestors.pop();
+		}
+		let depth = ancestors.len();
+		match hash {
+			Some(_) => {
+				stats.stored_nodes += 1;
+				stats.stored_bytes += size as u64;
+				if depth > 0 {
+					stats.hashed_children += 1;
+				}
+			},
+			None => stats.inline_children += 1,
+		}
+		let children = match node.node() {
+			Node::Empty => None,
+			Node::Leaf(..) => {
+				stats.leaves.add(size);
+				stats.entries += 1;
+				if stats.leaf_depths.len() <= depth {
+					stats.leaf_depths.resize(depth + 1, 0);
+				}
+				stats.leaf_depths[depth] += 1;
+				None
+			},
+			Node::Extension(partial, _) => {
+				stats.extensions.add(size);
+				Some(prefix.len() + partial.len())
+			},
+			Node::Branch(_, value) => {
+				stats.branches.add(size);
+				stats.entries += value.map_or(0, |_| 1);
+				Some(prefix.len() + 1)
+			},
+			Node::NibbledBranch(partial, _, value) => {
+				stats.branches.add(size);
+				stats.entries += value.map_or(0, |_| 1);
+				Some(prefix.len() + partial.len() + 1)
+			},
+		};
+		if let Some(children) = children {
+			ancestors.push(children);
+		}
+	}
+	Ok(stats)
+}
@@ -0,0 +1,188 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! View of the subtrie of the keys with a given prefix.
+
+use hash_db::EMPTY_PREFIX;
+use crate::nibble::NibbleSlice;
+use crate::node::{Node, NodeHandle};
+use crate::proof::generate_proof;
+use crate::rstd::{boxed::Box, vec::Vec};
+use super::{Result, TrieDB, Trie, TrieDBIterator, TrieItem, TrieIterator, Query, TrieLayout,
+	CError, TrieHash};
+
+/// A read-only `Trie` over the keys of a `TrieDB` starting with a prefix, the keys being
+/// relative to the prefix, see `TrieDB::subtrie`.
+///
+/// Lookups start from the node covering the prefix, with the nibble offset of the node for the
+/// database prefixes, and iterations never leave its subtrie. Without a node for the prefix,
+/// the view is empty.
+pub struct SubTrieDB<'a, 'db, L>
+where
+	L: TrieLayout,
+{
+	trie: &'a TrieDB<'db, L>,
+	prefix: Vec<u8>,
+	/// Hash of the node covering the prefix, or of the closest node fetched by hash above it,
+	/// with the nibble offset of the node.
+	start: Option<(TrieHash<L>, usize)>,
+}
+
+impl<'a, 'db, L> SubTrieDB<'a, 'db, L>
+where
+	L: TrieLayout,
+{
+	/// Open the view of the keys of `trie` starting with `prefix`, descending to the node
+	/// covering `prefix`.
+	pub fn new(trie: &'a TrieDB<'db, L>, prefix: &[u8]) -> Result<Self, TrieHash<L>, CError<L>> {
+		let key = NibbleSlice::new(prefix);
+		let mut hash = *trie.root();
+		let mut hash_at = 0;
+		let (mut node, _) =
+			trie.get_raw_or_lookup(hash, NodeHandle::Hash(hash.as_ref()), EMPTY_PREFIX)?;
+		let mut at = 0;
+		let start = loop {
+			let partial = key.mid(at);
+			// the partial key of the node, its child on the path of the prefix and the number
+			// of nibbles between them.
+			let (slice, child, index_len) = match node.node() {
+				Node::Empty => break None,
+				Node::Leaf(slice, _) => (slice, None, 0),
+				Node::Extension(slice, child) => (slice, Some(child), 0),
+				Node::Branch(children, _) => {
+					let child = partial.try_at(0).and_then(|i| children[i as usize]);
+					(NibbleSlice::new(&[]), child, 1)
+				},
+				Node::NibbledBranch(slice, children, _) => {
+					let child = partial.try_at(slice.len()).and_then(|i| children[i as usize]);
+					(slice, child, 1)
+				},
+			};
+			// the prefix ends in the partial key of the node, or the node is above it.
+			if slice.starts_with(&partial) {
+				break Some((hash, hash_at))
+			}
+			if !partial.starts_with(&slice) {
+				break None
+			}
+			let child = match child {
+				Some(child) => child,
+				None => break None,
+			};
+			at += slice.len() + index_len;
+			let (child, child_hash) = trie.get_raw_or_lookup(hash, child, key.mid(at).left())?;
+			if let Some(child_hash) = child_hash {
+				hash = child_hash;
+				hash_at = at;
+			}
+			node = child;
+		};
+		Ok(SubTrieDB { trie, prefix: prefix.to_vec(), start })
+	}
+
+	/// The prefix of the keys of the view.
+	pub fn prefix(&self) -> &[u8] { &self.prefix }
+
+	/// Key of the underlying trie for the key `key` of the view.
+	pub fn full_key(&self, key: &[u8]) -> Vec<u8> {
+		[&self.prefix[..], key].concat()
+	}
+
+	/// Generate a proof of the values at `keys`, relative to the prefix, with `generate_proof`.
+	/// The proof is checked against the root of the underlying trie, with the full keys.
+	pub fn generate_proof<K: AsRef<[u8]>>(
+		&self,
+		keys: &[K],
+	) -> Result<Vec<Vec<u8>>, TrieHash<L>, CError<L>> {
+		let keys: Vec<_> = keys.iter().map(|key| self.full_key(key.as_ref())).collect();
+		generate_proof(self.trie, &keys)
+	}
+}
+
+impl<'a, 'db, L> Trie<L> for SubTrieDB<'a, 'db, L>
+where
+	L: TrieLayout,
+{
+	/// Root of the underlying trie.
+	fn root(&self) -> &TrieHash<L> { self.trie.root() }
+
+	fn is_empty(&self) -> bool { self.start.is_none() }
+
+	fn get_with<'b, 'key, Q: Query<L::Hash>>(&'b self, key: &'key [u8], query: Q)
+		-> Result<Option<Q::Item>, TrieHash<L>, CError<L>>
+		where 'b: 'key
+	{
+		match self.start {
+			Some((hash, at)) => self.trie.get_with_at(&self.full_key(key), hash, at, query),
+			None => Ok(None),
+		}
+	}
+
+	fn iter<'b>(&'b self) -> Result<
+		Box<dyn TrieIterator<L, Item = TrieItem<'b, TrieHash<L>, CError<L>>> + 'b>,
+		TrieHash<L>,
+		CError<L>,
+	> {
+		SubTrieDBIterator::new(self).map(|iter| Box::new(iter) as Box<_>)
+	}
+}
+
+/// Iterator over the pairs of a `SubTrieDB`, with keys relative to its prefix.
+pub struct SubTrieDBIterator<'a, L>
+where
+	L: TrieLayout,
+{
+	inner: TrieDBIterator<'a, L>,
+	prefix: &'a [u8],
+}
+
+impl<'a, L> SubTrieDBIterator<'a, L>
+where
+	L: TrieLayout,
+{
+	/// Create a new iterator over the pairs of `subtrie`.
+	pub fn new<'db>(subtrie: &'a SubTrieDB<'a, 'db, L>) -> Result<Self, TrieHash<L>, CError<L>> {
+		Ok(SubTrieDBIterator {
+			inner: TrieDBIterator::new_prefixed(subtrie.trie, &subtrie.prefix)?,
+			prefix: &subtrie.prefix,
+		})
+	}
+}
+
+impl<'a, L> TrieIterator<L> for SubTrieDBIterator<'a, L>
+where
+	L: TrieLayout,
+{
+	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		let key = [self.prefix, key].concat();
+		self.inner.seek_in_prefix(self.prefix, &key, false)
+	}
+
+	fn seek_after(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		let key = [self.prefix, key].concat();
+		self.inner.seek_in_prefix(self.prefix, &key, true)
+	}
+}
+
+impl<'a, L> Iterator for SubTrieDBIterator<'a, L>
+where
+	L: TrieLayout,
+{
+	type Item = TrieItem<'a, TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let prefix_len = self.prefix.len();
+		self.inner.next().map(|item| item.map(|(key, value)| (key[prefix_len..].to_vec(), value)))
+	}
+}
@@ -0,0 +1,492 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Caches for `TrieDB`, see `TrieDB::new_with_cache`.
+//!
+//! Nodes are indexed by hash only, so a cache can be used by tries with different roots, at
+//! the same time or one after the other, the nodes they have in common being decoded once.
+//! Values are indexed by root and key, a trie never reading the values cached for another
+//! root.
+//!
+//! A `NodeCache` can be saved and loaded again after a restart, with `NodeCache::save_to` and
+//! `NodeCache::load_from`. The saved cache is a 4-byte magic followed by the entries, from the
+//! most to the least recently used, each entry being the hash of the node, the length of the
+//! node as a little-endian `u32`, and the encoded node.
+
+use hashbrown::{hash_map::RawEntryMut, HashMap};
+use crate::iterator::SharedNode;
+use crate::rstd::{cell::RefCell, hash::{BuildHasher, Hash}, vec::Vec, BTreeMap};
+#[cfg(not(feature = "hash-interning"))]
+use crate::rstd::{convert::Infallible, marker::PhantomData};
+use crate::{DBValue, TrieHash, TrieLayout};
+#[cfg(feature = "std")]
+use std::{borrow::Cow, convert::TryFrom, io::{self, Read, Write}};
+#[cfg(feature = "std")]
+use hash_db::Hasher;
+#[cfg(feature = "std")]
+use crate::node::OwnedNode;
+#[cfg(feature = "hash-interning")]
+use crate::interner::{HashInterner, InternedNode};
+
+/// Marker at the start of a saved cache.
+#[cfg(feature = "std")]
+const MAGIC: [u8; 4] = *b"tnc1";
+
+/// Result of `NodeCache::load_from`.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LoadSummary {
+	/// Number of nodes loaded into the cache.
+	pub loaded: usize,
+	/// Number of nodes skipped because they do not hash to their hash or cannot be decoded.
+	pub corrupt: usize,
+}
+
+/// Counters of a `NodeCache`, see `NodeCache::stats`.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NodeCacheStats {
+	/// Number of nodes found in the cache.
+	pub hits: u64,
+	/// Number of nodes not found in the cache, and read from the database.
+	pub misses: u64,
+	/// Number of cached nodes.
+	pub nodes: usize,
+	/// Total size of the encoded cached nodes.
+	pub bytes: usize,
+}
+
+impl NodeCacheStats {
+	/// Share of the nodes found in the cache, 0 if no node was looked for.
+	pub fn hit_rate(&self) -> f64 {
+		hit_rate(self.hits, self.misses)
+	}
+}
+
+/// Counters of a `ValueCache`, see `ValueCache::stats`.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ValueCacheStats {
+	/// Number of lookups answered by the cache.
+	pub hits: u64,
+	/// Number of lookups not answered by the cache, and run on the trie.
+	pub misses: u64,
+	/// Number of cached lookups, those of absent keys included.
+	pub entries: usize,
+	/// Total size of the keys and values of the cached lookups.
+	pub bytes: usize,
+}
+
+impl ValueCacheStats {
+	/// Share of the lookups answered by the cache, 0 if no lookup was made.
+	pub fn hit_rate(&self) -> f64 {
+		hit_rate(self.hits, self.misses)
+	}
+}
+
+fn hit_rate(hits: u64, misses: u64) -> f64 {
+	match hits + misses {
+		0 => 0.0,
+		total => hits as f64 / total as f64,
+	}
+}
+
+/// Entries bounded by their total size, evicting the least recently used entries.
+struct Lru<K, V> {
+	/// Entries with their size and last use.
+	entries: HashMap<K, (V, usize, u64)>,
+	/// Keys of the entries by the use they were indexed at, their last use or an earlier one.
+	recency: BTreeMap<u64, K>,
+	/// Use counter of the most recent use.
+	tick: u64,
+	/// Use counter of the least recent use, loaded entries being older than it.
+	oldest: u64,
+	bytes: usize,
+	max_bytes: usize,
+	hits: u64,
+	misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+	fn new(max_bytes: usize) -> Self {
+		// new uses count up and loaded entries count down from the middle.
+		let start = u64::MAX / 2;
+		Lru {
+			entries: HashMap::new(),
+			recency: BTreeMap::new(),
+			tick: start,
+			oldest: start,
+			bytes: 0,
+			max_bytes,
+			hits: 0,
+			misses: 0,
+		}
+	}
+
+	/// Get the entry at `key`, counting a hit or a miss.
+	fn get(&mut self, key: &K) -> Option<&V> {
+		self.get_with(key, |entry| entry == key)
+	}
+
+	/// Get the entry at the key `is_key` matches, counting a hit or a miss. `key` is a borrowed
+	/// form of the key, hashing as it.
+	fn get_with<Q: Hash + ?Sized>(
+		&mut self,
+		key: &Q,
+		is_key: impl FnMut(&K) -> bool,
+	) -> Option<&V> {
+		let hash = self.entries.hasher().hash_one(key);
+		self.tick += 1;
+		match self.entries.raw_entry_mut().from_hash(hash, is_key) {
+			RawEntryMut::Occupied(entry) => {
+				let (value, _, last_use) = entry.into_mut();
+				// `recency` is updated when the entry is about to be evicted.
+				*last_use = self.tick;
+				self.hits += 1;
+				Some(value)
+			},
+			RawEntryMut::Vacant(_) => {
+				self.misses += 1;
+				None
+			},
+		}
+	}
+
+	/// Insert `value` of size `size` at `key`, as the most recently used entry, evicting the
+	/// least recently used entries to make room for it. Entries larger than the cache are not
+	/// inserted.
+	fn insert(&mut self, key: K, value: V, size: usize) {
+		if size > self.max_bytes {
+			return;
+		}
+		self.tick += 1;
+		let tick = self.tick;
+		match self.entries.insert(key.clone(), (value, size, tick)) {
+			// the entry keeps its place in `recency`, updated on eviction.
+			Some((_, old_size, _)) => self.bytes -= old_size,
+			None => {
+				self.recency.insert(tick, key);
+			},
+		}
+		self.bytes += size;
+		while self.bytes > self.max_bytes {
+			let (indexed, evicted) = self.recency.pop_first()
+				.expect("the cache holds more bytes than its budget; qed");
+			let (_, _, last_use) = self.entries[&evicted];
+			if last_use != indexed {
+				// used since indexed: index it at its last use.
+				self.recency.insert(last_use, evicted);
+				continue;
+			}
+			let (_, size, _) = self.entries.remove(&evicted).expect("recency indexes entries; qed");
+			self.bytes -= size;
+		}
+	}
+
+	/// Insert `value` of size `size` at `key` as the least recently used entry, if `key` has no
+	/// entry and `value` fits in the room left.
+	#[cfg(feature = "std")]
+	fn insert_oldest(&mut self, key: K, value: V, size: usize) -> bool {
+		if self.entries.contains_key(&key) || self.bytes + size > self.max_bytes {
+			return false;
+		}
+		self.oldest -= 1;
+		self.recency.insert(self.oldest, key.clone());
+		self.entries.insert(key, (value, size, self.oldest));
+		self.bytes += size;
+		true
+	}
+
+	/// The entries, from the most to the least recently used.
+	#[cfg(feature = "std")]
+	fn by_recency(&self) -> Vec<(&K, &V)> {
+		let mut entries: Vec<_> = self.entries.iter().collect();
+		entries.sort_by_key(|(_, (_, _, last_use))| crate::rstd::cmp::Reverse(*last_use));
+		entries.into_iter().map(|(key, (value, _, _))| (key, value)).collect()
+	}
+
+	fn clear(&mut self) {
+		self.entries.clear();
+		self.recency.clear();
+		self.bytes = 0;
+	}
+}
+
+/// A cache of decoded nodes, bounded by the size of their encoding and evicting the least
+/// recently used nodes.
+///
+/// A `ValueCache`, with a size limit of its own, can be added with `with_values`. With the
+/// `hash-interning` feature, the child hashes of the cached nodes can be interned with
+/// `with_interning`.
+pub struct NodeCache<L: TrieLayout> {
+	nodes: RefCell<Lru<TrieHash<L>, CachedNode<L>>>,
+	values: Option<ValueCache<L>>,
+	#[cfg(feature = "hash-interning")]
+	interner: Option<HashInterner<L::Hash>>,
+}
+
+/// A node held by a `NodeCache`.
+enum CachedNode<L: TrieLayout> {
+	Decoded(SharedNode<L::Nibble>),
+	/// A node whose child hashes are in the interner of the cache, decoded again when read.
+	#[cfg(feature = "hash-interning")]
+	Interned(InternedNode<L::Hash>),
+	/// Without interning, a cached node does not depend on the layout.
+	#[cfg(not(feature = "hash-interning"))]
+	#[allow(dead_code)]
+	Never(PhantomData<L>, Infallible),
+}
+
+impl<L: TrieLayout> CachedNode<L> {
+	fn node(&self) -> SharedNode<L::Nibble> {
+		match self {
+			CachedNode::Decoded(node) => node.clone(),
+			#[cfg(feature = "hash-interning")]
+			CachedNode::Interned(node) => SharedNode::new(node.to_owned_node::<L::Codec>()
+				.expect("the node was decoded when it was cached; qed")),
+			#[cfg(not(feature = "hash-interning"))]
+			CachedNode::Never(_, never) => match *never {},
+		}
+	}
+
+	/// Encoding of the node.
+	#[cfg(feature = "std")]
+	fn encoded(&self) -> Cow<'_, [u8]> {
+		match self {
+			CachedNode::Decoded(node) => node.data().into(),
+			#[cfg(feature = "hash-interning")]
+			CachedNode::Interned(node) => node.encoded().into(),
+			#[cfg(not(feature = "hash-interning"))]
+			CachedNode::Never(_, never) => match *never {},
+		}
+	}
+}
+
+impl<L: TrieLayout> NodeCache<L> {
+	/// Create an empty cache holding nodes of at most `max_bytes` bytes of encoding in total.
+	pub fn new(max_bytes: usize) -> Self {
+		NodeCache {
+			nodes: RefCell::new(Lru::new(max_bytes)),
+			values: None,
+			#[cfg(feature = "hash-interning")]
+			interner: None,
+		}
+	}
+
+	/// Create an empty cache holding nodes of at most `max_bytes` bytes of encoding in total,
+	/// with a `ValueCache` holding lookups of at most `max_value_bytes` bytes in total.
+	pub fn with_values(max_bytes: usize, max_value_bytes: usize) -> Self {
+		NodeCache { values: Some(ValueCache::new(max_value_bytes)), ..Self::new(max_bytes) }
+	}
+
+	/// Intern the child hashes of the cached nodes in a `HashInterner` owned by the cache, so
+	/// that the nodes referencing the same child share its hash. An interned node is decoded
+	/// again each time it is read from the cache: interning trades this decoding for the
+	/// memory of the hashes shared by the cached nodes, as those of the tries of successive
+	/// roots. The size limit of the cache still counts the full encoding of the nodes.
+	#[cfg(feature = "hash-interning")]
+	pub fn with_interning(mut self) -> Self {
+		self.interner = Some(HashInterner::new());
+		self
+	}
+
+	/// The interner of the child hashes of the cached nodes, if any, see `with_interning`.
+	#[cfg(feature = "hash-interning")]
+	pub fn interner(&self) -> Option<&HashInterner<L::Hash>> {
+		self.interner.as_ref()
+	}
+
+	/// The cache of values, if any.
+	pub fn values(&self) -> Option<&ValueCache<L>> {
+		self.values.as_ref()
+	}
+
+	/// Get the node with hash `hash`, counting a hit or a miss.
+	pub fn get(&self, hash: &TrieHash<L>) -> Option<SharedNode<L::Nibble>> {
+		self.nodes.borrow_mut().get(hash).map(CachedNode::node)
+	}
+
+	/// Insert the node `node` with hash `hash`, as the most recently used node, evicting the
+	/// least recently used nodes to make room for it. Nodes larger than the cache are not
+	/// inserted.
+	pub fn insert(&self, hash: TrieHash<L>, node: SharedNode<L::Nibble>) {
+		let size = node.data().len();
+		let node = self.cached(node);
+		self.nodes.borrow_mut().insert(hash, node, size);
+		#[cfg(feature = "hash-interning")]
+		if let Some(interner) = &self.interner {
+			interner.collect_garbage_if_grown();
+		}
+	}
+
+	/// `node` as held by the cache, its child hashes interned if the cache interns them.
+	fn cached(&self, node: SharedNode<L::Nibble>) -> CachedNode<L> {
+		#[cfg(feature = "hash-interning")]
+		if let Some(interner) = &self.interner {
+			return CachedNode::Interned(InternedNode::from_owned_node(&node, Some(interner)));
+		}
+		CachedNode::Decoded(node)
+	}
+
+	/// Remove all the nodes, keeping the counters. The values are kept.
+	pub fn clear(&self) {
+		self.nodes.borrow_mut().clear();
+		#[cfg(feature = "hash-interning")]
+		if let Some(interner) = &self.interner {
+			interner.collect_garbage();
+		}
+	}
+
+	/// Write the cached nodes to `writer`, from the most to the least recently used.
+	#[cfg(feature = "std")]
+	pub fn save_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+		writer.write_all(&MAGIC)?;
+		for (hash, node) in self.nodes.borrow().by_recency() {
+			let data = node.encoded();
+			let len = u32::try_from(data.len())
+				.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "node too large"))?;
+			writer.write_all(hash.as_ref())?;
+			writer.write_all(&len.to_le_bytes())?;
+			writer.write_all(&data)?;
+		}
+		writer.flush()
+	}
+
+	/// Read nodes saved with `save_to` from `reader`, up to `max_bytes` bytes of nodes.
+	///
+	/// The nodes are read one at a time, and stop being read once the next node would go
+	/// over `max_bytes` or the size of the cache. Nodes which do not hash to their hash, or
+	/// cannot be decoded, are skipped. The loaded nodes are less recently used than the nodes
+	/// already in the cache, and keep the order they were saved in.
+	#[cfg(feature = "std")]
+	pub fn load_from<R: Read>(&self, mut reader: R, max_bytes: usize) -> io::Result<LoadSummary> {
+		let mut magic = [0u8; 4];
+		reader.read_exact(&mut magic)?;
+		if magic != MAGIC {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "not a saved node cache"));
+		}
+
+		let mut summary = LoadSummary::default();
+		let mut budget = max_bytes;
+		let mut hash = TrieHash::<L>::default();
+		loop {
+			// a clean end of input between two entries ends the cache.
+			if !read_exact_or_eof(&mut reader, hash.as_mut())? {
+				break;
+			}
+			let mut len = [0u8; 4];
+			reader.read_exact(&mut len)?;
+			let len = u32::from_le_bytes(len) as usize;
+			let fits = {
+				let nodes = self.nodes.borrow();
+				len <= budget && nodes.bytes + len <= nodes.max_bytes
+			};
+			if !fits {
+				break;
+			}
+			let mut data = vec![0; len];
+			reader.read_exact(&mut data)?;
+			if L::Hash::hash(&data) != hash {
+				summary.corrupt += 1;
+				continue;
+			}
+			let node = match OwnedNode::new::<L::Codec>(data) {
+				Ok(node) => self.cached(SharedNode::new(node)),
+				Err(_) => {
+					summary.corrupt += 1;
+					continue;
+				},
+			};
+			// entries already cached keep their place.
+			if self.nodes.borrow_mut().insert_oldest(hash, node, len) {
+				budget -= len;
+				summary.loaded += 1;
+			}
+		}
+		Ok(summary)
+	}
+
+	/// Hit and miss counters, and size of the cache.
+	pub fn stats(&self) -> NodeCacheStats {
+		let nodes = self.nodes.borrow();
+		NodeCacheStats {
+			hits: nodes.hits,
+			misses: nodes.misses,
+			nodes: nodes.entries.len(),
+			bytes: nodes.bytes,
+		}
+	}
+}
+
+/// Root of a trie and key in it.
+type ValueKey<L> = (TrieHash<L>, Vec<u8>);
+
+/// A cache of the results of `Trie::get` on `TrieDB`, absent keys included, indexed by root and
+/// key, bounded by the size of their keys and values and evicting the least recently used
+/// lookups.
+pub struct ValueCache<L: TrieLayout> {
+	values: RefCell<Lru<ValueKey<L>, Option<DBValue>>>,
+}
+
+impl<L: TrieLayout> ValueCache<L> {
+	/// Create an empty cache holding lookups of at most `max_bytes` bytes in total.
+	pub fn new(max_bytes: usize) -> Self {
+		ValueCache { values: RefCell::new(Lru::new(max_bytes)) }
+	}
+
+	/// Get the value of `key` in the trie at `root`, `Some(None)` if `key` is known to have no
+	/// value, counting a hit or a miss.
+	pub fn get(&self, root: &TrieHash<L>, key: &[u8]) -> Option<Option<DBValue>> {
+		// a `Vec<u8>` hashes as the slice it holds.
+		let is_key = |(entry_root, entry_key): &(TrieHash<L>, Vec<u8>)|
+			entry_root == root && entry_key == key;
+		self.values.borrow_mut().get_with(&(root, key), is_key).cloned()
+	}
+
+	/// Insert `value`, the value of `key` in the trie at `root` or `None` if `key` has no value,
+	/// as the most recently used lookup.
+	pub fn insert(&self, root: TrieHash<L>, key: &[u8], value: Option<DBValue>) {
+		let size = root.as_ref().len() + key.len() + value.as_ref().map_or(0, |value| value.len());
+		self.values.borrow_mut().insert((root, key.to_vec()), value, size)
+	}
+
+	/// Remove all the lookups, keeping the counters.
+	pub fn clear(&self) {
+		self.values.borrow_mut().clear()
+	}
+
+	/// Hit and miss counters, and size of the cache.
+	pub fn stats(&self) -> ValueCacheStats {
+		let values = self.values.borrow();
+		ValueCacheStats {
+			hits: values.hits,
+			misses: values.misses,
+			entries: values.entries.len(),
+			bytes: values.bytes,
+		}
+	}
+}
+
+/// Fill `buf` from `reader`, returning false if `reader` is at its end.
+#[cfg(feature = "std")]
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+	let mut read = 0;
+	while read < buf.len() {
+		match reader.read(&mut buf[read..]) {
+			Ok(0) if read == 0 => return Ok(false),
+			Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+			Ok(n) => read += n,
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(true)
+}
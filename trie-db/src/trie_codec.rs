@@ -25,20 +25,20 @@
 //! expected to save roughly (n - 1) hashes in size where n is the number of nodes in the partial
 //! trie.
 
-use hash_db::HashDB;
+use hash_db::{HashDB, EMPTY_PREFIX};
 use crate::{
-	CError, ChildReference, DBValue, NibbleVec, NodeCodec, Result,
+	CError, ChildReference, NibbleVec, NodeCodec, Result, SharedNode,
 	TrieHash, TrieError, TrieDB, TrieDBNodeIterator, TrieLayout,
-	nibble_ops::NIBBLE_LENGTH, node::{Node, NodeHandle, NodeHandlePlan, NodePlan, OwnedNode},
+	nibble::{Children, NibbleOps}, node::{Node, NodeHandle, NodeHandlePlan, NodePlan},
 };
 use crate::rstd::{
-	boxed::Box, convert::TryInto, marker::PhantomData, rc::Rc, result, vec, vec::Vec,
+	boxed::Box, convert::TryInto, marker::PhantomData, result, vec, vec::Vec,
 };
 
 struct EncoderStackEntry<C: NodeCodec> {
 	/// The prefix is the nibble path to the node in the trie.
-	prefix: NibbleVec,
-	node: Rc<OwnedNode<DBValue>>,
+	prefix: NibbleVec<C::Nibble>,
+	node: SharedNode<C::Nibble>,
 	/// The next entry in the stack is a child of the preceding entry at this index. For branch
 	/// nodes, the index is in [0, NIBBLE_LENGTH] and for extension nodes, the index is in [0, 1].
 	child_index: usize,
@@ -59,7 +59,7 @@ impl<C: NodeCodec> EncoderStackEntry<C> {
 	/// Preconditions:
 	/// - self.prefix + partial must be a prefix of child_prefix.
 	/// - if self.node is a branch, then child_prefix must be longer than self.prefix + partial.
-	fn advance_child_index(&mut self, child_prefix: &NibbleVec)
+	fn advance_child_index(&mut self, child_prefix: &NibbleVec<C::Nibble>)
 		-> result::Result<(), &'static str>
 	{
 		match self.node.node_plan() {
@@ -95,35 +95,46 @@ impl<C: NodeCodec> EncoderStackEntry<C> {
 	}
 
 	/// Generates the encoding of the subtrie rooted at this entry.
+	///
+	/// The compact node is at most as long as the node: its buffer is allocated once.
 	fn encode_node(&self) -> Result<Vec<u8>, C::HashOut, C::Error> {
 		let node_data = self.node.data();
-		Ok(match self.node.node_plan() {
-			NodePlan::Empty | NodePlan::Leaf { .. } => node_data.to_vec(),
+		let mut output = Vec::with_capacity(node_data.len());
+		match self.node.node_plan() {
+			NodePlan::Empty | NodePlan::Leaf { .. } => output.extend_from_slice(node_data),
 			NodePlan::Extension { partial, child: _ } => {
 				if !self.omit_children[0] {
-					node_data.to_vec()
+					output.extend_from_slice(node_data)
 				} else {
 					let partial = partial.build(node_data);
 					let empty_child = ChildReference::Inline(C::HashOut::default(), 0);
-					C::extension_node(partial.right_iter(), partial.len(), empty_child)
+					C::extension_node_into(
+						partial.right_iter(),
+						partial.len(),
+						empty_child,
+						&mut output,
+					)
 				}
 			}
 			NodePlan::Branch { value, children } => {
-				C::branch_node(
-					Self::branch_children(node_data, &children, &self.omit_children)?.iter(),
-					value.clone().map(|range| &node_data[range])
+				C::branch_node_into(
+					Self::branch_children(node_data, children, &self.omit_children)?.iter(),
+					value.clone().map(|range| &node_data[range]),
+					&mut output,
 				)
 			}
 			NodePlan::NibbledBranch { partial, value, children } => {
 				let partial = partial.build(node_data);
-				C::branch_node_nibbled(
+				C::branch_node_nibbled_into(
 					partial.right_iter(),
 					partial.len(),
-					Self::branch_children(node_data, &children, &self.omit_children)?.iter(),
-					value.clone().map(|range| &node_data[range])
+					Self::branch_children(node_data, children, &self.omit_children)?.iter(),
+					value.clone().map(|range| &node_data[range]),
+					&mut output,
 				)
 			}
-		})
+		}
+		Ok(output)
 	}
 
 	/// Generate the list of child references for a branch node with certain children omitted.
@@ -133,13 +144,13 @@ impl<C: NodeCodec> EncoderStackEntry<C> {
 	/// - omit_children[i] is only true if child_handles[i] is Some
 	fn branch_children(
 		node_data: &[u8],
-		child_handles: &[Option<NodeHandlePlan>; NIBBLE_LENGTH],
+		child_handles: &Children<NodeHandlePlan, C::Nibble>,
 		omit_children: &[bool],
-	) -> Result<[Option<ChildReference<C::HashOut>>; NIBBLE_LENGTH], C::HashOut, C::Error>
+	) -> Result<Children<ChildReference<C::HashOut>, C::Nibble>, C::HashOut, C::Error>
 	{
 		let empty_child = ChildReference::Inline(C::HashOut::default(), 0);
-		let mut children = [None; NIBBLE_LENGTH];
-		for i in 0..NIBBLE_LENGTH {
+		let mut children = Children::new();
+		for i in 0..C::Nibble::NIBBLE_LENGTH {
 			children[i] = if omit_children[i] {
 				Some(empty_child)
 			} else if let Some(child_plan) = &child_handles[i] {
@@ -199,7 +210,7 @@ pub fn encode_compact<L>(db: &TrieDB<L>) -> Result<Vec<Vec<u8>>, TrieHash<L>, CE
 				// direct parent as the nodes are yielded from the iterator in pre-order traversal
 				// order.
 				while let Some(mut last_entry) = stack.pop() {
-					if prefix.starts_with(&last_entry.prefix) {
+					if prefix.starts_with_vec(&last_entry.prefix) {
 						// advance_child_index preconditions are satisfied because of iterator
 						// correctness.
 						last_entry.advance_child_index(&prefix)
@@ -219,7 +230,8 @@ pub fn encode_compact<L>(db: &TrieDB<L>) -> Result<Vec<Vec<u8>>, TrieHash<L>, CE
 				let children_len = match node.node_plan() {
 					NodePlan::Empty | NodePlan::Leaf { .. } => 0,
 					NodePlan::Extension { .. } => 1,
-					NodePlan::Branch { .. } | NodePlan::NibbledBranch { .. } => NIBBLE_LENGTH,
+					NodePlan::Branch { .. } | NodePlan::NibbledBranch { .. } =>
+						L::Nibble::NIBBLE_LENGTH,
 				};
 				stack.push(EncoderStackEntry {
 					prefix,
@@ -237,7 +249,7 @@ pub fn encode_compact<L>(db: &TrieDB<L>) -> Result<Vec<Vec<u8>>, TrieHash<L>, CE
 				// If we hit an IncompleteDatabaseError, just ignore it and continue encoding the
 				// incomplete trie. This encoding must support partial tries, which can be used for
 				// space-efficient storage proofs.
-				TrieError::IncompleteDatabase(_) => {},
+				TrieError::IncompleteDatabase { .. } => {},
 				_ => return Err(err),
 			}
 		}
@@ -251,7 +263,7 @@ pub fn encode_compact<L>(db: &TrieDB<L>) -> Result<Vec<Vec<u8>>, TrieHash<L>, CE
 }
 
 struct DecoderStackEntry<'a, C: NodeCodec> {
-	node: Node<'a>,
+	node: Node<'a, C::Nibble>,
 	/// The next entry in the stack is a child of the preceding entry at this index. For branch
 	/// nodes, the index is in [0, NIBBLE_LENGTH] and for extension nodes, the index is in [0, 1].
 	child_index: usize,
@@ -285,8 +297,8 @@ impl<'a, C: NodeCodec> DecoderStackEntry<'a, C> {
 				}
 				self.child_index += 1;
 			}
-			Node::Branch(children, _) | Node::NibbledBranch(_, children, _) => {
-				while self.child_index < NIBBLE_LENGTH {
+			Node::Branch(ref children, _) | Node::NibbledBranch(_, ref children, _) => {
+				while self.child_index < C::Nibble::NIBBLE_LENGTH {
 					match children[self.child_index] {
 						Some(NodeHandle::Inline(data)) if data.is_empty() =>
 							return Ok(false),
@@ -309,7 +321,7 @@ impl<'a, C: NodeCodec> DecoderStackEntry<'a, C> {
 
 	/// Push the partial key of this entry's node (including the branch nibble) to the given
 	/// prefix.
-	fn push_to_prefix(&self, prefix: &mut NibbleVec) {
+	fn push_to_prefix(&self, prefix: &mut NibbleVec<C::Nibble>) {
 		match self.node {
 			Node::Empty => {}
 			Node::Leaf(partial, _) | Node::Extension(partial, _) => {
@@ -327,7 +339,7 @@ impl<'a, C: NodeCodec> DecoderStackEntry<'a, C> {
 
 	/// Pop the partial key of this entry's node (including the branch nibble) from the given
 	/// prefix.
-	fn pop_from_prefix(&self, prefix: &mut NibbleVec) {
+	fn pop_from_prefix(&self, prefix: &mut NibbleVec<C::Nibble>) {
 		match self.node {
 			Node::Empty => {}
 			Node::Leaf(partial, _) | Node::Extension(partial, _) => {
@@ -396,7 +408,7 @@ pub fn decode_compact<L, DB, T>(db: &mut DB, encoded: &[Vec<u8>])
 	let mut stack: Vec<DecoderStackEntry<L::Codec>> = Vec::new();
 
 	// The prefix of the next item to be read from the slice of encoded items.
-	let mut prefix = NibbleVec::new();
+	let mut prefix = NibbleVec::<L::Nibble>::new();
 
 	for (i, encoded_node) in encoded.iter().enumerate() {
 		let node = L::Codec::decode(encoded_node)
@@ -405,7 +417,7 @@ pub fn decode_compact<L, DB, T>(db: &mut DB, encoded: &[Vec<u8>])
 		let children_len = match node {
 			Node::Empty | Node::Leaf(..) => 0,
 			Node::Extension(..) => 1,
-			Node::Branch(..) | Node::NibbledBranch(..) => NIBBLE_LENGTH,
+			Node::Branch(..) | Node::NibbledBranch(..) => L::Nibble::NIBBLE_LENGTH,
 		};
 		let mut last_entry = DecoderStackEntry {
 			node,
@@ -438,7 +450,7 @@ pub fn decode_compact<L, DB, T>(db: &mut DB, encoded: &[Vec<u8>])
 		}
 	}
 
-	Err(Box::new(TrieError::IncompleteDatabase(<TrieHash<L>>::default())))
+	Err(Box::new(TrieError::incomplete_database(<TrieHash<L>>::default(), EMPTY_PREFIX)))
 }
 
 #[cfg(test)]
@@ -583,6 +595,67 @@ mod tests {
 		test_decode_compact::<NoExtensionLayout>(&encoded, items, root, encoded.len() - 1);
 	}
 
+	fn check_random_round_trips<L: TrieLayout>() {
+		use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+		for seed in 0..100 {
+			let mut rng = SmallRng::seed_from_u64(seed);
+			let key = |rng: &mut SmallRng| -> Vec<u8> {
+				(0..rng.gen_range(0, 4)).map(|_| rng.gen_range(0, 4) * 0x11).collect()
+			};
+			let mut db = <MemoryDB<L::Hash>>::default();
+			let mut root = Default::default();
+			{
+				let mut trie = <TrieDBMut<L>>::new(&mut db, &mut root);
+				for _ in 0..rng.gen_range(0, 50) {
+					let key = key(&mut rng);
+					// short values make inline nodes.
+					let len = if rng.gen() { rng.gen_range(1, 4) } else { rng.gen_range(30, 40) };
+					let value: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+					trie.insert(&key, &value).unwrap();
+				}
+			}
+
+			// Record the lookups of a few keys, present or not.
+			let mut recorder = Recorder::new();
+			let trie = <TrieDB<L>>::new(&db, &root).unwrap();
+			let items: Vec<_> = (0..rng.gen_range(1, 8)).map(|_| {
+				let key = key(&mut rng);
+				let value = trie.get_with(&key, &mut recorder).unwrap();
+				(key, value)
+			}).collect();
+			let mut partial_db = <MemoryDB<L::Hash>>::default();
+			let mut recorded_size = 0;
+			for record in recorder.drain() {
+				recorded_size += record.data.len();
+				partial_db.insert(EMPTY_PREFIX, &record.data);
+			}
+
+			let encoded = encode_compact::<L>(&<TrieDB<L>>::new(&partial_db, &root).unwrap())
+				.unwrap();
+			assert!(encoded.iter().map(|node| node.len()).sum::<usize>() <= recorded_size);
+			let mut decoded_db = MemoryDB::default();
+			let (decoded_root, used) = decode_compact::<L, _, _>(&mut decoded_db, &encoded)
+				.unwrap();
+			assert_eq!(decoded_root, root, "seed {}", seed);
+			assert_eq!(used, encoded.len());
+			let decoded_trie = <TrieDB<L>>::new(&decoded_db, &root).unwrap();
+			for (key, value) in items {
+				assert_eq!(decoded_trie.get(&key).unwrap(), value, "seed {}", seed);
+			}
+		}
+	}
+
+	#[test]
+	fn random_round_trips_with_ext() {
+		check_random_round_trips::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn random_round_trips_without_ext() {
+		check_random_round_trips::<NoExtensionLayout>();
+	}
+
 	#[test]
 	fn trie_decoding_fails_with_incomplete_database() {
 		let (_, encoded, _) = test_encode_compact::<ExtensionLayout>(
@@ -601,7 +674,7 @@ mod tests {
 		let mut db = MemoryDB::default();
 		match decode_compact::<ExtensionLayout, _, _>(&mut db, &encoded[..encoded.len() - 1]) {
 			Err(err) => match *err {
-				TrieError::IncompleteDatabase(_) => {}
+				TrieError::IncompleteDatabase { .. } => {}
 				_ => panic!("got unexpected TrieError"),
 			}
 			_ => panic!("decode was unexpectedly successful"),
@@ -12,18 +12,100 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use hash_db::{HashDBRef, Prefix, EMPTY_PREFIX};
-use crate::nibble::NibbleSlice;
-use crate::iterator::TrieDBNodeIterator;
-use crate::rstd::boxed::Box;
-use super::node::{NodeHandle, Node, OwnedNode, decode_hash};
-use super::lookup::Lookup;
-use super::{Result, DBValue, Trie, TrieItem, TrieError, TrieIterator, Query,
+use hash_db::{HashDBRef, Hasher, OwnedPrefix, Prefix, EMPTY_PREFIX};
+use hashbrown::HashMap;
+use crate::nibble::{NibbleOps, NibbleSlice};
+use crate::iterator::{HashedNode, SharedNode, TrieDBNodeIterator, TrieIteratorState};
+use crate::rstd::{self, boxed::Box, cell::{Cell, RefCell}, mem, vec, vec::Vec};
+use super::node::{NodeHandle, Node, OwnedNode, Value, decode_hash};
+use super::lookup::{fetch_node, Lookup};
+use super::{Result, DBValue, NodeCodec, Trie, TrieItem, TrieError, TrieIterator, Query,
 	TrieLayout, CError, TrieHash};
+use super::child_trie::{child_keyspace, child_root, ChildTrie};
 use super::nibble::NibbleVec;
+use super::verify::{validate, ValidationResult};
+use super::dump::{dump, DumpOptions, Format};
+use super::stats::{statistics, TrieStats};
+use super::pin::{PinnedTrie, PruningGuard};
+use super::proof::{AbsenceProof, ValueRangeProof};
+use super::node_provider::{NodeProvider, NodeProviderError};
+use super::trie_cache::NodeCache;
+use super::recorder::Recorder;
+use super::subtriedb::SubTrieDB;
+use super::value_chunks::{decode_index, read_chunked, read_chunks};
 
-#[cfg(feature = "std")]
-use crate::rstd::{fmt, vec::Vec};
+use crate::rstd::fmt;
+
+/// A page of key-value pairs read by `TrieDB::read_prefix_page`, with the key to resume
+/// reading after, `None` if the page is the last one.
+pub type PrefixPage = (Vec<(Vec<u8>, DBValue)>, Option<Vec<u8>>);
+
+/// Key and mapped value yielded by `TrieDB::scan_filtered`.
+pub type FilteredItem<T, L> = Result<(Vec<u8>, T), TrieHash<L>, CError<L>>;
+
+/// Value returned by `TrieDB::get_with_path`, with the prefixes and hashes of the nodes fetched
+/// by its lookup, from the root.
+pub type ValueWithPath<L> = (DBValue, Vec<(NibbleVec<LayoutNibble<L>>, TrieHash<L>)>);
+
+/// Entry returned by `TrieDB::get_longest_prefix`.
+pub type LongestPrefixMatch<L> = Result<Option<(Vec<u8>, DBValue)>, TrieHash<L>, CError<L>>;
+
+/// Value yielded by `TrieDB::iter_values`.
+pub type ValueItem<L> = Result<DBValue, TrieHash<L>, CError<L>>;
+
+/// Key yielded by `TrieDB::iter_keys`.
+pub type KeyItem<L> = Result<Vec<u8>, TrieHash<L>, CError<L>>;
+
+/// A value yielded by `TrieDB::iter_leaves`, with the node holding it.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct LeafInfo<H> {
+	/// Key of the value.
+	pub key: Vec<u8>,
+	/// The value, read from its value node if it has one.
+	pub value: DBValue,
+	/// Hash the node is stored under, `None` if it is inline in its parent.
+	pub node_hash: Option<H>,
+	/// Size of the encoded node.
+	pub encoded_len: usize,
+	/// Whether the value is held by a branch rather than by a leaf.
+	pub branch_value: bool,
+}
+
+/// Value yielded by `TrieDB::iter_leaves`.
+pub type LeafItem<L> = Result<LeafInfo<TrieHash<L>>, TrieHash<L>, CError<L>>;
+
+/// Size of the pieces `TrieDB::get_chunked` streams values in.
+pub const VALUE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of nodes of each type in a trie, counted by `TrieDB::count_nodes`. Inline nodes are
+/// counted as the others.
+#[derive(Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct NodeCounts {
+	/// Number of leaves.
+	pub leaves: usize,
+	/// Number of branches, with or without partial key.
+	pub branches: usize,
+	/// Number of extensions.
+	pub extensions: usize,
+	/// Number of empty nodes, only the root of an empty trie.
+	pub empty: usize,
+}
+
+/// A range of keys `[start, end)`, an empty `end` standing for the end of the keyspace.
+pub type KeyRange = (Vec<u8>, Vec<u8>);
+
+/// Levels of branches `TrieDB::par_iter_split` goes down at most to split the trie.
+const SPLIT_LEVELS: usize = 3;
+
+/// A subtrie of `TrieDB::par_iter_split`: its node, the hash of the node or of the node
+/// holding it, and its path.
+type SplitSubtrie<L> =
+	(OwnedNode<DBValue, LayoutNibble<L>>, TrieHash<L>, NibbleVec<LayoutNibble<L>>);
+
+/// Nibble width of the layout `L`.
+type LayoutNibble<L> = <L as TrieLayout>::Nibble;
 
 /// A `Trie` implementation using a generic `HashDB` backing database, a `Hasher`
 /// implementation to generate keys and a `NodeCodec` implementation to encode/decode
@@ -55,6 +137,83 @@ where
 	root: &'db TrieHash<L>,
 	/// The number of hashes performed so far in operations on this trie.
 	hash_count: usize,
+	/// Whether decoded nodes are checked with `NodeCodec::check_layout`.
+	strict: bool,
+	/// Number of entries of the trie, once counted.
+	entry_count: Cell<Option<usize>>,
+	/// Cache of decoded nodes, see `new_with_cache`.
+	cache: Option<&'db NodeCache<L>>,
+	/// Source of the nodes missing from `db`, see `TrieDBBuilder::with_node_provider`.
+	node_provider: Option<&'db dyn NodeProvider<L::Hash>>,
+	/// Nodes fetched from `node_provider`, if kept.
+	provided: Option<RefCell<ProvidedNodes<L>>>,
+}
+
+/// Nodes fetched from a node provider, with their prefix.
+type ProvidedNodes<L> = HashMap<TrieHash<L>, (OwnedPrefix, DBValue)>;
+
+/// Builder of a `TrieDB` with a cache or a node provider.
+pub struct TrieDBBuilder<'db, L: TrieLayout> {
+	db: &'db dyn HashDBRef<L::Hash, DBValue>,
+	root: &'db TrieHash<L>,
+	cache: Option<&'db NodeCache<L>>,
+	node_provider: Option<&'db dyn NodeProvider<L::Hash>>,
+	keep_provided_nodes: bool,
+}
+
+impl<'db, L: TrieLayout> TrieDBBuilder<'db, L> {
+	/// Builder of a trie with the backing database `db` and `root`, checked as with
+	/// `TrieDB::new`.
+	pub fn new(db: &'db dyn HashDBRef<L::Hash, DBValue>, root: &'db TrieHash<L>) -> Self {
+		TrieDBBuilder { db, root, cache: None, node_provider: None, keep_provided_nodes: false }
+	}
+
+	/// Keep the decoded nodes in `cache`, see `TrieDB::new_with_cache`.
+	pub fn with_cache(mut self, cache: &'db NodeCache<L>) -> Self {
+		self.cache = Some(cache);
+		self
+	}
+
+	/// Fetch the nodes missing from the database from `provider`, the root included, before
+	/// reporting them as missing.
+	///
+	/// The fetched nodes are checked against their hash, a node which does not match being
+	/// reported as `TrieError::HashMismatch`, and a failure of `provider` as
+	/// `TrieError::NodeProviderFailed`.
+	pub fn with_node_provider(mut self, provider: &'db dyn NodeProvider<L::Hash>) -> Self {
+		self.node_provider = Some(provider);
+		self
+	}
+
+	/// Keep the nodes fetched from the node provider, for the trie to fetch them once, see
+	/// `TrieDB::provided_nodes`.
+	pub fn keep_provided_nodes(mut self) -> Self {
+		self.keep_provided_nodes = true;
+		self
+	}
+
+	/// Build the trie. Returns an error if the root is neither in the database nor provided.
+	pub fn build(self) -> Result<TrieDB<'db, L>, TrieHash<L>, CError<L>> {
+		let trie = TrieDB {
+			db: self.db,
+			root: self.root,
+			hash_count: 0,
+			strict: true,
+			entry_count: Cell::new(None),
+			cache: self.cache,
+			node_provider: self.node_provider,
+			provided: match self.keep_provided_nodes {
+				true => Some(RefCell::new(HashMap::new())),
+				false => None,
+			},
+		};
+		if !trie.db.contains(trie.root, EMPTY_PREFIX)
+			&& trie.provide_node(trie.root, EMPTY_PREFIX)?.is_none()
+		{
+			return Err(Box::new(TrieError::InvalidStateRoot(*trie.root)));
+		}
+		Ok(trie)
+	}
 }
 
 impl<'db, L> TrieDB<'db, L>
@@ -63,17 +222,772 @@ where
 {
 	/// Create a new trie with the backing database `db` and `root`
 	/// Returns an error if `root` does not exist
+	///
+	/// Nodes are checked against the layout with `NodeCodec::check_layout` as they are
+	/// decoded, inconsistent nodes being reported as `TrieError::DecoderError`.
 	pub fn new(
 		db: &'db dyn HashDBRef<L::Hash, DBValue>,
 		root: &'db TrieHash<L>
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		let mut trie = Self::new_lenient(db, root)?;
+		trie.strict = true;
+		Ok(trie)
+	}
+
+	/// Same as `new`, without checking decoded nodes against the layout.
+	pub fn new_lenient(
+		db: &'db dyn HashDBRef<L::Hash, DBValue>,
+		root: &'db TrieHash<L>
 	) -> Result<Self, TrieHash<L>, CError<L>> {
 		if !db.contains(root, EMPTY_PREFIX) {
 			Err(Box::new(TrieError::InvalidStateRoot(*root)))
 		} else {
-			Ok(TrieDB {db, root, hash_count: 0})
+			Ok(TrieDB {
+				db,
+				root,
+				hash_count: 0,
+				strict: false,
+				entry_count: Cell::new(None),
+				cache: None,
+				node_provider: None,
+				provided: None,
+			})
 		}
 	}
 
+	/// Same as `new`, the decoded nodes being kept in `cache` and read from it first, as are
+	/// the results of `get` if `cache` has a `ValueCache`.
+	///
+	/// Nodes are cached by hash and values by root and key, so `cache` can be shared with tries
+	/// with other roots in the same database, see `NodeCache`.
+	pub fn new_with_cache(
+		db: &'db dyn HashDBRef<L::Hash, DBValue>,
+		root: &'db TrieHash<L>,
+		cache: &'db NodeCache<L>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		let mut trie = Self::new(db, root)?;
+		trie.cache = Some(cache);
+		Ok(trie)
+	}
+
+	/// Create a new trie without checking the presence of the root in the database.
+	pub(crate) fn new_unchecked(
+		db: &'db dyn HashDBRef<L::Hash, DBValue>,
+		root: &'db TrieHash<L>
+	) -> Self {
+		TrieDB {
+			db,
+			root,
+			hash_count: 0,
+			strict: true,
+			entry_count: Cell::new(None),
+			cache: None,
+			node_provider: None,
+			provided: None,
+		}
+	}
+
+	/// Pin the root of this trie, registering it with `guard` until the returned
+	/// `PinnedTrie` and the iterators created from it are dropped. The first pin of a root
+	/// walks the trie, passing the hashes of its nodes to `guard`.
+	pub fn pin<'a>(
+		&'a self,
+		guard: Option<&'a dyn PruningGuard<TrieHash<L>>>,
+	) -> PinnedTrie<'a, 'db, L> {
+		PinnedTrie::new(self, guard)
+	}
+
+	/// Read a page of at most `limit` key-value pairs with keys starting with `prefix`, in key
+	/// order, beginning with the first key strictly greater than `start_after`.
+	///
+	/// When pairs remain after the page, the last key of the page is returned to be passed
+	/// as `start_after` for the next page, so no key is ever read twice.
+	///
+	/// Panics if `limit` is zero.
+	pub fn read_prefix_page(
+		&self,
+		prefix: &[u8],
+		start_after: Option<&[u8]>,
+		limit: usize,
+	) -> Result<PrefixPage, TrieHash<L>, CError<L>> {
+		assert!(limit > 0, "a page holds at least one pair");
+		let mut iter = TrieDBIterator::new(self)?;
+		match start_after {
+			Some(start) if start >= prefix => iter.seek_after(start)?,
+			_ => iter.seek(prefix)?,
+		}
+
+		let mut pairs = Vec::new();
+		for item in iter {
+			let (key, value) = item?;
+			if !key.starts_with(prefix) {
+				break;
+			}
+			if pairs.len() == limit {
+				let next = pairs.last().map(|(key, _): &(Vec<u8>, DBValue)| key.clone());
+				return Ok((pairs, next));
+			}
+			pairs.push((key, value));
+		}
+		Ok((pairs, None))
+	}
+
+	/// Iterate over the key-value pairs with keys starting with `prefix`, or over all of them,
+	/// yielding the result of `map` on the values accepted by `filter`.
+	///
+	/// `filter` and `map` borrow the values from the trie nodes, so pairs rejected by `filter`
+	/// cost no copy of their key or value.
+	pub fn scan_filtered<'a, F, M, T>(
+		&'a self,
+		prefix: Option<&[u8]>,
+		filter: F,
+		map: M,
+	) -> Result<impl Iterator<Item = FilteredItem<T, L>> + 'a, TrieHash<L>, CError<L>>
+		where
+			F: Fn(&[u8]) -> bool + 'a,
+			M: Fn(&[u8]) -> T + 'a,
+	{
+		let mut inner = TrieDBNodeIterator::new(self)?;
+		if let Some(prefix) = prefix {
+			inner.prefix(prefix)?;
+		}
+		Ok(rstd::iter::from_fn(move || {
+			while let Some(item) = inner.next_node() {
+				match item {
+					Ok((prefix, hash, node)) => {
+						let item = value_item_with(self, prefix, hash, &node, |value| {
+							if filter(value) { Some(map(value)) } else { None }
+						});
+						if item.is_some() {
+							return item;
+						}
+					},
+					Err(err) => return Some(Err(err)),
+				}
+			}
+			None
+		}))
+	}
+
+	/// Iterate over the values of the trie, in the order of their keys, without building the
+	/// keys: the nodes are still fetched at their prefix, but no key is copied for the values.
+	///
+	/// Values at keys with an odd number of nibbles are errors, as with `iter`.
+	pub fn iter_values<'a>(
+		&'a self,
+	) -> Result<impl Iterator<Item = ValueItem<L>> + 'a, TrieHash<L>, CError<L>> {
+		let mut inner = TrieDBNodeIterator::new(self)?;
+		Ok(rstd::iter::from_fn(move || {
+			while let Some(item) = inner.next_node() {
+				match item {
+					Ok((prefix, hash, node)) => {
+						let (partial_len, value) = match node.node() {
+							Node::Leaf(partial, value) => (partial.len(), Some(value)),
+							Node::Branch(_, value) => (0, value),
+							Node::NibbledBranch(partial, _, value) => (partial.len(), value),
+							_ => (0, None),
+						};
+						let value = match value {
+							Some(value) => value,
+							None => continue,
+						};
+						// The key is only built for the error on odd keys.
+						return match (prefix.len() + partial_len) % L::Nibble::NIBBLE_PER_BYTE {
+							0 => {
+								let hash = hash.unwrap_or_default();
+								Some(self.read_value(value, hash, prefix.as_prefix()))
+							},
+							_ => value_item(self, prefix, hash, &node)
+								.map(|item| item.map(|(_, value)| value)),
+						};
+					},
+					Err(err) => return Some(Err(err)),
+				}
+			}
+			None
+		}))
+	}
+
+	/// Iterate over the keys of the trie, in order, without reading the values: the values are
+	/// not copied from the nodes, and value nodes are not fetched.
+	///
+	/// Keys with an odd number of nibbles are errors, as with `iter`.
+	pub fn iter_keys<'a>(
+		&'a self,
+	) -> Result<impl Iterator<Item = KeyItem<L>> + 'a, TrieHash<L>, CError<L>> {
+		let mut inner = TrieDBNodeIterator::new(self)?;
+		Ok(rstd::iter::from_fn(move || {
+			while let Some(item) = inner.next_node() {
+				match item {
+					Ok((prefix, _, node)) => {
+						let partial = match node.node() {
+							Node::Leaf(partial, _) | Node::NibbledBranch(partial, _, Some(_)) =>
+								partial,
+							Node::Branch(_, Some(_)) => NibbleSlice::new(&[]),
+							_ => continue,
+						};
+						let mut key = prefix.clone();
+						key.append_partial(partial.right());
+						let (key_slice, maybe_extra_nibble) = key.as_prefix();
+						let key = key_slice.to_vec();
+						return Some(match maybe_extra_nibble {
+							Some(extra_nibble) =>
+								Err(Box::new(TrieError::ValueAtIncompleteKey(key, extra_nibble))),
+							None => Ok(key),
+						});
+					},
+					Err(err) => return Some(Err(err)),
+				}
+			}
+			None
+		}))
+	}
+	/// Iterate over the values of the trie, in the order of their keys, with the node holding
+	/// them: its hash, `None` for an inline node, the size of its encoding and whether it is a
+	/// branch.
+	///
+	/// Values at keys with an odd number of nibbles are errors, as with `iter`.
+	pub fn iter_leaves<'a>(
+		&'a self,
+	) -> Result<impl Iterator<Item = LeafItem<L>> + 'a, TrieHash<L>, CError<L>> {
+		let mut inner = TrieDBNodeIterator::new(self)?;
+		Ok(rstd::iter::from_fn(move || {
+			while let Some(item) = inner.next_node() {
+				match item {
+					Ok((prefix, hash, node)) => {
+						let item = match value_item(self, prefix, hash, &node) {
+							Some(item) => item,
+							None => continue,
+						};
+						let branch_value = !matches!(node.node(), Node::Leaf(..));
+						return Some(item.map(|(key, value)| LeafInfo {
+							key,
+							value,
+							node_hash: hash,
+							encoded_len: node.data().len(),
+							branch_value,
+						}));
+					},
+					Err(err) => return Some(Err(err)),
+				}
+			}
+			None
+		}))
+	}
+
+	/// Stream the value at `key` to `sink`, in pieces of at most `VALUE_CHUNK_SIZE` bytes,
+	/// without assembling the value: a value stored in chunks, see `TrieLayout::MAX_VALUE_CHUNK`,
+	/// is read one chunk at a time, and the pieces of the other values are borrowed from their
+	/// node. Returns the length of the value, `None` if `key` has no value.
+	pub fn get_chunked(
+		&self,
+		key: &[u8],
+		sink: &mut dyn FnMut(&[u8]),
+	) -> Result<Option<usize>, TrieHash<L>, CError<L>> {
+		let mut stream = |value: &[u8]| value.chunks(VALUE_CHUNK_SIZE).for_each(&mut *sink);
+		self.look_up_slot(key, |slot, hash, prefix| match Value::from_slot::<L>(slot, hash)? {
+			Value::Inline(value) => {
+				stream(value);
+				Ok(value.len())
+			},
+			Value::Node(value_hash) => {
+				let (_, value) = self.read_value_node(value_hash, hash, prefix)?;
+				stream(&value);
+				Ok(value.len())
+			},
+			Value::Chunked(index_hash) => {
+				let index_hash = decode_hash::<L::Hash>(index_hash)
+					.ok_or_else(|| Box::new(TrieError::InvalidHash(hash, index_hash.to_vec())))?;
+				let mut len = 0;
+				read_chunks::<L::Hash, _>(
+					&index_hash,
+					L::MAX_VALUE_CHUNK,
+					|node_hash| self.fetch_node(node_hash, prefix, false),
+					|_, data, index, chunk| match chunk {
+						Some(_) => stream(data),
+						None => len = index.len,
+					},
+				)?;
+				Ok(len)
+			},
+		})
+	}
+
+	/// Get the value at `key` with the path of its lookup: the nodes fetched by hash, with their
+	/// prefix, inline nodes being part of the nodes holding them.
+	pub fn get_with_path(
+		&self,
+		key: &[u8],
+	) -> Result<Option<ValueWithPath<L>>, TrieHash<L>, CError<L>> {
+		let mut path = Vec::new();
+		let value = self.get_with(key, PathQuery::<L::Hash, L::Nibble>(&mut path))?;
+		Ok(value.map(|value| (value, path)))
+	}
+
+	/// Prove that `key` is absent from the trie, with the nodes on its path and where the path
+	/// leaves the trie, see `AbsenceProof::verify`. Returns `None` if `key` has a value.
+	pub fn prove_absence(
+		&self,
+		key: &[u8],
+	) -> Result<Option<AbsenceProof<L::Nibble>>, TrieHash<L>, CError<L>> {
+		AbsenceProof::generate(self, key)
+	}
+
+	/// Prove the bytes `range` of the value at `key`, with the nodes on its path and the chunks
+	/// holding the range when the value is stored in chunks, see `ValueRangeProof::verify`.
+	/// Returns `None` if `key` has no value.
+	pub fn prove_value_range(
+		&self,
+		key: &[u8],
+		range: rstd::ops::Range<usize>,
+	) -> Result<Option<ValueRangeProof>, TrieHash<L>, CError<L>> {
+		ValueRangeProof::generate(self, key, range)
+	}
+
+	/// Open the child trie at `parent_key`, see the `child_trie` module. An absent key opens
+	/// an empty child trie.
+	pub fn child_trie(
+		&self,
+		parent_key: &[u8],
+	) -> Result<ChildTrie<'db, L>, TrieHash<L>, CError<L>> {
+		let root = child_root::<L>(self.root, self.get(parent_key)?)?;
+		ChildTrie::new(self.db, child_keyspace::<L::Hash>(parent_key), root)
+	}
+
+	/// Get the values at `keys`, in the order of `keys`. The keys are looked up in sorted order,
+	/// each lookup starting from the deepest node shared with the path of the previous key, so
+	/// nodes common to several keys are fetched and decoded once. Repeated keys are looked up once,
+	/// unless their lookup fails.
+	pub fn get_batch(
+		&self,
+		keys: &[&[u8]],
+	) -> Vec<Result<Option<DBValue>, TrieHash<L>, CError<L>>> {
+		let mut order: Vec<usize> = (0..keys.len()).collect();
+		order.sort_by_key(|&index| keys[index]);
+		let mut results: Vec<Option<Result<Option<DBValue>, _, _>>> =
+			(0..keys.len()).map(|_| None).collect();
+		let mut path = Vec::new();
+		let mut previous: Option<usize> = None;
+		for index in order {
+			let key = NibbleSlice::new(keys[index]);
+			if let Some(previous) = previous {
+				// errors are not cloneable, a repeated key failing is looked up again.
+				if let (true, Some(Ok(value))) = (keys[previous] == keys[index], &results[previous]) {
+					results[index] = Some(Ok(value.clone()));
+					continue;
+				}
+				let common = NibbleSlice::new(keys[previous]).common_prefix(&key);
+				let kept = path.iter().take_while(|step: &&BatchStep<L>| step.start <= common).count();
+				path.truncate(kept);
+			}
+			results[index] = Some(self.look_up_from(key, &mut path));
+			previous = Some(index);
+		}
+		results.into_iter().map(|result| result.expect("every key is looked up; qed")).collect()
+	}
+
+	/// Get the entry whose key is the longest prefix of `key`, `key` included, in a single walk
+	/// down the path of `key`.
+	pub fn get_longest_prefix(&self, key: &[u8]) -> LongestPrefixMatch<L> {
+		let query = NibbleSlice::new(key);
+		// nibble length of the key of the deepest value found, and the value.
+		let mut found = None;
+		let mut hash = *self.root;
+		let (mut node, _) =
+			self.get_raw_or_lookup(hash, NodeHandle::Hash(hash.as_ref()), EMPTY_PREFIX)?;
+		let mut start = 0;
+		loop {
+			let partial = query.mid(start);
+			let (child, skipped) = match node.node() {
+				Node::Empty => break,
+				Node::Leaf(slice, value) => {
+					if partial.starts_with(&slice) && (start + slice.len()) % 2 == 0 {
+						let value = self.read_value(value, hash, partial.left())?;
+						found = Some((start + slice.len(), value));
+					}
+					break
+				},
+				Node::Extension(slice, child) => match partial.starts_with(&slice) {
+					true => (child, slice.len()),
+					false => break,
+				},
+				Node::Branch(children, value) => {
+					if let (Some(value), 0) = (value, start % 2) {
+						found = Some((start, self.read_value(value, hash, partial.left())?));
+					}
+					match partial.try_at(0).and_then(|i| children[i as usize]) {
+						Some(child) => (child, 1),
+						None => break,
+					}
+				},
+				Node::NibbledBranch(slice, children, value) => {
+					if !partial.starts_with(&slice) {
+						break
+					}
+					let value_at = start + slice.len();
+					if let (Some(value), 0) = (value, value_at % 2) {
+						found = Some((value_at, self.read_value(value, hash, partial.left())?));
+					}
+					match partial.try_at(slice.len()).and_then(|i| children[i as usize]) {
+						Some(child) => (child, slice.len() + 1),
+						None => break,
+					}
+				},
+			};
+			start += skipped;
+			let (child, child_hash) = self.get_raw_or_lookup(hash, child, query.mid(start).left())?;
+			hash = child_hash.unwrap_or(hash);
+			node = child;
+		}
+		Ok(found.map(|(nibbles, value)| (key[..nibbles / 2].to_vec(), value)))
+	}
+
+	/// Get the smallest key strictly greater than `key`.
+	pub fn next_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieHash<L>, CError<L>> {
+		let mut iter = TrieDBIterator::new(self)?;
+		iter.seek_after(key)?;
+		iter.next().transpose().map(|item| item.map(|(key, _)| key))
+	}
+
+	/// Get the largest key strictly smaller than `key`.
+	pub fn prev_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieHash<L>, CError<L>> {
+		let mut iter = TrieDBIterator::new_reversed(self)?;
+		iter.seek_after(key)?;
+		iter.next().transpose().map(|item| item.map(|(key, _)| key))
+	}
+
+	/// Open a view of the subtrie of the keys starting with `prefix`, see `SubTrieDB`.
+	pub fn subtrie<'a>(
+		&'a self,
+		prefix: &[u8],
+	) -> Result<SubTrieDB<'a, 'db, L>, TrieHash<L>, CError<L>> {
+		SubTrieDB::new(self, prefix)
+	}
+
+	/// Split the keys of the trie into at most `n` disjoint ranges `[start, end)` covering all
+	/// the possible keys, for as many iterations to run in parallel, each seeking `start` and
+	/// stopping at the first key not before `end`. The first range starts at the empty key and
+	/// the last range has an empty `end`, standing for the end of the keyspace.
+	///
+	/// The bounds are the paths of the subtries under the branches of the first levels of the
+	/// trie, so the ranges hold about as many of these subtries, not as many keys. Fewer than
+	/// `n` ranges are returned when there are fewer subtries.
+	///
+	/// # Example
+	/// ```
+	/// use reference_trie::{RefTrieDBMut, RefTrieDB, Trie, TrieMut};
+	/// use keccak_hasher::KeccakHasher;
+	/// use memory_db::*;
+	///
+	/// let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, _>::default();
+	/// let mut root = Default::default();
+	/// {
+	///     let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+	///     for i in 0..1000u32 {
+	///         t.insert(&i.to_be_bytes(), &[1; 32]).unwrap();
+	///     }
+	/// }
+	/// let ranges = RefTrieDB::new(&memdb, &root).unwrap().par_iter_split(4).unwrap();
+	/// let db = &memdb;
+	/// let counts: Vec<usize> = std::thread::scope(|scope| {
+	///     let threads: Vec<_> = ranges.iter().map(|(start, end)| scope.spawn(move || {
+	///         let t = RefTrieDB::new(db, &root).unwrap();
+	///         let mut iter = t.iter().unwrap();
+	///         iter.seek(start).unwrap();
+	///         iter.map(|item| item.unwrap().0)
+	///             .take_while(|key| end.is_empty() || key < end)
+	///             .count()
+	///     })).collect();
+	///     threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+	/// });
+	/// assert_eq!(counts.iter().sum::<usize>(), 1000);
+	/// ```
+	pub fn par_iter_split(
+		&self,
+		n: usize,
+	) -> Result<Vec<KeyRange>, TrieHash<L>, CError<L>> {
+		let (root, _) = self.get_raw_or_lookup(
+			*self.root,
+			NodeHandle::Hash(self.root.as_ref()),
+			EMPTY_PREFIX,
+		)?;
+		let mut subtries = vec![(root, *self.root, NibbleVec::new())];
+		for _ in 0..SPLIT_LEVELS {
+			if subtries.len() >= n {
+				break;
+			}
+			let mut split = false;
+			let mut next = Vec::with_capacity(subtries.len() * L::Nibble::NIBBLE_LENGTH);
+			for (node, hash, path) in subtries {
+				split |= self.split_subtrie(node, hash, path, &mut next)?;
+			}
+			subtries = next;
+			if !split {
+				break;
+			}
+		}
+
+		// the subtries are in key order: the first of each group of consecutive subtries
+		// bounds the range of the previous group.
+		let groups = n.max(1).min(subtries.len());
+		let mut ranges = Vec::with_capacity(groups);
+		let mut start = Vec::new();
+		for group in 1..groups {
+			let end = subtries[group * subtries.len() / groups].2.inner().to_vec();
+			ranges.push((mem::replace(&mut start, end.clone()), end));
+		}
+		ranges.push((start, Vec::new()));
+		Ok(ranges)
+	}
+
+	/// Push to `subtries` the subtries under the branch at the top of the subtrie of `node` at
+	/// `path`, `hash` being the hash of the node or of the node holding it, or the subtrie
+	/// itself if it has no branch. Returns whether the subtrie was split.
+	fn split_subtrie(
+		&self,
+		node: OwnedNode<DBValue, L::Nibble>,
+		hash: TrieHash<L>,
+		mut path: NibbleVec<L::Nibble>,
+		subtries: &mut Vec<SplitSubtrie<L>>,
+	) -> Result<bool, TrieHash<L>, CError<L>> {
+		let (partial, children) = match node.node() {
+			Node::Empty | Node::Leaf(..) => {
+				subtries.push((node, hash, path));
+				return Ok(false);
+			},
+			Node::Extension(partial, child) => {
+				path.append_partial(partial.right());
+				let (child, child_hash) = self.get_raw_or_lookup(hash, child, path.as_prefix())?;
+				return self.split_subtrie(child, child_hash.unwrap_or(hash), path, subtries);
+			},
+			Node::Branch(children, _) => (None, children),
+			Node::NibbledBranch(partial, children, _) => (Some(partial), children),
+		};
+		if let Some(partial) = partial {
+			path.append_partial(partial.right());
+		}
+		for (index, child) in children.iter().enumerate() {
+			if let Some(child) = child {
+				path.push(index as u8);
+				let (child, child_hash) = self.get_raw_or_lookup(hash, *child, path.as_prefix())?;
+				subtries.push((child, child_hash.unwrap_or(hash), path.clone()));
+				path.pop();
+			}
+		}
+		Ok(true)
+	}
+
+	/// Look up `key` from the node with hash `hash` at nibble `start` on its path.
+	pub(crate) fn get_with_at<Q: Query<L::Hash>>(
+		&self,
+		key: &[u8],
+		hash: TrieHash<L>,
+		start: usize,
+		query: Q,
+	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>> {
+		Lookup::<L, Q> { db: self.db, query, hash }.look_up_through(
+			NibbleSlice::new(key),
+			start,
+			self.strict,
+			self.cache,
+			|hash, prefix, is_root| self.fetch_node(hash, prefix, is_root),
+		)
+	}
+
+	/// Look up `key` from the last node of `path`, a path of nodes leading to it, pushing the
+	/// nodes fetched below it. Starts from the root if `path` is empty.
+	fn look_up_from(
+		&self,
+		key: NibbleSlice<L::Nibble>,
+		path: &mut Vec<BatchStep<L>>,
+	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		if path.is_empty() {
+			let (node, _) = self.get_raw_or_lookup(
+				*self.root,
+				NodeHandle::Hash(self.root.as_ref()),
+				EMPTY_PREFIX,
+			)?;
+			path.push(BatchStep { node, start: 0, hash: *self.root });
+		}
+		loop {
+			let step = path.last().expect("the root is in the path; qed");
+			let partial = key.mid(step.start);
+			let (child, skipped) = match step.node.node() {
+				Node::Empty => return Ok(None),
+				Node::Leaf(slice, value) => return match slice == partial {
+					true => self.read_value(value, step.hash, partial.left()).map(Some),
+					false => Ok(None),
+				},
+				Node::Extension(slice, child) => match partial.starts_with(&slice) {
+					true => (child, slice.len()),
+					false => return Ok(None),
+				},
+				Node::Branch(children, value) => match partial.try_at(0) {
+					None => return value
+						.map(|value| self.read_value(value, step.hash, partial.left()))
+						.transpose(),
+					Some(i) => match children[i as usize] {
+						Some(child) => (child, 1),
+						None => return Ok(None),
+					},
+				},
+				Node::NibbledBranch(slice, children, value) => {
+					if !partial.starts_with(&slice) {
+						return Ok(None)
+					}
+					match partial.try_at(slice.len()) {
+						None => return value
+							.map(|value| self.read_value(value, step.hash, partial.left()))
+							.transpose(),
+						Some(i) => match children[i as usize] {
+							Some(child) => (child, slice.len() + 1),
+							None => return Ok(None),
+						},
+					}
+				},
+			};
+			let start = step.start + skipped;
+			let (node, hash) = self.get_raw_or_lookup(step.hash, child, key.mid(start).left())?;
+			let hash = hash.unwrap_or(step.hash);
+			path.push(BatchStep { node, start, hash });
+		}
+	}
+
+	/// Length of the value at `key`, without copying the value, only the index of the chunks
+	/// of a value stored in chunks being read. Returns `None` if `key` has no value.
+	pub fn value_length(&self, key: &[u8]) -> Result<Option<usize>, TrieHash<L>, CError<L>> {
+		self.look_up_slot(key, |slot, hash, prefix| match Value::from_slot::<L>(slot, hash)? {
+			Value::Inline(value) => Ok(value.len()),
+			Value::Node(value_hash) =>
+				self.read_value_node(value_hash, hash, prefix).map(|(_, value)| value.len()),
+			Value::Chunked(index_hash) => {
+				let (index_hash, data) = self.read_value_node(index_hash, hash, prefix)?;
+				decode_index::<L::Hash, _>(&index_hash, &data, L::MAX_VALUE_CHUNK)
+					.map(|index| index.len)
+			},
+		})
+	}
+
+	/// Look up `key`, passing its value slot to `on_value` with the hash of the node holding
+	/// it, or of the closest node above it for inline nodes, and the prefix of the node.
+	/// Returns `None` if `key` has no value.
+	pub(crate) fn look_up_slot<R>(
+		&self,
+		key: &[u8],
+		on_value: impl FnOnce(&[u8], TrieHash<L>, Prefix) -> Result<R, TrieHash<L>, CError<L>>,
+	) -> Result<Option<R>, TrieHash<L>, CError<L>> {
+		let lookup = Lookup::<L, _> { db: self.db, query: |_: &[u8]| (), hash: *self.root };
+		lookup.look_up_slot_through(
+			NibbleSlice::new(key),
+			0,
+			self.strict,
+			self.cache,
+			&|hash: &TrieHash<L>, prefix, is_root| self.fetch_node(hash, prefix, is_root),
+			|_, slot, hash, prefix, _| on_value(slot, hash, prefix),
+		)
+	}
+
+	/// Same as `look_up_slot`, appending the nodes fetched by hash on the path of `key` to
+	/// `path`, from the root. The node cache is bypassed, for every node to be fetched.
+	pub(crate) fn look_up_slot_with_path<R>(
+		&self,
+		key: &[u8],
+		path: &mut Vec<DBValue>,
+		on_value: impl FnOnce(&[u8], TrieHash<L>, Prefix) -> Result<R, TrieHash<L>, CError<L>>,
+	) -> Result<Option<R>, TrieHash<L>, CError<L>> {
+		let path = RefCell::new(path);
+		let lookup = Lookup::<L, _> { db: self.db, query: |_: &[u8]| (), hash: *self.root };
+		lookup.look_up_slot_through(
+			NibbleSlice::new(key),
+			0,
+			self.strict,
+			None,
+			&|hash: &TrieHash<L>, prefix, is_root| {
+				let data = self.fetch_node(hash, prefix, is_root)?;
+				path.borrow_mut().push(data.clone());
+				Ok(data)
+			},
+			|_, slot, hash, prefix, _| on_value(slot, hash, prefix),
+		)
+	}
+
+	/// Number of entries of the trie, the leaves and the values of the branches, which is the
+	/// number of items of a full iteration.
+	///
+	/// The count is kept, the iterators created afterwards reporting it as their size hint, and
+	/// returned by the next calls without walking the trie again.
+	pub fn count_entries(&self) -> Result<usize, TrieHash<L>, CError<L>> {
+		match self.entry_count.get() {
+			Some(count) => Ok(count),
+			None => self.count().map(|(_, entries)| entries),
+		}
+	}
+
+	/// Number of nodes of each type in the trie. Also counts the entries, see `count_entries`.
+	pub fn count_nodes(&self) -> Result<NodeCounts, TrieHash<L>, CError<L>> {
+		self.count().map(|(nodes, _)| nodes)
+	}
+
+	/// Walk the whole trie to check its integrity: the presence of its nodes in the database,
+	/// their hashes and the invariants of the layout, reporting every violation found instead
+	/// of failing at the first one, see `verify::ViolationKind`.
+	///
+	/// Nodes are not checked with `NodeCodec::check_layout`, the checks of `validate` covering
+	/// the invariants of the layout. Only a failure of the node provider is returned as an error.
+	pub fn validate(&self) -> ValidationResult<L> {
+		validate(self, &[])
+	}
+
+	/// Same as `validate`, only checking the nodes of the keys starting with `prefix`, and the
+	/// nodes on the path to them.
+	pub fn validate_from(&self, prefix: &[u8]) -> ValidationResult<L> {
+		validate(self, prefix)
+	}
+
+	/// Write the nodes of the trie to `out` as an indented tree, a node per line with its type,
+	/// partial key in hex nibbles, value, children bitmap and hash, see `DumpOptions`. The
+	/// nodes which cannot be read are shown as such, in place of their subtree.
+	pub fn fmt_tree(&self, out: &mut impl fmt::Write, options: DumpOptions) -> fmt::Result {
+		dump(self, out, &options, Format::Tree)
+	}
+
+	/// Same as `fmt_tree`, as a Graphviz DOT digraph.
+	pub fn fmt_dot(&self, out: &mut impl fmt::Write, options: DumpOptions) -> fmt::Result {
+		dump(self, out, &options, Format::Dot)
+	}
+
+	/// Statistics of the nodes of the trie: the sizes of each type of node, the depths of the
+	/// leaves, the number of inline children and the size of the stored nodes, see `TrieStats`.
+	///
+	/// The trie is walked once, in a space bounded by its depth.
+	pub fn statistics(&self) -> Result<TrieStats, TrieHash<L>, CError<L>> {
+		statistics(self)
+	}
+
+	/// Walk the trie, counting its nodes and entries, and keep the number of entries.
+	fn count(&self) -> Result<(NodeCounts, usize), TrieHash<L>, CError<L>> {
+		let mut nodes = NodeCounts::default();
+		let mut entries = 0;
+		let mut iter = TrieDBNodeIterator::new(self)?;
+		while let Some(item) = iter.next_node() {
+			let (_, _, node) = item?;
+			match node.node() {
+				Node::Empty => nodes.empty += 1,
+				Node::Leaf(..) => {
+					nodes.leaves += 1;
+					entries += 1;
+				},
+				Node::Extension(..) => nodes.extensions += 1,
+				Node::Branch(_, value) | Node::NibbledBranch(_, _, value) => {
+					nodes.branches += 1;
+					entries += value.map_or(0, |_| 1);
+				},
+			}
+		}
+		self.entry_count.set(Some(entries));
+		Ok((nodes, entries))
+	}
+
 	/// Get the backing database.
 	pub fn db(&'db self) -> &'db dyn HashDBRef<L::Hash, DBValue> { self.db }
 
@@ -90,28 +1004,193 @@ where
 		parent_hash: TrieHash<L>,
 		node_handle: NodeHandle,
 		partial_key: Prefix,
-	) -> Result<(OwnedNode<DBValue>, Option<TrieHash<L>>), TrieHash<L>, CError<L>> {
+	) -> Result<(OwnedNode<DBValue, L::Nibble>, Option<TrieHash<L>>), TrieHash<L>, CError<L>> {
+		let (node, node_hash) = self.get_shared_node(parent_hash, node_handle, partial_key)?;
+		let node = SharedNode::try_unwrap(node).unwrap_or_else(|node| (*node).clone());
+		Ok((node, node_hash))
+	}
+
+	/// Read the node with hash `hash` from the database, or else from the node provider, see
+	/// `lookup::fetch_node`.
+	fn fetch_node(
+		&self,
+		hash: &TrieHash<L>,
+		prefix: Prefix,
+		is_root: bool,
+	) -> Result<DBValue, TrieHash<L>, CError<L>> {
+		fetch_node::<L>(self.db, hash, prefix, is_root, || self.provide_node(hash, prefix))
+	}
+
+	/// The value of `slot`, the value slot of the node at `prefix`, read from the database if it
+	/// is stored apart. `hash` is the hash of the node, or of the closest node above it.
+	pub(crate) fn read_value(
+		&self,
+		slot: &[u8],
+		hash: TrieHash<L>,
+		prefix: Prefix,
+	) -> Result<DBValue, TrieHash<L>, CError<L>> {
+		match Value::from_slot::<L>(slot, hash)? {
+			Value::Inline(value) => Ok(value.to_vec()),
+			Value::Node(value_hash) =>
+				self.read_value_node(value_hash, hash, prefix).map(|(_, value)| value),
+			Value::Chunked(index_hash) => {
+				let index_hash = decode_hash::<L::Hash>(index_hash)
+					.ok_or_else(|| Box::new(TrieError::InvalidHash(hash, index_hash.to_vec())))?;
+				read_chunked::<L::Hash, _>(
+					&index_hash,
+					L::MAX_VALUE_CHUNK,
+					|node_hash| self.fetch_node(node_hash, prefix, false),
+					|_, _| (),
+				)
+			},
+		}
+	}
+
+	/// Read the nodes storing `value`, the value of the node with hash `hash` at `prefix`, apart:
+	/// its value node, or the index of its chunks and then the chunks. Each node read is passed
+	/// to `visit` with its hash.
+	pub(crate) fn read_value_nodes(
+		&self,
+		value: Value,
+		hash: TrieHash<L>,
+		prefix: Prefix,
+		mut visit: impl FnMut(&TrieHash<L>, &[u8]),
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		match value {
+			Value::Inline(_) => Ok(()),
+			Value::Node(value_hash) => {
+				let (value_hash, value) = self.read_value_node(value_hash, hash, prefix)?;
+				visit(&value_hash, &value);
+				Ok(())
+			},
+			Value::Chunked(index_hash) => {
+				let index_hash = decode_hash::<L::Hash>(index_hash)
+					.ok_or_else(|| Box::new(TrieError::InvalidHash(hash, index_hash.to_vec())))?;
+				read_chunks::<L::Hash, _>(
+					&index_hash,
+					L::MAX_VALUE_CHUNK,
+					|node_hash| self.fetch_node(node_hash, prefix, false),
+					|node_hash, data, _, _| visit(node_hash, data),
+				)
+			},
+		}
+	}
+
+	/// The hash and the value of the value node with encoded hash `value_hash`, referenced by the
+	/// node with hash `hash` at `prefix`.
+	pub(crate) fn read_value_node(
+		&self,
+		value_hash: &[u8],
+		hash: TrieHash<L>,
+		prefix: Prefix,
+	) -> Result<(TrieHash<L>, DBValue), TrieHash<L>, CError<L>> {
+		let value_hash = decode_hash::<L::Hash>(value_hash)
+			.ok_or_else(|| Box::new(TrieError::InvalidHash(hash, value_hash.to_vec())))?;
+		Ok((value_hash, self.fetch_node(&value_hash, prefix, false)?))
+	}
+
+	/// Fetch the node with hash `hash` from the node provider, if any, checking its hash.
+	fn provide_node(
+		&self,
+		hash: &TrieHash<L>,
+		prefix: Prefix,
+	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		let provider = match self.node_provider {
+			Some(provider) => provider,
+			None => return Ok(None),
+		};
+		if let Some(provided) = &self.provided {
+			if let Some((_, data)) = provided.borrow().get(hash) {
+				return Ok(Some(data.clone()));
+			}
+		}
+		let data = match provider.provide(hash, prefix) {
+			Ok(Some(data)) => data,
+			Ok(None) => return Ok(None),
+			Err(NodeProviderError) => return Err(Box::new(TrieError::NodeProviderFailed(*hash))),
+		};
+		let found = L::Hash::hash(&data);
+		if found != *hash {
+			return Err(Box::new(TrieError::HashMismatch {
+				expected: *hash,
+				found,
+				prefix: (prefix.0.to_vec(), prefix.1),
+			}));
+		}
+		if let Some(provided) = &self.provided {
+			provided.borrow_mut().insert(*hash, ((prefix.0.to_vec(), prefix.1), data.clone()));
+		}
+		Ok(Some(data))
+	}
+
+	/// The nodes fetched from the node provider so far, with their prefix, if kept with
+	/// `TrieDBBuilder::keep_provided_nodes`, as a batch for `HashDB::emplace_batch`.
+	pub fn provided_nodes(&self) -> Vec<(TrieHash<L>, OwnedPrefix, DBValue)> {
+		match &self.provided {
+			Some(provided) => provided.borrow().iter()
+				.map(|(hash, (prefix, data))| (*hash, prefix.clone(), data.clone()))
+				.collect(),
+			None => Vec::new(),
+		}
+	}
+
+	/// Same as `get_raw_or_lookup`, returning the node shared with the cache of the trie.
+	pub(crate) fn get_shared_node(
+		&self,
+		parent_hash: TrieHash<L>,
+		node_handle: NodeHandle,
+		partial_key: Prefix,
+	) -> Result<HashedNode<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
+		self.read_node(parent_hash, node_handle, partial_key, self.strict)
+	}
+
+	/// Same as `get_shared_node`, never checking the node against the layout, for the checks
+	/// of `validate`.
+	pub(crate) fn get_lenient_node(
+		&self,
+		parent_hash: TrieHash<L>,
+		node_handle: NodeHandle,
+		partial_key: Prefix,
+	) -> Result<HashedNode<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
+		self.read_node(parent_hash, node_handle, partial_key, false)
+	}
+
+	fn read_node(
+		&self,
+		parent_hash: TrieHash<L>,
+		node_handle: NodeHandle,
+		partial_key: Prefix,
+		strict: bool,
+	) -> Result<HashedNode<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
 		let (node_hash, node_data) = match node_handle {
 			NodeHandle::Hash(data) => {
 				let node_hash = decode_hash::<L::Hash>(data)
 					.ok_or_else(|| Box::new(TrieError::InvalidHash(parent_hash, data.to_vec())))?;
-				let node_data = self.db
-					.get(&node_hash, partial_key)
-					.ok_or_else(|| {
-						if partial_key == EMPTY_PREFIX {
-							Box::new(TrieError::InvalidStateRoot(node_hash))
-						} else {
-							Box::new(TrieError::IncompleteDatabase(node_hash))
-						}
-					})?;
-
+				if let Some(node) = self.cache.and_then(|cache| cache.get(&node_hash)) {
+					if strict {
+						L::Codec::check_layout(node.node_plan(), L::USE_EXTENSION)
+							.map_err(|e| Box::new(TrieError::DecoderError(node_hash, e)))?;
+					}
+					return Ok((node, Some(node_hash)));
+				}
+				let node_data =
+					self.fetch_node(&node_hash, partial_key, partial_key == EMPTY_PREFIX)?;
 				(Some(node_hash), node_data)
 			}
 			NodeHandle::Inline(data) => (None, data.to_vec()),
 		};
+		profile!(nodes_decoded);
 		let owned_node = OwnedNode::new::<L::Codec>(node_data)
 			.map_err(|e| Box::new(TrieError::DecoderError(node_hash.unwrap_or(parent_hash), e)))?;
-		Ok((owned_node, node_hash))
+		if strict {
+			L::Codec::check_layout(owned_node.node_plan(), L::USE_EXTENSION)
+				.map_err(|e| Box::new(TrieError::DecoderError(node_hash.unwrap_or(parent_hash), e)))?;
+		}
+		let node = SharedNode::new(owned_node);
+		if let (Some(hash), Some(cache)) = (node_hash, self.cache) {
+			cache.insert(hash, node.clone());
+		}
+		Ok((node, node_hash))
 	}
 }
 
@@ -121,6 +1200,30 @@ where
 {
 	fn root(&self) -> &TrieHash<L> { self.root }
 
+	/// What is the value of the given key in this trie? Answered by the `ValueCache` of the
+	/// trie first, if any, see `new_with_cache`.
+	fn get<'a, 'key>(
+		&'a self,
+		key: &'key [u8],
+	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> where 'a: 'key {
+		let values = match self.cache.and_then(|cache| cache.values()) {
+			Some(values) => values,
+			None => return self.get_with(key, |v: &[u8]| v.to_vec()),
+		};
+		if let Some(value) = values.get(self.root, key) {
+			return Ok(value);
+		}
+		let value = self.get_with(key, |v: &[u8]| v.to_vec())?;
+		values.insert(*self.root, key, value.clone());
+		Ok(value)
+	}
+
+	/// Does the trie contain a given key? The lookup stops at the value slot of the key: a
+	/// value stored apart, in a value node or in chunks, is not fetched.
+	fn contains(&self, key: &[u8]) -> Result<bool, TrieHash<L>, CError<L>> {
+		self.look_up_slot(key, |_, _, _| Ok(())).map(|value| value.is_some())
+	}
+
 	fn get_with<'a, 'key, Q: Query<L::Hash>>(
 		&'a self,
 		key: &'key [u8],
@@ -128,11 +1231,7 @@ where
 	) -> Result<Option<Q::Item>, TrieHash<L>, CError<L>>
 		where 'a: 'key,
 	{
-		Lookup::<L, Q> {
-			db: self.db,
-			query: query,
-			hash: self.root.clone(),
-		}.look_up(NibbleSlice::new(key))
+		self.get_with_at(key, *self.root, 0, query)
 	}
 
 	fn iter<'a>(&'a self)-> Result<
@@ -153,7 +1252,7 @@ where
 {
 	trie: &'db TrieDB<'db, L>,
 	node_key: NodeHandle<'a>,
-	partial_key: NibbleVec,
+	partial_key: NibbleVec<L::Nibble>,
 	index: Option<u8>,
 }
 
@@ -261,16 +1360,86 @@ where
 	}
 }
 
+/// Node on the path of a key looked up by `TrieDB::get_batch`.
+struct BatchStep<L: TrieLayout> {
+	node: OwnedNode<DBValue, L::Nibble>,
+	/// Number of key nibbles before the node.
+	start: usize,
+	/// Hash of the node, or of the closest node above it for inline nodes.
+	hash: TrieHash<L>,
+}
+
+/// Query recording the path of a lookup, see `TrieDB::get_with_path`.
+struct PathQuery<'a, H: Hasher, N: NibbleOps>(&'a mut Vec<(NibbleVec<N>, H::Out)>);
+
+impl<'a, H: Hasher, N: NibbleOps> Query<H> for PathQuery<'a, H, N> {
+	type Item = DBValue;
+
+	fn decode(self, value: &[u8]) -> DBValue { value.to_vec() }
+
+	fn record_at(&mut self, hash: &H::Out, _data: &[u8], _depth: u32, (key, last): Prefix) {
+		let mut prefix = NibbleVec::from(NibbleSlice::new(key));
+		if let Some(last) = last {
+			prefix.push(N::at_left(0, last));
+		}
+		self.0.push((prefix, *hash));
+	}
+
+	// value nodes are not on the path.
+	fn record_value(&mut self, _hash: &H::Out, _value: &[u8], _depth: u32, _prefix: Prefix) {}
+}
+
 /// Iterator for going through all values in the trie in pre-order traversal order.
 pub struct TrieDBIterator<'a, L: TrieLayout> {
 	inner: TrieDBNodeIterator<'a, L>,
+	/// Number of items left, from `TrieDB::count_entries`, and whether it is exact: once the
+	/// iterator is moved, it is only an upper bound.
+	remaining: Option<(usize, bool)>,
 }
 
 impl<'a, L: TrieLayout> TrieDBIterator<'a, L> {
 	/// Create a new iterator.
 	pub fn new(db: &'a TrieDB<L>) -> Result<TrieDBIterator<'a, L>, TrieHash<L>, CError<L>> {
 		let inner = TrieDBNodeIterator::new(db)?;
-		Ok(TrieDBIterator { inner })
+		Ok(TrieDBIterator { inner, remaining: db.entry_count.get().map(|count| (count, true)) })
+	}
+
+	/// Create a new iterator recording the nodes it fetches in `recorder`, see
+	/// `TrieDBNodeIterator::new_with_recorder`.
+	pub fn new_with_recorder(
+		db: &'a TrieDB<L>,
+		recorder: &'a mut Recorder<TrieHash<L>>,
+	) -> Result<TrieDBIterator<'a, L>, TrieHash<L>, CError<L>> {
+		let inner = TrieDBNodeIterator::new_with_recorder(db, recorder)?;
+		Ok(TrieDBIterator { inner, remaining: db.entry_count.get().map(|count| (count, true)) })
+	}
+
+	/// Create a new iterator going through the values in descending key order, see
+	/// `TrieDBNodeIterator::new_reversed`.
+	pub fn new_reversed(
+		db: &'a TrieDB<L>,
+	) -> Result<TrieDBIterator<'a, L>, TrieHash<L>, CError<L>> {
+		let inner = TrieDBNodeIterator::new_reversed(db)?;
+		Ok(TrieDBIterator { inner, remaining: db.entry_count.get().map(|count| (count, true)) })
+	}
+
+	/// Iterate in descending key order from the last key not greater than `key`: the first
+	/// item is the one with the largest key not greater than `key`.
+	pub fn seek_rev(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.moved();
+		self.inner.seek_rev(key)
+	}
+
+	/// Seek `key`, or strictly after it if `after` is set, and restrict the iteration to
+	/// `prefix`, `key` starting with `prefix`.
+	pub(crate) fn seek_in_prefix(
+		&mut self,
+		prefix: &[u8],
+		key: &[u8],
+		after: bool,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		self.moved();
+		self.inner.seek_in_prefix(prefix, key, after)
 	}
 
 	/// Create a new iterator, but limited to a given prefix.
@@ -280,64 +1449,192 @@ impl<'a, L: TrieLayout> TrieDBIterator<'a, L> {
 
 		Ok(TrieDBIterator {
 			inner,
+			remaining: db.entry_count.get().map(|count| (count, false)),
 		})
 	}
 
+	/// Resume the iteration saved in `state` over `db`, see `TrieDBNodeIterator::restore`.
+	pub fn restore(
+		db: &'a TrieDB<L>,
+		state: &TrieIteratorState<TrieHash<L>, L::Nibble>,
+	) -> Result<TrieDBIterator<'a, L>, TrieHash<L>, CError<L>> {
+		Ok(TrieDBIterator {
+			inner: TrieDBNodeIterator::restore(db, state)?,
+			remaining: db.entry_count.get().map(|count| (count, false)),
+		})
+	}
+
+	/// Save the position of the iterator, to resume the iteration with `restore`.
+	pub fn save(&self) -> TrieIteratorState<TrieHash<L>, L::Nibble> {
+		self.inner.save()
+	}
+
+	/// Estimate of the part of the key space already iterated, see
+	/// `TrieDBNodeIterator::progress`.
+	pub fn progress(&self) -> f64 {
+		self.inner.progress()
+	}
+
+	/// The number of items left is no longer exact once the iterator is moved.
+	fn moved(&mut self) {
+		if let Some((_, exact)) = self.remaining.as_mut() {
+			*exact = false;
+		}
+	}
 }
 
 impl<'a, L: TrieLayout> TrieIterator<L> for TrieDBIterator<'a, L> {
 	/// Position the iterator on the first element with key >= `key`
 	fn seek(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.moved();
 		TrieIterator::seek(&mut self.inner, key)
 	}
+
+	/// Position the iterator on the first element with key > `key`
+	fn seek_after(&mut self, key: &[u8]) -> Result<(), TrieHash<L>, CError<L>> {
+		self.moved();
+		TrieIterator::seek_after(&mut self.inner, key)
+	}
 }
 
 impl<'a, L: TrieLayout> Iterator for TrieDBIterator<'a, L> {
 	type Item = TrieItem<'a, TrieHash<L>, CError<L>>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		while let Some(item) = self.inner.next() {
+		let trie = self.inner.trie();
+		while let Some(item) = self.inner.next_node() {
 			match item {
-				Ok((mut prefix, _, node)) => {
-					let maybe_value = match node.node() {
-						Node::Leaf(partial, value) => {
-							prefix.append_partial(partial.right());
-							Some(value)
+				Ok((prefix, hash, node)) =>
+					if let Some(item) = value_item(trie, prefix, hash, &node) {
+						if let Some((remaining, _)) = self.remaining.as_mut() {
+							*remaining = remaining.saturating_sub(1);
 						}
-						Node::Branch(_, value) => value,
-						Node::NibbledBranch(partial, _, value) => {
-							prefix.append_partial(partial.right());
-							value
-						}
-						_ => None,
-					};
-					if let Some(value) = maybe_value {
-						let (key_slice, maybe_extra_nibble) = prefix.as_prefix();
-						let key = key_slice.to_vec();
-						if let Some(extra_nibble) = maybe_extra_nibble {
-							return Some(Err(Box::new(
-								TrieError::ValueAtIncompleteKey(key, extra_nibble)
-							)));
-						}
-						return Some(Ok((key, value.to_vec())));
-					}
+						return Some(item);
+					},
+				Err(err) => {
+					self.moved();
+					return Some(Err(err));
 				},
-				Err(err) => return Some(Err(err)),
 			}
 		}
 		None
 	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		match self.remaining {
+			Some((remaining, true)) => (remaining, Some(remaining)),
+			Some((remaining, false)) => (0, Some(remaining)),
+			None => (0, None),
+		}
+	}
+}
+
+/// The key-value pair held by `node` of `trie`, with hash `hash`, at `prefix`, if any.
+pub(crate) fn value_item<L: TrieLayout>(
+	trie: &TrieDB<L>,
+	prefix: &NibbleVec<L::Nibble>,
+	hash: Option<TrieHash<L>>,
+	node: &OwnedNode<DBValue, L::Nibble>,
+) -> Option<TrieItem<'static, TrieHash<L>, CError<L>>> {
+	value_item_with::<L, _, _>(trie, prefix, hash, node, |value| Some(value.to_vec()))
+}
+
+/// The key of the value held by `node` of `trie`, with hash `hash`, at `prefix` and the result
+/// of `map` on the value, if any.
+///
+/// `map` borrows the value from the node, or from its value node, and the key is only built
+/// when it returns `Some`.
+pub(crate) fn value_item_with<L, T, M>(
+	trie: &TrieDB<L>,
+	prefix: &NibbleVec<L::Nibble>,
+	hash: Option<TrieHash<L>>,
+	node: &OwnedNode<DBValue, L::Nibble>,
+	map: M,
+) -> Option<FilteredItem<T, L>>
+	where
+		L: TrieLayout,
+		M: FnOnce(&[u8]) -> Option<T>,
+{
+	let (maybe_partial, maybe_value) = match node.node() {
+		Node::Leaf(partial, value) => (Some(partial), Some(value)),
+		Node::Branch(_, value) => (None, value),
+		Node::NibbledBranch(partial, _, value) => (Some(partial), value),
+		_ => (None, None),
+	};
+	let value = maybe_value?;
+	let key_len = prefix.len() + maybe_partial.map_or(0, |partial| partial.len());
+	let hash = hash.unwrap_or_default();
+	let slot = match Value::from_slot::<L>(value, hash) {
+		Ok(slot) => slot,
+		Err(error) => return Some(Err(error)),
+	};
+	// Values at keys with an odd number of nibbles are errors, whatever `map` returns.
+	let value = match (key_len % L::Nibble::NIBBLE_PER_BYTE, slot) {
+		(0, Value::Inline(value)) => Some(map(value)?),
+		(0, Value::Node(_)) | (0, Value::Chunked(_)) => {
+			match trie.read_value(value, hash, prefix.as_prefix()) {
+				Ok(value) => Some(map(&value)?),
+				Err(error) => return Some(Err(error)),
+			}
+		},
+		_ => None,
+	};
+	// Only copy the prefix for the values kept.
+	let mut prefix = prefix.clone();
+	if let Some(partial) = maybe_partial {
+		prefix.append_partial(partial.right());
+	}
+	let (key_slice, maybe_extra_nibble) = prefix.as_prefix();
+	let key = key_slice.to_vec();
+	match maybe_extra_nibble {
+		Some(extra_nibble) => Some(Err(Box::new(TrieError::ValueAtIncompleteKey(key, extra_nibble)))),
+		None => value.map(|value| Ok((key, value))),
+	}
 }
 
 #[cfg(test)]
 mod tests {
-	use memory_db::{MemoryDB, PrefixedKey};
+	use memory_db::{HashKey, MemoryDB, PrefixedKey};
 	use keccak_hasher::KeccakHasher;
 	use crate::DBValue;
 	use reference_trie::{RefTrieDB, RefTrieDBMut, RefLookup, Trie, TrieMut, NibbleSlice};
 	use reference_trie::{RefTrieDBNoExt, RefTrieDBMutNoExt};
+	use crate::VALUE_CHUNK_SIZE;
 	use hex_literal::hex;
 
+	#[test]
+	fn values_are_streamed_in_chunks() {
+		let large: Vec<u8> = (0..VALUE_CHUNK_SIZE * 5 / 2).map(|i| i as u8).collect();
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMutNoExt::new(&mut memdb, &mut root);
+			t.insert(b"large", &large).unwrap();
+			t.insert(b"small", b"value").unwrap();
+		}
+		let trie = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+
+		let mut chunks = Vec::new();
+		let length = trie.get_chunked(b"large", &mut |chunk| chunks.push(chunk.to_vec())).unwrap();
+		assert_eq!(length, Some(large.len()));
+		assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), vec![
+			VALUE_CHUNK_SIZE,
+			VALUE_CHUNK_SIZE,
+			VALUE_CHUNK_SIZE / 2,
+		]);
+		assert_eq!(chunks.concat(), large);
+
+		let mut chunks = Vec::new();
+		assert_eq!(trie.get_chunked(b"small", &mut |chunk| chunks.push(chunk.to_vec())).unwrap(), Some(5));
+		assert_eq!(chunks, vec![b"value".to_vec()]);
+		let mut calls = 0;
+		assert_eq!(trie.get_chunked(b"none", &mut |_| calls += 1).unwrap(), None);
+		assert_eq!(calls, 0);
+
+		assert_eq!(trie.value_length(b"large").unwrap(), Some(large.len()));
+		assert_eq!(trie.value_length(b"none").unwrap(), None);
+	}
+
 	#[test]
 	fn iterator_works() {
 		let pairs = vec![
@@ -701,4 +1998,642 @@ mod tests {
 		let query_result = lookup.look_up(NibbleSlice::new(b"A"));
 		assert_eq!(query_result.unwrap().unwrap(), true);
 	}
+
+	#[test]
+	fn truncated_node_returns_decoder_error() {
+		use hash_db::{HashDB, EMPTY_PREFIX};
+		use reference_trie::TrieError;
+
+		// leaf node with a partial of one nibble but no data.
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let root = memdb.insert(EMPTY_PREFIX, &[0x41]);
+
+		let t = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+		match t.get(b"A") {
+			Err(e) => match *e {
+				TrieError::DecoderError(hash, _) => assert_eq!(hash, root),
+				e => panic!("unexpected error {:?}", e),
+			},
+			Ok(_) => panic!("truncated node should not decode"),
+		}
+		assert!(t.iter().is_err());
+	}
+
+	type HashMemoryDB = MemoryDB<KeccakHasher, HashKey<KeccakHasher>, DBValue>;
+
+	/// Build a trie and apply `tamper` to the leaf node of "doge", returning the database,
+	/// the root and the hash of the tampered node.
+	fn tampered_trie(
+		tamper: impl Fn(&mut DBValue),
+	) -> (HashMemoryDB, [u8; 32], [u8; 32]) {
+		use hash_db::{HashDB, EMPTY_PREFIX};
+		use reference_trie::Recorder;
+
+		let mut memdb = HashMemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			t.insert(b"do", b"verb").unwrap();
+			t.insert(b"dog", b"puppy").unwrap();
+			t.insert(b"doge", &[0xaa; 32]).unwrap();
+		}
+		let leaf = {
+			let t = RefTrieDB::new(&memdb, &root).unwrap();
+			let mut recorder = Recorder::new();
+			t.get_with(b"doge", &mut recorder).unwrap();
+			let record = recorder.drain().pop().unwrap();
+			assert!(record.depth > 0);
+			record.hash
+		};
+		// `HashKey` ignores the prefix.
+		let mut data = memdb.get(&leaf, EMPTY_PREFIX).unwrap();
+		tamper(&mut data);
+		memdb.remove(&leaf, EMPTY_PREFIX);
+		memdb.emplace(leaf, EMPTY_PREFIX, data);
+		(memdb, root, leaf)
+	}
+
+	#[test]
+	fn padded_node_is_rejected() {
+		use reference_trie::TrieError;
+
+		let (memdb, root, leaf) = tampered_trie(|data| data.extend_from_slice(&[0, 0, 0]));
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		assert_eq!(t.get(b"do").unwrap(), Some(b"verb".to_vec()));
+
+		let check = |error: TrieError<[u8; 32], _>| match error {
+			TrieError::HashMismatch { expected, .. } if cfg!(feature = "verify-node-hash") =>
+				assert_eq!(expected, leaf),
+			TrieError::DecoderError(hash, _) if !cfg!(feature = "verify-node-hash") =>
+				assert_eq!(hash, leaf),
+			e => panic!("unexpected error {:?}", e),
+		};
+		check(*t.get(b"doge").unwrap_err());
+		check(*t.iter().unwrap().find_map(|item| item.err()).unwrap());
+	}
+
+	#[cfg(feature = "verify-node-hash")]
+	#[test]
+	fn bit_flipped_node_reports_hash_mismatch() {
+		use hash_db::Hasher;
+		use reference_trie::TrieError;
+
+		let (memdb, root, leaf) = tampered_trie(|data| *data.last_mut().unwrap() ^= 1);
+		let tampered = KeccakHasher::hash(&hash_db::HashDB::get(&memdb, &leaf, hash_db::EMPTY_PREFIX).unwrap());
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+
+		let expected_error = TrieError::HashMismatch {
+			expected: leaf,
+			found: tampered,
+			prefix: (b"dog".to_vec(), Some(0x60)),
+		};
+		assert_eq!(*t.get(b"doge").unwrap_err(), expected_error);
+		assert_eq!(*t.iter().unwrap().find_map(|item| item.err()).unwrap(), expected_error);
+	}
+
+	fn layout_mismatch_entries() -> Vec<(Vec<u8>, Vec<u8>)> {
+		// Decodes without error under the codec of the other layout.
+		vec![(vec![0x22, 0, 0], vec![7; 24]), (vec![0, 0x11], vec![7; 21])]
+	}
+
+	#[test]
+	fn other_layout_is_rejected() {
+		use reference_trie::TrieError;
+
+		let check = |error: TrieError<[u8; 32], _>| match error {
+			TrieError::DecoderError(..) => {},
+			e => panic!("unexpected error {:?}", e),
+		};
+		let mut entries = layout_mismatch_entries();
+		entries.extend(vec![
+			(b"do".to_vec(), b"verb".to_vec()),
+			(b"dog".to_vec(), b"puppy".to_vec()),
+			(b"doge".to_vec(), vec![0; 32]),
+			(b"horse".to_vec(), b"stallion".to_vec()),
+		]);
+		for count in 1..=entries.len() {
+			let entries = &entries[..count];
+			let mut memdb = HashMemoryDB::default();
+			let mut root = Default::default();
+			{
+				let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+				for (key, value) in entries {
+					t.insert(key, value).unwrap();
+				}
+			}
+			let t = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+			check(*t.get(&entries[0].0).unwrap_err());
+			match t.iter() {
+				Ok(mut iter) => check(*iter.next().unwrap().unwrap_err()),
+				Err(e) => check(*e),
+			}
+
+			let mut memdb = HashMemoryDB::default();
+			let mut root = Default::default();
+			{
+				let mut t = RefTrieDBMutNoExt::new(&mut memdb, &mut root);
+				for (key, value) in entries {
+					t.insert(key, value).unwrap();
+				}
+			}
+			let t = RefTrieDB::new(&memdb, &root).unwrap();
+			check(*t.get(&entries[0].0).unwrap_err());
+			match t.iter() {
+				Ok(mut iter) => check(*iter.next().unwrap().unwrap_err()),
+				Err(e) => check(*e),
+			};
+		}
+	}
+
+	#[test]
+	fn lenient_trie_misreads_other_layout() {
+		let entries = layout_mismatch_entries();
+		let mut memdb = HashMemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for (key, value) in &entries {
+				t.insert(key, value).unwrap();
+			}
+		}
+
+		let t = RefTrieDBNoExt::new_lenient(&memdb, &root).unwrap();
+		let read: Vec<_> = t.iter().unwrap().map(|item| item.unwrap()).collect();
+		assert!(!read.is_empty());
+		assert!(read.iter().all(|pair| !entries.contains(pair)));
+	}
+
+	fn prefix_page_trie() -> (HashMemoryDB, <KeccakHasher as hash_db::Hasher>::Out) {
+		let mut memdb = HashMemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			// keys being prefixes of other keys, around the page boundaries.
+			for key in [&b"a"[..], b"p", b"pa", b"pa\x00", b"pa\x00\x00", b"pab", b"pb", b"q"].iter() {
+				t.insert(key, key).unwrap();
+			}
+			for i in 0..100u8 {
+				t.insert(&[b'p', i % 7, i], &[i; 40]).unwrap();
+			}
+		}
+		(memdb, root)
+	}
+
+	#[test]
+	fn prefix_pages_reassemble_prefix_scan() {
+		use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+		let (memdb, root) = prefix_page_trie();
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let mut rng = SmallRng::seed_from_u64(42);
+		for prefix in [&b""[..], b"p", b"pa", b"pa\x00", b"p\x03", b"z"].iter() {
+			let expected: Vec<_> = t.iter().unwrap()
+				.map(|item| item.unwrap())
+				.filter(|(key, _)| key.starts_with(prefix))
+				.collect();
+			for _ in 0..20 {
+				let mut pairs = Vec::new();
+				let mut start_after: Option<Vec<u8>> = None;
+				loop {
+					let limit = rng.gen_range(1, 12);
+					let (page, next) = t.read_prefix_page(prefix, start_after.as_deref(), limit).unwrap();
+					assert!(page.len() <= limit);
+					pairs.extend(page);
+					match next {
+						Some(key) => {
+							assert_eq!(Some(&key), pairs.last().map(|(key, _)| key));
+							start_after = Some(key);
+						},
+						None => break,
+					}
+				}
+				assert_eq!(pairs, expected);
+			}
+		}
+	}
+
+	#[test]
+	fn prefix_page_start_after_any_key() {
+		let (memdb, root) = prefix_page_trie();
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+
+		// resuming after an absent key, a key before the prefix and a key after it.
+		let (page, next) = t.read_prefix_page(b"pa", Some(b"pa\x00\x00\x00"), 10).unwrap();
+		let keys: Vec<_> = page.into_iter().map(|(key, _)| key).collect();
+		assert_eq!(keys, vec![b"pab".to_vec()]);
+		assert_eq!(next, None);
+		let (page, _) = t.read_prefix_page(b"pa", Some(b"a"), 2).unwrap();
+		assert_eq!(page[0].0, b"pa".to_vec());
+		let (page, next) = t.read_prefix_page(b"pa", Some(b"pb"), 2).unwrap();
+		assert!(page.is_empty());
+		assert_eq!(next, None);
+
+		// a full page does not return a continuation when no pair is left.
+		let (page, next) = t.read_prefix_page(b"pa", None, 4).unwrap();
+		assert_eq!(page.len(), 4);
+		assert_eq!(next, None);
+	}
+
+	#[test]
+	fn errors_compare_equal() {
+		use reference_trie::{builder::TrieBuilder, ExtensionLayout, TrieDBNodeIterator, TrieError};
+
+		let mut built = TrieBuilder::<ExtensionLayout>::new()
+			.insert([0x01], [1; 32])
+			.insert([0x02], [2; 32])
+			.build();
+		let leaf_hash = {
+			let trie = built.trie();
+			let iter = TrieDBNodeIterator::new(&trie).unwrap();
+			iter.map(|item| item.unwrap())
+				.filter_map(|(prefix, hash, _)| hash.filter(|_| prefix.len() == 2))
+				.next()
+				.unwrap()
+		};
+		built.remove_node(&leaf_hash);
+		assert_eq!(
+			built.trie().get(&[0x01]),
+			Err(Box::new(TrieError::IncompleteDatabase {
+				hash: leaf_hash,
+				prefix: (vec![0x01], None),
+			})),
+		);
+		assert_eq!(built.trie().get(&[0x02]), Ok(Some(vec![2; 32])));
+	}
+
+	#[test]
+	fn iterator_yields_branch_values() {
+		let d: Vec<&[u8]> = vec![b"A", b"AA", b"AB", b"ABC", b"B"];
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+			for x in &d {
+				t.insert(x, x).unwrap();
+			}
+		}
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let keys: Vec<_> = t.iter().unwrap().map(|x| x.unwrap().0).collect();
+		assert_eq!(keys, d);
+		let mut iter = t.iter().unwrap();
+		iter.seek(b"AB").unwrap();
+		assert_eq!(iter.map(|x| x.unwrap().0).collect::<Vec<_>>(), &d[2..]);
+
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		{
+			let mut t = RefTrieDBMutNoExt::new(&mut memdb, &mut root);
+			for x in &d {
+				t.insert(x, x).unwrap();
+			}
+		}
+		let t = RefTrieDBNoExt::new(&memdb, &root).unwrap();
+		let keys: Vec<_> = t.iter().unwrap().map(|x| x.unwrap().0).collect();
+		assert_eq!(keys, d);
+	}
+
+	#[test]
+	fn iterator_rejects_values_at_odd_keys() {
+		use hash_db::{HashDB, EMPTY_PREFIX};
+		use reference_trie::{node::{encode_node, Node}, ExtensionLayout, TrieError};
+
+		// a root leaf with a single nibble of key.
+		let leaf = encode_node::<ExtensionLayout>(
+			&Node::Leaf(NibbleSlice::new_offset(&[0x01], 1), b"value"),
+		).unwrap();
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let root = memdb.insert(EMPTY_PREFIX, &leaf);
+
+		let t = RefTrieDB::new(&memdb, &root).unwrap();
+		let mut iter = t.iter().unwrap();
+		match iter.next().map(|item| item.map_err(|e| *e)) {
+			Some(Err(TrieError::ValueAtIncompleteKey(key, _))) => assert!(key.is_empty()),
+			result => panic!("unexpected result: {:?}", result),
+		}
+		assert!(iter.next().is_none());
+
+		let mut values = t.iter_values().unwrap();
+		match values.next().map(|item| item.map_err(|e| *e)) {
+			Some(Err(TrieError::ValueAtIncompleteKey(key, _))) => assert!(key.is_empty()),
+			result => panic!("unexpected result: {:?}", result),
+		}
+		assert!(values.next().is_none());
+	}
+
+	fn check_iter_values<L: reference_trie::TrieLayout>() {
+		use reference_trie::builder::TrieBuilder;
+
+		// branch values, inline and stored leaves.
+		let pairs: Vec<_> = (0..100u8)
+			.flat_map(|i| vec![
+				(vec![i / 8], vec![i; 2]),
+				(vec![i / 8, i], vec![i; 1 + i as usize % 40]),
+			])
+			.collect();
+		let built = TrieBuilder::<L>::new().extend(pairs).build();
+		let trie = built.trie();
+		let expected: Vec<_> = trie.iter().unwrap().map(|item| item.unwrap().1).collect();
+		let values: Vec<_> = trie.iter_values().unwrap().map(|item| item.unwrap()).collect();
+		assert_eq!(values, expected);
+	}
+
+	#[test]
+	fn iter_values_follows_the_keys() {
+		check_iter_values::<reference_trie::ExtensionLayout>();
+		check_iter_values::<reference_trie::NoExtensionLayout>();
+	}
+
+	fn check_iter_leaves<L: reference_trie::TrieLayout<Hash = KeccakHasher>>() {
+		use hash_db::{HashDB, EMPTY_PREFIX};
+		use reference_trie::{TrieDB, TrieDBMut};
+
+		let mut memdb = HashMemoryDB::default();
+		let mut root = Default::default();
+		// branch values, inline and stored leaves.
+		let keys: Vec<Vec<u8>> = (0..60u8)
+			.flat_map(|i| vec![vec![i / 8], vec![i / 8, i], vec![i / 8, i, 0x10]])
+			.collect();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut memdb, &mut root);
+			for key in keys.iter() {
+				let len = match key.len() {
+					3 => [2, 40][key[1] as usize % 2],
+					_ => 1 + key[key.len() - 1] as usize % 40,
+				};
+				t.insert(key, &vec![key.len() as u8; len]).unwrap();
+			}
+		}
+		let trie = TrieDB::<L>::new(&memdb, &root).unwrap();
+		let expected: Vec<_> = trie.iter().unwrap().map(|item| item.unwrap()).collect();
+		let leaves: Vec<_> = trie.iter_leaves().unwrap().map(|item| item.unwrap()).collect();
+		assert_eq!(
+			leaves.iter().map(|leaf| (leaf.key.clone(), leaf.value.clone())).collect::<Vec<_>>(),
+			expected,
+		);
+		for leaf in leaves.iter() {
+			let has_descendants = keys.iter()
+				.any(|key| key.len() > leaf.key.len() && key.starts_with(&leaf.key));
+			assert_eq!(leaf.branch_value, has_descendants, "{:?}", leaf.key);
+			match leaf.node_hash {
+				// `HashKey` ignores the prefix.
+				Some(hash) =>
+					assert_eq!(memdb.get(&hash, EMPTY_PREFIX).unwrap().len(), leaf.encoded_len),
+				None => assert!(leaf.encoded_len < 32),
+			}
+		}
+		assert!(leaves.iter().any(|leaf| leaf.branch_value));
+		assert!(leaves.iter().any(|leaf| leaf.node_hash.is_none()));
+		assert!(leaves.iter().any(|leaf| !leaf.branch_value && leaf.node_hash.is_some()));
+	}
+
+	#[test]
+	fn iter_leaves_yields_the_nodes_of_the_values() {
+		check_iter_leaves::<reference_trie::ExtensionLayout>();
+		check_iter_leaves::<reference_trie::NoExtensionLayout>();
+	}
+
+	fn check_lookup_path<L: reference_trie::TrieLayout>(branch_path: &[usize], leaf_path: &[usize]) {
+		use reference_trie::{builder::TrieBuilder, NibbleVec, TrieDBNodeIterator};
+
+		// 0x01 ends on a branch value, 0x0123 on a leaf below it.
+		let keys: Vec<&[u8]> = vec![&[0x01], &[0x01, 0x23], &[0x02]];
+		let built = TrieBuilder::<L>::new().extend(keys.iter().map(|key| (*key, [7; 32]))).build();
+		let trie = built.trie();
+		let stored: Vec<_> = TrieDBNodeIterator::new(&trie).unwrap()
+			.filter_map(|item| {
+				let (prefix, hash, _) = item.unwrap();
+				hash.map(|hash| (prefix, hash))
+			})
+			.collect();
+
+		for (key, lengths) in [(keys[0], branch_path), (keys[1], leaf_path)] {
+			let (value, path) = trie.get_with_path(key).unwrap().unwrap();
+			assert_eq!(value, vec![7; 32]);
+			let lookup = NibbleVec::from(NibbleSlice::new(key));
+			let expected: Vec<_> = stored.iter()
+				.filter(|(prefix, _)| lookup.starts_with_vec(prefix))
+				.cloned()
+				.collect();
+			assert_eq!(path, expected, "{:?}", key);
+			assert_eq!(path.iter().map(|(prefix, _)| prefix.len()).collect::<Vec<_>>(), lengths);
+		}
+		assert!(trie.get_with_path(&[0x01, 0x24]).unwrap().is_none());
+	}
+
+	#[test]
+	fn get_with_path_lists_the_fetched_nodes() {
+		check_lookup_path::<reference_trie::ExtensionLayout>(&[0, 1, 2], &[0, 1, 2, 3]);
+		check_lookup_path::<reference_trie::NoExtensionLayout>(&[0, 2], &[0, 2, 3]);
+	}
+
+	fn check_longest_prefix<L: reference_trie::TrieLayout>() {
+		use reference_trie::builder::TrieBuilder;
+
+		// branch values at 0x01 and 0x0123, a leaf below them and a leaf at nibble 1.
+		let keys: Vec<&[u8]> = vec![&[0x01], &[0x01, 0x23], &[0x01, 0x23, 0x45], &[0x12, 0x34]];
+		let value = |key: &[u8]| [key, &[0xff]].concat().repeat(8);
+		let longest = |entries: &[&[u8]], query: &[u8]| -> Option<(Vec<u8>, DBValue)> {
+			let built = TrieBuilder::<L>::new()
+				.extend(entries.iter().map(|key| (*key, value(key))))
+				.build();
+			let trie = built.trie();
+			let found = trie.get_longest_prefix(query).unwrap();
+			let expected = entries.iter()
+				.filter(|key| query.starts_with(key))
+				.max_by_key(|key| key.len())
+				.map(|key| (key.to_vec(), value(key)));
+			assert_eq!(found, expected, "{:?}", query);
+			found
+		};
+
+		assert_eq!(longest(&keys, &[0x01, 0x23, 0x45, 0x67]).unwrap().0, vec![0x01, 0x23, 0x45]);
+		// the partial key of the leaf diverges from the query in its second nibble.
+		assert_eq!(longest(&keys, &[0x01, 0x23, 0x46]).unwrap().0, vec![0x01, 0x23]);
+		assert_eq!(longest(&keys, &[0x01, 0x24]).unwrap().0, vec![0x01]);
+		assert_eq!(longest(&keys, &[0x01]).unwrap().0, vec![0x01]);
+		// the query ends inside the partial key of a leaf.
+		assert_eq!(longest(&keys, &[0x12]), None);
+		for query in [&[][..], &[0x02], &[0x10, 0x23], &[0x12, 0x35], &[0x12, 0x34, 0x56]] {
+			longest(&keys, query);
+		}
+		// a value at the empty key is a prefix of every key.
+		let with_root: Vec<&[u8]> = keys.iter().cloned().chain(Some(&[][..])).collect();
+		assert_eq!(longest(&with_root, &[0x02]).unwrap().0, Vec::<u8>::new());
+		assert_eq!(longest(&with_root, &[0x01, 0x24]).unwrap().0, vec![0x01]);
+		assert_eq!(longest(&[], &[0x01]), None);
+	}
+
+	#[test]
+	fn longest_prefix_follows_the_query() {
+		check_longest_prefix::<reference_trie::ExtensionLayout>();
+		check_longest_prefix::<reference_trie::NoExtensionLayout>();
+	}
+
+	fn check_closest_keys<L: reference_trie::TrieLayout>() {
+		use std::collections::BTreeSet;
+		use std::ops::Bound;
+		use reference_trie::builder::TrieBuilder;
+
+		let keys: BTreeSet<Vec<u8>> = vec![
+			vec![0x01], vec![0x01, 0x23], vec![0x01, 0x23, 0x45], vec![0x01, 0x24], vec![0x12, 0x34],
+		].into_iter().collect();
+		let built = TrieBuilder::<L>::new().extend(keys.iter().map(|key| (key, [7; 32]))).build();
+		let trie = built.trie();
+		// smaller than every key, existing keys, keys between them and larger than every key.
+		let queries: Vec<&[u8]> = vec![
+			&[], &[0x00, 0xff], &[0x01], &[0x01, 0x22], &[0x01, 0x23], &[0x01, 0x23, 0x45, 0x00],
+			&[0x01, 0x24], &[0x02], &[0x12, 0x34], &[0x12, 0x34, 0x00], &[0xff],
+		];
+		for query in queries {
+			let next = keys.range::<[u8], _>((Bound::Excluded(query), Bound::Unbounded)).next();
+			let prev = keys.range::<[u8], _>((Bound::Unbounded, Bound::Excluded(query))).next_back();
+			assert_eq!(trie.next_key(query).unwrap().as_ref(), next, "{:?}", query);
+			assert_eq!(trie.prev_key(query).unwrap().as_ref(), prev, "{:?}", query);
+		}
+		assert_eq!(trie.next_key(&[0xff]).unwrap(), None);
+		assert_eq!(trie.prev_key(&[]).unwrap(), None);
+
+		let empty = TrieBuilder::<L>::new().build();
+		assert_eq!(empty.trie().next_key(&[0x01]).unwrap(), None);
+		assert_eq!(empty.trie().prev_key(&[0x01]).unwrap(), None);
+	}
+
+	#[test]
+	fn closest_keys_are_strictly_around_the_key() {
+		check_closest_keys::<reference_trie::ExtensionLayout>();
+		check_closest_keys::<reference_trie::NoExtensionLayout>();
+	}
+
+	fn check_prefixed_iterator<L: reference_trie::TrieLayout>() {
+		use reference_trie::{builder::TrieBuilder, TrieDBIterator};
+
+		let keys: Vec<&[u8]> = vec![
+			// an extension from nibble 4 to nibble 8, without extension a nibbled branch.
+			&[0x01, 0x02, 0x03, 0x04, 0xaa],
+			&[0x01, 0x02, 0x03, 0x04, 0xbb],
+			&[0x01, 0x03],
+			// a leaf from nibble 1.
+			&[0x02, 0x34, 0x56],
+		];
+		let built = TrieBuilder::<L>::new().extend(keys.iter().map(|key| (*key, [7; 32]))).build();
+		let trie = built.trie();
+		let prefixed = |prefix: &[u8]| -> Vec<Vec<u8>> {
+			TrieDBIterator::new_prefixed(&trie, prefix).unwrap().map(|item| item.unwrap().0).collect()
+		};
+
+		assert_eq!(prefixed(&[]), keys);
+		assert_eq!(prefixed(&[0x01]), &keys[..3]);
+		// the prefix ends inside the partial key of the extension or branch.
+		assert_eq!(prefixed(&[0x01, 0x02, 0x03]), &keys[..2]);
+		assert_eq!(prefixed(&[0x01, 0x02, 0x03, 0x04, 0xbb]), &keys[1..2]);
+		// the prefix ends inside the partial key of the leaf.
+		assert_eq!(prefixed(&[0x02, 0x34]), &keys[3..]);
+		// no node for the prefix: diverging partial keys, missing child or key past a leaf.
+		for prefix in [&[0x01, 0x02, 0x04][..], &[0x02, 0x35], &[0x05], &[0x01, 0x03, 0x00]] {
+			assert!(prefixed(prefix).is_empty(), "{:?}", prefix);
+		}
+	}
+
+	#[test]
+	fn prefixed_iterator_stays_in_the_subtrie() {
+		check_prefixed_iterator::<reference_trie::ExtensionLayout>();
+		check_prefixed_iterator::<reference_trie::NoExtensionLayout>();
+	}
+
+	fn check_counts<L: reference_trie::TrieLayout>(extensions: usize) {
+		use reference_trie::{builder::TrieBuilder, TrieDBIterator, TrieDBNodeIterator, TrieIterator};
+
+		// a branch without value at nibble 1 and one with value at key 0x10.
+		let keys: Vec<&[u8]> = vec![&[0x10], &[0x10, 0x20], &[0x11]];
+		let built = TrieBuilder::<L>::new().extend(keys.iter().map(|key| (*key, [7; 32]))).build();
+		let trie = built.trie();
+		assert_eq!(TrieDBIterator::new(&trie).unwrap().size_hint(), (0, None));
+
+		let nodes = trie.count_nodes().unwrap();
+		assert_eq!(
+			(nodes.leaves, nodes.branches, nodes.extensions, nodes.empty),
+			(2, 2, extensions, 0),
+		);
+		assert_eq!(
+			nodes.leaves + nodes.branches + nodes.extensions,
+			TrieDBNodeIterator::new(&trie).unwrap().count(),
+		);
+		assert_eq!(trie.count_entries().unwrap(), keys.len());
+
+		// the iterators created after the count know their length.
+		let mut iter = TrieDBIterator::new(&trie).unwrap();
+		for remaining in (0..=keys.len()).rev() {
+			assert_eq!(iter.size_hint(), (remaining, Some(remaining)));
+			assert_eq!(iter.next().is_some(), remaining > 0);
+		}
+		assert_eq!(trie.iter().unwrap().collect::<Vec<_>>().len(), keys.len());
+		let mut iter = TrieDBIterator::new(&trie).unwrap();
+		iter.seek(keys[1]).unwrap();
+		assert_eq!(iter.size_hint(), (0, Some(keys.len())));
+		assert_eq!(TrieDBIterator::new_prefixed(&trie, &[0x11]).unwrap().size_hint(), (0, Some(3)));
+
+		let empty = TrieBuilder::<L>::new().build();
+		let nodes = empty.trie().count_nodes().unwrap();
+		assert_eq!((nodes.leaves, nodes.branches, nodes.extensions, nodes.empty), (0, 0, 0, 1));
+		assert_eq!(empty.trie().count_entries().unwrap(), 0);
+	}
+
+	#[test]
+	fn counts_with_extension() {
+		check_counts::<reference_trie::ExtensionLayout>(1);
+	}
+
+	#[test]
+	fn counts_without_extension() {
+		check_counts::<reference_trie::NoExtensionLayout>(0);
+	}
+
+	#[test]
+	fn counts_fail_on_missing_node() {
+		use reference_trie::{builder::TrieBuilder, TrieDBNodeIterator, TrieError};
+
+		let mut built = TrieBuilder::<reference_trie::ExtensionLayout>::new()
+			.extend((0..20u8).map(|i| ([i], [i; 32])))
+			.build();
+		let missing = TrieDBNodeIterator::new(&built.trie()).unwrap()
+			.filter_map(|item| item.unwrap().1)
+			.last()
+			.unwrap();
+		built.remove_node(&missing);
+		let trie = built.trie();
+		match trie.count_entries().map_err(|e| *e) {
+			Err(TrieError::IncompleteDatabase { hash, .. }) => assert_eq!(hash, missing),
+			result => panic!("unexpected result {:?}", result),
+		}
+		assert!(trie.count_nodes().is_err());
+	}
+
+	fn check_seek_after<L: reference_trie::TrieLayout>() {
+		use reference_trie::{builder::TrieBuilder, TrieDBIterator, TrieIterator};
+
+		// branch values at 0x01 and 0x0123, leaves at 0x012345, 0x0124 and 0x02.
+		let keys: Vec<&[u8]> = vec![
+			&[0x01], &[0x01, 0x23], &[0x01, 0x23, 0x45], &[0x01, 0x24], &[0x02],
+		];
+		let built = TrieBuilder::<L>::new().extend(keys.iter().map(|key| (*key, [3; 32]))).build();
+		let trie = built.trie();
+
+		let absent: Vec<&[u8]> = vec![
+			&[], &[0x00], &[0x01, 0x22], &[0x01, 0x23, 0x00], &[0x01, 0xff], &[0x03],
+		];
+		for key in keys.iter().chain(absent.iter()) {
+			let mut iter = TrieDBIterator::new(&trie).unwrap();
+			iter.seek_after(key).unwrap();
+			let found: Vec<_> = iter.map(|item| item.unwrap().0).collect();
+			let expected: Vec<_> = keys.iter().filter(|k| k > &key).map(|k| k.to_vec()).collect();
+			assert_eq!(found, expected, "seek_after {:?}", key);
+		}
+	}
+
+	#[test]
+	fn iterator_seek_after() {
+		check_seek_after::<reference_trie::ExtensionLayout>();
+		check_seek_after::<reference_trie::NoExtensionLayout>();
+	}
 }
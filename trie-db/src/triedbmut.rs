@@ -15,15 +15,25 @@
 //! In-memory trie representation.
 
 use super::{DBValue, node::NodeKey};
-use super::{Result, TrieError, TrieMut, TrieLayout, TrieHash, CError};
+use super::{
+	Result, Trie, TrieError, TrieItem, TrieMut, TrieLayout, TrieHash, CError, empty_trie_root,
+};
+use super::child_trie::{child_keyspace, child_root, KeySpacedDB, KeySpacedDBMut};
+use super::iterator::TrieDBNodeIterator;
 use super::lookup::Lookup;
-use super::node::{NodeHandle as EncodedNodeHandle, Node as EncodedNode, decode_hash};
+use super::triedb::TrieDB;
+use super::pin::PruningGuard;
+use super::node::{
+	NodeHandle as EncodedNodeHandle, Node as EncodedNode, OwnedNode, Value, decode_hash,
+	value_node_hash, value_slot, chunked_slot, store_value,
+};
+use super::value_chunks::{chunk_size, read_chunked, value_nodes, ChunkIndex};
 
-use hash_db::{HashDB, Hasher, Prefix, EMPTY_PREFIX};
-use hashbrown::HashSet;
+use hash_db::{HashDB, Hasher, OwnedPrefix, Prefix, EMPTY_PREFIX};
+use hashbrown::{HashMap, HashSet};
 
 use crate::node_codec::NodeCodec;
-use crate::nibble::{NibbleVec, NibbleSlice, nibble_ops, BackingByteVec};
+use crate::nibble::{BackingByteVec, Children, NibbleOps, NibbleSlice, NibbleVec};
 use crate::rstd::{
 	boxed::Box, convert::TryFrom, hash::Hash, mem, ops::Index, result, vec::Vec, VecDeque,
 };
@@ -36,11 +46,13 @@ use crate::rstd::fmt::{self, Debug};
 
 
 // For lookups into the Node storage buffer.
-// This is deliberately non-copyable.
+// This is deliberately non-copyable, only cloned along with the whole storage.
+#[derive(Clone)]
 #[cfg_attr(feature = "std", derive(Debug))]
 struct StorageHandle(usize);
 
 // Handles to nodes in the trie.
+#[derive(Clone)]
 #[cfg_attr(feature = "std", derive(Debug))]
 enum NodeHandle<H> {
 	/// Loaded into memory.
@@ -55,34 +67,116 @@ impl<H> From<StorageHandle> for NodeHandle<H> {
 	}
 }
 
-fn empty_children<H>() -> Box<[Option<NodeHandle<H>>; 16]> {
-	Box::new([
-		None, None, None, None, None, None, None, None,
-		None, None, None, None, None, None, None, None,
-	])
+fn empty_children<H, N: NibbleOps>() -> Box<Children<NodeHandle<H>, N>> {
+	Box::new(Children::new())
 }
 
 /// Type alias to indicate the nible covers a full key,
 /// therefore its left side is a full prefix.
-type NibbleFullKey<'key> = NibbleSlice<'key>;
+type NibbleFullKey<'key, N> = NibbleSlice<'key, N>;
+
+/// Nodes to remove from the database on commit, with their owned prefix.
+type DeathRow<H> = HashSet<(H, (BackingByteVec, Option<u8>))>;
+
+/// Nodes to write to the database at the end of a commit.
+type PendingWrites<H> = Vec<(H, OwnedPrefix, DBValue)>;
+
+/// Estimate of the nodes changed per changed key, for `TrieDBMut::reserve`: the leaf of the key,
+/// and at most one branch, the branches above being shared with other changed keys.
+const NODES_PER_CHANGE: usize = 2;
+
+/// Most nodes `TrieDBMut::reserve` reserves the room of.
+const MAX_RESERVED_NODES: usize = 1 << 24;
+
+/// Value of a node in memory.
+#[derive(Clone, PartialEq, Eq)]
+enum NodeValue<H> {
+	/// The value, stored by the commit as `TrieLayout::MAX_INLINE_VALUE` says.
+	Value(DBValue),
+	/// A value written in chunks by `TrieDBMut::insert_chunked`: the index of the chunks and the
+	/// prefix they are written at. The commit writes the index, and copies the chunks to the
+	/// prefix of the node holding the value if it is another one.
+	Chunked(ChunkIndex<H>, OwnedPrefix),
+}
+
+impl<H> NodeValue<H> {
+	/// Length of the value.
+	fn len(&self) -> usize {
+		match self {
+			NodeValue::Value(value) => value.len(),
+			NodeValue::Chunked(index, _) => index.len,
+		}
+	}
+
+	/// The bytes of a value held in memory, none for a chunked value.
+	fn as_slice(&self) -> &[u8] {
+		match self {
+			NodeValue::Value(value) => value,
+			NodeValue::Chunked(..) => &[],
+		}
+	}
+
+	/// The value slot of a committed value, see `TrieDBMut::commit_value`.
+	fn into_slot(self) -> DBValue {
+		match self {
+			NodeValue::Value(slot) => slot,
+			NodeValue::Chunked(..) => unreachable!("the commit stores the chunked values; qed"),
+		}
+	}
+}
+
+impl<H> From<DBValue> for NodeValue<H> {
+	fn from(value: DBValue) -> Self {
+		NodeValue::Value(value)
+	}
+}
+
+#[cfg(feature = "std")]
+impl<H: Debug> Debug for NodeValue<H> {
+	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			NodeValue::Value(value) => write!(fmt, "{:?}", ToHex(value)),
+			NodeValue::Chunked(index, prefix) => write!(fmt, "Chunked({:?}, {:?})", index, prefix),
+		}
+	}
+}
+
+/// The value `value` of a node in memory, `db` holding the chunks of a chunked value.
+fn read_node_value<H: Hasher, E>(
+	value: &NodeValue<H::Out>,
+	db: &dyn HashDB<H, DBValue>,
+) -> Result<DBValue, H::Out, E> {
+	let (index, prefix) = match value {
+		NodeValue::Value(value) => return Ok(value.clone()),
+		NodeValue::Chunked(index, prefix) => (index, (&prefix.0[..], prefix.1)),
+	};
+	let mut value = Vec::with_capacity(index.len);
+	for chunk_hash in index.chunks.iter() {
+		let chunk = db.get(chunk_hash, prefix)
+			.ok_or_else(|| Box::new(TrieError::incomplete_database(*chunk_hash, prefix)))?;
+		value.extend_from_slice(&chunk);
+	}
+	Ok(value)
+}
 
 /// Node types in the Trie.
-enum Node<H> {
+#[derive(Clone)]
+enum Node<H, N: NibbleOps> {
 	/// Empty node.
 	Empty,
 	/// A leaf node contains the end of a key and a value.
 	/// This key is encoded from a `NibbleSlice`, meaning it contains
 	/// a flag indicating it is a leaf.
-	Leaf(NodeKey, DBValue),
+	Leaf(NodeKey, NodeValue<H>),
 	/// An extension contains a shared portion of a key and a child node.
 	/// The shared portion is encoded from a `NibbleSlice` meaning it contains
 	/// a flag indicating it is an extension.
 	/// The child node is always a branch.
 	Extension(NodeKey, NodeHandle<H>),
-	/// A branch has up to 16 children and an optional value.
-	Branch(Box<[Option<NodeHandle<H>>; 16]>, Option<DBValue>),
+	/// A branch has up to `N::NIBBLE_LENGTH` children and an optional value.
+	Branch(Box<Children<NodeHandle<H>, N>>, Option<NodeValue<H>>),
 	/// Branch node with support for a nibble (to avoid extension node).
-	NibbledBranch(NodeKey, Box<[Option<NodeHandle<H>>; 16]>, Option<DBValue>),
+	NibbledBranch(NodeKey, Box<Children<NodeHandle<H>, N>>, Option<NodeValue<H>>),
 }
 
 #[cfg(feature = "std")]
@@ -99,23 +193,54 @@ impl<'a> Debug for ToHex<'a> {
 }
 
 #[cfg(feature = "std")]
-impl<H: Debug> Debug for Node<H> {
+impl<H: Debug, N: NibbleOps> Debug for Node<H, N> {
 	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
 		match *self {
 			Self::Empty => write!(fmt, "Empty"),
 			Self::Leaf((ref a, ref b), ref c) =>
-				write!(fmt, "Leaf({:?}, {:?})", (a, ToHex(&*b)), ToHex(&*c)),
+				write!(fmt, "Leaf({:?}, {:?})", (a, ToHex(&*b)), c),
 			Self::Extension((ref a, ref b), ref c) =>
 				write!(fmt, "Extension({:?}, {:?})", (a, ToHex(&*b)), c),
 			Self::Branch(ref a, ref b) =>
-				write!(fmt, "Branch({:?}, {:?}", a, b.as_ref().map(Vec::as_slice).map(ToHex)),
+				write!(fmt, "Branch({:?}, {:?}", a, b),
 			Self::NibbledBranch((ref a, ref b), ref c, ref d) =>
-				write!(fmt, "NibbledBranch({:?}, {:?}, {:?})", (a, ToHex(&*b)), c, d.as_ref().map(Vec::as_slice).map(ToHex)),
+				write!(fmt, "NibbledBranch({:?}, {:?}, {:?})", (a, ToHex(&*b)), c, d),
 		}
 	}
 }
 
-impl<O> Node<O>
+/// The value of the value slot `slot` of the node with hash `node_hash`, read from `db` if it
+/// is stored apart. `values` tells whether the value slots are tagged, see
+/// `TrieLayout::MAX_INLINE_VALUE`, the size of the chunks of the values stored in chunks, see
+/// `TrieLayout::MAX_VALUE_CHUNK`, and the prefix of the value nodes.
+fn read_value<H: Hasher, E>(
+	slot: &[u8],
+	(tagged, chunk_size, prefix): ValueSlots,
+	node_hash: H::Out,
+	db: &dyn HashDB<H, DBValue>,
+) -> Result<DBValue, H::Out, E> {
+	let fetch = |hash: &H::Out| db.get(hash, prefix)
+		.ok_or_else(|| Box::new(TrieError::incomplete_database(*hash, prefix)));
+	match Value::from_tagged_slot(slot, tagged, node_hash)? {
+		Value::Inline(value) => Ok(value.to_vec()),
+		Value::Node(value_hash) => {
+			let value_hash = decode_hash::<H>(value_hash)
+				.ok_or_else(|| Box::new(TrieError::InvalidHash(node_hash, value_hash.to_vec())))?;
+			fetch(&value_hash)
+		},
+		Value::Chunked(index_hash) => {
+			let index_hash = decode_hash::<H>(index_hash)
+				.ok_or_else(|| Box::new(TrieError::InvalidHash(node_hash, index_hash.to_vec())))?;
+			read_chunked::<H, E>(&index_hash, chunk_size, fetch, |_, _| ())
+		},
+	}
+}
+
+/// Whether the value slots are tagged, the size of the chunks of the values and the prefix of
+/// the value nodes, see `read_value`.
+type ValueSlots<'a> = (bool, Option<usize>, Prefix<'a>);
+
+impl<O, N: NibbleOps> Node<O, N>
 where
 	O: AsRef<[u8]> + AsMut<[u8]> + Default + crate::MaybeDebug
 		+ PartialEq + Eq + Hash + Send + Sync + Clone + Copy
@@ -124,12 +249,13 @@ where
 	fn inline_or_hash<C, H>(
 		parent_hash: H::Out,
 		child: EncodedNodeHandle,
+		values: ValueSlots,
 		db: &dyn HashDB<H, DBValue>,
-		storage: &mut NodeStorage<H::Out>
+		storage: &mut NodeStorage<H::Out, N>,
 	) -> Result<NodeHandle<H::Out>, H::Out, C::Error>
 	where
-		C: NodeCodec<HashOut=O>,
-		H: Hasher<Out=O>,
+		C: NodeCodec<HashOut = O, Nibble = N>,
+		H: Hasher<Out = O>,
 	{
 		let handle = match child {
 			EncodedNodeHandle::Hash(data) => {
@@ -138,123 +264,160 @@ where
 				NodeHandle::Hash(hash)
 			},
 			EncodedNodeHandle::Inline(data) => {
-				let child = Node::from_encoded::<C, H>(parent_hash, data, db, storage)?;
+				let child = Node::from_encoded::<C, H>(parent_hash, data, values, db, storage)?;
 				NodeHandle::InMemory(storage.alloc(Stored::New(child)))
 			},
 		};
 		Ok(handle)
 	}
 
-	// Decode a node from encoded bytes.
+	// Decode a node from encoded bytes. `values` tells how to read the values stored apart, see
+	// `read_value`.
 	fn from_encoded<'a, 'b, C, H>(
 		node_hash: H::Out,
 		data: &'a[u8],
+		values: ValueSlots,
 		db: &dyn HashDB<H, DBValue>,
-		storage: &'b mut NodeStorage<H::Out>,
+		storage: &'b mut NodeStorage<H::Out, N>,
 	) -> Result<Self, H::Out, C::Error>
 		where
-			C: NodeCodec<HashOut = O>, H: Hasher<Out = O>,
+			C: NodeCodec<HashOut = O, Nibble = N>, H: Hasher<Out = O>,
 	{
+		profile!(nodes_decoded);
 		let encoded_node = C::decode(data)
 			.map_err(|e| Box::new(TrieError::DecoderError(node_hash, e)))?;
 		let node = match encoded_node {
 			EncodedNode::Empty => Node::Empty,
-			EncodedNode::Leaf(k, v) => Node::Leaf(k.into(), v.to_vec()),
+			EncodedNode::Leaf(k, v) =>
+				Node::Leaf(k.into(), read_value(v, values, node_hash, db)?.into()),
 			EncodedNode::Extension(key, cb) => {
 				Node::Extension(
 					key.into(),
-					Self::inline_or_hash::<C, H>(node_hash, cb, db, storage)?
+					Self::inline_or_hash::<C, H>(node_hash, cb, values, db, storage)?
 				)
 			},
 			EncodedNode::Branch(encoded_children, val) => {
-				let mut child = |i:usize| match encoded_children[i] {
-					Some(child) => Self::inline_or_hash::<C, H>(node_hash, child, db, storage)
-						.map(Some),
-					None => Ok(None),
-				};
-
-				let children = Box::new([
-					child(0)?, child(1)?, child(2)?, child(3)?,
-					child(4)?, child(5)?, child(6)?, child(7)?,
-					child(8)?, child(9)?, child(10)?, child(11)?,
-					child(12)?, child(13)?, child(14)?, child(15)?,
-				]);
+				let mut children = empty_children();
+				for (child, encoded) in children.iter_mut().zip(encoded_children.iter()) {
+					if let Some(encoded) = *encoded {
+						*child = Some(Self::inline_or_hash::<C, H>(
+							node_hash,
+							encoded,
+							values,
+							db,
+							storage,
+						)?);
+					}
+				}
 
-				Node::Branch(children, val.map(|v| v.to_vec()))
+				let val = val.map(|v| read_value(v, values, node_hash, db).map(NodeValue::from))
+					.transpose()?;
+				Node::Branch(children, val)
 			},
 			EncodedNode::NibbledBranch(k, encoded_children, val) => {
-				let mut child = |i:usize| match encoded_children[i] {
-					Some(child) => Self::inline_or_hash::<C, H>(node_hash, child, db, storage)
-						.map(Some),
-					None => Ok(None),
-				};
-
-				let children = Box::new([
-					child(0)?, child(1)?, child(2)?, child(3)?,
-					child(4)?, child(5)?, child(6)?, child(7)?,
-					child(8)?, child(9)?, child(10)?, child(11)?,
-					child(12)?, child(13)?, child(14)?, child(15)?,
-				]);
+				let mut children = empty_children();
+				for (child, encoded) in children.iter_mut().zip(encoded_children.iter()) {
+					if let Some(encoded) = *encoded {
+						*child = Some(Self::inline_or_hash::<C, H>(
+							node_hash,
+							encoded,
+							values,
+							db,
+							storage,
+						)?);
+					}
+				}
 
-				Node::NibbledBranch(k.into(), children, val.map(|v| v.to_vec()))
+				let val = val.map(|v| read_value(v, values, node_hash, db).map(NodeValue::from))
+					.transpose()?;
+				Node::NibbledBranch(k.into(), children, val)
 			},
 		};
 		Ok(node)
 	}
 
+	/// The value of the node, if any.
+	fn value_mut(&mut self) -> Option<&mut NodeValue<O>> {
+		match self {
+			Node::Leaf(_, value) => Some(value),
+			Node::Branch(_, value) | Node::NibbledBranch(_, _, value) => value.as_mut(),
+			Node::Empty | Node::Extension(..) => None,
+		}
+	}
+
 	// TODO: parallelize
-	fn into_encoded<F, C, H>(self, mut child_cb: F) -> Vec<u8>
+	/// Commit the children of the node with `child_cb`, in order, before the node is encoded.
+	/// The value of the node is committed already, see `TrieDBMut::commit_value`.
+	fn commit_children<F>(self, mut child_cb: F) -> CommittedNode<O, N>
 	where
-		C: NodeCodec<HashOut=O>,
-		F: FnMut(NodeHandle<H::Out>, Option<&NibbleSlice>, Option<u8>) -> ChildReference<H::Out>,
-		H: Hasher<Out = O>,
+		F: FnMut(NodeHandle<O>, Option<&NibbleSlice<N>>, Option<u8>) -> ChildReference<O>,
 	{
 		match self {
-			Node::Empty => C::empty_node().to_vec(),
-			Node::Leaf(partial, value) => {
-				let pr = NibbleSlice::new_offset(&partial.1[..], partial.0);
-				C::leaf_node(pr.right(), &value)
-			},
+			Node::Empty => CommittedNode::Empty,
+			Node::Leaf(partial, value) => CommittedNode::Leaf(partial, value.into_slot()),
 			Node::Extension(partial, child) => {
 				let pr = NibbleSlice::new_offset(&partial.1[..], partial.0);
-				let it = pr.right_iter();
 				let c = child_cb(child, Some(&pr), None);
-				C::extension_node(
-					it,
-					pr.len(),
-					c,
-				)
+				CommittedNode::Extension(partial, c)
 			},
 			Node::Branch(mut children, value) => {
-				C::branch_node(
-					// map the `NodeHandle`s from the Branch to `ChildReferences`
-					children.iter_mut()
-						.map(Option::take)
-						.enumerate()
-						.map(|(i, maybe_child)| {
-							maybe_child.map(|child| child_cb(child, None, Some(i as u8)))
-						}),
-					value.as_ref().map(|v| &v[..])
-				)
+				let mut references = Children::new();
+				for (i, (reference, child)) in references.iter_mut()
+					.zip(children.iter_mut())
+					.enumerate()
+				{
+					*reference = child.take().map(|child| child_cb(child, None, Some(i as u8)));
+				}
+				CommittedNode::Branch(references, value.map(NodeValue::into_slot))
 			},
 			Node::NibbledBranch(partial, mut children, value) => {
 				let pr = NibbleSlice::new_offset(&partial.1[..], partial.0);
-				let it = pr.right_iter();
-				C::branch_node_nibbled(
-					it,
+				let mut references = Children::new();
+				for (i, (reference, child)) in references.iter_mut()
+					.zip(children.iter_mut())
+					.enumerate()
+				{
+					*reference = child.take()
+						.map(|child| child_cb(child, Some(&pr), Some(i as u8)));
+				}
+				CommittedNode::NibbledBranch(partial, references, value.map(NodeValue::into_slot))
+			},
+		}
+	}
+}
+
+/// A node whose children are committed, ready to be encoded.
+enum CommittedNode<HO, N: NibbleOps> {
+	Empty,
+	Leaf(NodeKey, DBValue),
+	Extension(NodeKey, ChildReference<HO>),
+	Branch(Children<ChildReference<HO>, N>, Option<DBValue>),
+	NibbledBranch(NodeKey, Children<ChildReference<HO>, N>, Option<DBValue>),
+}
+
+impl<HO: Copy, N: NibbleOps> CommittedNode<HO, N> {
+	/// Append the encoding of the node to `output`.
+	fn encode_into<C: NodeCodec<HashOut = HO, Nibble = N>>(&self, output: &mut Vec<u8>) {
+		match self {
+			CommittedNode::Empty => output.extend_from_slice(C::empty_node()),
+			CommittedNode::Leaf(partial, value) => {
+				let pr = NibbleSlice::<N>::new_offset(&partial.1[..], partial.0);
+				C::leaf_node_into(pr.right(), value, output)
+			},
+			CommittedNode::Extension(partial, child) => {
+				let pr = NibbleSlice::<N>::new_offset(&partial.1[..], partial.0);
+				C::extension_node_into(pr.right_iter(), pr.len(), *child, output)
+			},
+			CommittedNode::Branch(children, value) =>
+				C::branch_node_into(children.iter(), value.as_deref(), output),
+			CommittedNode::NibbledBranch(partial, children, value) => {
+				let pr = NibbleSlice::<N>::new_offset(&partial.1[..], partial.0);
+				C::branch_node_nibbled_into(
+					pr.right_iter(),
 					pr.len(),
-					// map the `NodeHandle`s from the Branch to `ChildReferences`
-					children.iter_mut()
-						.map(Option::take)
-						.enumerate()
-						.map(|(i, maybe_child)| {
-							//let branch_index = [i as u8];
-							maybe_child.map(|child| {
-								let pr = NibbleSlice::new_offset(&partial.1[..], partial.0);
-								child_cb(child, Some(&pr), Some(i as u8))
-							})
-						}),
-					value.as_ref().map(|v| &v[..])
+					children.iter(),
+					value.as_deref(),
+					output,
 				)
 			},
 		}
@@ -262,25 +425,25 @@ where
 }
 
 // post-inspect action.
-enum Action<H> {
+enum Action<H, N: NibbleOps> {
 	// Replace a node with a new one.
-	Replace(Node<H>),
+	Replace(Node<H, N>),
 	// Restore the original node. This trusts that the node is actually the original.
-	Restore(Node<H>),
+	Restore(Node<H, N>),
 	// if it is a new node, just clears the storage.
 	Delete,
 }
 
 // post-insert action. Same as action without delete
-enum InsertAction<H> {
+enum InsertAction<H, N: NibbleOps> {
 	// Replace a node with a new one.
-	Replace(Node<H>),
+	Replace(Node<H, N>),
 	// Restore the original node.
-	Restore(Node<H>),
+	Restore(Node<H, N>),
 }
 
-impl<H> InsertAction<H> {
-	fn into_action(self) -> Action<H> {
+impl<H, N: NibbleOps> InsertAction<H, N> {
+	fn into_action(self) -> Action<H, N> {
 		match self {
 			InsertAction::Replace(n) => Action::Replace(n),
 			InsertAction::Restore(n) => Action::Restore(n),
@@ -288,7 +451,7 @@ impl<H> InsertAction<H> {
 	}
 
 	// unwrap the node, disregarding replace or restore state.
-	fn unwrap_node(self) -> Node<H> {
+	fn unwrap_node(self) -> Node<H, N> {
 		match self {
 			InsertAction::Replace(n) | InsertAction::Restore(n) => n,
 		}
@@ -296,11 +459,12 @@ impl<H> InsertAction<H> {
 }
 
 // What kind of node is stored here.
-enum Stored<H> {
+#[derive(Clone)]
+enum Stored<H, N: NibbleOps> {
 	// A new node.
-	New(Node<H>),
+	New(Node<H, N>),
 	// A cached node, loaded from the DB.
-	Cached(Node<H>, H),
+	Cached(Node<H, N>, H),
 }
 
 /// Used to build a collection of child nodes from a collection of `NodeHandle`s
@@ -339,12 +503,13 @@ impl<'a, HO> TryFrom<EncodedNodeHandle<'a>> for ChildReference<HO>
 }
 
 /// Compact and cache-friendly storage for Trie nodes.
-struct NodeStorage<H> {
-	nodes: Vec<Stored<H>>,
+#[derive(Clone)]
+struct NodeStorage<H, N: NibbleOps> {
+	nodes: Vec<Stored<H, N>>,
 	free_indices: VecDeque<usize>,
 }
 
-impl<H> NodeStorage<H> {
+impl<H, N: NibbleOps> NodeStorage<H, N> {
 	/// Create a new storage.
 	fn empty() -> Self {
 		NodeStorage {
@@ -354,7 +519,7 @@ impl<H> NodeStorage<H> {
 	}
 
 	/// Allocate a new node in the storage.
-	fn alloc(&mut self, stored: Stored<H>) -> StorageHandle {
+	fn alloc(&mut self, stored: Stored<H, N>) -> StorageHandle {
 		if let Some(idx) = self.free_indices.pop_front() {
 			self.nodes[idx] = stored;
 			StorageHandle(idx)
@@ -364,8 +529,23 @@ impl<H> NodeStorage<H> {
 		}
 	}
 
+	/// Reserve the room of `additional` more nodes.
+	fn reserve(&mut self, additional: usize) {
+		self.nodes.reserve(additional.saturating_sub(self.free_indices.len()));
+	}
+
+	/// Number of nodes stored without growing the storage.
+	fn capacity(&self) -> usize {
+		self.nodes.capacity()
+	}
+
+	/// Number of nodes stored.
+	fn len(&self) -> usize {
+		self.nodes.len() - self.free_indices.len()
+	}
+
 	/// Remove a node from the storage, consuming the handle and returning the node.
-	fn destroy(&mut self, handle: StorageHandle) -> Stored<H> {
+	fn destroy(&mut self, handle: StorageHandle) -> Stored<H, N> {
 		let idx = handle.0;
 
 		self.free_indices.push_back(idx);
@@ -373,10 +553,10 @@ impl<H> NodeStorage<H> {
 	}
 }
 
-impl<'a, H> Index<&'a StorageHandle> for NodeStorage<H> {
-	type Output = Node<H>;
+impl<'a, H, N: NibbleOps> Index<&'a StorageHandle> for NodeStorage<H, N> {
+	type Output = Node<H, N>;
 
-	fn index(&self, handle: &'a StorageHandle) -> &Node<H> {
+	fn index(&self, handle: &'a StorageHandle) -> &Node<H, N> {
 		match self.nodes[handle.0] {
 			Stored::New(ref node) => node,
 			Stored::Cached(ref node, _) => node,
@@ -384,6 +564,149 @@ impl<'a, H> Index<&'a StorageHandle> for NodeStorage<H> {
 	}
 }
 
+/// Decision of a commit filter for a node about to be written to the database.
+/// See `TrieDBMut::set_skip_existing`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum WriteDecision {
+	/// Write the node.
+	WriteAnyway,
+	/// The node is already present in the store, do not touch the database.
+	SkipWrite,
+	/// The node is already present in the store, but its reference count must still be
+	/// incremented. The node is passed to `HashDB::emplace` under its hash, reference
+	/// counted backends are expected to only increment the count of the existing entry.
+	IncrementOnly,
+}
+
+/// Filter on the nodes written by `TrieDBMut::commit`.
+pub type SkipExisting<'a, L> = Box<dyn Fn(&TrieHash<L>, Prefix) -> WriteDecision + 'a>;
+
+/// Statistics about the nodes written by `TrieDBMut::commit`.
+#[derive(Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct CommitStats {
+	/// Number of nodes written to the database.
+	pub written: usize,
+	/// Number of nodes reported as already present by the commit filter.
+	pub already_present: usize,
+}
+
+/// What was freed by a removal from a `TrieDBMut`.
+#[derive(Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct RemovalInfo {
+	/// Number of removed keys.
+	pub keys: u32,
+	/// Total size of the removed values.
+	pub value_bytes: u64,
+	/// Number of nodes the trie shrunk by.
+	pub nodes_removed: u32,
+}
+
+impl RemovalInfo {
+	fn add_value(&mut self, len: usize) {
+		self.keys += 1;
+		self.value_bytes += len as u64;
+	}
+
+	fn accumulate(&mut self, other: &RemovalInfo) {
+		self.keys += other.keys;
+		self.value_bytes += other.value_bytes;
+		self.nodes_removed += other.nodes_removed;
+	}
+}
+
+/// Identifier of a checkpoint of the uncommitted changes of a `TrieDBMut`, see
+/// `TrieDBMut::checkpoint`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct CheckpointId(u64);
+
+/// Error of a rollback to, or release of, a checkpoint.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum CheckpointError {
+	/// The trie was committed since the checkpoint was taken.
+	Committed,
+	/// The checkpoint, or one taken before it, was already rolled back to or discarded.
+	Released,
+}
+
+/// Error of `TrieDBMut::insert_chunked`.
+#[cfg(feature = "std")]
+pub enum InsertChunkedError<L: TrieLayout> {
+	/// The value could not be read.
+	Read(std::io::Error),
+	/// The trie could not be changed.
+	Trie(Box<TrieError<TrieHash<L>, CError<L>>>),
+}
+
+#[cfg(feature = "std")]
+impl<L: TrieLayout> Debug for InsertChunkedError<L> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			InsertChunkedError::Read(error) => f.debug_tuple("Read").field(error).finish(),
+			InsertChunkedError::Trie(error) => f.debug_tuple("Trie").field(error).finish(),
+		}
+	}
+}
+
+/// The uncommitted state of a `TrieDBMut` at a checkpoint.
+struct Checkpoint<H, N: NibbleOps> {
+	id: u64,
+	storage: NodeStorage<H, N>,
+	root_handle: NodeHandle<H>,
+	death_row: DeathRow<H>,
+	/// Number of nodes copied to the database by `merge_from` at the checkpoint.
+	grafted: usize,
+	/// Number of chunks written to the database by `insert_chunked` at the checkpoint.
+	chunks: usize,
+}
+
+/// What was done by `TrieDBMut::canonicalize`.
+#[derive(Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct CanonicalizeStats {
+	/// Number of visited nodes.
+	pub visited: u64,
+	/// Number of nodes rewritten or removed to bring the trie to its canonical form.
+	pub rewritten: u64,
+	/// Whether the node budget ran out before every node was visited.
+	pub budget_exhausted: bool,
+}
+
+/// A node canonicalized by `TrieDBMut::canonicalize`, with whether it changed, or `None` if
+/// it was removed.
+type Canonicalized<H> = Option<(NodeHandle<H>, bool)>;
+
+/// The children of a node visited by `TrieDBMut::canonicalize`.
+#[derive(Clone, Copy)]
+enum Descend<'a, N: NibbleOps> {
+	/// No child is under the prefix.
+	None,
+	/// Every child is under the prefix.
+	All,
+	/// Only the child at the first nibble of the rest of the prefix, given with that rest.
+	Towards(NibbleSlice<'a, N>),
+}
+
+impl<'a, N: NibbleOps> Descend<'a, N> {
+	/// The children to visit under a node with `partial`, with the rest of the prefix
+	/// `prefix` at the node, if any.
+	fn new(prefix: Option<NibbleSlice<'a, N>>, partial: &NibbleSlice<N>) -> Self {
+		match prefix {
+			None => Descend::All,
+			Some(prefix) if partial.starts_with(&prefix) => Descend::All,
+			Some(prefix) if prefix.starts_with(partial) => match prefix.mid(partial.len()) {
+				rest if rest.is_empty() => Descend::All,
+				rest => Descend::Towards(rest),
+			},
+			Some(_) => Descend::None,
+		}
+	}
+}
+
 /// A `Trie` implementation using a generic `HashDB` backing database.
 ///
 /// Use it as a `TrieMut` trait object. You can use `db()` to get the backing database object.
@@ -415,14 +738,40 @@ pub struct TrieDBMut<'a, L>
 where
 	L: TrieLayout,
 {
-	storage: NodeStorage<TrieHash<L>>,
+	storage: NodeStorage<TrieHash<L>, L::Nibble>,
 	db: &'a mut dyn HashDB<L::Hash, DBValue>,
 	root: &'a mut TrieHash<L>,
 	root_handle: NodeHandle<TrieHash<L>>,
-	death_row: HashSet<(TrieHash<L>, (BackingByteVec, Option<u8>))>,
+	death_row: DeathRow<TrieHash<L>>,
 	/// The number of hash operations this trie has performed.
 	/// Note that none are performed until changes are committed.
 	hash_count: usize,
+	/// Optional filter consulted before writing each node on commit.
+	skip_existing: Option<SkipExisting<'a, L>>,
+	commit_stats: CommitStats,
+	/// Optional guard consulted before removing nodes on commit.
+	pruning_guard: Option<&'a dyn PruningGuard<TrieHash<L>>>,
+	/// Committed removals refused by the pruning guard.
+	deferred_removals: DeathRow<TrieHash<L>>,
+	/// Nodes removed by the pending removal, reported in `RemovalInfo`.
+	removed_nodes: u32,
+	/// Nodes encoded by the ongoing commit, written in a single batch once it is done.
+	pending_writes: PendingWrites<TrieHash<L>>,
+	/// Buffer of the encoding of the committed nodes, reused from one node to the next.
+	encode_buffer: Vec<u8>,
+	/// Checkpoints of the uncommitted changes, the latest last.
+	checkpoints: Vec<Checkpoint<TrieHash<L>, L::Nibble>>,
+	/// Identifier of the next checkpoint.
+	next_checkpoint: u64,
+	/// Identifier of the first checkpoint taken since the last commit.
+	first_uncommitted_checkpoint: u64,
+	/// Nodes copied to the database by `merge_from` since the last commit.
+	grafted: Vec<(TrieHash<L>, OwnedPrefix)>,
+	/// Chunks written to the database by `insert_chunked` since the last commit.
+	chunks: Vec<(TrieHash<L>, OwnedPrefix)>,
+	/// Chunks written since the last commit that no value of the ongoing commit kept yet, with
+	/// the number of times they were written.
+	unclaimed_chunks: HashMap<(TrieHash<L>, OwnedPrefix), usize>,
 }
 
 impl<'a, L> TrieDBMut<'a, L>
@@ -441,16 +790,31 @@ where
 			root_handle,
 			death_row: HashSet::new(),
 			hash_count: 0,
+			skip_existing: None,
+			commit_stats: CommitStats::default(),
+			pruning_guard: None,
+			deferred_removals: HashSet::new(),
+			removed_nodes: 0,
+			pending_writes: Vec::new(),
+			encode_buffer: Vec::new(),
+			checkpoints: Vec::new(),
+			next_checkpoint: 0,
+			first_uncommitted_checkpoint: 0,
+			grafted: Vec::new(),
+			chunks: Vec::new(),
+			unclaimed_chunks: HashMap::new(),
 		}
 	}
 
 	/// Create a new trie with the backing database `db` and `root.
-	/// Returns an error if `root` does not exist.
+	/// Returns an error if `root` does not exist, or is the default value of the hash while not
+	/// the empty trie root: such a root is likely uninitialized, see `new_or_existing`.
 	pub fn from_existing(
 		db: &'a mut dyn HashDB<L::Hash, DBValue>,
 		root: &'a mut TrieHash<L>,
 	) -> Result<Self, TrieHash<L>, CError<L>> {
-		if !db.contains(root, EMPTY_PREFIX) {
+		let uninitialized = *root == TrieHash::<L>::default() && *root != empty_trie_root::<L>();
+		if uninitialized || !db.contains(root, EMPTY_PREFIX) {
 			return Err(Box::new(TrieError::InvalidStateRoot(*root)));
 		}
 
@@ -462,8 +826,36 @@ where
 			root_handle,
 			death_row: HashSet::new(),
 			hash_count: 0,
+			skip_existing: None,
+			commit_stats: CommitStats::default(),
+			pruning_guard: None,
+			deferred_removals: HashSet::new(),
+			removed_nodes: 0,
+			pending_writes: Vec::new(),
+			encode_buffer: Vec::new(),
+			checkpoints: Vec::new(),
+			next_checkpoint: 0,
+			first_uncommitted_checkpoint: 0,
+			grafted: Vec::new(),
+			chunks: Vec::new(),
+			unclaimed_chunks: HashMap::new(),
 		})
 	}
+
+	/// Create a trie with the backing database `db` and `root`: a new trie if `root` is the
+	/// default value of the hash or the empty trie root, the existing trie otherwise.
+	/// Returns an error if an existing `root` does not exist.
+	pub fn new_or_existing(
+		db: &'a mut dyn HashDB<L::Hash, DBValue>,
+		root: &'a mut TrieHash<L>,
+	) -> Result<Self, TrieHash<L>, CError<L>> {
+		if *root == TrieHash::<L>::default() || *root == empty_trie_root::<L>() {
+			Ok(Self::new(db, root))
+		} else {
+			Self::from_existing(db, root)
+		}
+	}
+
 	/// Get the backing database.
 	pub fn db(&self) -> &dyn HashDB<L::Hash, DBValue> {
 		self.db
@@ -474,134 +866,1298 @@ where
 		self.db
 	}
 
-	// Cache a node by hash.
-	fn cache(
+	/// Set a filter consulted on commit before each node is written to the database,
+	/// with the node hash and prefix. This allows skipping writes of nodes that
+	/// are already known to be present in the backing store.
+	pub fn set_skip_existing(&mut self, check: SkipExisting<'a, L>) {
+		self.skip_existing = Some(check);
+	}
+
+	/// Set a guard consulted on commit before removing each node from the database.
+	/// Removals refused by the guard are deferred to a later commit.
+	pub fn set_pruning_guard(&mut self, guard: &'a dyn PruningGuard<TrieHash<L>>) {
+		self.pruning_guard = Some(guard);
+	}
+
+	/// Take the removals deferred by the pruning guard, with their prefix.
+	///
+	/// The deferred removals are retried by each commit, but are lost when the trie is dropped:
+	/// they are to be taken before, and applied to the database once the guard allows it,
+	/// directly or through `defer_removals` on a later trie.
+	pub fn take_deferred_removals(&mut self) -> Vec<(TrieHash<L>, OwnedPrefix)> {
+		self.deferred_removals.drain()
+			.map(|(hash, prefix)| (hash, (prefix.0.to_vec(), prefix.1)))
+			.collect()
+	}
+
+	/// Add `removals`, nodes with their prefix, to the removals of the next commit, which
+	/// consults the pruning guard before applying them.
+	pub fn defer_removals(
 		&mut self,
-		hash: TrieHash<L>,
-		key: Prefix,
-	) -> Result<StorageHandle, TrieHash<L>, CError<L>> {
-		let node_encoded = self.db.get(&hash, key)
-			.ok_or_else(|| Box::new(TrieError::IncompleteDatabase(hash)))?;
-		let node = Node::from_encoded::<L::Codec, L::Hash>(
-			hash,
-			&node_encoded,
-			&*self.db,
-			&mut self.storage
-		)?;
-		Ok(self.storage.alloc(Stored::Cached(node, hash)))
+		removals: impl IntoIterator<Item = (TrieHash<L>, OwnedPrefix)>,
+	) {
+		self.deferred_removals.extend(
+			removals.into_iter().map(|(hash, prefix)| (hash, (prefix.0[..].into(), prefix.1)))
+		);
 	}
 
-	// Inspect a node, choosing either to replace, restore, or delete it.
-	// If restored or replaced, returns the new node along with a flag of whether it was changed.
-	fn inspect<F>(
+	/// Reserve the room of the nodes changed by `expected_changes` more changed keys, so that the
+	/// node storage, the removed nodes and the nodes written on commit do not grow one change
+	/// after the other. About two nodes per change are reserved, up to a limit.
+	pub fn reserve(&mut self, expected_changes: usize) {
+		let nodes = expected_changes.saturating_mul(NODES_PER_CHANGE).min(MAX_RESERVED_NODES);
+		self.storage.reserve(nodes);
+		self.death_row.reserve(nodes);
+		self.pending_writes.reserve(nodes);
+	}
+
+	/// Number of nodes the trie can change in memory without growing its node storage.
+	pub fn capacity(&self) -> usize {
+		self.storage.capacity()
+	}
+
+	/// Number of nodes held in memory until the next commit: the nodes changed, and the nodes
+	/// loaded to be changed.
+	pub fn overlay_len(&self) -> usize {
+		self.storage.len()
+	}
+
+	/// Statistics on the nodes processed by the commits of this trie.
+	pub fn commit_stats(&self) -> &CommitStats {
+		&self.commit_stats
+	}
+
+	/// Remove `key` from the trie, returning its value along with what was freed.
+	pub fn remove_with_info(
 		&mut self,
-		stored: Stored<TrieHash<L>>,
-		key: &mut NibbleFullKey,
-		inspector: F,
-	) -> Result<Option<(Stored<TrieHash<L>>, bool)>, TrieHash<L>, CError<L>>
+		key: &[u8],
+	) -> Result<(Option<DBValue>, RemovalInfo), TrieHash<L>, CError<L>> {
+		self.removed_nodes = 0;
+		let value = self.remove(key)?;
+		let mut info = RemovalInfo {
+			nodes_removed: mem::take(&mut self.removed_nodes),
+			..Default::default()
+		};
+		if let Some(value) = &value {
+			info.add_value(value.len());
+		}
+		Ok((value, info))
+	}
+
+	/// Remove all of `keys` from the trie, returning what was freed.
+	pub fn remove_batch<I, K>(&mut self, keys: I) -> Result<RemovalInfo, TrieHash<L>, CError<L>>
 		where
-			F: FnOnce(
-				&mut Self,
-				Node<TrieHash<L>>,
-				&mut NibbleFullKey,
-			) -> Result<Action<TrieHash<L>>, TrieHash<L>, CError<L>>,
+			I: IntoIterator<Item = K>,
+			K: AsRef<[u8]>,
 	{
-		Ok(match stored {
-			Stored::New(node) => match inspector(self, node, key)? {
-				Action::Restore(node) => Some((Stored::New(node), false)),
-				Action::Replace(node) => Some((Stored::New(node), true)),
-				Action::Delete => None,
+		let mut info = RemovalInfo::default();
+		for key in keys {
+			info.accumulate(&self.remove_with_info(key.as_ref())?.1);
+		}
+		Ok(info)
+	}
+
+	/// Insert the value read from `reader` to its end at `key`, as `insert`, returning the old
+	/// value.
+	///
+	/// In layouts with `TrieLayout::MAX_VALUE_CHUNK`, a value cut in chunks is written to the
+	/// database chunk by chunk as it is read, only the index of its chunks staying in memory
+	/// until the commit. The chunks are dropped with the changes they belong to: by a rollback,
+	/// a discard, or a commit which no longer holds the value. On a read error, the trie is left
+	/// as it was.
+	#[cfg(feature = "std")]
+	pub fn insert_chunked(
+		&mut self,
+		key: &[u8],
+		reader: &mut dyn std::io::Read,
+	) -> result::Result<Option<DBValue>, InsertChunkedError<L>> {
+		use std::io::Read;
+
+		let mut value = Vec::new();
+		let chunk_size = match (L::MAX_INLINE_VALUE, L::MAX_VALUE_CHUNK) {
+			(Some(max), Some(chunk)) => {
+				// the value is cut in chunks once longer than both.
+				let limit = max.max(chunk) as u64 + 1;
+				(&mut *reader).take(limit).read_to_end(&mut value)
+					.map_err(InsertChunkedError::Read)?;
+				chunk_size::<L>(value.len())
 			},
-			Stored::Cached(node, hash) => match inspector(self, node, key)? {
-				Action::Restore(node) => Some((Stored::Cached(node, hash), false)),
-				Action::Replace(node) => {
-					self.death_row.insert((hash, key.left_owned()));
-					Some((Stored::New(node), true))
-				}
-				Action::Delete => {
-					self.death_row.insert((hash, key.left_owned()));
-					None
-				}
+			_ => None,
+		};
+		let chunk_size = match chunk_size {
+			Some(chunk_size) => chunk_size,
+			None => {
+				reader.read_to_end(&mut value).map_err(InsertChunkedError::Read)?;
+				return self.insert_owned(key, value).map_err(InsertChunkedError::Trie);
 			},
-		})
+		};
+
+		// the chunks are written at the prefix of the node holding the value, known once the
+		// value is in the trie.
+		let index = ChunkIndex { len: 0, chunk_size, chunks: Vec::new() };
+		let old_val = self.insert_value(key, NodeValue::Chunked(index, (Vec::new(), None)))
+			.map_err(InsertChunkedError::Trie)?;
+		let prefix = self.pending_value_mut(key).expect("the value is in memory; qed").1;
+		let written = self.chunks.len();
+		match self.write_chunks(value, chunk_size, &prefix, reader) {
+			Ok(index) => {
+				let value = self.pending_value_mut(key).expect("the value is in memory; qed").0;
+				*value = NodeValue::Chunked(index, prefix);
+			},
+			Err(error) => {
+				self.remove_chunks(written);
+				match old_val {
+					Some(old_val) => self.insert_value(key, old_val).map(|_| ()),
+					None => self.remove(key).map(|_| ()),
+				}.map_err(InsertChunkedError::Trie)?;
+				return Err(InsertChunkedError::Read(error));
+			},
+		}
+		old_val.map(|value| read_node_value(&value, &*self.db))
+			.transpose()
+			.map_err(InsertChunkedError::Trie)
 	}
 
-	// Walk the trie, attempting to find the key's node.
-	fn lookup<'x, 'key>(
-		&'x self,
-		mut partial: NibbleSlice<'key>,
-		handle: &NodeHandle<TrieHash<L>>,
-	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>
-		where 'x: 'key
-	{
-		let mut handle = handle;
+	/// Write at `prefix` the chunks of `chunk_size` bytes of the value starting with `head` and
+	/// read from `reader` to its end, returning their index.
+	#[cfg(feature = "std")]
+	fn write_chunks(
+		&mut self,
+		head: DBValue,
+		chunk_size: usize,
+		prefix: &OwnedPrefix,
+		reader: &mut dyn std::io::Read,
+	) -> std::io::Result<ChunkIndex<TrieHash<L>>> {
+		use std::io::Read;
+
+		let mut index = ChunkIndex { len: 0, chunk_size, chunks: Vec::new() };
+		let mut head = &head[..];
 		loop {
-			let (mid, child) = match *handle {
-				NodeHandle::Hash(ref hash) => return Lookup::<L, _> {
-					db: &self.db,
-					query: |v: &[u8]| v.to_vec(),
-					hash: hash.clone(),
-				}.look_up(partial),
-				NodeHandle::InMemory(ref handle) => match self.storage[handle] {
-					Node::Empty => return Ok(None),
-					Node::Leaf(ref key, ref value) => {
-						if NibbleSlice::from_stored(key) == partial {
-							return Ok(Some(value.to_vec()));
-						} else {
-							return Ok(None);
-						}
-					},
-					Node::Extension(ref slice, ref child) => {
-						let slice = NibbleSlice::from_stored(slice);
-						if partial.starts_with(&slice) {
-							(slice.len(), child)
-						} else {
-							return Ok(None);
-						}
-					},
-					Node::Branch(ref children, ref value) => {
-						if partial.is_empty() {
-							return Ok(value.as_ref().map(|v| v.to_vec()));
-						} else {
-							let idx = partial.at(0);
-							match children[idx as usize].as_ref() {
-								Some(child) => (1, child),
-								None => return Ok(None),
-							}
-						}
-					},
-					Node::NibbledBranch(ref slice, ref children, ref value) => {
-						let slice = NibbleSlice::from_stored(slice);
-						if partial.is_empty() {
-							return Ok(value.as_ref().map(|v| v.to_vec()));
-						} else if partial.starts_with(&slice) {
-							let idx = partial.at(0);
-							match children[idx as usize].as_ref() {
-								Some(child) => (1 + slice.len(), child),
-								None => return Ok(None),
-							}
-						} else {
-							return Ok(None)
-						}
-					},
-				}
-			};
-
-			partial = partial.mid(mid);
-			handle = child;
+			let mut chunk = Vec::with_capacity(chunk_size);
+			let from_head = head.len().min(chunk_size);
+			chunk.extend_from_slice(&head[..from_head]);
+			head = &head[from_head..];
+			(&mut *reader).take((chunk_size - chunk.len()) as u64).read_to_end(&mut chunk)?;
+			if chunk.is_empty() {
+				break;
+			}
+			let full = chunk.len() == chunk_size;
+			let hash = L::Hash::hash(&chunk);
+			index.len += chunk.len();
+			index.chunks.push(hash);
+			self.db.emplace(hash, (&prefix.0[..], prefix.1), chunk);
+			self.chunks.push((hash, prefix.clone()));
+			if !full {
+				break;
+			}
 		}
+		Ok(index)
 	}
 
-	/// Insert a key-value pair into the trie, creating new nodes if necessary.
+	/// The value at `key` held in memory, along with the prefix of its node. `None` if the
+	/// value or a node on the path to it is not in memory.
+	fn pending_value_mut(
+		&mut self,
+		key: &[u8],
+	) -> Option<(&mut NodeValue<TrieHash<L>>, OwnedPrefix)> {
+		let mut handle = match self.root_handle {
+			NodeHandle::InMemory(StorageHandle(handle)) => handle,
+			NodeHandle::Hash(_) => return None,
+		};
+		let mut partial = NibbleSlice::<L::Nibble>::new(key);
+		let prefix = loop {
+			let prefix = partial.left();
+			let prefix = (prefix.0.to_vec(), prefix.1);
+			let (children, node_partial) = match &self.storage.nodes[handle] {
+				Stored::New(Node::Leaf(..)) => break prefix,
+				Stored::New(Node::Extension(node_partial, child)) => {
+					let node_partial = NibbleSlice::<L::Nibble>::from_stored(node_partial);
+					partial = partial.mid(node_partial.len());
+					match child {
+						NodeHandle::InMemory(StorageHandle(child)) => handle = *child,
+						NodeHandle::Hash(_) => return None,
+					}
+					continue;
+				},
+				Stored::New(Node::Branch(children, _)) => (children, None),
+				Stored::New(Node::NibbledBranch(node_partial, children, _)) =>
+					(children, Some(node_partial)),
+				Stored::New(Node::Empty) | Stored::Cached(..) => return None,
+			};
+			if let Some(node_partial) = node_partial {
+				let node_partial = NibbleSlice::<L::Nibble>::from_stored(node_partial);
+				partial = partial.mid(node_partial.len());
+			}
+			if partial.is_empty() {
+				break prefix;
+			}
+			match &children[partial.at(0) as usize] {
+				Some(NodeHandle::InMemory(StorageHandle(child))) => handle = *child,
+				_ => return None,
+			}
+			partial = partial.mid(1);
+		};
+		match &mut self.storage.nodes[handle] {
+			Stored::New(node) => node.value_mut().map(|value| (value, prefix)),
+			Stored::Cached(..) => None,
+		}
+	}
+
+	/// Insert `value` at `key`, as `insert`, moving it into the trie.
+	fn insert_owned(
+		&mut self,
+		key: &[u8],
+		value: DBValue,
+	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
+		if value.is_empty() { return self.remove(key) }
+
+		#[cfg(feature = "std")]
+		trace!(target: "trie", "insert: key={:#x?}, value={:?}", key, ToHex(&value));
+
+		let old_val = self.insert_value(key, value.into())?;
+		old_val.map(|value| read_node_value(&value, &*self.db)).transpose()
+	}
+
+	/// Insert the non-empty `value` at `key`, returning the old value as held by the trie.
+	fn insert_value(
+		&mut self,
+		key: &[u8],
+		value: NodeValue<TrieHash<L>>,
+	) -> Result<Option<NodeValue<TrieHash<L>>>, TrieHash<L>, CError<L>> {
+		let mut old_val = None;
+		let root_handle = self.root_handle();
+		let (new_handle, _changed) = self.insert_at(
+			root_handle,
+			&mut NibbleSlice::new(key),
+			value,
+			&mut old_val,
+		)?;
+
+		#[cfg(feature = "std")]
+		trace!(target: "trie", "insert: altered trie={}", _changed);
+		self.root_handle = NodeHandle::InMemory(new_handle);
+
+		Ok(old_val)
+	}
+
+	/// Replace the value at `key` with the value `f` returns from the current one, `None` for
+	/// no value: the key is then removed.
+	///
+	/// The nodes on the path of `key` are fetched once, the change running over them in memory,
+	/// and the trie ends up as after the same `get` followed by `insert` or `remove`.
+	pub fn modify<F>(&mut self, key: &[u8], f: F) -> Result<(), TrieHash<L>, CError<L>>
+		where F: FnOnce(Option<DBValue>) -> Option<DBValue>,
+	{
+		#[cfg(feature = "std")]
+		trace!(target: "trie", "modify: key={:#x?}", key);
+
+		let root_handle = self.root_handle();
+		let (handle, old_val) = self.load_path_at(root_handle, &mut NibbleSlice::new(key))?;
+		self.root_handle = NodeHandle::InMemory(handle);
+		let old_val = old_val.map(|value| read_node_value(&value, &*self.db)).transpose()?;
+		let existed = old_val.is_some();
+		match f(old_val) {
+			Some(value) => {
+				self.insert(key, &value)?;
+			},
+			None if existed => {
+				self.remove(key)?;
+			},
+			None => {},
+		}
+		Ok(())
+	}
+
+	/// Apply a batch of changes, each setting the value of a key or removing it when `None`.
+	///
+	/// The changes are sorted by key, the last one winning for a key changed more than once,
+	/// and applied in a single descent: the changes under each child of a branch go down
+	/// together, the branch being fixed up once after all of them. The trie ends up as after
+	/// the same `insert` and `remove` calls in order.
+	pub fn apply<I>(&mut self, changes: I) -> Result<(), TrieHash<L>, CError<L>>
+		where I: IntoIterator<Item = (Vec<u8>, Option<DBValue>)>,
+	{
+		let mut changes: Vec<_> = changes.into_iter()
+			.map(|(key, value)| (key, value.filter(|value| !value.is_empty())))
+			.collect();
+		changes.sort_by(|a, b| a.0.cmp(&b.0));
+		changes.dedup_by(|next, kept| match next.0 == kept.0 {
+			true => {
+				mem::swap(next, kept);
+				true
+			},
+			false => false,
+		});
+		if changes.is_empty() {
+			return Ok(());
+		}
+
+		#[cfg(feature = "std")]
+		trace!(target: "trie", "apply: {} changes", changes.len());
+
+		let root_handle = self.root_handle();
+		match self.apply_at(root_handle, 0, &changes)? {
+			Some((handle, _changed)) => {
+				self.root_handle = NodeHandle::InMemory(handle);
+			}
+			None => {
+				self.root_handle = NodeHandle::Hash(L::Codec::hashed_null_node());
+			}
+		}
+		Ok(())
+	}
+
+	/// Copy every key-value pair of `other` into this trie, overwriting the values of the keys
+	/// in both. Returns the number of pairs copied.
+	///
+	/// Where this trie has no node at the position of a subtree of `other`, the subtree is
+	/// grafted: its nodes are copied to the database as they are, without being decoded into
+	/// memory or hashed again, and the node it hangs from references it by hash. The nodes of
+	/// both tries are walked together down to these subtrees, the subtrees under nodes of a
+	/// different shape being inserted pair by pair. The copied nodes are written to the
+	/// database right away, so that lookups see them, and removed again if the changes are
+	/// discarded or rolled back.
+	pub fn merge_from(&mut self, other: &TrieDB<L>) -> Result<u64, TrieHash<L>, CError<L>> {
+		#[cfg(feature = "std")]
+		trace!(target: "trie", "merge_from: root={:?}", other.root());
+
+		let source_root = *other.root();
+		if source_root == L::Codec::hashed_null_node() {
+			return Ok(0);
+		}
+		let root_handle = EncodedNodeHandle::Hash(source_root.as_ref());
+		let (source, _) = other.get_raw_or_lookup(source_root, root_handle, EMPTY_PREFIX)?;
+		let mut path = NibbleVec::new();
+		let mut copied = 0;
+		if self.is_empty() {
+			self.graft(other, source, (Some(source_root), source_root), &mut path, &mut copied)?;
+			if let NodeHandle::InMemory(handle) = self.root_handle() {
+				self.storage.destroy(handle);
+			}
+			self.root_handle = NodeHandle::Hash(source_root);
+			return Ok(copied);
+		}
+
+		let mut merge = Merge { other, conflicts: Vec::new(), copied };
+		let root_handle = self.root_handle();
+		let (handle, _changed) =
+			self.merge_at(root_handle, &mut path, source, source_root, &mut merge)?;
+		self.root_handle = NodeHandle::InMemory(handle);
+		let Merge { conflicts, mut copied, .. } = merge;
+		for (mut path, source, hash) in conflicts {
+			let mut pairs = Vec::new();
+			Self::walk_source(other, source, (None, hash), &mut path, &mut |path, node, node_hash| {
+				if let Some((key, slot)) = source_pair(path, node) {
+					let node_hash = node_hash.unwrap_or(hash);
+					pairs.push((key, other.read_value(slot, node_hash, path.as_prefix())?));
+				}
+				Ok(())
+			})?;
+			for (key, value) in pairs {
+				self.insert(&key, &value)?;
+				copied += 1;
+			}
+		}
+		Ok(copied)
+	}
+
+	/// Update the child trie at `parent_key` with `f`, see the `child_trie` module.
+	///
+	/// The root of the child trie is read from the value at `parent_key`, an absent key
+	/// standing for an empty child trie, and its nodes are read from and written to the
+	/// keyspace `child_keyspace(parent_key)` of the database. Once `f` returns, the changes of
+	/// the child trie are committed to the database and its new root is set at `parent_key`,
+	/// the key being removed if the child trie is empty. If `f` fails, the changes of the child
+	/// trie are discarded and the parent trie is left untouched.
+	pub fn child_trie_mut<F, R>(
+		&mut self,
+		parent_key: &[u8],
+		f: F,
+	) -> Result<R, TrieHash<L>, CError<L>>
+		where F: FnOnce(&mut TrieDBMut<L>) -> Result<R, TrieHash<L>, CError<L>>,
+	{
+		let mut root = child_root::<L>(self.root, self.get(parent_key)?)?;
+		let keyspace = child_keyspace::<L::Hash>(parent_key);
+		let result = {
+			let mut db = KeySpacedDBMut::new(&mut *self.db, keyspace);
+			let mut child = TrieDBMut::new_or_existing(&mut db, &mut root)?;
+			let result = f(&mut child);
+			match result {
+				Ok(_) => child.commit(),
+				Err(_) => child.discard(),
+			}
+			result
+		}?;
+		match root == L::Codec::hashed_null_node() {
+			true => self.remove(parent_key)?,
+			false => self.insert(parent_key, root.as_ref())?,
+		};
+		Ok(result)
+	}
+
+	/// Remove the child trie at `parent_key`, see `child_trie_mut`, returning its root, or
+	/// `None` if it is empty.
+	///
+	/// With `purge`, the nodes of the child trie are also removed from its keyspace in the
+	/// database. They are removed at once, while the removal of `parent_key` is committed with
+	/// the other changes of the trie.
+	pub fn remove_child_trie(
+		&mut self,
+		parent_key: &[u8],
+		purge: bool,
+	) -> Result<Option<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		let root = child_root::<L>(self.root, self.get(parent_key)?)?;
+		if root == L::Codec::hashed_null_node() {
+			return Ok(None);
+		}
+		if purge {
+			let keyspace = child_keyspace::<L::Hash>(parent_key);
+			let mut nodes = Vec::new();
+			{
+				let db = KeySpacedDB::new(&self.db, keyspace);
+				let child = TrieDB::<L>::new(&db, &root)?;
+				for item in TrieDBNodeIterator::new(&child)? {
+					if let (prefix, Some(hash), node) = item? {
+						let (key, last) = prefix.as_prefix();
+						if let Some(value) = node.value::<L>(hash)? {
+							let fetch = |hash: &TrieHash<L>| child.db().get(hash, (key, last));
+							for value_hash in value_nodes::<L>(value, fetch) {
+								nodes.push((value_hash, (key.to_vec(), last)));
+							}
+						}
+						nodes.push((hash, (key.to_vec(), last)));
+					}
+				}
+			}
+			KeySpacedDBMut::new(&mut *self.db, keyspace).remove_batch(nodes);
+		}
+		self.remove(parent_key)?;
+		Ok(Some(root))
+	}
+
+	/// Remove all the keys starting with `prefix` from the trie, returning what was freed.
+	///
+	/// The subtree under `prefix` is detached from the trie. When `measure` is unset, only the
+	/// subtree root and the nodes of the subtree already in memory are visited: the nodes only
+	/// stored in the database are neither loaded nor removed from it, and are not counted.
+	/// When `measure` is set, the whole subtree is loaded and counted, and its nodes are
+	/// removed from the database on commit.
+	pub fn clear_prefix(
+		&mut self,
+		prefix: &[u8],
+		measure: bool,
+	) -> Result<RemovalInfo, TrieHash<L>, CError<L>> {
+		#[cfg(feature = "std")]
+		trace!(target: "trie", "clear_prefix: prefix={:#x?}", prefix);
+
+		let root_handle = self.root_handle();
+		let mut key = NibbleSlice::new(prefix);
+		let mut info = RemovalInfo::default();
+		self.removed_nodes = 0;
+
+		match self.clear_prefix_at(root_handle, &mut key, measure, &mut info)? {
+			Some((handle, _changed)) => {
+				self.root_handle = NodeHandle::InMemory(handle);
+			}
+			None => {
+				self.root_handle = NodeHandle::Hash(L::Codec::hashed_null_node());
+			}
+		}
+
+		info.nodes_removed = mem::take(&mut self.removed_nodes);
+		Ok(info)
+	}
+
+	/// Rewrite the nodes of the trie, or of the subtrie under `prefix`, that are not in the
+	/// canonical form of the layout.
+	///
+	/// The changes of a `TrieDBMut` keep the trie canonical, but nodes written to the database
+	/// by other means may not be: a branch with a single child and no value, a branch without
+	/// children, or an extension followed by anything but a branch. These nodes are merged
+	/// with their child or removed as a removal would, so that once the whole trie is
+	/// canonicalized its root is the root of a trie built from the same key-value pairs. The
+	/// nodes on the path to `prefix` are checked too, as they may have to be merged with the
+	/// rewritten subtrie. Canonical nodes are left untouched and not written again.
+	///
+	/// At most `node_budget` nodes are visited, the nodes past the budget being left as they
+	/// are: a large trie can then be canonicalized one prefix at a time.
+	pub fn canonicalize(
+		&mut self,
+		prefix: Option<&[u8]>,
+		node_budget: Option<u64>,
+	) -> Result<CanonicalizeStats, TrieHash<L>, CError<L>> {
+		#[cfg(feature = "std")]
+		trace!(target: "trie", "canonicalize: prefix={:#x?}", prefix);
+
+		let root_handle = self.root_handle();
+		let prefix = prefix.map(NibbleSlice::new);
+		let budget = node_budget.unwrap_or(u64::MAX);
+		let mut stats = CanonicalizeStats::default();
+		match self.canonicalize_at(root_handle, &mut NibbleVec::new(), prefix, budget, &mut stats)? {
+			Some((handle, _changed)) => self.root_handle = handle,
+			None => {
+				self.root_handle = NodeHandle::Hash(L::Codec::hashed_null_node());
+			}
+		}
+		Ok(stats)
+	}
+
+	/// Iterate over the key-value pairs of the trie in key order, pending changes included.
+	///
+	/// The nodes held in memory are walked as they are, the subtrees left untouched being read
+	/// from the database: nothing is committed or hashed.
+	pub fn iter(&self) -> TrieDBMutIterator<'_, L> {
+		let root = match self.root_handle {
+			NodeHandle::Hash(hash) if hash == L::Codec::hashed_null_node() => None,
+			NodeHandle::Hash(hash) => Some(IterChild::Hash(hash)),
+			NodeHandle::InMemory(ref handle) => Some(IterChild::Memory(&self.storage[handle])),
+		};
+		TrieDBMutIterator {
+			storage: &self.storage,
+			db: &*self.db,
+			root,
+			stack: Vec::new(),
+			key: NibbleVec::new(),
+		}
+	}
+
+	/// Get the root without committing, or `None` if there are uncommitted changes.
+	/// Use `TrieMut::root` to commit and get the root.
+	pub fn committed_root(&self) -> Option<&TrieHash<L>> {
+		match self.root_handle {
+			NodeHandle::Hash(_) if self.death_row.is_empty() => Some(self.root),
+			_ => None,
+		}
+	}
+
+	/// Take a checkpoint of the uncommitted changes, to go back to with `rollback_to`.
+	///
+	/// The checkpoint copies the nodes held in memory and leaves the database untouched.
+	/// Checkpoints nest: rolling back to or discarding one releases the checkpoints taken
+	/// after it. A commit releases all of them, as the nodes it wrote stay in the database.
+	pub fn checkpoint(&mut self) -> CheckpointId {
+		let id = self.next_checkpoint;
+		self.next_checkpoint += 1;
+		self.checkpoints.push(Checkpoint {
+			id,
+			storage: self.storage.clone(),
+			root_handle: self.root_handle.clone(),
+			death_row: self.death_row.clone(),
+			grafted: self.grafted.len(),
+			chunks: self.chunks.len(),
+		});
+		CheckpointId(id)
+	}
+
+	/// Go back to the state of the trie at `checkpoint`, dropping the changes made since.
+	/// The checkpoint is released along with the ones taken after it.
+	pub fn rollback_to(
+		&mut self,
+		checkpoint: CheckpointId,
+	) -> result::Result<(), CheckpointError> {
+		let position = self.checkpoint_position(checkpoint)?;
+		let state = self.checkpoints.drain(position..).next().expect("position is in bounds");
+		self.storage = state.storage;
+		self.root_handle = state.root_handle;
+		self.death_row = state.death_row;
+		self.remove_grafted(state.grafted);
+		self.remove_chunks(state.chunks);
+		Ok(())
+	}
+
+	/// Release `checkpoint` and the checkpoints taken after it, keeping the changes made since.
+	pub fn discard_checkpoint(
+		&mut self,
+		checkpoint: CheckpointId,
+	) -> result::Result<(), CheckpointError> {
+		let position = self.checkpoint_position(checkpoint)?;
+		self.checkpoints.truncate(position);
+		Ok(())
+	}
+
+	/// Position of a checkpoint in the stack of checkpoints.
+	fn checkpoint_position(
+		&self,
+		checkpoint: CheckpointId,
+	) -> result::Result<usize, CheckpointError> {
+		match self.checkpoints.iter().position(|state| state.id == checkpoint.0) {
+			Some(position) => Ok(position),
+			None if checkpoint.0 < self.first_uncommitted_checkpoint =>
+				Err(CheckpointError::Committed),
+			None => Err(CheckpointError::Released),
+		}
+	}
+
+	/// The root the trie would have once committed, computed without writing anything: the
+	/// pending changes are kept in memory and can still be extended, committed or discarded.
+	pub fn root_preview(&self) -> TrieHash<L> {
+		match self.root_handle {
+			NodeHandle::Hash(hash) => hash,
+			NodeHandle::InMemory(ref handle) => match self.storage.nodes[handle.0] {
+				Stored::Cached(_, hash) => hash,
+				Stored::New(ref node) => {
+					let mut encoded = Vec::new();
+					self.preview_encode(node, &mut encoded);
+					L::Hash::hash(&encoded)
+				},
+			},
+		}
+	}
+
+	/// Drop the changes made since the last commit, the trie going back to its committed root.
+	pub fn discard(&mut self) {
+		#[cfg(feature = "std")]
+		trace!(target: "trie", "discard: {} nodes in memory", self.storage.nodes.len());
+
+		self.storage = NodeStorage::empty();
+		self.death_row.clear();
+		self.root_handle = NodeHandle::Hash(*self.root);
+		self.remove_grafted(0);
+		self.remove_chunks(0);
+	}
+
+	/// Remove from the database the nodes copied by `merge_from` after the first `keep`.
+	fn remove_grafted(&mut self, keep: usize) {
+		for (hash, prefix) in self.grafted.drain(keep..) {
+			self.db.remove(&hash, (&prefix.0[..], prefix.1));
+		}
+	}
+
+	/// Remove from the database the chunks written by `insert_chunked` after the first `keep`.
+	fn remove_chunks(&mut self, keep: usize) {
+		for (hash, prefix) in self.chunks.drain(keep..) {
+			self.db.remove(&hash, (&prefix.0[..], prefix.1));
+		}
+	}
+
+	// Cache a node by hash.
+	fn cache(
+		&mut self,
+		hash: TrieHash<L>,
+		key: Prefix,
+	) -> Result<StorageHandle, TrieHash<L>, CError<L>> {
+		let node_encoded = self.db.get(&hash, key)
+			.ok_or_else(|| Box::new(TrieError::incomplete_database(hash, key)))?;
+		profile!(nodes_fetched);
+		profile!(bytes_fetched, node_encoded.len());
+		profile!(cache_misses);
+		let node = Node::from_encoded::<L::Codec, L::Hash>(
+			hash,
+			&node_encoded,
+			(L::MAX_INLINE_VALUE.is_some(), L::MAX_VALUE_CHUNK, key),
+			&*self.db,
+			&mut self.storage
+		)?;
+		Ok(self.storage.alloc(Stored::Cached(node, hash)))
+	}
+
+	// Inspect a node, choosing either to replace, restore, or delete it.
+	// If restored or replaced, returns the new node along with a flag of whether it was changed.
+	fn inspect<F>(
+		&mut self,
+		stored: Stored<TrieHash<L>, L::Nibble>,
+		key: &mut NibbleFullKey<L::Nibble>,
+		inspector: F,
+	) -> Result<Option<(Stored<TrieHash<L>, L::Nibble>, bool)>, TrieHash<L>, CError<L>>
+		where
+			F: FnOnce(
+				&mut Self,
+				Node<TrieHash<L>, L::Nibble>,
+				&mut NibbleFullKey<L::Nibble>,
+			) -> Result<Action<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>>,
+	{
+		Ok(match stored {
+			Stored::New(node) => match inspector(self, node, key)? {
+				Action::Restore(node) => Some((Stored::New(node), false)),
+				Action::Replace(node) => Some((Stored::New(node), true)),
+				Action::Delete => None,
+			},
+			Stored::Cached(node, hash) => match inspector(self, node, key)? {
+				Action::Restore(node) => Some((Stored::Cached(node, hash), false)),
+				Action::Replace(node) => {
+					self.death_row.insert((hash, key.left_owned()));
+					Some((Stored::New(node), true))
+				}
+				Action::Delete => {
+					self.death_row.insert((hash, key.left_owned()));
+					None
+				}
+			},
+		})
+	}
+
+	// Walk the trie, attempting to find the key's node.
+	fn lookup<'x, 'key>(
+		&'x self,
+		mut partial: NibbleSlice<'key, L::Nibble>,
+		handle: &NodeHandle<TrieHash<L>>,
+	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>>
+		where 'x: 'key
+	{
+		let mut handle = handle;
+		loop {
+			let (mid, child) = match *handle {
+				NodeHandle::Hash(ref hash) => return Lookup::<L, _> {
+					db: &self.db,
+					query: |v: &[u8]| v.to_vec(),
+					hash: hash.clone(),
+				}.look_up(partial),
+				NodeHandle::InMemory(ref handle) => match self.storage[handle] {
+					Node::Empty => return Ok(None),
+					Node::Leaf(ref key, ref value) => {
+						if NibbleSlice::from_stored(key) == partial {
+							return read_node_value(value, &*self.db).map(Some);
+						} else {
+							return Ok(None);
+						}
+					},
+					Node::Extension(ref slice, ref child) => {
+						let slice = NibbleSlice::from_stored(slice);
+						if partial.starts_with(&slice) {
+							(slice.len(), child)
+						} else {
+							return Ok(None);
+						}
+					},
+					Node::Branch(ref children, ref value) => {
+						if partial.is_empty() {
+							let value = value.as_ref().map(|v| read_node_value(v, &*self.db));
+							return value.transpose();
+						} else {
+							let idx = partial.at(0);
+							match children[idx as usize].as_ref() {
+								Some(child) => (1, child),
+								None => return Ok(None),
+							}
+						}
+					},
+					Node::NibbledBranch(ref slice, ref children, ref value) => {
+						let slice = NibbleSlice::from_stored(slice);
+						if !partial.starts_with(&slice) {
+							return Ok(None)
+						} else if partial.len() == slice.len() {
+							let value = value.as_ref().map(|v| read_node_value(v, &*self.db));
+							return value.transpose();
+						} else {
+							let idx = partial.at(slice.len());
+							match children[idx as usize].as_ref() {
+								Some(child) => (1 + slice.len(), child),
+								None => return Ok(None),
+							}
+						}
+					},
+				}
+			};
+
+			partial = partial.mid(mid);
+			handle = child;
+		}
+	}
+
+	/// Load the nodes on the path of `key` from `handle` into memory, leaving them unchanged.
+	/// Returns the new handle of the node, with the value at `key`.
+	fn load_path_at(
+		&mut self,
+		handle: NodeHandle<TrieHash<L>>,
+		key: &mut NibbleFullKey<L::Nibble>,
+	) -> Result<(StorageHandle, Option<NodeValue<TrieHash<L>>>), TrieHash<L>, CError<L>> {
+		let h = match handle {
+			NodeHandle::InMemory(h) => {
+				profile!(cache_hits);
+				h
+			},
+			NodeHandle::Hash(h) => self.cache(h, key.left())?,
+		};
+		let mut stored = self.storage.destroy(h);
+		let partial = *key;
+		let value = match &mut stored {
+			Stored::New(node) | Stored::Cached(node, _) => match node {
+				Node::Empty => None,
+				Node::Leaf(encoded, value) => match NibbleSlice::from_stored(encoded) == partial {
+					true => Some(value.clone()),
+					false => None,
+				},
+				Node::Extension(encoded, child) => {
+					let slice = NibbleSlice::from_stored(encoded);
+					match partial.starts_with(&slice) {
+						true => {
+							key.advance(slice.len());
+							self.load_child(child, key)?
+						},
+						false => None,
+					}
+				},
+				Node::Branch(children, value) => match partial.try_at(0) {
+					None => value.clone(),
+					Some(index) => match children[index as usize].as_mut() {
+						Some(child) => {
+							key.advance(1);
+							self.load_child(child, key)?
+						},
+						None => None,
+					},
+				},
+				Node::NibbledBranch(encoded, children, value) => {
+					let slice = NibbleSlice::from_stored(encoded);
+					if !partial.starts_with(&slice) {
+						None
+					} else {
+						match partial.try_at(slice.len()) {
+							None => value.clone(),
+							Some(index) => match children[index as usize].as_mut() {
+								Some(child) => {
+									key.advance(slice.len() + 1);
+									self.load_child(child, key)?
+								},
+								None => None,
+							},
+						}
+					}
+				},
+			},
+		};
+		Ok((self.storage.alloc(stored), value))
+	}
+
+	/// Load the nodes on the path of `key` from `child`, see `load_path_at`.
+	fn load_child(
+		&mut self,
+		child: &mut NodeHandle<TrieHash<L>>,
+		key: &mut NibbleFullKey<L::Nibble>,
+	) -> Result<Option<NodeValue<TrieHash<L>>>, TrieHash<L>, CError<L>> {
+		let handle = mem::replace(child, NodeHandle::Hash(Default::default()));
+		let (handle, value) = self.load_path_at(handle, key)?;
+		*child = NodeHandle::InMemory(handle);
+		Ok(value)
+	}
+
+	/// Merge the subtree of `other` at `source` into the node at `handle`, both at `path`.
+	/// The subtrees under nodes of different shapes are left to insert in `conflicts`.
+	fn merge_at(
+		&mut self,
+		handle: NodeHandle<TrieHash<L>>,
+		path: &mut NibbleVec<L::Nibble>,
+		source: OwnedNode<DBValue, L::Nibble>,
+		source_hash: TrieHash<L>,
+		merge: &mut Merge<L>,
+	) -> Result<(StorageHandle, bool), TrieHash<L>, CError<L>> {
+		let h = match handle {
+			NodeHandle::InMemory(h) => {
+				profile!(cache_hits);
+				h
+			},
+			NodeHandle::Hash(h) => self.cache(h, path.as_prefix())?,
+		};
+		let (node, cached) = match self.storage.destroy(h) {
+			Stored::New(node) => (node, None),
+			Stored::Cached(node, hash) => (node, Some(hash)),
+		};
+
+		let same_partial = |partial: &NodeKey, source_partial: NibbleSlice<L::Nibble>| {
+			NibbleSlice::from_stored(partial) == source_partial
+		};
+		let same_shape = match (&node, source.node()) {
+			(Node::Branch(..), EncodedNode::Branch(..)) => true,
+			(Node::Extension(partial, _), EncodedNode::Extension(source_partial, _))
+			| (Node::NibbledBranch(partial, ..), EncodedNode::NibbledBranch(source_partial, ..)) =>
+				same_partial(partial, source_partial),
+			_ => false,
+		};
+		if !same_shape {
+			merge.conflicts.push((path.clone(), source, source_hash));
+			let stored = match cached {
+				Some(hash) => Stored::Cached(node, hash),
+				None => Stored::New(node),
+			};
+			return Ok((self.storage.alloc(stored), false));
+		}
+
+		let prefix_len = path.len();
+		let (node, changed) = match (node, source.node()) {
+			(Node::Extension(partial, child), EncodedNode::Extension(source_partial, source)) => {
+				path.append_partial(source_partial.right());
+				let (source_child, child_hash) =
+					merge.other.get_raw_or_lookup(source_hash, source, path.as_prefix())?;
+				let child_hash = child_hash.unwrap_or(source_hash);
+				let (child, changed) = self.merge_at(child, path, source_child, child_hash, merge)?;
+				(Node::Extension(partial, child.into()), changed)
+			},
+			(Node::Branch(mut children, mut value), _) => {
+				let changed = self.merge_branch(
+					&mut children,
+					&mut value,
+					&source,
+					path,
+					source_hash,
+					merge,
+				)?;
+				(Node::Branch(children, value), changed)
+			},
+			(Node::NibbledBranch(partial, mut children, mut value), _) => {
+				path.append_partial(NibbleSlice::<L::Nibble>::from_stored(&partial).right());
+				let changed = self.merge_branch(
+					&mut children,
+					&mut value,
+					&source,
+					path,
+					source_hash,
+					merge,
+				)?;
+				(Node::NibbledBranch(partial, children, value), changed)
+			},
+			_ => unreachable!("the shapes of the nodes match"),
+		};
+		path.drop_lasts(path.len() - prefix_len);
+
+		let stored = match (cached, changed) {
+			(Some(hash), false) => Stored::Cached(node, hash),
+			(Some(hash), true) => {
+				let prefix = path.as_prefix();
+				self.death_row.insert((hash, (prefix.0.into(), prefix.1)));
+				Stored::New(node)
+			},
+			(None, _) => Stored::New(node),
+		};
+		Ok((self.storage.alloc(stored), changed))
+	}
+
+	/// Merge the value and the children of the branch `source`, with children at `path`, into
+	/// a branch, returning whether the branch changed.
+	fn merge_branch(
+		&mut self,
+		children: &mut Children<NodeHandle<TrieHash<L>>, L::Nibble>,
+		value: &mut Option<NodeValue<TrieHash<L>>>,
+		source: &OwnedNode<DBValue, L::Nibble>,
+		path: &mut NibbleVec<L::Nibble>,
+		source_hash: TrieHash<L>,
+		merge: &mut Merge<L>,
+	) -> Result<bool, TrieHash<L>, CError<L>> {
+		let (source_children, source_value, partial_len) = match source.node() {
+			EncodedNode::Branch(children, value) => (children, value, 0),
+			EncodedNode::NibbledBranch(partial, children, value) =>
+				(children, value, partial.len()),
+			_ => unreachable!("the source node is a branch"),
+		};
+		let other = merge.other;
+		let mut changed = false;
+		if let Some(source_value) = source_value {
+			merge.copied += 1;
+			// the value nodes are at the prefix of their node, before its partial key.
+			let mut node_prefix = path.clone();
+			node_prefix.drop_lasts(partial_len);
+			let source_value =
+				other.read_value(source_value, source_hash, node_prefix.as_prefix())?.into();
+			if value.as_ref() != Some(&source_value) {
+				*value = Some(source_value);
+				changed = true;
+			}
+		}
+		for (index, source_child) in source_children.iter().enumerate() {
+			let source_child = match source_child {
+				Some(source_child) => *source_child,
+				None => continue,
+			};
+			path.push(index as u8);
+			let (source_node, child_hash) =
+				other.get_raw_or_lookup(source_hash, source_child, path.as_prefix())?;
+			let same_hash = match (&children[index], child_hash) {
+				(Some(NodeHandle::Hash(hash)), Some(child_hash)) => *hash == child_hash,
+				_ => false,
+			};
+			match children[index].take() {
+				Some(child) if same_hash => {
+					// the same subtree: only its pairs are counted.
+					let copied = &mut merge.copied;
+					let mut count = |path: &NibbleVec<_>, node: &OwnedNode<DBValue, _>, _| {
+						*copied += source_pair(path, node).map_or(0, |_| 1);
+						Ok(())
+					};
+					Self::walk_source(other, source_node, (None, source_hash), path, &mut count)?;
+					children[index] = Some(child);
+				},
+				Some(child) => {
+					let child_hash = child_hash.unwrap_or(source_hash);
+					let (child, child_changed) =
+						self.merge_at(child, path, source_node, child_hash, merge)?;
+					children[index] = Some(child.into());
+					changed |= child_changed;
+				},
+				None => {
+					let hashes = (child_hash, source_hash);
+					self.graft(other, source_node, hashes, path, &mut merge.copied)?;
+					children[index] = Some(Node::inline_or_hash::<L::Codec, L::Hash>(
+						source_hash,
+						source_child,
+						(L::MAX_INLINE_VALUE.is_some(), L::MAX_VALUE_CHUNK, path.as_prefix()),
+						&*self.db,
+						&mut self.storage,
+					)?);
+					changed = true;
+				},
+			}
+			path.pop();
+		}
+		Ok(changed)
+	}
+
+	/// Copy the nodes of the subtree of `other` at `source` and `path` to the database,
+	/// counting its pairs in `copied`.
+	fn graft(
+		&mut self,
+		other: &TrieDB<L>,
+		source: OwnedNode<DBValue, L::Nibble>,
+		(hash, parent_hash): (Option<TrieHash<L>>, TrieHash<L>),
+		path: &mut NibbleVec<L::Nibble>,
+		copied: &mut u64,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		let db = &mut *self.db;
+		let grafted = &mut self.grafted;
+		Self::walk_source(other, source, (hash, parent_hash), path, &mut |path, node, hash| {
+			if let Some(hash) = hash {
+				let prefix = path.as_prefix();
+				db.emplace(hash, prefix, node.data().to_vec());
+				grafted.push((hash, (prefix.0.to_vec(), prefix.1)));
+			}
+			let node_hash = hash.unwrap_or(parent_hash);
+			if let Some(value) = node.value::<L>(node_hash)? {
+				let prefix = path.as_prefix();
+				other.read_value_nodes(value, node_hash, prefix, |value_hash, value| {
+					db.emplace(*value_hash, prefix, value.to_vec());
+					grafted.push((*value_hash, (prefix.0.to_vec(), prefix.1)));
+				})?;
+			}
+			*copied += source_pair(path, node).map_or(0, |_| 1);
+			Ok(())
+		})
+	}
+
+	/// Visit the nodes of the subtree of `other` at `node` and `path`, each with its position
+	/// and its hash when not inline.
+	fn walk_source<F>(
+		other: &TrieDB<L>,
+		node: OwnedNode<DBValue, L::Nibble>,
+		(hash, parent_hash): (Option<TrieHash<L>>, TrieHash<L>),
+		path: &mut NibbleVec<L::Nibble>,
+		visit: &mut F,
+	) -> Result<(), TrieHash<L>, CError<L>>
+		where F: FnMut(&NibbleVec<L::Nibble>, &OwnedNode<DBValue, L::Nibble>, Option<TrieHash<L>>)
+			-> Result<(), TrieHash<L>, CError<L>>,
+	{
+		visit(path, &node, hash)?;
+		let node_hash = hash.unwrap_or(parent_hash);
+		let prefix_len = path.len();
+		match node.node() {
+			EncodedNode::Extension(partial, child) => {
+				path.append_partial(partial.right());
+				let (child, child_hash) =
+					other.get_raw_or_lookup(node_hash, child, path.as_prefix())?;
+				Self::walk_source(other, child, (child_hash, node_hash), path, visit)?;
+			},
+			EncodedNode::Branch(children, _) | EncodedNode::NibbledBranch(_, children, _) => {
+				if let EncodedNode::NibbledBranch(partial, ..) = node.node() {
+					path.append_partial(partial.right());
+				}
+				for (index, child) in children.iter().enumerate() {
+					if let Some(child) = child {
+						path.push(index as u8);
+						let (child, child_hash) =
+							other.get_raw_or_lookup(node_hash, *child, path.as_prefix())?;
+						Self::walk_source(other, child, (child_hash, node_hash), path, visit)?;
+						path.pop();
+					}
+				}
+			},
+			EncodedNode::Empty | EncodedNode::Leaf(..) => {},
+		}
+		path.drop_lasts(path.len() - prefix_len);
+		Ok(())
+	}
+
+	/// Apply `changes`, sorted by key with distinct keys, to the node at `handle`, which is
+	/// at the first `depth` nibbles of their keys.
+	fn apply_at(
+		&mut self,
+		mut handle: NodeHandle<TrieHash<L>>,
+		depth: usize,
+		mut changes: &[(Vec<u8>, Option<DBValue>)],
+	) -> Result<Option<(StorageHandle, bool)>, TrieHash<L>, CError<L>> {
+		let mut changed = false;
+		loop {
+			let mut key = NibbleSlice::new_offset(&changes[0].0, depth);
+			let h = match handle {
+				NodeHandle::InMemory(h) => {
+					profile!(cache_hits);
+					h
+				},
+				NodeHandle::Hash(h) => self.cache(h, key.left())?,
+			};
+			if changes.len() > 1 && Self::passes_through(&self.storage[&h], depth, changes) {
+				let stored = self.storage.destroy(h);
+				let opt = self.inspect(
+					stored,
+					&mut key,
+					move |trie, node, key| trie.apply_inspector(node, *key, depth, changes),
+				)?;
+				return Ok(opt.map(|(new, node_changed)| {
+					(self.storage.alloc(new), changed || node_changed)
+				}));
+			}
+
+			// the first change reshapes the node, the others may then go down together.
+			let ((first_key, value), rest) = changes.split_first().expect("changes are not empty");
+			let mut key = NibbleSlice::new_offset(first_key, depth);
+			let mut old_val = None;
+			let result = match value {
+				Some(value) => Some(self.insert_at(
+					NodeHandle::InMemory(h),
+					&mut key,
+					value.clone().into(),
+					&mut old_val,
+				)?),
+				None => self.remove_at(NodeHandle::InMemory(h), &mut key, &mut old_val)?,
+			};
+			changes = rest;
+			match result {
+				Some((new, node_changed)) if changes.is_empty() =>
+					return Ok(Some((new, changed || node_changed))),
+				Some((new, node_changed)) => {
+					handle = NodeHandle::InMemory(new);
+					changed |= node_changed;
+				},
+				None if changes.is_empty() => return Ok(None),
+				None => {
+					handle = NodeHandle::InMemory(self.storage.alloc(Stored::New(Node::Empty)));
+					changed = true;
+				},
+			}
+		}
+	}
+
+	/// Whether all the `changes` to a node at `depth` go through it to a branch.
+	fn passes_through(
+		node: &Node<TrieHash<L>, L::Nibble>,
+		depth: usize,
+		changes: &[(Vec<u8>, Option<DBValue>)],
+	) -> bool {
+		let partial = match node {
+			Node::Branch(..) => return true,
+			Node::Extension(encoded, _) | Node::NibbledBranch(encoded, ..) =>
+				NibbleSlice::<L::Nibble>::from_stored(encoded),
+			Node::Empty | Node::Leaf(..) => return false,
+		};
+		changes.iter().all(|(key, _)| NibbleSlice::new_offset(key, depth).starts_with(&partial))
+	}
+
+	/// The inspector applying changes going through the node to a branch.
+	fn apply_inspector(
+		&mut self,
+		node: Node<TrieHash<L>, L::Nibble>,
+		key: NibbleSlice<L::Nibble>,
+		depth: usize,
+		changes: &[(Vec<u8>, Option<DBValue>)],
+	) -> Result<Action<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
+		Ok(match node {
+			Node::Extension(encoded, child) => {
+				let child_depth = depth + NibbleSlice::<L::Nibble>::from_stored(&encoded).len();
+				match self.apply_at(child, child_depth, changes)? {
+					Some((new_child, true)) =>
+						Action::Replace(self.fix(Node::Extension(encoded, new_child.into()), key)?),
+					Some((new_child, false)) =>
+						Action::Restore(Node::Extension(encoded, new_child.into())),
+					None => Action::Delete,
+				}
+			},
+			Node::Branch(mut children, mut value) => {
+				let changed = self.apply_to_branch(&mut children, &mut value, depth, changes)?;
+				self.fix_branch(Node::Branch(children, value), changed, key)?
+			},
+			Node::NibbledBranch(encoded, mut children, mut value) => {
+				let depth = depth + NibbleSlice::<L::Nibble>::from_stored(&encoded).len();
+				let changed = self.apply_to_branch(&mut children, &mut value, depth, changes)?;
+				self.fix_branch(Node::NibbledBranch(encoded, children, value), changed, key)?
+			},
+			Node::Empty | Node::Leaf(..) => unreachable!("changes never go through a leaf"),
+		})
+	}
+
+	/// Apply `changes` to the value and the children of a branch whose children are at
+	/// `depth + 1`, returning whether the branch changed.
+	fn apply_to_branch(
+		&mut self,
+		children: &mut Children<NodeHandle<TrieHash<L>>, L::Nibble>,
+		value: &mut Option<NodeValue<TrieHash<L>>>,
+		depth: usize,
+		mut changes: &[(Vec<u8>, Option<DBValue>)],
+	) -> Result<bool, TrieHash<L>, CError<L>> {
+		let mut changed = false;
+		// a change to the branch value sorts first.
+		if let Some(((key, new_value), rest)) = changes.split_first() {
+			if key.len() * L::Nibble::NIBBLE_PER_BYTE == depth {
+				let new_value = new_value.clone().map(NodeValue::from);
+				changed = *value != new_value;
+				*value = new_value;
+				changes = rest;
+			}
+		}
+		while !changes.is_empty() {
+			let index = NibbleSlice::<L::Nibble>::new(&changes[0].0).at(depth);
+			let end = changes.iter()
+				.position(|(key, _)| NibbleSlice::<L::Nibble>::new(key).at(depth) != index)
+				.unwrap_or(changes.len());
+			let (group, rest) = changes.split_at(end);
+			changes = rest;
+			let index = index as usize;
+			let existed = children[index].is_some();
+			let child = match children[index].take() {
+				Some(child) => child,
+				None => NodeHandle::InMemory(self.storage.alloc(Stored::New(Node::Empty))),
+			};
+			match self.apply_at(child, depth + 1, group)? {
+				Some((new_child, child_changed)) => {
+					children[index] = Some(new_child.into());
+					changed |= child_changed;
+				},
+				None => changed |= existed,
+			}
+		}
+		Ok(changed)
+	}
+
+	/// Restore a branch left unchanged, or fix it up after changes, deleting it when nothing
+	/// is left.
+	fn fix_branch(
+		&mut self,
+		node: Node<TrieHash<L>, L::Nibble>,
+		changed: bool,
+		key: NibbleSlice<L::Nibble>,
+	) -> Result<Action<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
+		if !changed {
+			return Ok(Action::Restore(node));
+		}
+		let empty = match &node {
+			Node::Branch(children, value) | Node::NibbledBranch(_, children, value) =>
+				value.is_none() && children.iter().all(Option::is_none),
+			_ => false,
+		};
+		Ok(match empty {
+			true => Action::Delete,
+			false => Action::Replace(self.fix(node, key)?),
+		})
+	}
+
+	/// Insert a key-value pair into the trie, creating new nodes if necessary.
 	fn insert_at(
 		&mut self,
 		handle: NodeHandle<TrieHash<L>>,
-		key: &mut NibbleFullKey,
-		value: DBValue,
-		old_val: &mut Option<DBValue>,
+		key: &mut NibbleFullKey<L::Nibble>,
+		value: NodeValue<TrieHash<L>>,
+		old_val: &mut Option<NodeValue<TrieHash<L>>>,
 	) -> Result<(StorageHandle, bool), TrieHash<L>, CError<L>> {
 		let h = match handle {
-			NodeHandle::InMemory(h) => h,
+			NodeHandle::InMemory(h) => {
+				profile!(cache_hits);
+				h
+			},
 			NodeHandle::Hash(h) => self.cache(h, key.left())?,
 		};
 		// cache then destroy for hash handle (handle being root in most case)
@@ -616,15 +2172,15 @@ where
 	/// The insertion inspector.
 	fn insert_inspector(
 		&mut self,
-		node: Node<TrieHash<L>>,
-		key: &mut NibbleFullKey,
-		value: DBValue,
-		old_val: &mut Option<DBValue>,
-	) -> Result<InsertAction<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		node: Node<TrieHash<L>, L::Nibble>,
+		key: &mut NibbleFullKey<L::Nibble>,
+		value: NodeValue<TrieHash<L>>,
+		old_val: &mut Option<NodeValue<TrieHash<L>>>,
+	) -> Result<InsertAction<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
 		let partial = key.clone();
 
 		#[cfg(feature = "std")]
-		trace!(target: "trie", "augmented (partial: {:?}, value: {:?})", partial, ToHex(&value));
+		trace!(target: "trie", "augmented (partial: {:?}, value: {:?})", partial, value);
 
 		Ok(match node {
 			Node::Empty => {
@@ -963,11 +2519,14 @@ where
 	fn remove_at(
 		&mut self,
 		handle: NodeHandle<TrieHash<L>>,
-		key: &mut NibbleFullKey,
-		old_val: &mut Option<DBValue>,
+		key: &mut NibbleFullKey<L::Nibble>,
+		old_val: &mut Option<NodeValue<TrieHash<L>>>,
 	) -> Result<Option<(StorageHandle, bool)>, TrieHash<L>, CError<L>> {
 		let stored = match handle {
-			NodeHandle::InMemory(h) => self.storage.destroy(h),
+			NodeHandle::InMemory(h) => {
+				profile!(cache_hits);
+				self.storage.destroy(h)
+			},
 			NodeHandle::Hash(h) => {
 				let handle = self.cache(h, key.left())?;
 				self.storage.destroy(handle)
@@ -986,26 +2545,19 @@ where
 	/// The removal inspector.
 	fn remove_inspector(
 		&mut self,
-		node: Node<TrieHash<L>>,
-		key: &mut NibbleFullKey,
-		old_val: &mut Option<DBValue>,
-	) -> Result<Action<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		node: Node<TrieHash<L>, L::Nibble>,
+		key: &mut NibbleFullKey<L::Nibble>,
+		old_val: &mut Option<NodeValue<TrieHash<L>>>,
+	) -> Result<Action<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
 		let partial = key.clone();
 		Ok(match (node, partial.is_empty()) {
 			(Node::Empty, _) => Action::Delete,
 			(Node::Branch(c, None), true) => Action::Restore(Node::Branch(c, None)),
-			(Node::NibbledBranch(n, c, None), true) =>
-				Action::Restore(Node::NibbledBranch(n, c, None)),
 			(Node::Branch(children, Some(val)), true) => {
 				*old_val = Some(val);
 				// always replace since we took the value out.
 				Action::Replace(self.fix(Node::Branch(children, None), key.clone())?)
 			},
-			(Node::NibbledBranch(n, children, Some(val)), true) => {
-				*old_val = Some(val);
-				// always replace since we took the value out.
-				Action::Replace(self.fix(Node::NibbledBranch(n, children, None), key.clone())?)
-			},
 			(Node::Branch(mut children, value), false) => {
 				let idx = partial.at(0) as usize;
 				if let Some(child) = children[idx].take() {
@@ -1041,7 +2593,8 @@ where
 					Action::Restore(Node::Branch(children, value))
 				}
 			},
-			(Node::NibbledBranch(encoded, mut children, value), false) => {
+			// an empty key only matches a branch with an empty partial.
+			(Node::NibbledBranch(encoded, mut children, value), _) => {
 				let (common, existing_length) = {
 					let existing_key = NibbleSlice::from_stored(&encoded);
 					(existing_key.common_prefix(&partial), existing_key.len())
@@ -1104,58 +2657,445 @@ where
 					}
 				}
 			},
-			(Node::Leaf(encoded, value), _) => {
-				if NibbleSlice::from_stored(&encoded) == partial {
-					// this is the node we were looking for. Let's delete it.
-					*old_val = Some(value);
-					Action::Delete
+			(Node::Leaf(encoded, value), _) => {
+				if NibbleSlice::from_stored(&encoded) == partial {
+					// this is the node we were looking for. Let's delete it.
+					*old_val = Some(value);
+					self.removed_nodes += 1;
+					Action::Delete
+				} else {
+					// leaf the node alone.
+					#[cfg(feature = "std")]
+					trace!(
+						target: "trie",
+						"restoring leaf wrong partial, partial={:?}, existing={:?}",
+						partial,
+						NibbleSlice::<L::Nibble>::from_stored(&encoded),
+					);
+					Action::Restore(Node::Leaf(encoded, value))
+				}
+			},
+			(Node::Extension(encoded, child_branch), _) => {
+				let (common, existing_length) = {
+					let existing_key = NibbleSlice::from_stored(&encoded);
+					(existing_key.common_prefix(&partial), existing_key.len())
+				};
+				if common == existing_length {
+					// try to remove from the child branch.
+					#[cfg(feature = "std")]
+					trace!(target: "trie", "removing from extension child, partial={:?}", partial);
+					let prefix = key.clone();
+					key.advance(common);
+					match self.remove_at(child_branch, key, old_val)? {
+						Some((new_child, changed)) => {
+							let new_child = new_child.into();
+
+							// if the child branch was unchanged, then the extension is too.
+							// otherwise, this extension may need fixing.
+							match changed {
+								true => Action::Replace(
+									self.fix(Node::Extension(encoded, new_child), prefix)?
+								),
+								false => Action::Restore(Node::Extension(encoded, new_child)),
+							}
+						}
+						None => {
+							// the whole branch got deleted.
+							// that means that this extension is useless.
+							self.removed_nodes += 1;
+							Action::Delete
+						}
+					}
+				} else {
+					// partway through an extension -- nothing to do here.
+					Action::Restore(Node::Extension(encoded, child_branch))
+				}
+			},
+		})
+	}
+
+	/// Removes the nodes under a prefix from the trie.
+	fn clear_prefix_at(
+		&mut self,
+		handle: NodeHandle<TrieHash<L>>,
+		key: &mut NibbleFullKey<L::Nibble>,
+		measure: bool,
+		info: &mut RemovalInfo,
+	) -> Result<Option<(StorageHandle, bool)>, TrieHash<L>, CError<L>> {
+		let stored = match handle {
+			NodeHandle::InMemory(h) => {
+				profile!(cache_hits);
+				self.storage.destroy(h)
+			},
+			NodeHandle::Hash(h) => {
+				let handle = self.cache(h, key.left())?;
+				self.storage.destroy(handle)
+			}
+		};
+
+		let opt = self.inspect(
+			stored,
+			key,
+			move |trie, node, key| trie.clear_prefix_inspector(node, key, measure, info),
+		)?;
+
+		Ok(opt.map(|(new, changed)| (self.storage.alloc(new), changed)))
+	}
+
+	/// The prefix removal inspector.
+	fn clear_prefix_inspector(
+		&mut self,
+		node: Node<TrieHash<L>, L::Nibble>,
+		key: &mut NibbleFullKey<L::Nibble>,
+		measure: bool,
+		info: &mut RemovalInfo,
+	) -> Result<Action<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
+		let partial = *key;
+		Ok(match node {
+			Node::Empty => Action::Delete,
+			Node::Leaf(encoded, value) => {
+				if NibbleSlice::from_stored(&encoded).starts_with(&partial) {
+					self.removed_nodes += 1;
+					info.add_value(value.len());
+					Action::Delete
+				} else {
+					Action::Restore(Node::Leaf(encoded, value))
+				}
+			},
+			Node::Extension(encoded, child) => {
+				let existing_key = NibbleSlice::from_stored(&encoded);
+				if existing_key.starts_with(&partial) {
+					// the whole extension is under the prefix.
+					let node = Node::Extension(encoded, child);
+					self.detach(node, &mut left_nibbles(key), measure, info)?;
+					Action::Delete
+				} else if partial.starts_with(&existing_key) {
+					let prefix = *key;
+					key.advance(existing_key.len());
+					match self.clear_prefix_at(child, key, measure, info)? {
+						Some((new_child, true)) => Action::Replace(
+							self.fix(Node::Extension(encoded, new_child.into()), prefix)?
+						),
+						Some((new_child, false)) =>
+							Action::Restore(Node::Extension(encoded, new_child.into())),
+						None => {
+							// the whole branch got deleted.
+							self.removed_nodes += 1;
+							Action::Delete
+						},
+					}
+				} else {
+					Action::Restore(Node::Extension(encoded, child))
+				}
+			},
+			Node::Branch(mut children, value) => {
+				if partial.is_empty() {
+					// the whole branch is under the prefix.
+					let node = Node::Branch(children, value);
+					self.detach(node, &mut left_nibbles(key), measure, info)?;
+					return Ok(Action::Delete);
+				}
+				let idx = partial.at(0) as usize;
+				if let Some(child) = children[idx].take() {
+					let prefix = *key;
+					key.advance(1);
+					match self.clear_prefix_at(child, key, measure, info)? {
+						Some((new, changed)) => {
+							children[idx] = Some(new.into());
+							let branch = Node::Branch(children, value);
+							match changed {
+								true => Action::Replace(branch),
+								false => Action::Restore(branch),
+							}
+						},
+						None => Action::Replace(self.fix(Node::Branch(children, value), prefix)?),
+					}
 				} else {
-					// leaf the node alone.
-					#[cfg(feature = "std")]
-					trace!(
-						target: "trie",
-						"restoring leaf wrong partial, partial={:?}, existing={:?}",
-						partial,
-						NibbleSlice::from_stored(&encoded),
-					);
-					Action::Restore(Node::Leaf(encoded, value))
+					Action::Restore(Node::Branch(children, value))
 				}
 			},
-			(Node::Extension(encoded, child_branch), _) => {
-				let (common, existing_length) = {
+			Node::NibbledBranch(encoded, mut children, value) => {
+				let (under_prefix, through_prefix, existing_length) = {
 					let existing_key = NibbleSlice::from_stored(&encoded);
-					(existing_key.common_prefix(&partial), existing_key.len())
+					(
+						existing_key.starts_with(&partial),
+						partial.starts_with(&existing_key),
+						existing_key.len(),
+					)
 				};
-				if common == existing_length {
-					// try to remove from the child branch.
-					#[cfg(feature = "std")]
-					trace!(target: "trie", "removing from extension child, partial={:?}", partial);
-					let prefix = key.clone();
-					key.advance(common);
-					match self.remove_at(child_branch, key, old_val)? {
-						Some((new_child, changed)) => {
-							let new_child = new_child.into();
-
-							// if the child branch was unchanged, then the extension is too.
-							// otherwise, this extension may need fixing.
+				if under_prefix {
+					// the whole branch is under the prefix.
+					let node = Node::NibbledBranch(encoded, children, value);
+					self.detach(node, &mut left_nibbles(key), measure, info)?;
+					return Ok(Action::Delete);
+				}
+				if !through_prefix {
+					return Ok(Action::Restore(Node::NibbledBranch(encoded, children, value)));
+				}
+				// `partial` goes through the branch partial key: it has a nibble at the branch.
+				let idx = partial.at(existing_length) as usize;
+				if let Some(child) = children[idx].take() {
+					let prefix = *key;
+					key.advance(existing_length + 1);
+					match self.clear_prefix_at(child, key, measure, info)? {
+						Some((new, changed)) => {
+							children[idx] = Some(new.into());
+							let branch = Node::NibbledBranch(encoded, children, value);
 							match changed {
-								true => Action::Replace(
-									self.fix(Node::Extension(encoded, new_child), prefix)?
-								),
-								false => Action::Restore(Node::Extension(encoded, new_child)),
+								true => Action::Replace(branch),
+								false => Action::Restore(branch),
 							}
-						}
-						None => {
-							// the whole branch got deleted.
-							// that means that this extension is useless.
-							Action::Delete
-						}
+						},
+						None => Action::Replace(
+							self.fix(Node::NibbledBranch(encoded, children, value), prefix)?
+						),
 					}
 				} else {
-					// partway through an extension -- nothing to do here.
-					Action::Restore(Node::Extension(encoded, child_branch))
+					Action::Restore(Node::NibbledBranch(encoded, children, value))
+				}
+			},
+		})
+	}
+
+	/// Free a node detached from the trie at `prefix` along with its descendants, counting
+	/// them in `info`. Descendants only stored in the database are loaded and removed when
+	/// `measure` is set, and left untouched otherwise.
+	fn detach(
+		&mut self,
+		node: Node<TrieHash<L>, L::Nibble>,
+		prefix: &mut NibbleVec<L::Nibble>,
+		measure: bool,
+		info: &mut RemovalInfo,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		self.removed_nodes += 1;
+		let (partial, mut children, value) = match node {
+			Node::Empty => {
+				self.removed_nodes -= 1;
+				return Ok(());
+			},
+			Node::Leaf(_, value) => {
+				info.add_value(value.len());
+				return Ok(());
+			},
+			Node::Extension(partial, child) => {
+				let length = prefix.len();
+				prefix.append_partial(NibbleSlice::<L::Nibble>::from_stored(&partial).right());
+				self.detach_child(child, prefix, measure, info)?;
+				prefix.drop_lasts(prefix.len() - length);
+				return Ok(());
+			},
+			Node::Branch(children, value) => (None, children, value),
+			Node::NibbledBranch(partial, children, value) => (Some(partial), children, value),
+		};
+		if let Some(value) = value {
+			info.add_value(value.len());
+		}
+		let length = prefix.len();
+		if let Some(partial) = partial {
+			prefix.append_partial(NibbleSlice::<L::Nibble>::from_stored(&partial).right());
+		}
+		for (index, child) in children.iter_mut().enumerate() {
+			if let Some(child) = child.take() {
+				prefix.push(index as u8);
+				self.detach_child(child, prefix, measure, info)?;
+				prefix.pop();
+			}
+		}
+		prefix.drop_lasts(prefix.len() - length);
+		Ok(())
+	}
+
+	/// Free the detached subtree at `child`, see `detach`.
+	fn detach_child(
+		&mut self,
+		child: NodeHandle<TrieHash<L>>,
+		prefix: &mut NibbleVec<L::Nibble>,
+		measure: bool,
+		info: &mut RemovalInfo,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		let stored = match child {
+			NodeHandle::InMemory(h) => self.storage.destroy(h),
+			NodeHandle::Hash(h) if measure => {
+				let handle = self.cache(h, prefix.as_prefix())?;
+				self.storage.destroy(handle)
+			},
+			NodeHandle::Hash(_) => return Ok(()),
+		};
+		let node = match stored {
+			Stored::New(node) => node,
+			Stored::Cached(node, hash) => {
+				let (start, last) = prefix.as_prefix();
+				self.death_row.insert((hash, (start.into(), last)));
+				node
+			},
+		};
+		self.detach(node, prefix, measure, info)
+	}
+
+	/// Canonicalize the node at `handle` found at `path`, with the rest of the prefix at the
+	/// node, see `canonicalize`. Returns the handle of the new node with whether it changed,
+	/// or `None` if the node was removed.
+	fn canonicalize_at(
+		&mut self,
+		handle: NodeHandle<TrieHash<L>>,
+		path: &mut NibbleVec<L::Nibble>,
+		prefix: Option<NibbleSlice<L::Nibble>>,
+		budget: u64,
+		stats: &mut CanonicalizeStats,
+	) -> Result<Canonicalized<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		if stats.visited >= budget {
+			stats.budget_exhausted = true;
+			return Ok(Some((handle, false)));
+		}
+		stats.visited += 1;
+		let stored = match handle {
+			NodeHandle::InMemory(h) => self.storage.destroy(h),
+			NodeHandle::Hash(h) => {
+				let handle = self.cache(h, path.as_prefix())?;
+				self.storage.destroy(handle)
+			}
+		};
+
+		// the nibbles of `path`, as the left side of the key of the node.
+		let (start, last) = path.as_prefix();
+		let mut key_bytes: BackingByteVec = start.into();
+		key_bytes.extend(last);
+		let mut key = NibbleSlice::new_offset(&key_bytes, path.len());
+		let opt = self.inspect(
+			stored,
+			&mut key,
+			move |trie, node, key| trie.canonicalize_inspector(node, *key, path, prefix, budget, stats),
+		)?;
+
+		Ok(opt.map(|(new, changed)| (self.storage.alloc(new).into(), changed)))
+	}
+
+	/// The canonicalization inspector.
+	fn canonicalize_inspector(
+		&mut self,
+		node: Node<TrieHash<L>, L::Nibble>,
+		key: NibbleSlice<L::Nibble>,
+		path: &mut NibbleVec<L::Nibble>,
+		prefix: Option<NibbleSlice<L::Nibble>>,
+		budget: u64,
+		stats: &mut CanonicalizeStats,
+	) -> Result<Action<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
+		Ok(match node {
+			// only the root of the empty trie is an empty node.
+			Node::Empty if path.is_empty() => Action::Restore(Node::Empty),
+			Node::Empty => {
+				stats.rewritten += 1;
+				Action::Delete
+			},
+			Node::Leaf(encoded, value) => Action::Restore(Node::Leaf(encoded, value)),
+			Node::Extension(encoded, child) => {
+				let partial = NibbleSlice::from_stored(&encoded);
+				let length = path.len();
+				path.append_partial(partial.right());
+				let result = match Descend::new(prefix, &partial) {
+					Descend::None => Some((child, false)),
+					Descend::All => self.canonicalize_at(child, path, None, budget, stats)?,
+					Descend::Towards(rest) =>
+						self.canonicalize_at(child, path, Some(rest), budget, stats)?,
+				};
+				path.drop_lasts(path.len() - length);
+				let (child, changed) = match result {
+					Some(result) => result,
+					None => {
+						stats.rewritten += 1;
+						return Ok(Action::Delete);
+					},
+				};
+				// a child left in the database is taken as canonical.
+				let canonical = match child {
+					NodeHandle::InMemory(ref h) => matches!(self.storage[h], Node::Branch(..)),
+					NodeHandle::Hash(_) => true,
+				};
+				let node = Node::Extension(encoded, child);
+				if !canonical {
+					stats.rewritten += 1;
+					Action::Replace(self.fix(node, key)?)
+				} else if changed {
+					Action::Replace(node)
+				} else {
+					Action::Restore(node)
+				}
+			},
+			Node::Branch(mut children, value) => {
+				let descend = Descend::new(prefix, &NibbleSlice::new(&[]));
+				let changed = self.canonicalize_children(&mut children, path, descend, budget, stats)?;
+				self.canonical_branch(Node::Branch(children, value), key, changed, stats)?
+			},
+			Node::NibbledBranch(encoded, mut children, value) => {
+				let partial = NibbleSlice::from_stored(&encoded);
+				let descend = Descend::new(prefix, &partial);
+				let length = path.len();
+				path.append_partial(partial.right());
+				let changed = self.canonicalize_children(&mut children, path, descend, budget, stats)?;
+				path.drop_lasts(path.len() - length);
+				self.canonical_branch(Node::NibbledBranch(encoded, children, value), key, changed, stats)?
+			},
+		})
+	}
+
+	/// Canonicalize the children of a branch at `path`, returning whether any changed.
+	fn canonicalize_children(
+		&mut self,
+		children: &mut Children<NodeHandle<TrieHash<L>>, L::Nibble>,
+		path: &mut NibbleVec<L::Nibble>,
+		descend: Descend<L::Nibble>,
+		budget: u64,
+		stats: &mut CanonicalizeStats,
+	) -> Result<bool, TrieHash<L>, CError<L>> {
+		let mut changed = false;
+		for (index, slot) in children.iter_mut().enumerate() {
+			let prefix = match descend {
+				Descend::None => break,
+				Descend::All => None,
+				Descend::Towards(rest) if rest.at(0) == index as u8 => Some(rest.mid(1)),
+				Descend::Towards(_) => continue,
+			};
+			if let Some(child) = slot.take() {
+				path.push(index as u8);
+				let result = self.canonicalize_at(child, path, prefix, budget, stats)?;
+				path.pop();
+				match result {
+					Some((child, child_changed)) => {
+						changed |= child_changed;
+						*slot = Some(child);
+					},
+					None => changed = true,
 				}
+			}
+		}
+		Ok(changed)
+	}
+
+	/// The action bringing a branch at `key` to its canonical form, its children being
+	/// canonicalized already.
+	fn canonical_branch(
+		&mut self,
+		node: Node<TrieHash<L>, L::Nibble>,
+		key: NibbleSlice<L::Nibble>,
+		changed: bool,
+		stats: &mut CanonicalizeStats,
+	) -> Result<Action<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
+		let (used, has_value) = match node {
+			Node::Branch(ref children, ref value) | Node::NibbledBranch(_, ref children, ref value) =>
+				(children.iter().filter(|child| child.is_some()).count(), value.is_some()),
+			node => return Ok(Action::Restore(node)),
+		};
+		Ok(match (used, has_value) {
+			(0, false) => {
+				stats.rewritten += 1;
+				Action::Delete
 			},
+			(0, true) | (1, false) => {
+				stats.rewritten += 1;
+				Action::Replace(self.fix(node, key)?)
+			},
+			_ if changed => Action::Replace(node),
+			_ => Action::Restore(node),
 		})
 	}
 
@@ -1167,9 +3107,9 @@ where
 	/// - Extension node followed by anything other than a Branch node.
 	fn fix(
 		&mut self,
-		node: Node<TrieHash<L>>,
-		key: NibbleSlice,
-	) -> Result<Node<TrieHash<L>>, TrieHash<L>, CError<L>> {
+		node: Node<TrieHash<L>, L::Nibble>,
+		key: NibbleSlice<L::Nibble>,
+	) -> Result<Node<TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
 		match node {
 			Node::Branch(mut children, value) => {
 				// if only a single value, transmute to leaf/extension and feed through fixed.
@@ -1180,7 +3120,7 @@ where
 					Many,
 				};
 				let mut used_index = UsedIndex::None;
-				for i in 0..16 {
+				for i in 0..L::Nibble::NIBBLE_LENGTH {
 					match (children[i].is_none(), &used_index) {
 						(false, &UsedIndex::None) => used_index = UsedIndex::One(i as u8),
 						(false, &UsedIndex::One(_)) => {
@@ -1197,7 +3137,9 @@ where
 					(UsedIndex::One(a), None) => {
 						// only one onward node. make an extension.
 
-						let new_partial = NibbleSlice::new_offset(&[a], 1).to_stored();
+						let offset = L::Nibble::NIBBLE_PER_BYTE - 1;
+						let new_partial = NibbleSlice::<L::Nibble>::new_offset(&[a], offset)
+							.to_stored();
 						let child = children[a as usize].take()
 							.expect("used_index only set if occupied; qed");
 						let new_node = Node::Extension(new_partial, child);
@@ -1207,7 +3149,7 @@ where
 						// make a leaf.
 						#[cfg(feature = "std")]
 						trace!(target: "trie", "fixing: branch -> leaf");
-						Ok(Node::Leaf(NibbleSlice::new(&[]).to_stored(), value))
+						Ok(Node::Leaf(NibbleSlice::<L::Nibble>::new(&[]).to_stored(), value))
 					}
 					(_, value) => {
 						// all is well.
@@ -1226,7 +3168,7 @@ where
 					Many,
 				};
 				let mut used_index = UsedIndex::None;
-				for i in 0..16 {
+				for i in 0..L::Nibble::NIBBLE_LENGTH {
 					match (children[i].is_none(), &used_index) {
 						(false, &UsedIndex::None) => used_index = UsedIndex::One(i as u8),
 						(false, &UsedIndex::One(_)) => {
@@ -1244,17 +3186,8 @@ where
 						// only one onward node. use child instead
 						let child = children[a as usize].take()
 							.expect("used_index only set if occupied; qed");
-						let mut key2 = key.clone();
-						key2.advance((enc_nibble.1.len() * nibble_ops::NIBBLE_PER_BYTE) - enc_nibble.0);
-						let (start, alloc_start, prefix_end) = match key2.left() {
-							(start, None) => (start, None, Some(nibble_ops::push_at_left(0, a, 0))),
-							(start, Some(v)) => {
-								let mut so: BackingByteVec = start.into();
-								so.push(nibble_ops::pad_left(v) | a);
-								(start, Some(so), None)
-							},
-						};
-						let child_prefix = (alloc_start.as_ref().map(|start| &start[..]).unwrap_or(start), prefix_end);
+						let owned_prefix = child_prefix(&key, &enc_nibble, Some(a));
+						let child_prefix = (&owned_prefix.0[..], owned_prefix.1);
 						let stored = match child {
 							NodeHandle::InMemory(h) => self.storage.destroy(h),
 							NodeHandle::Hash(h) => {
@@ -1275,26 +3208,28 @@ where
 						match child_node {
 							Node::Leaf(sub_partial, value) => {
 								let mut enc_nibble = enc_nibble;
-								combine_key(
+								combine_key::<L::Nibble>(
 									&mut enc_nibble,
-									(nibble_ops::NIBBLE_PER_BYTE - 1, &[a][..]),
+									(L::Nibble::NIBBLE_PER_BYTE - 1, &[a][..]),
 								);
-								combine_key(
+								combine_key::<L::Nibble>(
 									&mut enc_nibble,
 									(sub_partial.0, &sub_partial.1[..]),
 								);
+								self.removed_nodes += 1;
 								Ok(Node::Leaf(enc_nibble, value))
 							},
 							Node::NibbledBranch(sub_partial, ch_children, ch_value) => {
 								let mut enc_nibble = enc_nibble;
-								combine_key(
+								combine_key::<L::Nibble>(
 									&mut enc_nibble,
-									(nibble_ops::NIBBLE_PER_BYTE - 1, &[a][..]),
+									(L::Nibble::NIBBLE_PER_BYTE - 1, &[a][..]),
 								);
-								combine_key(
+								combine_key::<L::Nibble>(
 									&mut enc_nibble,
 									(sub_partial.0, &sub_partial.1[..]),
 								);
+								self.removed_nodes += 1;
 								Ok(Node::NibbledBranch(enc_nibble, ch_children, ch_value))
 							},
 							_ => unreachable!(),
@@ -1315,21 +3250,11 @@ where
 				}
 			},
 			Node::Extension(partial, child) => {
-				// We could advance key, but this code can also be called
-				// recursively, so there might be some prefix from branch.
-				let last = partial.1[partial.1.len() - 1] & (255 >> 4);
-				let mut key2 = key.clone();
-				key2.advance((partial.1.len() * nibble_ops::NIBBLE_PER_BYTE) - partial.0 - 1);
-				let (start, alloc_start, prefix_end) = match key2.left() {
-					(start, None) => (start, None, Some(nibble_ops::push_at_left(0, last, 0))),
-					(start, Some(v)) => {
-						let mut so: BackingByteVec = start.into();
-						// Complete last byte with `last`.
-						so.push(nibble_ops::pad_left(v) | last);
-						(start, Some(so), None)
-					},
-				};
-				let child_prefix = (alloc_start.as_ref().map(|start| &start[..]).unwrap_or(start), prefix_end);
+				// The child prefix is built from the partial rather than by advancing key:
+				// this code can also be called recursively on a combined partial, which
+				// does not follow key.
+				let owned_prefix = child_prefix(&key, &partial, None);
+				let child_prefix = (&owned_prefix.0[..], owned_prefix.1);
 
 				let stored = match child {
 					NodeHandle::InMemory(h) => self.storage.destroy(h),
@@ -1355,7 +3280,8 @@ where
 						}
 						// subpartial
 						let mut partial = partial;
-						combine_key(&mut partial, (sub_partial.0, &sub_partial.1[..]));
+						combine_key::<L::Nibble>(&mut partial, (sub_partial.0, &sub_partial.1[..]));
+						self.removed_nodes += 1;
 						#[cfg(feature = "std")]
 						trace!(
 							target: "trie",
@@ -1372,7 +3298,8 @@ where
 						}
 						// subpartial oly
 						let mut partial = partial;
-						combine_key(&mut partial, (sub_partial.0, &sub_partial.1[..]));
+						combine_key::<L::Nibble>(&mut partial, (sub_partial.0, &sub_partial.1[..]));
+						self.removed_nodes += 1;
 						#[cfg(feature = "std")]
 						trace!(
 							target: "trie",
@@ -1406,22 +3333,54 @@ where
 		#[cfg(feature = "std")]
 		trace!(target: "trie", "Committing trie changes to db.");
 
+		// the checkpoints cannot go back past the nodes written now.
+		self.checkpoints.clear();
+		self.first_uncommitted_checkpoint = self.next_checkpoint;
+		self.grafted.clear();
+		// the chunks are kept by the values that still hold them, see `commit_value`.
+		for chunk in self.chunks.drain(..) {
+			*self.unclaimed_chunks.entry(chunk).or_insert(0) += 1;
+		}
+
 		// always kill all the nodes on death row.
 		#[cfg(feature = "std")]
 		trace!(target: "trie", "{:?} nodes to remove from db", self.death_row.len());
-		for (hash, prefix) in self.death_row.drain() {
-			self.db.remove(&hash, (&prefix.0[..], prefix.1));
+		let mut deferred = HashSet::new();
+		let mut removals = Vec::with_capacity(self.death_row.len() + self.deferred_removals.len());
+		for (hash, prefix) in self.death_row.drain().chain(self.deferred_removals.drain()) {
+			let prune = match self.pruning_guard {
+				Some(guard) => guard.can_prune(&hash, (&prefix.0[..], prefix.1)),
+				None => true,
+			};
+			if prune {
+				removals.push((hash, (prefix.0.to_vec(), prefix.1)));
+			} else {
+				deferred.insert((hash, prefix));
+			}
+		}
+		self.deferred_removals = deferred;
+		if L::MAX_INLINE_VALUE.is_some() {
+			self.add_value_node_removals(&mut removals);
+		}
+		if !removals.is_empty() {
+			self.db.remove_batch(removals);
 		}
 
 		let handle = match self.root_handle() {
-			NodeHandle::Hash(_) => return, // no changes necessary.
+			NodeHandle::Hash(hash) => {
+				// no node to write, the root may only have been emptied.
+				*self.root = hash;
+				self.remove_unclaimed_chunks();
+				return;
+			},
 			NodeHandle::InMemory(h) => h,
 		};
 
 		match self.storage.destroy(handle) {
-			Stored::New(node) => {
+			Stored::New(mut node) => {
+				self.commit_value(&mut node, EMPTY_PREFIX);
 				let mut k = NibbleVec::new();
-				let encoded_root = node.into_encoded::<_, L::Codec, L::Hash>(
+				let node = node.commit_children(
 					|child, o_slice, o_index| {
 						let mov = k.append_optional_slice_and_nibble(o_slice, o_index);
 						let cr = self.commit_child(child, &mut k);
@@ -1429,10 +3388,17 @@ where
 						cr
 					}
 				);
+				let mut encoded_root = mem::take(&mut self.encode_buffer);
+				encoded_root.clear();
+				node.encode_into::<L::Codec>(&mut encoded_root);
 				#[cfg(feature = "std")]
 				trace!(target: "trie", "encoded root node: {:#x?}", &encoded_root[..]);
-				*self.root = self.db.insert(EMPTY_PREFIX, &encoded_root[..]);
+				*self.root = self.write_node(EMPTY_PREFIX, &encoded_root);
+				self.encode_buffer = encoded_root;
 				self.hash_count += 1;
+				if !self.pending_writes.is_empty() {
+					self.db.emplace_batch(mem::take(&mut self.pending_writes));
+				}
 
 				self.root_handle = NodeHandle::Hash(*self.root);
 			}
@@ -1444,6 +3410,35 @@ where
 				);
 			}
 		}
+		self.remove_unclaimed_chunks();
+	}
+
+	/// Remove from the database the chunks written since the last commit that no committed
+	/// value kept.
+	fn remove_unclaimed_chunks(&mut self) {
+		for ((hash, prefix), count) in self.unclaimed_chunks.drain() {
+			for _ in 0..count {
+				self.db.remove(&hash, (&prefix.0[..], prefix.1));
+			}
+		}
+	}
+
+	/// Add to `removals`, nodes to remove from the database, their value nodes.
+	fn add_value_node_removals(&self, removals: &mut Vec<(TrieHash<L>, OwnedPrefix)>) {
+		let mut values = Vec::new();
+		for (hash, prefix) in removals.iter() {
+			let node = self.db.get(hash, (&prefix.0[..], prefix.1))
+				.and_then(|data| OwnedNode::new::<L::Codec>(data).ok());
+			// a node whose value slot does not decode has no value nodes to remove.
+			let value = node.as_ref().and_then(|node| node.value::<L>(*hash).ok().flatten());
+			if let Some(value) = value {
+				let fetch = |hash: &TrieHash<L>| self.db.get(hash, (&prefix.0[..], prefix.1));
+				for value_hash in value_nodes::<L>(value, fetch) {
+					values.push((value_hash, prefix.clone()));
+				}
+			}
+		}
+		removals.extend(values);
 	}
 
 	/// Commit a node by hashing it and writing it to the db. Returns a
@@ -1454,18 +3449,19 @@ where
 	fn commit_child(
 		&mut self,
 		handle: NodeHandle<TrieHash<L>>,
-		prefix: &mut NibbleVec,
+		prefix: &mut NibbleVec<L::Nibble>,
 	) -> ChildReference<TrieHash<L>> {
 		match handle {
 			NodeHandle::Hash(hash) => ChildReference::Hash(hash),
 			NodeHandle::InMemory(storage_handle) => {
 				match self.storage.destroy(storage_handle) {
 					Stored::Cached(_, hash) => ChildReference::Hash(hash),
-					Stored::New(node) => {
-						let encoded = {
+					Stored::New(mut node) => {
+						self.commit_value(&mut node, prefix.as_prefix());
+						let node = {
 							let commit_child = |
 								node_handle,
-								o_slice: Option<&NibbleSlice>,
+								o_slice: Option<&NibbleSlice<L::Nibble>>,
 								o_index: Option<u8>
 							| {
 								let mov = prefix.append_optional_slice_and_nibble(o_slice, o_index);
@@ -1473,10 +3469,14 @@ where
 								prefix.drop_lasts(mov);
 								cr
 							};
-							node.into_encoded::<_, L::Codec, L::Hash>(commit_child)
+							node.commit_children(commit_child)
 						};
-						if encoded.len() >= L::Hash::LENGTH {
-							let hash = self.db.insert(prefix.as_prefix(), &encoded[..]);
+						// The children are committed: the buffer is free for this node.
+						let mut encoded = mem::take(&mut self.encode_buffer);
+						encoded.clear();
+						node.encode_into::<L::Codec>(&mut encoded);
+						let child_ref = if encoded.len() >= L::Hash::LENGTH {
+							let hash = self.write_node(prefix.as_prefix(), &encoded);
 							self.hash_count +=1;
 							ChildReference::Hash(hash)
 						} else {
@@ -1486,13 +3486,154 @@ where
 							let len = encoded.len();
 							h.as_mut()[..len].copy_from_slice(&encoded[..len]);
 							ChildReference::Inline(h, len)
-						}
+						};
+						self.encode_buffer = encoded;
+						child_ref
 					}
 				}
 			}
 		}
 	}
 
+	/// Replace the value of `node`, at `prefix`, with its value slot, writing the value node of
+	/// a value too large to be held by `node`, or the index of a value written in chunks.
+	fn commit_value(&mut self, node: &mut Node<TrieHash<L>, L::Nibble>, prefix: Prefix) {
+		let value = match node.value_mut() {
+			Some(value) => value,
+			None => return,
+		};
+		let slot = match value {
+			NodeValue::Value(value) => {
+				let max = match L::MAX_INLINE_VALUE {
+					Some(max) => max,
+					None => return,
+				};
+				let hash = match value.len() > max {
+					true => store_value::<L>(value, |node| self.write_node(prefix, node)),
+					false => None,
+				};
+				value_slot::<L>(value, hash.as_ref()).expect("the layout has value nodes; qed")
+			},
+			NodeValue::Chunked(index, written) => {
+				for chunk in index.chunks.iter() {
+					self.claim_chunk(chunk, written, prefix);
+				}
+				chunked_slot::<L>(&self.write_node(prefix, &index.encode()))
+			},
+		};
+		*value = NodeValue::Value(slot);
+	}
+
+	/// Keep the chunk `hash` written at `written` for a value committed at `prefix`: the chunk
+	/// is copied to `prefix` if it is written elsewhere, or kept by another value already.
+	fn claim_chunk(&mut self, hash: &TrieHash<L>, written: &OwnedPrefix, prefix: Prefix) {
+		let written_at = (&written.0[..], written.1);
+		if written_at == prefix {
+			if let Some(count) = self.unclaimed_chunks.get_mut(&(*hash, written.clone())) {
+				if *count > 0 {
+					*count -= 1;
+					return;
+				}
+			}
+		}
+		if let Some(chunk) = self.db.get(hash, written_at) {
+			self.db.emplace(*hash, prefix, chunk);
+		}
+	}
+
+	/// The reference to a node the commit would produce, see `root_preview`.
+	fn preview_child(&self, handle: &NodeHandle<TrieHash<L>>) -> ChildReference<TrieHash<L>> {
+		let node = match handle {
+			NodeHandle::Hash(hash) => return ChildReference::Hash(*hash),
+			NodeHandle::InMemory(handle) => match self.storage.nodes[handle.0] {
+				Stored::Cached(_, hash) => return ChildReference::Hash(hash),
+				Stored::New(ref node) => node,
+			},
+		};
+		let mut encoded = Vec::new();
+		self.preview_encode(node, &mut encoded);
+		if encoded.len() >= L::Hash::LENGTH {
+			ChildReference::Hash(L::Hash::hash(&encoded))
+		} else {
+			let mut h = <TrieHash<L>>::default();
+			h.as_mut()[..encoded.len()].copy_from_slice(&encoded);
+			ChildReference::Inline(h, encoded.len())
+		}
+	}
+
+	/// Append the encoding of a node in memory to `output`, as the commit would.
+	fn preview_encode(&self, node: &Node<TrieHash<L>, L::Nibble>, output: &mut Vec<u8>) {
+		let slot = |value: &NodeValue<TrieHash<L>>| match value {
+			NodeValue::Value(value) => value_slot::<L>(value, value_node_hash::<L>(value).as_ref()),
+			NodeValue::Chunked(index, _) =>
+				Some(chunked_slot::<L>(&L::Hash::hash(&index.encode()))),
+		};
+		match node {
+			Node::Empty => output.extend_from_slice(L::Codec::empty_node()),
+			Node::Leaf(partial, value) => {
+				let pr = NibbleSlice::<L::Nibble>::new_offset(&partial.1[..], partial.0);
+				let slot = slot(value);
+				let slot = slot.as_deref().unwrap_or(value.as_slice());
+				L::Codec::leaf_node_into(pr.right(), slot, output)
+			},
+			Node::Extension(partial, child) => {
+				let pr = NibbleSlice::<L::Nibble>::new_offset(&partial.1[..], partial.0);
+				let child = self.preview_child(child);
+				L::Codec::extension_node_into(pr.right_iter(), pr.len(), child, output)
+			},
+			Node::Branch(children, value) => {
+				let slot = value.as_ref().and_then(slot);
+				L::Codec::branch_node_into(
+					children.iter()
+						.map(|child| child.as_ref().map(|child| self.preview_child(child))),
+					slot.as_deref().or(value.as_ref().map(NodeValue::as_slice)),
+					output,
+				)
+			},
+			Node::NibbledBranch(partial, children, value) => {
+				let pr = NibbleSlice::<L::Nibble>::new_offset(&partial.1[..], partial.0);
+				let slot = value.as_ref().and_then(slot);
+				L::Codec::branch_node_nibbled_into(
+					pr.right_iter(),
+					pr.len(),
+					children.iter().map(|child| child.as_ref().map(|child| self.preview_child(child))),
+					slot.as_deref().or(value.as_ref().map(NodeValue::as_slice)),
+					output,
+				)
+			},
+		}
+	}
+
+	/// Queue an encoded node for writing at the end of the commit, unless the commit filter
+	/// says otherwise.
+	fn write_node(&mut self, prefix: Prefix, encoded: &[u8]) -> TrieHash<L> {
+		let hash = L::Hash::hash(encoded);
+		let write = match &self.skip_existing {
+			Some(check) => match check(&hash, prefix) {
+				WriteDecision::WriteAnyway => {
+					self.commit_stats.written += 1;
+					true
+				},
+				WriteDecision::SkipWrite => {
+					self.commit_stats.already_present += 1;
+					false
+				},
+				WriteDecision::IncrementOnly => {
+					self.commit_stats.already_present += 1;
+					true
+				},
+			},
+			None => {
+				self.commit_stats.written += 1;
+				true
+			},
+		};
+		if write {
+			self.pending_writes.push((hash, (prefix.0.to_vec(), prefix.1), encoded.to_vec()));
+		}
+		hash
+	}
+
 	// a hack to get the root node's handle
 	fn root_handle(&self) -> NodeHandle<TrieHash<L>> {
 		match self.root_handle {
@@ -1502,7 +3643,233 @@ where
 	}
 }
 
-
+/// State of a `TrieDBMut::merge_from`.
+struct Merge<'o, 'db, L: TrieLayout> {
+	other: &'o TrieDB<'db, L>,
+	/// Subtrees of `other` under nodes of a different shape, with their position and hash.
+	conflicts: Vec<(NibbleVec<L::Nibble>, OwnedNode<DBValue, L::Nibble>, TrieHash<L>)>,
+	/// Number of pairs copied.
+	copied: u64,
+}
+
+/// The key and value slot of a node of a `TrieDB` at `path`, for a node with a value.
+fn source_pair<'n, N: NibbleOps>(
+	path: &NibbleVec<N>,
+	node: &'n OwnedNode<DBValue, N>,
+) -> Option<(Vec<u8>, &'n [u8])> {
+	let (partial, value) = match node.node() {
+		EncodedNode::Leaf(partial, value) => (Some(partial), value),
+		EncodedNode::Branch(_, Some(value)) => (None, value),
+		EncodedNode::NibbledBranch(partial, _, Some(value)) => (Some(partial), value),
+		_ => return None,
+	};
+	let mut key = path.clone();
+	if let Some(partial) = partial {
+		key.append_partial(partial.right());
+	}
+	Some((key.inner().to_vec(), value))
+}
+
+/// Iterator over the key-value pairs of a `TrieDBMut`, see `TrieDBMut::iter`.
+pub struct TrieDBMutIterator<'t, L: TrieLayout> {
+	storage: &'t NodeStorage<TrieHash<L>, L::Nibble>,
+	db: &'t dyn HashDB<L::Hash, DBValue>,
+	/// The root, until it is visited.
+	root: Option<IterChild<'t, TrieHash<L>, L::Nibble>>,
+	/// The nodes on the path to the next pair.
+	stack: Vec<IterFrame<'t, TrieHash<L>, L::Nibble>>,
+	/// The key of the node on top of the stack.
+	key: NibbleVec<L::Nibble>,
+}
+
+/// A child to visit: a node in memory, or an encoded node.
+enum IterChild<'t, H, N: NibbleOps> {
+	Memory(&'t Node<H, N>),
+	Hash(H),
+	/// An inline node, with the hash of the node holding it.
+	Inline(DBValue, H),
+}
+
+/// A node in memory, or decoded from the database with its hash.
+enum IterNode<'t, H, N: NibbleOps> {
+	Memory(&'t Node<H, N>),
+	Encoded(Box<OwnedNode<DBValue, N>>, H),
+}
+
+/// A node on the path of a `TrieDBMutIterator`.
+struct IterFrame<'t, H, N: NibbleOps> {
+	node: IterNode<'t, H, N>,
+	/// Length of the key before the node, the nibble leading to it included.
+	key_len: usize,
+	/// Next step of the visit: the value first, then the children in order.
+	step: usize,
+}
+
+/// What to do at a step of the visit of a node.
+enum IterStep<'t, H, N: NibbleOps> {
+	Value(DBValue),
+	Child(IterChild<'t, H, N>, Option<u8>),
+	Skip,
+	Done,
+}
+
+impl<'t, L: TrieLayout> TrieDBMutIterator<'t, L> {
+	/// The next step of the node on top of the stack.
+	fn step(&mut self) -> Result<IterStep<'t, TrieHash<L>, L::Nibble>, TrieHash<L>, CError<L>> {
+		let (storage, db, key) = (self.storage, self.db, &self.key);
+		let frame = self.stack.last_mut().expect("the stack is not empty");
+		let step = frame.step;
+		frame.step += 1;
+		let memory_child = |handle: &NodeHandle<TrieHash<L>>| match handle {
+			NodeHandle::Hash(hash) => IterChild::Hash(*hash),
+			NodeHandle::InMemory(handle) => IterChild::Memory(&storage[handle]),
+		};
+		Ok(match &frame.node {
+			IterNode::Memory(node) => match (node, step) {
+				(Node::Leaf(_, value), 0) => IterStep::Value(read_node_value(value, db)?),
+				(Node::Extension(_, child), 0) => IterStep::Child(memory_child(child), None),
+				(Node::Branch(_, value), 0) | (Node::NibbledBranch(_, _, value), 0) => match value {
+					Some(value) => IterStep::Value(read_node_value(value, db)?),
+					None => IterStep::Skip,
+				},
+				(Node::Branch(children, _), step) | (Node::NibbledBranch(_, children, _), step)
+					if (1..=children.len()).contains(&step) => match &children[step - 1] {
+					Some(child) => IterStep::Child(memory_child(child), Some(step as u8 - 1)),
+					None => IterStep::Skip,
+				},
+				_ => IterStep::Done,
+			},
+			IterNode::Encoded(encoded, hash) => {
+				let encoded_child = |handle: EncodedNodeHandle| match handle {
+					EncodedNodeHandle::Hash(data) => decode_hash::<L::Hash>(data)
+						.map(IterChild::Hash)
+						.ok_or_else(|| Box::new(TrieError::InvalidHash(*hash, data.to_vec()))),
+					EncodedNodeHandle::Inline(data) => Ok(IterChild::Inline(data.to_vec(), *hash)),
+				};
+				// the value nodes are at the prefix of their node, before its partial key.
+				let encoded_value = |slot: &[u8], partial_len: usize| {
+					match Value::from_slot::<L>(slot, *hash)? {
+						Value::Inline(value) => Ok(IterStep::Value(value.to_vec())),
+						Value::Node(_) | Value::Chunked(_) => {
+							let mut prefix = key.clone();
+							prefix.drop_lasts(partial_len);
+							let values = (true, L::MAX_VALUE_CHUNK, prefix.as_prefix());
+							read_value(slot, values, *hash, db)
+								.map(IterStep::Value)
+						},
+					}
+				};
+				match (encoded.node(), step) {
+					(EncodedNode::Leaf(partial, value), 0) => encoded_value(value, partial.len())?,
+					(EncodedNode::Extension(_, child), 0) => match encoded_child(child) {
+						Ok(child) => IterStep::Child(child, None),
+						Err(error) => return Err(error),
+					},
+					(EncodedNode::Branch(_, Some(value)), 0) => encoded_value(value, 0)?,
+					(EncodedNode::NibbledBranch(partial, _, Some(value)), 0) =>
+						encoded_value(value, partial.len())?,
+					(EncodedNode::Branch(_, None), 0)
+					| (EncodedNode::NibbledBranch(_, _, None), 0) => IterStep::Skip,
+					(EncodedNode::Branch(children, _), step)
+					| (EncodedNode::NibbledBranch(_, children, _), step)
+						if (1..=children.len()).contains(&step) =>
+						match children[step - 1] {
+							Some(child) => match encoded_child(child) {
+								Ok(child) => IterStep::Child(child, Some(step as u8 - 1)),
+								Err(error) => return Err(error),
+							},
+							None => IterStep::Skip,
+						},
+					_ => IterStep::Done,
+				}
+			},
+		})
+	}
+
+	/// Visit `child`, reached through `nibble` from the node on top of the stack.
+	fn push(
+		&mut self,
+		child: IterChild<'t, TrieHash<L>, L::Nibble>,
+		nibble: Option<u8>,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		let key_len = self.key.len();
+		if let Some(nibble) = nibble {
+			self.key.push(nibble);
+		}
+		let node = match child {
+			IterChild::Memory(node) => IterNode::Memory(node),
+			IterChild::Hash(hash) => {
+				let data = self.db.get(&hash, self.key.as_prefix());
+				let decoded = data
+					.ok_or_else(|| {
+						Box::new(TrieError::incomplete_database(hash, self.key.as_prefix()))
+					})
+					.and_then(|data| OwnedNode::new::<L::Codec>(data)
+						.map_err(|error| Box::new(TrieError::DecoderError(hash, error))));
+				match decoded {
+					Ok(node) => IterNode::Encoded(Box::new(node), hash),
+					Err(error) => {
+						self.key.drop_lasts(self.key.len() - key_len);
+						return Err(error);
+					},
+				}
+			},
+			IterChild::Inline(data, hash) => match OwnedNode::new::<L::Codec>(data) {
+				Ok(node) => IterNode::Encoded(Box::new(node), hash),
+				Err(error) => {
+					self.key.drop_lasts(self.key.len() - key_len);
+					return Err(Box::new(TrieError::DecoderError(hash, error)));
+				},
+			},
+		};
+		let partial = match &node {
+			IterNode::Memory(Node::Leaf(partial, _))
+			| IterNode::Memory(Node::Extension(partial, _))
+			| IterNode::Memory(Node::NibbledBranch(partial, _, _)) =>
+				Some(NibbleSlice::new_offset(&partial.1[..], partial.0)),
+			IterNode::Memory(_) => None,
+			IterNode::Encoded(encoded, _) => match encoded.node() {
+				EncodedNode::Leaf(partial, _)
+				| EncodedNode::Extension(partial, _)
+				| EncodedNode::NibbledBranch(partial, _, _) => Some(partial),
+				_ => None,
+			},
+		};
+		if let Some(partial) = partial {
+			self.key.append_partial(partial.right());
+		}
+		self.stack.push(IterFrame { node, key_len, step: 0 });
+		Ok(())
+	}
+}
+
+impl<'t, L: TrieLayout> Iterator for TrieDBMutIterator<'t, L> {
+	type Item = TrieItem<'t, TrieHash<L>, CError<L>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if let Some(root) = self.root.take() {
+			if let Err(error) = self.push(root, None) {
+				return Some(Err(error));
+			}
+		}
+		while !self.stack.is_empty() {
+			match self.step() {
+				Ok(IterStep::Value(value)) => return Some(Ok((self.key.inner().to_vec(), value))),
+				Ok(IterStep::Child(child, nibble)) => if let Err(error) = self.push(child, nibble) {
+					return Some(Err(error));
+				},
+				Ok(IterStep::Skip) => {},
+				Ok(IterStep::Done) => {
+					let frame = self.stack.pop().expect("the stack is not empty");
+					self.key.drop_lasts(self.key.len() - frame.key_len);
+				},
+				Err(error) => return Some(Err(error)),
+			}
+		}
+		None
+	}
+}
+
 impl<'a, L> TrieMut<L> for TrieDBMut<'a, L>
 where
 	L: TrieLayout,
@@ -1533,26 +3900,7 @@ where
 		key: &[u8],
 		value: &[u8],
 	) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
-		if value.is_empty() { return self.remove(key) }
-
-		let mut old_val = None;
-
-		#[cfg(feature = "std")]
-		trace!(target: "trie", "insert: key={:#x?}, value={:?}", key, ToHex(&value));
-
-		let root_handle = self.root_handle();
-		let (new_handle, _changed) = self.insert_at(
-			root_handle,
-			&mut NibbleSlice::new(key),
-			value.to_vec(),
-			&mut old_val,
-		)?;
-
-		#[cfg(feature = "std")]
-		trace!(target: "trie", "insert: altered trie={}", _changed);
-		self.root_handle = NodeHandle::InMemory(new_handle);
-
-		Ok(old_val)
+		self.insert_owned(key, value.to_vec())
 	}
 
 	fn remove(&mut self, key: &[u8]) -> Result<Option<DBValue>, TrieHash<L>, CError<L>> {
@@ -1573,11 +3921,10 @@ where
 				#[cfg(feature = "std")]
 				trace!(target: "trie", "remove: obliterated trie");
 				self.root_handle = NodeHandle::Hash(L::Codec::hashed_null_node());
-				*self.root = L::Codec::hashed_null_node();
 			}
 		}
 
-		Ok(old_val)
+		old_val.map(|value| read_node_value(&value, &*self.db)).transpose()
 	}
 }
 
@@ -1591,14 +3938,38 @@ where
 }
 
 /// combine two NodeKeys
-fn combine_key(start: &mut NodeKey, end: (usize, &[u8])) {
-	debug_assert!(start.0 < nibble_ops::NIBBLE_PER_BYTE);
-	debug_assert!(end.0 < nibble_ops::NIBBLE_PER_BYTE);
-	let final_offset = (start.0 + end.0) % nibble_ops::NIBBLE_PER_BYTE;
-	let _shifted = nibble_ops::shift_key(start, final_offset);
+/// Prefix of the children of the node at `key` with partial `partial`, followed by the
+/// child `index` for branches.
+fn child_prefix<N: NibbleOps>(
+	key: &NibbleSlice<N>,
+	partial: &NodeKey,
+	index: Option<u8>,
+) -> (BackingByteVec, Option<u8>) {
+	let mut path = left_nibbles(key);
+	path.append_partial(NibbleSlice::<N>::from_stored(partial).right());
+	if let Some(index) = index {
+		path.push(index);
+	}
+	let (start, last) = path.as_prefix();
+	(start.into(), last)
+}
+
+/// The nibbles before `key`, that is the prefix of the node at `key`.
+fn left_nibbles<N: NibbleOps>(key: &NibbleSlice<N>) -> NibbleVec<N> {
+	let whole = key.back(0);
+	let mut path = NibbleVec::from(whole);
+	path.truncate(whole.len() - key.len());
+	path
+}
+
+fn combine_key<N: NibbleOps>(start: &mut NodeKey, end: (usize, &[u8])) {
+	debug_assert!(start.0 < N::NIBBLE_PER_BYTE);
+	debug_assert!(end.0 < N::NIBBLE_PER_BYTE);
+	let final_offset = (start.0 + end.0) % N::NIBBLE_PER_BYTE;
+	let _shifted = N::shift_key(start, final_offset);
 	let st = if end.0 > 0 {
 		let sl = start.1.len();
-		start.1[sl - 1] |= nibble_ops::pad_right(end.1[0]);
+		start.1[sl - 1] |= N::pad_right(N::NIBBLE_PER_BYTE - end.0, end.1[0]);
 		1
 	} else {
 		0
@@ -1613,10 +3984,14 @@ mod tests {
 	use log::debug;
 	use crate::DBValue;
 	use memory_db::{MemoryDB, PrefixedKey};
-	use hash_db::{Hasher, HashDB};
+	use hash_db::{AsHashDB, Hasher, HashDB, OwnedPrefix, Prefix, EMPTY_PREFIX};
 	use keccak_hasher::KeccakHasher;
-	use reference_trie::{RefTrieDBMutNoExt, RefTrieDBMut, TrieMut, NodeCodec,
-		ReferenceNodeCodec, reference_trie_root, reference_trie_root_no_extension};
+	use reference_trie::{RefTrieDBMutNoExt, RefTrieDBMut, TrieMut, NodeCodec, WriteDecision,
+		ReferenceNodeCodec, reference_trie_root, reference_trie_root_no_extension, RemovalInfo,
+		ExtensionLayout, NoExtensionLayout, RefTrieDBNoExt, Trie, TrieDB, TrieDBMut,
+		TrieDBNodeIterator, TrieError, TrieLayout};
+	use reference_trie::node::{Node, NodeHandle};
+	use reference_trie::{Children, NibbleSlice, Radix16};
 	use crate::nibble::BackingByteVec;
 
 	fn populate_trie<'db>(
@@ -2068,13 +4443,162 @@ mod tests {
 		}
 	}
 
+	fn check_modify<L: TrieLayout<Hash = KeccakHasher>>() {
+		let mut seed = Default::default();
+		let map = |count| StandardMap {
+			alphabet: Alphabet::Custom(b"abc".to_vec()),
+			min_key: 3,
+			journal_key: 2,
+			value_mode: ValueMode::Index,
+			count,
+		};
+		let x = unique_entries(map(60), &mut seed);
+		// present keys, then mostly absent ones.
+		let keys: Vec<_> = x.iter().map(|(key, _)| key.clone())
+			.chain(unique_entries(map(40), &mut seed).into_iter().map(|(key, _)| key))
+			.collect();
+		// remove, append to, keep or set the value.
+		let change = |op: usize, old: Option<DBValue>| match op % 4 {
+			0 => None,
+			1 => old.map(|mut value| {
+				value.push(op as u8);
+				value
+			}),
+			2 => old,
+			_ => Some(vec![op as u8; 1 + op % 40]),
+		};
+
+		let (db, root) = build_trie::<L>(&x);
+		let (mut modified_db, mut modified_root) = (db.clone(), root);
+		let (mut expected_db, mut expected_root) = (db.clone(), root);
+		{
+			let mut modified = TrieDBMut::<L>::from_existing(&mut modified_db, &mut modified_root).unwrap();
+			let mut expected = TrieDBMut::<L>::from_existing(&mut expected_db, &mut expected_root).unwrap();
+			for (op, key) in keys.iter().enumerate() {
+				modified.modify(key, |old| change(op, old)).unwrap();
+				let old = expected.get(key).unwrap();
+				let existed = old.is_some();
+				match change(op, old) {
+					Some(value) => { expected.insert(key, &value).unwrap(); },
+					None if existed => { expected.remove(key).unwrap(); },
+					None => {},
+				}
+				assert_eq!(modified.get(key).unwrap(), expected.get(key).unwrap());
+				if op % 10 == 9 {
+					assert_eq!(modified.root(), expected.root());
+				}
+			}
+		}
+		assert_eq!(modified_root, expected_root);
+		assert_eq!(modified_db.keys(), expected_db.keys());
+	}
+
+	#[test]
+	fn modify_matches_get_then_insert_or_remove() {
+		check_modify::<ExtensionLayout>();
+		check_modify::<NoExtensionLayout>();
+	}
+
+	fn check_apply<L: TrieLayout<Hash = KeccakHasher>>(seed: u64) {
+		use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+		let mut rng = SmallRng::seed_from_u64(seed);
+		// short keys over few bytes, for branch values and shared partials.
+		let random_key = |rng: &mut SmallRng| -> Vec<u8> {
+			let len = rng.gen_range(0, 4);
+			(0..len).map(|_| [0x00, 0x01, 0x10, 0xf1][rng.gen_range(0, 4)]).collect()
+		};
+		let entries: Vec<_> = (0..rng.gen_range(0, 40))
+			.map(|i| (random_key(&mut rng), vec![i as u8; 1 + i % 40]))
+			.collect();
+		// inserts, removals and empty values, some of the keys changed more than once.
+		let changes: Vec<(Vec<u8>, Option<DBValue>)> = (0..rng.gen_range(0, 60))
+			.map(|i| {
+				let value = match rng.gen_range(0, 4) {
+					0 => None,
+					1 => Some(Vec::new()),
+					_ => Some(vec![0xff; 1 + i % 40]),
+				};
+				(random_key(&mut rng), value)
+			})
+			.collect();
+
+		let (db, root) = build_trie::<L>(&entries);
+		let (mut applied_db, mut applied_root) = (db.clone(), root);
+		let (mut expected_db, mut expected_root) = (db, root);
+		{
+			let mut applied = TrieDBMut::<L>::from_existing(&mut applied_db, &mut applied_root).unwrap();
+			applied.apply(changes.clone()).unwrap();
+			let mut expected = TrieDBMut::<L>::from_existing(&mut expected_db, &mut expected_root).unwrap();
+			for (key, value) in changes.iter() {
+				match value {
+					Some(value) => expected.insert(key, value).unwrap(),
+					None => expected.remove(key).unwrap(),
+				};
+			}
+			for key in entries.iter().map(|(key, _)| key).chain(changes.iter().map(|(key, _)| key)) {
+				assert_eq!(applied.get(key).unwrap(), expected.get(key).unwrap());
+			}
+		}
+		assert_eq!(applied_root, expected_root, "seed {}", seed);
+		assert_eq!(applied_db.keys(), expected_db.keys(), "seed {}", seed);
+	}
+
+	#[test]
+	fn apply_matches_sequential_changes() {
+		for seed in 0..500 {
+			check_apply::<ExtensionLayout>(seed);
+			check_apply::<NoExtensionLayout>(seed);
+		}
+	}
+
+	#[test]
+	fn old_values_match_a_prior_get() {
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		// branch values and leaves, stored in the database before the changes.
+		let keys: Vec<&[u8]> = vec![b"do", b"dog", b"doge", b"horse", b"house"];
+		populate_trie(&mut db, &mut root, &keys.iter()
+			.map(|key| (key.to_vec(), [*key, &[0xff; 32]].concat()))
+			.collect::<Vec<_>>()
+		).commit();
+
+		let mut t = RefTrieDBMut::from_existing(&mut db, &mut root).unwrap();
+		// overwritten and fresh keys, then removed ones.
+		for key in [&b"dog"[..], b"dot", b"do", b"d", b"horses", b"house"] {
+			let before = t.get(key).unwrap();
+			assert_eq!(t.insert(key, &[0x11; 40]).unwrap(), before, "{:?}", key);
+		}
+		for key in [&b"doge"[..], b"dog", b"dot", b"cat", b"horse", b"horse"] {
+			let before = t.get(key).unwrap();
+			assert_eq!(t.remove(key).unwrap(), before, "{:?}", key);
+		}
+	}
+
+	#[test]
+	fn remove_key_ending_in_branch_partial() {
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMutNoExt::new(&mut db, &mut root);
+		// a branch with partial `f1` under `0xf10f`, holding the value of `0xf10ff1`.
+		t.insert(&[0xf1, 0x00], &[1; 32]).unwrap();
+		t.insert(&[0xf1, 0x0f, 0xf1], &[2; 32]).unwrap();
+		t.insert(&[0xf1, 0x0f, 0xf1, 0x0f], &[3; 32]).unwrap();
+		let root_before = *t.root();
+		assert_eq!(t.remove(&[0xf1, 0x0f]).unwrap(), None);
+		assert_eq!(*t.root(), root_before);
+		// the nodes on the path are now held in memory.
+		assert_eq!(t.get(&[0xf1, 0x0f, 0xf1]).unwrap(), Some(vec![2; 32]));
+		assert_eq!(t.get(&[0xf1, 0x0f]).unwrap(), None);
+	}
+
 	#[test]
 	fn combine_test() {
 		let a: BackingByteVec = [0x12, 0x34][..].into();
 		let b: &[u8] = [0x56, 0x78][..].into();
 		let test_comb = |a: (_, &BackingByteVec), b, c| {
 			let mut a = (a.0, a.1.clone());
-			super::combine_key(&mut a, b);
+			super::combine_key::<crate::nibble::Radix16>(&mut a, b);
 			assert_eq!((a.0, &a.1[..]), c);
 		};
 		test_comb((0, &a), (0, &b), (0, &[0x12, 0x34, 0x56, 0x78][..]));
@@ -2083,11 +4607,698 @@ mod tests {
 		test_comb((1, &a), (1, &b), (0, &[0x23, 0x46, 0x78][..]));
 	}
 
+	/// Backend recording the hashes of the nodes written to it, and the number of batches.
+	#[derive(Default)]
+	struct RecordingDB {
+		db: MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>,
+		writes: Vec<<KeccakHasher as Hasher>::Out>,
+		batches: usize,
+		removal_batches: usize,
+	}
+
+	impl HashDB<KeccakHasher, DBValue> for RecordingDB {
+		fn get(&self, key: &<KeccakHasher as Hasher>::Out, prefix: Prefix) -> Option<DBValue> {
+			self.db.get(key, prefix)
+		}
+
+		fn contains(&self, key: &<KeccakHasher as Hasher>::Out, prefix: Prefix) -> bool {
+			self.db.contains(key, prefix)
+		}
+
+		fn insert(&mut self, prefix: Prefix, value: &[u8]) -> <KeccakHasher as Hasher>::Out {
+			let hash = self.db.insert(prefix, value);
+			self.writes.push(hash);
+			hash
+		}
+
+		fn emplace(&mut self, key: <KeccakHasher as Hasher>::Out, prefix: Prefix, value: DBValue) {
+			self.writes.push(key);
+			self.db.emplace(key, prefix, value)
+		}
+
+		fn remove(&mut self, key: &<KeccakHasher as Hasher>::Out, prefix: Prefix) {
+			self.db.remove(key, prefix)
+		}
+
+		fn emplace_batch(&mut self, items: Vec<(<KeccakHasher as Hasher>::Out, OwnedPrefix, DBValue)>) {
+			self.batches += 1;
+			for (key, prefix, value) in items {
+				self.emplace(key, (&prefix.0[..], prefix.1), value);
+			}
+		}
+
+		fn remove_batch(&mut self, items: Vec<(<KeccakHasher as Hasher>::Out, OwnedPrefix)>) {
+			self.removal_batches += 1;
+			for (key, prefix) in items {
+				self.remove(&key, (&prefix.0[..], prefix.1));
+			}
+		}
+	}
+
+	impl AsHashDB<KeccakHasher, DBValue> for RecordingDB {
+		fn as_hash_db(&self) -> &dyn HashDB<KeccakHasher, DBValue> { self }
+		fn as_hash_db_mut<'a>(&'a mut self) -> &'a mut (dyn HashDB<KeccakHasher, DBValue> + 'a) {
+			self
+		}
+	}
+
+	#[test]
+	fn committed_root_tracks_pending_changes() {
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut memdb, &mut root);
+		// freshly constructed empty trie.
+		assert_eq!(t.committed_root(), Some(&reference_hashed_null_node()));
+
+		t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]).unwrap();
+		assert_eq!(t.committed_root(), None);
+
+		let committed = *t.root();
+		assert_eq!(t.committed_root(), Some(&committed));
+		// lookups do not make the trie dirty.
+		assert_eq!(t.get(&[0x01u8, 0x23]).unwrap(), Some(vec![0x01u8, 0x23]));
+		assert_eq!(t.committed_root(), Some(&committed));
+
+		// removing the only key empties the root, but the removal is not committed yet.
+		t.remove(&[0x01u8, 0x23]).unwrap();
+		assert_eq!(t.committed_root(), None);
+		assert_eq!(t.root(), &reference_hashed_null_node());
+		assert_eq!(t.committed_root(), Some(&reference_hashed_null_node()));
+	}
+
+	fn check_root_preview<L: TrieLayout<Hash = KeccakHasher>>() {
+		let mut seed = Default::default();
+		let x = unique_entries(StandardMap {
+			alphabet: Alphabet::Custom(b"abcd".to_vec()),
+			min_key: 2,
+			journal_key: 3,
+			value_mode: ValueMode::Mirror,
+			count: 200,
+		}, &mut seed);
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		TrieDBMut::<L>::new(&mut db, &mut root).apply(
+			x.iter().step_by(2).map(|(key, value)| (key.clone(), Some(value.clone())))
+		).unwrap();
+		let (committed_db, committed_root) = (db.clone(), root);
+
+		// the odd keys in, then the even keys out until the trie is empty.
+		let changes = x.iter().skip(1).step_by(2).map(|(key, value)| (key, Some(value)))
+			.chain(x.iter().map(|(key, _)| (key, None)));
+		let mut t = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap();
+		for (i, (key, value)) in changes.enumerate() {
+			match value {
+				Some(value) => t.insert(key, value).unwrap(),
+				None => t.remove(key).unwrap(),
+			};
+			if i % 10 == 0 {
+				let preview = t.root_preview();
+				assert_eq!(t.root_preview(), preview);
+				assert_eq!(t.committed_root(), None);
+				assert!(!t.db().contains(&preview, EMPTY_PREFIX) || preview == committed_root);
+			}
+		}
+		assert_eq!(t.root_preview(), reference_hashed_null_node());
+		t.discard();
+		assert_eq!(t.committed_root(), Some(&committed_root));
+		drop(t);
+		assert!(db == committed_db);
+		assert_eq!(root, committed_root);
+
+		let mut t = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap();
+		for (key, value) in x.iter().skip(1).step_by(2) {
+			t.insert(key, value).unwrap();
+		}
+		let preview = t.root_preview();
+		assert_eq!(*t.root(), preview);
+		assert_eq!(t.root_preview(), preview);
+	}
+
+	#[test]
+	fn root_preview_writes_nothing() {
+		check_root_preview::<ExtensionLayout>();
+		check_root_preview::<NoExtensionLayout>();
+	}
+
+	#[test]
+	fn discard_drops_pending_changes() {
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut db, &mut root);
+		t.insert(b"dog", &[1; 33]).unwrap();
+		t.insert(b"doge", &[2; 33]).unwrap();
+		let committed = *t.root();
+
+		t.insert(b"dog", &[3; 33]).unwrap();
+		t.remove(b"doge").unwrap();
+		t.insert(b"horse", &[4; 33]).unwrap();
+		t.discard();
+		assert_eq!(t.committed_root(), Some(&committed));
+		assert_eq!(t.get(b"dog").unwrap(), Some(vec![1; 33]));
+		assert_eq!(t.get(b"doge").unwrap(), Some(vec![2; 33]));
+		assert_eq!(t.get(b"horse").unwrap(), None);
+
+		// emptying the trie and discarding it.
+		t.clear_prefix(b"", true).unwrap();
+		assert!(t.is_empty());
+		t.discard();
+		assert_eq!(t.get(b"doge").unwrap(), Some(vec![2; 33]));
+		assert_eq!(*t.root(), committed);
+	}
+
+	#[test]
+	fn commit_skips_existing_nodes() {
+		let mut seed = Default::default();
+		let x = StandardMap {
+			alphabet: Alphabet::Custom(b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_".to_vec()),
+			min_key: 5,
+			journal_key: 0,
+			value_mode: ValueMode::Index,
+			count: 100,
+		}.make_with(&mut seed);
+
+		let mut archive = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut archive_root = Default::default();
+		populate_trie(&mut archive, &mut archive_root, &x).commit();
+
+		let mut y = x.clone();
+		y.push((b"new key".to_vec(), b"new value".to_vec()));
+
+		let mut db = RecordingDB::default();
+		let mut root = Default::default();
+		let stats = {
+			let mut t = RefTrieDBMut::new(&mut db, &mut root);
+			t.set_skip_existing(Box::new(|hash: &_, prefix| if archive.contains(hash, prefix) {
+				WriteDecision::SkipWrite
+			} else {
+				WriteDecision::WriteAnyway
+			}));
+			for (key, value) in y.iter() {
+				t.insert(key, value).unwrap();
+			}
+			t.commit();
+			t.commit_stats().clone()
+		};
+		assert_eq!(root, reference_trie_root(y));
+		assert!(stats.already_present > 0);
+		assert!(stats.written > 0);
+		assert_eq!(stats.written, db.writes.len());
+		for hash in db.writes.iter() {
+			assert!(archive.keys().keys().all(|k| &k[k.len() - 32..] != hash.as_ref()));
+		}
+	}
+
+	#[test]
+	fn commit_increment_only_existing_nodes() {
+		let x = vec![
+			(vec![0x01u8, 0x23], vec![0x01u8; 32]),
+			(vec![0x01u8, 0x81], vec![0x02u8; 32]),
+			(vec![0x81u8, 0x23], vec![0x03u8; 32]),
+		];
+		let mut db = RecordingDB::default();
+		let mut root = Default::default();
+		populate_trie(&mut db, &mut root, &x).commit();
+		let written = db.writes.len();
+
+		let mut root2 = Default::default();
+		{
+			let existing = db.db.clone();
+			let mut t = RefTrieDBMut::new(&mut db, &mut root2);
+			t.set_skip_existing(Box::new(move |hash: &_, prefix| {
+				if existing.contains(hash, prefix) {
+					WriteDecision::IncrementOnly
+				} else {
+					WriteDecision::WriteAnyway
+				}
+			}));
+			for (key, value) in x.iter() {
+				t.insert(key, value).unwrap();
+			}
+			t.commit();
+			assert_eq!(t.commit_stats().written, 0);
+			assert_eq!(t.commit_stats().already_present, written);
+		}
+		assert_eq!(root, root2);
+		assert_eq!(db.db.raw(&root, EMPTY_PREFIX).unwrap().1, 2);
+	}
+
+	#[test]
+	fn commit_writes_in_one_batch() {
+		let mut seed = Default::default();
+		let x = StandardMap {
+			alphabet: Alphabet::Low,
+			min_key: 5,
+			journal_key: 0,
+			value_mode: ValueMode::Random,
+			count: 100,
+		}.make_with(&mut seed);
+		let update = |t: &mut RefTrieDBMut| {
+			t.commit();
+			// nothing left to write.
+			t.commit();
+			for (key, _) in x.iter().take(10) {
+				t.remove(key).unwrap();
+			}
+			t.insert(b"new key", &[0xff; 32]).unwrap();
+			t.commit();
+		};
+
+		let mut db = RecordingDB::default();
+		let mut root = Default::default();
+		update(&mut populate_trie(&mut db, &mut root, &x));
+		// the first commit also removes the replaced empty root.
+		assert_eq!((db.batches, db.removal_batches), (2, 2));
+
+		// the batches of `MemoryDB` give the same content as the writes one by one.
+		let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut memdb_root = Default::default();
+		update(&mut populate_trie(&mut memdb, &mut memdb_root, &x));
+		assert_eq!(memdb_root, root);
+		assert_eq!(memdb.drain(), db.db.drain());
+	}
+
+	type HashedMemoryDB = MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+
+	fn build_trie<L: TrieLayout<Hash = KeccakHasher>>(
+		x: &[(Vec<u8>, Vec<u8>)],
+	) -> (HashedMemoryDB, <KeccakHasher as Hasher>::Out) {
+		let mut db = HashedMemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+			for (key, value) in x {
+				t.insert(key, value).unwrap();
+			}
+		}
+		(db, root)
+	}
+
+	fn node_count<L: TrieLayout<Hash = KeccakHasher>>(db: &HashedMemoryDB, root: &<KeccakHasher as Hasher>::Out) -> u32 {
+		let trie = TrieDB::<L>::new(db, root).unwrap();
+		TrieDBNodeIterator::new(&trie).unwrap()
+			.filter(|item| !matches!(item.as_ref().unwrap().2.node(), Node::Empty))
+			.count() as u32
+	}
+
+	/// Entries of a standard map, keeping the last value of duplicated keys.
+	fn unique_entries(map: StandardMap, seed: &mut <KeccakHasher as Hasher>::Out) -> Vec<(Vec<u8>, Vec<u8>)> {
+		map.make_with(seed).into_iter().collect::<std::collections::BTreeMap<_, _>>().into_iter().collect()
+	}
+
+	fn check_removal_info<L: TrieLayout<Hash = KeccakHasher>>(x: &[(Vec<u8>, Vec<u8>)], prefix: &[u8]) {
+		let (removed, kept): (Vec<_>, Vec<_>) = x.iter().cloned()
+			.partition(|(key, _)| key.starts_with(prefix));
+		let value_bytes: u64 = removed.iter().map(|(_, value)| value.len() as u64).sum();
+		let (db, root) = build_trie::<L>(x);
+		let (expected_db, expected_root) = build_trie::<L>(&kept);
+		let nodes_removed = node_count::<L>(&db, &root) - node_count::<L>(&expected_db, &expected_root);
+
+		let check = |info: RemovalInfo| {
+			assert_eq!(info.keys, removed.len() as u32);
+			assert_eq!(info.value_bytes, value_bytes);
+			assert_eq!(info.nodes_removed, nodes_removed);
+		};
+
+		// detach the subtree from a committed trie, measuring it.
+		let mut cleared_db = db.clone();
+		let mut cleared_root = root;
+		{
+			let mut t = TrieDBMut::<L>::from_existing(&mut cleared_db, &mut cleared_root).unwrap();
+			check(t.clear_prefix(prefix, true).unwrap());
+		}
+		assert_eq!(cleared_root, expected_root);
+		assert_eq!(cleared_db.keys(), expected_db.keys());
+
+		// remove the keys one by one.
+		let mut batch_db = db.clone();
+		let mut batch_root = root;
+		{
+			let mut t = TrieDBMut::<L>::from_existing(&mut batch_db, &mut batch_root).unwrap();
+			check(t.remove_batch(removed.iter().map(|(key, _)| key)).unwrap());
+		}
+		assert_eq!(batch_root, expected_root);
+		assert_eq!(batch_db.keys(), expected_db.keys());
+
+		// without measuring, only the in-memory nodes are counted.
+		let mut db = HashedMemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+			for (key, value) in x {
+				t.insert(key, value).unwrap();
+			}
+			check(t.clear_prefix(prefix, false).unwrap());
+		}
+		assert_eq!(root, expected_root);
+		let mut lazy_db = HashedMemoryDB::default();
+		let mut lazy_root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut lazy_db, &mut lazy_root);
+			for (key, value) in x {
+				t.insert(key, value).unwrap();
+			}
+			t.commit();
+			let info = t.clear_prefix(prefix, false).unwrap();
+			assert!(info.keys <= removed.len() as u32);
+			assert!(info.value_bytes <= value_bytes);
+		}
+		assert_eq!(lazy_root, expected_root);
+	}
+
+	#[test]
+	fn removals_report_freed_sizes() {
+		let mut seed = Default::default();
+		for _ in 0..20 {
+			let x = unique_entries(StandardMap {
+				alphabet: Alphabet::Custom(b"abc".to_vec()),
+				min_key: 3,
+				journal_key: 2,
+				value_mode: ValueMode::Index,
+				count: 60,
+			}, &mut seed);
+			for prefix in [&b""[..], b"a", b"ab", b"abc", b"b\x00", b"z"].iter() {
+				check_removal_info::<ExtensionLayout>(&x, prefix);
+				check_removal_info::<NoExtensionLayout>(&x, prefix);
+			}
+		}
+	}
+
+	#[test]
+	fn clear_prefix_splits_partial_keys() {
+		let x: Vec<(Vec<u8>, Vec<u8>)> = vec![
+			(b"abcd1".to_vec(), vec![1; 40]),
+			(b"abcd2".to_vec(), vec![2; 40]),
+			(b"abcd2x".to_vec(), vec![3; 40]),
+			(b"ax".to_vec(), vec![4; 40]),
+			(b"b".to_vec(), vec![5; 40]),
+		];
+		// prefixes ending inside the partial key shared by the `abcd` keys and inside a leaf,
+		// and prefixes leaving a branch with a single child, merged with it.
+		let prefixes: [&[u8]; 9] = [b"", b"a", b"ab", b"abc", b"abcd2", b"abcd2x", b"ax", b"b", b"c"];
+		for prefix in prefixes.iter() {
+			check_removal_info::<ExtensionLayout>(&x, prefix);
+			check_removal_info::<NoExtensionLayout>(&x, prefix);
+		}
+	}
+
+	#[test]
+	fn clear_prefix_diverging_in_branch_partial() {
+		let x: Vec<(Vec<u8>, Vec<u8>)> = vec![
+			(vec![0xab, 0xcd, 0x01], vec![1; 40]),
+			(vec![0xab, 0xcd, 0x02], vec![2; 40]),
+		];
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		let mut t = populate_trie_no_extension(&mut db, &mut root, &x);
+		t.commit();
+		let before = *t.root();
+		// the prefix leaves the partial key of the root branch before its end.
+		let info = t.clear_prefix(&[0xaf], false).unwrap();
+		assert_eq!(info, RemovalInfo::default());
+		assert_eq!(*t.root(), before);
+
+		let prefixes: [&[u8]; 4] = [&[0xaf], &[0xab, 0xce], &[0xab, 0xcd, 0x03], &[0xb0]];
+		for prefix in prefixes.iter() {
+			check_removal_info::<ExtensionLayout>(&x, prefix);
+			check_removal_info::<NoExtensionLayout>(&x, prefix);
+		}
+	}
+
+	#[test]
+	fn remove_with_info_reports_value() {
+		let mut db = HashedMemoryDB::default();
+		let mut root = Default::default();
+		let mut t = RefTrieDBMut::new(&mut db, &mut root);
+		t.insert(b"dog", &[1; 40]).unwrap();
+		t.insert(b"doge", &[2; 40]).unwrap();
+		t.commit();
+		let (value, info) = t.remove_with_info(b"doge").unwrap();
+		assert_eq!(value, Some(vec![2; 40]));
+		// the leaf is removed, and the extension gets merged with the branch turned into a leaf.
+		assert_eq!(info, RemovalInfo { keys: 1, value_bytes: 40, nodes_removed: 2 });
+		let (value, info) = t.remove_with_info(b"cat").unwrap();
+		assert_eq!(value, None);
+		assert_eq!(info, RemovalInfo::default());
+	}
+
+	#[test]
+	fn clear_prefix_on_prefixed_db() {
+		let mut seed = Default::default();
+		let x = unique_entries(StandardMap {
+			alphabet: Alphabet::Custom(b"abc".to_vec()),
+			min_key: 3,
+			journal_key: 2,
+			value_mode: ValueMode::Random,
+			count: 100,
+		}, &mut seed);
+		let kept: Vec<_> = x.iter().filter(|(key, _)| !key.starts_with(b"ab")).cloned().collect();
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut root = Default::default();
+		populate_trie_no_extension(&mut db, &mut root, &x).commit();
+		{
+			let mut t = RefTrieDBMutNoExt::from_existing(&mut db, &mut root).unwrap();
+			t.clear_prefix(b"ab", true).unwrap();
+		}
+		assert_eq!(root, reference_trie_root_no_extension(kept.clone()));
+		let trie = RefTrieDBNoExt::new(&db, &root).unwrap();
+		let pairs: Vec<_> = trie.iter().unwrap().map(|item| item.unwrap()).collect();
+		assert_eq!(pairs, kept);
+	}
+
+	#[test]
+	fn constructors_check_the_root() {
+		let mut db = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::default();
+		let mut valid = Default::default();
+		populate_trie(&mut db, &mut valid, &[(b"dog".to_vec(), b"puppy".to_vec())]).commit();
+		// A node stored at the default hash, which an uninitialized root must not build on.
+		let zeroed = <KeccakHasher as Hasher>::Out::default();
+		db.emplace(zeroed, EMPTY_PREFIX, b"not a node".to_vec());
+		let empty = reference_hashed_null_node();
+		let bogus = KeccakHasher::hash(b"bogus");
+
+		fn invalid_root<T, E: std::fmt::Debug>(result: Result<T, Box<TrieError<[u8; 32], E>>>) -> [u8; 32] {
+			match result.map(|_| ()).map_err(|error| *error) {
+				Err(TrieError::InvalidStateRoot(root)) => root,
+				result => panic!("unexpected result: {:?}", result),
+			}
+		}
+
+		for root in [zeroed, empty, valid, bogus] {
+			let mut root = root;
+			assert!(RefTrieDBMut::new(&mut db, &mut root).is_empty());
+			assert_eq!(root, empty);
+		}
+
+		let mut root = zeroed;
+		assert_eq!(invalid_root(RefTrieDBMut::from_existing(&mut db, &mut root)), zeroed);
+		let mut root = empty;
+		assert!(RefTrieDBMut::from_existing(&mut db, &mut root).unwrap().is_empty());
+		let mut root = valid;
+		let t = RefTrieDBMut::from_existing(&mut db, &mut root).unwrap();
+		assert_eq!(t.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+		drop(t);
+		let mut root = bogus;
+		assert_eq!(invalid_root(RefTrieDBMut::from_existing(&mut db, &mut root)), bogus);
+
+		for root in [zeroed, empty] {
+			let mut root = root;
+			assert!(RefTrieDBMut::new_or_existing(&mut db, &mut root).unwrap().is_empty());
+			assert_eq!(root, empty);
+		}
+		let mut root = valid;
+		let t = RefTrieDBMut::new_or_existing(&mut db, &mut root).unwrap();
+		assert_eq!(t.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+		drop(t);
+		let mut root = bogus;
+		assert_eq!(invalid_root(RefTrieDBMut::new_or_existing(&mut db, &mut root)), bogus);
+	}
+
 	#[test]
 	fn nice_debug_for_node() {
 		use super::Node;
-		let e: Node<u32> = Node::Leaf((1, vec![1, 2, 3].into()), vec![4, 5, 6]);
+		let e: Node<u32, crate::nibble::Radix16> =
+			Node::Leaf((1, vec![1, 2, 3].into()), vec![4, 5, 6].into());
 		assert_eq!(format!("{:?}", e), "Leaf((1, 010203), 040506)");
 	}
 
+	/// Store the encoding of `node` in `db` unless it is inline, returning the reference to it.
+	fn store_node<L>(db: &mut HashedMemoryDB, node: Node) -> Vec<u8>
+		where L: TrieLayout<Hash = KeccakHasher, Nibble = Radix16>
+	{
+		let encoded = reference_trie::node::encode_node::<L>(&node).unwrap();
+		match encoded.len() {
+			len if len < 32 => encoded,
+			_ => db.insert(EMPTY_PREFIX, &encoded).as_ref().to_vec(),
+		}
+	}
+
+	/// The reference to a child, hashes being 32 bytes long.
+	fn child(reference: &[u8]) -> NodeHandle<'_> {
+		match reference.len() {
+			32 => NodeHandle::Hash(reference),
+			_ => NodeHandle::Inline(reference),
+		}
+	}
+
+	/// The children of a branch with the given references.
+	fn branch_children<'a>(children: &[(usize, &'a [u8])]) -> Children<NodeHandle<'a>> {
+		let mut handles = Children::new();
+		for (index, reference) in children {
+			handles[*index] = Some(child(reference));
+		}
+		handles
+	}
+
+	/// A single nibble, as a partial key.
+	fn nibble(nibble: &'static [u8; 1]) -> NibbleSlice<'static> {
+		NibbleSlice::new_offset(nibble, 1)
+	}
+
+	/// A trie with the extension layout whose nodes are not canonical.
+	fn denormalized_trie_with_ext() -> (HashedMemoryDB, <KeccakHasher as Hasher>::Out) {
+		type L = ExtensionLayout;
+		let mut memdb = HashedMemoryDB::default();
+		let db = &mut memdb;
+		let values: Vec<Vec<u8>> = (1..8).map(|i| vec![i; 32]).collect();
+
+		// a branch with a single child and no value, at 0x15.
+		let leaf_0 = store_node::<L>(db, Node::Leaf(nibble(&[0x07]), &values[0]));
+		let leaf_3 = store_node::<L>(db, Node::Leaf(nibble(&[0x08]), &values[1]));
+		let inner = branch_children(&[(0, &leaf_0), (3, &leaf_3)]);
+		let inner = store_node::<L>(db, Node::Branch(inner, None));
+		let single = store_node::<L>(db, Node::Branch(branch_children(&[(5, &inner)]), None));
+		// a chain of two extensions, at 0x2.
+		let leaf_0 = store_node::<L>(db, Node::Leaf(NibbleSlice::new(&[0x56]), &values[2]));
+		let leaf_1 = store_node::<L>(db, Node::Leaf(NibbleSlice::new(&[0x78]), &values[3]));
+		let inner = branch_children(&[(0, &leaf_0), (1, &leaf_1)]);
+		let inner = store_node::<L>(db, Node::Branch(inner, None));
+		let lower = store_node::<L>(db, Node::Extension(nibble(&[0x04]), child(&inner)));
+		let chain = store_node::<L>(db, Node::Extension(nibble(&[0x03]), child(&lower)));
+		// an extension to a branch holding only a value, at 0x3.
+		let valued = store_node::<L>(db, Node::Branch(branch_children(&[]), Some(&values[4])));
+		let to_valued = store_node::<L>(db, Node::Extension(nibble(&[0x04]), child(&valued)));
+		// an extension to a leaf, at 0x4.
+		let leaf = store_node::<L>(db, Node::Leaf(NibbleSlice::new(&[0x67]), &values[5]));
+		let to_leaf = store_node::<L>(db, Node::Extension(nibble(&[0x05]), child(&leaf)));
+		// an empty branch, at 0x5, and a canonical leaf, at 0x6.
+		let empty = store_node::<L>(db, Node::Branch(branch_children(&[]), None));
+		let canonical = store_node::<L>(db, Node::Leaf(nibble(&[0x00]), &values[6]));
+
+		let children = branch_children(&[
+			(1, &single), (2, &chain), (3, &to_valued), (4, &to_leaf), (5, &empty), (6, &canonical),
+		]);
+		let encoded = reference_trie::node::encode_node::<L>(&Node::Branch(children, None)).unwrap();
+		let root = db.insert(EMPTY_PREFIX, &encoded);
+		(memdb, root)
+	}
+
+	/// A trie with the layout without extension whose nodes are not canonical.
+	fn denormalized_trie_without_ext() -> (HashedMemoryDB, <KeccakHasher as Hasher>::Out) {
+		type L = NoExtensionLayout;
+		let mut memdb = HashedMemoryDB::default();
+		let db = &mut memdb;
+		let values: Vec<Vec<u8>> = (1..4).map(|i| vec![i; 32]).collect();
+
+		// a branch with a single child and no value, at 0x1.
+		let leaf_3 = store_node::<L>(db, Node::Leaf(NibbleSlice::new(&[]), &values[0]));
+		let leaf_4 = store_node::<L>(db, Node::Leaf(NibbleSlice::new(&[]), &values[1]));
+		let inner = branch_children(&[(3, &leaf_3), (4, &leaf_4)]);
+		let inner = store_node::<L>(db, Node::NibbledBranch(NibbleSlice::new(&[]), inner, None));
+		let single = branch_children(&[(0, &inner)]);
+		let single = store_node::<L>(db, Node::NibbledBranch(nibble(&[0x05]), single, None));
+		// a branch holding only a value, at 0x2.
+		let valued = Node::NibbledBranch(nibble(&[0x03]), branch_children(&[]), Some(&values[2]));
+		let valued = store_node::<L>(db, valued);
+
+		let children = branch_children(&[(1, &single), (2, &valued)]);
+		let root = Node::NibbledBranch(NibbleSlice::new(&[]), children, None);
+		let encoded = reference_trie::node::encode_node::<L>(&root).unwrap();
+		let root = db.insert(EMPTY_PREFIX, &encoded);
+		(memdb, root)
+	}
+
+	/// The pairs of a trie, read without checking the nodes against the layout.
+	fn trie_pairs<L: TrieLayout<Hash = KeccakHasher>>(
+		db: &HashedMemoryDB,
+		root: &<KeccakHasher as Hasher>::Out,
+	) -> Vec<(Vec<u8>, Vec<u8>)> {
+		let trie = TrieDB::<L>::new_lenient(db, root).unwrap();
+		trie.iter().unwrap().map(|pair| pair.unwrap()).collect()
+	}
+
+	fn check_canonicalize<L: TrieLayout<Hash = KeccakHasher>>(
+		(mut db, mut root): (HashedMemoryDB, <KeccakHasher as Hasher>::Out),
+		expected_keys: &[&[u8]],
+	) {
+		let pairs = trie_pairs::<L>(&db, &root);
+		let keys: Vec<&[u8]> = pairs.iter().map(|(key, _)| &key[..]).collect();
+		assert_eq!(keys, expected_keys);
+		let (_, rebuilt) = build_trie::<L>(&pairs);
+		assert_ne!(root, rebuilt);
+
+		let stats = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap()
+			.canonicalize(None, None).unwrap();
+		assert!(stats.rewritten > 0);
+		assert!(!stats.budget_exhausted);
+		assert_eq!(root, rebuilt);
+		let strict = TrieDB::<L>::new(&db, &root).unwrap();
+		assert_eq!(strict.iter().unwrap().map(|pair| pair.unwrap()).collect::<Vec<_>>(), pairs);
+
+		// a canonical trie is left untouched.
+		let nodes = db.keys();
+		let mut t = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap();
+		let stats = t.canonicalize(None, None).unwrap();
+		assert_eq!(stats.rewritten, 0);
+		assert_eq!(t.committed_root(), None);
+		t.commit();
+		assert_eq!(t.commit_stats().written, 0);
+		drop(t);
+		assert_eq!(root, rebuilt);
+		assert_eq!(db.keys(), nodes);
+	}
+
+	#[test]
+	fn canonicalize_merges_nodes_with_ext() {
+		check_canonicalize::<ExtensionLayout>(
+			denormalized_trie_with_ext(),
+			&[
+				&[0x15, 0x07], &[0x15, 0x38], &[0x23, 0x40, 0x56], &[0x23, 0x41, 0x78], &[0x34],
+				&[0x45, 0x67], &[0x60],
+			],
+		);
+	}
+
+	#[test]
+	fn canonicalize_merges_nodes_without_ext() {
+		check_canonicalize::<NoExtensionLayout>(
+			denormalized_trie_without_ext(),
+			&[&[0x15, 0x03], &[0x15, 0x04], &[0x23]],
+		);
+	}
+
+	#[test]
+	fn canonicalize_by_prefix_and_budget() {
+		type L = ExtensionLayout;
+		let (mut db, mut root) = denormalized_trie_with_ext();
+		let pairs = trie_pairs::<L>(&db, &root);
+		let (_, rebuilt) = build_trie::<L>(&pairs);
+
+		// the budget stops the walk, the nodes visited first being rewritten.
+		let stats = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap()
+			.canonicalize(None, Some(3)).unwrap();
+		assert_eq!(stats.visited, 3);
+		assert!(stats.budget_exhausted);
+		assert_ne!(root, rebuilt);
+		assert_eq!(trie_pairs::<L>(&db, &root), pairs);
+
+		// the subtries are canonicalized one at a time.
+		for prefix in [&[0x15][..], &[0x23], &[0x34], &[0x45], &[0x50]] {
+			assert_ne!(root, rebuilt);
+			let stats = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap()
+				.canonicalize(Some(prefix), None).unwrap();
+			assert!(!stats.budget_exhausted);
+			assert_eq!(trie_pairs::<L>(&db, &root), pairs);
+		}
+		assert_eq!(root, rebuilt);
+	}
 }
@@ -0,0 +1,242 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Values stored in chunks, see `TrieLayout::MAX_VALUE_CHUNK`.
+//!
+//! A value stored apart and larger than `MAX_VALUE_CHUNK` is cut in chunks of `MAX_VALUE_CHUNK`
+//! bytes, the last one shorter. The chunks are stored as value nodes, under their hash and the
+//! prefix of the node holding the value, and so is their index: the length of the value as a
+//! little-endian `u64`, followed by the hashes of the chunks in order. The value slot of the
+//! node holds the hash of the index, see `node::Value::Chunked`.
+//!
+//! The length of a value is read from its index alone, and a part of the value from the chunks
+//! holding it.
+
+use hash_db::Hasher;
+use crate::node::{decode_hash, Value};
+use crate::rstd::{boxed::Box, convert::TryFrom, ops::Range, vec::Vec};
+use crate::{DBValue, Result, TrieError, TrieHash, TrieLayout};
+
+/// Size of the length of the value at the start of an index.
+const LENGTH_SIZE: usize = 8;
+
+/// Index of the chunks of a value.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct ChunkIndex<H> {
+	/// Length of the value.
+	pub len: usize,
+	/// Size of the chunks, the last one being shorter.
+	pub chunk_size: usize,
+	/// Hashes of the chunks, in order.
+	pub chunks: Vec<H>,
+}
+
+impl<H: AsRef<[u8]> + AsMut<[u8]> + Default> ChunkIndex<H> {
+	/// Index of `value` cut in chunks of `chunk_size` bytes, hashed with `Hs`.
+	pub fn new<Hs: Hasher<Out = H>>(value: &[u8], chunk_size: usize) -> Self {
+		ChunkIndex {
+			len: value.len(),
+			chunk_size,
+			chunks: value.chunks(chunk_size).map(Hs::hash).collect(),
+		}
+	}
+
+	/// Encoding of the index, as stored.
+	pub fn encode(&self) -> Vec<u8> {
+		let hash_len = H::default().as_ref().len();
+		let mut encoded = Vec::with_capacity(LENGTH_SIZE + self.chunks.len() * hash_len);
+		encoded.extend_from_slice(&(self.len as u64).to_le_bytes());
+		for chunk in self.chunks.iter() {
+			encoded.extend_from_slice(chunk.as_ref());
+		}
+		encoded
+	}
+
+	/// Decode the index `data` of a value cut in chunks of `chunk_size` bytes. `None` if `data`
+	/// does not hold the hashes of as many chunks as the length of the value needs, or if the
+	/// value fits in one chunk.
+	pub fn decode(data: &[u8], chunk_size: usize) -> Option<Self> {
+		if data.len() < LENGTH_SIZE || chunk_size == 0 {
+			return None;
+		}
+		let (len, hashes) = data.split_at(LENGTH_SIZE);
+		let mut len_bytes = [0u8; LENGTH_SIZE];
+		len_bytes.copy_from_slice(len);
+		let len = usize::try_from(u64::from_le_bytes(len_bytes)).ok()?;
+		if len <= chunk_size {
+			return None;
+		}
+		let hash_len = H::default().as_ref().len();
+		let count = (len - 1) / chunk_size + 1;
+		if count.checked_mul(hash_len) != Some(hashes.len()) {
+			return None;
+		}
+		let chunks = hashes.chunks(hash_len).map(|hash| {
+			let mut chunk = H::default();
+			chunk.as_mut().copy_from_slice(hash);
+			chunk
+		}).collect();
+		Some(ChunkIndex { len, chunk_size, chunks })
+	}
+
+	/// Range of the bytes of the value held by chunk `index`.
+	pub fn chunk_range(&self, index: usize) -> Range<usize> {
+		let start = index * self.chunk_size;
+		start..(start + self.chunk_size).min(self.len)
+	}
+
+	/// Range of the indices of the chunks holding the bytes `range` of the value, empty for an
+	/// empty `range`.
+	pub fn chunks_of(&self, range: Range<usize>) -> Range<usize> {
+		if range.start >= range.end {
+			return 0..0;
+		}
+		range.start / self.chunk_size..(range.end - 1) / self.chunk_size + 1
+	}
+}
+
+/// Size of the chunks of a value of `len` bytes stored in a trie of `L`, `None` if the value is
+/// not stored in chunks.
+pub(crate) fn chunk_size<L: TrieLayout>(len: usize) -> Option<usize> {
+	let max_inline = L::MAX_INLINE_VALUE?;
+	let chunk_size = L::MAX_VALUE_CHUNK?;
+	(len > max_inline && len > chunk_size).then_some(chunk_size)
+}
+
+/// Decode `data`, the index read under `hash` of a value cut in chunks of `chunk_size` bytes,
+/// `chunk_size` being `TrieLayout::MAX_VALUE_CHUNK`.
+pub(crate) fn decode_index<H: Hasher, E>(
+	hash: &H::Out,
+	data: &[u8],
+	chunk_size: Option<usize>,
+) -> Result<ChunkIndex<H::Out>, H::Out, E> {
+	chunk_size
+		.and_then(|chunk_size| ChunkIndex::decode(data, chunk_size))
+		.ok_or_else(|| Box::new(TrieError::InvalidValueChunks(*hash)))
+}
+
+/// Check that `chunk` has the length of the chunk `i` of `index`, the index read under `hash`.
+pub(crate) fn check_chunk<H: Hasher, E>(
+	hash: &H::Out,
+	index: &ChunkIndex<H::Out>,
+	i: usize,
+	chunk: &[u8],
+) -> Result<(), H::Out, E> {
+	match index.chunk_range(i).len() == chunk.len() {
+		true => Ok(()),
+		false => Err(Box::new(TrieError::InvalidValueChunks(*hash))),
+	}
+}
+
+/// Read with `fetch` the index with hash `hash` of a value cut in chunks of `chunk_size` bytes
+/// and then its chunks, one at a time. Each node read is passed to `visit` with its hash, the
+/// index and the position of the chunk, `None` for the index.
+pub(crate) fn read_chunks<H: Hasher, E>(
+	hash: &H::Out,
+	chunk_size: Option<usize>,
+	mut fetch: impl FnMut(&H::Out) -> Result<DBValue, H::Out, E>,
+	mut visit: impl FnMut(&H::Out, &[u8], &ChunkIndex<H::Out>, Option<usize>),
+) -> Result<(), H::Out, E> {
+	let data = fetch(hash)?;
+	let index = decode_index::<H, E>(hash, &data, chunk_size)?;
+	visit(hash, &data, &index, None);
+	for (i, chunk_hash) in index.chunks.iter().enumerate() {
+		let chunk = fetch(chunk_hash)?;
+		check_chunk::<H, E>(hash, &index, i, &chunk)?;
+		visit(chunk_hash, &chunk, &index, Some(i));
+	}
+	Ok(())
+}
+
+/// Read with `fetch` the value whose index has hash `hash`, as `read_chunks`, passing each node
+/// read to `record` with its hash.
+pub(crate) fn read_chunked<H: Hasher, E>(
+	hash: &H::Out,
+	chunk_size: Option<usize>,
+	fetch: impl FnMut(&H::Out) -> Result<DBValue, H::Out, E>,
+	mut record: impl FnMut(&H::Out, &[u8]),
+) -> Result<DBValue, H::Out, E> {
+	let mut value = Vec::new();
+	read_chunks::<H, E>(hash, chunk_size, fetch, |node_hash, data, index, chunk| {
+		record(node_hash, data);
+		match chunk {
+			None => value.reserve_exact(index.len),
+			Some(_) => value.extend_from_slice(data),
+		}
+	})?;
+	Ok(value)
+}
+
+/// Hashes of the nodes storing `value` apart in `L`, as written by `node::store_value`: its
+/// value node, or its chunks followed by their index. The index is read with `fetch`, and the
+/// chunks are left out if it cannot be read.
+pub(crate) fn value_nodes<L: TrieLayout>(
+	value: Value,
+	fetch: impl FnOnce(&TrieHash<L>) -> Option<DBValue>,
+) -> Vec<TrieHash<L>> {
+	match value {
+		Value::Inline(_) => Vec::new(),
+		Value::Node(hash) => decode_hash::<L::Hash>(hash).into_iter().collect(),
+		Value::Chunked(hash) => {
+			let hash = match decode_hash::<L::Hash>(hash) {
+				Some(hash) => hash,
+				None => return Vec::new(),
+			};
+			let index = L::MAX_VALUE_CHUNK.and_then(|chunk_size| {
+				fetch(&hash).and_then(|data| ChunkIndex::<TrieHash<L>>::decode(&data, chunk_size))
+			});
+			let mut nodes = index.map_or_else(Vec::new, |index| index.chunks);
+			nodes.push(hash);
+			nodes
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use keccak_hasher::KeccakHasher;
+	use super::ChunkIndex;
+
+	type Index = ChunkIndex<<KeccakHasher as hash_db::Hasher>::Out>;
+
+	#[test]
+	fn index_round_trips() {
+		let value: Vec<u8> = (0..250u8).collect();
+		let index = Index::new::<KeccakHasher>(&value, 64);
+		assert_eq!(index.chunks.len(), 4);
+		assert_eq!(index.chunk_range(3), 192..250);
+		let encoded = index.encode();
+		assert_eq!(encoded.len(), 8 + 4 * 32);
+		assert_eq!(Index::decode(&encoded, 64), Some(index.clone()));
+
+		// the chunk count follows the length and the chunk size.
+		assert_eq!(Index::decode(&encoded, 128), None);
+		assert_eq!(Index::decode(&encoded[..encoded.len() - 1], 64), None);
+		assert_eq!(Index::decode(&encoded[..7], 64), None);
+		// a value fitting in one chunk is not chunked.
+		let single = Index::new::<KeccakHasher>(&value[..64], 64);
+		assert_eq!(Index::decode(&single.encode(), 64), None);
+	}
+
+	#[test]
+	fn ranges_map_to_chunks() {
+		let index = Index::new::<KeccakHasher>(&[7; 250], 64);
+		assert_eq!(index.chunks_of(0..1), 0..1);
+		assert_eq!(index.chunks_of(63..65), 0..2);
+		assert_eq!(index.chunks_of(64..128), 1..2);
+		assert_eq!(index.chunks_of(100..250), 1..4);
+		assert_eq!(index.chunks_of(10..10), 0..0);
+	}
+}
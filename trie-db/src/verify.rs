@@ -0,0 +1,778 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Integrity checks of the tries of several roots against one database.
+//!
+//! `verify_roots` walks every node of the tries of a set of roots, typically the roots of the
+//! last blocks, to check that the database holds all of them. The subtrees shared by the
+//! roots are only walked once.
+//!
+//! `TrieDB::validate` walks the trie of one root to check, besides the presence of its nodes,
+//! their hashes and the structural invariants of the layout, reporting all the violations.
+
+use hash_db::{HashDBRef, Hasher};
+use hashbrown::HashMap;
+use crate::nibble::{NibbleSlice, NibbleVec};
+use crate::node::{decode_hash, NodeHandle, NodeHandlePlan, NodePlan, OwnedNode, Value};
+use crate::rstd::{boxed::Box, vec::Vec};
+use crate::{CError, DBValue, Result, Trie, TrieDB, TrieError, TrieHash, TrieLayout};
+
+/// Result of the check of the trie of a root.
+#[derive(PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum RootStatus<H, E> {
+	/// All the nodes of the trie are in the database.
+	Complete,
+	/// Nodes of the trie are missing from the database.
+	Incomplete {
+		/// Number of references to missing nodes, the root included.
+		missing: u32,
+	},
+	/// A node of the trie cannot be decoded, or references a child with an invalid hash.
+	/// Takes precedence over missing nodes.
+	Corrupt {
+		/// First error met when walking the trie in key order.
+		first_error: Box<TrieError<H, E>>,
+	},
+	/// The node budget ran out before the trie could be fully checked.
+	Partial,
+}
+
+/// Check that the tries of `roots` can be fully read from `db`, returning the status of each
+/// root in order.
+///
+/// A subtree shared by several roots is only walked once, its result being reused for the
+/// following roots. When `budget` is given, at most `budget` nodes are fetched from `db`:
+/// the roots which could not be fully checked within it are `Partial`.
+pub fn verify_roots<L: TrieLayout>(
+	db: &dyn HashDBRef<L::Hash, DBValue>,
+	roots: &[TrieHash<L>],
+	budget: Option<u64>,
+) -> Vec<RootStatus<TrieHash<L>, CError<L>>>
+	where
+		CError<L>: Clone,
+{
+	let mut walker = Walker::<L> { checked: HashMap::new(), budget };
+	roots.iter().map(|root| {
+		let trie = TrieDB::new_unchecked(db, root);
+		let mut key = NibbleVec::new();
+		match walker.check_node(&trie, *root, NodeHandle::Hash(root.as_ref()), &mut key) {
+			Some(Summary { first_error: Some(first_error), .. }) => RootStatus::Corrupt { first_error },
+			Some(Summary { missing: 0, .. }) => RootStatus::Complete,
+			Some(Summary { missing, .. }) => RootStatus::Incomplete { missing },
+			None => RootStatus::Partial,
+		}
+	}).collect()
+}
+
+/// Result of the check of a subtree.
+struct Summary<L: TrieLayout> {
+	missing: u32,
+	first_error: Option<Box<TrieError<TrieHash<L>, CError<L>>>>,
+}
+
+impl<L: TrieLayout> Clone for Summary<L> where CError<L>: Clone {
+	fn clone(&self) -> Self {
+		Summary { missing: self.missing, first_error: self.first_error.clone() }
+	}
+}
+
+impl<L: TrieLayout> Summary<L> {
+	fn complete() -> Self {
+		Summary { missing: 0, first_error: None }
+	}
+
+	fn add(&mut self, other: Self) {
+		self.missing = self.missing.saturating_add(other.missing);
+		if self.first_error.is_none() {
+			self.first_error = other.first_error;
+		}
+	}
+}
+
+/// A node in the database, identified by its hash and its prefix.
+type NodeKey<L> = (TrieHash<L>, (Vec<u8>, Option<u8>));
+
+struct Walker<L: TrieLayout> {
+	/// Subtrees already checked.
+	checked: HashMap<NodeKey<L>, Summary<L>>,
+	/// Remaining number of nodes to fetch.
+	budget: Option<u64>,
+}
+
+impl<L: TrieLayout> Walker<L> where CError<L>: Clone {
+	/// Check the subtree of the child of `parent_hash` at `key`, or `None` if the budget ran
+	/// out.
+	fn check_node(
+		&mut self,
+		trie: &TrieDB<L>,
+		parent_hash: TrieHash<L>,
+		handle: NodeHandle,
+		key: &mut NibbleVec<L::Nibble>,
+	) -> Option<Summary<L>> {
+		let node_key = match handle {
+			NodeHandle::Hash(data) => decode_hash::<L::Hash>(data).map(|hash| {
+				let (prefix, last) = key.as_prefix();
+				(hash, (prefix.to_vec(), last))
+			}),
+			// invalid hashes or inline nodes, checked with their parent.
+			NodeHandle::Inline(_) => None,
+		};
+		if let Some(node_key) = &node_key {
+			if let Some(summary) = self.checked.get(node_key) {
+				return Some(summary.clone());
+			}
+			match &mut self.budget {
+				Some(0) => return None,
+				Some(budget) => *budget -= 1,
+				None => (),
+			}
+		}
+
+		let summary = match trie.get_raw_or_lookup(parent_hash, handle, key.as_prefix()) {
+			Ok((node, node_hash)) =>
+				self.check_children(trie, node_hash.unwrap_or(parent_hash), &node, key)?,
+			Err(error) => match *error {
+				TrieError::InvalidStateRoot(_) | TrieError::IncompleteDatabase { .. } =>
+					Summary { missing: 1, first_error: None },
+				_ => Summary { missing: 0, first_error: Some(error) },
+			},
+		};
+		if let Some(node_key) = node_key {
+			self.checked.insert(node_key, summary.clone());
+		}
+		Some(summary)
+	}
+
+	/// Check the value node of `node`, at `key`, or the chunks of its value and their index, if
+	/// its value is stored apart.
+	fn check_value(
+		&mut self,
+		trie: &TrieDB<L>,
+		node_hash: TrieHash<L>,
+		node: &OwnedNode<DBValue, L::Nibble>,
+		key: &NibbleVec<L::Nibble>,
+	) -> Option<Summary<L>> {
+		let (value, value_hash) = match node.value::<L>(node_hash) {
+			Ok(Some(value @ Value::Node(value_hash)))
+			| Ok(Some(value @ Value::Chunked(value_hash))) => (value, value_hash),
+			Ok(_) => return Some(Summary::complete()),
+			Err(error) => return Some(Summary { missing: 0, first_error: Some(error) }),
+		};
+		let node_key = decode_hash::<L::Hash>(value_hash).map(|hash| {
+			let (prefix, last) = key.as_prefix();
+			(hash, (prefix.to_vec(), last))
+		});
+		if let Some(node_key) = &node_key {
+			if let Some(summary) = self.checked.get(node_key) {
+				return Some(summary.clone());
+			}
+			match &mut self.budget {
+				Some(0) => return None,
+				Some(budget) => *budget -= 1,
+				None => (),
+			}
+		}
+
+		let summary = match trie.read_value_nodes(value, node_hash, key.as_prefix(), |_, _| ()) {
+			Ok(()) => Summary::complete(),
+			Err(error) => match *error {
+				TrieError::IncompleteDatabase { .. } => Summary { missing: 1, first_error: None },
+				_ => Summary { missing: 0, first_error: Some(error) },
+			},
+		};
+		if let Some(node_key) = node_key {
+			self.checked.insert(node_key, summary.clone());
+		}
+		Some(summary)
+	}
+
+	/// Check the children of `node`, at `key`.
+	fn check_children(
+		&mut self,
+		trie: &TrieDB<L>,
+		node_hash: TrieHash<L>,
+		node: &OwnedNode<DBValue, L::Nibble>,
+		key: &mut NibbleVec<L::Nibble>,
+	) -> Option<Summary<L>> {
+		let data = node.data();
+		let mut summary = self.check_value(trie, node_hash, node, key)?;
+		let (partial, children) = match node.node_plan() {
+			NodePlan::Empty | NodePlan::Leaf { .. } => return Some(summary),
+			NodePlan::Extension { partial, child } => {
+				let partial = partial.build(data);
+				key.append_partial(partial.right());
+				summary.add(self.check_node(trie, node_hash, child.build(data), key)?);
+				key.drop_lasts(partial.len());
+				return Some(summary);
+			},
+			NodePlan::Branch { children, .. } => (None, children),
+			NodePlan::NibbledBranch { partial, children, .. } => (Some(partial.build(data)), children),
+		};
+		if let Some(partial) = partial {
+			key.append_partial(partial.right());
+		}
+		for (i, child) in children.iter().enumerate() {
+			if let Some(child) = child {
+				key.push(i as u8);
+				summary.add(self.check_node(trie, node_hash, child.build(data), key)?);
+				key.pop();
+			}
+		}
+		if let Some(partial) = partial {
+			key.drop_lasts(partial.len());
+		}
+		Some(summary)
+	}
+}
+
+/// A violation of the invariants of a trie found by `TrieDB::validate`.
+#[derive(PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum ViolationKind<H, E> {
+	/// The node is missing from the database.
+	MissingNode,
+	/// The node read from the database hashes to `found` instead of its reference.
+	HashMismatch {
+		/// Hash of the node read.
+		found: H,
+	},
+	/// The node cannot be decoded, or references a child with an invalid hash.
+	Corrupt(Box<TrieError<H, E>>),
+	/// A type of node the layout does not use: extensions and branches without partial key in
+	/// layouts without extensions, branches with a partial key in the others.
+	UnexpectedNode,
+	/// An empty node below the root.
+	EmptyChild,
+	/// An extension with an empty partial key.
+	EmptyExtension,
+	/// An extension whose child is a leaf or an extension, which should have been merged into
+	/// the child.
+	UnmergedExtension,
+	/// A branch without value and with less than two children, or with a value and no children,
+	/// which should have been merged into its child or replaced by a leaf.
+	UnderfullBranch {
+		/// Number of children of the branch.
+		children: u8,
+	},
+}
+
+/// A violation of the invariants of a trie, and the node it was found at.
+#[derive(PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct Violation<H, E> {
+	/// Prefix of the node, the nibbles of the keys below it.
+	pub prefix: (Vec<u8>, Option<u8>),
+	/// Hash of the node, or of the node it is inlined in.
+	pub hash: H,
+	/// The violation.
+	pub kind: ViolationKind<H, E>,
+}
+
+/// Result of `TrieDB::validate`.
+#[derive(PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct ValidationReport<H, E> {
+	/// Number of nodes read, inline nodes and value nodes included.
+	pub nodes: u64,
+	/// Violations found, in key order, a node coming before its children.
+	pub violations: Vec<Violation<H, E>>,
+}
+
+impl<H, E> Default for ValidationReport<H, E> {
+	fn default() -> Self {
+		ValidationReport { nodes: 0, violations: Vec::new() }
+	}
+}
+
+impl<H, E> ValidationReport<H, E> {
+	/// Whether no violation was found.
+	pub fn is_valid(&self) -> bool {
+		self.violations.is_empty()
+	}
+}
+
+/// Result of `TrieDB::validate`, failing on a failure of the node provider only.
+pub type ValidationResult<L> =
+	Result<ValidationReport<TrieHash<L>, CError<L>>, TrieHash<L>, CError<L>>;
+
+/// Type of a node, as seen by the check of its parent.
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum NodeType {
+	Empty,
+	Leaf,
+	Extension,
+	Branch,
+}
+
+/// Walk the nodes of `trie` on the path to the keys starting with `prefix`, and the nodes of
+/// their subtree, see `TrieDB::validate_from`.
+pub(crate) fn validate<L: TrieLayout>(trie: &TrieDB<L>, prefix: &[u8]) -> ValidationResult<L> {
+	let mut validator = Validator {
+		trie,
+		prefix: NibbleSlice::new(prefix),
+		report: Default::default(),
+	};
+	let root = *trie.root();
+	validator.check_node(root, NodeHandle::Hash(root.as_ref()), &mut NibbleVec::new(), true)?;
+	Ok(validator.report)
+}
+
+struct Validator<'a, 'db, L: TrieLayout> {
+	trie: &'a TrieDB<'db, L>,
+	/// Nibbles of the keys of the subtree to check.
+	prefix: NibbleSlice<'a, L::Nibble>,
+	report: ValidationReport<TrieHash<L>, CError<L>>,
+}
+
+impl<'a, 'db, L: TrieLayout> Validator<'a, 'db, L> {
+	fn violation(
+		&mut self,
+		key: &NibbleVec<L::Nibble>,
+		hash: TrieHash<L>,
+		kind: ViolationKind<TrieHash<L>, CError<L>>,
+	) {
+		let (prefix, last) = key.as_prefix();
+		self.report.violations.push(Violation { prefix: (prefix.to_vec(), last), hash, kind });
+	}
+
+	/// Whether the node at `key` is in the subtree to check or on the path to it.
+	fn on_path(&self, key: &NibbleVec<L::Nibble>) -> bool {
+		(0..key.len().min(self.prefix.len())).all(|i| key.at(i) == self.prefix.at(i))
+	}
+
+	/// Check the child of `parent_hash` at `key`, and its subtree. Returns the type of the
+	/// child, or `None` if it could not be read.
+	fn check_node(
+		&mut self,
+		parent_hash: TrieHash<L>,
+		handle: NodeHandle,
+		key: &mut NibbleVec<L::Nibble>,
+		is_root: bool,
+	) -> Result<Option<NodeType>, TrieHash<L>, CError<L>> {
+		let reference = match handle {
+			NodeHandle::Hash(data) => decode_hash::<L::Hash>(data).unwrap_or(parent_hash),
+			NodeHandle::Inline(_) => parent_hash,
+		};
+		let read = self.trie.get_lenient_node(parent_hash, handle, key.as_prefix());
+		let (node, node_hash) = match read {
+			Ok(node) => node,
+			Err(error) => {
+				self.read_violation(key, reference, error)?;
+				return Ok(None);
+			},
+		};
+		self.report.nodes += 1;
+		if let Some(hash) = node_hash {
+			let found = L::Hash::hash(node.data());
+			if found != hash {
+				self.violation(key, hash, ViolationKind::HashMismatch { found });
+			}
+		}
+		let node_hash = node_hash.unwrap_or(parent_hash);
+		self.check_value(node_hash, &node, key)?;
+
+		let data = node.data();
+		match node.node_plan() {
+			NodePlan::Empty => {
+				if !is_root {
+					self.violation(key, node_hash, ViolationKind::EmptyChild);
+				}
+				Ok(Some(NodeType::Empty))
+			},
+			NodePlan::Leaf { .. } => Ok(Some(NodeType::Leaf)),
+			NodePlan::Extension { partial, child } => {
+				if !L::USE_EXTENSION {
+					self.violation(key, node_hash, ViolationKind::UnexpectedNode);
+				}
+				let partial = partial.build(data);
+				if partial.is_empty() {
+					self.violation(key, node_hash, ViolationKind::EmptyExtension);
+				}
+				key.append_partial(partial.right());
+				let child = match self.on_path(key) {
+					true => self.check_node(node_hash, child.build(data), key, false)?,
+					false => None,
+				};
+				key.drop_lasts(partial.len());
+				if let Some(NodeType::Leaf) | Some(NodeType::Extension) = child {
+					self.violation(key, node_hash, ViolationKind::UnmergedExtension);
+				}
+				Ok(Some(NodeType::Extension))
+			},
+			NodePlan::Branch { value, children } => {
+				if !L::USE_EXTENSION {
+					self.violation(key, node_hash, ViolationKind::UnexpectedNode);
+				}
+				self.check_children_count(key, node_hash, value.is_some(), children);
+				self.check_children(node_hash, data, children, key)?;
+				Ok(Some(NodeType::Branch))
+			},
+			NodePlan::NibbledBranch { partial, value, children } => {
+				if L::USE_EXTENSION {
+					self.violation(key, node_hash, ViolationKind::UnexpectedNode);
+				}
+				self.check_children_count(key, node_hash, value.is_some(), children);
+				let partial = partial.build(data);
+				key.append_partial(partial.right());
+				self.check_children(node_hash, data, children, key)?;
+				key.drop_lasts(partial.len());
+				Ok(Some(NodeType::Branch))
+			},
+		}
+	}
+
+	/// Report the failure to read the node `reference` at `key`, only returning the failures of
+	/// the node provider.
+	fn read_violation(
+		&mut self,
+		key: &NibbleVec<L::Nibble>,
+		reference: TrieHash<L>,
+		error: Box<TrieError<TrieHash<L>, CError<L>>>,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		let kind = match *error {
+			TrieError::InvalidStateRoot(_) | TrieError::IncompleteDatabase { .. } =>
+				ViolationKind::MissingNode,
+			TrieError::HashMismatch { found, .. } => ViolationKind::HashMismatch { found },
+			TrieError::NodeProviderFailed(_) => return Err(error),
+			_ => ViolationKind::Corrupt(error),
+		};
+		self.violation(key, reference, kind);
+		Ok(())
+	}
+
+	/// Check the value node of `node`, at `key`, or the chunks of its value and their index, if
+	/// its value is stored apart.
+	fn check_value(
+		&mut self,
+		node_hash: TrieHash<L>,
+		node: &OwnedNode<DBValue, L::Nibble>,
+		key: &NibbleVec<L::Nibble>,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		let (value, value_hash) = match node.value::<L>(node_hash)? {
+			Some(value @ Value::Node(value_hash)) | Some(value @ Value::Chunked(value_hash)) =>
+				(value, value_hash),
+			_ => return Ok(()),
+		};
+		let reference = decode_hash::<L::Hash>(value_hash).unwrap_or(node_hash);
+		let mut mismatches = Vec::new();
+		let mut nodes = 0;
+		let read = self.trie.read_value_nodes(value, node_hash, key.as_prefix(), |hash, data| {
+			nodes += 1;
+			let found = L::Hash::hash(data);
+			if found != *hash {
+				mismatches.push((*hash, found));
+			}
+		});
+		self.report.nodes += nodes;
+		for (hash, found) in mismatches {
+			self.violation(key, hash, ViolationKind::HashMismatch { found });
+		}
+		match read {
+			Ok(()) => Ok(()),
+			Err(error) => self.read_violation(key, reference, error),
+		}
+	}
+
+	/// Check the number of children of the branch at `key`.
+	fn check_children_count(
+		&mut self,
+		key: &NibbleVec<L::Nibble>,
+		node_hash: TrieHash<L>,
+		has_value: bool,
+		children: &[Option<NodeHandlePlan>],
+	) {
+		let count = children.iter().filter(|child| child.is_some()).count() as u8;
+		if count == 0 || (count == 1 && !has_value) {
+			self.violation(key, node_hash, ViolationKind::UnderfullBranch { children: count });
+		}
+	}
+
+	/// Check the children of the branch with hash `node_hash` on the path to the subtree to
+	/// check, `key` being the prefix of the children.
+	fn check_children(
+		&mut self,
+		node_hash: TrieHash<L>,
+		data: &[u8],
+		children: &[Option<NodeHandlePlan>],
+		key: &mut NibbleVec<L::Nibble>,
+	) -> Result<(), TrieHash<L>, CError<L>> {
+		for (i, child) in children.iter().enumerate() {
+			if let Some(child) = child {
+				key.push(i as u8);
+				if self.on_path(key) {
+					self.check_node(node_hash, child.build(data), key, false)?;
+				}
+				key.pop();
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use hash_db::{HashDB, Hasher, EMPTY_PREFIX};
+	use keccak_hasher::KeccakHasher;
+	use memory_db::{MemoryDB, PrefixedKey};
+	use reference_trie::{
+		verify::{verify_roots, RootStatus, Violation, ViolationKind}, ChildReference,
+		ExtensionLayout, NibbleSlice, NoExtensionLayout, NodeCodec, Radix16, TrieDB, TrieDBMut,
+		TrieDBNodeIterator, TrieError, TrieLayout, TrieMut, ValueNodeLayout,
+	};
+	use crate::DBValue;
+
+	type PrefixedMemoryDB = MemoryDB<KeccakHasher, PrefixedKey<KeccakHasher>, DBValue>;
+	type Root = <KeccakHasher as Hasher>::Out;
+
+	fn value(i: u32) -> Vec<u8> {
+		let mut value = vec![0; 32];
+		value[..4].copy_from_slice(&i.to_be_bytes());
+		value
+	}
+
+	/// Ten roots over 400 keys, each root updating one key of the previous root: the tries
+	/// share all their nodes but the ones on the path to the updated keys.
+	fn build_roots<L: TrieLayout<Hash = KeccakHasher>>(db: &mut PrefixedMemoryDB) -> Vec<Root> {
+		let mut pairs: Vec<_> = (0..400u32).map(|i| (i.to_be_bytes(), value(i))).collect();
+		(0..10u32).map(|step| {
+			if step > 0 {
+				pairs[(step * 37) as usize].1 = value(step + 1000);
+			}
+			let mut step_db = PrefixedMemoryDB::default();
+			let mut root = Default::default();
+			{
+				let mut t = TrieDBMut::<L>::new(&mut step_db, &mut root);
+				for (key, value) in pairs.iter() {
+					t.insert(key, value).unwrap();
+				}
+			}
+			db.consolidate(step_db);
+			root
+		}).collect()
+	}
+
+	#[test]
+	fn shared_roots_are_complete() {
+		let mut db = PrefixedMemoryDB::default();
+		let roots = build_roots::<ExtensionLayout>(&mut db);
+		let statuses = verify_roots::<ExtensionLayout>(&db, &roots, None);
+		assert_eq!(statuses, vec![RootStatus::Complete; roots.len()]);
+	}
+
+	#[test]
+	fn shared_nodes_are_fetched_once() {
+		let mut db = PrefixedMemoryDB::default();
+		let roots = build_roots::<NoExtensionLayout>(&mut db);
+		// the nodes of the ten tries, each fetched once.
+		let node_count = db.keys().len() as u64;
+		let statuses = verify_roots::<NoExtensionLayout>(&db, &roots, Some(node_count));
+		assert_eq!(statuses, vec![RootStatus::Complete; roots.len()]);
+
+		let statuses = verify_roots::<NoExtensionLayout>(&db, &roots, Some(node_count - 1));
+		assert_eq!(&statuses[..9], &vec![RootStatus::Complete; 9][..]);
+		assert_eq!(statuses[9], RootStatus::Partial);
+
+	}
+
+	#[test]
+	fn broken_root_is_reported() {
+		let mut db = PrefixedMemoryDB::default();
+		let roots = build_roots::<ExtensionLayout>(&mut db);
+		// the root node of the last root is only used by it.
+		db.remove(&roots[9], EMPTY_PREFIX);
+		let mut checked = roots.clone();
+		checked.push(KeccakHasher::hash(b"missing"));
+
+		let statuses = verify_roots::<ExtensionLayout>(&db, &checked, None);
+		assert_eq!(&statuses[..9], &vec![RootStatus::Complete; 9][..]);
+		assert_eq!(statuses[9], RootStatus::Incomplete { missing: 1 });
+		assert_eq!(statuses[10], RootStatus::Incomplete { missing: 1 });
+
+		let corrupt = db.insert(EMPTY_PREFIX, &[0xff, 0xff, 0xff]);
+		match &verify_roots::<ExtensionLayout>(&db, &[corrupt], None)[..] {
+			[RootStatus::Corrupt { first_error }] => match **first_error {
+				TrieError::DecoderError(hash, _) => assert_eq!(hash, corrupt),
+				ref error => panic!("unexpected error: {:?}", error),
+			},
+			statuses => panic!("unexpected statuses: {:?}", statuses),
+		}
+		let empty = <ExtensionLayout as TrieLayout>::Codec::hashed_null_node();
+		assert_eq!(verify_roots::<ExtensionLayout>(&db, &[empty], None), vec![RootStatus::Complete]);
+	}
+
+	/// A trie of the keys `[i << 4, j << 4]`: a branch at the root, a node at the prefix
+	/// `(vec![i << 4], None)` and the leaves at the prefixes `(vec![i << 4], Some(j << 4))`.
+	fn build_grid<L: TrieLayout<Hash = KeccakHasher>>(db: &mut PrefixedMemoryDB) -> Root {
+		let mut root = Default::default();
+		let mut t = TrieDBMut::<L>::new(db, &mut root);
+		for i in 0..16u8 {
+			for j in 0..16u8 {
+				t.insert(&[i << 4, j << 4], &value((i as u32) << 8 | j as u32)).unwrap();
+			}
+		}
+		t.commit();
+		drop(t);
+		root
+	}
+
+	/// Hash and data of the node at `prefix` in the trie at `root`.
+	fn node_at<L: TrieLayout<Hash = KeccakHasher>>(
+		db: &PrefixedMemoryDB,
+		root: &Root,
+		prefix: (Vec<u8>, Option<u8>),
+	) -> (Root, DBValue) {
+		let trie = TrieDB::<L>::new(db, root).unwrap();
+		TrieDBNodeIterator::new(&trie).unwrap()
+			.map(|item| item.unwrap())
+			.find(|(key, hash, _)| {
+				let (key, last) = key.as_prefix();
+				hash.is_some() && (key, last) == (&prefix.0[..], prefix.1)
+			})
+			.map(|(_, hash, node)| (hash.unwrap(), node.data().to_vec()))
+			.expect("no node at prefix")
+	}
+
+	fn missing_nodes_are_reported<L: TrieLayout<Hash = KeccakHasher>>()
+		where <L::Codec as NodeCodec>::Error: PartialEq,
+	{
+		let mut db = PrefixedMemoryDB::default();
+		let root = build_grid::<L>(&mut db);
+		let report = TrieDB::<L>::new(&db, &root).unwrap().validate().unwrap();
+		assert!(report.is_valid());
+		// the root, 16 nodes at depth 1 and 256 leaves, with an extension above the branches of
+		// depth 1 with extensions.
+		let nodes = if L::USE_EXTENSION { 289 } else { 273 };
+		assert_eq!(report.nodes, nodes);
+
+		let first = (vec![0x10], Some(0x20));
+		let second = (vec![0x30], Some(0x40));
+		let (first_hash, _) = node_at::<L>(&db, &root, first.clone());
+		let (second_hash, _) = node_at::<L>(&db, &root, second.clone());
+		db.remove(&first_hash, (&first.0, first.1));
+		db.remove(&second_hash, (&second.0, second.1));
+
+		let trie = TrieDB::<L>::new(&db, &root).unwrap();
+		let report = trie.validate().unwrap();
+		assert_eq!(report.nodes, nodes - 2);
+		assert_eq!(report.violations, vec![
+			Violation { prefix: first.clone(), hash: first_hash, kind: ViolationKind::MissingNode },
+			Violation { prefix: second, hash: second_hash, kind: ViolationKind::MissingNode },
+		]);
+
+		let report = trie.validate_from(&[0x10]).unwrap();
+		assert_eq!(report.violations, vec![
+			Violation { prefix: first, hash: first_hash, kind: ViolationKind::MissingNode },
+		]);
+		let report = trie.validate_from(&[0x20]).unwrap();
+		assert!(report.is_valid());
+		assert!(report.nodes < nodes / 10);
+	}
+
+	#[test]
+	fn missing_nodes_are_reported_extension() {
+		missing_nodes_are_reported::<ExtensionLayout>();
+	}
+
+	#[test]
+	fn missing_nodes_are_reported_no_extension() {
+		missing_nodes_are_reported::<NoExtensionLayout>();
+	}
+
+	#[test]
+	fn missing_value_nodes_are_reported() {
+		let mut db = PrefixedMemoryDB::default();
+		let mut root = Default::default();
+		let mut t = TrieDBMut::<ValueNodeLayout>::new(&mut db, &mut root);
+		for i in 0..16u8 {
+			for j in 0..16u8 {
+				t.insert(&[i << 4, j << 4], &[i << 4 | j; 40]).unwrap();
+			}
+		}
+		t.commit();
+		drop(t);
+		let statuses = verify_roots::<ValueNodeLayout>(&db, &[root], None);
+		assert_eq!(statuses, vec![RootStatus::Complete]);
+		let report = TrieDB::<ValueNodeLayout>::new(&db, &root).unwrap().validate().unwrap();
+		assert!(report.is_valid());
+		// the nodes of the trie of the grid and the 256 value nodes of its leaves.
+		assert_eq!(report.nodes, 273 + 256);
+
+		// the value node is stored at the prefix of its leaf.
+		let prefix = (vec![0x10], Some(0x20));
+		let value_hash = KeccakHasher::hash(&[0x12; 40]);
+		db.remove(&value_hash, (&prefix.0, prefix.1));
+		let statuses = verify_roots::<ValueNodeLayout>(&db, &[root], None);
+		assert_eq!(statuses, vec![RootStatus::Incomplete { missing: 1 }]);
+		let report = TrieDB::<ValueNodeLayout>::new(&db, &root).unwrap().validate().unwrap();
+		assert_eq!(report.nodes, 273 + 255);
+		assert_eq!(report.violations, vec![
+			Violation { prefix, hash: value_hash, kind: ViolationKind::MissingNode },
+		]);
+	}
+
+	#[test]
+	fn hash_mismatch_is_reported() {
+		let mut db = PrefixedMemoryDB::default();
+		let root = build_grid::<NoExtensionLayout>(&mut db);
+		let prefix = (vec![0x10], Some(0x20));
+		let (hash, _) = node_at::<NoExtensionLayout>(&db, &root, prefix.clone());
+		let (_, other) = node_at::<NoExtensionLayout>(&db, &root, (vec![0x10], Some(0x30)));
+		db.remove(&hash, (&prefix.0, prefix.1));
+		db.emplace(hash, (&prefix.0, prefix.1), other.clone());
+
+		let report = TrieDB::<NoExtensionLayout>::new(&db, &root).unwrap().validate().unwrap();
+		assert_eq!(report.violations, vec![Violation {
+			prefix,
+			hash,
+			kind: ViolationKind::HashMismatch { found: KeccakHasher::hash(&other) },
+		}]);
+	}
+
+	#[test]
+	fn structural_violations_are_reported() {
+		type Codec = <ExtensionLayout as TrieLayout>::Codec;
+		let mut db = PrefixedMemoryDB::default();
+		let leaf = Codec::leaf_node(NibbleSlice::<Radix16>::new(&[0x34]).right(), &value(0));
+
+		// an extension to a leaf, which should have been a single leaf.
+		let leaf_hash = db.insert((&[0x12], None), &leaf);
+		let extension = Codec::extension_node(
+			NibbleSlice::<Radix16>::new(&[0x12]).right_iter(),
+			2,
+			ChildReference::Hash(leaf_hash),
+		);
+		let root = db.insert(EMPTY_PREFIX, &extension);
+		let report = TrieDB::<ExtensionLayout>::new(&db, &root).unwrap().validate().unwrap();
+		assert_eq!(report.nodes, 2);
+		assert_eq!(report.violations, vec![Violation {
+			prefix: (vec![], None),
+			hash: root,
+			kind: ViolationKind::UnmergedExtension,
+		}]);
+
+		// a branch with a single child and no value, which should have been merged into it.
+		let leaf_hash = db.insert((&[], Some(0x10)), &leaf);
+		let mut children = vec![None; 16];
+		children[1] = Some(ChildReference::Hash(leaf_hash));
+		let branch = Codec::branch_node(children.iter(), None);
+		let root = db.insert(EMPTY_PREFIX, &branch);
+		let report = TrieDB::<ExtensionLayout>::new(&db, &root).unwrap().validate().unwrap();
+		assert_eq!(report.violations, vec![Violation {
+			prefix: (vec![], None),
+			hash: root,
+			kind: ViolationKind::UnderfullBranch { children: 1 },
+		}]);
+	}
+}
@@ -0,0 +1,129 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Node reads of `TrieDB::get_batch` against independent lookups.
+
+use std::cell::Cell;
+use std::collections::HashSet;
+
+use hash_db::{HashDBRef, Hasher, EMPTY_PREFIX};
+use keccak_hasher::KeccakHasher;
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{DBValue, FnHashDB, Trie, TrieDB, TrieError};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+
+/// Database counting the node reads in `reads`.
+fn counting_db<'a>(
+	db: &'a MemoryDB,
+	reads: &'a Cell<usize>,
+) -> impl HashDBRef<KeccakHasher, DBValue> + 'a {
+	FnHashDB::new(move |key: &_, prefix| {
+		reads.set(reads.get() + 1);
+		HashDBRef::get(db, key, prefix)
+	})
+}
+
+/// 500 keys sharing a 16 bytes prefix within each of 5 groups, as storage keys of a few accounts.
+fn keys() -> Vec<Vec<u8>> {
+	(0..500u32).map(|i| {
+		let mut key = vec![(i % 5) as u8; 16];
+		key.extend_from_slice(&KeccakHasher::hash(&i.to_le_bytes())[..16]);
+		key
+	}).collect()
+}
+
+fn build_trie<L: TrieLayout<Hash = KeccakHasher>>() -> BuiltTrie<L> {
+	TrieBuilder::new()
+		.extend(keys().into_iter().enumerate().map(|(i, key)| (key, vec![i as u8; 1 + i % 40])))
+		.build()
+}
+
+fn check_batch<L: TrieLayout<Hash = KeccakHasher>>() {
+	let built = build_trie::<L>();
+	let reads = Cell::new(0);
+	let counting = counting_db(built.db(), &reads);
+	let trie = TrieDB::<L>::new(&counting, built.root()).unwrap();
+
+	// present and absent keys, unsorted, with keys ending inside the shared prefixes.
+	let mut keys = keys();
+	keys.reverse();
+	keys.extend(vec![vec![1; 16], vec![1; 8], vec![9; 32], Vec::new()]);
+	let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+
+	reads.set(0);
+	let expected: Vec<_> = keys.iter().map(|key| trie.get(key).unwrap()).collect();
+	let independent_reads = reads.get();
+
+	reads.set(0);
+	let values: Vec<_> = trie.get_batch(&keys).into_iter().map(|value| value.unwrap()).collect();
+	let batch_reads = reads.get();
+	assert_eq!(values, expected);
+	// the nodes on the paths of the keys are fetched once.
+	let nodes: HashSet<_> = keys.iter()
+		.filter_map(|key| trie.get_with_path(key).unwrap())
+		.flat_map(|(_, path)| path.into_iter().map(|(_, hash)| hash))
+		.collect();
+	// the absent keys end on the paths of present keys.
+	assert_eq!(batch_reads, nodes.len());
+	assert!(batch_reads * 3 < independent_reads, "{} against {}", batch_reads, independent_reads);
+
+	// repeated keys do not read more nodes.
+	let repeated: Vec<&[u8]> = keys.iter().chain(keys.iter().step_by(3)).cloned().collect();
+	reads.set(0);
+	let values: Vec<_> = trie.get_batch(&repeated).into_iter().map(|value| value.unwrap()).collect();
+	assert_eq!(reads.get(), batch_reads);
+	assert_eq!(&values[..keys.len()], &expected[..]);
+	assert_eq!(&values[keys.len()..], &expected.iter().step_by(3).cloned().collect::<Vec<_>>()[..]);
+
+	assert!(trie.get_batch(&[]).is_empty());
+}
+
+#[test]
+fn batch_shares_nodes_with_extension() {
+	check_batch::<ExtensionLayout>();
+}
+
+#[test]
+fn batch_shares_nodes_without_extension() {
+	check_batch::<NoExtensionLayout>();
+}
+
+#[test]
+fn batch_reports_errors_per_key() {
+	let mut built = build_trie::<ExtensionLayout>();
+	let keys = keys();
+	// remove the leaf of the first key: only its lookup fails.
+	let (missing, prefix) = {
+		let (_, path) = built.trie().get_with_path(&keys[0]).unwrap().unwrap();
+		let (prefix, hash) = path.last().unwrap().clone();
+		(hash, prefix)
+	};
+	assert_ne!(prefix.as_prefix(), EMPTY_PREFIX);
+	built.remove_node(&missing);
+
+	let trie = built.trie();
+	let batch: Vec<&[u8]> = vec![&keys[1], &keys[0], &keys[5], &keys[0]];
+	let results = trie.get_batch(&batch);
+	for (key, result) in batch.iter().zip(results) {
+		match key == &&keys[0][..] {
+			true => match *result.unwrap_err() {
+				TrieError::IncompleteDatabase { hash, .. } => assert_eq!(hash, missing),
+				ref error => panic!("unexpected error {:?}", error),
+			},
+			false => assert_eq!(result.unwrap(), trie.get(key).unwrap()),
+		}
+	}
+}
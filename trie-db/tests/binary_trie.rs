@@ -0,0 +1,188 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tries with 1-bit nibbles, with the binary layout of the reference trie.
+
+use std::collections::BTreeMap;
+
+use hash_db::Hasher;
+use keccak_hasher::KeccakHasher;
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{BinaryLayout, NoExtensionLayout, TrieLayout};
+use trie_db::node::Node;
+use trie_db::proof::{generate_proof, verify_proof};
+use trie_db::{
+	decode_compact, encode_compact, trie_visit, DBValue, NodeCodec, Trie, TrieDB, TrieDBMut,
+	TrieDBNodeIterator, TrieError, TrieMut,
+};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+type Pairs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn pairs() -> Pairs {
+	let mut pairs = Pairs::new();
+	for i in 0..200u32 {
+		let key = KeccakHasher::hash(&i.to_le_bytes());
+		let len = 1 + (i as usize % 5);
+		pairs.insert(key[..len].to_vec(), vec![i as u8; 1 + (i as usize % 40)]);
+	}
+	pairs.insert(Vec::new(), b"empty key".to_vec());
+	pairs.insert(vec![0xff; 8], b"long key".to_vec());
+	pairs
+}
+
+fn build<L: TrieLayout<Hash = KeccakHasher>>(pairs: &Pairs) -> BuiltTrie<L> {
+	TrieBuilder::new().extend(pairs).build()
+}
+
+#[test]
+fn binary_trie_reads_its_pairs_back() {
+	let pairs = pairs();
+	let built = build::<BinaryLayout>(&pairs);
+	let trie = built.trie();
+	for (key, value) in &pairs {
+		assert_eq!(trie.get(key).unwrap(), Some(value.clone()));
+		assert!(trie.contains(key).unwrap());
+	}
+	assert_eq!(trie.get(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]).unwrap(), None);
+	let read: Pairs = trie.iter().unwrap().map(|item| item.unwrap()).collect();
+	assert_eq!(read, pairs);
+}
+
+#[test]
+fn binary_trie_iterator_seeks() {
+	let pairs = pairs();
+	let built = build::<BinaryLayout>(&pairs);
+	let trie = built.trie();
+	for seek in [vec![0x80], vec![0x12, 0x34], vec![0xff; 8]] {
+		let mut iter = trie.iter().unwrap();
+		iter.seek(&seek).unwrap();
+		let keys: Vec<_> = iter.map(|item| item.unwrap().0).collect();
+		let expected: Vec<_> = pairs.range(seek.clone()..).map(|(key, _)| key.clone()).collect();
+		assert_eq!(keys, expected);
+	}
+}
+
+#[test]
+fn binary_trie_branches_have_two_children() {
+	let built = build::<BinaryLayout>(&pairs());
+	let trie = built.trie();
+	let mut branches = 0;
+	for item in TrieDBNodeIterator::new(&trie).unwrap() {
+		let (_, _, node) = item.unwrap();
+		match node.node() {
+			Node::NibbledBranch(partial, children, _) => {
+				assert!((0..partial.len()).all(|i| partial.at(i) <= 1));
+				assert_eq!(children.len(), 2);
+				branches += 1;
+			},
+			Node::Leaf(partial, _) => assert!((0..partial.len()).all(|i| partial.at(i) <= 1)),
+			Node::Empty => (),
+			node => panic!("unexpected node in a binary trie: {:?}", node),
+		}
+	}
+	assert!(branches > 0);
+}
+
+#[test]
+fn binary_trie_is_built_the_same_by_trie_visit() {
+	let pairs = pairs();
+	let built = build::<BinaryLayout>(&pairs);
+	let mut db = MemoryDB::default();
+	let mut visitor = trie_db::TrieBuilder::new(&mut db);
+	trie_visit::<BinaryLayout, _, _, _, _>(pairs.iter(), &mut visitor);
+	assert_eq!(visitor.root, Some(*built.root()));
+}
+
+#[test]
+fn binary_trie_removes_its_pairs() {
+	let pairs = pairs();
+	let (mut db, mut root) = build::<BinaryLayout>(&pairs).into_parts();
+	let half: Pairs = pairs.iter().step_by(2).map(|(k, v)| (k.clone(), v.clone())).collect();
+	{
+		let mut trie = TrieDBMut::<BinaryLayout>::from_existing(&mut db, &mut root).unwrap();
+		for key in pairs.keys().skip(1).step_by(2) {
+			assert!(trie.remove(key).unwrap().is_some());
+		}
+	}
+	assert_eq!(root, *build::<BinaryLayout>(&half).root());
+	{
+		let mut trie = TrieDBMut::<BinaryLayout>::from_existing(&mut db, &mut root).unwrap();
+		for key in half.keys() {
+			trie.remove(key).unwrap();
+		}
+	}
+	assert_eq!(root, <BinaryLayout as TrieLayout>::Codec::hashed_null_node());
+}
+
+/// Number of children of the branches of `proof`, encoded with `L`.
+fn proof_children<L: TrieLayout>(proof: &[Vec<u8>]) -> Vec<usize> {
+	proof.iter().filter_map(|encoded| {
+		match L::Codec::decode(encoded).unwrap() {
+			Node::NibbledBranch(_, children, _) =>
+				Some(children.iter().filter(|child| child.is_some()).count()),
+			_ => None,
+		}
+	}).collect()
+}
+
+#[test]
+fn binary_trie_proofs_hold_one_sibling_per_level() {
+	let pairs = pairs();
+	let built = build::<BinaryLayout>(&pairs);
+	let trie = built.trie();
+	let (key, value) = pairs.iter().nth(57).unwrap();
+	let proof = generate_proof(&trie, &[key]).unwrap();
+	let items = [(key.clone(), Some(value.clone()))];
+	verify_proof::<BinaryLayout, _, _, _>(built.root(), &proof, items.iter()).unwrap();
+	let children = proof_children::<BinaryLayout>(&proof);
+	assert!(!children.is_empty());
+	assert!(children.iter().all(|&count| count <= 2));
+	let absent = [(vec![0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc], None::<Vec<u8>>)];
+	let proof = generate_proof(&trie, &[&absent[0].0]).unwrap();
+	verify_proof::<BinaryLayout, _, _, _>(built.root(), &proof, absent.iter()).unwrap();
+
+	// the same pairs in a hex trie, whose branches hold up to 15 siblings.
+	let hex = build::<NoExtensionLayout>(&pairs);
+	let hex_proof = generate_proof(&hex.trie(), &[key]).unwrap();
+	assert!(proof_children::<NoExtensionLayout>(&hex_proof).iter().any(|&count| count > 2));
+}
+
+#[test]
+fn binary_trie_proves_absence() {
+	let built = build::<BinaryLayout>(&pairs());
+	let trie = built.trie();
+	for key in [&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc][..], &[0xff; 9][..]] {
+		let proof = trie.prove_absence(key).unwrap().unwrap();
+		proof.verify::<BinaryLayout>(built.root(), key).unwrap();
+	}
+}
+
+#[test]
+fn binary_trie_compact_encoding_round_trips() {
+	let built = build::<BinaryLayout>(&pairs());
+	let encoded = encode_compact::<BinaryLayout>(&built.trie()).unwrap();
+	let mut db = MemoryDB::default();
+	let (root, used) = decode_compact::<BinaryLayout, _, _>(&mut db, &encoded).unwrap();
+	assert_eq!(root, *built.root());
+	assert_eq!(used, encoded.len());
+}
+
+#[test]
+fn binary_codec_rejects_hex_nodes() {
+	let (db, root) = build::<NoExtensionLayout>(&pairs()).into_parts();
+	let trie = TrieDB::<BinaryLayout>::new(&db, &root).unwrap();
+	let error = trie.get(&[0x12, 0x34]).unwrap_err();
+	assert!(matches!(*error, TrieError::DecoderError(..)));
+}
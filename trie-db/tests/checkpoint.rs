@@ -0,0 +1,141 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checkpoints of the uncommitted changes of `TrieDBMut`, nested and rolled back.
+
+use keccak_hasher::KeccakHasher;
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{CheckpointError, DBValue, Trie, TrieMut};
+
+fn key(i: u32) -> Vec<u8> {
+	// keys sharing prefixes of various lengths.
+	vec![(i % 3) as u8, (i % 7) as u8, (i % 5) as u8, i as u8]
+}
+
+fn build_trie<L: TrieLayout<Hash = KeccakHasher>>() -> BuiltTrie<L> {
+	TrieBuilder::new().extend((0..100).map(|i| (key(i), [i as u8; 40]))).build()
+}
+
+fn pairs<L: TrieLayout>(built: &BuiltTrie<L>) -> Vec<(Vec<u8>, DBValue)> {
+	built.trie().iter().unwrap().map(|item| item.unwrap()).collect()
+}
+
+fn check_nested_rollbacks<L: TrieLayout<Hash = KeccakHasher>>() {
+	let mut built = build_trie::<L>();
+	let (initial_db, initial_root) = (built.db().clone(), *built.root());
+	let mut roots = Vec::new();
+	{
+		let mut t = built.trie_mut();
+		// a checkpoint before each round of changes, rolled back innermost first.
+		let mut checkpoints = Vec::new();
+		for round in 0..5u32 {
+			checkpoints.push(t.checkpoint());
+			roots.push(t.root_preview());
+			for i in 0..20 {
+				let i = round * 20 + i;
+				match i % 3 {
+					0 => t.remove(&key(i)).unwrap(),
+					_ => t.insert(&key(i + 50), &[round as u8 + 1; 36]).unwrap(),
+				};
+			}
+		}
+		for checkpoint in checkpoints.into_iter().rev() {
+			let changed = t.root_preview();
+			t.rollback_to(checkpoint).unwrap();
+			assert_ne!(t.root_preview(), changed);
+			assert_eq!(t.root_preview(), roots.pop().unwrap());
+		}
+		assert_eq!(t.root_preview(), initial_root);
+		assert_eq!(t.get(&key(0)).unwrap(), Some(vec![0; 40]));
+	}
+	assert!(*built.db() == initial_db);
+	assert_eq!(*built.root(), initial_root);
+}
+
+#[test]
+fn nested_rollbacks_with_extension() {
+	check_nested_rollbacks::<ExtensionLayout>();
+}
+
+#[test]
+fn nested_rollbacks_without_extension() {
+	check_nested_rollbacks::<NoExtensionLayout>();
+}
+
+#[test]
+fn rollback_to_an_outer_checkpoint() {
+	let mut built = build_trie::<ExtensionLayout>();
+	let mut expected = build_trie::<ExtensionLayout>();
+	expected.trie_mut().remove(&key(1)).unwrap();
+	{
+		let mut t = built.trie_mut();
+		t.remove(&key(1)).unwrap();
+		let outer = t.checkpoint();
+		t.remove(&key(2)).unwrap();
+		let inner = t.checkpoint();
+		t.insert(&key(200), &[1; 40]).unwrap();
+		t.rollback_to(outer).unwrap();
+
+		// the inner checkpoint went along with the outer one.
+		assert_eq!(t.rollback_to(inner), Err(CheckpointError::Released));
+		assert_eq!(t.discard_checkpoint(outer), Err(CheckpointError::Released));
+		assert_eq!(t.get(&key(2)).unwrap(), Some(vec![2; 40]));
+		assert_eq!(t.get(&key(200)).unwrap(), None);
+	}
+	// the changes from before the outer checkpoint are committed as if alone.
+	assert_eq!(built.root(), expected.root());
+	assert!(built.db() == expected.db());
+}
+
+#[test]
+fn discarded_checkpoints_keep_the_changes() {
+	let mut built = build_trie::<NoExtensionLayout>();
+	{
+		let mut t = built.trie_mut();
+		let outer = t.checkpoint();
+		t.insert(&key(200), &[1; 40]).unwrap();
+		let inner = t.checkpoint();
+		t.remove(&key(3)).unwrap();
+		t.discard_checkpoint(inner).unwrap();
+		assert_eq!(t.rollback_to(inner), Err(CheckpointError::Released));
+		assert_eq!(t.get(&key(3)).unwrap(), None);
+
+		// rolling back to the outer checkpoint still drops both changes.
+		let checkpoint = t.checkpoint();
+		t.rollback_to(outer).unwrap();
+		assert_eq!(t.discard_checkpoint(checkpoint), Err(CheckpointError::Released));
+		assert_eq!(t.get(&key(3)).unwrap(), Some(vec![3; 40]));
+		assert_eq!(t.get(&key(200)).unwrap(), None);
+	}
+	assert_eq!(pairs(&built).len(), 100);
+}
+
+#[test]
+fn commits_release_the_checkpoints() {
+	let mut built = build_trie::<ExtensionLayout>();
+	let mut t = built.trie_mut();
+	let before = t.checkpoint();
+	t.insert(&key(200), &[1; 40]).unwrap();
+	let committed = *t.root();
+	assert_eq!(t.rollback_to(before), Err(CheckpointError::Committed));
+	assert_eq!(t.discard_checkpoint(before), Err(CheckpointError::Committed));
+
+	// checkpoints taken since the commit go back to it.
+	let after = t.checkpoint();
+	t.remove(&key(200)).unwrap();
+	t.rollback_to(after).unwrap();
+	assert_eq!(*t.root(), committed);
+	assert_eq!(t.get(&key(200)).unwrap(), Some(vec![1; 40]));
+}
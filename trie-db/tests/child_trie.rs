@@ -0,0 +1,197 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Child tries kept in the keyspace of their key, through `TrieDBMut::child_trie_mut` and
+//! `TrieDB::child_trie`.
+
+use std::collections::BTreeMap;
+
+use hash_db::{HashDBRef, Hasher, OwnedPrefix, EMPTY_PREFIX};
+use keccak_hasher::KeccakHasher;
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::child_trie::{child_keyspace, KeySpacedDB};
+use trie_db::{DBValue, Trie, TrieDB, TrieDBMut, TrieDBNodeIterator, TrieError, TrieMut};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+type Root = <KeccakHasher as Hasher>::Out;
+type Pairs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn child_pairs() -> Pairs {
+	(0..20u8).map(|i| (vec![i / 4, i], vec![i; 40])).collect()
+}
+
+fn read_child<L: TrieLayout<Hash = KeccakHasher>>(db: &MemoryDB, root: &Root, key: &[u8]) -> Pairs {
+	let parent = TrieDB::<L>::new(db, root).unwrap();
+	let child = parent.child_trie(key).unwrap();
+	child.iter().unwrap().map(|item| item.unwrap()).collect()
+}
+
+/// The hashes and prefixes of the nodes of the child trie at `key`.
+fn child_nodes<L: TrieLayout<Hash = KeccakHasher>>(
+	db: &MemoryDB,
+	root: &Root,
+	key: &[u8],
+) -> Vec<(Root, OwnedPrefix)> {
+	let parent = TrieDB::<L>::new(db, root).unwrap();
+	let child = parent.child_trie(key).unwrap();
+	let trie = child.trie();
+	TrieDBNodeIterator::new(&trie).unwrap()
+		.filter_map(|item| {
+			let (prefix, hash, _) = item.unwrap();
+			let (key, last) = prefix.as_prefix();
+			hash.map(|hash| (hash, (key.to_vec(), last)))
+		})
+		.collect()
+}
+
+fn check_child_tries<L: TrieLayout<Hash = KeccakHasher>>() {
+	let pairs = child_pairs();
+	let expected_root = {
+		let mut db = MemoryDB::default();
+		let mut root = Default::default();
+		let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+		for (key, value) in pairs.iter() {
+			t.insert(key, value).unwrap();
+		}
+		*t.root()
+	};
+
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+		t.insert(b"alfa", &[1; 40]).unwrap();
+		for parent_key in [&b"child1"[..], b"child2"].iter() {
+			t.child_trie_mut(parent_key, |child| {
+				for (key, value) in pairs.iter() {
+					child.insert(key, value)?;
+				}
+				Ok(())
+			}).unwrap();
+			assert_eq!(t.get(parent_key).unwrap(), Some(expected_root.as_ref().to_vec()));
+		}
+	}
+	assert_eq!(read_child::<L>(&db, &root, b"child1"), pairs);
+	assert_eq!(read_child::<L>(&db, &root, b"child2"), pairs);
+	{
+		let parent = TrieDB::<L>::new(&db, &root).unwrap();
+		let child = parent.child_trie(b"child1").unwrap();
+		assert_eq!(child.root(), &expected_root);
+		assert_eq!(child.get(&[0, 1]).unwrap(), Some(vec![1; 40]));
+		assert_eq!(child.get(b"alfa").unwrap(), None);
+	}
+
+	// the nodes of the two child tries are kept apart, none in the keyspace of the parent.
+	let nodes1 = child_nodes::<L>(&db, &root, b"child1");
+	let nodes2 = child_nodes::<L>(&db, &root, b"child2");
+	assert_eq!(nodes1.len(), nodes2.len());
+	for (hash, prefix) in nodes1.iter() {
+		let prefix = (&prefix.0[..], prefix.1);
+		for key in [&b"child1"[..], b"child2"].iter() {
+			let keyspaced = KeySpacedDB::new(&db, child_keyspace::<KeccakHasher>(key));
+			assert!(keyspaced.contains(hash, prefix));
+		}
+		assert!(!HashDBRef::contains(&db, hash, prefix));
+	}
+
+	// a child trie without key is empty.
+	{
+		let parent = TrieDB::<L>::new(&db, &root).unwrap();
+		let child = parent.child_trie(b"child3").unwrap();
+		assert!(child.is_empty());
+		assert_eq!(child.get(&[0, 1]).unwrap(), None);
+		assert_eq!(child.iter().unwrap().count(), 0);
+	}
+
+	// an update of the first child trie leaves the second one untouched, and a failed update
+	// leaves both untouched.
+	let mut updated = pairs.clone();
+	updated.remove(&vec![0, 1]);
+	updated.insert(b"new".to_vec(), vec![2; 40]);
+	{
+		let mut t = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap();
+		t.child_trie_mut(b"child1", |child| {
+			child.remove(&[0, 1])?;
+			child.insert(b"new", &[2; 40]).map(|_| ())
+		}).unwrap();
+		let failed = t.child_trie_mut(b"child2", |child| {
+			child.insert(b"new", &[2; 40])?;
+			Err::<(), _>(Box::new(TrieError::IncompleteDatabase {
+				hash: Default::default(),
+				prefix: (Vec::new(), None),
+			}))
+		});
+		assert!(failed.is_err());
+		assert_eq!(t.get(b"child2").unwrap(), Some(expected_root.as_ref().to_vec()));
+	}
+	assert_eq!(read_child::<L>(&db, &root, b"child1"), updated);
+	assert_eq!(read_child::<L>(&db, &root, b"child2"), pairs);
+
+	// removing a child trie purges its nodes on demand.
+	let nodes1 = child_nodes::<L>(&db, &root, b"child1");
+	{
+		let mut t = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap();
+		assert_ne!(t.remove_child_trie(b"child1", true).unwrap(), None);
+		assert_eq!(t.remove_child_trie(b"child2", false).unwrap(), Some(expected_root));
+		assert_eq!(t.remove_child_trie(b"child3", true).unwrap(), None);
+		assert_eq!(t.get(b"child1").unwrap(), None);
+	}
+	let keyspaced = KeySpacedDB::new(&db, child_keyspace::<KeccakHasher>(b"child1"));
+	for (hash, prefix) in nodes1.iter() {
+		assert!(!keyspaced.contains(hash, (&prefix.0, prefix.1)));
+	}
+	let keyspaced = KeySpacedDB::new(&db, child_keyspace::<KeccakHasher>(b"child2"));
+	assert!(keyspaced.contains(&expected_root, EMPTY_PREFIX));
+	assert!(TrieDB::<L>::new(&db, &root).unwrap().child_trie(b"child2").unwrap().is_empty());
+
+	// a child trie emptied by an update has no key.
+	{
+		let mut t = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap();
+		t.child_trie_mut(b"child4", |child| child.insert(b"a", &[3; 40]).map(|_| ())).unwrap();
+		assert!(t.get(b"child4").unwrap().is_some());
+		t.child_trie_mut(b"child4", |child| child.remove(b"a").map(|_| ())).unwrap();
+		assert_eq!(t.get(b"child4").unwrap(), None);
+	}
+	assert_eq!(TrieDB::<L>::new(&db, &root).unwrap().iter().unwrap().count(), 1);
+}
+
+#[test]
+fn child_tries_with_extension() {
+	check_child_tries::<ExtensionLayout>();
+}
+
+#[test]
+fn child_tries_without_extension() {
+	check_child_tries::<NoExtensionLayout>();
+}
+
+#[test]
+fn child_trie_values_must_be_roots() {
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut t = TrieDBMut::<ExtensionLayout>::new(&mut db, &mut root);
+		t.insert(b"child", b"not a root").unwrap();
+		let result = t.child_trie_mut(b"child", |_| Ok(()));
+		assert!(matches!(result.map_err(|e| *e), Err(TrieError::InvalidHash(..))));
+	}
+	let parent = TrieDB::<ExtensionLayout>::new(&db, &root).unwrap();
+	match parent.child_trie(b"child").map_err(|e| *e) {
+		Err(TrieError::InvalidHash(hash, value)) => {
+			assert_eq!(hash, root);
+			assert_eq!(value, b"not a root".to_vec());
+		},
+		_ => panic!("the value of the key is not a root"),
+	}
+}
@@ -0,0 +1,234 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `trie_diff` against the differences of the pairs of two tries, and the nodes it reads.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use hash_db::{HashDBRef, Hasher};
+use keccak_hasher::KeccakHasher;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{
+	node::{Node, NodeHandle}, trie_diff, DBValue, DiffItem, FnHashDB, TrieDB, TrieDBMut,
+	TrieDBNodeIterator, TrieMut,
+};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+type Root = <KeccakHasher as Hasher>::Out;
+type Pairs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn random_value(rng: &mut SmallRng) -> Vec<u8> {
+	// short values make inline nodes.
+	let len = match rng.gen() {
+		true => rng.gen_range(1, 4),
+		false => rng.gen_range(30, 40),
+	};
+	(0..len).map(|_| rng.gen()).collect()
+}
+
+/// Remove `removed` from the trie at `root` in `db` and insert `pairs`, returning the new root.
+/// The nodes of both tries are kept.
+fn update<L: TrieLayout<Hash = KeccakHasher>>(
+	db: &mut MemoryDB,
+	root: &Root,
+	removed: &[Vec<u8>],
+	pairs: &Pairs,
+) -> Root {
+	let mut new_db = db.clone();
+	let mut new_root = *root;
+	{
+		let mut trie = match *root == Root::default() {
+			true => TrieDBMut::<L>::new(&mut new_db, &mut new_root),
+			false => TrieDBMut::<L>::from_existing(&mut new_db, &mut new_root).unwrap(),
+		};
+		for key in removed {
+			trie.remove(key).unwrap();
+		}
+		for (key, value) in pairs {
+			trie.insert(key, value).unwrap();
+		}
+	}
+	db.consolidate(new_db);
+	new_root
+}
+
+fn expected_diff(from: &Pairs, to: &Pairs) -> Vec<DiffItem> {
+	let keys: BTreeSet<_> = from.keys().chain(to.keys()).collect();
+	keys.into_iter().filter_map(|key| match (from.get(key), to.get(key)) {
+		(Some(old), Some(new)) if old == new => None,
+		(Some(old), Some(new)) => Some(DiffItem::Changed(key.clone(), old.clone(), new.clone())),
+		(Some(old), None) => Some(DiffItem::Removed(key.clone(), old.clone())),
+		(None, Some(new)) => Some(DiffItem::Added(key.clone(), new.clone())),
+		(None, None) => None,
+	}).collect()
+}
+
+fn diff<L: TrieLayout<Hash = KeccakHasher>>(
+	db: &dyn HashDBRef<KeccakHasher, DBValue>,
+	from: &Root,
+	to: &Root,
+) -> Vec<DiffItem> {
+	let from = TrieDB::<L>::new(db, from).unwrap();
+	let to = TrieDB::<L>::new(db, to).unwrap();
+	let items = trie_diff(&from, &to).unwrap().map(|item| item.unwrap()).collect();
+	items
+}
+
+fn check_random_diffs<L: TrieLayout<Hash = KeccakHasher>>() {
+	for seed in 0..300 {
+		let mut rng = SmallRng::seed_from_u64(seed);
+		// few distinct nibbles make branches, extensions and shared subtrees.
+		let alphabet = [0x00, 0x01, 0x10, 0x11, 0xf0];
+		let key = |rng: &mut SmallRng| -> Vec<u8> {
+			(0..rng.gen_range(0, 4)).map(|_| alphabet[rng.gen_range(0, alphabet.len())]).collect()
+		};
+		let from: Pairs = (0..rng.gen_range(0, 60)).map(|_| (key(&mut rng), random_value(&mut rng)))
+			.collect();
+		let mut to = from.clone();
+		let mut removed = Vec::new();
+		for _ in 0..rng.gen_range(0, 8) {
+			match rng.gen_range(0, 3) {
+				0 => { to.insert(key(&mut rng), random_value(&mut rng)); },
+				1 => if let Some(key) = from.keys().nth(rng.gen_range(0, from.len().max(1))) {
+					to.insert(key.clone(), random_value(&mut rng));
+				},
+				_ => {
+					let key = key(&mut rng);
+					to.remove(&key);
+					removed.push(key);
+				},
+			}
+		}
+
+		let mut db = MemoryDB::default();
+		let from_root = update::<L>(&mut db, &Root::default(), &[], &from);
+		let to_root = update::<L>(&mut db, &from_root, &removed, &to);
+		let (forward, backward) = (expected_diff(&from, &to), expected_diff(&to, &from));
+		assert_eq!(diff::<L>(&db, &from_root, &to_root), forward, "seed {}", seed);
+		assert_eq!(diff::<L>(&db, &to_root, &from_root), backward, "seed {}", seed);
+	}
+}
+
+#[test]
+fn random_diffs_with_extension() {
+	check_random_diffs::<ExtensionLayout>();
+}
+
+#[test]
+fn random_diffs_without_extension() {
+	check_random_diffs::<NoExtensionLayout>();
+}
+
+/// The hashes of the nodes of the trie at `root`, with the hashes of their children.
+fn nodes<L: TrieLayout<Hash = KeccakHasher>>(db: &MemoryDB, root: &Root) -> Vec<(Root, Vec<Root>)> {
+	let trie = TrieDB::<L>::new(db, root).unwrap();
+	TrieDBNodeIterator::new(&trie).unwrap().filter_map(|item| {
+		let (_, hash, node) = item.unwrap();
+		let children = match node.node() {
+			Node::Extension(_, child) => vec![child],
+			Node::Branch(children, _) | Node::NibbledBranch(_, children, _) =>
+				children.iter().flatten().cloned().collect(),
+			_ => Vec::new(),
+		};
+		let children = children.into_iter().filter_map(|child| match child {
+			NodeHandle::Hash(hash) => {
+				let mut child = Root::default();
+				child.as_mut().copy_from_slice(hash);
+				Some(child)
+			},
+			NodeHandle::Inline(_) => None,
+		}).collect();
+		hash.map(|hash| (hash, children))
+	}).collect()
+}
+
+fn check_unchanged_subtrees_are_skipped<L: TrieLayout<Hash = KeccakHasher>>() {
+	let mut rng = SmallRng::seed_from_u64(7);
+	let from: Pairs = (0..1000).map(|_| {
+		let key: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
+		(key, (0..40).map(|_| rng.gen()).collect())
+	}).collect();
+	let mut to = from.clone();
+	for key in from.keys().step_by(200) {
+		to.insert(key.clone(), vec![1; 40]);
+	}
+	let mut db = MemoryDB::default();
+	let from_root = update::<L>(&mut db, &Root::default(), &[], &from);
+	let to_root = update::<L>(&mut db, &from_root, &[], &to);
+
+	let reads = RefCell::new(Vec::new());
+	let counting_db = FnHashDB::new(|key: &Root, prefix| {
+		reads.borrow_mut().push(*key);
+		HashDBRef::get(&db, key, prefix)
+	});
+	assert_eq!(diff::<L>(&counting_db, &from_root, &to_root), expected_diff(&from, &to));
+
+	// only the changed nodes and their children are read, the children being compared by hash.
+	let (from_nodes, to_nodes) = (nodes::<L>(&db, &from_root), nodes::<L>(&db, &to_root));
+	let from_hashes: HashSet<_> = from_nodes.iter().map(|(hash, _)| *hash).collect();
+	let to_hashes: HashSet<_> = to_nodes.iter().map(|(hash, _)| *hash).collect();
+	let mut expected_reads: HashSet<_> = [from_root, to_root].iter().cloned().collect();
+	for ((hash, children), other) in from_nodes.iter().map(|node| (node, &to_hashes))
+		.chain(to_nodes.iter().map(|node| (node, &from_hashes)))
+	{
+		if !other.contains(hash) {
+			expected_reads.insert(*hash);
+			expected_reads.extend(children.iter().cloned());
+		}
+	}
+	let reads = reads.into_inner();
+	assert!(reads.iter().all(|hash| expected_reads.contains(hash)));
+	assert!(reads.len() * 10 < from_nodes.len() + to_nodes.len());
+}
+
+#[test]
+fn unchanged_subtrees_are_skipped_with_extension() {
+	check_unchanged_subtrees_are_skipped::<ExtensionLayout>();
+}
+
+#[test]
+fn unchanged_subtrees_are_skipped_without_extension() {
+	check_unchanged_subtrees_are_skipped::<NoExtensionLayout>();
+}
+
+#[test]
+fn identical_tries_have_no_differences() {
+	let mut rng = SmallRng::seed_from_u64(3);
+	let pairs: Pairs = (0..100).map(|i: u32| (i.to_be_bytes().to_vec(), random_value(&mut rng)))
+		.collect();
+	let mut db = MemoryDB::default();
+	let root = update::<ExtensionLayout>(&mut db, &Root::default(), &[], &pairs);
+
+	let reads = RefCell::new(0);
+	let counting_db = FnHashDB::new(|key: &Root, prefix| {
+		*reads.borrow_mut() += 1;
+		HashDBRef::get(&db, key, prefix)
+	});
+	let before = {
+		let trie = TrieDB::<ExtensionLayout>::new(&counting_db, &root).unwrap();
+		let _ = TrieDBNodeIterator::new(&trie).unwrap();
+		*reads.borrow()
+	};
+	*reads.borrow_mut() = 0;
+	assert_eq!(diff::<ExtensionLayout>(&counting_db, &root, &root), Vec::new());
+	// the roots are compared without reading their children.
+	assert_eq!(*reads.borrow(), 2 * before);
+
+	let empty = update::<ExtensionLayout>(&mut db, &Root::default(), &[], &Pairs::new());
+	assert_eq!(diff::<ExtensionLayout>(&db, &empty, &empty), Vec::new());
+	let added: Vec<_> = pairs.iter().map(|(k, v)| DiffItem::Added(k.clone(), v.clone())).collect();
+	assert_eq!(diff::<ExtensionLayout>(&db, &empty, &root), added);
+}
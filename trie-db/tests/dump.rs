@@ -0,0 +1,130 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dumps of the nodes of a trie with `TrieDB::fmt_tree` and `TrieDB::fmt_dot`.
+
+use keccak_hasher::KeccakHasher;
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{DumpOptions, Trie, TrieDBNodeIterator};
+
+/// Three keys, two of them under an extension or a branch with partial key `2`, the leaf with
+/// the long value being the only leaf not inlined.
+fn build<L: TrieLayout<Hash = KeccakHasher>>() -> BuiltTrie<L> {
+	TrieBuilder::new()
+		.insert([0x12, 0x34], [0x01, 0x02])
+		.insert([0x12, 0x56], [0xaa; 40])
+		.insert([0x78], [0x03])
+		.build()
+}
+
+fn tree<L: TrieLayout>(built: &BuiltTrie<L>, options: DumpOptions) -> String {
+	let mut out = String::new();
+	built.trie().fmt_tree(&mut out, options).unwrap();
+	out
+}
+
+fn without_hashes() -> DumpOptions {
+	DumpOptions { hashes: false, ..Default::default() }
+}
+
+#[test]
+fn tree_shows_node_types_partials_values_and_children() {
+	let built = build::<ExtensionLayout>();
+	assert_eq!(tree(&built, without_hashes()), concat!(
+		"branch children .1.....7........\n",
+		"  1: extension partial 2\n",
+		"    branch children ...3.5..........\n",
+		"      3: leaf partial 4 value 0x0102\n",
+		"      5: leaf partial 6 value <40 bytes>\n",
+		"  7: leaf partial 8 value 0x03\n",
+	));
+
+	let built = build::<NoExtensionLayout>();
+	let options = DumpOptions { max_inline_value: 40, ..without_hashes() };
+	assert_eq!(tree(&built, options), format!(concat!(
+		"branch children .1.....7........\n",
+		"  1: branch partial 2 children ...3.5..........\n",
+		"    3: leaf partial 4 value 0x0102\n",
+		"    5: leaf partial 6 value 0x{}\n",
+		"  7: leaf partial 8 value 0x03\n",
+	), "aa".repeat(40)));
+}
+
+#[test]
+fn tree_shows_hashes() {
+	let built = build::<ExtensionLayout>();
+	let out = tree(&built, DumpOptions::default());
+	let lines: Vec<_> = out.lines().collect();
+	let root_hex: String = built.root().iter().map(|byte| format!("{:02x}", byte)).collect();
+	assert_eq!(lines[0], format!("branch children .1.....7........ hash 0x{}", root_hex));
+	assert_eq!(lines[3], "      3: leaf partial 4 value 0x0102 inline");
+	assert!(lines[4].starts_with("      5: leaf partial 6 value <40 bytes> hash 0x"));
+}
+
+#[test]
+fn depth_and_prefix_restrict_the_tree() {
+	let built = build::<ExtensionLayout>();
+	let options = DumpOptions { max_depth: Some(1), ..without_hashes() };
+	assert_eq!(tree(&built, options), concat!(
+		"branch children .1.....7........\n",
+		"  1: extension partial 2\n",
+		"  7: leaf partial 8 value 0x03\n",
+	));
+
+	let options = DumpOptions { prefix: vec![0x12, 0x56], ..without_hashes() };
+	assert_eq!(tree(&built, options), concat!(
+		"branch children .1.....7........\n",
+		"  1: extension partial 2\n",
+		"    branch children ...3.5..........\n",
+		"      5: leaf partial 6 value <40 bytes>\n",
+	));
+}
+
+#[test]
+fn missing_nodes_are_shown() {
+	let mut built = build::<ExtensionLayout>();
+	let hash = TrieDBNodeIterator::new(&built.trie()).unwrap()
+		.map(|item| item.unwrap())
+		.filter(|(key, hash, _)| key.len() == 3 && hash.is_some())
+		.map(|(_, hash, _)| hash.unwrap())
+		.next()
+		.unwrap();
+	built.remove_node(&hash);
+
+	let out = tree(&built, DumpOptions::default());
+	let hash_hex: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+	assert_eq!(out.lines().nth(4), Some(&format!("      5: missing node hash 0x{}", hash_hex)[..]));
+	assert!(built.trie().get(&[0x12, 0x56]).is_err());
+}
+
+#[test]
+fn dot_output() {
+	let built = build::<ExtensionLayout>();
+	let mut out = String::new();
+	let options = DumpOptions { max_depth: Some(2), ..without_hashes() };
+	built.trie().fmt_dot(&mut out, options).unwrap();
+	assert_eq!(out, concat!(
+		"digraph trie {\n",
+		"\tnode [shape=box, fontname=monospace];\n",
+		"\tn0 [label=\"branch\\nchildren .1.....7........\"];\n",
+		"\tn1 [label=\"extension\\npartial 2\"];\n",
+		"\tn0 -> n1 [label=\"1\"];\n",
+		"\tn2 [label=\"branch\\nchildren ...3.5..........\"];\n",
+		"\tn1 -> n2;\n",
+		"\tn3 [label=\"leaf\\npartial 8\\nvalue 0x03\"];\n",
+		"\tn0 -> n3 [label=\"7\"];\n",
+		"}\n",
+	));
+}
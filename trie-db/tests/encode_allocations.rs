@@ -0,0 +1,134 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Allocation count of the node encodings into reused buffers, in its own test binary so that
+//! the counting allocator does not affect other tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use hash_db::Hasher;
+use keccak_hasher::KeccakHasher;
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieConfiguration, TrieLayout, TrieMut};
+use trie_db::{DBValue, NibbleSlice, NodeCodec, TrieDBMut};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+
+struct CountingAllocator;
+
+thread_local! {
+	static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout)
+	}
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+		System.realloc(ptr, layout, new_size)
+	}
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+	ALLOCATIONS.with(|count| count.get())
+}
+
+/// 10000 sorted pairs, with hashed keys so that the trie has about as many leaves.
+fn pairs() -> Vec<(Vec<u8>, Vec<u8>)> {
+	let mut pairs: Vec<_> = (0..10_000u32)
+		.map(|i| (KeccakHasher::hash(&i.to_le_bytes()).to_vec(), i.to_le_bytes().to_vec()))
+		.collect();
+	pairs.sort();
+	pairs
+}
+
+fn check_codec_buffer<L: TrieLayout>() {
+	let pairs = pairs();
+	let mut expected = Vec::new();
+	for (key, value) in pairs.iter() {
+		let partial = NibbleSlice::<L::Nibble>::new(key);
+		expected.extend_from_slice(&L::Codec::leaf_node(partial.right(), value));
+	}
+
+	let mut output = Vec::new();
+	let before = allocations();
+	for (key, value) in pairs.iter() {
+		L::Codec::leaf_node_into(NibbleSlice::<L::Nibble>::new(key).right(), value, &mut output);
+	}
+	let allocated = allocations() - before;
+	assert_eq!(output, expected);
+	// The buffer grows a few times for the whole batch.
+	assert!(allocated < 32, "{} allocations", allocated);
+}
+
+#[test]
+fn codec_encodes_into_one_buffer() {
+	check_codec_buffer::<ExtensionLayout>();
+	check_codec_buffer::<NoExtensionLayout>();
+}
+
+fn check_trie_root<L: TrieConfiguration>() {
+	let pairs = pairs();
+	let before = allocations();
+	let root = L::trie_root(pairs.iter().map(|(key, value)| (key, value)));
+	let allocated = allocations() - before;
+	assert_ne!(root, Default::default());
+	// The root builder only allocates its buffers, not the encodings of the nodes.
+	assert!(allocated < 32, "{} allocations", allocated);
+}
+
+#[test]
+fn trie_root_does_not_allocate_per_node() {
+	check_trie_root::<ExtensionLayout>();
+	check_trie_root::<NoExtensionLayout>();
+}
+
+fn check_commit<L: TrieLayout<Hash = KeccakHasher>>() {
+	let pairs = pairs();
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+	for (key, value) in pairs.iter() {
+		t.insert(key, value).unwrap();
+	}
+	let before = allocations();
+	t.commit();
+	let allocated = allocations() - before;
+	let written = t.commit_stats().written;
+	assert!(written > pairs.len());
+	// Each written node allocates the value and the prefix handed to the database, the
+	// encodings share one buffer.
+	assert!(
+		allocated < 2 * written + 64,
+		"{} allocations for {} nodes",
+		allocated,
+		written,
+	);
+}
+
+#[test]
+fn commit_reuses_its_encoding_buffer() {
+	check_commit::<ExtensionLayout>();
+	check_commit::<NoExtensionLayout>();
+}
@@ -0,0 +1,204 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Allocation count of `trie_db::exists` and `TrieDB::contains`, in its own test binary so
+//! that the counting allocator does not affect other tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use hash_db::{HashDBRef, Hasher};
+use keccak_hasher::KeccakHasher;
+use reference_trie::{
+	ExtensionLayout, NoExtensionLayout, RefTrieDBMut, RefTrieDBMutNoExt, TrieLayout, TrieMut,
+};
+use trie_db::{exists, DBValue, FnHashDB, NodeCache, NodeCodec, Trie, TrieDB};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+
+struct CountingAllocator;
+
+thread_local! {
+	static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout)
+	}
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+		System.realloc(ptr, layout, new_size)
+	}
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+	ALLOCATIONS.with(|count| count.get())
+}
+
+/// Database counting the node reads in `reads`, each of them returning a newly allocated
+/// buffer.
+fn counting_db<'a>(
+	db: &'a MemoryDB,
+	reads: &'a Cell<usize>,
+) -> impl HashDBRef<KeccakHasher, DBValue> + 'a {
+	FnHashDB::new(move |key: &_, prefix| {
+		reads.set(reads.get() + 1);
+		HashDBRef::get(db, key, prefix)
+	})
+}
+
+fn entries() -> Vec<(Vec<u8>, Vec<u8>)> {
+	let mut entries: Vec<(Vec<u8>, Vec<u8>)> = vec![
+		(b"do".to_vec(), b"verb".to_vec()),
+		(b"dog".to_vec(), b"puppy".to_vec()),
+		(b"doge".to_vec(), vec![0; 32]),
+		(b"horse".to_vec(), b"stallion".to_vec()),
+		(b"house".to_vec(), b"building".to_vec()),
+	];
+	for i in 0..64u8 {
+		entries.push((vec![b'x', i, i], vec![i; 40]));
+	}
+	entries
+}
+
+fn check_allocations<L>(mut build: impl FnMut(&mut MemoryDB, &mut <KeccakHasher as Hasher>::Out))
+	where L: TrieLayout<Hash = KeccakHasher>,
+{
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	build(&mut db, &mut root);
+	let reads = Cell::new(0);
+	let db = counting_db(&db, &reads);
+
+	let mut keys: Vec<(Vec<u8>, bool)> = entries().into_iter().map(|(k, _)| (k, true)).collect();
+	keys.extend(vec![
+		(b"d".to_vec(), false),
+		(b"dot".to_vec(), false),
+		(b"dogecoin".to_vec(), false),
+		(vec![b'x', 3], false),
+		(vec![0xff; 40], false),
+	]);
+
+	for (key, expected) in keys {
+		reads.set(0);
+		let before = allocations();
+		let result = exists::<L>(&db, &root, &key, None).unwrap();
+		let allocated = allocations() - before;
+		assert_eq!(result, expected, "key {:?}", key);
+		assert!(reads.get() > 0);
+		// Nothing is allocated besides the nodes handed out by the database.
+		assert_eq!(allocated, reads.get(), "key {:?}", key);
+
+		let trie = TrieDB::<L>::new(&db, &root).unwrap();
+		reads.set(0);
+		let before = allocations();
+		assert_eq!(trie.contains(&key).unwrap(), expected, "key {:?}", key);
+		assert_eq!(allocations() - before, reads.get(), "key {:?}", key);
+	}
+}
+
+#[test]
+fn exists_only_allocates_database_reads() {
+	check_allocations::<ExtensionLayout>(|db, root| {
+		let mut trie = RefTrieDBMut::new(db, root);
+		for (key, value) in entries() {
+			trie.insert(&key, &value).unwrap();
+		}
+	});
+	check_allocations::<NoExtensionLayout>(|db, root| {
+		let mut trie = RefTrieDBMutNoExt::new(db, root);
+		for (key, value) in entries() {
+			trie.insert(&key, &value).unwrap();
+		}
+	});
+}
+
+fn check_cache_hits<L>(mut build: impl FnMut(&mut MemoryDB, &mut <KeccakHasher as Hasher>::Out))
+	where L: TrieLayout<Hash = KeccakHasher>,
+{
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	build(&mut db, &mut root);
+	let reads = Cell::new(0);
+	let db = counting_db(&db, &reads);
+	let cache = NodeCache::<L>::new(1 << 20);
+
+	let mut keys: Vec<(Vec<u8>, bool)> = entries().into_iter().map(|(k, _)| (k, true)).collect();
+	keys.push((b"dot".to_vec(), false));
+	keys.push((vec![b'x', 3], false));
+	for (key, expected) in keys {
+		assert_eq!(exists::<L>(&db, &root, &key, Some(&cache)).unwrap(), expected);
+
+		// the nodes on the path of `key` are now cached.
+		reads.set(0);
+		let misses = cache.stats().misses;
+		let before = allocations();
+		let result = exists::<L>(&db, &root, &key, Some(&cache)).unwrap();
+		let allocated = allocations() - before;
+		assert_eq!(result, expected, "key {:?}", key);
+		assert_eq!((reads.get(), allocated), (0, 0), "key {:?}", key);
+		assert_eq!(cache.stats().misses, misses, "key {:?}", key);
+	}
+}
+
+#[test]
+fn exists_cache_hits_do_not_read_nor_allocate() {
+	check_cache_hits::<ExtensionLayout>(|db, root| {
+		let mut trie = RefTrieDBMut::new(db, root);
+		for (key, value) in entries() {
+			trie.insert(&key, &value).unwrap();
+		}
+	});
+	check_cache_hits::<NoExtensionLayout>(|db, root| {
+		let mut trie = RefTrieDBMutNoExt::new(db, root);
+		for (key, value) in entries() {
+			trie.insert(&key, &value).unwrap();
+		}
+	});
+}
+
+#[test]
+fn exists_on_empty_trie_does_not_read() {
+	let memdb = MemoryDB::default();
+	let reads = Cell::new(0);
+	let db = counting_db(&memdb, &reads);
+	let root = <ExtensionLayout as TrieLayout>::Codec::hashed_null_node();
+
+	let before = allocations();
+	assert!(!exists::<ExtensionLayout>(&db, &root, b"dog", None).unwrap());
+	assert_eq!(allocations() - before, 0);
+	assert_eq!(reads.get(), 0);
+}
+
+#[test]
+fn exists_reports_missing_root() {
+	let memdb = MemoryDB::default();
+	let reads = Cell::new(0);
+	let db = counting_db(&memdb, &reads);
+	let root = KeccakHasher::hash(b"missing");
+	match exists::<ExtensionLayout>(&db, &root, b"dog", None).map_err(|e| *e) {
+		Err(trie_db::TrieError::InvalidStateRoot(hash)) => assert_eq!(hash, root),
+		result => panic!("expected TrieError::InvalidStateRoot, got {:?}", result),
+	}
+}
@@ -0,0 +1,154 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memory used by the nodes of tries sharing subtrees, with and without interned child hashes.
+
+#![cfg(feature = "hash-interning")]
+
+use keccak_hasher::KeccakHasher;
+use reference_trie::{ExtensionLayout, NoExtensionLayout};
+use trie_db::interner::{HashInterner, InternedNode};
+use trie_db::{DBValue, NodeCache, Trie, TrieDB, TrieDBMut, TrieLayout, TrieMut};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+type Root = <KeccakHasher as trie_db::Hasher>::Out;
+
+/// 200 versions of a trie of 256 keys, each version updating one key of the previous one,
+/// with their roots: the branches of a version reference the unchanged subtrees of the
+/// previous.
+fn shared_versions<L: TrieLayout<Hash = KeccakHasher>>() -> (MemoryDB, Vec<Root>) {
+	let mut db = MemoryDB::default();
+	let mut roots = Vec::new();
+	let mut pairs: Vec<_> = (0..=255u8).map(|i| ([i], vec![i; 32])).collect();
+	for step in 0..200u32 {
+		let mut value = vec![0; 32];
+		value[..4].copy_from_slice(&step.to_be_bytes());
+		pairs[(step * 37 % 256) as usize].1 = value;
+		let mut step_db = MemoryDB::default();
+		let mut root = Default::default();
+		{
+			let mut t = TrieDBMut::<L>::new(&mut step_db, &mut root);
+			for (key, value) in pairs.iter() {
+				t.insert(key, value).unwrap();
+			}
+		}
+		db.consolidate(step_db);
+		roots.push(root);
+	}
+	(db, roots)
+}
+
+/// The nodes of `shared_versions`.
+fn shared_nodes<L: TrieLayout<Hash = KeccakHasher>>() -> Vec<DBValue> {
+	let (mut db, _) = shared_versions::<L>();
+	db.drain().into_iter().map(|(_, (node, _))| node).collect()
+}
+
+fn check_savings<L: TrieLayout<Hash = KeccakHasher>>() {
+	let nodes = shared_nodes::<L>();
+	let plain: Vec<_> = nodes.iter()
+		.map(|node| InternedNode::<KeccakHasher>::new::<L::Codec>(node.clone(), None).unwrap())
+		.collect();
+	let plain_size: usize = plain.iter().map(InternedNode::heap_size).sum();
+	assert_eq!(plain_size, nodes.iter().map(|node| node.len()).sum::<usize>());
+
+	let interner = HashInterner::<KeccakHasher>::new();
+	let interned: Vec<_> = nodes.iter()
+		.map(|node| InternedNode::new::<L::Codec>(node.clone(), Some(&interner)).unwrap())
+		.collect();
+	let references: usize = interned.iter().map(InternedNode::interned_hashes).sum();
+	let interned_size = interned.iter().map(InternedNode::heap_size).sum::<usize>()
+		+ interner.heap_size();
+	// most child hashes are shared, and interning saves at least a fifth of the memory.
+	assert!(interner.len() * 4 < references);
+	assert!(interned_size * 10 < plain_size * 8);
+
+	for (node, interned) in nodes.iter().zip(interned.iter()) {
+		assert_eq!(&interned.encoded(), node);
+		assert_eq!(interned.to_owned_node::<L::Codec>().unwrap().data(), &node[..]);
+	}
+
+	// the hashes are freed when no node references them.
+	assert_eq!(interner.collect_garbage(), 0);
+	let distinct = interner.len();
+	drop(interned);
+	assert_eq!(interner.collect_garbage(), distinct);
+	assert!(interner.is_empty());
+}
+
+#[test]
+fn interning_saves_memory_with_ext() {
+	check_savings::<ExtensionLayout>();
+}
+
+#[test]
+fn interning_saves_memory_without_ext() {
+	check_savings::<NoExtensionLayout>();
+}
+
+fn check_cache_interning<L: TrieLayout<Hash = KeccakHasher>>() {
+	let (db, roots) = shared_versions::<L>();
+	let cache = NodeCache::<L>::new(usize::MAX).with_interning();
+	let plain_cache = NodeCache::<L>::new(usize::MAX);
+	for root in roots.iter() {
+		let expected: Vec<_> = TrieDB::<L>::new(&db, root).unwrap()
+			.iter().unwrap().map(Result::unwrap).collect();
+		for cache in [&cache, &plain_cache] {
+			let trie = TrieDB::<L>::new_with_cache(&db, root, cache).unwrap();
+			let pairs: Vec<_> = trie.iter().unwrap().map(Result::unwrap).collect();
+			assert_eq!(pairs, expected);
+			// read again, from the cache.
+			let pairs: Vec<_> = trie.iter().unwrap().map(Result::unwrap).collect();
+			assert_eq!(pairs, expected);
+			assert_eq!(trie.get(&[7]).unwrap(), expected.get(7).map(|(_, value)| value.clone()));
+		}
+	}
+	assert_eq!(cache.stats(), plain_cache.stats());
+
+	// the cached nodes reference each pooled hash several times on average.
+	let interner = cache.interner().unwrap();
+	let counter = HashInterner::<KeccakHasher>::new();
+	let references: usize = shared_nodes::<L>().into_iter()
+		.map(|node| InternedNode::new::<L::Codec>(node, Some(&counter)).unwrap())
+		.map(|node| node.interned_hashes())
+		.sum();
+	assert!(interner.len() * 4 < references);
+
+	// interned nodes are saved with their full encoding.
+	let mut saved = Vec::new();
+	cache.save_to(&mut saved).unwrap();
+	let mut plain_saved = Vec::new();
+	plain_cache.save_to(&mut plain_saved).unwrap();
+	assert_eq!(saved.len(), plain_saved.len());
+
+	// a loaded cache interns the loaded nodes.
+	let loaded = NodeCache::<L>::new(usize::MAX).with_interning();
+	let summary = loaded.load_from(&saved[..], usize::MAX).unwrap();
+	assert_eq!(summary.loaded, cache.stats().nodes);
+	assert_eq!(loaded.interner().unwrap().len(), interner.len());
+
+	// clearing the cache frees the pooled hashes.
+	cache.clear();
+	assert!(interner.is_empty());
+}
+
+#[test]
+fn node_cache_interns_child_hashes_with_ext() {
+	check_cache_interning::<ExtensionLayout>();
+}
+
+#[test]
+fn node_cache_interns_child_hashes_without_ext() {
+	check_cache_interning::<NoExtensionLayout>();
+}
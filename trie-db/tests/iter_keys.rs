@@ -0,0 +1,107 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `TrieDB::iter_keys` against the keys of `iter`, without reading the value nodes.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+
+use hash_db::{HashDBRef, Hasher};
+use keccak_hasher::KeccakHasher;
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{
+	ExtensionLayout, NoExtensionLayout, TrieLayout, ValueNodeLayout, VALUE_NODE_THRESHOLD,
+};
+use trie_db::{FnHashDB, Trie, TrieDB};
+
+type Pairs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Keys ending on branch values, on inline leaves and on stored leaves, with values larger than
+/// `VALUE_NODE_THRESHOLD` on every kind of node.
+fn pairs() -> Pairs {
+	(0..60u8)
+		.flat_map(|i| vec![
+			(vec![i / 8], vec![i; 1 + i as usize % 3 * 40]),
+			(vec![i / 8, i], vec![i; 1 + i as usize % 2 * 40]),
+			(vec![i / 8, i, 0x10], vec![i; 2 + i as usize % 4 * 20]),
+		])
+		.collect()
+}
+
+fn build<L: TrieLayout<Hash = KeccakHasher>>(pairs: &Pairs) -> BuiltTrie<L> {
+	TrieBuilder::new().extend(pairs).build()
+}
+
+/// Iterate over the keys of the trie of `pairs`, returning the number of reads of the hashes
+/// of the large values.
+fn check_iter_keys<L: TrieLayout<Hash = KeccakHasher>>() -> usize {
+	let pairs = pairs();
+	let built = build::<L>(&pairs);
+	let value_hashes: HashSet<_> = pairs.values()
+		.filter(|value| value.len() > VALUE_NODE_THRESHOLD)
+		.map(|value| KeccakHasher::hash(value))
+		.collect();
+	let value_reads = Cell::new(0);
+	let counting_db = FnHashDB::new(|key: &_, prefix| {
+		if value_hashes.contains(key) {
+			value_reads.set(value_reads.get() + 1);
+		}
+		HashDBRef::get(built.db(), key, prefix)
+	});
+
+	let trie = TrieDB::<L>::new(&counting_db, built.root()).unwrap();
+	let keys: Vec<_> = trie.iter_keys().unwrap().map(|key| key.unwrap()).collect();
+	let reads = value_reads.get();
+	assert_eq!(keys, pairs.keys().cloned().collect::<Vec<_>>());
+	let expected: Vec<_> = trie.iter().unwrap().map(|item| item.unwrap().0).collect();
+	assert_eq!(keys, expected);
+	reads
+}
+
+#[test]
+fn iter_keys_follows_iter() {
+	check_iter_keys::<ExtensionLayout>();
+	check_iter_keys::<NoExtensionLayout>();
+}
+
+#[test]
+fn iter_keys_does_not_read_value_nodes() {
+	assert_eq!(check_iter_keys::<ValueNodeLayout>(), 0);
+}
+
+#[test]
+fn iter_reads_the_value_nodes_skipped_by_iter_keys() {
+	let pairs = pairs();
+	let built = build::<ValueNodeLayout>(&pairs);
+	let reads = Cell::new(0);
+	let counting_db = FnHashDB::new(|key: &_, prefix| {
+		reads.set(reads.get() + 1);
+		HashDBRef::get(built.db(), key, prefix)
+	});
+	let trie = TrieDB::<ValueNodeLayout>::new(&counting_db, built.root()).unwrap();
+	reads.set(0);
+	trie.iter_keys().unwrap().for_each(|key| drop(key.unwrap()));
+	let key_reads = reads.replace(0);
+	trie.iter().unwrap().for_each(|item| drop(item.unwrap()));
+	let large = pairs.values().filter(|value| value.len() > VALUE_NODE_THRESHOLD).count();
+	assert!(large > 0);
+	assert_eq!(reads.get(), key_reads + large);
+}
+
+#[test]
+fn iter_keys_of_empty_trie() {
+	let built = build::<NoExtensionLayout>(&Pairs::new());
+	let trie = built.trie();
+	assert_eq!(trie.iter_keys().unwrap().count(), 0);
+}
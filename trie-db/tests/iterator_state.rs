@@ -0,0 +1,97 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Iterations resumed from states encoded to bytes, on new `TrieDB`s.
+
+#![cfg(feature = "codec")]
+
+use codec::{Decode, Encode};
+use keccak_hasher::KeccakHasher;
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout};
+use trie_db::{TrieDB, TrieDBIterator, TrieIteratorState, TrieLayout};
+
+type Root = <KeccakHasher as hash_db::Hasher>::Out;
+
+fn build_trie<L: TrieLayout<Hash = KeccakHasher>>() -> BuiltTrie<L> {
+	// branch values, inline and stored leaves, and long shared partial keys.
+	TrieBuilder::new().extend((0..60u8).flat_map(|i| vec![
+		(vec![i / 4], vec![i; 3]),
+		(vec![i / 4, i], vec![i; 1 + i as usize % 40]),
+		(vec![0xaa, 0xbb, 0xcc, i / 16, i], vec![i; 33]),
+	])).build()
+}
+
+fn new_iter<'a, L: TrieLayout>(trie: &'a TrieDB<L>, reversed: bool) -> TrieDBIterator<'a, L> {
+	match reversed {
+		true => TrieDBIterator::new_reversed(trie).unwrap(),
+		false => TrieDBIterator::new(trie).unwrap(),
+	}
+}
+
+fn check_resume_from_bytes<L: TrieLayout<Hash = KeccakHasher>>(reversed: bool) {
+	let built = build_trie::<L>();
+	let expected: Vec<_> = new_iter(&built.trie(), reversed).map(|item| item.unwrap()).collect();
+
+	for split in 0..=expected.len() {
+		let (mut pairs, encoded) = {
+			let trie = built.trie();
+			let mut iter = new_iter(&trie, reversed);
+			let pairs: Vec<_> = iter.by_ref().take(split).map(|item| item.unwrap()).collect();
+			(pairs, iter.save().encode())
+		};
+
+		let state = TrieIteratorState::<Root>::decode(&mut &encoded[..]).unwrap();
+		assert_eq!(state.encode(), encoded);
+		let trie = built.trie();
+		pairs.extend(TrieDBIterator::restore(&trie, &state).unwrap().map(|item| item.unwrap()));
+		assert_eq!(pairs, expected, "split at {}", split);
+	}
+}
+
+#[test]
+fn resume_from_bytes_with_extension() {
+	check_resume_from_bytes::<ExtensionLayout>(false);
+	check_resume_from_bytes::<ExtensionLayout>(true);
+}
+
+#[test]
+fn resume_from_bytes_without_extension() {
+	check_resume_from_bytes::<NoExtensionLayout>(false);
+	check_resume_from_bytes::<NoExtensionLayout>(true);
+}
+
+#[test]
+fn invalid_states_are_not_decoded() {
+	let built = build_trie::<ExtensionLayout>();
+	let trie = built.trie();
+	let mut iter = TrieDBIterator::new(&trie).unwrap();
+	iter.nth(70).unwrap().unwrap();
+	let encoded = iter.save().encode();
+
+	for len in 0..encoded.len() {
+		assert!(TrieIteratorState::<Root>::decode(&mut &encoded[..len]).is_err(), "len {}", len);
+	}
+	// the status of the first crumb follows the root, the trail length and the root crumb hash.
+	let status = 32 + 1 + 1 + 32;
+	for &invalid in [0u8, 3, 4].iter() {
+		let mut tampered = encoded.clone();
+		tampered[status] = invalid;
+		assert!(TrieIteratorState::<Root>::decode(&mut &tampered[..]).is_err());
+	}
+	let mut tampered = encoded.clone();
+	tampered[status] = 2;
+	tampered.insert(status + 1, 16);
+	assert!(TrieIteratorState::<Root>::decode(&mut &tampered[..]).is_err());
+}
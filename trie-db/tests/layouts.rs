@@ -0,0 +1,256 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests shared by the reference layouts, `ComplexLayout` covering the interactions of the
+//! layout options.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use hash_db::Hasher;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::{
+	proof::{generate_proof, verify_proof}, NodeCodec, Trie, TrieConfiguration, TrieDB,
+	TrieDBIterator, TrieDBMut, TrieLayout, TrieMut,
+};
+use trie_db::DBValue;
+
+type MemoryDB<L> = memory_db::MemoryDB<
+	<L as TrieLayout>::Hash,
+	memory_db::HashKey<<L as TrieLayout>::Hash>,
+	DBValue,
+>;
+type Root<L> = <<L as TrieLayout>::Hash as Hasher>::Out;
+type Pairs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Run the shared tests against each layout.
+///
+/// New reference layouts are added to the list, and new layout options are enabled in
+/// `ComplexLayout`. New shared tests are added to each layout module.
+macro_rules! test_layouts {
+	($($module:ident: $layout:ty,)*) => {
+		$(
+			mod $module {
+				#[test]
+				fn insert_get_remove() {
+					super::insert_get_remove::<$layout>();
+				}
+
+				#[test]
+				fn iterate_and_seek() {
+					super::iterate_and_seek::<$layout>();
+				}
+
+				#[test]
+				fn seek_after() {
+					super::seek_after::<$layout>();
+				}
+
+				#[test]
+				fn proofs() {
+					super::proofs::<$layout>();
+				}
+
+				#[test]
+				fn random_operations() {
+					super::random_operations::<$layout>();
+				}
+			}
+		)*
+	}
+}
+
+test_layouts! {
+	extension: reference_trie::ExtensionLayout,
+	no_extension: reference_trie::NoExtensionLayout,
+	complex: reference_trie::ComplexLayout,
+}
+
+/// Seeds of the random tests.
+const SEEDS: [u64; 4] = [0, 1, 42, 2020];
+
+/// A random key, sharing prefixes with the other keys. Keys are up to 4 bytes long, the empty
+/// key included.
+fn random_key(rng: &mut SmallRng) -> Vec<u8> {
+	const ALPHABET: [u8; 4] = [0x00, 0x0f, 0x10, 0xf1];
+	let len = rng.gen_range(0, 5);
+	(0..len).map(|_| ALPHABET[rng.gen_range(0, ALPHABET.len())]).collect()
+}
+
+/// A random value, with a size around the sizes from which nodes are hashed.
+fn random_value(rng: &mut SmallRng) -> Vec<u8> {
+	let len = rng.gen_range(1, 48);
+	(0..len).map(|_| rng.gen()).collect()
+}
+
+fn random_pairs(seed: u64, count: usize) -> Pairs {
+	let mut rng = SmallRng::seed_from_u64(seed);
+	(0..count).map(|_| (random_key(&mut rng), random_value(&mut rng))).collect()
+}
+
+fn build_trie<L: TrieLayout>(pairs: &Pairs) -> (MemoryDB<L>, Root<L>) {
+	let mut db = MemoryDB::<L>::default();
+	let mut root = Default::default();
+	{
+		let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+		for (key, value) in pairs {
+			t.insert(key, value).unwrap();
+		}
+	}
+	(db, root)
+}
+
+fn check_content<L: TrieConfiguration>(db: &MemoryDB<L>, root: &Root<L>, pairs: &Pairs) {
+	assert_eq!(*root, L::trie_root(pairs.iter()));
+	let t = TrieDB::<L>::new(db, root).unwrap();
+	for (key, value) in pairs {
+		assert_eq!(t.get(key).unwrap().as_ref(), Some(value));
+	}
+	let content: Pairs = t.iter().unwrap().map(|item| item.unwrap()).collect();
+	assert_eq!(&content, pairs);
+}
+
+fn insert_get_remove<L: TrieConfiguration>() {
+	for seed in SEEDS.iter() {
+		let mut pairs = random_pairs(*seed, 200);
+		let (mut db, mut root) = build_trie::<L>(&pairs);
+		check_content::<L>(&db, &root, &pairs);
+
+		let removed: Vec<Vec<u8>> = pairs.keys().step_by(2).cloned().collect();
+		{
+			let mut t = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap();
+			for key in removed.iter() {
+				assert_eq!(t.remove(key).unwrap().as_ref(), pairs.get(key));
+			}
+		}
+		for key in removed.iter() {
+			pairs.remove(key);
+		}
+		check_content::<L>(&db, &root, &pairs);
+		let t = TrieDB::<L>::new(&db, &root).unwrap();
+		for key in removed.iter() {
+			assert_eq!(t.get(key).unwrap(), None);
+		}
+
+		{
+			let mut t = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap();
+			for key in pairs.keys() {
+				t.remove(key).unwrap();
+			}
+		}
+		assert_eq!(root, L::Codec::hashed_null_node());
+	}
+}
+
+fn iterate_and_seek<L: TrieConfiguration>() {
+	for seed in SEEDS.iter() {
+		let pairs = random_pairs(*seed, 100);
+		let (db, root) = build_trie::<L>(&pairs);
+		let t = TrieDB::<L>::new(&db, &root).unwrap();
+
+		let mut rng = SmallRng::seed_from_u64(*seed);
+		for _ in 0..20 {
+			let key = random_key(&mut rng);
+			let mut iter = t.iter().unwrap();
+			iter.seek(&key).unwrap();
+			let seeked: Vec<_> = iter.map(|item| item.unwrap()).collect();
+			let expected: Vec<_> = pairs.range(key.clone()..)
+				.map(|(key, value)| (key.clone(), value.clone()))
+				.collect();
+			assert_eq!(seeked, expected);
+
+			let prefixed: Vec<_> = TrieDBIterator::new_prefixed(&t, &key).unwrap()
+				.map(|item| item.unwrap())
+				.collect();
+			let expected: Vec<_> = pairs.iter()
+				.filter(|(k, _)| k.starts_with(&key))
+				.map(|(key, value)| (key.clone(), value.clone()))
+				.collect();
+			assert_eq!(prefixed, expected);
+		}
+	}
+}
+
+fn seek_after<L: TrieConfiguration>() {
+	for seed in SEEDS.iter() {
+		let pairs = random_pairs(*seed, 100);
+		let (db, root) = build_trie::<L>(&pairs);
+		let t = TrieDB::<L>::new(&db, &root).unwrap();
+
+		let mut rng = SmallRng::seed_from_u64(*seed);
+		let existing: Vec<&Vec<u8>> = pairs.keys().collect();
+		for _ in 0..40 {
+			// existing and missing keys alike.
+			let key = match rng.gen_range(0, 2) {
+				0 => existing[rng.gen_range(0, existing.len())].clone(),
+				_ => random_key(&mut rng),
+			};
+			let mut iter = t.iter().unwrap();
+			iter.seek_after(&key).unwrap();
+			let seeked: Vec<_> = iter.map(|item| item.unwrap()).collect();
+			let expected: Vec<_> = pairs.range((Bound::Excluded(key), Bound::Unbounded))
+				.map(|(key, value)| (key.clone(), value.clone()))
+				.collect();
+			assert_eq!(seeked, expected);
+		}
+	}
+}
+
+fn proofs<L: TrieConfiguration>() {
+	for seed in SEEDS.iter() {
+		let pairs = random_pairs(*seed, 100);
+		let (db, root) = build_trie::<L>(&pairs);
+		let t = TrieDB::<L>::new(&db, &root).unwrap();
+
+		let mut rng = SmallRng::seed_from_u64(*seed);
+		let mut keys: Vec<Vec<u8>> = (0..10).map(|_| random_key(&mut rng)).collect();
+		keys.sort();
+		keys.dedup();
+		let proof = generate_proof::<_, L, _, _>(&t, &keys).unwrap();
+		let mut items: Vec<(Vec<u8>, Option<Vec<u8>>)> = keys.iter()
+			.map(|key| (key.clone(), pairs.get(key).cloned()))
+			.collect();
+		verify_proof::<L, _, _, _>(&root, &proof, &items).unwrap();
+
+		items[0].1 = match items[0].1 {
+			Some(_) => None,
+			None => Some(b"value".to_vec()),
+		};
+		assert!(verify_proof::<L, _, _, _>(&root, &proof, &items).is_err());
+	}
+}
+
+fn random_operations<L: TrieConfiguration>() {
+	for seed in SEEDS.iter() {
+		let mut rng = SmallRng::seed_from_u64(*seed);
+		let mut pairs = Pairs::new();
+		let mut db = MemoryDB::<L>::default();
+		let mut root = Default::default();
+		let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+		for step in 1..=500 {
+			let key = random_key(&mut rng);
+			if rng.gen_range(0, 3) == 0 {
+				assert_eq!(t.remove(&key).unwrap(), pairs.remove(&key));
+			} else {
+				let value = random_value(&mut rng);
+				assert_eq!(t.insert(&key, &value).unwrap(), pairs.insert(key, value));
+			}
+			if step % 50 == 0 {
+				assert_eq!(*t.root(), L::trie_root(pairs.iter()));
+			}
+		}
+		drop(t);
+		check_content::<L>(&db, &root, &pairs);
+	}
+}
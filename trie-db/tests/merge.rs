@@ -0,0 +1,178 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `TrieDBMut::merge_from` against building the union of two tries from scratch.
+
+use std::collections::BTreeMap;
+
+use keccak_hasher::KeccakHasher;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{DBValue, Trie, TrieDB, TrieDBMut, TrieMut};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+type Pairs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn random_pairs(rng: &mut SmallRng, alphabet: &[u8]) -> Pairs {
+	(0..rng.gen_range(0, 60)).map(|_| {
+		let key_len = rng.gen_range(0, 4);
+		let key = (0..key_len).map(|_| alphabet[rng.gen_range(0, alphabet.len())]).collect();
+		// short values make inline nodes.
+		let value_len = match rng.gen() {
+			true => rng.gen_range(1, 4),
+			false => rng.gen_range(30, 40),
+		};
+		(key, (0..value_len).map(|_| rng.gen()).collect())
+	}).collect()
+}
+
+fn build<L: TrieLayout<Hash = KeccakHasher>>(pairs: &Pairs) -> BuiltTrie<L> {
+	TrieBuilder::new().extend(pairs).build()
+}
+
+fn check_merge<L: TrieLayout<Hash = KeccakHasher>>(seed: u64, shared_db: bool) {
+	let mut rng = SmallRng::seed_from_u64(seed);
+	let a = random_pairs(&mut rng, &[0x00, 0x01, 0x10]);
+	let b = random_pairs(&mut rng, &[0x00, 0x10, 0x11]);
+	let mut union = a.clone();
+	union.extend(b.clone());
+	let expected_root = *build::<L>(&union).root();
+
+	let (mut db, mut root) = build::<L>(&a).into_parts();
+	let (mut source_db, source_root) = build::<L>(&b).into_parts();
+	if shared_db {
+		db.consolidate(source_db);
+		source_db = db.clone();
+	}
+	{
+		let source = TrieDB::<L>::new(&source_db, &source_root).unwrap();
+		let mut t = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap();
+		assert_eq!(t.merge_from(&source).unwrap(), b.len() as u64, "seed {}", seed);
+		for (key, value) in union.iter() {
+			assert_eq!(t.get(key).unwrap().as_ref(), Some(value));
+		}
+	}
+	assert_eq!(root, expected_root, "seed {}", seed);
+	let trie = TrieDB::<L>::new(&db, &root).unwrap();
+	let pairs: Pairs = trie.iter().unwrap().map(|item| item.unwrap()).collect();
+	assert_eq!(pairs, union);
+}
+
+#[test]
+fn merge_matches_union_with_extension() {
+	for seed in 0..200 {
+		check_merge::<ExtensionLayout>(seed, seed % 2 == 0);
+	}
+}
+
+#[test]
+fn merge_matches_union_without_extension() {
+	for seed in 0..200 {
+		check_merge::<NoExtensionLayout>(seed, seed % 2 == 0);
+	}
+}
+
+/// 100 keys of 32 bytes under the first half of the nibbles, 100 under the second.
+fn halves() -> (Pairs, Pairs) {
+	let mut rng = SmallRng::seed_from_u64(7);
+	let mut pairs = |high: u8| -> Pairs {
+		(0..100).map(|_| {
+			let mut key: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
+			key[0] = key[0] % 0x80 + high;
+			(key, vec![high; 40])
+		}).collect()
+	};
+	(pairs(0), pairs(0x80))
+}
+
+fn check_grafts<L: TrieLayout<Hash = KeccakHasher>>() {
+	let (a, b) = halves();
+	let mut union = a.clone();
+	union.extend(b.clone());
+	let built_source = build::<L>(&b);
+	let source = built_source.trie();
+	let source_root = *built_source.root();
+
+	// into an empty trie, the root of the source is reused.
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+		assert_eq!(t.merge_from(&source).unwrap(), 100);
+		assert_eq!(*t.root(), source_root);
+		assert_eq!(t.commit_stats().written, 0);
+	}
+
+	// the subtrees of the second half hang from the root branch of the first half: only the
+	// root is written, the nodes of the source being copied as they are.
+	let mut built = build::<L>(&a);
+	{
+		let mut t = built.trie_mut();
+		assert_eq!(t.merge_from(&source).unwrap(), 100);
+		t.commit();
+		assert_eq!(t.commit_stats().written, 1);
+	}
+	assert_eq!(built.root(), build::<L>(&union).root());
+}
+
+#[test]
+fn disjoint_subtrees_are_grafted_with_extension() {
+	check_grafts::<ExtensionLayout>();
+}
+
+#[test]
+fn disjoint_subtrees_are_grafted_without_extension() {
+	check_grafts::<NoExtensionLayout>();
+}
+
+#[test]
+fn discarded_merges_leave_the_database_untouched() {
+	let (a, b) = halves();
+	let built_source = build::<ExtensionLayout>(&b);
+	let source = built_source.trie();
+	let (mut db, mut root) = build::<ExtensionLayout>(&a).into_parts();
+	let (initial_db, initial_root) = (db.clone(), root);
+	{
+		let mut t = TrieDBMut::<ExtensionLayout>::from_existing(&mut db, &mut root).unwrap();
+		let checkpoint = t.checkpoint();
+		t.merge_from(&source).unwrap();
+		t.rollback_to(checkpoint).unwrap();
+		assert_eq!(t.get(&b.keys().next().unwrap()[..]).unwrap(), None);
+		t.merge_from(&source).unwrap();
+		t.discard();
+	}
+	assert_eq!(root, initial_root);
+	// the copied nodes are left with no reference.
+	db.purge();
+	assert!(db == initial_db);
+}
+
+#[test]
+fn merging_a_trie_into_itself_changes_nothing() {
+	let (a, b) = halves();
+	let mut union = a.clone();
+	union.extend(b);
+	let (mut db, mut root) = build::<NoExtensionLayout>(&union).into_parts();
+	let (source_db, source_root) = (db.clone(), root);
+	let source = TrieDB::<NoExtensionLayout>::new(&source_db, &source_root).unwrap();
+	{
+		let mut t = TrieDBMut::<NoExtensionLayout>::from_existing(&mut db, &mut root).unwrap();
+		assert_eq!(t.merge_from(&source).unwrap(), 200);
+		t.commit();
+		assert_eq!(t.commit_stats().written, 0);
+	}
+	assert_eq!(root, source_root);
+	assert!(db == source_db);
+}
@@ -0,0 +1,158 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `migrate_trie` and `migrate_trie_range` between layouts with other codecs and hashers.
+
+use std::collections::BTreeMap;
+
+use hash_db::Hasher;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{
+	ComplexLayout, ExtensionLayout, NoExtensionLayout, TrieLayout, ValueNodeLayout,
+};
+use trie_db::{
+	empty_trie_root, migrate_trie, migrate_trie_range, DBValue, MigrationError, Trie, TrieDB,
+};
+
+type MemoryDB<L> = memory_db::MemoryDB<
+	<L as TrieLayout>::Hash,
+	memory_db::PrefixedKey<<L as TrieLayout>::Hash>,
+	DBValue,
+>;
+type Root<L> = <<L as TrieLayout>::Hash as Hasher>::Out;
+type Pairs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn random_pairs(rng: &mut SmallRng) -> Pairs {
+	(0..rng.gen_range(0, 200)).map(|_| {
+		let key_len = rng.gen_range(0, 5);
+		let key = (0..key_len).map(|_| [0x00, 0x01, 0x10, 0xff][rng.gen_range(0, 4)]).collect();
+		// short values make inline nodes, long ones value nodes.
+		let value_len = match rng.gen() {
+			true => rng.gen_range(1, 4),
+			false => rng.gen_range(30, 50),
+		};
+		(key, (0..value_len).map(|_| rng.gen()).collect())
+	}).collect()
+}
+
+fn build<L: TrieLayout>(pairs: &Pairs) -> BuiltTrie<L> {
+	TrieBuilder::new().extend(pairs).build()
+}
+
+fn content<L: TrieLayout>(db: &MemoryDB<L>, root: &Root<L>) -> Pairs {
+	let t = TrieDB::<L>::new(db, root).unwrap();
+	let pairs = t.iter().unwrap().map(|item| item.unwrap()).collect();
+	pairs
+}
+
+/// Migrate a random trie from `Lsrc` to `Ldst` and back, in full and in chunks.
+fn check_migration<Lsrc: TrieLayout, Ldst: TrieLayout>(seed: u64) {
+	let mut rng = SmallRng::seed_from_u64(seed);
+	let pairs = random_pairs(&mut rng);
+	let src = build::<Lsrc>(&pairs);
+	let (src_db, src_root) = (src.db(), src.root());
+
+	let mut dst_db = MemoryDB::<Ldst>::default();
+	let dst_root = migrate_trie::<Lsrc, Ldst>(src_db, src_root, &mut dst_db).unwrap();
+	assert_eq!(content::<Ldst>(&dst_db, &dst_root), pairs, "seed {}", seed);
+	assert_eq!(dst_root, *build::<Ldst>(&pairs).root());
+
+	let mut back_db = MemoryDB::<Lsrc>::default();
+	let back_root = migrate_trie::<Ldst, Lsrc>(&dst_db, &dst_root, &mut back_db).unwrap();
+	assert_eq!(back_root, *src_root, "seed {}", seed);
+	assert_eq!(content::<Lsrc>(&back_db, &back_root), pairs);
+
+	let limit = rng.gen_range(1, 20);
+	let mut chunk_db = MemoryDB::<Ldst>::default();
+	let mut chunk_root = empty_trie_root::<Ldst>();
+	let mut range = (Vec::new(), Vec::new());
+	let mut chunks = 0;
+	loop {
+		let cursor = migrate_trie_range::<Lsrc, Ldst>(
+			src_db,
+			src_root,
+			&mut chunk_db,
+			&mut chunk_root,
+			&range,
+			limit,
+		).unwrap();
+		chunks += 1;
+		match cursor {
+			Some(key) => range.0 = key,
+			None => break,
+		}
+	}
+	assert_eq!(chunk_root, dst_root, "seed {}", seed);
+	assert_eq!(chunks, pairs.len().div_ceil(limit).max(1));
+	assert_eq!(content::<Ldst>(&chunk_db, &chunk_root), pairs);
+}
+
+#[test]
+fn migrate_between_extension_and_no_extension() {
+	for seed in 0..20 {
+		check_migration::<ExtensionLayout, NoExtensionLayout>(seed);
+	}
+}
+
+#[test]
+fn migrate_to_value_nodes() {
+	for seed in 0..20 {
+		check_migration::<NoExtensionLayout, ValueNodeLayout>(seed);
+	}
+}
+
+#[test]
+fn migrate_to_another_hasher() {
+	for seed in 0..20 {
+		check_migration::<NoExtensionLayout, ComplexLayout>(seed);
+	}
+}
+
+#[test]
+fn migrate_range_stops_at_the_end_of_the_range() {
+	let pairs: Pairs = (0u8..100).map(|i| (vec![i], vec![i; 40])).collect();
+	let src = build::<ExtensionLayout>(&pairs);
+	let (src_db, src_root) = (src.db(), src.root());
+
+	let mut dst_db = MemoryDB::<NoExtensionLayout>::default();
+	let mut dst_root = empty_trie_root::<NoExtensionLayout>();
+	let range = (vec![10], vec![20]);
+	let cursor = migrate_trie_range::<ExtensionLayout, NoExtensionLayout>(
+		src_db, src_root, &mut dst_db, &mut dst_root, &range, 4,
+	).unwrap();
+	assert_eq!(cursor, Some(vec![14]));
+	let range = (vec![14], vec![20]);
+	let cursor = migrate_trie_range::<ExtensionLayout, NoExtensionLayout>(
+		src_db, src_root, &mut dst_db, &mut dst_root, &range, 10,
+	).unwrap();
+	assert_eq!(cursor, None);
+
+	let expected: Pairs = pairs.range(vec![10]..vec![20])
+		.map(|(key, value)| (key.clone(), value.clone()))
+		.collect();
+	assert_eq!(content::<NoExtensionLayout>(&dst_db, &dst_root), expected);
+}
+
+#[test]
+fn migrate_missing_root_is_a_source_error() {
+	let src_db = MemoryDB::<ExtensionLayout>::default();
+	let mut dst_db = MemoryDB::<NoExtensionLayout>::default();
+	let result = migrate_trie::<ExtensionLayout, NoExtensionLayout>(
+		&src_db,
+		&[0x42; 32],
+		&mut dst_db,
+	);
+	assert!(matches!(result, Err(MigrationError::Source(_))));
+}
@@ -0,0 +1,103 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hash and prefix of the node reported by `TrieError::IncompleteDatabase`.
+
+use hash_db::Hasher;
+use keccak_hasher::KeccakHasher;
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{Trie, TrieDB, TrieDBNodeIterator, TrieError, TrieMut};
+
+type Root = <KeccakHasher as Hasher>::Out;
+type OwnedPrefix = (Vec<u8>, Option<u8>);
+
+/// A trie of 16 keys, the branch under the first nibble 1 being removed from its database,
+/// with the hash and prefix of the removed branch. The values differ from one branch to the
+/// next, so that the removed branch is the only node with its hash.
+fn build<L: TrieLayout<Hash = KeccakHasher>>() -> (BuiltTrie<L>, Root, OwnedPrefix) {
+	let pairs = (0..16u8).map(|i| ([i / 4 * 16 + i % 4, i % 4], [i; 32]));
+	let mut built = TrieBuilder::new().extend(pairs).build();
+	let (prefix, hash) = {
+		let t = built.trie();
+		TrieDBNodeIterator::new(&t).unwrap()
+			.map(|item| item.unwrap())
+			.find(|(prefix, hash, _)| prefix.len() == 1 && prefix.at(0) == 1 && hash.is_some())
+			.map(|(prefix, hash, _)| {
+				let prefix = prefix.as_prefix();
+				((prefix.0.to_vec(), prefix.1), hash.unwrap())
+			})
+			.unwrap()
+	};
+	built.remove_node(&hash);
+	(built, hash, prefix)
+}
+
+fn assert_missing<L: TrieLayout<Hash = KeccakHasher>>(
+	error: TrieError<Root, trie_db::CError<L>>,
+	expected_hash: Root,
+	expected_prefix: &OwnedPrefix,
+) {
+	assert_eq!(error.missing_node_hash(), Some(&expected_hash));
+	match error {
+		TrieError::IncompleteDatabase { hash, prefix } => {
+			assert_eq!(hash, expected_hash);
+			assert_eq!(&prefix, expected_prefix);
+		},
+		error => panic!("unexpected error {:?}", error),
+	}
+}
+
+fn reports_hash_and_prefix<L: TrieLayout<Hash = KeccakHasher>>() {
+	let (mut built, hash, prefix) = build::<L>();
+	assert_eq!(prefix, (Vec::new(), Some(0x10)));
+
+	{
+		let t = built.trie();
+		assert_missing::<L>(*t.get(&[0x12, 2]).unwrap_err(), hash, &prefix);
+		assert_eq!(t.get(&[0x22, 2]).unwrap(), Some(vec![10; 32]));
+
+		let error = t.iter().unwrap().find_map(|item| item.err()).unwrap();
+		assert_missing::<L>(*error, hash, &prefix);
+
+		let mut iter = t.iter().unwrap();
+		assert_missing::<L>(*iter.seek(&[0x13]).unwrap_err(), hash, &prefix);
+		let mut iter = t.iter().unwrap();
+		iter.seek(&[0x20]).unwrap();
+		assert_eq!(iter.next().unwrap().unwrap().0, vec![0x20, 0]);
+	}
+
+	let mut t = built.trie_mut();
+	assert_missing::<L>(*t.insert(&[0x11, 9], &[9; 32]).unwrap_err(), hash, &prefix);
+}
+
+#[test]
+fn reports_hash_and_prefix_with_extension() {
+	reports_hash_and_prefix::<ExtensionLayout>();
+}
+
+#[test]
+fn reports_hash_and_prefix_without_extension() {
+	reports_hash_and_prefix::<NoExtensionLayout>();
+}
+
+#[test]
+fn missing_root_hash() {
+	let (built, _, _) = build::<ExtensionLayout>();
+	let missing = KeccakHasher::hash(b"missing");
+	let error = TrieDB::<ExtensionLayout>::new(built.db(), &missing).err().unwrap();
+	assert_eq!(error.missing_node_hash(), Some(&missing));
+	let error: TrieError<Root, ()> = TrieError::ValueAtIncompleteKey(Vec::new(), 0);
+	assert_eq!(error.missing_node_hash(), None);
+}
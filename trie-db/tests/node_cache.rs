@@ -0,0 +1,330 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads of `TrieDB` through a `NodeCache` and a `ValueCache`, against reads without cache, and
+//! `NodeCache` saved and loaded again.
+
+use hash_db::Hasher;
+use keccak_hasher::KeccakHasher;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{DBValue, LoadSummary, NodeCache, Trie, TrieDB, TrieMut};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+type Root = <KeccakHasher as Hasher>::Out;
+
+fn random_keys(seed: u64, count: usize) -> Vec<Vec<u8>> {
+	let mut rng = SmallRng::seed_from_u64(seed);
+	(0..count).map(|_| {
+		let len = rng.gen_range(1, 6);
+		(0..len).map(|_| rng.gen_range(0, 8)).collect()
+	}).collect()
+}
+
+fn build<L: TrieLayout<Hash = KeccakHasher>>(keys: &[Vec<u8>]) -> BuiltTrie<L> {
+	TrieBuilder::new().extend(keys.iter().map(|key| (key, [key.len() as u8; 40]))).build()
+}
+
+fn repeated_lookups_hit<L: TrieLayout<Hash = KeccakHasher>>() {
+	let keys = random_keys(1, 200);
+	let built = build::<L>(&keys[..150]);
+	let (db, root) = (built.db(), built.root());
+	let uncached = TrieDB::<L>::new(db, root).unwrap();
+	let cache = NodeCache::<L>::new(1 << 20);
+	let t = TrieDB::<L>::new_with_cache(db, root, &cache).unwrap();
+
+	for key in keys.iter() {
+		assert_eq!(t.get(key).unwrap(), uncached.get(key).unwrap());
+	}
+	let first = cache.stats();
+	assert!(first.misses > 0 && first.nodes > 0);
+	for key in keys.iter() {
+		assert_eq!(t.get(key).unwrap(), uncached.get(key).unwrap());
+	}
+	let second = cache.stats();
+	assert_eq!(second.misses, first.misses);
+	assert!(second.hits > first.hits);
+	assert!(second.hit_rate() > first.hit_rate());
+}
+
+#[test]
+fn repeated_lookups_hit_with_extension() {
+	repeated_lookups_hit::<ExtensionLayout>();
+}
+
+#[test]
+fn repeated_lookups_hit_without_extension() {
+	repeated_lookups_hit::<NoExtensionLayout>();
+}
+
+fn shared_between_roots<L: TrieLayout<Hash = KeccakHasher>>() {
+	let keys = random_keys(2, 200);
+	let (mut db, root) = build::<L>(&keys[..100]).into_parts();
+	let (other_db, other_root) = build::<L>(&keys).into_parts();
+	db.consolidate(other_db);
+
+	let cache = NodeCache::<L>::new(1 << 20);
+	let t = TrieDB::<L>::new_with_cache(&db, &root, &cache).unwrap();
+	let other = TrieDB::<L>::new_with_cache(&db, &other_root, &cache).unwrap();
+	for key in keys.iter() {
+		let in_first = keys[..100].contains(key);
+		assert_eq!(t.get(key).unwrap().is_some(), in_first);
+		assert!(other.get(key).unwrap().is_some());
+	}
+
+	// the nodes both tries read were decoded when read by the first one.
+	let before = cache.stats();
+	let iter = TrieDB::<L>::new_with_cache(&db, &other_root, &cache).unwrap();
+	assert_eq!(iter.iter().unwrap().count(), other.iter().unwrap().count());
+	assert!(cache.stats().hits > before.hits);
+}
+
+#[test]
+fn shared_between_roots_with_extension() {
+	shared_between_roots::<ExtensionLayout>();
+}
+
+#[test]
+fn shared_between_roots_without_extension() {
+	shared_between_roots::<NoExtensionLayout>();
+}
+
+#[test]
+fn iterations_read_the_cache() {
+	let keys = random_keys(3, 150);
+	let built = build::<ExtensionLayout>(&keys);
+	let (db, root) = (built.db(), built.root());
+	let uncached = TrieDB::<ExtensionLayout>::new(db, root).unwrap();
+	let expected: Vec<_> = uncached.iter().unwrap().map(|item| item.unwrap()).collect();
+
+	let cache = NodeCache::<ExtensionLayout>::new(1 << 20);
+	let t = TrieDB::<ExtensionLayout>::new_with_cache(db, root, &cache).unwrap();
+	let pairs: Vec<_> = t.iter().unwrap().map(|item| item.unwrap()).collect();
+	assert_eq!(pairs, expected);
+	let misses = cache.stats().misses;
+	let pairs: Vec<_> = t.iter().unwrap().map(|item| item.unwrap()).collect();
+	assert_eq!(pairs, expected);
+	assert_eq!(cache.stats().misses, misses);
+
+	// lookups find the nodes read by the iterations.
+	for key in keys.iter() {
+		assert!(t.get(key).unwrap().is_some());
+	}
+	assert_eq!(cache.stats().misses, misses);
+}
+
+#[test]
+fn cache_stays_within_its_budget() {
+	let keys = random_keys(4, 200);
+	let built = build::<NoExtensionLayout>(&keys);
+	let (db, root) = (built.db(), built.root());
+	let uncached = TrieDB::<NoExtensionLayout>::new(db, root).unwrap();
+	let cache = NodeCache::<NoExtensionLayout>::new(1024);
+	let t = TrieDB::<NoExtensionLayout>::new_with_cache(db, root, &cache).unwrap();
+	for _ in 0..2 {
+		for key in keys.iter() {
+			assert_eq!(t.get(key).unwrap(), uncached.get(key).unwrap());
+			assert!(cache.stats().bytes <= 1024);
+		}
+	}
+	assert!(cache.stats().nodes > 0);
+
+	cache.clear();
+	assert_eq!((cache.stats().nodes, cache.stats().bytes), (0, 0));
+	assert_eq!(t.get(&keys[0]).unwrap(), uncached.get(&keys[0]).unwrap());
+}
+
+fn values_follow_the_root<L: TrieLayout<Hash = KeccakHasher>>() {
+	let mut keys = random_keys(5, 200);
+	keys.sort();
+	keys.dedup();
+	let (mut db, root) = build::<L>(&keys[..keys.len() / 2]).into_parts();
+	// the second root changes the values of some keys, removes some and adds others.
+	let mut other = build::<L>(&keys[..keys.len() / 2]);
+	{
+		let mut t = other.trie_mut();
+		for (i, key) in keys.iter().enumerate().skip(keys.len() / 4) {
+			match i % 3 {
+				0 => t.remove(key).map(|_| ()).unwrap(),
+				_ => t.insert(key, &[i as u8; 20]).map(|_| ()).unwrap(),
+			}
+		}
+	}
+	let (other_db, other_root) = other.into_parts();
+	db.consolidate(other_db);
+
+	let uncached = TrieDB::<L>::new(&db, &root).unwrap();
+	let other_uncached = TrieDB::<L>::new(&db, &other_root).unwrap();
+	let cache = NodeCache::<L>::with_values(1 << 20, 1 << 20);
+	let t = TrieDB::<L>::new_with_cache(&db, &root, &cache).unwrap();
+	let other = TrieDB::<L>::new_with_cache(&db, &other_root, &cache).unwrap();
+	for _ in 0..2 {
+		for key in keys.iter() {
+			assert_eq!(t.get(key).unwrap(), uncached.get(key).unwrap());
+			assert_eq!(other.get(key).unwrap(), other_uncached.get(key).unwrap());
+		}
+	}
+	let values = cache.values().unwrap().stats();
+	assert_eq!(values.misses, 2 * keys.len() as u64);
+	assert_eq!(values.hits, 2 * keys.len() as u64);
+	assert_eq!(values.entries, 2 * keys.len());
+
+	// absent keys are answered by the cache, without reading any node.
+	let nodes = cache.stats();
+	for key in keys.iter().filter(|key| uncached.get(key).unwrap().is_none()) {
+		assert_eq!(t.get(key).unwrap(), None);
+	}
+	assert_eq!(cache.stats(), nodes);
+
+	// a trie opened again at a root reads the values cached for it.
+	let reopened = TrieDB::<L>::new_with_cache(&db, &other_root, &cache).unwrap();
+	for key in keys.iter() {
+		assert_eq!(reopened.get(key).unwrap(), other_uncached.get(key).unwrap());
+	}
+	assert_eq!(cache.values().unwrap().stats().misses, 2 * keys.len() as u64);
+}
+
+#[test]
+fn values_follow_the_root_with_extension() {
+	values_follow_the_root::<ExtensionLayout>();
+}
+
+#[test]
+fn values_follow_the_root_without_extension() {
+	values_follow_the_root::<NoExtensionLayout>();
+}
+
+#[test]
+fn value_cache_stays_within_its_budget() {
+	let keys = random_keys(6, 200);
+	let built = build::<ExtensionLayout>(&keys);
+	let (db, root) = (built.db(), built.root());
+	let uncached = TrieDB::<ExtensionLayout>::new(db, root).unwrap();
+	let cache = NodeCache::<ExtensionLayout>::with_values(1 << 20, 1024);
+	let t = TrieDB::<ExtensionLayout>::new_with_cache(db, root, &cache).unwrap();
+	for key in keys.iter() {
+		assert_eq!(t.get(key).unwrap(), uncached.get(key).unwrap());
+		assert!(cache.values().unwrap().stats().bytes <= 1024);
+	}
+
+	// the most recent lookups are kept.
+	let values = cache.values().unwrap();
+	let hits = values.stats().hits;
+	let last = &keys[keys.len() - 1];
+	assert_eq!(t.get(last).unwrap(), uncached.get(last).unwrap());
+	assert_eq!(values.stats().hits, hits + 1);
+
+	values.clear();
+	assert_eq!((values.stats().entries, values.stats().bytes), (0, 0));
+	assert!(NodeCache::<ExtensionLayout>::new(1024).values().is_none());
+}
+
+/// Read every key of `keys` from the trie at `root` through `cache`.
+fn read_all<L: TrieLayout<Hash = KeccakHasher>>(
+	cache: &NodeCache<L>,
+	db: &MemoryDB,
+	root: &Root,
+	keys: &[Vec<u8>],
+) {
+	let t = TrieDB::<L>::new_with_cache(db, root, cache).unwrap();
+	for key in keys {
+		assert_eq!(t.get(key).unwrap(), Some(vec![key.len() as u8; 40]));
+	}
+}
+
+/// Offsets of the nodes of the entries of a saved cache.
+fn node_offsets(saved: &[u8]) -> Vec<usize> {
+	let mut offsets = Vec::new();
+	let mut offset = 4;
+	while offset < saved.len() {
+		let mut len = [0u8; 4];
+		len.copy_from_slice(&saved[offset + 32..offset + 36]);
+		offsets.push(offset + 36);
+		offset += 36 + u32::from_le_bytes(len) as usize;
+	}
+	offsets
+}
+
+fn saved_cache_round_trips<L: TrieLayout<Hash = KeccakHasher>>() {
+	let keys = random_keys(7, 200);
+	let built = build::<L>(&keys);
+	let (db, root) = (built.db(), built.root());
+	let cache = NodeCache::<L>::new(1 << 20);
+	read_all(&cache, db, root, &keys);
+	let mut saved = Vec::new();
+	cache.save_to(&mut saved).unwrap();
+
+	let loaded = NodeCache::<L>::new(1 << 20);
+	let summary = loaded.load_from(&saved[..], usize::MAX).unwrap();
+	assert_eq!(summary, LoadSummary { loaded: cache.stats().nodes, corrupt: 0 });
+	assert_eq!(loaded.stats().bytes, cache.stats().bytes);
+	read_all(&loaded, db, root, &keys);
+	assert_eq!(loaded.stats().misses, 0);
+
+	// loading is bounded, the most recently used nodes being loaded first.
+	let bounded = NodeCache::<L>::new(1 << 20);
+	let summary = bounded.load_from(&saved[..], 1000).unwrap();
+	assert!(summary.loaded > 0 && summary.loaded < cache.stats().nodes);
+	assert!(bounded.stats().bytes <= 1000);
+	assert!(bounded.get(root).is_some());
+
+	// so is it by the size of the cache, and the nodes already cached are kept.
+	let small = NodeCache::<L>::new(1000);
+	read_all(&small, db, root, &keys[..1]);
+	let before = small.stats();
+	let summary = small.load_from(&saved[..], usize::MAX).unwrap();
+	assert!(summary.loaded > 0 && summary.loaded < cache.stats().nodes);
+	assert_eq!(small.stats().nodes, before.nodes + summary.loaded);
+	assert!(small.stats().bytes <= 1000);
+	read_all(&small, db, root, &keys[..1]);
+	assert_eq!(small.stats().misses, before.misses);
+}
+
+#[test]
+fn saved_cache_round_trips_with_extension() {
+	saved_cache_round_trips::<ExtensionLayout>();
+}
+
+#[test]
+fn saved_cache_round_trips_without_extension() {
+	saved_cache_round_trips::<NoExtensionLayout>();
+}
+
+#[test]
+fn corrupt_entries_are_skipped() {
+	let keys = random_keys(8, 200);
+	let built = build::<ExtensionLayout>(&keys);
+	let (db, root) = (built.db(), built.root());
+	let cache = NodeCache::<ExtensionLayout>::new(1 << 20);
+	read_all(&cache, db, root, &keys);
+	let mut saved = Vec::new();
+	cache.save_to(&mut saved).unwrap();
+	let offsets = node_offsets(&saved);
+	assert_eq!(offsets.len(), cache.stats().nodes);
+	for offset in offsets.iter().skip(1).step_by(10).take(3) {
+		saved[*offset] ^= 0xff;
+	}
+
+	let loaded = NodeCache::<ExtensionLayout>::new(1 << 20);
+	let summary = loaded.load_from(&saved[..], usize::MAX).unwrap();
+	assert_eq!(summary, LoadSummary { loaded: cache.stats().nodes - 3, corrupt: 3 });
+	read_all(&loaded, db, root, &keys);
+	// only the skipped nodes are read from the database.
+	assert!(loaded.stats().hits > 0);
+	assert_eq!(loaded.stats().misses, 3);
+
+	assert!(loaded.load_from(&b"nope"[..], usize::MAX).is_err());
+	assert!(loaded.load_from(&saved[..saved.len() - 1], usize::MAX).is_err());
+}
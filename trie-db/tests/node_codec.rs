@@ -0,0 +1,232 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Round trips of the nodes of every kind through `encode_node` and the reference codecs.
+
+use reference_trie::node::{encode_node, Node, NodeHandle};
+use reference_trie::{
+	BinaryLayout, Children, ExtensionLayout, NibbleOps, NibbleSlice, NoExtensionLayout, NodeCodec,
+	TrieError, TrieLayout,
+};
+use trie_db::node::OwnedNode;
+
+const HASH: [u8; 32] = [0xaa; 32];
+const INLINE: &[u8] = &[0x01, 0x02, 0x03];
+
+/// Children of a branch with a child at each of `indices`, alternating hashes and inline nodes.
+fn children<N: NibbleOps>(indices: &[usize]) -> Children<NodeHandle<'static>, N> {
+	let mut children = Children::new();
+	for (n, &i) in indices.iter().enumerate() {
+		children[i] = Some(if n % 2 == 0 {
+			NodeHandle::Hash(&HASH)
+		} else {
+			NodeHandle::Inline(INLINE)
+		});
+	}
+	children
+}
+
+/// Encode `node`, decode it back, and encode the decoded node again.
+fn round_trip<L: TrieLayout>(node: Node<L::Nibble>) -> Vec<u8> {
+	let encoded = encode_node::<L>(&node).unwrap();
+	let decoded = L::Codec::decode(&encoded).unwrap();
+	assert_eq!(decoded, node);
+	assert_eq!(encode_node::<L>(&decoded).unwrap(), encoded);
+	encoded
+}
+
+const ALL: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+#[test]
+fn every_node_round_trips_with_extension() {
+	round_trip::<ExtensionLayout>(Node::Empty);
+	round_trip::<ExtensionLayout>(Node::Leaf(NibbleSlice::new(&[]), b"value"));
+	round_trip::<ExtensionLayout>(Node::Leaf(NibbleSlice::new(&[0x12, 0x34]), b"value"));
+	round_trip::<ExtensionLayout>(Node::Leaf(NibbleSlice::new_offset(&[0x12, 0x34], 1), b"value"));
+	round_trip::<ExtensionLayout>(Node::Extension(
+		NibbleSlice::new(&[0x12]),
+		NodeHandle::Hash(&HASH),
+	));
+	round_trip::<ExtensionLayout>(Node::Extension(
+		NibbleSlice::new_offset(&[0x12, 0x34], 1),
+		NodeHandle::Inline(INLINE),
+	));
+	for value in [None, Some(&b"value"[..])] {
+		round_trip::<ExtensionLayout>(Node::Branch(children(&ALL), value));
+		round_trip::<ExtensionLayout>(Node::Branch(children(&[0]), value));
+		round_trip::<ExtensionLayout>(Node::Branch(children(&[15]), value));
+		round_trip::<ExtensionLayout>(Node::Branch(children(&[3, 9]), value));
+	}
+}
+
+#[test]
+fn every_node_round_trips_without_extension() {
+	round_trip::<NoExtensionLayout>(Node::Empty);
+	round_trip::<NoExtensionLayout>(Node::Leaf(NibbleSlice::new(&[]), b"value"));
+	round_trip::<NoExtensionLayout>(Node::Leaf(NibbleSlice::new(&[0x12, 0x34]), b"value"));
+	round_trip::<NoExtensionLayout>(Node::Leaf(NibbleSlice::new_offset(&[0x12, 0x34], 1), b"value"));
+	for value in [None, Some(&b"value"[..])] {
+		for partial in [NibbleSlice::new(&[]), NibbleSlice::new_offset(&[0x12, 0x34], 1)] {
+			round_trip::<NoExtensionLayout>(Node::NibbledBranch(partial, children(&ALL), value));
+			round_trip::<NoExtensionLayout>(Node::NibbledBranch(partial, children(&[0]), value));
+			round_trip::<NoExtensionLayout>(Node::NibbledBranch(partial, children(&[15]), value));
+			round_trip::<NoExtensionLayout>(Node::NibbledBranch(partial, children(&[3, 9]), value));
+		}
+	}
+}
+
+#[test]
+fn every_node_round_trips_binary() {
+	round_trip::<BinaryLayout>(Node::Empty);
+	round_trip::<BinaryLayout>(Node::Leaf(NibbleSlice::new(&[]), b"value"));
+	round_trip::<BinaryLayout>(Node::Leaf(NibbleSlice::new(&[0x12, 0x34]), b"value"));
+	for offset in 1..8 {
+		let partial = NibbleSlice::new_offset(&[0x12, 0x34], offset);
+		round_trip::<BinaryLayout>(Node::Leaf(partial, b"value"));
+	}
+	for value in [None, Some(&b"value"[..])] {
+		for partial in [NibbleSlice::new(&[]), NibbleSlice::new_offset(&[0x12, 0x34], 3)] {
+			round_trip::<BinaryLayout>(Node::NibbledBranch(partial, children(&[0, 1]), value));
+			round_trip::<BinaryLayout>(Node::NibbledBranch(partial, children(&[1]), value));
+		}
+	}
+}
+
+#[test]
+fn binary_nodes_pack_eight_nibbles_a_byte() {
+	// 11 nibbles: the last 3 bits of the first byte, and a full byte.
+	let encoded = round_trip::<BinaryLayout>(
+		Node::Leaf(NibbleSlice::new_offset(&[0x05, 0x34], 5), b"value")
+	);
+	assert_eq!(&encoded[..3], &[(0b01 << 6) | 11, 0x05, 0x34]);
+	// A single byte bitmap for the two children.
+	let encoded = round_trip::<BinaryLayout>(
+		Node::NibbledBranch(NibbleSlice::new(&[]), children(&[0, 1]), None)
+	);
+	assert_eq!(&encoded[..2], &[0b10 << 6, 0b11]);
+}
+
+#[test]
+fn binary_codec_rejects_invalid_nodes() {
+	type Codec = <BinaryLayout as TrieLayout>::Codec;
+	let leaf = encode_node::<BinaryLayout>(
+		&Node::Leaf(NibbleSlice::new_offset(&[0x05, 0x34], 5), b"value")
+	).unwrap();
+	// A set padding bit before the partial key.
+	let mut padded = leaf.clone();
+	padded[1] |= 0x80;
+	assert!(Codec::decode(&padded).is_err());
+
+	// A child at an index over 1 in the bitmap.
+	let branch = encode_node::<BinaryLayout>(
+		&Node::NibbledBranch(NibbleSlice::new(&[]), children(&[0, 1]), None)
+	).unwrap();
+	let mut over = branch.clone();
+	over[1] |= 0b100;
+	assert!(Codec::decode(&over).is_err());
+
+	// The two bytes bitmap of the radix 16 encoding.
+	let hex = encode_node::<NoExtensionLayout>(
+		&Node::NibbledBranch(NibbleSlice::new(&[]), children(&[0, 1]), None)
+	).unwrap();
+	assert!(Codec::decode(&hex).is_err());
+}
+
+#[test]
+fn decoded_nodes_re_encode_their_bytes() {
+	// A child reference edited in the decoded node, as a repair tool does.
+	let encoded = round_trip::<NoExtensionLayout>(
+		Node::NibbledBranch(NibbleSlice::new(&[0x12]), children(&[1, 2]), None)
+	);
+	let mut node = <NoExtensionLayout as TrieLayout>::Codec::decode(&encoded).unwrap();
+	let other = [0xbb; 32];
+	if let Node::NibbledBranch(_, children, _) = &mut node {
+		children[1] = Some(NodeHandle::Hash(&other));
+	}
+	let edited = encode_node::<NoExtensionLayout>(&node).unwrap();
+	assert_eq!(edited.len(), encoded.len());
+	assert_eq!(<NoExtensionLayout as TrieLayout>::Codec::decode(&edited).unwrap(), node);
+}
+
+#[test]
+fn invalid_child_handles_are_rejected() {
+	let long = [0x01; 33];
+	for child in [NodeHandle::Hash(&HASH[..31]), NodeHandle::Inline(&long)] {
+		let node = Node::Branch(children(&[]), None);
+		let node = match node {
+			Node::Branch(mut children, value) => {
+				children[4] = Some(child);
+				Node::Branch(children, value)
+			},
+			_ => unreachable!(),
+		};
+		match encode_node::<ExtensionLayout>(&node).map_err(|error| *error) {
+			Err(TrieError::InvalidHash(_, data)) => assert!(data.len() == 31 || data.len() == 33),
+			result => panic!("unexpected result: {:?}", result),
+		}
+	}
+}
+
+/// The non-canonical encodings the codecs decode, encoded differently once decoded.
+#[test]
+fn non_canonical_encodings_re_encode_differently() {
+	// With extensions, the padding nibble of an odd partial key is not checked.
+	let canonical = encode_node::<ExtensionLayout>(
+		&Node::Leaf(NibbleSlice::new_offset(&[0x02, 0x34], 1), b"value")
+	).unwrap();
+	let mut padded = canonical.clone();
+	let partial_start = 1;
+	assert_eq!(padded[partial_start], 0x02);
+	padded[partial_start] |= 0x50;
+	let decoded = <ExtensionLayout as TrieLayout>::Codec::decode(&padded).unwrap();
+	assert_eq!(encode_node::<ExtensionLayout>(&decoded).unwrap(), canonical);
+	// Without extensions, the padding is checked instead.
+	let canonical = encode_node::<NoExtensionLayout>(
+		&Node::Leaf(NibbleSlice::new_offset(&[0x02, 0x34], 1), b"value")
+	).unwrap();
+	let mut padded = canonical.clone();
+	assert_eq!(padded[partial_start], 0x02);
+	padded[partial_start] |= 0x50;
+	assert!(<NoExtensionLayout as TrieLayout>::Codec::decode(&padded).is_err());
+
+	// A length in a longer compact encoding than needed is rejected too.
+	let mut long_length = canonical[..canonical.len() - 6].to_vec();
+	long_length.extend_from_slice(&[(5 << 2) | 1, 0]);
+	long_length.extend_from_slice(b"value");
+	assert!(<NoExtensionLayout as TrieLayout>::Codec::decode(&long_length).is_err());
+
+	// An inline child as long as a hash is decoded as a hash, with the same encoding.
+	let inline = [0x01; 32];
+	let mut as_inline = children(&[]);
+	as_inline[0] = Some(NodeHandle::Inline(&inline));
+	let encoded = encode_node::<ExtensionLayout>(&Node::Branch(as_inline, None)).unwrap();
+	let mut as_hash = children(&[]);
+	as_hash[0] = Some(NodeHandle::Hash(&inline));
+	assert_eq!(
+		<ExtensionLayout as TrieLayout>::Codec::decode(&encoded).unwrap(),
+		Node::Branch(as_hash, None),
+	);
+}
+
+#[test]
+fn owned_nodes_clone() {
+	let encoded = encode_node::<ExtensionLayout>(&Node::Branch(children(&[0, 7]), Some(b"value")))
+		.unwrap();
+	let owned = OwnedNode::new::<<ExtensionLayout as TrieLayout>::Codec>(encoded).unwrap();
+	let copy = owned.clone();
+	assert_eq!(copy, owned);
+	assert_eq!(copy.node(), owned.node());
+	drop(owned);
+	assert_eq!(copy.node(), Node::Branch(children(&[0, 7]), Some(b"value")));
+}
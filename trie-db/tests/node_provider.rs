@@ -0,0 +1,200 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `TrieDB` reading the nodes missing from its database from a node provider, backed by a
+//! second database standing for the network.
+
+use std::cell::Cell;
+
+use hash_db::{HashDB, HashDBRef, Hasher, Prefix};
+use keccak_hasher::KeccakHasher;
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{
+	DBValue, NodeProvider, NodeProviderError, Trie, TrieDBBuilder, TrieDBNodeIterator, TrieError,
+};
+use trie_db::TrieDB;
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+type Root = <KeccakHasher as Hasher>::Out;
+
+/// Provider reading the nodes of a remote database, counting the fetches.
+struct Remote<'a> {
+	db: &'a MemoryDB,
+	fetches: Cell<usize>,
+}
+
+impl<'a> NodeProvider<KeccakHasher> for Remote<'a> {
+	fn provide(&self, hash: &Root, prefix: Prefix) -> Result<Option<DBValue>, NodeProviderError> {
+		self.fetches.set(self.fetches.get() + 1);
+		Ok(HashDBRef::get(self.db, hash, prefix))
+	}
+}
+
+/// Provider which cannot reach the network.
+struct Unreachable;
+
+impl NodeProvider<KeccakHasher> for Unreachable {
+	fn provide(&self, _: &Root, _: Prefix) -> Result<Option<DBValue>, NodeProviderError> {
+		Err(NodeProviderError)
+	}
+}
+
+fn keys() -> Vec<Vec<u8>> {
+	(0..100u8).map(|i| vec![i / 10, i % 10, i]).collect()
+}
+
+fn build_trie<L: TrieLayout<Hash = KeccakHasher>>() -> BuiltTrie<L> {
+	TrieBuilder::new().extend(keys().into_iter().map(|key| (key.clone(), [key[2]; 40]))).build()
+}
+
+/// The full database, a local database holding the root node only, and the root.
+fn build<L: TrieLayout<Hash = KeccakHasher>>() -> (MemoryDB, MemoryDB, Root) {
+	let remote = build_trie::<L>();
+	let mut local = build_trie::<L>();
+	let nodes: Vec<_> = TrieDBNodeIterator::new(&remote.trie()).unwrap()
+		.filter_map(|item| {
+			let (prefix, hash, _) = item.unwrap();
+			hash.filter(|_| !prefix.is_empty())
+		})
+		.collect();
+	// children first, each node being found from its parent.
+	for hash in nodes.iter().rev() {
+		local.remove_node(hash);
+	}
+	let (remote, root) = remote.into_parts();
+	(remote, local.into_parts().0, root)
+}
+
+fn reads_missing_nodes_from_the_provider<L: TrieLayout<Hash = KeccakHasher>>() {
+	let (remote_db, local, root) = build::<L>();
+	let full = TrieDB::<L>::new(&remote_db, &root).unwrap();
+	let expected: Vec<_> = full.iter().unwrap().map(|item| item.unwrap()).collect();
+
+	// without provider, the nodes below the root are missing.
+	let t = TrieDB::<L>::new(&local, &root).unwrap();
+	assert!(matches!(*t.get(&keys()[0]).unwrap_err(), TrieError::IncompleteDatabase { .. }));
+
+	let remote = Remote { db: &remote_db, fetches: Cell::new(0) };
+	let t = TrieDBBuilder::<L>::new(&local, &root).with_node_provider(&remote).build().unwrap();
+	for key in keys() {
+		assert_eq!(t.get(&key).unwrap(), full.get(&key).unwrap());
+	}
+	assert!(t.get(&[200]).unwrap().is_none());
+	let pairs: Vec<_> = t.iter().unwrap().map(|item| item.unwrap()).collect();
+	assert_eq!(pairs, expected);
+	assert!(remote.fetches.get() > 0);
+	assert!(t.provided_nodes().is_empty());
+}
+
+#[test]
+fn reads_missing_nodes_from_the_provider_with_extension() {
+	reads_missing_nodes_from_the_provider::<ExtensionLayout>();
+}
+
+#[test]
+fn reads_missing_nodes_from_the_provider_without_extension() {
+	reads_missing_nodes_from_the_provider::<NoExtensionLayout>();
+}
+
+#[test]
+fn provided_nodes_are_fetched_once() {
+	let (remote_db, mut local, root) = build::<ExtensionLayout>();
+	let remote = Remote { db: &remote_db, fetches: Cell::new(0) };
+	let provided = {
+		let t = TrieDBBuilder::<ExtensionLayout>::new(&local, &root)
+			.with_node_provider(&remote)
+			.keep_provided_nodes()
+			.build()
+			.unwrap();
+		assert_eq!(t.iter().unwrap().count(), keys().len());
+		let fetches = remote.fetches.get();
+		assert_eq!(t.iter().unwrap().count(), keys().len());
+		for key in keys() {
+			assert!(t.get(&key).unwrap().is_some());
+		}
+		assert_eq!(remote.fetches.get(), fetches);
+		let provided = t.provided_nodes();
+		assert_eq!(provided.len(), fetches);
+		provided
+	};
+
+	// the provided nodes complete the local database.
+	local.emplace_batch(provided);
+	let t = TrieDB::<ExtensionLayout>::new(&local, &root).unwrap();
+	assert_eq!(t.iter().unwrap().count(), keys().len());
+}
+
+#[test]
+fn closures_provide_nodes() {
+	let (remote_db, local, root) = build::<NoExtensionLayout>();
+	let remote = |hash: &Root, prefix: Prefix| HashDBRef::get(&remote_db, hash, prefix);
+	let t = TrieDBBuilder::<NoExtensionLayout>::new(&local, &root)
+		.with_node_provider(&remote)
+		.build()
+		.unwrap();
+	for key in keys() {
+		assert_eq!(t.get(&key).unwrap(), Some(vec![key[2]; 40]));
+	}
+
+	// a root the provider has as the only node.
+	let empty = MemoryDB::new(&[]);
+	let t = TrieDBBuilder::<NoExtensionLayout>::new(&empty, &root)
+		.with_node_provider(&remote)
+		.build()
+		.unwrap();
+	assert_eq!(t.get(&keys()[5]).unwrap(), Some(vec![5; 40]));
+}
+
+#[test]
+fn provider_failures_are_not_absences() {
+	let (_, local, root) = build::<ExtensionLayout>();
+
+	// the provider does not have the nodes either: they are missing.
+	let nowhere = |_: &Root, _: Prefix| None;
+	let t = TrieDBBuilder::<ExtensionLayout>::new(&local, &root)
+		.with_node_provider(&nowhere)
+		.build()
+		.unwrap();
+	assert!(matches!(*t.get(&keys()[0]).unwrap_err(), TrieError::IncompleteDatabase { .. }));
+	let missing_root = KeccakHasher::hash(b"missing");
+	let error = TrieDBBuilder::<ExtensionLayout>::new(&local, &missing_root)
+		.with_node_provider(&nowhere)
+		.build()
+		.err()
+		.unwrap();
+	assert_eq!(*error, TrieError::InvalidStateRoot(missing_root));
+
+	// the provider fails: the failure is reported for the node it was asked for.
+	let t = TrieDBBuilder::<ExtensionLayout>::new(&local, &root)
+		.with_node_provider(&Unreachable)
+		.build()
+		.unwrap();
+	match *t.get(&keys()[0]).unwrap_err() {
+		TrieError::NodeProviderFailed(hash) => assert_ne!(hash, root),
+		ref error => panic!("unexpected error {:?}", error),
+	}
+	assert!(matches!(
+		t.iter().unwrap().find_map(|item| item.err()).map(|error| *error),
+		Some(TrieError::NodeProviderFailed(_)),
+	));
+
+	// the provider returns another node: the node does not match its hash.
+	let wrong = |_: &Root, _: Prefix| Some(vec![0u8; 4]);
+	let t = TrieDBBuilder::<ExtensionLayout>::new(&local, &root)
+		.with_node_provider(&wrong)
+		.build()
+		.unwrap();
+	assert!(matches!(*t.get(&keys()[0]).unwrap_err(), TrieError::HashMismatch { .. }));
+}
@@ -0,0 +1,110 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "codec")]
+
+use keccak_hasher::KeccakHasher;
+use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut};
+use trie_db::{decode_pairs, encode_pairs_to, DBValue, PAIRS_CHUNK_SIZE};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+
+fn build_trie() -> (MemoryDB, <KeccakHasher as hash_db::Hasher>::Out) {
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut trie = RefTrieDBMut::new(&mut db, &mut root);
+		trie.insert(b"pra", b"before").unwrap();
+		trie.insert(b"pre", b"prefix itself").unwrap();
+		for i in 0..200u16 {
+			let mut key = b"pre".to_vec();
+			key.extend_from_slice(&i.to_be_bytes());
+			trie.insert(&key, &[i as u8; 40]).unwrap();
+		}
+		trie.insert(b"prf", b"after").unwrap();
+	}
+	(db, root)
+}
+
+fn collect_pairs(trie: &RefTrieDB, prefix: &[u8]) -> Vec<(Vec<u8>, DBValue)> {
+	trie.iter().unwrap()
+		.map(|item| item.unwrap())
+		.filter(|(key, _)| key.starts_with(prefix))
+		.collect()
+}
+
+#[test]
+fn encode_pairs_matches_iteration() {
+	let (db, root) = build_trie();
+	let trie = RefTrieDB::new(&db, &root).unwrap();
+
+	for prefix in [&b""[..], b"pr", b"pre", b"pre\x00", b"prz"].iter() {
+		let expected = collect_pairs(&trie, prefix);
+		let mut out = Vec::new();
+		let (count, continuation) = encode_pairs_to(&trie, prefix, None, &mut out, None).unwrap();
+		assert_eq!(count as usize, expected.len());
+		assert_eq!(continuation, None);
+
+		let mut input = &out[..];
+		assert_eq!(decode_pairs(&mut input).unwrap(), expected);
+		assert!(input.is_empty());
+	}
+	assert!(collect_pairs(&trie, b"pre").len() > 2 * PAIRS_CHUNK_SIZE);
+}
+
+#[test]
+fn encode_pairs_paginates() {
+	let (db, root) = build_trie();
+	let trie = RefTrieDB::new(&db, &root).unwrap();
+	let expected = collect_pairs(&trie, b"pre");
+
+	for &limit in [1, 50, PAIRS_CHUNK_SIZE, expected.len()].iter() {
+		let mut pairs = Vec::new();
+		let mut start_at = None;
+		loop {
+			let mut out = Vec::new();
+			let (count, continuation) = encode_pairs_to(
+				&trie,
+				b"pre",
+				start_at.as_ref().map(|key: &Vec<u8>| &key[..]),
+				&mut out,
+				Some(limit),
+			).unwrap();
+			let page = decode_pairs(&mut &out[..]).unwrap();
+			assert_eq!(page.len(), count as usize);
+			assert!(page.len() <= limit);
+			pairs.extend(page);
+			match continuation {
+				Some(key) => {
+					assert_eq!(key, expected[pairs.len()].0);
+					start_at = Some(key);
+				}
+				None => break,
+			}
+		}
+		assert_eq!(pairs, expected);
+	}
+}
+
+#[test]
+fn encode_pairs_with_zero_limit() {
+	let (db, root) = build_trie();
+	let trie = RefTrieDB::new(&db, &root).unwrap();
+
+	let mut out = Vec::new();
+	let (count, continuation) = encode_pairs_to(&trie, b"pre", None, &mut out, Some(0)).unwrap();
+	assert_eq!(count, 0);
+	assert_eq!(continuation, Some(b"pre".to_vec()));
+	assert!(decode_pairs(&mut &out[..]).unwrap().is_empty());
+}
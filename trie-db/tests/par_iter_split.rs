@@ -0,0 +1,97 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `TrieDB::par_iter_split` ranges against a single iteration over the whole trie.
+
+use keccak_hasher::KeccakHasher;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{Trie, TrieDB};
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn build<L: TrieLayout<Hash = KeccakHasher>>(keys: &[Vec<u8>]) -> BuiltTrie<L> {
+	TrieBuilder::new().extend(keys.iter().map(|key| (key, [key.len() as u8; 4]))).build()
+}
+
+/// The pairs of each of the `n` ranges, checking the ranges are contiguous.
+fn split_pairs<L: TrieLayout<Hash = KeccakHasher>>(t: &TrieDB<L>, n: usize) -> Vec<Pairs> {
+	let ranges = t.par_iter_split(n).unwrap();
+	assert!(!ranges.is_empty() && ranges.len() <= n.max(1));
+	assert!(ranges[0].0.is_empty());
+	assert!(ranges[ranges.len() - 1].1.is_empty());
+	for window in ranges.windows(2) {
+		assert!(window[0].0 < window[0].1);
+		assert_eq!(window[0].1, window[1].0);
+	}
+	ranges.iter().map(|(start, end)| {
+		let mut iter = t.iter().unwrap();
+		iter.seek(start).unwrap();
+		iter.map(|item| item.unwrap())
+			.take_while(|(key, _)| end.is_empty() || key < end)
+			.collect()
+	}).collect()
+}
+
+fn check_split<L: TrieLayout<Hash = KeccakHasher>>(keys: &[Vec<u8>], expected_ranges: usize) {
+	let built = build::<L>(keys);
+	let t = built.trie();
+	let all: Pairs = t.iter().unwrap().map(|item| item.unwrap()).collect();
+	for &n in [0, 1, 2, 3, 16, 100].iter() {
+		let split = split_pairs(&t, n);
+		assert_eq!(split.concat(), all, "{} ranges", n);
+	}
+	assert_eq!(split_pairs(&t, 16).len(), expected_ranges);
+}
+
+fn check_random_splits<L: TrieLayout<Hash = KeccakHasher>>() {
+	let mut rng = SmallRng::seed_from_u64(7);
+	for _ in 0..20 {
+		let keys: Vec<Vec<u8>> = (0..rng.gen_range(0, 300)).map(|_| {
+			let len = rng.gen_range(0, 5);
+			(0..len).map(|_| rng.gen_range(0, 3) * 0x11).collect()
+		}).collect();
+		let built = build::<L>(&keys);
+		let t = built.trie();
+		let all: Pairs = t.iter().unwrap().map(|item| item.unwrap()).collect();
+		for n in 0..20 {
+			assert_eq!(split_pairs(&t, n).concat(), all);
+		}
+	}
+}
+
+fn check_splits<L: TrieLayout<Hash = KeccakHasher>>() {
+	// an empty trie and a single leaf have a single range.
+	check_split::<L>(&[], 1);
+	check_split::<L>(&[b"alfa".to_vec()], 1);
+	// keys with a common prefix, under an extension or a branch with a partial key, with a
+	// value at the prefix.
+	let keys: Vec<Vec<u8>> = (0..=255u8).map(|i| vec![0xaa, 0xbb, i]).collect();
+	check_split::<L>(&[&keys[..], &[vec![0xaa, 0xbb]]].concat(), 16);
+	// keys spread over the root branch.
+	let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_be_bytes()[2..].to_vec()).collect();
+	check_split::<L>(&keys, 16);
+	check_random_splits::<L>();
+}
+
+#[test]
+fn splits_with_extension() {
+	check_splits::<ExtensionLayout>();
+}
+
+#[test]
+fn splits_without_extension() {
+	check_splits::<NoExtensionLayout>();
+}
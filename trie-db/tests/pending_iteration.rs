@@ -0,0 +1,150 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Iteration over `TrieDBMut` with pending changes, against `TrieDB` once committed.
+
+use hash_db::{HashDB, Hasher};
+use keccak_hasher::KeccakHasher;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{DBValue, Trie, TrieDB, TrieDBMut, TrieError, TrieMut};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+type Root = <KeccakHasher as Hasher>::Out;
+
+fn random_key(rng: &mut SmallRng) -> Vec<u8> {
+	let len = rng.gen_range(0, 4);
+	(0..len).map(|_| rng.gen_range(0, 4)).collect()
+}
+
+fn random_value(rng: &mut SmallRng) -> Vec<u8> {
+	// short values make inline nodes.
+	let len = match rng.gen() {
+		true => rng.gen_range(1, 4),
+		false => rng.gen_range(30, 40),
+	};
+	(0..len).map(|_| rng.gen()).collect()
+}
+
+fn committed_pairs<L: TrieLayout<Hash = KeccakHasher>>(
+	db: &MemoryDB,
+	root: &Root,
+) -> Vec<(Vec<u8>, DBValue)> {
+	let trie = TrieDB::<L>::new(db, root).unwrap();
+	trie.iter().unwrap().map(|item| item.unwrap()).collect()
+}
+
+fn check_pending_iteration<L: TrieLayout<Hash = KeccakHasher>>(seed: u64) {
+	let mut rng = SmallRng::seed_from_u64(seed);
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut t = TrieDBMut::<L>::new(&mut db, &mut root);
+		for _ in 0..rng.gen_range(0, 50) {
+			t.insert(&random_key(&mut rng), &random_value(&mut rng)).unwrap();
+		}
+	}
+
+	let (mut committed_db, mut committed_root) = (db.clone(), root);
+	let mut t = TrieDBMut::<L>::from_existing(&mut db, &mut root).unwrap();
+	// inserts of new and existing keys, and removals, a few at a time.
+	for _ in 0..5 {
+		let mut committed = TrieDBMut::<L>::from_existing(&mut committed_db, &mut committed_root)
+			.unwrap();
+		for _ in 0..rng.gen_range(0, 10) {
+			let key = random_key(&mut rng);
+			match rng.gen_range(0, 3) {
+				0 => {
+					t.remove(&key).unwrap();
+					committed.remove(&key).unwrap();
+				},
+				_ => {
+					let value = random_value(&mut rng);
+					t.insert(&key, &value).unwrap();
+					committed.insert(&key, &value).unwrap();
+				},
+			}
+		}
+		drop(committed);
+		let pairs: Vec<_> = t.iter().map(|item| item.unwrap()).collect();
+		assert_eq!(pairs, committed_pairs::<L>(&committed_db, &committed_root), "seed {}", seed);
+	}
+	assert_eq!(t.committed_root(), None);
+}
+
+#[test]
+fn pending_iteration_with_extension() {
+	for seed in 0..100 {
+		check_pending_iteration::<ExtensionLayout>(seed);
+	}
+}
+
+#[test]
+fn pending_iteration_without_extension() {
+	for seed in 0..100 {
+		check_pending_iteration::<NoExtensionLayout>(seed);
+	}
+}
+
+#[test]
+fn emptied_and_new_tries_yield_nothing() {
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	let mut t = TrieDBMut::<ExtensionLayout>::new(&mut db, &mut root);
+	assert_eq!(t.iter().count(), 0);
+	t.insert(b"dog", b"cat").unwrap();
+	t.insert(b"doge", &[1; 40]).unwrap();
+	let keys: Vec<_> = t.iter().map(|item| item.unwrap().0).collect();
+	assert_eq!(keys, vec![b"dog".to_vec(), b"doge".to_vec()]);
+	t.commit();
+	t.remove(b"dog").unwrap();
+	t.remove(b"doge").unwrap();
+	assert_eq!(t.iter().count(), 0);
+}
+
+#[test]
+fn missing_nodes_are_errors() {
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut t = TrieDBMut::<ExtensionLayout>::new(&mut db, &mut root);
+		for i in 0..16u8 {
+			t.insert(&[i << 4, 0], &[i; 40]).unwrap();
+		}
+	}
+	// the leaf under the first nibble of the root branch.
+	let (prefix, hash) = {
+		let trie = TrieDB::<ExtensionLayout>::new(&db, &root).unwrap();
+		let (prefix, hash, _) = trie_db::TrieDBNodeIterator::new(&trie).unwrap()
+			.nth(1)
+			.unwrap()
+			.unwrap();
+		(prefix, hash.unwrap())
+	};
+	HashDB::remove(&mut db, &hash, prefix.as_prefix());
+
+	let mut t = TrieDBMut::<ExtensionLayout>::from_existing(&mut db, &mut root).unwrap();
+	t.insert(&[0xf0, 0x01], &[1; 40]).unwrap();
+	let items: Vec<_> = t.iter().collect();
+	match items[0] {
+		Err(ref error) => match **error {
+			TrieError::IncompleteDatabase { hash: missing, .. } => assert_eq!(missing, hash),
+			ref error => panic!("unexpected error {:?}", error),
+		},
+		ref item => panic!("unexpected item {:?}", item),
+	}
+	// the other pairs are still yielded, the pending one included.
+	assert_eq!(items.len(), 17);
+	assert!(items[1..].iter().all(|item| item.is_ok()));
+}
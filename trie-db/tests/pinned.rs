@@ -0,0 +1,278 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Iteration of pinned tries while new roots are committed to the same database.
+
+use std::sync::{Arc, Mutex};
+
+use hash_db::{AsHashDB, HashDB, HashDBRef, Hasher, OwnedPrefix, Prefix};
+use keccak_hasher::KeccakHasher;
+use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut};
+use trie_db::{DBValue, FnHashDB, PinnedRoots, PruningGuard};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+type Hash = <KeccakHasher as Hasher>::Out;
+/// Iterated item, with errors formatted for comparison.
+type Item = Result<(Vec<u8>, DBValue), String>;
+
+/// Handle on a database shared between a reader and a writer.
+#[derive(Clone, Default)]
+struct SharedDB(Arc<Mutex<MemoryDB>>);
+
+impl HashDB<KeccakHasher, DBValue> for SharedDB {
+	fn get(&self, key: &Hash, prefix: Prefix) -> Option<DBValue> {
+		HashDB::get(&*self.0.lock().unwrap(), key, prefix)
+	}
+
+	fn contains(&self, key: &Hash, prefix: Prefix) -> bool {
+		HashDB::contains(&*self.0.lock().unwrap(), key, prefix)
+	}
+
+	fn insert(&mut self, prefix: Prefix, value: &[u8]) -> Hash {
+		self.0.lock().unwrap().insert(prefix, value)
+	}
+
+	fn emplace(&mut self, key: Hash, prefix: Prefix, value: DBValue) {
+		self.0.lock().unwrap().emplace(key, prefix, value)
+	}
+
+	fn remove(&mut self, key: &Hash, prefix: Prefix) {
+		self.0.lock().unwrap().remove(key, prefix)
+	}
+}
+
+impl AsHashDB<KeccakHasher, DBValue> for SharedDB {
+	fn as_hash_db(&self) -> &dyn HashDB<KeccakHasher, DBValue> { self }
+	fn as_hash_db_mut<'a>(&'a mut self) -> &'a mut (dyn HashDB<KeccakHasher, DBValue> + 'a) {
+		self
+	}
+}
+
+/// Read-only handle on the database of `db`.
+fn reader(db: &SharedDB) -> impl HashDBRef<KeccakHasher, DBValue> {
+	let db = db.clone();
+	FnHashDB::new(move |key: &_, prefix| HashDB::get(&db, key, prefix))
+}
+
+fn key(i: u32) -> Vec<u8> {
+	i.to_be_bytes().to_vec()
+}
+
+fn build_trie(db: &mut SharedDB) -> Hash {
+	let mut root = Default::default();
+	{
+		let mut t = RefTrieDBMut::new(db, &mut root);
+		for i in 0..200 {
+			t.insert(&key(i), &[i as u8; 40]).unwrap();
+		}
+	}
+	root
+}
+
+/// Iterate the trie at `root`, committing changes through `writer` every few items.
+fn iterate_while_mutating(
+	reader: &dyn HashDBRef<KeccakHasher, DBValue>,
+	writer: &mut SharedDB,
+	root: &Hash,
+	guard: Option<&PinnedRoots<Hash>>,
+) -> (Vec<Item>, Hash) {
+	let trie = RefTrieDB::new(reader, root).unwrap();
+	let pinned = trie.pin(guard.map(|guard| guard as &dyn PruningGuard<Hash>));
+	let mut iter = pinned.iter().unwrap();
+	drop(pinned);
+
+	let mut new_root = *root;
+	let mut t = RefTrieDBMut::from_existing(writer, &mut new_root).unwrap();
+	if let Some(guard) = guard {
+		t.set_pruning_guard(guard);
+	}
+
+	let mut items = Vec::new();
+	let mut round = 0;
+	for item in iter.by_ref() {
+		items.push(item.map_err(|e| format!("{:?}", e)));
+		if items.len() % 10 == 0 {
+			// remove keys ahead of the iterator, overwrite keys behind it and add new ones.
+			for i in 0..5 {
+				t.remove(&key(199 - round * 5 - i)).unwrap();
+			}
+			t.insert(&key(round), b"overwritten").unwrap();
+			t.insert(&key(1000 + round), b"new").unwrap();
+			t.commit();
+			round += 1;
+		}
+	}
+	drop(iter);
+	t.commit();
+	drop(t);
+	(items, new_root)
+}
+
+#[test]
+fn pinned_iteration_matches_snapshot() {
+	let guard = PinnedRoots::default();
+	let mut writer = SharedDB::default();
+	let root = build_trie(&mut writer);
+	let reader = reader(&writer);
+
+	let snapshot: Vec<_> = RefTrieDB::new(&reader, &root).unwrap()
+		.iter().unwrap()
+		.map(|item| item.map_err(|e| format!("{:?}", e)))
+		.collect();
+	assert_eq!(snapshot.len(), 200);
+
+	let (items, new_root) = iterate_while_mutating(&reader, &mut writer, &root, Some(&guard));
+	assert_eq!(items, snapshot);
+	assert!(!guard.has_pins());
+
+	// the new root is complete, and the old nodes were pruned after the pins were released.
+	let trie = RefTrieDB::new(&reader, &new_root).unwrap();
+	assert_eq!(trie.get(&key(0)).unwrap(), Some(b"overwritten".to_vec()));
+	assert_eq!(trie.get(&key(1000)).unwrap(), Some(b"new".to_vec()));
+	assert_eq!(trie.get(&key(199)).unwrap(), None);
+	assert!(trie.iter().unwrap().all(|item| item.is_ok()));
+	assert!(RefTrieDB::new(&reader, &root).is_err());
+}
+
+#[test]
+fn unpinned_iteration_sees_pruned_nodes() {
+	let mut writer = SharedDB::default();
+	let root = build_trie(&mut writer);
+	let reader = reader(&writer);
+
+	let (items, _) = iterate_while_mutating(&reader, &mut writer, &root, None);
+	assert!(items.iter().any(|item| item.is_err()));
+}
+
+#[test]
+fn iterators_hold_their_own_pin() {
+	let guard = PinnedRoots::default();
+	let mut writer = SharedDB::default();
+	let root = build_trie(&mut writer);
+
+	let reader = reader(&writer);
+	let trie = RefTrieDB::new(&reader, &root).unwrap();
+	let pinned = trie.pin(Some(&guard));
+	assert!(guard.is_pinned(&root));
+	let iter = pinned.iter().unwrap();
+	let node_iter = pinned.node_iter().unwrap();
+	drop(pinned);
+	assert!(guard.is_pinned(&root));
+	drop(iter);
+	assert!(guard.is_pinned(&root));
+	drop(node_iter);
+	assert!(!guard.is_pinned(&root));
+	assert!(!guard.has_pins());
+}
+
+/// Overwrite every key of the trie at `root`, with `guard` pinning `root`, returning the new
+/// root and the removals deferred by the guard.
+fn overwrite_pinned(
+	writer: &mut SharedDB,
+	root: &Hash,
+	guard: &PinnedRoots<Hash>,
+) -> (Hash, Vec<(Hash, OwnedPrefix)>) {
+	let reader = reader(writer);
+	let trie = RefTrieDB::new(&reader, root).unwrap();
+	let pinned = trie.pin(Some(guard));
+	let mut new_root = *root;
+	let mut t = RefTrieDBMut::from_existing(writer, &mut new_root).unwrap();
+	t.set_pruning_guard(guard);
+	for i in 0..200 {
+		t.insert(&key(i), &[i as u8 + 1; 40]).unwrap();
+	}
+	t.commit();
+	let deferred = t.take_deferred_removals();
+	drop(t);
+	drop(pinned);
+	(new_root, deferred)
+}
+
+#[test]
+fn deferred_removals_are_applied_after_unpinning() {
+	let guard = PinnedRoots::default();
+	let mut writer = SharedDB::default();
+	let root = build_trie(&mut writer);
+	let reader = reader(&writer);
+
+	let (new_root, deferred) = overwrite_pinned(&mut writer, &root, &guard);
+	assert!(!deferred.is_empty());
+	assert!(RefTrieDB::new(&reader, &root).unwrap().iter().unwrap().all(|item| item.is_ok()));
+	for (hash, prefix) in deferred.iter() {
+		writer.remove(hash, (&prefix.0[..], prefix.1));
+	}
+	assert!(RefTrieDB::new(&reader, &root).is_err());
+	let trie = RefTrieDB::new(&reader, &new_root).unwrap();
+	assert_eq!(trie.iter().unwrap().filter(|item| item.is_ok()).count(), 200);
+}
+
+#[test]
+fn deferred_removals_are_handed_to_a_later_trie() {
+	let guard = PinnedRoots::default();
+	let mut writer = SharedDB::default();
+	let root = build_trie(&mut writer);
+	let reader = reader(&writer);
+
+	let (mut new_root, deferred) = overwrite_pinned(&mut writer, &root, &guard);
+	let count = deferred.len();
+	// still pinned: the removals stay deferred.
+	let trie = RefTrieDB::new(&reader, &root).unwrap();
+	let pinned = trie.pin(Some(&guard));
+	let mut t = RefTrieDBMut::from_existing(&mut writer, &mut new_root).unwrap();
+	t.set_pruning_guard(&guard);
+	t.defer_removals(deferred);
+	t.commit();
+	let deferred = t.take_deferred_removals();
+	assert_eq!(deferred.len(), count);
+	drop(pinned);
+	t.defer_removals(deferred);
+	t.commit();
+	assert!(t.take_deferred_removals().is_empty());
+	drop(t);
+	assert!(RefTrieDB::new(&reader, &root).is_err());
+	assert!(RefTrieDB::new(&reader, &new_root).unwrap().iter().unwrap().all(|item| item.is_ok()));
+}
+
+#[test]
+fn pins_only_keep_the_nodes_of_their_root() {
+	let guard = PinnedRoots::default();
+	let mut writer = SharedDB::default();
+	let root = build_trie(&mut writer);
+	let reader = reader(&writer);
+	let trie = RefTrieDB::new(&reader, &root).unwrap();
+	let pinned = trie.pin(Some(&guard));
+
+	// a root committed after the pin, then changed: its own nodes are pruned.
+	let mut new_root = root;
+	let mut t = RefTrieDBMut::from_existing(&mut writer, &mut new_root).unwrap();
+	t.set_pruning_guard(&guard);
+	t.insert(&key(0), b"first").unwrap();
+	t.commit();
+	let first_root = *t.root();
+	let deferred = t.take_deferred_removals();
+	assert!(!deferred.is_empty());
+	t.insert(&key(0), b"second").unwrap();
+	t.commit();
+	assert!(t.take_deferred_removals().is_empty());
+	drop(t);
+	assert!(RefTrieDB::new(&reader, &first_root).is_err());
+
+	// the pinned root is intact, its nodes replaced by the first change stay deferred.
+	assert!(pinned.iter().unwrap().all(|item| item.is_ok()));
+	for (hash, _) in deferred.iter() {
+		assert!(!guard.can_prune(hash, hash_db::EMPTY_PREFIX));
+	}
+	drop(pinned);
+	assert!(deferred.iter().all(|(hash, _)| guard.can_prune(hash, hash_db::EMPTY_PREFIX)));
+}
@@ -0,0 +1,98 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "trie-profiling")]
+
+use keccak_hasher::KeccakHasher;
+use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut};
+use trie_db::profiling::{take_and_reset, ProfileSnapshot};
+use trie_db::DBValue;
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+
+fn build_trie() -> (MemoryDB, <KeccakHasher as hash_db::Hasher>::Out) {
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut t = RefTrieDBMut::new(&mut db, &mut root);
+		t.insert(b"do", b"verb").unwrap();
+		t.insert(b"dog", &[1; 32]).unwrap();
+		t.insert(b"doge", &[2; 32]).unwrap();
+	}
+	(db, root)
+}
+
+fn encoded_len(db: &MemoryDB) -> u64 {
+	db.clone().drain().values().map(|(node, _)| node.len() as u64).sum()
+}
+
+#[test]
+fn lookups_are_counted() {
+	let (db, root) = build_trie();
+	let t = RefTrieDB::new(&db, &root).unwrap();
+	take_and_reset();
+	assert_eq!(t.get(b"doge").unwrap(), Some(vec![2; 32]));
+	// the five nodes of the trie are on the path to "doge", none of them is inline.
+	assert_eq!(take_and_reset(), ProfileSnapshot {
+		lookups: 1,
+		nodes_decoded: 5,
+		nodes_fetched: 5,
+		bytes_fetched: encoded_len(&db),
+		..Default::default()
+	});
+	assert_eq!(t.get(b"cat").unwrap(), None);
+	let counters = take_and_reset();
+	assert_eq!((counters.lookups, counters.nodes_decoded, counters.nodes_fetched), (1, 1, 1));
+}
+
+#[test]
+fn iterator_steps_are_counted() {
+	let (db, root) = build_trie();
+	let t = RefTrieDB::new(&db, &root).unwrap();
+	take_and_reset();
+	assert_eq!(t.iter().unwrap().count(), 3);
+	assert_eq!(take_and_reset(), ProfileSnapshot {
+		nodes_decoded: 5,
+		nodes_fetched: 5,
+		bytes_fetched: encoded_len(&db),
+		iterator_steps: 5,
+		..Default::default()
+	});
+}
+
+#[test]
+fn triedbmut_storage_is_counted() {
+	let (mut db, mut root) = build_trie();
+	let mut t = RefTrieDBMut::from_existing(&mut db, &mut root).unwrap();
+	take_and_reset();
+	t.insert(b"doge", &[3; 32]).unwrap();
+	let counters = take_and_reset();
+	assert_eq!((counters.cache_misses, counters.nodes_fetched, counters.cache_hits), (5, 5, 0));
+	// all the nodes on the path were loaded by the first insert.
+	t.insert(b"doge", &[4; 32]).unwrap();
+	let counters = take_and_reset();
+	assert_eq!((counters.cache_misses, counters.nodes_fetched, counters.cache_hits), (0, 0, 5));
+}
+
+#[test]
+fn counters_are_per_thread() {
+	let (db, root) = build_trie();
+	take_and_reset();
+	std::thread::spawn(move || {
+		let t = RefTrieDB::new(&db, &root).unwrap();
+		t.get(b"doge").unwrap();
+		assert_eq!(take_and_reset().lookups, 1);
+	}).join().unwrap();
+	assert_eq!(take_and_reset(), ProfileSnapshot::default());
+}
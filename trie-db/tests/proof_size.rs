@@ -0,0 +1,131 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proof size estimates against the generated proofs, in its own test binary so that the
+//! allocator measuring the memory used does not affect other tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::proof::{estimate_proof_size, generate_proof};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+
+struct MeasuringAllocator;
+
+thread_local! {
+	/// Bytes allocated and not freed yet, and their peak since the last reset.
+	static ALLOCATED: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+fn record(allocated: usize, freed: usize) {
+	let _ = ALLOCATED.try_with(|cell| {
+		let (current, peak) = cell.get();
+		let current = (current + allocated).saturating_sub(freed);
+		cell.set((current, peak.max(current)));
+	});
+}
+
+unsafe impl GlobalAlloc for MeasuringAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		record(layout.size(), 0);
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		record(0, layout.size());
+		System.dealloc(ptr, layout)
+	}
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		record(new_size, layout.size());
+		System.realloc(ptr, layout, new_size)
+	}
+}
+
+#[global_allocator]
+static GLOBAL: MeasuringAllocator = MeasuringAllocator;
+
+/// Peak of the bytes allocated by `f` and not freed yet.
+fn peak_allocated<T>(f: impl FnOnce() -> T) -> (T, usize) {
+	let base = ALLOCATED.with(|cell| {
+		let (current, _) = cell.get();
+		cell.set((current, current));
+		current
+	});
+	let result = f();
+	let peak = ALLOCATED.with(|cell| cell.get().1);
+	(result, peak - base)
+}
+
+fn random_trie<L: TrieLayout>(rng: &mut SmallRng) -> BuiltTrie<L> {
+	let pairs: Vec<_> = (0..5_000)
+		.map(|_| {
+			let key: Vec<u8> = (0..rng.gen_range(1, 8)).map(|_| rng.gen()).collect();
+			let value: Vec<u8> = (0..rng.gen_range(1, 100)).map(|_| rng.gen()).collect();
+			(key, value)
+		})
+		.collect();
+	TrieBuilder::new().extend(pairs).build()
+}
+
+fn check_estimates<L: TrieLayout>() {
+	let mut rng = SmallRng::seed_from_u64(47);
+	let built = random_trie::<L>(&mut rng);
+	let trie = built.trie();
+	for _ in 0..50 {
+		// Present and absent keys alike.
+		let keys: Vec<Vec<u8>> = (0..rng.gen_range(1, 200))
+			.map(|_| (0..rng.gen_range(0, 8)).map(|_| rng.gen()).collect())
+			.collect();
+		let proof = generate_proof::<_, L, _, _>(&trie, keys.iter()).unwrap();
+		let estimate = estimate_proof_size::<_, L, _, _>(&trie, keys.iter()).unwrap();
+		let size: usize = proof.iter().map(Vec::len).sum();
+		assert_eq!(estimate.exact_known_bytes, size);
+		assert!(estimate.upper_bound_bytes >= size);
+	}
+}
+
+#[test]
+fn estimates_match_proofs_with_extension() {
+	check_estimates::<ExtensionLayout>();
+}
+
+#[test]
+fn estimates_match_proofs_without_extension() {
+	check_estimates::<NoExtensionLayout>();
+}
+
+#[test]
+fn estimates_do_not_keep_the_proof() {
+	let mut rng = SmallRng::seed_from_u64(247);
+	let built = random_trie::<NoExtensionLayout>(&mut rng);
+	let trie = built.trie();
+	let keys: Vec<Vec<u8>> = (0..2_000)
+		.map(|_| (0..rng.gen_range(1, 8)).map(|_| rng.gen()).collect())
+		.collect();
+
+	let (proof, generated) = peak_allocated(|| {
+		generate_proof::<_, NoExtensionLayout, _, _>(&trie, keys.iter()).unwrap()
+	});
+	let (estimate, estimated) = peak_allocated(|| {
+		estimate_proof_size::<_, NoExtensionLayout, _, _>(&trie, keys.iter()).unwrap()
+	});
+	let size: usize = proof.iter().map(Vec::len).sum();
+	assert_eq!(estimate.exact_known_bytes, size);
+	// The estimate only keeps a length per proof node, the nodes on one path aside.
+	assert!(generated > size);
+	assert!(estimated * 4 < generated, "estimate peak {}, generation peak {}", estimated, generated);
+}
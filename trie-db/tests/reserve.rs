@@ -0,0 +1,109 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Growth of the structures of `TrieDBMut` with and without `TrieDBMut::reserve`, in its own
+//! test binary so that the counting allocator does not affect other tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use hash_db::Hasher;
+use keccak_hasher::KeccakHasher;
+use reference_trie::{NoExtensionLayout, TrieMut};
+use trie_db::{DBValue, TrieDBMut};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+
+/// Allocations at least this large: the growth of the structures holding a node or a change each.
+const LARGE: usize = 64 * 1024;
+
+struct CountingAllocator;
+
+thread_local! {
+	static LARGE_ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn record(size: usize) {
+	if size >= LARGE {
+		let _ = LARGE_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+	}
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		record(layout.size());
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout)
+	}
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		record(new_size);
+		System.realloc(ptr, layout, new_size)
+	}
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn large_allocations() -> usize {
+	LARGE_ALLOCATIONS.with(|count| count.get())
+}
+
+const CHANGES: u32 = 100_000;
+
+fn key(i: u32) -> <KeccakHasher as Hasher>::Out {
+	KeccakHasher::hash(&i.to_le_bytes())
+}
+
+/// Large allocations of changing the values of every key of a trie of `CHANGES` keys and of
+/// committing the changes, reserving the room of the changes first with `reserve`.
+fn changes_allocations(reserve: bool) -> usize {
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut t = TrieDBMut::<NoExtensionLayout>::new(&mut db, &mut root);
+		for i in 0..CHANGES {
+			t.insert(&key(i), &[1; 8]).unwrap();
+		}
+	}
+
+	let before = large_allocations();
+	let mut t = TrieDBMut::<NoExtensionLayout>::from_existing(&mut db, &mut root).unwrap();
+	if reserve {
+		t.reserve(CHANGES as usize);
+		assert!(t.capacity() >= 2 * CHANGES as usize);
+	}
+	for i in 0..CHANGES {
+		t.insert(&key(i), &[2; 8]).unwrap();
+	}
+	assert!(t.overlay_len() > CHANGES as usize);
+	if reserve {
+		assert!(t.capacity() <= 2 * CHANGES as usize, "the estimate covers the changed nodes");
+	}
+	t.commit();
+	assert_eq!(t.overlay_len(), 0);
+	drop(t);
+	large_allocations() - before
+}
+
+#[test]
+fn reserve_avoids_growing_the_structures() {
+	let without = changes_allocations(false);
+	let with = changes_allocations(true);
+	// The database growing on commit in both runs.
+	assert!(with * 2 < without, "{} large allocations with reserve, {} without", with, without);
+}
@@ -0,0 +1,117 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Value allocations of `TrieDB::scan_filtered`, in its own test binary so that the counting
+//! allocator does not affect other tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use keccak_hasher::KeccakHasher;
+use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut};
+use trie_db::DBValue;
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+
+/// Size of the values, distinct from the size of any other buffer allocated by the scans.
+const VALUE_LEN: usize = 300;
+
+struct CountingAllocator;
+
+thread_local! {
+	static VALUE_ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		if layout.size() == VALUE_LEN {
+			let _ = VALUE_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+		}
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout)
+	}
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn value_allocations() -> usize {
+	VALUE_ALLOCATIONS.with(|count| count.get())
+}
+
+/// Account-like values, with a nonzero balance at offset 8 for one entry in a hundred.
+fn build_trie(count: u32) -> (MemoryDB, <KeccakHasher as hash_db::Hasher>::Out) {
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut t = RefTrieDBMut::new(&mut db, &mut root);
+		for i in 0..count {
+			let mut value = vec![1; VALUE_LEN];
+			value[8] = if i % 100 == 0 { 1 } else { 0 };
+			t.insert(&i.to_be_bytes(), &value).unwrap();
+		}
+	}
+	(db, root)
+}
+
+fn has_balance(value: &[u8]) -> bool {
+	value[8] != 0
+}
+
+#[test]
+fn filtered_scan_only_copies_accepted_values() {
+	let (db, root) = build_trie(2000);
+	let t = RefTrieDB::new(&db, &root).unwrap();
+
+	let before = value_allocations();
+	let scanned: Vec<_> = t.iter().unwrap()
+		.map(|item| item.unwrap())
+		.filter(|(_, value)| has_balance(value))
+		.collect();
+	let scan_then_filter = value_allocations() - before;
+
+	let before = value_allocations();
+	let filtered: Vec<_> = t.scan_filtered(None, has_balance, |value| value.to_vec()).unwrap()
+		.map(|item| item.unwrap())
+		.collect();
+	let scan_filtered = value_allocations() - before;
+
+	assert_eq!(filtered, scanned);
+	assert_eq!(filtered.len(), 20);
+	assert_eq!(scan_then_filter, 2000);
+	assert_eq!(scan_filtered, 20);
+}
+
+#[test]
+fn filtered_scan_maps_under_prefix() {
+	let (db, root) = build_trie(1000);
+	let t = RefTrieDB::new(&db, &root).unwrap();
+
+	let prefix = 300u32.to_be_bytes();
+	let balances: Vec<_> = t.scan_filtered(Some(&prefix[..3]), has_balance, |value| value[8])
+		.unwrap()
+		.map(|item| item.unwrap())
+		.collect();
+	// keys 256 to 511 are under the prefix, and 300, 400 and 500 have a balance.
+	let expected: Vec<_> = [300u32, 400, 500].iter()
+		.map(|i| (i.to_be_bytes().to_vec(), 1))
+		.collect();
+	assert_eq!(balances, expected);
+
+	let none: Vec<_> = t.scan_filtered(None, |_| false, |value| value.len()).unwrap().collect();
+	assert!(none.is_empty());
+}
@@ -0,0 +1,114 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Iterations over `FatDB` and `SecTrieDB`, in the order of the hashed keys.
+
+use hash_db::Hasher;
+use keccak_hasher::KeccakHasher;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::{DBValue, FatDB, FatDBMut, SecTrieDB, SecTrieDBMut, Trie, TrieError, TrieMut};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+type Root = <KeccakHasher as Hasher>::Out;
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Random pairs with distinct keys, sorted by hashed key.
+fn random_pairs(seed: u64) -> Pairs {
+	let mut rng = SmallRng::seed_from_u64(seed);
+	let mut pairs: Pairs = (0..50u8).map(|i| {
+		let key_len = rng.gen_range(0, 6);
+		let mut key: Vec<u8> = (0..key_len).map(|_| rng.gen()).collect();
+		key.push(i);
+		(key, vec![i; rng.gen_range(1, 40)])
+	}).collect();
+	pairs.sort_by_key(|(key, _)| KeccakHasher::hash(key));
+	pairs
+}
+
+fn check_fatdb_iteration<L: TrieLayout<Hash = KeccakHasher>>() {
+	let pairs = random_pairs(1);
+	let mut db = MemoryDB::default();
+	let mut root = Root::default();
+	{
+		let mut t = FatDBMut::<L>::new(&mut db, &mut root);
+		for (key, value) in pairs.iter().rev() {
+			t.insert(key, value).unwrap();
+		}
+	}
+	let t = FatDB::<L>::new(&db, &root).unwrap();
+	let iterated: Pairs = t.iter().unwrap().map(|item| item.unwrap()).collect();
+	assert_eq!(iterated, pairs);
+
+	let mut iter = t.iter().unwrap();
+	iter.seek(&pairs[20].0).unwrap();
+	assert_eq!(iter.next().unwrap().unwrap(), pairs[20]);
+	iter.seek_after(&pairs[20].0).unwrap();
+	assert_eq!(iter.next().unwrap().unwrap(), pairs[21]);
+	iter.seek_after(&pairs[49].0).unwrap();
+	assert!(iter.next().is_none());
+}
+
+fn check_sectriedb_iteration<L: TrieLayout<Hash = KeccakHasher>>() {
+	let pairs = random_pairs(2);
+	let hashed: Pairs = pairs.iter()
+		.map(|(key, value)| (KeccakHasher::hash(key).as_ref().to_vec(), value.clone()))
+		.collect();
+	let mut db = MemoryDB::default();
+	let mut root = Root::default();
+	{
+		let mut t = SecTrieDBMut::<L>::new(&mut db, &mut root);
+		for (key, value) in pairs.iter() {
+			t.insert(key, value).unwrap();
+		}
+	}
+	let t = SecTrieDB::<L>::new(&db, &root).unwrap();
+	let iterated: Pairs = t.iter().unwrap().map(|item| item.unwrap()).collect();
+	assert_eq!(iterated, hashed);
+
+	let mut iter = t.iter().unwrap();
+	iter.seek(&hashed[30].0).unwrap();
+	assert_eq!(iter.next().unwrap().unwrap(), hashed[30]);
+	iter.seek_after(&hashed[30].0).unwrap();
+	assert_eq!(iter.next().unwrap().unwrap(), hashed[31]);
+
+	// the keys hashed by a `SecTrieDBMut` have no preimage for a `FatDB`.
+	let fat = FatDB::<L>::new(&db, &root).unwrap();
+	let error = fat.iter().unwrap().next().unwrap().unwrap_err();
+	match *error {
+		TrieError::IncompleteDatabase { hash, .. } =>
+			assert_eq!(hash, KeccakHasher::hash(&hashed[0].0)),
+		ref error => panic!("unexpected error {:?}", error),
+	}
+}
+
+#[test]
+fn fatdb_iteration_with_extension() {
+	check_fatdb_iteration::<ExtensionLayout>();
+}
+
+#[test]
+fn fatdb_iteration_without_extension() {
+	check_fatdb_iteration::<NoExtensionLayout>();
+}
+
+#[test]
+fn sectriedb_iteration_with_extension() {
+	check_sectriedb_iteration::<ExtensionLayout>();
+}
+
+#[test]
+fn sectriedb_iteration_without_extension() {
+	check_sectriedb_iteration::<NoExtensionLayout>();
+}
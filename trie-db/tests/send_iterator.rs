@@ -0,0 +1,54 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The owned node iterators and the node items are `Send` with the `send-node-iterator`
+//! feature.
+
+#![cfg(feature = "send-node-iterator")]
+
+use std::thread;
+
+use keccak_hasher::KeccakHasher;
+use reference_trie::ExtensionLayout;
+use trie_db::{
+	DBValue, OwnedTrieDBIterator, OwnedTrieDBNodeIterator, SharedNode, TrieDBMut, TrieMut,
+};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+
+fn assert_send<T: Send>() {}
+
+#[test]
+fn node_iterators_are_send() {
+	assert_send::<SharedNode>();
+	assert_send::<OwnedTrieDBNodeIterator<ExtensionLayout, MemoryDB>>();
+	assert_send::<OwnedTrieDBIterator<ExtensionLayout, MemoryDB>>();
+}
+
+#[test]
+fn node_iteration_moves_to_another_thread() {
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut t = TrieDBMut::<ExtensionLayout>::new(&mut db, &mut root);
+		for i in 0..100u32 {
+			t.insert(&i.to_be_bytes(), &[i as u8; 40]).unwrap();
+		}
+	}
+	let iter = OwnedTrieDBNodeIterator::<ExtensionLayout, _>::new(db, root).unwrap();
+	let nodes: Vec<SharedNode> = thread::spawn(move || {
+		iter.map(|item| item.unwrap().2).collect()
+	}).join().unwrap();
+	assert!(nodes.len() > 100);
+}
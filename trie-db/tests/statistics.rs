@@ -0,0 +1,124 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `TrieDB::statistics` against a small trie of known shape and against the node counts and
+//! the database of larger tries.
+
+use keccak_hasher::KeccakHasher;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout, TrieLayout};
+use trie_db::DBValue;
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+
+fn build<L: TrieLayout<Hash = KeccakHasher>>(pairs: &[(Vec<u8>, Vec<u8>)]) -> BuiltTrie<L> {
+	TrieBuilder::new().extend(pairs.iter().cloned()).build()
+}
+
+/// Three keys, two of them under an extension or a branch with partial key `2`, the leaf with
+/// the long value being the only leaf not inlined.
+fn small_pairs() -> Vec<(Vec<u8>, Vec<u8>)> {
+	vec![
+		(vec![0x12, 0x34], vec![0x01, 0x02]),
+		(vec![0x12, 0x56], vec![0xaa; 40]),
+		(vec![0x78], vec![0x03]),
+	]
+}
+
+/// Total size of the nodes of `db`.
+fn db_bytes(db: &MemoryDB) -> u64 {
+	db.clone().drain().values().map(|(data, _)| data.len() as u64).sum()
+}
+
+#[test]
+fn small_trie_statistics() {
+	let built = build::<ExtensionLayout>(&small_pairs());
+	let stats = built.trie().statistics().unwrap();
+	assert_eq!(stats.leaves.count, 3);
+	assert_eq!(stats.extensions.count, 1);
+	assert_eq!(stats.branches.count, 2);
+	assert_eq!(stats.entries, 3);
+	assert_eq!(stats.leaf_depths, vec![0, 1, 0, 2]);
+	assert_eq!((stats.inline_children, stats.hashed_children), (2, 3));
+	assert_eq!(stats.inline_percent(), 40);
+	assert_eq!(stats.stored_nodes, 4);
+	assert_eq!(stats.stored_bytes, db_bytes(built.db()));
+	assert!(stats.leaves.min < 8 && stats.leaves.max > 40);
+	assert_eq!(stats.leaves.percentile(100), stats.leaves.max);
+	assert_eq!(stats.leaves.percentile(50), 7);
+
+	let built = build::<NoExtensionLayout>(&small_pairs());
+	let stats = built.trie().statistics().unwrap();
+	assert_eq!(stats.leaves.count, 3);
+	assert_eq!(stats.extensions.count, 0);
+	assert_eq!(stats.branches.count, 2);
+	assert_eq!(stats.leaf_depths, vec![0, 1, 2]);
+	assert_eq!(stats.stored_bytes, db_bytes(built.db()));
+}
+
+fn random_trie_statistics<L: TrieLayout<Hash = KeccakHasher>>() {
+	let mut rng = SmallRng::seed_from_u64(7);
+	let pairs: Vec<_> = (0..2000).map(|_| {
+		let key: Vec<u8> = (0..rng.gen_range(1, 8)).map(|_| rng.gen()).collect();
+		let value = vec![0x55; rng.gen_range(1, 64)];
+		(key, value)
+	}).collect();
+	let built = build::<L>(&pairs);
+	let trie = built.trie();
+	let stats = trie.statistics().unwrap();
+	let counts = trie.count_nodes().unwrap();
+
+	assert_eq!(stats.entries, trie.count_entries().unwrap() as u64);
+	assert_eq!(stats.leaves.count, counts.leaves as u64);
+	assert_eq!(stats.extensions.count, counts.extensions as u64);
+	assert_eq!(stats.branches.count, counts.branches as u64);
+	assert_eq!(stats.leaf_depths.iter().sum::<u64>(), stats.leaves.count);
+	assert_eq!(stats.stored_nodes, built.db().keys().len() as u64);
+	assert_eq!(stats.stored_bytes, db_bytes(built.db()));
+	let nodes = (counts.leaves + counts.extensions + counts.branches) as u64;
+	assert_eq!(stats.inline_children + stats.hashed_children, nodes - 1);
+	for sizes in [&stats.leaves, &stats.branches] {
+		assert_eq!(sizes.buckets.iter().sum::<u64>(), sizes.count);
+		assert!(sizes.min <= sizes.average() && sizes.average() <= sizes.max);
+		assert!(sizes.percentile(50) <= sizes.percentile(90));
+		assert!(sizes.percentile(90) <= sizes.percentile(100));
+		assert_eq!(sizes.percentile(100), sizes.max);
+	}
+
+	let summary = stats.to_string();
+	assert!(summary.starts_with(&format!("entries: {}\n", stats.entries)));
+	let leaves = format!("leaves: {} nodes, {} bytes", stats.leaves.count, stats.leaves.bytes);
+	assert!(summary.contains(&leaves));
+}
+
+#[test]
+fn random_trie_statistics_extension() {
+	random_trie_statistics::<ExtensionLayout>();
+}
+
+#[test]
+fn random_trie_statistics_no_extension() {
+	random_trie_statistics::<NoExtensionLayout>();
+}
+
+#[test]
+fn empty_trie_statistics() {
+	let built = build::<ExtensionLayout>(&[]);
+	let stats = built.trie().statistics().unwrap();
+	assert_eq!(stats.entries, 0);
+	assert_eq!(stats.stored_nodes, 1);
+	assert_eq!(stats.leaves.percentile(50), 0);
+	assert_eq!(stats.inline_percent(), 0);
+}
@@ -0,0 +1,135 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads of `SubTrieDB` views against those of the whole trie, over a prefixed database.
+
+use std::collections::BTreeMap;
+
+use hash_db::Hasher;
+use keccak_hasher::KeccakHasher;
+use reference_trie::builder::{BuiltTrie, TrieBuilder};
+use reference_trie::{ExtensionLayout, NoExtensionLayout};
+use trie_db::{proof, NibbleSlice, NibbleVec, Trie, TrieDBNodeIterator, TrieLayout};
+
+/// Three maps under the prefixes 0x01, 0x02 and 0x03, with a value at 0x02, and a single key
+/// under 0x05.
+fn entries() -> BTreeMap<Vec<u8>, Vec<u8>> {
+	let mut entries = BTreeMap::new();
+	for map in 1..4u8 {
+		for i in 0..50u32 {
+			let hash = KeccakHasher::hash(&[&[map][..], &i.to_le_bytes()].concat());
+			let key = [&[map][..], &hash[..1 + i as usize % 4]].concat();
+			entries.insert(key, vec![map; 1 + i as usize % 40]);
+		}
+	}
+	entries.insert(vec![0x02], vec![0x22; 4]);
+	entries.insert(vec![0x05, 0x06, 0x07], vec![0x55; 33]);
+	entries
+}
+
+fn build_trie<L: TrieLayout<Hash = KeccakHasher>>() -> BuiltTrie<L> {
+	TrieBuilder::new().extend(entries()).build()
+}
+
+/// Entries under `prefix`, with keys relative to it.
+fn relative(prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+	entries().into_iter()
+		.filter(|(key, _)| key.starts_with(prefix))
+		.map(|(key, value)| (key[prefix.len()..].to_vec(), value))
+		.collect()
+}
+
+fn check_subtrie<L: TrieLayout>(built: &BuiltTrie<L>, prefix: &[u8]) {
+	let trie = built.trie();
+	let sub = trie.subtrie(prefix).unwrap();
+	let expected = relative(prefix);
+	assert_eq!(sub.is_empty(), expected.is_empty(), "{:?}", prefix);
+
+	for (key, value) in expected.iter() {
+		assert_eq!(sub.get(key).unwrap().as_ref(), Some(value));
+	}
+	for (key, _) in relative(&[]) {
+		assert_eq!(sub.get(&key).unwrap(), trie.get(&sub.full_key(&key)).unwrap());
+	}
+	let pairs: Vec<_> = sub.iter().unwrap().map(|item| item.unwrap()).collect();
+	assert_eq!(pairs, expected);
+
+	// seeks stay in the subtrie, past its last key included.
+	let mut seeks: Vec<Vec<u8>> = expected.iter().map(|(key, _)| key.clone()).step_by(7).collect();
+	seeks.extend(vec![Vec::new(), vec![0x80], vec![0xff; 3]]);
+	for seek in seeks {
+		let mut iter = sub.iter().unwrap();
+		iter.seek(&seek).unwrap();
+		let from: Vec<_> = expected.iter().filter(|(key, _)| *key >= seek).cloned().collect();
+		let pairs: Vec<_> = iter.map(|item| item.unwrap()).collect();
+		assert_eq!(pairs, from, "{:?} {:?}", prefix, seek);
+
+		let mut iter = sub.iter().unwrap();
+		iter.seek_after(&seek).unwrap();
+		let after: Vec<_> = expected.iter().filter(|(key, _)| *key > seek).cloned().collect();
+		let pairs: Vec<_> = iter.map(|item| item.unwrap()).collect();
+		assert_eq!(pairs, after, "{:?} {:?}", prefix, seek);
+	}
+}
+
+fn check_views<L: TrieLayout<Hash = KeccakHasher>>() {
+	let built = build_trie::<L>();
+	// the prefix 0x05 and 0x0506 end in the partial key of a leaf, 0x04 and 0x0508 have no node.
+	let prefixes = [&[][..], &[0x01], &[0x02], &[0x03], &[0x04], &[0x05], &[0x05, 0x06], &[0x05, 0x08]];
+	for prefix in prefixes {
+		check_subtrie(&built, prefix);
+	}
+
+	// the nodes outside of the path to 0x01 and of its subtrie are not needed.
+	let subtrie = NibbleVec::from(NibbleSlice::new(&[0x01]));
+	let unneeded: Vec<_> = TrieDBNodeIterator::new(&built.trie()).unwrap()
+		.map(|item| item.unwrap())
+		.filter(|(prefix, _, _)| {
+			!prefix.starts_with_vec(&subtrie) && !subtrie.starts_with_vec(prefix)
+		})
+		.filter_map(|(_, hash, _)| hash)
+		.collect();
+	let mut pruned = build_trie::<L>();
+	// children first, each node being found from its parent.
+	for hash in unneeded.iter().rev() {
+		pruned.remove_node(hash);
+	}
+	check_subtrie(&pruned, &[0x01]);
+}
+
+#[test]
+fn views_with_extension() {
+	check_views::<ExtensionLayout>();
+}
+
+#[test]
+fn views_without_extension() {
+	check_views::<NoExtensionLayout>();
+}
+
+#[test]
+fn proofs_of_relative_keys() {
+	let built = build_trie::<NoExtensionLayout>();
+	let trie = built.trie();
+	let sub = trie.subtrie(&[0x03]).unwrap();
+	let mut keys: Vec<Vec<u8>> =
+		relative(&[0x03]).into_iter().map(|(key, _)| key).step_by(5).collect();
+	keys.push(vec![0xff, 0xff]);
+
+	let proof = sub.generate_proof(&keys).unwrap();
+	let items: Vec<_> = keys.iter()
+		.map(|key| (sub.full_key(key), sub.get(key).unwrap()))
+		.collect();
+	proof::verify_proof::<NoExtensionLayout, _, _, _>(built.root(), &proof, items.iter()).unwrap();
+}
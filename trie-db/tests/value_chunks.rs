@@ -0,0 +1,554 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Values stored in chunks, with the chunked value layout of the reference trie.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+
+use hash_db::{HashDB, Hasher, Prefix, EMPTY_PREFIX};
+use keccak_hasher::KeccakHasher;
+use reference_trie::{ChunkedValueLayout, ValueNodeLayout, VALUE_CHUNK_LEN, VALUE_NODE_THRESHOLD};
+use trie_db::node::{decode_hash, Value};
+use trie_db::proof::{generate_proof, verify_proof, VerifyError};
+use trie_db::value_chunks::ChunkIndex;
+use trie_db::verify::{verify_roots, RootStatus, ViolationKind};
+use trie_db::{
+	exists, DBValue, FnHashDB, InsertChunkedError, Trie, TrieBuilder, TrieConfiguration, TrieDB,
+	TrieDBMut, TrieDBNodeIterator, TrieMut, trie_visit,
+};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+type HashedMemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+type Root = <KeccakHasher as Hasher>::Out;
+type Index = ChunkIndex<Root>;
+type Pairs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// A value of `len` bytes, differing from one chunk to the next.
+fn value(len: usize, seed: u8) -> Vec<u8> {
+	(0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+/// Pairs with inline values, values in one value node and values over many chunks, at leaves
+/// and in branches.
+fn pairs() -> Pairs {
+	let mut pairs = Pairs::new();
+	for i in 0..60u32 {
+		let key = KeccakHasher::hash(&i.to_le_bytes());
+		let len = 1 + (i as usize % 4);
+		pairs.insert(key[..len].to_vec(), value(1 + i as usize * 37 % 700, i as u8));
+	}
+	pairs.insert(Vec::new(), value(VALUE_CHUNK_LEN * 3 + 1, 0xee));
+	pairs.insert(vec![0x01], value(VALUE_CHUNK_LEN, 0x01));
+	// identical chunks are stored once per use.
+	pairs.insert(vec![0x02], vec![0xaa; VALUE_CHUNK_LEN * 5]);
+	pairs
+}
+
+fn build<DB: HashDB<KeccakHasher, DBValue> + Default>(pairs: &Pairs) -> (DB, Root) {
+	let mut db = DB::default();
+	let mut root = Default::default();
+	{
+		let mut trie = TrieDBMut::<ChunkedValueLayout>::new(&mut db, &mut root);
+		for (key, value) in pairs {
+			trie.insert(key, value).unwrap();
+		}
+	}
+	(db, root)
+}
+
+/// Number of entries of `db` with a positive reference count.
+fn stored(db: &HashedMemoryDB) -> usize {
+	db.keys().values().filter(|rc| **rc > 0).count()
+}
+
+/// The key of a value of many chunks in `pairs`.
+fn chunked_key(pairs: &Pairs) -> Vec<u8> {
+	pairs.iter()
+		.find(|(_, value)| value.len() > VALUE_CHUNK_LEN * 3)
+		.map(|(key, _)| key.clone())
+		.unwrap()
+}
+
+#[test]
+fn chunked_values_read_back() {
+	let pairs = pairs();
+	let (db, root) = build::<MemoryDB>(&pairs);
+	let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+	for (key, value) in &pairs {
+		assert_eq!(trie.get(key).unwrap().as_ref(), Some(value));
+		assert_eq!(trie.value_length(key).unwrap(), Some(value.len()));
+	}
+	assert_eq!(trie.get(b"absent").unwrap(), None);
+	let items = trie.iter().unwrap().map(|item| item.unwrap()).collect::<Vec<_>>();
+	assert_eq!(items, pairs.clone().into_iter().collect::<Vec<_>>());
+
+	let mut root = root;
+	let mut db = db;
+	let trie = TrieDBMut::<ChunkedValueLayout>::from_existing(&mut db, &mut root).unwrap();
+	assert_eq!(trie.get(&chunked_key(&pairs)).unwrap().as_ref(), pairs.get(&chunked_key(&pairs)));
+	let items = trie.iter().map(|item| item.unwrap()).collect::<Vec<_>>();
+	assert_eq!(items, pairs.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn large_values_are_stored_in_chunks() {
+	let pairs = pairs();
+	let (db, root) = build::<HashedMemoryDB>(&pairs);
+	assert_eq!(root, ChunkedValueLayout::trie_root(&pairs));
+	assert_ne!(root, ValueNodeLayout::trie_root(&pairs));
+
+	let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+	let mut chunked = 0;
+	for item in TrieDBNodeIterator::new(&trie).unwrap() {
+		let (_, _, node) = item.unwrap();
+		match node.value::<ChunkedValueLayout>(Default::default()).unwrap() {
+			Some(Value::Chunked(hash)) => {
+				let index_hash = decode_hash::<KeccakHasher>(hash).unwrap();
+				let data = db.get(&index_hash, EMPTY_PREFIX).unwrap();
+				let index = Index::decode(&data, VALUE_CHUNK_LEN).unwrap();
+				assert!(index.len > VALUE_CHUNK_LEN);
+				for chunk in &index.chunks {
+					assert!(db.contains(chunk, EMPTY_PREFIX));
+				}
+				chunked += 1;
+			},
+			Some(Value::Node(hash)) => {
+				let value = db.get(&decode_hash::<KeccakHasher>(hash).unwrap(), EMPTY_PREFIX);
+				let len = value.unwrap().len();
+				assert!(len > VALUE_NODE_THRESHOLD && len <= VALUE_CHUNK_LEN);
+			},
+			Some(Value::Inline(value)) => assert!(value.len() <= VALUE_NODE_THRESHOLD),
+			None => (),
+		}
+	}
+	assert_eq!(chunked, pairs.values().filter(|value| value.len() > VALUE_CHUNK_LEN).count());
+	// the whole value is never stored.
+	for value in pairs.values().filter(|value| value.len() > VALUE_CHUNK_LEN) {
+		assert!(!db.contains(&KeccakHasher::hash(value), EMPTY_PREFIX));
+	}
+}
+
+#[test]
+fn builder_writes_chunks() {
+	let pairs = pairs();
+	let (_, root) = build::<HashedMemoryDB>(&pairs);
+	let mut db = HashedMemoryDB::default();
+	let mut builder = TrieBuilder::new(&mut db);
+	trie_visit::<ChunkedValueLayout, _, _, _, _>(pairs.iter(), &mut builder);
+	assert_eq!(builder.root, Some(root));
+	let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+	for (key, value) in &pairs {
+		assert_eq!(trie.get(key).unwrap().as_ref(), Some(value));
+	}
+}
+
+#[test]
+fn chunked_reads_fetch_one_chunk_at_a_time() {
+	let mut pairs = pairs();
+	// a value larger than any node of the trie.
+	let key = b"large".to_vec();
+	pairs.insert(key.clone(), value(VALUE_CHUNK_LEN * 100 + 7, 0x11));
+	let value = &pairs[&key];
+	let (db, root) = build::<MemoryDB>(&pairs);
+	let reads = Cell::new(0);
+	let largest = Cell::new(0);
+	let counting = FnHashDB::<KeccakHasher, _>::new(|hash: &Root, prefix: Prefix| {
+		let data = db.get(hash, prefix);
+		reads.set(reads.get() + 1);
+		largest.set(largest.get().max(data.as_ref().map_or(0, Vec::len)));
+		data
+	});
+	let trie = TrieDB::<ChunkedValueLayout>::new(&counting, &root).unwrap();
+	reads.set(0);
+
+	let mut pieces = Vec::new();
+	let len = trie.get_chunked(&key, &mut |piece| pieces.push(piece.to_vec())).unwrap();
+	assert_eq!(len, Some(value.len()));
+	assert_eq!(pieces.concat(), *value);
+	let chunks = value.len().div_ceil(VALUE_CHUNK_LEN);
+	assert_eq!(pieces.len(), chunks);
+	assert!(pieces.iter().all(|piece| piece.len() <= VALUE_CHUNK_LEN));
+	// no node read is as large as the value.
+	assert!(largest.get() < value.len());
+	let streamed = reads.replace(0);
+
+	// the length is read from the index, without the chunks.
+	assert_eq!(trie.value_length(&key).unwrap(), Some(value.len()));
+	assert_eq!(reads.get(), streamed - chunks);
+}
+
+#[test]
+fn insert_chunked_reads_the_value() {
+	let pairs = pairs();
+	let key = chunked_key(&pairs);
+	let (mut db, mut root) = build::<MemoryDB>(&pairs);
+	let new_value = value(VALUE_CHUNK_LEN * 10 + 3, 0x42);
+	{
+		let mut trie = TrieDBMut::<ChunkedValueLayout>::from_existing(&mut db, &mut root).unwrap();
+		let old = trie.insert_chunked(&key, &mut &new_value[..]).unwrap();
+		assert_eq!(old.as_ref(), pairs.get(&key));
+		assert_eq!(trie.insert_chunked(b"new", &mut &new_value[..]).unwrap(), None);
+
+		struct Failing;
+		impl io::Read for Failing {
+			fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+				Err(io::Error::other("read failed"))
+			}
+		}
+		match trie.insert_chunked(b"failed", &mut Failing) {
+			Err(InsertChunkedError::Read(error)) => assert_eq!(error.kind(), io::ErrorKind::Other),
+			result => panic!("unexpected result {:?}", result),
+		}
+	}
+	let mut expected = pairs.clone();
+	expected.insert(key.clone(), new_value.clone());
+	expected.insert(b"new".to_vec(), new_value);
+	assert_eq!(root, ChunkedValueLayout::trie_root(&expected));
+}
+
+#[test]
+fn changes_release_chunks() {
+	let pairs = pairs();
+	let (mut db, mut root) = build::<HashedMemoryDB>(&pairs);
+	let chunked: Vec<_> = pairs.iter()
+		.filter(|(_, value)| value.len() > VALUE_CHUNK_LEN)
+		.map(|(key, _)| key.clone())
+		.collect();
+	// the new values share their first chunks with the old ones.
+	let mut updated = pairs.clone();
+	for key in &chunked {
+		updated.get_mut(key).unwrap()[VALUE_CHUNK_LEN] ^= 0xff;
+	}
+	{
+		let mut trie = TrieDBMut::<ChunkedValueLayout>::from_existing(&mut db, &mut root).unwrap();
+		for key in &chunked {
+			trie.insert(key, &updated[key]).unwrap();
+		}
+	}
+	let (expected_db, expected_root) = build::<HashedMemoryDB>(&updated);
+	assert_eq!(root, expected_root);
+	db.purge();
+	assert_eq!(db.keys(), expected_db.keys());
+
+	{
+		let mut trie = TrieDBMut::<ChunkedValueLayout>::from_existing(&mut db, &mut root).unwrap();
+		for key in pairs.keys() {
+			trie.remove(key).unwrap();
+		}
+		assert!(trie.is_empty());
+	}
+	db.purge();
+	assert_eq!(stored(&db), 0);
+}
+
+#[test]
+fn merge_copies_chunks() {
+	let pairs = pairs();
+	let (mut left, mut right) = (Pairs::new(), Pairs::new());
+	for (i, (key, value)) in pairs.iter().enumerate() {
+		let side = if i % 3 == 0 { &mut left } else { &mut right };
+		side.insert(key.clone(), value.clone());
+	}
+	let (mut db, mut root) = build::<MemoryDB>(&left);
+	let (other_db, other_root) = build::<MemoryDB>(&right);
+	{
+		let other = TrieDB::<ChunkedValueLayout>::new(&other_db, &other_root).unwrap();
+		let mut trie = TrieDBMut::<ChunkedValueLayout>::from_existing(&mut db, &mut root).unwrap();
+		assert_eq!(trie.merge_from(&other).unwrap(), right.len() as u64);
+	}
+	assert_eq!(root, ChunkedValueLayout::trie_root(&pairs));
+	let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+	for (key, value) in &pairs {
+		assert_eq!(trie.get(key).unwrap().as_ref(), Some(value));
+	}
+}
+
+#[test]
+fn proofs_hold_chunked_values() {
+	let pairs = pairs();
+	let keys = vec![chunked_key(&pairs), vec![0x01], b"absent".to_vec()];
+	let items: Vec<_> = keys.iter().map(|key| (key.clone(), pairs.get(key).cloned())).collect();
+	let (db, root) = build::<MemoryDB>(&pairs);
+	let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+	let proof = generate_proof::<_, ChunkedValueLayout, _, _>(&trie, &keys).unwrap();
+	verify_proof::<ChunkedValueLayout, _, _, _>(&root, &proof, &items).unwrap();
+
+	let mut wrong = items.clone();
+	wrong[0].1.as_mut().unwrap()[VALUE_CHUNK_LEN * 2] ^= 1;
+	assert!(verify_proof::<ChunkedValueLayout, _, _, _>(&root, &proof, &wrong).is_err());
+}
+
+#[test]
+fn missing_chunks_are_reported() {
+	let pairs = pairs();
+	let key = chunked_key(&pairs);
+	let (mut db, root) = build::<MemoryDB>(&pairs);
+	let index = Index::new::<KeccakHasher>(&pairs[&key], VALUE_CHUNK_LEN);
+	let index_hash = KeccakHasher::hash(&index.encode());
+	let prefix = {
+		let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+		TrieDBNodeIterator::new(&trie).unwrap()
+			.find_map(|item| {
+				let (prefix, _, node) = item.unwrap();
+				match node.value::<ChunkedValueLayout>(Default::default()).unwrap() {
+					Some(Value::Chunked(hash)) if hash == index_hash.as_ref() => Some(prefix),
+					_ => None,
+				}
+			})
+			.unwrap()
+	};
+	let (key_prefix, last) = prefix.as_prefix();
+	HashDB::remove(&mut db, &index.chunks[1], (key_prefix, last));
+
+	assert_eq!(
+		verify_roots::<ChunkedValueLayout>(&db, &[root], None),
+		vec![RootStatus::Incomplete { missing: 1 }],
+	);
+	let report = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap().validate().unwrap();
+	assert_eq!(report.violations.len(), 1);
+	assert_eq!(report.violations[0].hash, index_hash);
+	assert!(matches!(report.violations[0].kind, ViolationKind::MissingNode));
+	let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+	assert!(trie.get(&key).is_err());
+}
+
+#[test]
+fn value_range_proofs_hold_the_chunks_of_the_range() {
+	let pairs = pairs();
+	let key = chunked_key(&pairs);
+	let value = &pairs[&key];
+	let (db, root) = build::<MemoryDB>(&pairs);
+	let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+	let ranges = [
+		0..1,
+		10..VALUE_CHUNK_LEN,
+		VALUE_CHUNK_LEN - 1..VALUE_CHUNK_LEN + 1,
+		VALUE_CHUNK_LEN * 2..value.len(),
+		0..value.len(),
+		5..5,
+	];
+	for range in ranges.iter().cloned() {
+		let proof = trie.prove_value_range(&key, range.clone()).unwrap().unwrap();
+		let chunks = Index::decode(&proof.index, VALUE_CHUNK_LEN).unwrap().chunks_of(range.clone());
+		assert_eq!(proof.chunks.len(), chunks.len());
+		let bytes = proof.verify::<ChunkedValueLayout>(&root, &key, range.clone()).unwrap();
+		assert_eq!(bytes, value[range].to_vec());
+	}
+	assert_eq!(trie.prove_value_range(b"absent", 0..1).unwrap(), None);
+}
+
+#[test]
+fn value_range_proofs_of_values_not_in_chunks() {
+	let pairs = pairs();
+	let (db, root) = build::<MemoryDB>(&pairs);
+	let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+	for (key, value) in pairs.iter().filter(|(_, value)| value.len() <= VALUE_CHUNK_LEN) {
+		let range = value.len() / 2..value.len();
+		let proof = trie.prove_value_range(key, range.clone()).unwrap().unwrap();
+		assert!(proof.index.is_empty());
+		assert_eq!(proof.chunks.len(), (value.len() > VALUE_NODE_THRESHOLD) as usize);
+		let bytes = proof.verify::<ChunkedValueLayout>(&root, key, range.clone()).unwrap();
+		assert_eq!(bytes, value[range].to_vec());
+	}
+}
+
+#[test]
+fn invalid_value_range_proofs_are_rejected() {
+	let pairs = pairs();
+	let key = chunked_key(&pairs);
+	let len = pairs[&key].len();
+	let (db, root) = build::<MemoryDB>(&pairs);
+	let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+	let range = VALUE_CHUNK_LEN..VALUE_CHUNK_LEN * 2 + 1;
+	let proof = trie.prove_value_range(&key, range.clone()).unwrap().unwrap();
+	let verify = |proof: &trie_db::proof::ValueRangeProof, range| {
+		proof.verify::<ChunkedValueLayout>(&root, &key, range)
+	};
+
+	let mut tampered = proof.clone();
+	tampered.chunks[0][0] ^= 1;
+	assert_eq!(verify(&tampered, range.clone()), Err(VerifyError::ValueChunksMismatch));
+	let mut tampered = proof.clone();
+	tampered.index[0] ^= 1;
+	assert_eq!(verify(&tampered, range.clone()), Err(VerifyError::ValueChunksMismatch));
+	let mut short = proof.clone();
+	short.chunks.pop();
+	assert_eq!(verify(&short, range.clone()), Err(VerifyError::IncompleteProof));
+	let mut long = proof.clone();
+	long.chunks.push(long.chunks[0].clone());
+	assert_eq!(verify(&long, range.clone()), Err(VerifyError::ExtraneousNode));
+	// the chunks of another range.
+	assert_eq!(verify(&proof, 0..VALUE_CHUNK_LEN + 1), Err(VerifyError::ValueChunksMismatch));
+	assert_eq!(verify(&proof, len - 1..len + 1), Err(VerifyError::ValueRangeOutOfBounds(len)));
+	let mut truncated = proof.clone();
+	truncated.nodes.pop();
+	assert_eq!(verify(&truncated, range.clone()), Err(VerifyError::IncompleteProof));
+	assert!(matches!(
+		proof.verify::<ChunkedValueLayout>(&Root::default(), &key, range),
+		Err(VerifyError::RootMismatch(_)),
+	));
+
+	// a range past the end of the value is proven by the index alone.
+	let past = trie.prove_value_range(&key, len + 10..len + 20).unwrap().unwrap();
+	assert!(past.chunks.is_empty());
+	assert_eq!(verify(&past, len + 10..len + 20), Err(VerifyError::ValueRangeOutOfBounds(len)));
+}
+
+#[test]
+fn key_checks_do_not_read_chunks() {
+	let pairs = pairs();
+	let (db, root) = build::<MemoryDB>(&pairs);
+	let mut value_nodes = HashSet::new();
+	for value in pairs.values().filter(|value| value.len() > VALUE_NODE_THRESHOLD) {
+		if value.len() > VALUE_CHUNK_LEN {
+			let index = Index::new::<KeccakHasher>(value, VALUE_CHUNK_LEN);
+			value_nodes.insert(KeccakHasher::hash(&index.encode()));
+			value_nodes.extend(index.chunks);
+		} else {
+			value_nodes.insert(KeccakHasher::hash(value));
+		}
+	}
+	let value_reads = Cell::new(0);
+	let counting = FnHashDB::<KeccakHasher, _>::new(|hash: &Root, prefix: Prefix| {
+		if value_nodes.contains(hash) {
+			value_reads.set(value_reads.get() + 1);
+		}
+		db.get(hash, prefix)
+	});
+	let trie = TrieDB::<ChunkedValueLayout>::new(&counting, &root).unwrap();
+	let absent = b"absent".to_vec();
+	for key in pairs.keys().chain(Some(&absent)) {
+		let expected = pairs.contains_key(key);
+		assert_eq!(trie.contains(key).unwrap(), expected);
+		assert_eq!(exists::<ChunkedValueLayout>(&counting, &root, key, None).unwrap(), expected);
+	}
+	assert_eq!(value_reads.get(), 0);
+
+	// reading the values does fetch them.
+	for key in pairs.keys() {
+		trie.get(key).unwrap().unwrap();
+	}
+	assert!(value_reads.get() >= value_nodes.len());
+}
+
+/// A reader failing once `data` is read.
+struct FailingAfter<'a>(&'a [u8]);
+
+impl io::Read for FailingAfter<'_> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.0.is_empty() {
+			return Err(io::Error::other("read failed"));
+		}
+		self.0.read(buf)
+	}
+}
+
+#[test]
+fn insert_chunked_writes_chunks_as_read() {
+	let pairs = pairs();
+	let key = chunked_key(&pairs);
+	let (mut db, mut root) = build::<HashedMemoryDB>(&pairs);
+	let mut committed = db.clone();
+	committed.purge();
+	let new_value = value(VALUE_CHUNK_LEN * 10 + 3, 0x42);
+	{
+		let mut trie = TrieDBMut::<ChunkedValueLayout>::from_existing(&mut db, &mut root).unwrap();
+		assert_eq!(trie.insert_chunked(b"new", &mut &new_value[..]).unwrap(), None);
+		let index = Index::new::<KeccakHasher>(&new_value, VALUE_CHUNK_LEN);
+		for chunk in &index.chunks {
+			assert!(trie.db().contains(chunk, EMPTY_PREFIX));
+		}
+		// the index alone is written by the commit.
+		let index_hash = KeccakHasher::hash(&index.encode());
+		assert!(!trie.db().contains(&index_hash, EMPTY_PREFIX));
+		assert_eq!(trie.get(b"new").unwrap(), Some(new_value.clone()));
+
+		// a failed read leaves the trie and the database as they were.
+		let read = &new_value[..VALUE_CHUNK_LEN * 4 + 1];
+		match trie.insert_chunked(&key, &mut FailingAfter(read)) {
+			Err(InsertChunkedError::Read(error)) => assert_eq!(error.kind(), io::ErrorKind::Other),
+			result => panic!("unexpected result {:?}", result),
+		}
+		assert_eq!(trie.get(&key).unwrap().as_ref(), pairs.get(&key));
+		trie.remove(b"new").unwrap();
+	}
+	assert_eq!(root, ChunkedValueLayout::trie_root(&pairs));
+	db.purge();
+	assert_eq!(db.keys(), committed.keys());
+}
+
+#[test]
+fn insert_chunked_chunks_follow_their_node() {
+	let first = value(VALUE_CHUNK_LEN * 3 + 5, 0x10);
+	let second = value(VALUE_CHUNK_LEN * 2 + 1, 0x20);
+	let mut db = MemoryDB::default();
+	let mut root = Default::default();
+	{
+		let mut trie = TrieDBMut::<ChunkedValueLayout>::new(&mut db, &mut root);
+		// written at the root, the value ends up in a leaf below a branch.
+		trie.insert_chunked(b"\x12\x34", &mut &first[..]).unwrap();
+		trie.insert(b"\x12\x35", b"v").unwrap();
+		// written in a leaf, the value ends up in the branch.
+		trie.insert_chunked(b"\x12\x35\x01", &mut &second[..]).unwrap();
+		trie.insert_chunked(b"\x12", &mut &second[..]).unwrap();
+		trie.remove(b"\x12\x35\x01").unwrap();
+	}
+	let mut expected = Pairs::new();
+	expected.insert(b"\x12\x34".to_vec(), first);
+	expected.insert(b"\x12\x35".to_vec(), b"v".to_vec());
+	expected.insert(b"\x12".to_vec(), second);
+	let (mut expected_db, expected_root) = build::<MemoryDB>(&expected);
+	assert_eq!(root, expected_root);
+	let trie = TrieDB::<ChunkedValueLayout>::new(&db, &root).unwrap();
+	for (key, value) in &expected {
+		assert_eq!(trie.get(key).unwrap().as_ref(), Some(value));
+	}
+	db.purge();
+	expected_db.purge();
+	assert_eq!(db.keys(), expected_db.keys());
+}
+
+#[test]
+fn dropped_changes_remove_their_chunks() {
+	let pairs = pairs();
+	let key = chunked_key(&pairs);
+	let (mut db, mut root) = build::<HashedMemoryDB>(&pairs);
+	let mut committed = db.clone();
+	committed.purge();
+	let new_value = value(VALUE_CHUNK_LEN * 6, 0x42);
+	{
+		let mut trie = TrieDBMut::<ChunkedValueLayout>::from_existing(&mut db, &mut root).unwrap();
+		trie.insert_chunked(b"kept", &mut &new_value[..]).unwrap();
+		let checkpoint = trie.checkpoint();
+		trie.insert_chunked(&key, &mut &new_value[..]).unwrap();
+		trie.insert_chunked(b"dropped", &mut &new_value[..]).unwrap();
+		trie.rollback_to(checkpoint).unwrap();
+		assert_eq!(trie.get(&key).unwrap().as_ref(), pairs.get(&key));
+		assert_eq!(trie.get(b"kept").unwrap(), Some(new_value.clone()));
+		trie.discard();
+		// overwritten before the commit, the chunks are not kept.
+		trie.insert_chunked(b"replaced", &mut &new_value[..]).unwrap();
+		trie.insert(b"replaced", b"short").unwrap();
+	}
+	let mut expected = pairs.clone();
+	expected.insert(b"replaced".to_vec(), b"short".to_vec());
+	let (mut expected_db, expected_root) = build::<HashedMemoryDB>(&expected);
+	assert_eq!(root, expected_root);
+	db.purge();
+	expected_db.purge();
+	assert_eq!(db.keys(), expected_db.keys());
+	assert!(committed.keys().len() < db.keys().len());
+}
@@ -0,0 +1,301 @@
+// Copyright 2020 Parity Technologies
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Value nodes, holding the values too large for their node, with the value node layout of the
+//! reference trie.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+
+use hash_db::{HashDB, Hasher, Prefix, EMPTY_PREFIX};
+use keccak_hasher::KeccakHasher;
+use reference_trie::{NoExtensionLayout, ValueNodeLayout, VALUE_NODE_THRESHOLD};
+use trie_db::node::{NodePlan, Value};
+use trie_db::proof::{generate_proof, verify_proof};
+use trie_db::{
+	exists, DBValue, FnHashDB, NodeCodec, Recorder, Trie, TrieBuilder, TrieConfiguration, TrieDB,
+	TrieDBMut, TrieDBNodeIterator, TrieError, TrieLayout, TrieMut, trie_visit,
+};
+
+type MemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::PrefixedKey<KeccakHasher>, DBValue>;
+type HashedMemoryDB = memory_db::MemoryDB<KeccakHasher, memory_db::HashKey<KeccakHasher>, DBValue>;
+type Root = <KeccakHasher as Hasher>::Out;
+type Pairs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Pairs with values on both sides of the threshold, at leaves and in branches.
+fn pairs() -> Pairs {
+	let mut pairs = Pairs::new();
+	for i in 0..100u32 {
+		let key = KeccakHasher::hash(&i.to_le_bytes());
+		let len = 1 + (i as usize % 4);
+		pairs.insert(key[..len].to_vec(), vec![i as u8; 1 + (i as usize * 7 % 200)]);
+	}
+	pairs.insert(Vec::new(), vec![0xee; VALUE_NODE_THRESHOLD + 1]);
+	pairs.insert(vec![0x01], vec![0x01; VALUE_NODE_THRESHOLD]);
+	pairs
+}
+
+fn build<L: TrieLayout<Hash = KeccakHasher>, DB: HashDB<KeccakHasher, DBValue> + Default>(
+	pairs: &Pairs,
+) -> (DB, Root) {
+	let mut db = DB::default();
+	let mut root = Default::default();
+	{
+		let mut trie = TrieDBMut::<L>::new(&mut db, &mut root);
+		for (key, value) in pairs {
+			trie.insert(key, value).unwrap();
+		}
+	}
+	(db, root)
+}
+
+/// Number of entries of `db` with a positive reference count.
+fn stored(db: &HashedMemoryDB) -> usize {
+	db.keys().values().filter(|rc| **rc > 0).count()
+}
+
+#[test]
+fn value_nodes_read_back() {
+	let pairs = pairs();
+	let (db, root) = build::<ValueNodeLayout, MemoryDB>(&pairs);
+	let trie = TrieDB::<ValueNodeLayout>::new(&db, &root).unwrap();
+	for (key, value) in &pairs {
+		assert_eq!(trie.get(key).unwrap().as_ref(), Some(value));
+	}
+	assert_eq!(trie.get(b"absent").unwrap(), None);
+	let items = trie.iter().unwrap().map(|item| item.unwrap()).collect::<Vec<_>>();
+	assert_eq!(items, pairs.clone().into_iter().collect::<Vec<_>>());
+
+	let mut root = root;
+	let mut db = db;
+	let trie = TrieDBMut::<ValueNodeLayout>::from_existing(&mut db, &mut root).unwrap();
+	let items = trie.iter().map(|item| item.unwrap()).collect::<Vec<_>>();
+	assert_eq!(items, pairs.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn large_values_are_stored_in_value_nodes() {
+	let pairs = pairs();
+	let (db, root) = build::<ValueNodeLayout, HashedMemoryDB>(&pairs);
+	for value in pairs.values().filter(|value| value.len() > VALUE_NODE_THRESHOLD) {
+		assert!(db.contains(&KeccakHasher::hash(value), EMPTY_PREFIX));
+	}
+	let (_, inline_root) = build::<NoExtensionLayout, HashedMemoryDB>(&pairs);
+	assert_ne!(root, inline_root);
+	assert_eq!(root, ValueNodeLayout::trie_root(&pairs));
+
+	let trie = TrieDB::<ValueNodeLayout>::new(&db, &root).unwrap();
+	let mut hashed = 0;
+	for item in TrieDBNodeIterator::new(&trie).unwrap() {
+		let (_, _, node) = item.unwrap();
+		match node.value::<ValueNodeLayout>(Default::default()).unwrap() {
+			Some(Value::Node(hash)) => {
+				let mut value_hash = Root::default();
+				value_hash.copy_from_slice(hash);
+				assert!(db.contains(&value_hash, EMPTY_PREFIX));
+				hashed += 1;
+			},
+			Some(Value::Inline(value)) => assert!(value.len() <= VALUE_NODE_THRESHOLD),
+			Some(Value::Chunked(_)) => panic!("the layout does not store values in chunks"),
+			None => (),
+		}
+	}
+	assert_eq!(hashed, pairs.values().filter(|value| value.len() > VALUE_NODE_THRESHOLD).count());
+}
+
+#[test]
+fn changes_release_value_nodes() {
+	let pairs = pairs();
+	let (mut db, mut root) = build::<ValueNodeLayout, HashedMemoryDB>(&pairs);
+	let large = pairs.iter()
+		.filter(|(_, value)| value.len() > VALUE_NODE_THRESHOLD)
+		.map(|(key, _)| key.clone())
+		.collect::<Vec<_>>();
+	{
+		let mut trie = TrieDBMut::<ValueNodeLayout>::from_existing(&mut db, &mut root).unwrap();
+		for key in &large {
+			trie.insert(key, &[0xaa; VALUE_NODE_THRESHOLD * 2]).unwrap();
+		}
+	}
+	let mut updated = pairs.clone();
+	for key in &large {
+		updated.insert(key.clone(), vec![0xaa; VALUE_NODE_THRESHOLD * 2]);
+	}
+	let (expected_db, expected_root) = build::<ValueNodeLayout, HashedMemoryDB>(&updated);
+	assert_eq!(root, expected_root);
+	let trie = TrieDB::<ValueNodeLayout>::new(&db, &root).unwrap();
+	assert_eq!(trie.get(&large[0]).unwrap(), Some(vec![0xaa; VALUE_NODE_THRESHOLD * 2]));
+	db.purge();
+	assert_eq!(stored(&db), stored(&expected_db));
+
+	{
+		let mut trie = TrieDBMut::<ValueNodeLayout>::from_existing(&mut db, &mut root).unwrap();
+		for key in pairs.keys() {
+			trie.remove(key).unwrap();
+		}
+		assert!(trie.is_empty());
+	}
+	db.purge();
+	assert_eq!(stored(&db), 0);
+}
+
+#[test]
+fn builder_writes_value_nodes() {
+	let pairs = pairs();
+	let (_, root) = build::<ValueNodeLayout, HashedMemoryDB>(&pairs);
+	let mut db = HashedMemoryDB::default();
+	let mut builder = TrieBuilder::new(&mut db);
+	trie_visit::<ValueNodeLayout, _, _, _, _>(pairs.iter(), &mut builder);
+	assert_eq!(builder.root, Some(root));
+	let trie = TrieDB::<ValueNodeLayout>::new(&db, &root).unwrap();
+	for (key, value) in &pairs {
+		assert_eq!(trie.get(key).unwrap().as_ref(), Some(value));
+	}
+}
+
+#[test]
+fn proofs_leave_value_nodes_out() {
+	let pairs = pairs();
+	let keys = pairs.keys().step_by(7).cloned().collect::<Vec<_>>();
+	let items = keys.iter()
+		.map(|key| (key.clone(), pairs.get(key).cloned()))
+		.chain(Some((b"absent".to_vec(), None)))
+		.collect::<Vec<_>>();
+	let proof_keys = items.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+
+	let (db, root) = build::<ValueNodeLayout, MemoryDB>(&pairs);
+	let trie = TrieDB::<ValueNodeLayout>::new(&db, &root).unwrap();
+	let proof = generate_proof::<_, ValueNodeLayout, _, _>(&trie, &proof_keys).unwrap();
+	let stats = verify_proof::<ValueNodeLayout, _, _, _>(&root, &proof, &items).unwrap();
+	assert_eq!(stats.value_bytes, keys.iter().map(|key| pairs[key].len()).sum::<usize>());
+
+	let mut wrong = items.clone();
+	let large = wrong.iter_mut()
+		.find(|(_, value)| value.as_ref().is_some_and(|value| value.len() > VALUE_NODE_THRESHOLD))
+		.unwrap();
+	large.1.as_mut().unwrap()[0] ^= 1;
+	assert!(verify_proof::<ValueNodeLayout, _, _, _>(&root, &proof, &wrong).is_err());
+
+	let (inline_db, inline_root) = build::<NoExtensionLayout, MemoryDB>(&pairs);
+	let inline_trie = TrieDB::<NoExtensionLayout>::new(&inline_db, &inline_root).unwrap();
+	let inline_proof = generate_proof::<_, NoExtensionLayout, _, _>(&inline_trie, &proof_keys)
+		.unwrap();
+	let size = |proof: &[Vec<u8>]| proof.iter().map(Vec::len).sum::<usize>();
+	assert!(size(&proof) < size(&inline_proof));
+}
+
+#[test]
+fn recorder_includes_value_nodes_on_demand() {
+	let pairs = pairs();
+	let (db, root) = build::<ValueNodeLayout, MemoryDB>(&pairs);
+	let trie = TrieDB::<ValueNodeLayout>::new(&db, &root).unwrap();
+	let (key, value) = pairs.iter().find(|(_, value)| value.len() > VALUE_NODE_THRESHOLD).unwrap();
+	let value_hash = KeccakHasher::hash(value);
+
+	let mut with_values = Recorder::new();
+	trie.get_with(key, &mut with_values).unwrap();
+	let records = with_values.drain();
+	assert_eq!(records.last().map(|record| record.hash), Some(value_hash));
+
+	let mut without_values = Recorder::new();
+	without_values.record_values(false);
+	trie.get_with(key, &mut without_values).unwrap();
+	let without = without_values.drain();
+	assert_eq!(without.len(), records.len() - 1);
+	assert!(without.iter().all(|record| record.hash != value_hash));
+
+	let mut proof_db = HashedMemoryDB::default();
+	for record in &records {
+		proof_db.insert(EMPTY_PREFIX, &record.data);
+	}
+	let proof_trie = TrieDB::<ValueNodeLayout>::new(&proof_db, &root).unwrap();
+	assert_eq!(proof_trie.get(key).unwrap().as_ref(), Some(value));
+}
+
+#[test]
+fn merge_copies_value_nodes() {
+	let pairs = pairs();
+	let (mut left, mut right) = (Pairs::new(), Pairs::new());
+	for (i, (key, value)) in pairs.iter().enumerate() {
+		let side = if i % 3 == 0 { &mut left } else { &mut right };
+		side.insert(key.clone(), value.clone());
+	}
+	let (mut db, mut root) = build::<ValueNodeLayout, MemoryDB>(&left);
+	let (other_db, other_root) = build::<ValueNodeLayout, MemoryDB>(&right);
+	{
+		let other = TrieDB::<ValueNodeLayout>::new(&other_db, &other_root).unwrap();
+		let mut trie = TrieDBMut::<ValueNodeLayout>::from_existing(&mut db, &mut root).unwrap();
+		assert_eq!(trie.merge_from(&other).unwrap(), right.len() as u64);
+	}
+	assert_eq!(root, ValueNodeLayout::trie_root(&pairs));
+	let trie = TrieDB::<ValueNodeLayout>::new(&db, &root).unwrap();
+	for (key, value) in &pairs {
+		assert_eq!(trie.get(key).unwrap().as_ref(), Some(value));
+	}
+}
+
+#[test]
+fn key_checks_do_not_read_value_nodes() {
+	let pairs = pairs();
+	let (db, root) = build::<ValueNodeLayout, MemoryDB>(&pairs);
+	let mut value_nodes = HashSet::new();
+	for value in pairs.values().filter(|value| value.len() > VALUE_NODE_THRESHOLD) {
+		value_nodes.insert(KeccakHasher::hash(value));
+	}
+	let value_reads = Cell::new(0);
+	let counting = FnHashDB::<KeccakHasher, _>::new(|hash: &Root, prefix: Prefix| {
+		if value_nodes.contains(hash) {
+			value_reads.set(value_reads.get() + 1);
+		}
+		db.get(hash, prefix)
+	});
+	let trie = TrieDB::<ValueNodeLayout>::new(&counting, &root).unwrap();
+	let absent = b"absent".to_vec();
+	for key in pairs.keys().chain(Some(&absent)) {
+		let expected = pairs.contains_key(key);
+		assert_eq!(trie.contains(key).unwrap(), expected);
+		assert_eq!(exists::<ValueNodeLayout>(&counting, &root, key, None).unwrap(), expected);
+	}
+	assert_eq!(value_reads.get(), 0);
+
+	// reading the values does fetch them.
+	for key in pairs.keys() {
+		trie.get(key).unwrap().unwrap();
+	}
+	assert!(value_reads.get() >= value_nodes.len());
+}
+
+#[test]
+fn value_slots_with_unknown_tags_do_not_decode() {
+	let pairs = vec![(b"key".to_vec(), b"value".to_vec())].into_iter().collect();
+	let (mut db, root) = build::<ValueNodeLayout, HashedMemoryDB>(&pairs);
+	let mut data = db.get(&root, EMPTY_PREFIX).unwrap();
+	let value = match <ValueNodeLayout as TrieLayout>::Codec::decode_plan(&data).unwrap() {
+		NodePlan::Leaf { value, .. } => value,
+		_ => panic!("the root of a single pair is a leaf"),
+	};
+	data[value.start] = 7;
+	let bad_root = db.insert(EMPTY_PREFIX, &data);
+
+	let trie = TrieDB::<ValueNodeLayout>::new(&db, &bad_root).unwrap();
+	let expected = TrieError::InvalidValueTag(bad_root, 7);
+	assert_eq!(*trie.get(b"key").unwrap_err(), expected);
+	let items = trie.iter().unwrap().collect::<Vec<_>>();
+	assert_eq!(items.len(), 1);
+	assert_eq!(*items.into_iter().next().unwrap().unwrap_err(), expected);
+
+	// the empty slots of the omitted values of proofs are empty values.
+	let slot = Value::from_slot::<ValueNodeLayout>(&[], root).unwrap();
+	assert_eq!(slot, Value::Inline(&[]));
+}